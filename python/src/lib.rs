@@ -0,0 +1,253 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::PathBuf;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyKeyError, PyOSError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use u4pak::{Error, ErrorKind, Pak, Record, Variant};
+use u4pak::index::Encoding;
+use u4pak::pack::{PackOptions, PackPath};
+use u4pak::pak::{compression_method_name, HexDisplay, Options, COMPR_NONE, COMPR_ZLIB};
+use u4pak::unpack::{unpack_record_to_writer, UnpackOptions};
+
+create_exception!(u4pak_rs, U4PakError, PyException);
+
+fn to_py_err(error: Error) -> PyErr {
+    match error.kind() {
+        ErrorKind::Io => PyOSError::new_err(error.to_string()),
+        _ => U4PakError::new_err(error.to_string()),
+    }
+}
+
+fn decode_key(encryption_key: Option<&str>) -> PyResult<Option<Vec<u8>>> {
+    encryption_key.map(|key| base64::decode(key)
+        .map_err(|error| PyValueError::new_err(format!("illegal encryption key: {}", error))))
+        .transpose()
+}
+
+fn get_thread_count(threads: Option<usize>) -> PyResult<NonZeroUsize> {
+    match threads {
+        Some(threads) => NonZeroUsize::new(threads)
+            .ok_or_else(|| PyValueError::new_err("threads must be greater than 0")),
+        None => Ok(NonZeroUsize::new(num_cpus::get()).unwrap_or(NonZeroUsize::new(1).unwrap())),
+    }
+}
+
+#[pyclass(name = "Record", module = "u4pak_rs")]
+#[derive(Clone)]
+struct PyRecord {
+    #[pyo3(get)]
+    filename: String,
+    #[pyo3(get)]
+    offset: u64,
+    #[pyo3(get)]
+    size: u64,
+    #[pyo3(get)]
+    uncompressed_size: u64,
+    #[pyo3(get)]
+    compression_method: String,
+    #[pyo3(get)]
+    timestamp: Option<u64>,
+    // hex string
+    #[pyo3(get)]
+    sha1: Option<String>,
+    #[pyo3(get)]
+    encrypted: bool,
+    #[pyo3(get)]
+    compression_block_size: u32,
+}
+
+impl From<&Record> for PyRecord {
+    fn from(record: &Record) -> Self {
+        Self {
+            filename: record.filename().to_string(),
+            offset: record.offset(),
+            size: record.size(),
+            uncompressed_size: record.uncompressed_size(),
+            compression_method: compression_method_name(record.compression_method()).to_string(),
+            timestamp: record.timestamp(),
+            sha1: record.sha1().as_ref().map(|sha1| HexDisplay::new(sha1).to_string()),
+            encrypted: record.encrypted(),
+            compression_block_size: record.compression_block_size(),
+        }
+    }
+}
+
+#[pymethods]
+impl PyRecord {
+    fn __repr__(&self) -> String {
+        format!("Record({:?}, offset={}, size={}, uncompressed_size={}, compression_method={:?})",
+            self.filename, self.offset, self.size, self.uncompressed_size, self.compression_method)
+    }
+}
+
+#[pyclass(name = "Pak", module = "u4pak_rs")]
+struct PyPak {
+    pak: Pak,
+    path: PathBuf,
+    encryption_key: Option<Vec<u8>>,
+}
+
+#[pymethods]
+impl PyPak {
+    #[new]
+    #[pyo3(signature = (path, *, variant = "standard", encoding = "utf-8", encryption_key = None, ignore_magic = false, force_version = None))]
+    fn new(path: PathBuf, variant: &str, encoding: &str, encryption_key: Option<&str>, ignore_magic: bool, force_version: Option<u32>) -> PyResult<Self> {
+        let variant: Variant = variant.try_into().map_err(to_py_err)?;
+        let encoding: Encoding = encoding.try_into().map_err(to_py_err)?;
+        let encryption_key = decode_key(encryption_key)?;
+
+        let pak = Pak::from_path(&path, Options {
+            variant,
+            ignore_magic,
+            encoding,
+            force_version,
+            encryption_key: encryption_key.clone(),
+            ..Options::default()
+        }).map_err(to_py_err)?;
+
+        Ok(Self { pak, path, encryption_key })
+    }
+
+    #[getter]
+    fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    #[getter]
+    fn version(&self) -> u32 {
+        self.pak.version()
+    }
+
+    #[getter]
+    fn mount_point(&self) -> Option<String> {
+        self.pak.index().mount_point().map(str::to_string)
+    }
+
+    #[getter]
+    fn index_offset(&self) -> u64 {
+        self.pak.index_offset()
+    }
+
+    #[getter]
+    fn index_size(&self) -> u64 {
+        self.pak.index_size()
+    }
+
+    fn records(&self) -> Vec<PyRecord> {
+        self.pak.index().records().iter().map(PyRecord::from).collect()
+    }
+
+    fn record(&self, path: &str) -> Option<PyRecord> {
+        self.pak.record(path).map(PyRecord::from)
+    }
+
+    fn __len__(&self) -> usize {
+        self.pak.index().records().len()
+    }
+
+    fn __contains__(&self, path: &str) -> bool {
+        self.pak.record(path).is_some()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Pak({:?}, version={}, records={})",
+            self.path, self.pak.version(), self.pak.index().records().len())
+    }
+
+    // returns the decompressed (and decrypted) data of a single record
+    fn read<'py>(&self, py: Python<'py>, path: &str) -> PyResult<&'py PyBytes> {
+        let record = self.pak.record(path)
+            .ok_or_else(|| PyKeyError::new_err(path.to_string()))?;
+
+        let data = py.allow_threads(|| -> u4pak::Result<Vec<u8>> {
+            let mut file = File::open(&self.path)
+                .map_err(|error| Error::io_with_path(error, &self.path))?;
+            let mut data = Vec::with_capacity(record.uncompressed_size() as usize);
+            unpack_record_to_writer(record, self.pak.version(), self.pak.variant(), &mut file, &mut data, self.encryption_key.clone(), self.pak.cipher())?;
+            Ok(data)
+        }).map_err(to_py_err)?;
+
+        Ok(PyBytes::new(py, &data))
+    }
+
+    #[pyo3(signature = (outdir, paths = None, *, threads = None, verbose = false, allow_unsafe_paths = false))]
+    fn unpack(&self, py: Python<'_>, outdir: PathBuf, paths: Option<Vec<String>>, threads: Option<usize>, verbose: bool, allow_unsafe_paths: bool) -> PyResult<()> {
+        let thread_count = get_thread_count(threads)?;
+        let paths: Option<Vec<&str>> = paths.as_ref()
+            .map(|paths| paths.iter().map(String::as_str).collect());
+
+        py.allow_threads(|| {
+            let mut file = File::open(&self.path)
+                .map_err(|error| Error::io_with_path(error, &self.path))?;
+            u4pak::unpack::unpack(&self.pak, &mut file, &outdir, UnpackOptions {
+                paths: paths.as_deref(),
+                thread_count,
+                encryption_key: self.encryption_key.clone(),
+                verbose,
+                allow_unsafe_paths,
+                ..UnpackOptions::default()
+            })
+        }).map_err(to_py_err)
+    }
+}
+
+// sources use the same syntax as the pack sub-command, e.g. "dir" or
+// ":zlib,rename=foo.txt:dir/file.txt"
+#[pyfunction]
+#[pyo3(signature = (path, sources, *, version = 3, mount_point = None, compression = "none", compression_level = 6, encoding = "utf-8", encrypt = false, encryption_key = None, threads = None))]
+#[allow(clippy::too_many_arguments)]
+fn pack(py: Python<'_>, path: PathBuf, sources: Vec<String>, version: u32, mount_point: Option<&str>, compression: &str,
+        compression_level: u32, encoding: &str, encrypt: bool, encryption_key: Option<&str>, threads: Option<usize>) -> PyResult<PyPak> {
+    let compression_method = match compression.to_ascii_lowercase().as_str() {
+        "none" => COMPR_NONE,
+        "zlib" => COMPR_ZLIB,
+        _ => return Err(PyValueError::new_err(format!("unsupported compression method: {:?}", compression))),
+    };
+    let compression_level = NonZeroU32::new(compression_level)
+        .filter(|&level| level.get() <= 9)
+        .ok_or_else(|| PyValueError::new_err("compression_level must be between 1 and 9"))?;
+    let encoding: Encoding = encoding.try_into().map_err(to_py_err)?;
+    let encryption_key = decode_key(encryption_key)?;
+    let thread_count = get_thread_count(threads)?;
+
+    let mut pack_paths = Vec::with_capacity(sources.len());
+    for source in &sources {
+        pack_paths.push(PackPath::try_from(source.as_str()).map_err(to_py_err)?);
+    }
+
+    let pak = py.allow_threads(|| {
+        u4pak::pack::pack(&path, &pack_paths, PackOptions {
+            version,
+            mount_point,
+            compression_method,
+            compression_level,
+            encoding,
+            encrypted: encrypt,
+            encryption_key: encryption_key.clone(),
+            thread_count,
+            ..PackOptions::default()
+        })
+    }).map_err(to_py_err)?;
+
+    Ok(PyPak { pak, path, encryption_key })
+}
+
+#[pymodule]
+fn u4pak_rs(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyPak>()?;
+    m.add_class::<PyRecord>()?;
+    m.add_function(wrap_pyfunction!(pack, m)?)?;
+    m.add("U4PakError", py.get_type::<U4PakError>())?;
+    Ok(())
+}
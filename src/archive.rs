@@ -0,0 +1,540 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{fs::File, io::{stderr, Write}, num::NonZeroU32};
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use flate2::{Compression, Crc, write::DeflateEncoder};
+
+use crate::{Error, Filter, Pak, Record, Result};
+use crate::encode::Encode;
+use crate::encode;
+use crate::predicate::{self, Predicate};
+use crate::pak::COMPR_NONE;
+use crate::progress::{Progress, ProgressCallback};
+use crate::unpack::{assert_safe_paths, unpack_record_to_writer_with, UnpackBuffers};
+use crate::util::{make_pak_path, parse_pak_path, COMPR_LEVEL_DEFAULT};
+
+// Unpacks records directly into a ZIP or tar archive (e.g. to stdout) instead
+// of writing lots of small files into a directory.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+}
+
+#[derive(Debug)]
+pub struct ArchiveOptions<'a> {
+    pub paths: Option<&'a [&'a str]>,
+    // file names are printed to stderr, since the archive might go to stdout
+    pub verbose: bool,
+    pub null_separated: bool,
+    pub encryption_key: Option<Vec<u8>>,
+    // ZIP only: deflate level for records that are compressed in the pak,
+    // uncompressed records are stored
+    pub compression_level: NonZeroU32,
+    // for records without timestamp (seconds since the epoch)
+    pub fallback_timestamp: Option<u64>,
+    pub allow_unsafe_paths: bool,
+    pub predicate: Option<&'a Predicate>,
+    pub progress: Option<&'a dyn ProgressCallback>,
+}
+
+impl Default for ArchiveOptions<'_> {
+    fn default() -> Self {
+        Self {
+            paths: None,
+            verbose: false,
+            null_separated: false,
+            encryption_key: None,
+            compression_level: COMPR_LEVEL_DEFAULT,
+            fallback_timestamp: None,
+            allow_unsafe_paths: false,
+            predicate: None,
+            progress: None,
+        }
+    }
+}
+
+const ZIP_LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const ZIP_DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const ZIP_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x02014b50;
+const ZIP_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_EXTRA_FIELD_TAG: u16 = 0x0001;
+
+const ZIP_METHOD_STORE: u16 = 0;
+const ZIP_METHOD_DEFLATE: u16 = 8;
+// CRC and sizes follow the data in a data descriptor, so records can be
+// streamed into the archive without knowing them upfront
+const ZIP_FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+const ZIP_FLAGS: u16 = ZIP_FLAG_DATA_DESCRIPTOR | ZIP_FLAG_UTF8;
+const ZIP_VERSION: u16 = 20;
+const ZIP64_VERSION: u16 = 45;
+// upper byte 3 = unix, so the external attributes are unix file modes
+const ZIP_VERSION_MADE_BY: u16 = (3 << 8) | ZIP64_VERSION;
+const ZIP_EXTERNAL_ATTRS: u32 = 0o100644 << 16;
+
+const ZIP_MAX_U16: u64 = 0xFFFF;
+const ZIP_MAX_U32: u64 = 0xFFFF_FFFF;
+
+#[derive(Debug)]
+struct ZipEntry {
+    name: String,
+    method: u16,
+    dos_time: u16,
+    dos_date: u16,
+    crc32: u32,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+}
+
+impl ZipEntry {
+    #[inline]
+    fn sizes_need_zip64(&self) -> bool {
+        self.size >= ZIP_MAX_U32 || self.compressed_size >= ZIP_MAX_U32
+    }
+}
+
+// Counts the bytes written through it, so sizes and offsets are known without
+// buffering the written data.
+struct CountingWriter<W: Write> {
+    writer: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    #[inline]
+    fn new(writer: W) -> Self {
+        Self { writer, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.writer.write(buf)?;
+        self.count += count as u64;
+        Ok(count)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+enum ZipBody<W: Write> {
+    Store(CountingWriter<W>),
+    Deflate(DeflateEncoder<CountingWriter<W>>),
+}
+
+// Computes the CRC-32 and size of the uncompressed data of a ZIP entry while
+// it is written (and maybe compressed) into the archive.
+struct ZipEntryWriter<W: Write> {
+    crc: Crc,
+    size: u64,
+    body: ZipBody<W>,
+}
+
+impl<W: Write> ZipEntryWriter<W> {
+    // returns (crc32, compressed size, size)
+    fn finish(self) -> Result<(u32, u64, u64)> {
+        let compressed_size = match self.body {
+            ZipBody::Store(writer) => writer.count,
+            ZipBody::Deflate(encoder) => encoder.finish()?.count,
+        };
+        Ok((self.crc.sum(), compressed_size, self.size))
+    }
+}
+
+impl<W: Write> Write for ZipEntryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = match &mut self.body {
+            ZipBody::Store(writer) => writer.write(buf)?,
+            ZipBody::Deflate(encoder) => encoder.write(buf)?,
+        };
+        self.crc.update(&buf[..count]);
+        self.size += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.body {
+            ZipBody::Store(writer) => writer.flush(),
+            ZipBody::Deflate(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// upper bound of the deflated size (same formula as zlib's deflateBound())
+#[inline]
+fn deflate_bound(size: u64) -> u64 {
+    size + (size >> 12) + (size >> 14) + (size >> 25) + 13
+}
+
+// returns (time, date) in MS-DOS format, clamped to the representable range
+fn dos_date_time(timestamp: u64) -> (u16, u16) {
+    let datetime = match NaiveDateTime::from_timestamp_opt(timestamp.min(i64::MAX as u64) as i64, 0) {
+        Some(datetime) if datetime.year() >= 1980 => datetime,
+        _ => return (0, (1 << 5) | 1),
+    };
+
+    if datetime.year() > 1980 + 127 {
+        return ((23 << 11) | (59 << 5) | 29, (127 << 9) | (12 << 5) | 31);
+    }
+
+    let time = (datetime.hour() << 11) | (datetime.minute() << 5) | (datetime.second() / 2);
+    let date = ((datetime.year() as u32 - 1980) << 9) | (datetime.month() << 5) | datetime.day();
+
+    (time as u16, date as u16)
+}
+
+struct ZipWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    compression_level: NonZeroU32,
+    entries: Vec<ZipEntry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn new(writer: W, compression_level: NonZeroU32) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            compression_level,
+            entries: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    // The data is streamed by write_data. Whether the data descriptor needs
+    // 64 bit sizes has to be decided before that, so it is done based on the
+    // expected size.
+    fn add(&mut self, name: String, size: u64, compress: bool, timestamp: u64, write_data: impl FnOnce(&mut ZipEntryWriter<&mut W>) -> Result<()>) -> Result<()> {
+        let compress = compress && size > 0;
+        let zip64 = size >= ZIP_MAX_U32 || (compress && deflate_bound(size) >= ZIP_MAX_U32);
+        let method = if compress { ZIP_METHOD_DEFLATE } else { ZIP_METHOD_STORE };
+        let (dos_time, dos_date) = dos_date_time(timestamp);
+        let offset = self.offset;
+
+        let mut header = Vec::with_capacity(30 + name.len() + 20);
+        encode!(&mut header,
+            ZIP_LOCAL_FILE_HEADER_SIGNATURE,
+            (if zip64 { ZIP64_VERSION } else { ZIP_VERSION }),
+            ZIP_FLAGS,
+            method,
+            dos_time,
+            dos_date,
+            // crc32, compressed size and size are in the data descriptor
+            0u32,
+            (if zip64 { ZIP_MAX_U32 as u32 } else { 0u32 }),
+            (if zip64 { ZIP_MAX_U32 as u32 } else { 0u32 }),
+            name.len() as u16,
+            (if zip64 { 20u16 } else { 0u16 }),
+        );
+        header.write_all(name.as_bytes())?;
+        if zip64 {
+            encode!(&mut header,
+                ZIP64_EXTRA_FIELD_TAG,
+                16u16,
+                0u64,
+                0u64,
+            );
+        }
+        self.write(&header)?;
+
+        let writer = CountingWriter::new(&mut self.writer);
+        let mut entry_writer = ZipEntryWriter {
+            crc: Crc::new(),
+            size: 0,
+            body: if compress {
+                ZipBody::Deflate(DeflateEncoder::new(writer, Compression::new(self.compression_level.get())))
+            } else {
+                ZipBody::Store(writer)
+            },
+        };
+        write_data(&mut entry_writer)?;
+        let (crc32, compressed_size, size) = entry_writer.finish()?;
+        self.offset += compressed_size;
+
+        let entry = ZipEntry {
+            name,
+            method,
+            dos_time,
+            dos_date,
+            crc32,
+            compressed_size,
+            size,
+            offset,
+        };
+
+        if !zip64 && entry.sizes_need_zip64() {
+            return Err(Error::new(format!(
+                "record is bigger than expected, {} bytes don't fit a ZIP data descriptor",
+                entry.size.max(entry.compressed_size))));
+        }
+
+        header.clear();
+        encode!(&mut header, ZIP_DATA_DESCRIPTOR_SIGNATURE, entry.crc32);
+        if zip64 {
+            encode!(&mut header, entry.compressed_size, entry.size);
+        } else {
+            encode!(&mut header, entry.compressed_size as u32, entry.size as u32);
+        }
+        self.write(&header)?;
+        self.entries.push(entry);
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        let central_directory_offset = self.offset;
+        let mut header = Vec::new();
+        let entries = std::mem::take(&mut self.entries);
+
+        for entry in &entries {
+            let zip64_sizes = entry.sizes_need_zip64();
+            let zip64_offset = entry.offset >= ZIP_MAX_U32;
+
+            let mut extra = Vec::new();
+            if zip64_sizes {
+                encode!(&mut extra, entry.size, entry.compressed_size);
+            }
+            if zip64_offset {
+                encode!(&mut extra, entry.offset);
+            }
+
+            header.clear();
+            encode!(&mut header,
+                ZIP_CENTRAL_DIRECTORY_SIGNATURE,
+                ZIP_VERSION_MADE_BY,
+                (if zip64_sizes || zip64_offset { ZIP64_VERSION } else { ZIP_VERSION }),
+                ZIP_FLAGS,
+                entry.method,
+                entry.dos_time,
+                entry.dos_date,
+                entry.crc32,
+                (if zip64_sizes { ZIP_MAX_U32 as u32 } else { entry.compressed_size as u32 }),
+                (if zip64_sizes { ZIP_MAX_U32 as u32 } else { entry.size as u32 }),
+                entry.name.len() as u16,
+                (if extra.is_empty() { 0u16 } else { extra.len() as u16 + 4 }),
+                // comment length, disk number start, internal attributes
+                0u16,
+                0u16,
+                0u16,
+                ZIP_EXTERNAL_ATTRS,
+                (if zip64_offset { ZIP_MAX_U32 as u32 } else { entry.offset as u32 }),
+            );
+            header.write_all(entry.name.as_bytes())?;
+            if !extra.is_empty() {
+                encode!(&mut header, ZIP64_EXTRA_FIELD_TAG, extra.len() as u16);
+                header.write_all(&extra)?;
+            }
+            self.write(&header)?;
+        }
+
+        let central_directory_size = self.offset - central_directory_offset;
+        let entry_count = entries.len() as u64;
+
+        header.clear();
+        if entry_count >= ZIP_MAX_U16 || central_directory_size >= ZIP_MAX_U32 || central_directory_offset >= ZIP_MAX_U32 {
+            let zip64_end_offset = self.offset;
+            encode!(&mut header,
+                ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE,
+                // size of the remaining record
+                44u64,
+                ZIP_VERSION_MADE_BY,
+                ZIP64_VERSION,
+                // number of this disk, disk with the central directory
+                0u32,
+                0u32,
+                entry_count,
+                entry_count,
+                central_directory_size,
+                central_directory_offset,
+
+                ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE,
+                0u32,
+                zip64_end_offset,
+                // total number of disks
+                1u32,
+            );
+        }
+
+        encode!(&mut header,
+            ZIP_END_OF_CENTRAL_DIRECTORY_SIGNATURE,
+            0u16,
+            0u16,
+            entry_count.min(ZIP_MAX_U16) as u16,
+            entry_count.min(ZIP_MAX_U16) as u16,
+            central_directory_size.min(ZIP_MAX_U32) as u32,
+            central_directory_offset.min(ZIP_MAX_U32) as u32,
+            // comment length
+            0u16,
+        );
+        self.write(&header)?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_GNU_MAGIC: &[u8] = b"ustar  \0";
+const TAR_TYPE_FILE: u8 = b'0';
+const TAR_TYPE_GNU_LONGNAME: u8 = b'L';
+
+fn write_tar_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if value < (1u64 << (3 * digits as u32)) {
+        let octal = format!("{:0width$o}", value, width = digits);
+        field[..digits].copy_from_slice(octal.as_bytes());
+        field[digits] = 0;
+    } else {
+        // GNU base-256 encoding for big values (e.g. files >= 8 GiB)
+        for (index, byte) in field.iter_mut().rev().enumerate() {
+            *byte = if index < 8 { (value >> (index * 8)) as u8 } else { 0 };
+        }
+        field[0] |= 0x80;
+    }
+}
+
+fn tar_header(name: &[u8], size: u64, mtime: u64, typeflag: u8) -> [u8; TAR_BLOCK_SIZE] {
+    let mut header = [0u8; TAR_BLOCK_SIZE];
+    let name_len = name.len().min(100);
+    header[..name_len].copy_from_slice(&name[..name_len]);
+    write_tar_octal(&mut header[100..108], 0o644);
+    write_tar_octal(&mut header[108..116], 0);
+    write_tar_octal(&mut header[116..124], 0);
+    write_tar_octal(&mut header[124..136], size);
+    write_tar_octal(&mut header[136..148], mtime);
+    header[156] = typeflag;
+    header[257..265].copy_from_slice(TAR_GNU_MAGIC);
+
+    // the checksum is calculated with the checksum field set to spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    let checksum = format!("{:06o}\0 ", checksum);
+    header[148..156].copy_from_slice(checksum.as_bytes());
+
+    header
+}
+
+struct TarWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn write_padding(&mut self, size: u64) -> Result<()> {
+        let padding = (TAR_BLOCK_SIZE - (size % TAR_BLOCK_SIZE as u64) as usize) % TAR_BLOCK_SIZE;
+        self.writer.write_all(&[0u8; TAR_BLOCK_SIZE][..padding])?;
+        Ok(())
+    }
+
+    // The size goes into the header before the data is streamed by
+    // write_data, so write_data has to write exactly that many bytes.
+    fn add(&mut self, name: &str, size: u64, timestamp: u64, write_data: impl FnOnce(&mut CountingWriter<&mut W>) -> Result<()>) -> Result<()> {
+        let name = name.as_bytes();
+        if name.len() > 100 {
+            let mut long_name = name.to_vec();
+            long_name.push(0);
+            self.writer.write_all(&tar_header(b"././@LongLink", long_name.len() as u64, 0, TAR_TYPE_GNU_LONGNAME))?;
+            self.writer.write_all(&long_name)?;
+            self.write_padding(long_name.len() as u64)?;
+        }
+        self.writer.write_all(&tar_header(name, size, timestamp, TAR_TYPE_FILE))?;
+
+        let mut writer = CountingWriter::new(&mut self.writer);
+        write_data(&mut writer)?;
+        if writer.count != size {
+            return Err(Error::new(format!(
+                "record size mismatch, expected {} bytes but got {}",
+                size, writer.count)));
+        }
+
+        self.write_padding(size)
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.writer.write_all(&[0u8; 2 * TAR_BLOCK_SIZE])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+enum ArchiveWriter<W: Write> {
+    Zip(ZipWriter<W>),
+    Tar(TarWriter<W>),
+}
+
+pub fn unpack_to_archive(pak: &Pak, in_file: &mut File, writer: impl Write, format: ArchiveFormat, options: ArchiveOptions) -> Result<()> {
+    let mut filter: Option<Filter> = options.paths.map(|paths| paths.into());
+    let records: Vec<&Record> = if let Some(filter) = &mut filter {
+        pak.filter_records(filter).into_iter()
+            .filter(|record| predicate::matches(options.predicate, record))
+            .collect()
+    } else {
+        pak.index().records().iter()
+            .filter(|record| predicate::matches(options.predicate, record))
+            .collect()
+    };
+
+    if let Some(filter) = &filter {
+        filter.assert_all_visited()?;
+    }
+
+    if !options.allow_unsafe_paths {
+        assert_safe_paths(records.iter().copied())?;
+    }
+
+    let progress = Progress::new(options.progress);
+    for record in &records {
+        progress.add_total(1, record.uncompressed_size());
+    }
+    progress.finish_totals();
+
+    let mut archive = match format {
+        ArchiveFormat::Zip => ArchiveWriter::Zip(ZipWriter::new(writer, options.compression_level)),
+        ArchiveFormat::Tar => ArchiveWriter::Tar(TarWriter { writer }),
+    };
+
+    let linesep = if options.null_separated { '\0' } else { '\n' };
+    let mut buffers = UnpackBuffers::new();
+    for record in records {
+        let name = make_pak_path(parse_pak_path(record.filename()));
+        let timestamp = record.timestamp().or(options.fallback_timestamp).unwrap_or(0);
+
+        if options.verbose {
+            let _ = write!(stderr(), "{}{}", name, linesep);
+        }
+
+        let mut unpack = |mut writer: &mut dyn Write| unpack_record_to_writer_with(
+            record, pak.version(), pak.variant(), in_file, &mut writer,
+            options.encryption_key.clone(), pak.cipher(), &mut buffers);
+
+        match &mut archive {
+            ArchiveWriter::Zip(zip) => zip.add(name, record.uncompressed_size(), record.compression_method() != COMPR_NONE, timestamp, |writer| unpack(writer)),
+            ArchiveWriter::Tar(tar) => tar.add(&name, record.uncompressed_size(), timestamp, |writer| unpack(writer)),
+        }.map_err(|error| error.with_path_if_none(record.filename()))?;
+
+        progress.advance(record.filename(), record.uncompressed_size());
+    }
+
+    match archive {
+        ArchiveWriter::Zip(zip) => zip.finish(),
+        ArchiveWriter::Tar(tar) => tar.finish(),
+    }
+}
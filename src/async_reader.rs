@@ -0,0 +1,291 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use aes::BLOCK_SIZE;
+use flate2::bufread::ZlibDecoder;
+
+use crate::{Error, ErrorKind, Pak, Record, Result};
+use crate::decrypt::Cipher;
+use crate::index::secondary_index_ranges;
+use crate::pak::{compression_method_name, Options, COMPR_NONE, COMPR_ZLIB};
+use crate::util::align;
+
+// Positional reads for async I/O. This is runtime agnostic so u4pak doesn't
+// need to depend on tokio. A tokio file can be wrapped like this:
+//
+//     struct TokioFile(tokio::fs::File);
+//
+//     impl AsyncReadAt for TokioFile {
+//         async fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+//             self.0.seek(std::io::SeekFrom::Start(offset)).await?;
+//             self.0.read(buf).await
+//         }
+//
+//         async fn size(&mut self) -> std::io::Result<u64> {
+//             Ok(self.0.metadata().await?.len())
+//         }
+//     }
+pub trait AsyncReadAt {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> impl Future<Output = std::io::Result<usize>> + Send;
+
+    // total size of the pak file
+    fn size(&mut self) -> impl Future<Output = std::io::Result<u64>> + Send;
+}
+
+// Don't fetch less than this, so the many small reads of the parser don't
+// each become an I/O request.
+const MIN_FETCH_SIZE: usize = 64 * 1024;
+
+// Records that aren't split into compression blocks are read in chunks of this
+// size. It is a multiple of the AES block size, so each chunk of an encrypted
+// record can be decrypted on its own.
+const STREAM_CHUNK_SIZE: u64 = 1024 * 1024;
+
+async fn read_exact_at(reader: &mut impl AsyncReadAt, mut offset: u64, mut buf: &mut [u8]) -> Result<()> {
+    while !buf.is_empty() {
+        let count = reader.read_at(offset, buf).await?;
+        if count == 0 {
+            return Err(Error::io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        offset += count as u64;
+        buf = &mut buf[count..];
+    }
+    Ok(())
+}
+
+// Read + Seek over the parts of the file that where already fetched. A read
+// of anything else fails and remembers what was missing, so it can be fetched
+// before parsing again.
+struct SparseFile {
+    size: u64,
+    pos: u64,
+    chunks: BTreeMap<u64, Vec<u8>>,
+    missing: Option<(u64, usize)>,
+}
+
+impl SparseFile {
+    fn new(size: u64) -> Self {
+        Self {
+            size,
+            pos: 0,
+            chunks: BTreeMap::new(),
+            missing: None,
+        }
+    }
+
+    fn contains(&self, offset: u64, len: usize) -> bool {
+        match self.chunks.range(..=offset).next_back() {
+            Some((&start, chunk)) => offset + len as u64 <= start + chunk.len() as u64,
+            None => false,
+        }
+    }
+
+    async fn fetch(&mut self, reader: &mut impl AsyncReadAt, offset: u64, len: usize) -> Result<()> {
+        if self.contains(offset, len) {
+            return Ok(());
+        }
+        let len = len.max(MIN_FETCH_SIZE).min(self.size.saturating_sub(offset) as usize);
+        let mut chunk = vec![0u8; len];
+        read_exact_at(reader, offset, &mut chunk).await?;
+        self.chunks.insert(offset, chunk);
+        Ok(())
+    }
+
+    // Runs parse until it doesn't fail because of data that wasn't fetched
+    // yet, fetching that data in between.
+    async fn parse<T>(&mut self, reader: &mut impl AsyncReadAt, mut parse: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        loop {
+            self.pos = 0;
+            self.missing = None;
+            match parse(self) {
+                Ok(value) => return Ok(value),
+                Err(error) => match self.missing.take() {
+                    Some((offset, len)) => self.fetch(reader, offset, len).await?,
+                    None => return Err(error),
+                }
+            }
+        }
+    }
+}
+
+impl Read for SparseFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+
+        if let Some((&start, chunk)) = self.chunks.range(..=self.pos).next_back() {
+            let chunk_offset = (self.pos - start) as usize;
+            if chunk_offset < chunk.len() {
+                let count = buf.len().min(chunk.len() - chunk_offset);
+                buf[..count].copy_from_slice(&chunk[chunk_offset..chunk_offset + count]);
+                self.pos += count as u64;
+                return Ok(count);
+            }
+        }
+
+        self.missing = Some((self.pos, buf.len()));
+        Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "data not fetched yet"))
+    }
+}
+
+impl Seek for SparseFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset)     => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek to a negative position")),
+        }
+    }
+}
+
+impl Pak {
+    // Reads only the footer and index, i.e. the same as Pak::from_reader(),
+    // but without blocking on I/O. The footer, the primary index and the
+    // secondary indices are fetched one after the other, so the whole index is
+    // only parsed once.
+    pub async fn from_async_reader(reader: &mut impl AsyncReadAt, options: Options) -> Result<Pak> {
+        let size = reader.size().await?;
+        let mut file = SparseFile::new(size);
+
+        // the footer is at the end
+        let tail_offset = size.saturating_sub(MIN_FETCH_SIZE as u64);
+        file.fetch(reader, tail_offset, MIN_FETCH_SIZE).await?;
+        let footer = file.parse(reader, |file| Pak::read_footer(file, &options)).await?;
+
+        file.fetch(reader, footer.index_offset(), footer.index_size() as usize).await?;
+        if footer.version() >= 10 {
+            let ranges = file.parse(reader, |file| secondary_index_ranges(file, &footer, &options)).await?;
+            for (offset, size) in ranges {
+                file.fetch(reader, offset, size as usize).await?;
+            }
+        }
+
+        file.parse(reader, |file| Pak::from_reader(file, options.clone())).await
+    }
+}
+
+// Reads size bytes (aligned to the AES block size if the record is encrypted)
+// at offset into buffer and decrypts them. Returns the last encrypted block
+// for decrypting the data that follows.
+#[allow(clippy::too_many_arguments)]
+async fn read_chunk_at(reader: &mut impl AsyncReadAt, record: &Record, offset: u64, size: u64, buffer: &mut Vec<u8>, encryption_key: &Option<Vec<u8>>, cipher: &dyn Cipher, prev: Option<&[u8; BLOCK_SIZE]>) -> Result<Option<[u8; BLOCK_SIZE]>> {
+    let buffer_length = if record.encrypted() { align(size, BLOCK_SIZE as u64) } else { size };
+    buffer.clear();
+    buffer.resize(buffer_length as usize, 0);
+    read_exact_at(reader, offset, buffer).await?;
+
+    if !record.encrypted() {
+        return Ok(None);
+    }
+
+    let key = match encryption_key {
+        Some(key) => key,
+        None => return Err(Error::new(
+            "File is encrypted, but no encryption key was provided".to_string(),
+        ).with_kind(ErrorKind::EncryptedNoKey).with_path(record.filename())),
+    };
+    let mut last = [0u8; BLOCK_SIZE];
+    if buffer.len() >= BLOCK_SIZE {
+        last.copy_from_slice(&buffer[buffer.len() - BLOCK_SIZE..]);
+    }
+    match prev {
+        Some(prev) => cipher.decrypt_after(buffer, key, prev),
+        None => cipher.decrypt(buffer, key),
+    }
+    buffer.truncate(size as usize);
+    Ok(Some(last))
+}
+
+#[inline]
+fn decompression_error(record: &Record, error: std::io::Error) -> Error {
+    Error::new(format!("decompression failed: {}", error))
+        .with_kind(ErrorKind::DecompressionFailed)
+        .with_path(record.filename())
+}
+
+// Streams the uncompressed data of a record to sink. Zlib compressed records
+// are read and decompressed one compression block at a time, everything else
+// (uncompressed or version 2 records without compression blocks) in chunks of
+// STREAM_CHUNK_SIZE, so only that much of the record is in memory at once.
+pub async fn stream_record<F>(pak: &Pak, reader: &mut impl AsyncReadAt, record: &Record, encryption_key: Option<Vec<u8>>, mut sink: F) -> Result<()>
+where F: FnMut(&[u8]) -> Result<()> {
+    let version = pak.version();
+    let variant = pak.variant();
+    let cipher = pak.cipher();
+    let data_start = record.data_offset(version, variant);
+    let mut in_buffer = Vec::new();
+
+    match record.compression_method() {
+        COMPR_NONE => {
+            let mut offset = data_start;
+            let mut remaining = record.size();
+            let mut prev = None;
+            while remaining > 0 {
+                let chunk_size = remaining.min(STREAM_CHUNK_SIZE);
+                prev = read_chunk_at(reader, record, offset, chunk_size, &mut in_buffer, &encryption_key, cipher, prev.as_ref()).await?;
+                sink(&in_buffer)?;
+                offset += chunk_size;
+                remaining -= chunk_size;
+            }
+        }
+        COMPR_ZLIB if record.compression_blocks().is_some() => {
+            let mut out_buffer = Vec::with_capacity(record.compression_block_size() as usize);
+            for (block_start, block_end) in record.block_ranges(version) {
+                read_chunk_at(reader, record, block_start, block_end - block_start, &mut in_buffer, &encryption_key, cipher, None).await?;
+
+                out_buffer.clear();
+                ZlibDecoder::new(&in_buffer[..]).read_to_end(&mut out_buffer)
+                    .map_err(|error| decompression_error(record, error))?;
+                sink(&out_buffer)?;
+            }
+        }
+        COMPR_ZLIB => {
+            // version 2 has compression support, but neither compression
+            // blocks nor encryption
+            let mut zlib = flate2::write::ZlibDecoder::new(Vec::new());
+            let mut offset = data_start;
+            let mut remaining = record.size();
+            while remaining > 0 {
+                let chunk_size = remaining.min(STREAM_CHUNK_SIZE);
+                read_chunk_at(reader, record, offset, chunk_size, &mut in_buffer, &encryption_key, cipher, None).await?;
+                zlib.write_all(&in_buffer).map_err(|error| decompression_error(record, error))?;
+                let out_buffer = zlib.get_mut();
+                if !out_buffer.is_empty() {
+                    sink(out_buffer)?;
+                    out_buffer.clear();
+                }
+                offset += chunk_size;
+                remaining -= chunk_size;
+            }
+            let out_buffer = zlib.finish().map_err(|error| decompression_error(record, error))?;
+            if !out_buffer.is_empty() {
+                sink(&out_buffer)?;
+            }
+        }
+        _ => {
+            return Err(Error::new(format!(
+                    "unsupported compression method: {}",
+                    compression_method_name(record.compression_method())))
+                .with_kind(ErrorKind::UnknownCompressionMethod)
+                .with_path(record.filename()));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,167 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Compression benchmark for bench-compress: compresses a sample of the files
+// that would be packed with every combination of the given compression levels
+// and block sizes, block by block like pack does, to find settings that give
+// a good trade-off between package size and packing time.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::{Error, Result};
+use crate::pak::{COMPRESSION_BLOCK_HEADER_SIZE, COMPR_NONE, COMPR_ZLIB};
+use crate::walkdir::walkdir;
+
+// take at least this much of each file, so the sample isn't just file starts
+const MIN_FILE_SAMPLE_SIZE: u64 = 256 * 1024;
+
+// zlib only counts if it saves at least 1/SIGNIFICANT_SAVING of the size
+const SIGNIFICANT_SAVING: u64 = 20;
+
+// settings within 1/NEARLY_AS_SMALL of the smallest size count as equally good
+const NEARLY_AS_SMALL: u64 = 100;
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    // maximum number of bytes taken from all files together
+    pub sample_size: u64,
+    pub levels: Vec<u32>,
+    pub block_sizes: Vec<u32>,
+    // files with these extensions are stored uncompressed by pack anyway
+    pub no_compress_exts: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    pub compression_method: u32,
+    // 0 for COMPR_NONE
+    pub level: u32,
+    // 0 for COMPR_NONE
+    pub block_size: u32,
+    // including the compression block headers of the index
+    pub compressed_size: u64,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub file_count: usize,
+    pub sample_size: u64,
+    // stored uncompressed first, then zlib by level and block size
+    pub results: Vec<BenchResult>,
+}
+
+impl BenchReport {
+    // The zlib setting that packs fastest while being (nearly) as small as
+    // the smallest one, or storing uncompressed if zlib hardly saves anything.
+    pub fn recommendation(&self) -> Option<&BenchResult> {
+        let zlib = self.results.iter().filter(|result| result.compression_method == COMPR_ZLIB);
+        let smallest = zlib.clone().map(|result| result.compressed_size).min()?;
+
+        if smallest + self.sample_size / SIGNIFICANT_SAVING > self.sample_size {
+            return self.results.iter().find(|result| result.compression_method == COMPR_NONE);
+        }
+
+        zlib.filter(|result| result.compressed_size <= smallest + smallest / NEARLY_AS_SMALL)
+            .min_by_key(|result| (result.elapsed, result.compressed_size))
+    }
+}
+
+fn read_sample(path: &Path, max_size: u64) -> Result<Vec<u8>> {
+    let file = File::open(path).map_err(|error| Error::io_with_path(error, path))?;
+    let mut data = Vec::new();
+    file.take(max_size).read_to_end(&mut data).map_err(|error| Error::io_with_path(error, path))?;
+    Ok(data)
+}
+
+// The files of dir that pack would compress, sorted by path.
+fn compressible_files(dir: &Path, options: &BenchOptions) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for entry in walkdir(dir).map_err(|error| Error::io_with_path(error, dir))? {
+        let entry = entry.map_err(|error| Error::io_with_path(error, dir))?;
+        let path = entry.path();
+        let excluded = path.extension().is_some_and(|ext|
+            options.no_compress_exts.contains(&ext.to_string_lossy().to_ascii_lowercase()));
+        if !excluded {
+            let size = entry.metadata().map_err(|error| Error::io_with_path(error, &path))?.len();
+            files.push((path, size));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn compress_sample(samples: &[Vec<u8>], level: u32, block_size: u32, buffer: &mut Vec<u8>) -> Result<u64> {
+    let mut compressed_size = 0;
+    for sample in samples {
+        for block in sample.chunks(block_size as usize) {
+            buffer.clear();
+            let mut zlib = ZlibEncoder::new(&mut *buffer, Compression::new(level));
+            zlib.write_all(block)?;
+            zlib.finish()?;
+            compressed_size += buffer.len() as u64 + COMPRESSION_BLOCK_HEADER_SIZE;
+        }
+    }
+    Ok(compressed_size)
+}
+
+pub fn bench_compress(dir: impl AsRef<Path>, options: &BenchOptions) -> Result<BenchReport> {
+    let dir = dir.as_ref();
+    let files = compressible_files(dir, options)?;
+
+    // spread the sample over as many files as possible
+    let per_file = if files.is_empty() { 0 } else {
+        (options.sample_size / files.len() as u64).max(MIN_FILE_SAMPLE_SIZE)
+    };
+    let mut samples = Vec::new();
+    let mut sample_size = 0u64;
+    for (path, size) in &files {
+        if sample_size >= options.sample_size {
+            break;
+        }
+        if *size == 0 {
+            continue;
+        }
+        let sample = read_sample(path, per_file.min(options.sample_size - sample_size))?;
+        sample_size += sample.len() as u64;
+        samples.push(sample);
+    }
+
+    let mut results = vec![BenchResult {
+        compression_method: COMPR_NONE,
+        level: 0,
+        block_size: 0,
+        compressed_size: sample_size,
+        elapsed: Duration::default(),
+    }];
+
+    let mut buffer = Vec::new();
+    for &level in &options.levels {
+        for &block_size in &options.block_sizes {
+            let start = Instant::now();
+            let compressed_size = compress_sample(&samples, level, block_size, &mut buffer)?;
+            results.push(BenchResult {
+                compression_method: COMPR_ZLIB,
+                level,
+                block_size,
+                compressed_size,
+                elapsed: start.elapsed(),
+            });
+        }
+    }
+
+    Ok(BenchReport {
+        file_count: samples.len(),
+        sample_size,
+        results,
+    })
+}
@@ -4,9 +4,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{fmt::Display, fs::File, io::Read, path::PathBuf};
+use std::{convert::TryFrom, fmt::Display, fs::File, io::{BufRead, Read, Write}, path::{Path, PathBuf}};
 
-use crate::{Error, Result};
+use crate::{Error, ErrorKind, Result};
+
+pub const DEFAULT_ACTION_ENV: &str = "U4PAK_DEFAULT_ACTION";
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 enum ParseState {
@@ -32,7 +34,7 @@ fn parser_error(source: &[u8], index: usize, message: impl Display) -> Error {
             .position(|byte| byte == b'\n') {
         line_end
     } else {
-        source.len()
+        source.len() - index
     };
 
     let line = String::from_utf8_lossy(&source[line_start..line_end]);
@@ -53,132 +55,332 @@ fn parser_error(source: &[u8], index: usize, message: impl Display) -> Error {
     Error::new(message)
 }
 
-pub fn parse_arg_file(bin_name: String, source: &[u8]) -> Result<Vec<String>> {
-    let mut args = vec![bin_name];
+// Deeper nesting of @include is most likely an include cycle.
+const MAX_INCLUDE_DEPTH: usize = 32;
 
-    let mut state = ParseState::Space;
-    let mut start_index = 0usize;
-    let mut buffer = String::new();
+// Appends source[start..end] to buffer, replacing ${NAME} with the value of
+// the environment variable NAME. $${ is a literal ${.
+fn push_expanded(buffer: &mut String, source: &[u8], start: usize, end: usize) -> Result<()> {
+    let value = match std::str::from_utf8(&source[start..end]) {
+        Ok(value) => value,
+        Err(error) => {
+            return Err(parser_error(source, start + error.valid_up_to(), error));
+        }
+    };
 
-    for (index, &byte) in source.iter().enumerate() {
-        match state {
-            ParseState::Space => {
-                match byte {
-                    b'"' => {
+    let mut rest = value;
+    let mut offset = start;
+    while let Some(pos) = rest.find('$') {
+        buffer.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+
+        if let Some(after) = after.strip_prefix("${") {
+            buffer.push_str("${");
+            rest = after;
+            offset += pos + 3;
+        } else if let Some(name) = after.strip_prefix('{') {
+            let close = match name.find('}') {
+                Some(close) => close,
+                None => return Err(parser_error(source, offset + pos, "unterminated ${")),
+            };
+            let name = &name[..close];
+            if name.is_empty() {
+                return Err(parser_error(source, offset + pos, "empty environment variable name"));
+            }
+            match std::env::var(name) {
+                Ok(value) => buffer.push_str(&value),
+                Err(std::env::VarError::NotPresent) => {
+                    return Err(parser_error(source, offset + pos, format!("environment variable is not set: {}", name)));
+                }
+                Err(error) => {
+                    return Err(parser_error(source, offset + pos, format!("environment variable {}: {}", name, error)));
+                }
+            }
+            let consumed = pos + close + 3;
+            rest = &rest[consumed..];
+            offset += consumed;
+        } else {
+            buffer.push('$');
+            rest = after;
+            offset += pos + 1;
+        }
+    }
+    buffer.push_str(rest);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct ArgFileParser<'a> {
+    source: &'a [u8],
+    // directory of the file, @include paths are relative to it
+    dir: &'a Path,
+    depth: usize,
+    // index of an @include that still waits for its path
+    include_index: Option<usize>,
+}
+
+impl ArgFileParser<'_> {
+    fn finish_arg(&mut self, arg: String, arg_index: usize, quoted: bool, args: &mut Vec<String>) -> Result<()> {
+        if self.include_index.take().is_some() {
+            self.include(self.dir.join(arg), args)
+        } else if !quoted && arg == "@include" {
+            self.include_index = Some(arg_index);
+            Ok(())
+        } else {
+            args.push(arg);
+            Ok(())
+        }
+    }
+
+    fn include(&self, path: PathBuf, args: &mut Vec<String>) -> Result<()> {
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(Error::new(format!(
+                "@include nested more than {} levels deep, do the files include each other?",
+                MAX_INCLUDE_DEPTH)).with_path(path));
+        }
+
+        let source = match std::fs::read(&path) {
+            Ok(source) => source,
+            Err(error) => return Err(Error::io_with_path(error, path)),
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        ArgFileParser {
+            source: &source,
+            dir,
+            depth: self.depth + 1,
+            include_index: None,
+        }.parse(args).map_err(|error| error.with_path_if_none(&path))
+    }
+
+    fn parse(mut self, args: &mut Vec<String>) -> Result<()> {
+        let source = self.source;
+        let mut state = ParseState::Space;
+        let mut start_index = 0usize;
+        let mut arg_index = 0usize;
+        let mut buffer = String::new();
+        // quoted arguments are never @include
+        let mut quoted = false;
+
+        for (index, &byte) in source.iter().enumerate() {
+            match state {
+                ParseState::Space => {
+                    match byte {
+                        b'"' => {
+                            start_index = index + 1;
+                            arg_index = index;
+                            quoted = true;
+                            state = ParseState::QuotedString;
+                        }
+                        b'#' => {
+                            state = ParseState::Comment;
+                        }
+                        _ if byte.is_ascii_whitespace() => {}
+                        _ => {
+                            start_index = index;
+                            arg_index = index;
+                            state = ParseState::PlainString;
+                        }
+                    }
+                }
+                ParseState::Comment => {
+                    if byte == b'\n' {
+                        state = ParseState::Space;
+                    }
+                }
+                ParseState::PlainString => {
+                    if byte.is_ascii_whitespace() {
+                        push_expanded(&mut buffer, source, start_index, index)?;
+                        self.finish_arg(std::mem::take(&mut buffer), arg_index, quoted, args)?;
+                        quoted = false;
+                        state = ParseState::Space;
+                    } else if byte == b'"' {
+                        push_expanded(&mut buffer, source, start_index, index)?;
                         start_index = index + 1;
+                        quoted = true;
                         state = ParseState::QuotedString;
                     }
-                    b'#' => {
-                        state = ParseState::Comment;
+                }
+                ParseState::QuotedString => {
+                    if byte == b'"' {
+                        state = ParseState::Quote;
                     }
-                    _ if byte.is_ascii_whitespace() => {}
-                    _ => {
+                }
+                ParseState::Quote => {
+                    if byte == b'"' {
+                        push_expanded(&mut buffer, source, start_index, index - 1)?;
+                        buffer.push('"');
+                        start_index = index + 1;
+                        state = ParseState::QuotedString;
+                    } else if byte.is_ascii_whitespace() {
+                        push_expanded(&mut buffer, source, start_index, index - 1)?;
+                        self.finish_arg(std::mem::take(&mut buffer), arg_index, quoted, args)?;
+                        quoted = false;
+                        state = ParseState::Space;
+                    } else {
+                        push_expanded(&mut buffer, source, start_index, index - 1)?;
                         start_index = index;
                         state = ParseState::PlainString;
                     }
                 }
             }
-            ParseState::Comment => {
-                if byte == b'\n' {
-                    state = ParseState::Space;
-                }
-            }
+        }
+
+        match state {
+            ParseState::Comment | ParseState::Space => {}
             ParseState::PlainString => {
-                if byte.is_ascii_whitespace() {
-                    let value = match std::str::from_utf8(&source[start_index..index]) {
-                        Ok(value) => value,
-                        Err(error) => {
-                            return Err(parser_error(source, start_index + error.valid_up_to(), &error));
-                        }
-                    };
-                    buffer.push_str(value);
-                    args.push(buffer.to_owned());
-                    buffer.clear();
-                    state = ParseState::Space;
-                } else if byte == b'"' {
-                    let value = match std::str::from_utf8(&source[start_index..index]) {
-                        Ok(value) => value,
-                        Err(error) => {
-                            return Err(parser_error(source, start_index + error.valid_up_to(), &error));
-                        }
-                    };
-                    buffer.push_str(value);
-                    start_index = index + 1;
-                    state = ParseState::QuotedString;
-                }
+                push_expanded(&mut buffer, source, start_index, source.len())?;
+                self.finish_arg(buffer, arg_index, quoted, args)?;
             }
             ParseState::QuotedString => {
-                if byte == b'"' {
-                    state = ParseState::Quote;
-                }
+                let index = if let Some(&b'\n') = source.last() {
+                    source.len() - 1
+                } else {
+                    source.len()
+                };
+                return Err(parser_error(source, index, "unexpected end of file"));
             }
             ParseState::Quote => {
-                if byte == b'"' {
-                    buffer.push('"');
-                    start_index = index + 1;
-                    state = ParseState::QuotedString;
-                } else if byte.is_ascii_whitespace() {
-                    let value = match std::str::from_utf8(&source[start_index..index - 1]) {
-                        Ok(value) => value,
-                        Err(error) => {
-                            return Err(parser_error(source, start_index + error.valid_up_to(), &error));
-                        }
-                    };
-                    buffer.push_str(value);
-                    args.push(buffer.to_owned());
-                    buffer.clear();
-                    state = ParseState::Space;
-                } else {
-                    let value = match std::str::from_utf8(&source[start_index..index - 1]) {
-                        Ok(value) => value,
-                        Err(error) => {
-                            return Err(parser_error(source, start_index + error.valid_up_to(), &error));
-                        }
-                    };
-                    buffer.push_str(value);
-                    start_index = index;
-                    state = ParseState::PlainString;
-                }
+                push_expanded(&mut buffer, source, start_index, source.len() - 1)?;
+                self.finish_arg(buffer, arg_index, quoted, args)?;
             }
         }
+
+        if let Some(index) = self.include_index {
+            return Err(parser_error(source, index, "@include without a file"));
+        }
+
+        Ok(())
     }
+}
 
-    match state {
-        ParseState::Comment | ParseState::Space => {}
-        ParseState::PlainString => {
-            let value = match std::str::from_utf8(&source[start_index..]) {
-                Ok(value) => value,
-                Err(error) => {
-                    return Err(parser_error(source, start_index + error.valid_up_to(), error));
-                }
-            };
-            buffer.push_str(value);
-            args.push(buffer);
+// Arguments from a .u4pak file. dir is the directory of the file, for the
+// paths of @include.
+pub fn parse_arg_file(bin_name: String, source: &[u8], dir: &Path) -> Result<Vec<String>> {
+    let mut args = vec![bin_name];
+
+    ArgFileParser {
+        source,
+        dir,
+        depth: 0,
+        include_index: None,
+    }.parse(&mut args)?;
+
+    Ok(args)
+}
+
+// What to do with a package that is passed as the only argument, e.g. when
+// it was dropped on u4pak.exe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DefaultAction {
+    Info,
+    List,
+    Check,
+    Unpack,
+    Prompt,
+}
+
+impl TryFrom<&str> for DefaultAction {
+    type Error = Error;
+
+    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+        let trim_value = value.trim();
+
+        if trim_value.eq_ignore_ascii_case("info") {
+            Ok(DefaultAction::Info)
+        } else if trim_value.eq_ignore_ascii_case("list") {
+            Ok(DefaultAction::List)
+        } else if trim_value.eq_ignore_ascii_case("check") {
+            Ok(DefaultAction::Check)
+        } else if trim_value.eq_ignore_ascii_case("unpack") {
+            Ok(DefaultAction::Unpack)
+        } else if trim_value.eq_ignore_ascii_case("prompt") {
+            Ok(DefaultAction::Prompt)
+        } else {
+            Err(Error::new(format!(
+                "illegal value for {}: {:?}, possible values: info, list, check, unpack, prompt",
+                DEFAULT_ACTION_ENV, value))
+                .with_kind(ErrorKind::InvalidArgument))
         }
-        ParseState::QuotedString => {
-            let index = if let Some(&b'\n') = source.last() {
-                source.len() - 1
-            } else {
-                source.len()
-            };
-            return Err(parser_error(source, index, "unexpected end of file"));
+    }
+}
+
+impl DefaultAction {
+    // Without U4PAK_DEFAULT_ACTION ask what to do if u4pak has its own
+    // terminal window (it was started by double click or drag and drop),
+    // otherwise show the info like before.
+    pub fn from_env(owns_terminal: bool) -> Result<Self> {
+        match std::env::var(DEFAULT_ACTION_ENV) {
+            Ok(value) if !value.trim().is_empty() => DefaultAction::try_from(value.as_str()),
+            _ if owns_terminal => Ok(DefaultAction::Prompt),
+            _ => Ok(DefaultAction::Info),
         }
-        ParseState::Quote => {
-            let value = match std::str::from_utf8(&source[start_index..source.len() - 1]) {
-                Ok(value) => value,
-                Err(error) => {
-                    return Err(parser_error(source, start_index + error.valid_up_to(), &error));
-                }
-            };
-            buffer.push_str(value);
-            args.push(buffer);
+    }
+
+    // The arguments to run for the package, None for Prompt.
+    pub fn args(self, bin_name: String, path: String) -> Option<Vec<String>> {
+        match self {
+            DefaultAction::Info   => Some(vec![bin_name, "info".to_string(), "-h".to_string(), path]),
+            DefaultAction::List   => Some(vec![bin_name, "list".to_string(), "-h".to_string(), path]),
+            DefaultAction::Check  => Some(vec![bin_name, "check".to_string(), "--progress".to_string(), path]),
+            DefaultAction::Unpack => {
+                let outdir = unpack_dir(&path);
+                Some(vec![bin_name, "unpack".to_string(), "--progress".to_string(),
+                     "--outdir".to_string(), outdir.to_string_lossy().into_owned(), path])
+            }
+            DefaultAction::Prompt => None,
         }
     }
+}
 
-    Ok(args)
+// Foo.pak is unpacked to Foo next to it.
+fn unpack_dir(path: &str) -> PathBuf {
+    Path::new(path).with_extension("")
 }
 
-pub fn get_args_from_file() -> Result<Option<Vec<String>>> {
+// Asks on the terminal what to do with the package. None if the user wants
+// to quit (or stdin is closed).
+pub fn prompt_action(path: &str) -> Result<Option<DefaultAction>> {
+    let stdin = std::io::stdin();
+    let mut stdin = stdin.lock();
+    let mut stdout = std::io::stdout();
+    let mut line = String::new();
+
+    loop {
+        write!(stdout,
+            "What do you want to do with {}?\n\
+            \x20 [i] show information\n\
+            \x20 [l] list files\n\
+            \x20 [c] check integrity\n\
+            \x20 [u] unpack to {}\n\
+            \x20 [q] quit\n\
+            Choice [u]: ",
+            path, unpack_dir(path).to_string_lossy())?;
+        stdout.flush()?;
+
+        line.clear();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let action = match line.trim().to_ascii_lowercase().as_str() {
+            "" | "u" | "unpack" => DefaultAction::Unpack,
+            "i" | "info"        => DefaultAction::Info,
+            "l" | "list"        => DefaultAction::List,
+            "c" | "check"       => DefaultAction::Check,
+            "q" | "quit"        => return Ok(None),
+            choice => {
+                writeln!(stdout, "Unknown choice: {:?}\n", choice)?;
+                continue;
+            }
+        };
+
+        return Ok(Some(action));
+    }
+}
+
+pub fn get_args_from_file(owns_terminal: bool) -> Result<Option<Vec<String>>> {
     let mut args = std::env::args();
     if args.len() != 2 {
         return Ok(None);
@@ -196,7 +398,14 @@ pub fn get_args_from_file() -> Result<Option<Vec<String>>> {
             if ext.eq_ignore_ascii_case("u4pak") {
                 arg
             } else if ext.eq_ignore_ascii_case("pak") {
-                return Ok(Some(vec![bin_name, "info".to_string(), "-h".to_string(), arg]));
+                let action = match DefaultAction::from_env(owns_terminal)? {
+                    DefaultAction::Prompt => match prompt_action(&arg)? {
+                        Some(action) => action,
+                        None => std::process::exit(0),
+                    },
+                    action => action,
+                };
+                return Ok(action.args(bin_name, arg));
             } else {
                 return Ok(None);
             }
@@ -218,7 +427,8 @@ pub fn get_args_from_file() -> Result<Option<Vec<String>>> {
         Err(error) => return Err(Error::io_with_path(error, path))
     }
 
-    match parse_arg_file(bin_name, &source) {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    match parse_arg_file(bin_name, &source, dir) {
         Ok(args) => {
             if let Some(path) = path.parent() {
                 // no components means the file path was relative to the current directory
@@ -230,6 +440,117 @@ pub fn get_args_from_file() -> Result<Option<Vec<String>>> {
             }
             Ok(Some(args))
         },
-        Err(error) => Err(error.with_path(path))
+        Err(error) => Err(error.with_path_if_none(path))
+    }
+}
+
+// Path lists as produced by e.g. `find` or `find -print0`. If the list
+// contains any NULL byte it is NULL separated, otherwise new line separated.
+pub fn parse_path_list(source: &[u8]) -> std::result::Result<Vec<String>, std::string::FromUtf8Error> {
+    let mut paths = Vec::new();
+
+    if source.contains(&0) {
+        for path in source.split(|&byte| byte == 0) {
+            if !path.is_empty() {
+                paths.push(String::from_utf8(path.to_vec())?);
+            }
+        }
+    } else {
+        for line in source.split(|&byte| byte == b'\n') {
+            let line = if let Some((b'\r', line)) = line.split_last() {
+                line
+            } else {
+                line
+            };
+            if !line.is_empty() {
+                paths.push(String::from_utf8(line.to_vec())?);
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+pub fn read_path_list(path: &str) -> Result<Vec<String>> {
+    let mut source = Vec::new();
+
+    if path == "-" {
+        if let Err(error) = std::io::stdin().read_to_end(&mut source) {
+            return Err(Error::io_with_path(error, "<stdin>"));
+        }
+    } else {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) => return Err(Error::io_with_path(error, path))
+        };
+        if let Err(error) = file.read_to_end(&mut source) {
+            return Err(Error::io_with_path(error, path));
+        }
+    }
+
+    match parse_path_list(&source) {
+        Ok(paths) => Ok(paths),
+        Err(error) => Err(Error::new(format!("illegal file name in path list: {}", error))
+            .with_path(if path == "-" { "<stdin>" } else { path }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_path_list_lines() {
+        assert_eq!(parse_path_list(b"a.txt\nb/c.txt\n").unwrap(), vec!["a.txt", "b/c.txt"]);
+        assert_eq!(parse_path_list(b"a.txt\r\nb/c.txt\r\n\r\nd.txt").unwrap(), vec!["a.txt", "b/c.txt", "d.txt"]);
+        // only a trailing CR is removed
+        assert_eq!(parse_path_list(b"a\rb.txt\n").unwrap(), vec!["a\rb.txt"]);
+        assert!(parse_path_list(b"").unwrap().is_empty());
+        assert!(parse_path_list(b"\n\r\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_path_list_nul_separated() {
+        assert_eq!(parse_path_list(b"a.txt\0b\nc.txt\0\0").unwrap(), vec!["a.txt", "b\nc.txt"]);
+        assert!(parse_path_list(b"\0").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_path_list_invalid_utf8() {
+        assert!(parse_path_list(b"a.txt\n\xFF.txt\n").is_err());
+        assert!(parse_path_list(b"a.txt\0\xC3\0").is_err());
+    }
+
+    fn expand(source: &str) -> Result<String> {
+        let mut buffer = String::new();
+        push_expanded(&mut buffer, source.as_bytes(), 0, source.len())?;
+        Ok(buffer)
+    }
+
+    #[test]
+    fn expand_env_vars() {
+        std::env::set_var("U4PAK_TEST_EXPAND", "/tmp/game");
+        std::env::set_var("U4PAK_TEST_EXPAND_EMPTY", "");
+        assert_eq!(expand("${U4PAK_TEST_EXPAND}/Content").unwrap(), "/tmp/game/Content");
+        assert_eq!(expand("a${U4PAK_TEST_EXPAND_EMPTY}b${U4PAK_TEST_EXPAND}").unwrap(), "ab/tmp/game");
+        assert_eq!(expand("plain $HOME $ {x}").unwrap(), "plain $HOME $ {x}");
+        assert_eq!(expand("$").unwrap(), "$");
+    }
+
+    #[test]
+    fn expand_escaped() {
+        assert_eq!(expand("$${U4PAK_TEST_NOT_SET}").unwrap(), "${U4PAK_TEST_NOT_SET}");
+        assert_eq!(expand("$$${U4PAK_TEST_NOT_SET}").unwrap(), "$${U4PAK_TEST_NOT_SET}");
+    }
+
+    #[test]
+    fn expand_errors() {
+        assert!(expand("${U4PAK_TEST_NOT_SET}").is_err());
+        assert!(expand("${}").is_err());
+        assert!(expand("${U4PAK_TEST_EXPAND").is_err());
+        assert!(expand("a ${").is_err());
+
+        let error = expand("a ${U4PAK_TEST_NOT_SET}").unwrap_err();
+        assert!(error.to_string().starts_with("1:3: environment variable is not set: U4PAK_TEST_NOT_SET\n"), "{}", error);
     }
 }
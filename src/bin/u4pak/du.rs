@@ -0,0 +1,100 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use u4pak::{DirTree, Error, Pak, Result};
+use u4pak::dirtree::ROOT;
+use u4pak::util::{format_size, parse_pak_path, print_headless_table, print_table, Align::*};
+
+#[derive(Debug, Default)]
+pub struct DuOptions<'a> {
+    pub path: Option<&'a str>,
+    pub human_readable: bool,
+    pub no_header: bool,
+    // sort by compressed instead of uncompressed size
+    pub sort_by_compressed: bool,
+    // relative to path
+    pub max_depth: Option<usize>,
+}
+
+struct DirUsage {
+    path: String,
+    file_count: usize,
+    size: u64,
+    uncompressed_size: u64,
+}
+
+fn collect(tree: &DirTree, node: usize, path: &mut Vec<String>, depth: usize, max_depth: Option<usize>, dirs: &mut Vec<DirUsage>) {
+    let dir = &tree.nodes()[node];
+    dirs.push(DirUsage {
+        path: format!("/{}", path.join("/")),
+        file_count: dir.file_count(),
+        size: dir.size(),
+        uncompressed_size: dir.uncompressed_size(),
+    });
+
+    if matches!(max_depth, Some(max_depth) if depth >= max_depth) {
+        return;
+    }
+
+    for child in tree.sorted_children(node) {
+        let child_node = &tree.nodes()[child];
+        if child_node.is_dir() {
+            path.push(child_node.name().to_string());
+            collect(tree, child, path, depth + 1, max_depth, dirs);
+            path.pop();
+        }
+    }
+}
+
+// Prints the cumulative sizes of all directories (under options.path),
+// biggest first.
+pub fn du(pak: &Pak, options: DuOptions) -> Result<()> {
+    let tree = DirTree::new(pak.index().records())?;
+
+    let mut path: Vec<String> = Vec::new();
+    let node = if let Some(dir_path) = options.path {
+        let node = tree.lookup(dir_path)
+            .ok_or_else(|| Error::new("path not found".to_string()).with_path(dir_path))?;
+        if !tree.nodes()[node].is_dir() {
+            return Err(Error::new("not a directory".to_string()).with_path(dir_path));
+        }
+        path.extend(parse_pak_path(dir_path).map(str::to_string));
+        node
+    } else {
+        ROOT
+    };
+
+    let mut dirs = Vec::new();
+    collect(&tree, node, &mut path, 0, options.max_depth, &mut dirs);
+
+    if options.sort_by_compressed {
+        dirs.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    } else {
+        dirs.sort_by(|a, b| b.uncompressed_size.cmp(&a.uncompressed_size).then_with(|| a.path.cmp(&b.path)));
+    }
+
+    let fmt_size = if options.human_readable {
+        |size: u64| format_size(size)
+    } else {
+        |size: u64| format!("{}", size)
+    };
+
+    let body: Vec<Vec<String>> = dirs.iter().map(|dir| vec![
+        fmt_size(dir.uncompressed_size),
+        fmt_size(dir.size),
+        format!("{}", dir.file_count),
+        dir.path.clone(),
+    ]).collect();
+
+    let align = [Right, Right, Right, Left];
+    if options.no_header {
+        print_headless_table(&body, &align);
+    } else {
+        print_table(&["Size", "Compr.", "Files", "Directory"], &align, &body);
+    }
+
+    Ok(())
+}
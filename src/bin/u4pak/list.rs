@@ -4,11 +4,13 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::io::Write;
+use std::{collections::HashMap, io::Write};
 
 use chrono::NaiveDateTime;
+use serde::Serialize;
 
-use u4pak::{Filter, util::print_headless_table};
+use u4pak::{Filter, MergedView, index::LazyIndex, Predicate, predicate, util::print_headless_table};
+use u4pak::serialize::Document;
 use u4pak::util::{format_size, print_table, Align::*};
 use u4pak::result::Result;
 use u4pak::record::Record;
@@ -18,14 +20,22 @@ use crate::sort::{sort, Order};
 
 #[derive(Debug, PartialEq)]
 pub enum ListStyle {
-    Table { human_readable: bool, no_header: bool },
+    // ratio: size / uncompressed size, blocks: number of compression blocks
+    Table { human_readable: bool, no_header: bool, ratio: bool, blocks: bool },
     OnlyNames { null_separated: bool },
+    // a JSON document, see serialize::Document
+    Json,
 }
 
 pub struct ListOptions<'a> {
     pub order: Option<&'a Order>,
     pub style: ListStyle,
     pub paths: Option<&'a [&'a str]>,
+    pub predicate: Option<&'a Predicate>,
+    // list the directories containing the files instead of the files
+    pub only_dirs: bool,
+    // files deeper than that are listed as their directory at that depth
+    pub depth: Option<usize>,
 }
 
 impl ListOptions<'_> {
@@ -38,7 +48,7 @@ impl ListOptions<'_> {
 impl Default for ListStyle {
     #[inline]
     fn default() -> Self {
-        ListStyle::Table { human_readable: false, no_header: false }
+        ListStyle::Table { human_readable: false, no_header: false, ratio: false, blocks: false }
     }
 }
 
@@ -49,6 +59,9 @@ impl Default for ListOptions<'_> {
             order: None,
             style: ListStyle::default(),
             paths: None,
+            predicate: None,
+            only_dirs: false,
+            depth: None,
         }
     }
 }
@@ -58,9 +71,9 @@ pub fn list(pak: Pak, options: ListOptions) -> Result<()> {
     match (options.order, options.paths) {
         (Some(order), Some(paths)) => {
             let mut filter = Filter::from_paths(paths.iter().cloned());
-            let mut records = pak.index().records()
-                .iter()
-                .filter(|record| filter.visit(record.filename()))
+            let mut records = pak.filter_records(&mut filter)
+                .into_iter()
+                .filter(|record| predicate::matches(options.predicate, record))
                 .collect();
 
             sort(&mut records, order);
@@ -68,23 +81,227 @@ pub fn list(pak: Pak, options: ListOptions) -> Result<()> {
             filter.assert_all_visited()?;
         }
         (Some(order), None) => {
-            let mut records = pak.index().records().iter().collect();
+            let mut records = pak.index().iter()
+                .filter(|record| predicate::matches(options.predicate, record))
+                .collect();
 
             sort(&mut records, order);
             list_records(version, &records, options)?;
         }
         (None, Some(paths)) => {
             let mut filter = Filter::from_paths(paths.iter().cloned());
-            let records = pak.index().records()
-                .iter()
-                .filter(|record| filter.visit(record.filename()))
+            let records = pak.filter_records(&mut filter)
+                .into_iter()
+                .filter(|record| predicate::matches(options.predicate, record))
                 .collect::<Vec<_>>();
 
             list_records(version, &records, options)?;
             filter.assert_all_visited()?;
         }
         (None, None) => {
-            list_records(version, pak.index().records(), options)?;
+            if options.predicate.is_some() {
+                let records = pak.index().iter()
+                    .filter(|record| predicate::matches(options.predicate, record))
+                    .collect::<Vec<_>>();
+
+                list_records(version, &records, options)?;
+            } else {
+                list_records(version, pak.index().records(), options)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Lists the files of the view that are overridden by a package of higher
+// priority, together with the package they are from and the one that wins.
+pub fn list_shadowed(view: &MergedView, names: &[impl AsRef<str>], options: ListOptions) -> Result<()> {
+    let mut filter = options.paths.map(|paths| Filter::from_paths(paths.iter().cloned()));
+    let mut shadowed = view.shadowed().iter()
+        .filter(|shadowed| match &mut filter {
+            Some(filter) => filter.visit(&shadowed.entry.path),
+            None => true,
+        })
+        .filter(|shadowed| predicate::matches(options.predicate, shadowed.entry.record))
+        .collect::<Vec<_>>();
+
+    if let Some(order) = options.order {
+        sort(&mut shadowed, order);
+    }
+
+    match options.style {
+        ListStyle::Table { human_readable, no_header, .. } => {
+            let fmt_size = if human_readable {
+                |size: u64| format_size(size)
+            } else {
+                |size: u64| format!("{}", size)
+            };
+
+            let body: Vec<Vec<String>> = shadowed.iter().map(|shadowed| {
+                let record = shadowed.entry.record;
+                vec![
+                    fmt_size(record.uncompressed_size()),
+                    fmt_size(record.size()),
+                    HexDisplay::new(record.sha1().as_ref().unwrap_or(&NULL_SHA1)).to_string(),
+                    names[shadowed.entry.package].as_ref().to_string(),
+                    names[shadowed.overridden_by].as_ref().to_string(),
+                    shadowed.entry.path.clone(),
+                ]
+            }).collect();
+
+            let align = [Right, Right, Left, Left, Left, Left];
+            if no_header {
+                print_headless_table(&body, &align);
+            } else {
+                print_table(
+                    &["Size", "Compr.", "SHA-1", "Package", "Overridden-By", "Filename"],
+                    &align,
+                    &body,
+                );
+            }
+        }
+        ListStyle::OnlyNames { null_separated } => {
+            let sep = [if null_separated { 0 } else { b'\n' }];
+            let mut stdout = std::io::stdout();
+            for shadowed in &shadowed {
+                stdout.write_all(shadowed.entry.path.as_bytes())?;
+                stdout.write_all(&sep)?;
+            }
+        }
+        ListStyle::Json => {
+            let files: Vec<_> = shadowed.iter().map(|shadowed| {
+                // including the mount point if the mount points differ
+                let mut record = shadowed.entry.record.clone();
+                record.set_filename(shadowed.entry.path.clone());
+                ShadowedFile {
+                    record,
+                    package: names[shadowed.entry.package].as_ref(),
+                    overridden_by: names[shadowed.overridden_by].as_ref(),
+                }
+            }).collect();
+            println!("{}", serde_json::to_string(&Document::new(Files { files }))?);
+        }
+    }
+
+    if let Some(filter) = &filter {
+        filter.assert_all_visited()?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Files<T> {
+    files: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct Entries {
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct ShadowedFile<'a> {
+    #[serde(flatten)]
+    record: Record,
+    package: &'a str,
+    overridden_by: &'a str,
+}
+
+// A file or a directory with the sum of the files below it.
+#[derive(Serialize)]
+struct Entry {
+    path: String,
+    file_count: usize,
+    size: u64,
+    uncompressed_size: u64,
+}
+
+impl Entry {
+    #[inline]
+    fn add(&mut self, record: &Record) {
+        self.file_count += 1;
+        self.size += record.size();
+        self.uncompressed_size += record.uncompressed_size();
+    }
+}
+
+// Entries in the order in which they first appear in records. Directories
+// get a trailing slash.
+fn collect_entries(records: &[impl AsRef<Record>], only_dirs: bool, depth: Option<usize>) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut dirs: HashMap<String, usize> = HashMap::new();
+
+    let mut add_dir = |entries: &mut Vec<Entry>, components: &[&str], record: &Record| {
+        let mut path = components.join("/");
+        path.push('/');
+        let index = *dirs.entry(path).or_insert_with_key(|path| {
+            entries.push(Entry { path: path.clone(), file_count: 0, size: 0, uncompressed_size: 0 });
+            entries.len() - 1
+        });
+        entries[index].add(record);
+    };
+
+    for record in records {
+        let record = record.as_ref();
+        let components: Vec<&str> = record.filename().split('/')
+            .filter(|component| !component.is_empty())
+            .collect();
+        let dir_count = components.len().saturating_sub(1);
+
+        if only_dirs {
+            let dir_count = depth.map_or(dir_count, |depth| dir_count.min(depth));
+            for level in 1..=dir_count {
+                add_dir(&mut entries, &components[..level], record);
+            }
+        } else if let Some(depth) = depth.filter(|&depth| depth < components.len()) {
+            add_dir(&mut entries, &components[..depth], record);
+        } else {
+            let mut entry = Entry { path: record.filename().to_string(), file_count: 0, size: 0, uncompressed_size: 0 };
+            entry.add(record);
+            entries.push(entry);
+        }
+    }
+
+    entries
+}
+
+fn list_entries(records: &[impl AsRef<Record>], options: ListOptions) -> Result<()> {
+    let entries = collect_entries(records, options.only_dirs, options.depth);
+
+    match options.style {
+        ListStyle::Table { human_readable, no_header, .. } => {
+            let fmt_size = if human_readable {
+                |size: u64| format_size(size)
+            } else {
+                |size: u64| format!("{}", size)
+            };
+
+            let body: Vec<Vec<String>> = entries.iter().map(|entry| vec![
+                fmt_size(entry.uncompressed_size),
+                fmt_size(entry.size),
+                format!("{}", entry.file_count),
+                entry.path.clone(),
+            ]).collect();
+
+            let align = [Right, Right, Right, Left];
+            if no_header {
+                print_headless_table(&body, &align);
+            } else {
+                print_table(&["Size", "Compr.", "Files", "Path"], &align, &body);
+            }
+        }
+        ListStyle::OnlyNames { null_separated } => {
+            let sep = [if null_separated { 0 } else { b'\n' }];
+            let mut stdout = std::io::stdout();
+            for entry in &entries {
+                stdout.write_all(entry.path.as_bytes())?;
+                stdout.write_all(&sep)?;
+            }
+        }
+        ListStyle::Json => {
+            println!("{}", serde_json::to_string(&Document::new(Entries { entries }))?);
         }
     }
 
@@ -92,8 +309,12 @@ pub fn list(pak: Pak, options: ListOptions) -> Result<()> {
 }
 
 fn list_records(version: u32, records: &[impl AsRef<Record>], options: ListOptions) -> Result<()> {
+    if options.only_dirs || options.depth.is_some() {
+        return list_entries(records, options);
+    }
+
     match options.style {
-        ListStyle::Table { human_readable, no_header } => {
+        ListStyle::Table { human_readable, no_header, ratio, blocks } => {
             let mut body: Vec<Vec<String>> = Vec::new();
 
             let fmt_size = if human_readable {
@@ -108,9 +329,22 @@ fn list_records(version: u32, records: &[impl AsRef<Record>], options: ListOptio
                     format!("{}", record.offset()),
                     fmt_size(record.uncompressed_size()),
                     fmt_size(record.size()),
-                    compression_method_name(record.compression_method()).to_owned(),
-                    fmt_size(record.compression_block_size() as u64),
                 ];
+                if ratio {
+                    row.push(if record.uncompressed_size() == 0 {
+                        "-".to_string()
+                    } else {
+                        format!("{:.3}", record.size() as f64 / record.uncompressed_size() as f64)
+                    });
+                }
+                row.push(compression_method_name(record.compression_method()).to_owned());
+                row.push(fmt_size(record.compression_block_size() as u64));
+                if blocks {
+                    row.push(match record.compression_blocks() {
+                        Some(blocks) => format!("{}", blocks.len()),
+                        None => "-".to_string(),
+                    });
+                }
                 if version == 1 {
                     if let Some(timestamp) = record.timestamp() {
                         if let Some(timestamp) = NaiveDateTime::from_timestamp_opt(timestamp as i64, 0) {
@@ -129,39 +363,32 @@ fn list_records(version: u32, records: &[impl AsRef<Record>], options: ListOptio
                 body.push(row);
             }
 
+            let mut header = vec!["Offset", "Size", "Compr."];
+            let mut align = vec![Right, Right, Right];
+            if ratio {
+                header.push("Ratio");
+                align.push(Right);
+            }
+            header.extend_from_slice(&["Method", "Block-Size"]);
+            align.extend([Left, Right]);
+            if blocks {
+                header.push("Blocks");
+                align.push(Right);
+            }
             if version == 1 {
-                let align = [Right, Right, Right, Left, Right, Left, Left, Left];
-                if no_header {
-                    print_headless_table(&body, &align);
-                } else {
-                    print_table(
-                        &["Offset", "Size", "Compr.", "Method", "Block-Size", "Timestamp", "SHA-1", "Filename"],
-                        &align,
-                        &body,
-                    );
-                }
+                header.push("Timestamp");
+                align.push(Left);
             } else if version >= 3 {
-                let align = [Right, Right, Right, Left, Right, Left, Left, Left];
-                if no_header {
-                    print_headless_table(&body, &align);
-                } else {
-                    print_table(
-                        &["Offset", "Size", "Compr.", "Method", "Block-Size", "Encrypted", "SHA-1", "Filename"],
-                        &align,
-                        &body,
-                    );
-                }
+                header.push("Encrypted");
+                align.push(Left);
+            }
+            header.extend_from_slice(&["SHA-1", "Filename"]);
+            align.extend([Left, Left]);
+
+            if no_header {
+                print_headless_table(&body, &align);
             } else {
-                let align = [Right, Right, Right, Left, Right, Left, Left];
-                if no_header {
-                    print_headless_table(&body, &align);
-                } else {
-                    print_table(
-                        &["Offset", "Size", "Compr.", "Method", "Block-Size", "SHA-1", "Filename"],
-                        &align,
-                        &body,
-                    );
-                }
+                print_table(&header, &align, &body);
             }
         }
         ListStyle::OnlyNames { null_separated } => {
@@ -172,6 +399,22 @@ fn list_records(version: u32, records: &[impl AsRef<Record>], options: ListOptio
                 stdout.write_all(&sep)?;
             }
         }
+        ListStyle::Json => {
+            let files: Vec<&Record> = records.iter().map(AsRef::as_ref).collect();
+            println!("{}", serde_json::to_string(&Document::new(Files { files }))?);
+        }
+    }
+
+    Ok(())
+}
+
+// Names in index order, without decoding the whole index first.
+pub fn list_names_lazy(index: &LazyIndex, null_separated: bool) -> Result<()> {
+    let sep = [if null_separated { 0 } else { b'\n' }];
+    let mut stdout = std::io::stdout();
+    for filename in index.filenames() {
+        stdout.write_all(filename?.as_bytes())?;
+        stdout.write_all(&sep)?;
     }
 
     Ok(())
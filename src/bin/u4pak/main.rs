@@ -8,59 +8,323 @@ use clap::{App, AppSettings, Arg, ArgMatches, SubCommand, crate_version, crate_a
 use terminal_size::{terminal_size, Width};
 
 use env_logger::Env;
+use log::{debug, warn};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter, Write};
 use std::{
+    collections::HashSet,
     convert::TryInto,
     io::stderr,
     num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::Path,
+    sync::Arc,
+    time::UNIX_EPOCH,
 };
 
 #[cfg(target_family = "windows")]
 use std::convert::TryFrom;
 
-use u4pak::check::{check, CheckOptions};
-use u4pak::info::info;
-use u4pak::pack::{pack, PackOptions, PackPath};
-use u4pak::pak::{Options, COMPR_NONE, COMPR_ZLIB};
-use u4pak::unpack::{unpack, UnpackOptions};
-use u4pak::util::{parse_compression_level, parse_size};
-use u4pak::{Error, Pak, Result, Variant};
+use u4pak::archive::{unpack_to_archive, ArchiveFormat, ArchiveOptions};
+use u4pak::bench::{bench_compress, BenchOptions};
+use u4pak::check::{analyze_layout, check_with_report, CheckErrorKind, CheckOptions, CheckReport};
+use u4pak::decrypt::{default_cipher, parse_cipher, Cipher};
+use u4pak::dedupe::find_duplicates;
+use u4pak::entry::read_record_range;
+use u4pak::grep::{grep, GrepOptions, Pattern as GrepPattern};
+use u4pak::hash::{hash, DigestAlgorithm, HashOptions};
+use u4pak::index::{read_raw_index, IndexTransform, LazyIndex};
+use u4pak::hints::game_hints;
+use u4pak::info::{info, info_by_extension, info_game_hints, info_json, info_lazy, info_lazy_json, info_merged, info_toc};
+use u4pak::keycheck::{check_keys, search_keys, KeyCheck};
+use u4pak::keyring::{parse_guid, parse_key};
+use u4pak::locate::{locate, Location, RecordPart};
+use u4pak::pread::{IoStrategy, DEFAULT_READ_SIZE};
+use u4pak::tune::tune;
+use u4pak::manifest::{read_index_template, read_manifest, write_index_template};
+use u4pak::pack::{normalize_ext, pack, pack_iostore, PackOptions, PackPath, DEFAULT_NO_COMPRESS_EXTS};
+use u4pak::pak::{HexDisplay, Options, COMPR_NONE, COMPR_ZLIB, DEFAULT_FOOTER_SCAN_SIZE, PAK_MAGIC};
+use u4pak::patch::patch;
+use u4pak::portable::{NamePolicy, NORMALIZE_NFC_DEFAULT};
+use u4pak::predicate::{CompareOp, Field, Predicate};
+use u4pak::progress::ProgressCallback;
+use u4pak::ranges::byte_ranges;
+use u4pak::rename::{rename, set_mount_point};
+use u4pak::repair::repair;
+use u4pak::salvage::{salvage, write_rebuilt_pak, SalvageOptions};
+use u4pak::slack::{copy_slack, find_slack};
+use u4pak::stream::copy_raw_index;
+use u4pak::sig::{check_signature, read_private_key, read_public_key, sign, signature_path};
+use u4pak::range_reader::{HttpTransport, RangeReader};
+use u4pak::unpack::{unpack, unpack_from_reader, unpack_from_stream, unpack_toc, unpack_with_report, Overwrite, UnpackOptions, UnpackReport};
+use u4pak::schedule::WorkOrder;
+use u4pak::serialize::Document;
+use u4pak::util::{format_size, parse_compression_level, parse_size, print_table, write_hexdump, Align};
+use u4pak::verify::{verify, VerifyOptions};
+use u4pak::{Error, ErrorKind, Filter, Keyring, MergedView, Pak, Result, Toc, Variant, Warnings};
 
 pub mod sort;
 use sort::parse_order;
 
 mod list;
-use list::{list, ListOptions, ListStyle};
+use list::{list, list_names_lazy, list_shadowed, ListOptions, ListStyle};
+
+mod tree;
+use tree::{tree, TreeOptions};
+
+mod du;
+use du::{du, DuOptions};
+
+mod serve;
+use serve::{serve, ServeOptions};
 
 pub mod args;
+use args::read_path_list;
+
 pub mod io;
 
-#[cfg(target_os = "linux")]
-pub use u4pak::mount::{mount, MountOptions};
+mod progress;
+use progress::{JsonProgress, ProgressBar, ProgressOutput, Summary};
+
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+pub use u4pak::mount::{mount, MountOptions, DEFAULT_ENTRY_CACHE_SIZE};
+
+fn get_paths(args: &clap::ArgMatches) -> Result<Option<Vec<String>>> {
+    let mut paths = Vec::new();
 
-fn get_paths<'a>(args: &'a clap::ArgMatches) -> Result<Option<Vec<&'a str>>> {
     if let Some(arg_paths) = args.values_of("paths") {
-        let count = arg_paths.len();
-        if count == 0 {
-            Ok(None)
-        } else {
-            let mut paths: Vec<&str> = Vec::with_capacity(count);
-            for path in arg_paths {
-                if path.is_empty() {
-                    return Err(Error::new(
-                        "Path may not be empty. Use \"/\" to reference the root directory of a pak archive."
-                        .to_string()));
-                }
-                paths.push(path);
+        for path in arg_paths {
+            if path.is_empty() {
+                return Err(Error::new(
+                    "Path may not be empty. Use \"/\" to reference the root directory of a pak archive."
+                    .to_string()));
             }
-            Ok(Some(paths))
+            paths.push(path.to_string());
+        }
+    }
+
+    if let Some(files_from) = args.value_of("files-from") {
+        paths.append(&mut read_path_list(files_from)?);
+    }
+
+    // an empty --files-from list selects no files, not all of them
+    if paths.is_empty() && !args.is_present("files-from") {
+        Ok(None)
+    } else {
+        Ok(Some(paths))
+    }
+}
+
+fn get_predicate(args: &clap::ArgMatches) -> Result<Option<Predicate>> {
+    let mut items = Vec::new();
+
+    if let Some(exprs) = args.values_of("where") {
+        for expr in exprs {
+            items.push(Predicate::parse(expr)?);
         }
+    }
+
+    if let Some(size) = args.value_of("min-size") {
+        items.push(Predicate::Compare(Field::UncompressedSize, CompareOp::Ge, parse_size(size)? as u64));
+    }
+
+    if let Some(size) = args.value_of("max-size") {
+        items.push(Predicate::Compare(Field::UncompressedSize, CompareOp::Le, parse_size(size)? as u64));
+    }
+
+    if args.is_present("encrypted-only") {
+        items.push(Predicate::Compare(Field::Encrypted, CompareOp::Ne, 0));
+    }
+
+    Ok(match items.len() {
+        0 => None,
+        1 => items.pop(),
+        _ => Some(Predicate::And(items)),
+    })
+}
+
+#[inline]
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+// IoStore containers are detected by the extension of the .utoc file
+#[inline]
+fn is_toc(path: &str) -> bool {
+    matches!(Path::new(path).extension(), Some(ext) if ext.eq_ignore_ascii_case("utoc"))
+}
+
+// Further packages on the command line of list are detected by their extension.
+#[inline]
+fn is_package_path(path: &str) -> bool {
+    matches!(Path::new(path).extension(), Some(ext) if ext.eq_ignore_ascii_case("pak") || ext.eq_ignore_ascii_case("utoc"))
+}
+
+// PACKAGE argument of list and unpack for reading the package from stdin
+const STDIN_PATH: &str = "-";
+
+// keys from the environment don't show up in the shell history or process list
+const ENCRYPTION_KEY_ENV: &str = "U4PAK_AES_KEY";
+const CRYPTO_JSON_ENV: &str = "U4PAK_CRYPTO_JSON";
+
+// The package read from stdin needs its index from --index-file, stdin itself
+// can't seek to it.
+fn open_index_file(args: &clap::ArgMatches, options: Options) -> Result<Pak> {
+    let index_path = match args.value_of("index-file") {
+        Some(index_path) => index_path,
+        None => return Err(Error::new(
+            "--index-file is needed to read a package from stdin, see export-index --raw".to_string())
+            .with_kind(ErrorKind::InvalidArgument)),
+    };
+    let mut file = match File::open(index_path) {
+        Ok(file) => file,
+        Err(error) => return Err(Error::io_with_path(error, index_path)),
+    };
+    Pak::from_index_reader(&mut BufReader::new(&mut file), options)
+        .map_err(|error| error.with_path_if_none(index_path))
+}
+
+// Opens a pak, a .utoc (as a pak that can only be listed) or a pak URL.
+fn open_package(path: &str, options: Options) -> Result<Pak> {
+    if is_url(path) {
+        Ok(open_url(path, options)?.0)
+    } else if is_toc(path) {
+        Ok(Toc::from_path(path, options.encryption_key)?.to_pak())
+    } else {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) => return Err(Error::io_with_path(error, path)),
+        };
+        Pak::from_reader(&mut BufReader::new(&mut file), options)
+            .map_err(|error| error.with_path_if_none(path))
+    }
+}
+
+// Exit codes, see README.adoc. Scripts may depend on them, so don't change
+// existing ones.
+const EXIT_FAILURE: i32         = 1; // other errors, nothing found by grep/locate or no key works for keycheck
+const EXIT_USAGE: i32           = 2;
+const EXIT_UNSUPPORTED: i32     = 3;
+const EXIT_CORRUPT: i32         = 4;
+const EXIT_IO: i32              = 5;
+const EXIT_NO_KEY: i32          = 6;
+const EXIT_PATH_NOT_FOUND: i32  = 7;
+const EXIT_UNSAFE_PATH: i32     = 8;
+// check and verify exit with EXIT_ERRORS_BASE plus the number of errors
+// (differences), at most EXIT_ERRORS_MAX
+const EXIT_ERRORS_BASE: i32     = 100;
+const EXIT_ERRORS_MAX: i32      = 127;
+
+fn exit_code(error: &Error) -> i32 {
+    match error.kind() {
+        ErrorKind::InvalidArgument => EXIT_USAGE,
+        ErrorKind::UnsupportedVersion |
+        ErrorKind::InvalidMagic |
+        ErrorKind::UnknownCompressionMethod => EXIT_UNSUPPORTED,
+        ErrorKind::CorruptIndex |
+        ErrorKind::DecompressionFailed => EXIT_CORRUPT,
+        ErrorKind::Io => EXIT_IO,
+        ErrorKind::EncryptedNoKey => EXIT_NO_KEY,
+        ErrorKind::PathNotFound => EXIT_PATH_NOT_FOUND,
+        ErrorKind::UnsafePath => EXIT_UNSAFE_PATH,
+        ErrorKind::Other |
+        ErrorKind::ChannelDisconnected => EXIT_FAILURE,
+    }
+}
+
+#[inline]
+fn error_count_exit_code(count: usize) -> i32 {
+    std::cmp::min(EXIT_ERRORS_BASE as usize + count, EXIT_ERRORS_MAX as usize) as i32
+}
+
+fn open_url(url: &str, options: Options) -> Result<(Pak, RangeReader<HttpTransport>)> {
+    let mut reader = RangeReader::new(HttpTransport::new(url)?)?;
+    let pak = Pak::from_reader(&mut reader, options)
+        .map_err(|error| error.with_path_if_none(url))?;
+    Ok((pak, reader))
+}
+
+fn get_magic(args: &clap::ArgMatches) -> Result<u32> {
+    let magic = if let Some(magic) = args.value_of("magic") { magic.trim() } else { return Ok(PAK_MAGIC) };
+    let result = if let Some(hex) = magic.strip_prefix("0x").or_else(|| magic.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        magic.parse()
+    };
+    result.map_err(|error| Error::new(format!("illegal --magic {:?}: {}", magic, error)).with_kind(ErrorKind::InvalidArgument))
+}
+
+// decimal or hexadecimal with 0x prefix
+fn parse_offset(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let result = if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else {
+        value.parse()
+    };
+    result.map_err(|error| Error::new(format!("illegal offset {:?}: {}", value, error)).with_kind(ErrorKind::InvalidArgument))
+}
+
+fn get_index_transform(args: &clap::ArgMatches) -> Result<Option<IndexTransform>> {
+    let key = if let Some(key) = args.value_of("index-xor") { key.trim() } else { return Ok(None) };
+    let result = if let Some(hex) = key.strip_prefix("0x").or_else(|| key.strip_prefix("0X")) {
+        u8::from_str_radix(hex, 16)
+    } else {
+        key.parse()
+    };
+    let key = result.map_err(|error| Error::new(format!("illegal --index-xor {:?}: {}", key, error)).with_kind(ErrorKind::InvalidArgument))?;
+    Ok(Some(IndexTransform::xor(key)))
+}
+
+fn get_name_list(args: &clap::ArgMatches) -> Result<Option<Vec<String>>> {
+    if let Some(path) = args.value_of("name-list") {
+        Ok(Some(read_path_list(path)?))
     } else {
         Ok(None)
     }
 }
 
+fn get_progress_output(matches: &clap::ArgMatches, args: &clap::ArgMatches) -> Result<Option<ProgressOutput>> {
+    let command = matches.subcommand_name().unwrap_or_default();
+    let format = args.value_of("progress");
+    if let Some(path) = args.value_of("progress-file") {
+        if format == Some("bar") {
+            return Err(Error::new("--progress-file can only be used with --progress=json".to_string())
+                .with_kind(ErrorKind::InvalidArgument));
+        }
+        // also works for named pipes, but blocks until the reading end is opened
+        let file = File::create(path).map_err(|error| Error::io_with_path(error, path))?;
+        return Ok(Some(ProgressOutput::Json(JsonProgress::new(Box::new(file), command))));
+    }
+
+    Ok(match format {
+        Some("json") => Some(ProgressOutput::Json(JsonProgress::new(Box::new(std::io::stderr()), command))),
+        _ if args.is_present("progress") => Some(ProgressOutput::Bar(ProgressBar::new())),
+        _ => None,
+    })
+}
+
+// global arguments may be given before or after the sub-command
+fn is_global_present(matches: &clap::ArgMatches, name: &str) -> bool {
+    matches.is_present(name) || matches.subcommand().1.is_some_and(|args| args.is_present(name))
+}
+
+// the summary event of --progress=json replaces the summary line
+fn get_summary<'a>(matches: &clap::ArgMatches, progress_output: &'a Option<ProgressOutput>) -> Option<Summary<'a>> {
+    if is_global_present(matches, "quiet") || progress_output.as_ref().is_some_and(ProgressOutput::is_json) {
+        None
+    } else {
+        Some(Summary::new(progress_output.as_ref()))
+    }
+}
+
+fn get_progress_callback<'a>(summary: &'a Option<Summary>, progress_output: &'a Option<ProgressOutput>) -> Option<&'a dyn ProgressCallback> {
+    if let Some(summary) = summary {
+        Some(summary)
+    } else {
+        progress_output.as_ref().map(|output| output as &dyn ProgressCallback)
+    }
+}
+
 fn get_threads(args: &clap::ArgMatches) -> Result<NonZeroUsize> {
     let threads = if let Some(threads) = args.value_of("threads") {
         if threads.eq_ignore_ascii_case("auto") {
@@ -79,6 +343,167 @@ fn get_threads(args: &clap::ArgMatches) -> Result<NonZeroUsize> {
     Ok(threads.unwrap_or_else(|| NonZeroUsize::new(1).unwrap()))
 }
 
+fn print_key_check(label: &str, result: &KeyCheck) {
+    if result.works() {
+        let mut reasons = Vec::new();
+        if result.sha1_matches {
+            reasons.push("index SHA-1 matches".to_string());
+        }
+        if let Some(mount_point) = &result.mount_point {
+            reasons.push(format!("mount point {:?}", mount_point));
+        }
+        println!("{}: works ({})", label, reasons.join(", "));
+    } else {
+        println!("{}: doesn't work", label);
+    }
+}
+
+fn get_cipher(args: &clap::ArgMatches) -> Result<Arc<dyn Cipher>> {
+    if let Some(value) = args.value_of("cipher") {
+        parse_cipher(value)
+    } else {
+        Ok(default_cipher())
+    }
+}
+
+fn get_keyring(args: &clap::ArgMatches) -> Result<Keyring> {
+    let mut keyring = if let Some(path) = args.value_of("keyring") {
+        Keyring::from_path(path)?
+    } else {
+        Keyring::new()
+    };
+
+    if let Some(path) = args.value_of("crypto-json") {
+        keyring.merge(Keyring::from_crypto_json_path(path)?);
+    }
+
+    Ok(keyring)
+}
+
+fn check_encrypt_key(encrypted: bool, encryption_key: &Option<Vec<u8>>) -> Result<()> {
+    if encrypted && encryption_key.is_none() {
+        return Err(Error::new(format!(
+            "--encrypt needs a key: --encryption-key, ${} or --crypto-json", ENCRYPTION_KEY_ENV))
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+    Ok(())
+}
+
+// --encryption-key (or $U4PAK_AES_KEY), otherwise the key for the GUID 0 of
+// the keyring, e.g. the EncryptionKey of --crypto-json.
+fn get_default_key(args: &clap::ArgMatches, keyring: &Keyring) -> Result<Option<Vec<u8>>> {
+    if let Some(key) = args.value_of("encryption-key") {
+        Ok(Some(base64::decode(key).map_err(|error|
+            Error::new(format!("illegal --encryption-key: {}", error)).with_kind(ErrorKind::InvalidArgument))?))
+    } else {
+        Ok(keyring.get(0).cloned())
+    }
+}
+
+// --encryption-key (or $U4PAK_AES_KEY), or the key of the --keyring (or
+// --crypto-json) that matches the encryption GUID in the footer of the
+// package. If the footer can't be read this is left to the actual reading of
+// the package to report.
+fn get_encryption_key(args: &clap::ArgMatches, path: &str) -> Result<Option<Vec<u8>>> {
+    let keyring = get_keyring(args)?;
+    let encryption_key = get_default_key(args, &keyring)?;
+    if keyring.is_empty() || is_url(path) || is_toc(path) {
+        return Ok(encryption_key);
+    }
+
+    let force_version = if let Some(version) = args.value_of("force-version") {
+        Some(version.parse()?)
+    } else {
+        None
+    };
+
+    let options = Options {
+        ignore_magic: args.is_present("ignore-magic"),
+        magic: get_magic(args)?,
+        force_version,
+        encryption_key,
+        keyring,
+        scan_footer: get_scan_footer(args)?,
+        ..Options::default()
+    };
+
+    // the footer of a package read from stdin is in the --index-file
+    if path == STDIN_PATH {
+        let index_path = match args.value_of("index-file") {
+            Some(index_path) => index_path,
+            None => return Ok(options.encryption_key),
+        };
+        let mut file = File::open(index_path).map_err(|error| Error::io_with_path(error, index_path))?;
+        return match Pak::read_index_footer(&mut BufReader::new(&mut file), &options) {
+            Ok(footer) => Ok(options.encryption_key_for(&footer).cloned()),
+            Err(_) => Ok(options.encryption_key),
+        };
+    }
+
+    let mut file = File::open(path).map_err(|error| Error::io_with_path(error, path))?;
+    match Pak::read_footer(&mut BufReader::new(&mut file), &options) {
+        Ok(footer) => Ok(options.encryption_key_for(&footer).cloned()),
+        Err(_) => Ok(options.encryption_key),
+    }
+}
+
+fn get_scan_footer(args: &clap::ArgMatches) -> Result<Option<u64>> {
+    if !args.is_present("scan-footer") {
+        return Ok(None);
+    }
+    if let Some(value) = args.value_of("scan-footer") {
+        Ok(Some(parse_size(value)? as u64))
+    } else {
+        Ok(Some(DEFAULT_FOOTER_SCAN_SIZE))
+    }
+}
+
+fn get_io_strategy(args: &clap::ArgMatches) -> Result<(IoStrategy, NonZeroUsize)> {
+    let strategy = if let Some(value) = args.value_of("io") {
+        value.try_into()?
+    } else {
+        IoStrategy::default()
+    };
+
+    let read_size = if let Some(value) = args.value_of("read-size") {
+        NonZeroUsize::new(parse_size(value)?).ok_or_else(||
+            Error::new("read size may not be 0".to_string()).with_kind(ErrorKind::InvalidArgument))?
+    } else {
+        DEFAULT_READ_SIZE
+    };
+
+    Ok((strategy, read_size))
+}
+
+// For unpack and check --threads auto probes the storage the package is on
+// and picks the thread count and, unless given, the read size for it.
+fn get_tuned_io(args: &clap::ArgMatches, path: &str) -> Result<(NonZeroUsize, IoStrategy, NonZeroUsize)> {
+    let thread_count = get_threads(args)?;
+    let (strategy, read_size) = get_io_strategy(args)?;
+    if !args.value_of("threads").unwrap_or("auto").eq_ignore_ascii_case("auto") {
+        return Ok((thread_count, strategy, read_size));
+    }
+
+    let tuning = tune(path)?;
+    debug!("{} looks like it is on {} storage, using {} threads and {} byte reads",
+        path, tuning.storage.name(), tuning.thread_count, tuning.read_size);
+
+    let read_size = if args.is_present("read-size") { read_size } else { tuning.read_size };
+    Ok((tuning.thread_count, strategy, read_size))
+}
+
+fn get_max_memory(args: &clap::ArgMatches) -> Result<Option<NonZeroU64>> {
+    if let Some(value) = args.value_of("max-memory") {
+        let size = parse_size(value)? as u64;
+        if size == 0 {
+            return Err(Error::new("memory limit may not be 0".to_string()).with_kind(ErrorKind::InvalidArgument));
+        }
+        Ok(NonZeroU64::new(size))
+    } else {
+        Ok(None)
+    }
+}
+
 pub fn parse_compression_method(value: &str) -> Result<u32> {
     if value.eq_ignore_ascii_case("none") {
         Ok(COMPR_NONE)
@@ -108,6 +533,23 @@ fn arg_package<'a, 'b>() -> Arg<'a, 'b> {
         .help("An Unreal Engine 4 pak file")
 }
 
+fn arg_index_file<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("index-file")
+        .long("index-file")
+        .takes_value(true)
+        .value_name("FILE")
+        .help(
+            "Read the index of a package that is read from stdin (PACKAGE is -) from FILE. \
+            FILE is the end of the package starting at the index, as written by \
+            export-index --raw.")
+}
+
+fn arg_package_or_url<'a, 'b>() -> Arg<'a, 'b> {
+    arg_package()
+        .help("An Unreal Engine 4 pak file, a .utoc file of an IoStore container or a http:// URL of a pak file, \
+               which is then read using HTTP range requests")
+}
+
 fn arg_paths<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name("paths")
         .index(2)
@@ -116,6 +558,40 @@ fn arg_paths<'a, 'b>() -> Arg<'a, 'b> {
         .help("If given, only consider these files from the package.")
 }
 
+fn arg_files_from<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("files-from")
+        .long("files-from")
+        .short("@")
+        .takes_value(true)
+        .value_name("FILE")
+        .help(
+            "Read additional paths from FILE, one path per line. Use - to read from stdin. \
+            If the list contains NULL bytes the paths are NULL separated instead, \
+            e.g. for use with find -print0. An empty list selects no files.")
+}
+
+fn arg_progress<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("progress")
+        .long("progress")
+        .takes_value(true)
+        .min_values(0)
+        .require_equals(true)
+        .value_name("FORMAT")
+        .possible_values(&["bar", "json"])
+        .help(
+            "Show a progress bar on stderr. With --progress=json write newline-delimited \
+            JSON events (start, file, error and summary) to stderr instead, for programs \
+            that run u4pak.")
+}
+
+fn arg_progress_file<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("progress-file")
+        .long("progress-file")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("Write the events of --progress=json to FILE (e.g. a named pipe) instead of stderr. Implies --progress=json.")
+}
+
 fn arg_verbose<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name("verbose")
         .long("verbose")
@@ -133,6 +609,45 @@ fn arg_variant<'a, 'b>() -> Arg<'a, 'b> {
         .help("Pak variant: 'standard' or 'conan_exiles'.")
 }
 
+fn arg_no_detect_variant<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no-detect-variant")
+        .long("no-detect-variant")
+        .takes_value(false)
+        .help("Always use the variant given by --variant. By default another variant is used if \
+               the records of a version < 10 index don't fit the given one.")
+}
+
+fn arg_format<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .value_name("FORMAT")
+        .possible_values(&["table", "json"])
+        .help("Output format, table by default. The JSON output starts with a schema_version field, which only changes \
+               when fields are removed or change their meaning (see README.adoc).")
+}
+
+fn arg_no_detect_block_offsets<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no-detect-block-offsets")
+        .long("no-detect-block-offsets")
+        .takes_value(false)
+        .help("Always take the compression block offsets of version 3 to 7 packages as the version implies \
+               (absolute before version 5, relative to the file since). By default the data is checked \
+               for which one is used.")
+}
+
+fn arg_scan_footer<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("scan-footer")
+        .long("scan-footer")
+        .takes_value(true)
+        .min_values(0)
+        .require_equals(true)
+        .value_name("SIZE")
+        .help("If no footer is found at the end of the package (e.g. because something was appended \
+               to it), search the last SIZE bytes backwards for one. Combined with --force-version \
+               only footers of that version are considered. [default SIZE: 16M]")
+}
+
 fn arg_ignore_magic<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name("ignore-magic")
         .long("ignore-magic")
@@ -140,6 +655,41 @@ fn arg_ignore_magic<'a, 'b>() -> Arg<'a, 'b> {
         .help("Ignore file magic.")
 }
 
+fn arg_magic<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("magic")
+        .long("magic")
+        .takes_value(true)
+        .value_name("MAGIC")
+        .help("File magic used by the package, for games that use a non-standard one. \
+               Decimal or hexadecimal with 0x prefix. [default: 0x5A6F12E1]")
+}
+
+fn arg_index_xor<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("index-xor")
+        .long("index-xor")
+        .takes_value(true)
+        .value_name("KEY")
+        .help("XOR the (decrypted) index with the byte KEY before reading it, for games that \
+               obfuscate their index that way. Decimal or hexadecimal with 0x prefix.")
+}
+
+fn arg_name_list<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("name-list")
+        .long("name-list")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("Recover file names of paks that only have a path hash index by hashing the names \
+               listed in FILE (one per line, relative to the mount point). Pass - to read from stdin.")
+}
+
+fn arg_harvest_names<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("harvest-names")
+        .long("harvest-names")
+        .takes_value(false)
+        .help("Recover file names of paks that only have a path hash index by guessing them from \
+               the package paths referenced in the .uasset and .umap files of the pak.")
+}
+
 fn arg_encoding<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name("encoding")
         .long("encoding")
@@ -159,10 +709,58 @@ fn arg_threads<'a, 'b>() -> Arg<'a, 'b> {
         .value_name("COUNT")
         .help(
             "Number of threads to use for the operation. \
-            'auto' means use the number of logical cores on your computer.",
+            'auto' means use the number of logical cores on your computer. For unpack \
+            and check 'auto' probes whether the package is on an SSD, HDD or network \
+            mount and picks the thread count and read size for that.",
+        )
+}
+
+fn arg_max_memory<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("max-memory")
+        .long("max-memory")
+        .takes_value(true)
+        .value_name("SIZE")
+        .help(
+            "Limit the memory used for file data read by all threads together to about SIZE. \
+            Files bigger than that are processed one at a time. [default: no limit]",
+        )
+}
+
+fn arg_io<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("io")
+        .long("io")
+        .takes_value(true)
+        .value_name("STRATEGY")
+        .possible_values(&["pread", "buffered"])
+        .help(
+            "How the threads read the package. 'pread' uses positional reads and keeps \
+            its buffer when seeking, 'buffered' seeks and reads through a buffer. \
+            Platforms without positional reads always use 'buffered'. [default: pread]",
+        )
+}
+
+fn arg_order<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("order")
+        .long("order")
+        .takes_value(true)
+        .value_name("ORDER")
+        .possible_values(&["index", "offset", "size"])
+        .default_value("index")
+        .help(
+            "Order in which the files are handed to the threads. 'offset' reads the package \
+            front to back, which is a lot faster on HDDs and network file systems. 'size' \
+            starts with the biggest files, so no thread is left with a giant file at the end.",
         )
 }
 
+fn arg_read_size<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("read-size")
+        .long("read-size")
+        .takes_value(true)
+        .value_name("SIZE")
+        .help("Size of the reads of each thread. Bigger reads go directly to the file. [default: 256K]")
+}
+
 fn arg_force_version<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name("force-version")
         .long("force-version")
@@ -197,7 +795,80 @@ fn arg_encryption_key<'a, 'b>() -> Arg<'a, 'b> {
         .short("k")
         .takes_value(true)
         .value_name("ENCRYPTION_KEY")
-        .help("Base64 encoded 16 byte AES encryption key")
+        .env(ENCRYPTION_KEY_ENV)
+        .hide_env_values(true)
+        .help("Base64 encoded 16 byte AES encryption key. Set the environment variable instead \
+               so the key doesn't show up in the shell history or the process list.")
+}
+
+fn arg_crypto_json<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("crypto-json")
+        .long("crypto-json")
+        .takes_value(true)
+        .value_name("FILE")
+        .env(CRYPTO_JSON_ENV)
+        .help("Crypto.json of an Unreal Engine project with the AES keys: EncryptionKey is used \
+               like --encryption-key, SecondaryEncryptionKeys like the keys of --keyring.")
+}
+
+fn arg_keyring<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("keyring")
+        .long("keyring")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("File with one AES key per line for games that use several keys: the encryption GUID \
+               as printed by info, then the base64 encoded key. The key matching the GUID in the footer \
+               of the package is used instead of --encryption-key.")
+}
+
+fn arg_cipher<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("cipher")
+        .long("cipher")
+        .takes_value(true)
+        .value_name("CIPHER")
+        .default_value("ecb")
+        .help("How encrypted data is encrypted: ecb (AES-256-ECB, used by Unreal Engine), cbc \
+               (AES-256-CBC with an IV of zeros) or cbc:IV with the IV as 32 hex digits, for games \
+               that deviate from Unreal Engine.")
+}
+
+fn arg_where<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("where")
+        .long("where")
+        .short("w")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("EXPR")
+        .help(
+            "Only select files whose metadata matches EXPR. EXPR is a comma separated list of \
+            conditions that all have to match, like 'method=zlib' or 'size>=1M,!encrypted'. \
+            Fields: offset, size, uncompressed_size, method, timestamp, encrypted. \
+            Operators: =, !=, <, <=, >, >=. A plain field name means the field is not 0, \
+            ! in front of it means it is 0. Can be passed multiple times.")
+}
+
+fn arg_min_size<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("min-size")
+        .long("min-size")
+        .takes_value(true)
+        .value_name("SIZE")
+        .help("Only select files with an uncompressed size of at least SIZE. Same as --where='uncompressed_size>=SIZE'.")
+}
+
+fn arg_max_size<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("max-size")
+        .long("max-size")
+        .takes_value(true)
+        .value_name("SIZE")
+        .help("Only select files with an uncompressed size of at most SIZE. Same as --where='uncompressed_size<=SIZE'.")
+}
+
+fn arg_encrypted_only<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("encrypted-only")
+        .long("encrypted-only")
+        .takes_value(false)
+        .help("Only select encrypted files. Same as --where=encrypted.")
 }
 
 #[cfg(target_family = "windows")]
@@ -275,6 +946,11 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                 \t\":rename=/:C:\\Users\\Alice\\My Documents\\U4Project\\Some Files\"\n\
                 \t\":zlib,rename=/:Some Other Files\"\n\
                 \n\
+                ${NAME} in an argument is replaced by the value of the environment variable NAME, \
+                write $${ for a literal ${. The unquoted argument @include followed by the path of \
+                another .u4pak file inserts the arguments of that file, relative to the directory of \
+                the file that contains the @include.\n\
+                \n\
                 If u4pak.exe is run by double clicking or by dropping a .u4pak file on it it won't \
                 immediately close the terminal window, but will instead ask you to press ENTER. It does \
                 this so you have a chance to read the output. Since I don't use Windows (I cross compile \
@@ -282,12 +958,39 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                 please report a bug. In order to force the \"Press ENTER to continue...\" message you can \
                 pass the argument --pause-on-exit=always (Windows-only).\n\
                 \n\
+                If a .pak file is the only argument u4pak does what the environment variable \
+                U4PAK_DEFAULT_ACTION says: info, list, check, unpack (into a folder next to the package \
+                named like it without the extension) or prompt, which asks which of these to do. If it \
+                isn't set u4pak asks when it runs in its own terminal window (double click or drag and \
+                drop on Windows) and shows the info otherwise.\n\
+                \n\
                 Homepage: https://github.com/panzi/rust-u4pak\n\
                 Report issues to: https://github.com/panzi/rust-u4pak/issues")
         .version(crate_version!())
         .global_setting(AppSettings::VersionlessSubcommands)
         .author(crate_authors!());
 
+    let app = app
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .takes_value(true)
+            .global(true)
+            .value_name("LEVEL")
+            .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+            .help("Only log messages of LEVEL and above. [default: the RUST_LOG environment variable or warn]"))
+        .arg(Arg::with_name("quiet")
+            .long("quiet")
+            .short("q")
+            .takes_value(false)
+            .global(true)
+            .help("Don't print the summary line after pack and unpack."))
+        .arg(Arg::with_name("log-file")
+            .long("log-file")
+            .takes_value(true)
+            .global(true)
+            .value_name("FILE")
+            .help("Append log messages to FILE instead of writing them to stderr."));
+
     #[cfg(target_family = "windows")]
     let app = app.arg(
         Arg::with_name("pause-on-exit")
@@ -303,27 +1006,121 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
             .about("Show summarized information of a package")
             .arg(arg_variant())
             .arg(arg_human_readable())
+            .arg(arg_format())
+            .arg(Arg::with_name("by-extension")
+                .long("by-extension")
+                .takes_value(false)
+                .help(
+                    "Also print count, sizes and compression ratio of the files \
+                    grouped by file extension."))
+            .arg(Arg::with_name("game-hints")
+                .long("game-hints")
+                .takes_value(false)
+                .help(
+                    "Guess the engine version and game from the footer, mount point and file names, \
+                    and suggest the flags needed to read the package. Printed before the other \
+                    information, so it also works for packages that can't be read with the given flags."))
             .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
             .arg(arg_encoding())
             .arg(arg_force_version())
-            .arg(arg_package())
-            .arg(arg_encryption_key()))
-        .subcommand(SubCommand::with_name("list")
-            .alias("l")
-            .about("List content of a package")
+            .arg(arg_package_or_url()
+                .multiple(true)
+                .help("An Unreal Engine 4 pak file, a .utoc file of an IoStore container or a http:// URL of a pak file, \
+                       which is then read using HTTP range requests. If several packages are given the effective \
+                       files are summarized that the engine would see when mounting all of them, where _P paks \
+                       override other paks and later packages override earlier ones of the same priority."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("tree")
+            .about("Print content of a package as a directory tree")
             .arg(arg_variant())
-            .arg(Arg::with_name("only-names")
-                .long("only-names")
-                .short("n")
-                .takes_value(false)
-                .help(
-                    "Only print file names. \
-                    This is useful for use with xargs and the like."))
-            .arg(Arg::with_name("no-header")
-                .long("no-header")
-                .short("H")
+            .arg(arg_human_readable())
+            .arg(Arg::with_name("compressed")
+                .long("compressed")
+                .short("c")
                 .takes_value(false)
-                .conflicts_with("only-names")
+                .help("Also print compressed sizes (compressed / uncompressed)."))
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_package())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("du")
+            .about("Summarize sizes of the directories in a package, biggest first")
+            .arg(arg_variant())
+            .arg(arg_human_readable())
+            .arg(Arg::with_name("no-header")
+                .long("no-header")
+                .short("H")
+                .takes_value(false)
+                .help("Don't print table header"))
+            .arg(Arg::with_name("compressed")
+                .long("compressed")
+                .short("c")
+                .takes_value(false)
+                .help("Sort by compressed size instead of uncompressed size."))
+            .arg(Arg::with_name("max-depth")
+                .long("max-depth")
+                .short("d")
+                .takes_value(true)
+                .value_name("N")
+                .help("Only print directories that are at most N levels below PATH."))
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_package())
+            .arg(Arg::with_name("path")
+                .index(2)
+                .value_name("PATH")
+                .help("Only summarize this directory of the package."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("list")
+            .alias("l")
+            .about("List content of a package")
+            .arg(arg_variant())
+            .arg(arg_format()
+                .conflicts_with("only-names"))
+            .arg(Arg::with_name("only-names")
+                .long("only-names")
+                .short("n")
+                .takes_value(false)
+                .help(
+                    "Only print file names. \
+                    This is useful for use with xargs and the like."))
+            .arg(Arg::with_name("no-header")
+                .long("no-header")
+                .short("H")
+                .takes_value(false)
+                .conflicts_with("only-names")
                 .help("Don't print table header"))
             .arg(Arg::with_name("sort")
                 .long("sort")
@@ -341,20 +1138,77 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                     * b, compression-block-size - size of blocks a compressed file is split into\n\
                     * t, timestamp              - timestamp of a file (only in pak version 1)\n\
                     * e, encrypted              - whether the file is encrypted\n\
+                    * r, ratio                  - compressed size / uncompressed size\n\
+                    * x, extension              - the file name extension\n\
                     \n\
                     You can invert the sort order by prepending - to the key. E.g.:\n\
                     \n\
-                    u4pak list --sort=-size,-timestamp,name")
+                    u4pak list --sort=-size,-timestamp,name\n\
+                    u4pak list --sort=extension,-ratio")
             )
             .arg(arg_print0().requires("only-names"))
+            .arg(Arg::with_name("only-dirs")
+                .long("only-dirs")
+                .takes_value(false)
+                .conflicts_with("shadowed")
+                .help(
+                    "List the directories containing the listed files (with a trailing slash) \
+                    instead of the files, together with the number and size of the files below \
+                    each directory."))
+            .arg(Arg::with_name("depth")
+                .long("depth")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with("shadowed")
+                .help(
+                    "Only list entries at most N levels deep. Files that are deeper are listed \
+                    as their directory at that level, together with the number and size of the \
+                    files below it."))
+            .arg(Arg::with_name("ratio")
+                .long("ratio")
+                .takes_value(false)
+                .conflicts_with_all(&["only-names", "only-dirs", "depth", "shadowed"])
+                .help("Add a column with the compression ratio (compressed size / size) of each file."))
+            .arg(Arg::with_name("blocks")
+                .long("blocks")
+                .takes_value(false)
+                .conflicts_with_all(&["only-names", "only-dirs", "depth", "shadowed"])
+                .help("Add a column with the number of compression blocks of each file."))
             .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
             .arg(arg_encoding())
             .arg(arg_force_version())
             .arg(arg_human_readable())
             .arg(arg_threads())
-            .arg(arg_package())
+            .arg(arg_where())
+            .arg(arg_min_size())
+            .arg(arg_max_size())
+            .arg(arg_encrypted_only())
+            .arg(Arg::with_name("shadowed")
+                .long("shadowed")
+                .takes_value(false)
+                .help(
+                    "When listing several packages list the files that are overridden by \
+                    a package of higher priority instead of the effective files."))
+            .arg(arg_package_or_url()
+                .help("An Unreal Engine 4 pak file, a .utoc file of an IoStore container or a http:// URL of a pak file, \
+                       which is then read using HTTP range requests. Further packages can be given as the following \
+                       arguments ending in .pak or .utoc, in which case the effective files are listed that the engine \
+                       would see when mounting all of them. _P paks override other paks and later packages override \
+                       earlier ones of the same priority. Pass - together with --index-file to list a package \
+                       that is read from stdin, stdin itself isn't read then."))
+            .arg(arg_index_file())
             .arg(arg_paths())
-            .arg(arg_encryption_key()))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
         .subcommand(SubCommand::with_name("check")
             .alias("c")
             .about("Check consistency of a package")
@@ -362,26 +1216,532 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                 .long("abort-on-error")
                 .takes_value(false)
                 .help("Stop on the first found error."))
+            .arg(Arg::with_name("deep")
+                .long("deep")
+                .takes_value(false)
+                .help(
+                    "Also decompress all files and check that the decompressed size matches \
+                    the uncompressed size. Encrypted files need --encryption-key for this."))
+            .arg(Arg::with_name("gaps")
+                .long("gaps")
+                .takes_value(false)
+                .help("Print regions of the file that aren't used by any record."))
+            .arg(Arg::with_name("report")
+                .long("report")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write all found errors as JSON to FILE."))
+            .arg(Arg::with_name("signature")
+                .long("signature")
+                .takes_value(false)
+                .requires("signing-key")
+                .help(
+                    "Also check the package against its .sig file (same name as the package). \
+                    Needs the public key given with --signing-key."))
+            .arg(Arg::with_name("signing-key")
+                .long("signing-key")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("PEM encoded RSA public (or private) key for --signature."))
             .arg(arg_variant())
             .arg(arg_print0())
             .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
             .arg(arg_encoding())
             .arg(arg_force_version())
             .arg(arg_ignore_null_checksums())
             .arg(arg_threads())
+            .arg(arg_max_memory())
+            .arg(arg_io())
+            .arg(arg_read_size())
+            .arg(arg_order())
+            .arg(arg_progress())
+            .arg(arg_progress_file())
             .arg(arg_verbose())
             .arg(arg_package())
             .arg(arg_paths())
-            .arg(arg_encryption_key()))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Compare the content of a package with a directory")
+            .arg(arg_variant())
+            .arg(arg_print0())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_threads())
+            .arg(arg_package())
+            .arg(Arg::with_name("dir")
+                .index(2)
+                .required(true)
+                .value_name("DIR")
+                .help("Directory to compare the package with, e.g. where the package was unpacked to."))
+            .arg(Arg::with_name("paths")
+                .index(3)
+                .multiple(true)
+                .value_name("PATH")
+                .help("If given, only consider these files from the package and directory."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("hash")
+            .about("Print the SHA-1 (or SHA-256) of the content of the files in a package in the format of sha1sum")
+            .arg(arg_variant())
+            .arg(arg_print0())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_threads())
+            .arg(Arg::with_name("recompute")
+                .long("recompute")
+                .takes_value(false)
+                .help("Compute the SHA-1 of all files. Per default the SHA-1 from the index is used \
+                       where it is the SHA-1 of the content (uncompressed and unencrypted files)."))
+            .arg(Arg::with_name("digest")
+                .long("digest")
+                .takes_value(true)
+                .value_name("ALGORITHM")
+                .possible_values(&["sha1", "sha256"])
+                .default_value("sha1")
+                .help("Digest algorithm to use. The output is compatible with sha1sum/sha256sum."))
+            .arg(arg_package())
+            .arg(arg_paths())
+            .arg(arg_files_from())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("grep")
+            .about("Search for a string or byte sequence in the (decompressed) content of the files in a package")
+            .arg(arg_variant())
+            .arg(arg_print0())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_threads())
+            .arg(Arg::with_name("ignore-case")
+                .long("ignore-case")
+                .short("i")
+                .takes_value(false)
+                .conflicts_with("hex")
+                .help("Ignore case of ASCII letters."))
+            .arg(Arg::with_name("utf16")
+                .long("utf16")
+                .takes_value(false)
+                .conflicts_with("hex")
+                .help("Also search for the UTF-16LE encoding of PATTERN."))
+            .arg(Arg::with_name("hex")
+                .long("hex")
+                .short("x")
+                .takes_value(false)
+                .help("PATTERN is a sequence of hex bytes, e.g. \"DEADBEEF\" or \"DE AD BE EF\"."))
+            .arg(Arg::with_name("files-with-matches")
+                .long("files-with-matches")
+                .short("l")
+                .takes_value(false)
+                .help("Only print the paths of the matching files, not the offsets of the matches."))
+            .arg(Arg::with_name("max-count")
+                .long("max-count")
+                .short("m")
+                .takes_value(true)
+                .value_name("NUM")
+                .help("Only report the first NUM matches per file."))
+            .arg(arg_package())
+            .arg(Arg::with_name("pattern")
+                .index(2)
+                .required(true)
+                .value_name("PATTERN")
+                .help("String to search for."))
+            .arg(Arg::with_name("paths")
+                .index(3)
+                .multiple(true)
+                .value_name("PATH")
+                .help("If given, only search these files from the package."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("locate")
+            .about("Show which file (and compression block) of a package covers a given file offset")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(Arg::with_name("offset")
+                .long("offset")
+                .short("o")
+                .takes_value(true)
+                .required(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("OFFSET")
+                .help("Offset in the package file. Decimal or hexadecimal with 0x prefix. Can be given several times."))
+            .arg(arg_package())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("ranges")
+            .about("Print the byte ranges of a package that are needed to extract the given files")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(Arg::with_name("include-index")
+                .long("include-index")
+                .short("i")
+                .takes_value(false)
+                .help("Also print the ranges of the index and the footer, which are needed to read the package at all."))
+            .arg(Arg::with_name("header")
+                .long("header")
+                .takes_value(false)
+                .help("Print all ranges in one line as value for a HTTP Range header (bytes=START-END,...)."))
+            .arg(arg_package_or_url())
+            .arg(arg_paths())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("dedupe")
+            .about("Find files with the same content in a package")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(Arg::with_name("report")
+                .long("report")
+                .takes_value(false)
+                .required(true)
+                .help(
+                    "Print groups of files with the same SHA-1 and uncompressed size and how \
+                    many bytes sharing their data would save. Use pack --dedupe to write \
+                    packages that share the data of duplicates."))
+            .arg(arg_print0())
+            .arg(arg_package())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("slack")
+            .about("Show the regions of a package that aren't used by any file, index or the footer")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(Arg::with_name("ignore-padding")
+                .long("ignore-padding")
+                .takes_value(false)
+                .help("Don't show regions that contain only zero bytes."))
+            .arg(Arg::with_name("dump")
+                .long("dump")
+                .takes_value(true)
+                .value_name("OUTDIR")
+                .help("Write the content of each shown region to OUTDIR/slack-0xOFFSET.bin."))
+            .arg(arg_package())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("keycheck")
+            .about("Find out which of the given AES keys the index of a package is encrypted with, \
+                    without unpacking anything")
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_scan_footer())
+            .arg(arg_force_version())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher())
+            .arg(arg_threads())
+            .arg(Arg::with_name("wordlist")
+                .long("wordlist")
+                .short("w")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("File with one candidate key per line (base64 encoded or as hex with 0x prefix). \
+                       They are tried in parallel and only the ones that work are printed. Lines that \
+                       aren't keys are skipped."))
+            .arg(arg_package())
+            .arg(Arg::with_name("key")
+                .index(2)
+                .multiple(true)
+                .value_name("KEY")
+                .help("Candidate keys, base64 encoded or as hex with 0x prefix. The keys of --keyring are \
+                       tried as well.")))
+        .subcommand(SubCommand::with_name("hexdump")
+            .about("Print a hexdump of a part of the (decompressed) content of a file in a package")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(Arg::with_name("offset")
+                .long("offset")
+                .short("o")
+                .takes_value(true)
+                .default_value("0")
+                .value_name("OFFSET")
+                .help("Offset in the uncompressed content of the file. Decimal or hexadecimal with 0x prefix."))
+            .arg(Arg::with_name("length")
+                .long("length")
+                .short("n")
+                .takes_value(true)
+                .default_value("256")
+                .value_name("LENGTH")
+                .help("Number of bytes to dump. Decimal or hexadecimal with 0x prefix."))
+            .arg(arg_package())
+            .arg(Arg::with_name("path")
+                .index(2)
+                .required(true)
+                .value_name("PATH")
+                .help("File in the package."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("repair")
+            .about("Write a copy of a package with an index rebuilt from the inline record headers")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_print0())
+            .arg(arg_package())
+            .arg(Arg::with_name("output")
+                .index(2)
+                .required(true)
+                .value_name("OUTPUT")
+                .help("Write the repaired package to OUTPUT."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("rename")
+            .about("Rename a file or directory in a package by rewriting only its index (in place)")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_print0())
+            .arg(arg_verbose())
+            .arg(arg_package())
+            .arg(Arg::with_name("old-path")
+                .index(2)
+                .required(true)
+                .value_name("OLD_PATH")
+                .help("File or directory in the package."))
+            .arg(Arg::with_name("new-path")
+                .index(3)
+                .required(true)
+                .value_name("NEW_PATH")
+                .help("New path of the file or directory."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("set-mount-point")
+            .about("Change the mount point of a package by rewriting only its index (in place)")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_print0())
+            .arg(arg_package())
+            .arg(Arg::with_name("mount-point")
+                .index(2)
+                .required(true)
+                .value_name("MOUNT_POINT")
+                .help("The new mount point, e.g. \"../../../\"."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("dump-index")
+            .about("Write the raw (decrypted) index of a package to files or hexdump it, for debugging")
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_package())
+            .arg(Arg::with_name("outdir")
+                .index(2)
+                .value_name("OUTDIR")
+                .help("Write primary_index.bin and, for version >= 10, encoded_records.bin, \
+                       path_hash_index.bin and full_directory_index.bin to OUTDIR. \
+                       If not given a hexdump of them is written to stdout."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("export-index")
+            .about("Write the index metadata of a package as JSON template for pack --index-template")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_package())
+            .arg(Arg::with_name("output")
+                .index(2)
+                .required(true)
+                .value_name("OUTPUT")
+                .help("Write the index template to OUTPUT. Pass - to write it to stdout. \
+                       The source of each file is its path in the package, so the template \
+                       fits a directory the package was unpacked to (sources are relative \
+                       to the directory containing the template)."))
+            .arg(Arg::with_name("raw")
+                .long("raw")
+                .takes_value(false)
+                .help(
+                    "Instead of a template write the end of the package starting at the index \
+                    (the index, the secondary indices and the footer) as it is. This is what \
+                    list and unpack --index-file need to read the package from stdin."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
+        .subcommand(SubCommand::with_name("salvage")
+            .about("Recover content of a package with a broken or missing index")
+            .arg(arg_variant())
+            .arg(Arg::with_name("version")
+                .long("version")
+                .short("V")
+                .takes_value(true)
+                .help(
+                    "Expect inline record headers of given VERSION \
+                    [default: 3 when --variant=standard, 4 when --variant=conan_exiles]"))
+            .arg(Arg::with_name("ignore-checksums")
+                .long("ignore-checksums")
+                .takes_value(false)
+                .help(
+                    "Also accept records where the SHA-1 checksum in the header doesn't match the data. \
+                    This finds records with corrupted data, but also gives more false positives."))
+            .arg(arg_human_readable())
+            .arg(arg_encoding())
+            .arg(arg_print0())
+            .arg(arg_threads())
+            .arg(arg_verbose())
+            .arg(Arg::with_name("outdir")
+                .long("outdir")
+                .short("o")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Unpack the found records to DIR."))
+            .arg(Arg::with_name("rebuild")
+                .long("rebuild")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write a copy of the package with a reconstructed index to FILE."))
+            .arg(arg_package())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
         .subcommand(SubCommand::with_name("unpack")
             .alias("u")
             .about("Unpack content of a package")
             .arg(arg_variant())
             .arg(arg_print0())
             .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
             .arg(arg_encoding())
             .arg(arg_force_version())
             .arg(arg_threads())
+            .arg(arg_max_memory())
+            .arg(arg_io())
+            .arg(arg_read_size())
+            .arg(arg_order())
+            .arg(arg_progress())
+            .arg(arg_progress_file())
             .arg(arg_verbose())
             .arg(Arg::with_name("dirname-from-compression")
                 .long("dirname-from-compression")
@@ -397,19 +1757,156 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                 .value_name("DIR")
                 .default_value(".")
                 .help("Write unpacked files to DIR."))
-            .arg(arg_package())
+            .arg(Arg::with_name("overwrite")
+                .long("overwrite")
+                .takes_value(false)
+                .conflicts_with_all(&["no-clobber", "update"])
+                .help("Overwrite existing files. This is the default."))
+            .arg(Arg::with_name("no-clobber")
+                .long("no-clobber")
+                .short("n")
+                .takes_value(false)
+                .conflicts_with_all(&["overwrite", "update"])
+                .help("Don't overwrite existing files."))
+            .arg(Arg::with_name("update")
+                .long("update")
+                .short("u")
+                .takes_value(false)
+                .conflicts_with_all(&["overwrite", "no-clobber"])
+                .help(
+                    "Only overwrite existing files if the file in the package is newer \
+                    (pak version 1) or if the content differs (all other versions)."))
+            .arg(Arg::with_name("preserve-timestamps")
+                .long("preserve-timestamps")
+                .short("p")
+                .takes_value(false)
+                .help(
+                    "Set the modification time of unpacked files to the timestamp stored in the \
+                    package. Only pak version 1 stores timestamps, see also --pak-timestamp."))
+            .arg(Arg::with_name("pak-timestamp")
+                .long("pak-timestamp")
+                .takes_value(false)
+                .help(
+                    "Use the modification time of the package for files without a timestamp. \
+                    Implies --preserve-timestamps."))
+            .arg(Arg::with_name("digest")
+                .long("digest")
+                .takes_value(true)
+                .value_name("ALGORITHM")
+                .possible_values(&["sha1", "sha256"])
+                .conflicts_with_all(&["to-zip", "to-tar"])
+                .help(
+                    "Print a digest of the content of every unpacked file in the format of \
+                    sha1sum/sha256sum (paths relative to the output directory)."))
+            .arg(Arg::with_name("allow-unsafe-paths")
+                .long("allow-unsafe-paths")
+                .takes_value(false)
+                .help(
+                    "Also unpack files with paths containing '..' or similar that could \
+                    escape the output directory. Only use this with trusted packages!"))
+            .arg(Arg::with_name("strip-prefix")
+                .long("strip-prefix")
+                .takes_value(true)
+                .value_name("PREFIX")
+                .conflicts_with_all(&["to-zip", "to-tar", "flatten"])
+                .help(
+                    "Drop this leading directory (e.g. Game/Content/) from the paths of \
+                    the unpacked files. Files outside of it keep their full path."))
+            .arg(Arg::with_name("flatten")
+                .long("flatten")
+                .takes_value(false)
+                .conflicts_with_all(&["to-zip", "to-tar"])
+                .help(
+                    "Unpack all files directly into the output directory without their \
+                    directories. Files with the same name get a ' (2)', ' (3)' etc. suffix, \
+                    the first one in unpack order keeps its name."))
+            .arg(Arg::with_name("invalid-names")
+                .long("invalid-names")
+                .takes_value(true)
+                .value_name("POLICY")
+                .possible_values(&["keep", "percent", "replace", "error"])
+                .conflicts_with_all(&["to-zip", "to-tar"])
+                .help(
+                    "What to do with file names that can't be written on some file systems \
+                    (control characters, any of <>:\"\\|?*, trailing dots or spaces, DOS \
+                    device names like CON): keep them as they are, percent-encode or replace \
+                    the offending characters, or refuse to unpack anything. \
+                    [default: percent on Windows, keep elsewhere]"))
+            .arg(Arg::with_name("nfc")
+                .long("nfc")
+                .takes_value(false)
+                .conflicts_with_all(&["to-zip", "to-tar"])
+                .help("Normalize file names to Unicode NFC. This is the default on macOS."))
+            .arg(Arg::with_name("no-nfc")
+                .long("no-nfc")
+                .takes_value(false)
+                .conflicts_with("nfc")
+                .help("Don't normalize file names to Unicode NFC, even on macOS."))
+            .arg(Arg::with_name("to-zip")
+                .long("to-zip")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["to-tar", "dirname-from-compression"])
+                .help(
+                    "Write the unpacked files into a ZIP archive instead of a directory. \
+                    Pass - to write to stdout. Files that are compressed in the package \
+                    are deflated, others are stored."))
+            .arg(Arg::with_name("to-tar")
+                .long("to-tar")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["to-zip", "dirname-from-compression"])
+                .help("Write the unpacked files into a tar archive instead of a directory. Pass - to write to stdout."))
+            .arg(Arg::with_name("skip-errors")
+                .long("skip-errors")
+                .takes_value(false)
+                .conflicts_with_all(&["to-zip", "to-tar"])
+                .help(
+                    "Keep going when a file can't be unpacked (e.g. corrupt compressed data). \
+                    The failed files are written to OUTDIR/errors.json (path, offset and reason) \
+                    and the exit code is 100 plus their count. Their output files may be incomplete."))
+            .arg(Arg::with_name("errors-file")
+                .long("errors-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("skip-errors")
+                .help("Write the files that couldn't be unpacked with --skip-errors to FILE instead of OUTDIR/errors.json."))
+            .arg(Arg::with_name("fallback-decompression")
+                .long("fallback-decompression")
+                .takes_value(false)
+                .conflicts_with_all(&["to-zip", "to-tar"])
+                .help(
+                    "When the zlib data of a file is corrupt, retry it as raw deflate and gzip stream and with \
+                    the compression block offsets taken as absolute instead of relative to the file (or the other \
+                    way around). Some version 5 to 7 packages get the latter wrong. Files that could be unpacked \
+                    like this are reported as warnings."))
+            .arg(arg_where())
+            .arg(arg_min_size())
+            .arg(arg_max_size())
+            .arg(arg_encrypted_only())
+            .arg(arg_package_or_url()
+                .help("An Unreal Engine 4 pak file, a .utoc file of an IoStore container or a http:// URL of a pak file, \
+                       which is then read using HTTP range requests. Pass - together with --index-file to read the \
+                       package front to back from stdin, e.g. while it is downloaded or decompressed."))
+            .arg(arg_index_file())
             .arg(arg_paths())
-            .arg(arg_encryption_key()))
+            .arg(arg_files_from())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher()))
         .subcommand(SubCommand::with_name("pack")
             .alias("p")
             .about("Create a new package")
             .arg(arg_variant())
+            .arg(arg_magic())
             .arg(Arg::with_name("version")
                 .long("version")
                 .short("V")
                 .takes_value(true)
                 .help(
-                    "Create package of given VERSION. Supported versions are: 1, 2, and 3 \
+                    "Create package of given VERSION. Supported versions are: 1, 2, 3, 4, 5, and 7 \
+                    (only 4 for --variant=conan_exiles) \
                     [default: 3 when --variant=standard, 4 when --variant=conan_exiles]"))
             .arg(Arg::with_name("mount-point")
                 .long("mount-point")
@@ -444,32 +1941,123 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                 .help(
                     "Minimum size of files to be compressed. Note that it makes no sense to \
                     try to compress files smaller than 100 bytes or so, because of the \
-                    compression overhead."))
-            .arg(arg_encoding())
-            .arg(arg_print0())
-            .arg(arg_threads())
-            .arg(arg_verbose())
-            .arg(arg_package())
-            .arg(Arg::with_name("paths")
-                .index(2)
+                    compression overhead. Can be overridden per path with min_size=SIZE."))
+            .arg(Arg::with_name("no-compress-ext")
+                .long("no-compress-ext")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_delimiter(",")
+                .value_name("EXT")
+                .help(
+                    "Don't compress files with these extensions, even if compression is \
+                    enabled by --compression-method. Files with one of these extensions \
+                    are already stored uncompressed by default: 7z, bk2, gz, jpeg, jpg, \
+                    mp3, mp4, ogg, opus, png, usm, webm, webp, wem, zip. This rule does \
+                    not apply to paths that explicitly specify zlib (e.g. :zlib:PATH)."))
+            .arg(Arg::with_name("compress-ext")
+                .long("compress-ext")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_delimiter(",")
+                .value_name("EXT")
+                .help(
+                    "Remove these extensions from the list of extensions that won't be \
+                    compressed. Pass --compress-ext=all to clear the whole list."))
+            .arg(Arg::with_name("encrypt")
+                .long("encrypt")
+                .takes_value(false)
+                .help(
+                    "Encrypt all files. Can be overridden per path with encrypt and no_encrypt. \
+                    Only supported starting with version 3."))
+            .arg(arg_encryption_key())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher())
+            .arg(Arg::with_name("encryption-guid")
+                .long("encryption-guid")
+                .takes_value(true)
+                .value_name("GUID")
+                .help(
+                    "Encryption GUID (32 hex digits) to write into the header of an encrypted \
+                    container, i.e. the GUID of --encryption-key. Only for --format=iostore. \
+                    [default: 0]"))
+            .arg(Arg::with_name("signing-key")
+                .long("signing-key")
+                .takes_value(true)
+                .value_name("FILE")
+                .help(
+                    "Sign the package with this PEM encoded RSA private key, i.e. also write a \
+                    .sig file with the same name as the package."))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["pak", "iostore"])
+                .default_value("pak")
+                .help(
+                    "Container format to write. iostore writes a .utoc and .ucas file plus an empty \
+                    stub .pak file (of the given --version) with the same name as PACKAGE. Files are \
+                    stored as they are, i.e. they are not converted to zen packages."))
+            .arg(Arg::with_name("dedupe")
+                .long("dedupe")
+                .takes_value(false)
+                .help(
+                    "Write the data of files with the same content only once and let all of \
+                    their records point to it. Only for --format=pak."))
+            .arg(Arg::with_name("manifest")
+                .long("manifest")
+                .takes_value(true)
+                .value_name("FILE")
+                .help(
+                    "Read the list of files to pack from a JSON manifest. Each entry can overload \
+                    the same settings as the path syntax described below:\n\
+                    \n\
+                    \t{\"files\": [\n\
+                    \t\t{\"source\": \"Some/Folder\", \"path\": \"/Foo/Bar\", \"compression\": \"zlib\", \
+                    \"level\": 7, \"block_size\": \"64K\", \"min_size\": \"1K\", \"encrypted\": true}\n\
+                    \t]}\n\
+                    \n\
+                    Relative source paths are relative to the directory containing the manifest. \
+                    Files from the manifest are packed after the files given as PATH."))
+            .arg(Arg::with_name("index-template")
+                .long("index-template")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("manifest")
+                .help(
+                    "Pack the files listed in an index template as written by export-index. \
+                    An index template is a manifest that also gives the version, variant and \
+                    mount point of the package, which are used unless given explicitly. \
+                    Edit the \"path\" of a file to rename it in the package."))
+            .arg(arg_files_from())
+            .arg(arg_encoding())
+            .arg(arg_print0())
+            .arg(arg_threads())
+            .arg(arg_max_memory())
+            .arg(arg_progress())
+            .arg(arg_progress_file())
+            .arg(arg_verbose())
+            .arg(arg_package())
+            .arg(Arg::with_name("paths")
+                .index(2)
                 .multiple(true)
                 .value_name("PATH")
                 .help(
                     "Pack these files or directories. You can overload certain settings for a path using a special syntax, e.g.:\n\
                     \n\
                     Linux/Unix:\n\
-                    \tu4pak pack Archive.pak :zlib,level=7,block_size=65536,rename=/Foo/Bar:/Some/Folder\n\
+                    \tu4pak pack Archive.pak :zlib,level=7,block_size=65536,min_size=1K,rename=/Foo/Bar:/Some/Folder\n\
                     \n\
                     Windows:\n\
-                    \tu4pak pack Archive.pak :zlib,level=7,block_size=65536,rename=/Foo/Bar:C:\\Some\\Folder\n\
+                    \tu4pak pack Archive.pak :zlib,level=7,block_size=65536,min_size=1K,rename=/Foo/Bar:C:\\Some\\Folder\n\
                     \n\
                     This means add the fiels from the folder '/Some/Folder' ('C:\\Some\\Folder') \
                     from your hard disk, use zlib compression at compression level 7 with a \
-                    compression block size of 65536 bytes, and rename the folder to be 'Foo/Bar' \
-                    inside of the pak archive file.\n\
+                    compression block size of 65536 bytes, only compress files of at least 1 KiB, \
+                    and rename the folder to be 'Foo/Bar' inside of the pak archive file.\n\
                     \n\
                     Instead of 'zlib' you can also write 'none' to not compress the files from the \
-                    given path. If you don't say any of either the default value provided by \
+                    given path. Use 'encrypt' or 'no_encrypt' to override --encrypt for the given path. If you don't say any of either the default value provided by \
                     --compression-method is used. Same goes for all the other parameters. \
                     If you don't specify 'rename' then the same path is used for the folder inside \
                     of the pak archive as the files on your hard disk have.\n\
@@ -477,6 +2065,11 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                     This is handy if you want to compress some files, but not all:\n\
                     \tu4pak pack Archive.pak :zlib,rename=/:ZlibFiles :none,rename=/:UncompressedFiles
                     \n\
+                    Paths may contain the wildcards *, ?, [abc] and ** (any number of folders), which \
+                    u4pak expands itself (also on Windows). 'rename' replaces the folder before the first \
+                    wildcard, e.g.:\n\
+                    \tu4pak pack Archive.pak \":zlib,rename=/Game:Content/**/*.uasset\"\n\
+                    \n\
                     If the default parameters are all you need (and you provide relative paths \
                     that don't need renaming) you can simply say e.g.:\n\
                     \n\
@@ -485,18 +2078,159 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                     \n\
                     Windows:\n\
                     \tu4pak pack Archive.pak Some\\Folder\n\
-                    ")));
+                    ")))
+        .subcommand(SubCommand::with_name("bench-compress")
+            .about("Try compression levels and block sizes on a sample of the files in a directory")
+            .arg(Arg::with_name("sample-size")
+                .long("sample-size")
+                .takes_value(true)
+                .default_value("16M")
+                .value_name("SIZE")
+                .help("Compress at most this many bytes of all files together."))
+            .arg(Arg::with_name("levels")
+                .long("levels")
+                .takes_value(true)
+                .default_value("1,3,6,9")
+                .value_name("LEVEL,...")
+                .help("Compression levels to try."))
+            .arg(Arg::with_name("block-sizes")
+                .long("block-sizes")
+                .takes_value(true)
+                .default_value("16K,64K,256K")
+                .value_name("SIZE,...")
+                .help("Compression block sizes to try."))
+            .arg(Arg::with_name("dir")
+                .index(1)
+                .required(true)
+                .value_name("DIR")
+                .help(
+                    "Directory with the files to pack. Files with extensions that pack stores \
+                    uncompressed by default are skipped.")))
+        .subcommand(SubCommand::with_name("patch")
+            .about("Create a patch package with only the files that differ from a base package")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_force_version())
+            .arg(Arg::with_name("base")
+                .long("base")
+                .takes_value(true)
+                .required(true)
+                .value_name("PACKAGE")
+                .help("The package to compare with. Its mount point and version are used for the patch package."))
+            .arg(Arg::with_name("source")
+                .long("source")
+                .takes_value(true)
+                .required(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATH")
+                .help(
+                    "Files or directories to compare with the base package. Supports the same \
+                    syntax as the paths of the pack sub-command, e.g. :zlib,rename=/Game:ModFiles"))
+            .arg(Arg::with_name("version")
+                .long("version")
+                .short("V")
+                .takes_value(true)
+                .help(
+                    "Create package of given VERSION. Supported versions are: 1, 2, 3, 4, 5, and 7 \
+                    [default: version of the base package, or 3 if that version can't be written]"))
+            .arg(Arg::with_name("mount-point")
+                .long("mount-point")
+                .short("m")
+                .takes_value(true)
+                .help("Mount-point field of the package [default: mount point of the base package]"))
+            .arg(Arg::with_name("compression-method")
+                .long("compression-method")
+                .short("c")
+                .takes_value(true)
+                .default_value("none")
+                .help("Default compression method."))
+            .arg(Arg::with_name("compression-level")
+                .long("compression-level")
+                .short("l")
+                .takes_value(true)
+                .default_value("default")
+                .help(
+                    "Default compression level. Allowed values are the integers from 1 to 9, \
+                    or the strings 'fast' (=1), 'best' (=9), and 'default' (=6)."))
+            .arg(Arg::with_name("encrypt")
+                .long("encrypt")
+                .takes_value(false)
+                .help("Encrypt all files of the patch package."))
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher())
+            .arg(arg_encoding())
+            .arg(arg_print0())
+            .arg(arg_threads())
+            .arg(arg_progress())
+            .arg(arg_progress_file())
+            .arg(arg_verbose())
+            .arg(Arg::with_name("package")
+                .index(1)
+                .required(true)
+                .value_name("PACKAGE")
+                .help(
+                    "The patch package to create. Unreal Engine only prefers it over the base \
+                    package if the name ends in _P, e.g. Game_P.pak")))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Serve the content of a package as static files via HTTP")
+            .arg(arg_variant())
+            .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
+            .arg(arg_encoding())
+            .arg(arg_force_version())
+            .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher())
+            .arg(arg_threads())
+            .arg(arg_verbose())
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .short("l")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .default_value("127.0.0.1:8080")
+                .help(
+                    "Address and port to listen on. Use 0.0.0.0:PORT to make the \
+                    server reachable from other computers."))
+            .arg(arg_package()));
 
-    #[cfg(target_os = "linux")]
+    #[cfg(all(target_os = "linux", feature = "fuse"))]
     let app = app.subcommand(
         SubCommand::with_name("mount")
             .alias("m")
             .about("Mount package as read-only filesystem")
             .arg(arg_variant())
             .arg(arg_ignore_magic())
+            .arg(arg_magic())
+            .arg(arg_index_xor())
+            .arg(arg_name_list())
+            .arg(arg_harvest_names())
+            .arg(arg_no_detect_variant())
+            .arg(arg_no_detect_block_offsets())
+            .arg(arg_scan_footer())
             .arg(arg_encoding())
             .arg(arg_force_version())
             .arg(arg_encryption_key())
+            .arg(arg_keyring())
+            .arg(arg_crypto_json())
+            .arg(arg_cipher())
             .arg(
                 Arg::with_name("foregound")
                     .long("foreground")
@@ -511,6 +2245,17 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
                     .takes_value(false)
                     .help("Debug mode. Implies --foreground."),
             )
+            .arg(
+                Arg::with_name("cache-size")
+                    .long("cache-size")
+                    .takes_value(true)
+                    .value_name("SIZE")
+                    .help(
+                        "Memory for the decompressed content of compressed files without \
+                        compression blocks (version 2), which can only be decompressed as \
+                        a whole. The most recently read file is always kept. [default: 64M]",
+                    ),
+            )
             .arg(arg_package())
             .arg(
                 Arg::with_name("mountpt")
@@ -523,9 +2268,35 @@ fn make_app<'a, 'b>() -> App<'a, 'b> {
     app
 }
 
+fn init_logger(matches: &ArgMatches) -> Result<()> {
+    // global arguments may be given before or after the sub-command
+    let (_, sub_args) = matches.subcommand();
+    let value_of = |name| sub_args.and_then(|args| args.value_of(name)).or_else(|| matches.value_of(name));
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("warn"));
+
+    if let Some(level) = value_of("log-level") {
+        builder.parse_filters(level);
+    }
+
+    if let Some(path) = value_of("log-file") {
+        let file = match std::fs::OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => file,
+            Err(error) => return Err(Error::io_with_path(error, path)),
+        };
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+
+    builder.init();
+    Ok(())
+}
+
 fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
-    let args_from_file = match args::get_args_from_file() {
+    #[cfg(target_family = "windows")]
+    let owns_terminal = windows::owns_terminal();
+    #[cfg(not(target_family = "windows"))]
+    let owns_terminal = false;
+
+    let args_from_file = match args::get_args_from_file(owns_terminal) {
         Ok(args_from_file) => args_from_file,
         Err(error) => {
             let _ = error.write_to(&mut stderr(), false);
@@ -533,7 +2304,7 @@ fn main() {
             {
                 windows::pause_if_owns_terminal();
             }
-            return;
+            std::process::exit(exit_code(&error));
         }
     };
 
@@ -552,7 +2323,7 @@ fn main() {
                 {
                     windows::pause_if_owns_terminal();
                 }
-                std::process::exit(1);
+                std::process::exit(EXIT_USAGE);
             } else {
                 println!("{}", error);
                 #[cfg(target_family = "windows")]
@@ -564,19 +2335,32 @@ fn main() {
         }
     };
 
+    if let Err(error) = init_logger(&matches) {
+        let _ = error.write_to(&mut stderr(), false);
+        #[cfg(target_family = "windows")]
+        {
+            windows::pause_if_owns_terminal();
+        }
+        std::process::exit(exit_code(&error));
+    }
+
     #[cfg(target_family = "windows")]
     let pause: Pause = match matches.value_of("pause-on-exit").unwrap().try_into() {
         Ok(pause) => pause,
         Err(error) => {
             eprintln!("{}", error);
             windows::pause_if_owns_terminal();
-            std::process::exit(1);
+            std::process::exit(EXIT_USAGE);
         }
     };
 
-    if let Err(error) = run(&matches) {
-        let _ = error.write_to(&mut stderr(), false);
-    }
+    let status = match run(&matches) {
+        Ok(()) => 0,
+        Err(error) => {
+            let _ = error.write_to(&mut stderr(), false);
+            exit_code(&error)
+        }
+    };
 
     #[cfg(target_family = "windows")]
     match pause {
@@ -584,6 +2368,10 @@ fn main() {
         Pause::Never => {}
         Pause::Auto => windows::pause_if_owns_terminal(),
     }
+
+    if status != 0 {
+        std::process::exit(status);
+    }
 }
 
 fn run(matches: &ArgMatches) -> Result<()> {
@@ -592,6 +2380,116 @@ fn run(matches: &ArgMatches) -> Result<()> {
             let variant = args.value_of("variant").unwrap().try_into()?;
             let human_readable = args.is_present("human-readable");
             let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let packages: Vec<&str> = args.values_of("package").unwrap().collect();
+            let path = packages[0];
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let options = Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key,
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
+            };
+
+            let json = args.value_of("format") == Some("json");
+            if json && (args.is_present("game-hints") || packages.len() > 1 || is_toc(path)) {
+                return Err(Error::new("--format=json only works with a single pak file or URL and without --game-hints".to_string())
+                    .with_kind(ErrorKind::InvalidArgument));
+            }
+
+            if args.is_present("game-hints") {
+                if packages.len() > 1 || is_toc(path) || is_url(path) {
+                    return Err(Error::new("--game-hints only works with a single local pak file".to_string())
+                        .with_kind(ErrorKind::InvalidArgument));
+                }
+                let mut file = File::open(path).map_err(|error| Error::io_with_path(error, path))?;
+                let hints = game_hints(&mut BufReader::new(&mut file), &options)?;
+                info_game_hints(&hints);
+                println!();
+            }
+
+            if packages.len() > 1 {
+                let paks = packages.iter()
+                    .map(|path| open_package(path, options.clone()))
+                    .collect::<Result<Vec<_>>>()?;
+                let view = MergedView::new(&paks, &packages);
+
+                info_merged(&view, &packages, human_readable)?;
+                if args.is_present("by-extension") {
+                    println!();
+                    info_by_extension(&view.to_pak(), human_readable)?;
+                }
+                return Ok(());
+            }
+
+            if is_toc(path) {
+                let toc = Toc::from_path(path, options.encryption_key)?;
+                info_toc(&toc, human_readable)?;
+                return Ok(());
+            }
+
+            if !is_url(path) && !args.is_present("by-extension") {
+                // the summary doesn't need the names, so the records can be
+                // decoded one at a time
+                let index = LazyIndex::from_path(path, &options)?;
+                if json {
+                    println!("{}", serde_json::to_string(&info_lazy_json(&index)?)?);
+                } else {
+                    info_lazy(&index, human_readable)?;
+                }
+                return Ok(());
+            }
+
+            let pak = if is_url(path) {
+                open_url(path, options)?.0
+            } else {
+                Pak::from_path(path, options)?
+            };
+
+            if json {
+                println!("{}", serde_json::to_string(&info_json(&pak, args.is_present("by-extension")))?);
+                return Ok(());
+            }
+
+            info(&pak, human_readable)?;
+            if args.is_present("by-extension") {
+                println!();
+                info_by_extension(&pak, human_readable)?;
+            }
+        }
+        ("tree", Some(args)) => {
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let human_readable = args.is_present("human-readable");
+            let compressed = args.is_present("compressed");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
             let encoding = args.value_of("encoding").unwrap().try_into()?;
             let path = args.value_of("package").unwrap();
 
@@ -601,30 +2499,91 @@ fn run(matches: &ArgMatches) -> Result<()> {
                 None
             };
 
-            let encryption_key = if let Some(key) = args.value_of("encryption-key") {
-                Some(
-                    base64::decode(
-                        key.parse::<String>()
-                            .expect("Failed to read encryption key."),
-                    )
-                    .expect("Failed to parse encryption key."),
-                )
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let pak = Pak::from_path(
+                path,
+                Options {
+                    variant,
+                    ignore_magic,
+                    magic,
+                    encoding,
+                    force_version,
+                    encryption_key,
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
+                },
+            )?;
+
+            tree(&pak, TreeOptions {
+                human_readable,
+                compressed,
+            })?;
+        }
+        ("du", Some(args)) => {
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let human_readable = args.is_present("human-readable");
+            let no_header = args.is_present("no-header");
+            let sort_by_compressed = args.is_present("compressed");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+
+            let max_depth = if let Some(max_depth) = args.value_of("max-depth") {
+                Some(max_depth.parse()?)
+            } else {
+                None
+            };
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
             } else {
                 None
             };
 
+            let encryption_key = get_encryption_key(args, path)?;
+
             let pak = Pak::from_path(
-                &path,
+                path,
                 Options {
                     variant,
                     ignore_magic,
+                    magic,
                     encoding,
                     force_version,
                     encryption_key,
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
                 },
             )?;
 
-            info(&pak, human_readable)?;
+            du(&pak, DuOptions {
+                path: args.value_of("path"),
+                human_readable,
+                no_header,
+                sort_by_compressed,
+                max_depth,
+            })?;
         }
         ("list", Some(args)) => {
             let order = if let Some(order) = args.value_of("sort") {
@@ -639,15 +2598,41 @@ fn run(matches: &ArgMatches) -> Result<()> {
             let null_separated = args.is_present("print0");
             let only_names = args.is_present("only-names");
             let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
             let no_header = args.is_present("no-header");
-            let encoding = args.value_of("encoding").unwrap().try_into()?;
-            let path = args.value_of("package").unwrap();
-            let paths = get_paths(args)?;
-            let paths: Option<&[&str]> = if let Some(paths) = &paths {
-                Some(paths)
+            let only_dirs = args.is_present("only-dirs");
+            let depth = if let Some(depth) = args.value_of("depth") {
+                match depth.parse()? {
+                    0 => return Err(Error::new("depth may not be 0".to_string()).with_kind(ErrorKind::InvalidArgument)),
+                    depth => Some(depth),
+                }
             } else {
                 None
             };
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let package_count = args.values_of("paths")
+                .map(|values| values.take_while(|path| is_package_path(path)).count())
+                .unwrap_or(0);
+            let mut paths = get_paths(args)?;
+            let mut packages = vec![path.to_string()];
+            if let Some(paths) = &mut paths {
+                packages.extend(paths.drain(..package_count));
+            }
+            if matches!(&paths, Some(paths) if paths.is_empty()) && !args.is_present("files-from") {
+                paths = None;
+            }
+            let shadowed = args.is_present("shadowed");
+            if shadowed && packages.len() < 2 {
+                return Err(Error::new("--shadowed needs more than one package".to_string()));
+            }
+            let paths: Option<Vec<&str>> = paths.as_ref()
+                .map(|paths| paths.iter().map(String::as_str).collect());
+            let paths = paths.as_deref();
+            let predicate = get_predicate(args)?;
 
             let force_version = if let Some(version) = args.value_of("force-version") {
                 Some(version.parse()?)
@@ -655,18 +2640,131 @@ fn run(matches: &ArgMatches) -> Result<()> {
                 None
             };
 
-            let encryption_key = if let Some(key) = args.value_of("encryption-key") {
-                Some(
-                    base64::decode(
-                        key.parse::<String>()
-                            .expect("Failed to read encryption key."),
-                    )
-                    .expect("Failed to parse encryption key."),
-                )
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let options = Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key,
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
+            };
+
+            let style = if args.value_of("format") == Some("json") {
+                ListStyle::Json
+            } else if only_names {
+                ListStyle::OnlyNames { null_separated }
+            } else {
+                ListStyle::Table {
+                    human_readable,
+                    no_header,
+                    ratio: args.is_present("ratio"),
+                    blocks: args.is_present("blocks"),
+                }
+            };
+
+            if path == STDIN_PATH {
+                if packages.len() > 1 {
+                    return Err(Error::new("a package read from stdin can't be listed together with other packages".to_string())
+                        .with_kind(ErrorKind::InvalidArgument));
+                }
+                let pak = open_index_file(args, options)?;
+                return list(
+                    pak,
+                    ListOptions {
+                        order,
+                        style,
+                        paths,
+                        predicate: predicate.as_ref(),
+                        only_dirs,
+                        depth,
+                    },
+                );
+            }
+
+            if let ListStyle::OnlyNames { null_separated } = style {
+                if packages.len() == 1 && !is_url(path) && !is_toc(path) &&
+                   order.is_none() && paths.is_none() && predicate.is_none() &&
+                   !only_dirs && depth.is_none() &&
+                   options.name_list.is_none() && !options.harvest_names {
+                    let index = LazyIndex::from_path(path, &options)?;
+                    return list_names_lazy(&index, null_separated);
+                }
+            }
+
+            let pak = if packages.len() > 1 {
+                let paks = packages.iter()
+                    .map(|path| open_package(path, options.clone()))
+                    .collect::<Result<Vec<_>>>()?;
+                let view = MergedView::new(&paks, &packages);
+
+                if shadowed {
+                    return list_shadowed(&view, &packages, ListOptions {
+                        order,
+                        style,
+                        paths,
+                        predicate: predicate.as_ref(),
+                        ..ListOptions::default()
+                    });
+                }
+
+                view.to_pak()
+            } else {
+                open_package(path, options)?
+            };
+
+            list(
+                pak,
+                ListOptions {
+                    order,
+                    style,
+                    paths,
+                    predicate: predicate.as_ref(),
+                    only_dirs,
+                    depth,
+                },
+            )?;
+        }
+        ("check", Some(args)) => {
+            let null_separated = args.is_present("print0");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let ignore_null_checksums = args.is_present("ignore-null-checksums");
+            let abort_on_error = args.is_present("abort-on-error");
+            let verbose = args.is_present("verbose");
+            let deep = args.is_present("deep");
+            let report_gaps = args.is_present("gaps");
+            let report_path = args.value_of("report");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let paths = get_paths(args)?;
+            let paths: Option<Vec<&str>> = paths.as_ref()
+                .map(|paths| paths.iter().map(String::as_str).collect());
+            let paths = paths.as_deref();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
             } else {
                 None
             };
 
+            let encryption_key = get_encryption_key(args, path)?;
+
             let mut file = match File::open(path) {
                 Ok(file) => file,
                 Err(error) => return Err(Error::io_with_path(error, path)),
@@ -678,99 +2776,1128 @@ fn run(matches: &ArgMatches) -> Result<()> {
                 Options {
                     variant,
                     ignore_magic,
+                    magic,
                     encoding,
                     force_version,
-                    encryption_key,
+                    encryption_key: encryption_key.clone(),
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
                 },
             )?;
 
-            drop(reader);
+            let (thread_count, io_strategy, read_size) = get_tuned_io(args, path)?;
+            let progress_output = get_progress_output(matches, args)?;
+            let options = CheckOptions {
+                variant,
+                abort_on_error,
+                ignore_null_checksums,
+                null_separated,
+                verbose,
+                thread_count,
+                max_memory: get_max_memory(args)?,
+                io_strategy,
+                read_size,
+                order: args.value_of("order").unwrap().try_into()?,
+                paths,
+                progress: progress_output.as_ref().map(|output| output as &dyn ProgressCallback),
+                deep,
+                encryption_key,
+            };
 
-            list(
-                pak,
-                ListOptions {
-                    order,
-                    style: if only_names {
-                        ListStyle::OnlyNames { null_separated }
-                    } else {
-                        ListStyle::Table {
-                            human_readable,
-                            no_header,
-                        }
-                    },
-                    paths,
-                },
-            )?;
-        }
-        ("check", Some(args)) => {
+            let sep = if null_separated { '\0' } else { '\n' };
+            if report_gaps {
+                for gap in analyze_layout(&pak).1 {
+                    print!("gap: {}..{} ({} bytes){}", gap.offset, gap.offset + gap.size, gap.size, sep);
+                }
+            }
+
+            let result = check_with_report(&pak, &mut file, options);
+            if let Some(output) = &progress_output {
+                for error in result.iter().flatten() {
+                    output.error(
+                        error.error.path().as_ref().map(|path| path.to_string_lossy()).as_deref(),
+                        error.kind.as_str(),
+                        &error.error.error_type().to_string());
+                }
+                output.finish(&result);
+            }
+            let mut errors = result?;
+
+            if args.is_present("signature") {
+                let key = read_public_key(args.value_of("signing-key").unwrap())?;
+                let seperator = if null_separated { '\0' } else { '\n' };
+                let warnings = Warnings::callback(move |warning| eprint!("{}{}", warning, seperator));
+                errors.extend(check_signature(path, signature_path(path), &key, &warnings)?);
+            }
+
+            // --progress=json already reported the errors of the files as events
+            if !progress_output.as_ref().is_some_and(ProgressOutput::is_json) {
+                let mut stderr = std::io::stderr();
+                for error in &errors {
+                    let _ = error.error.write_to(&mut stderr, null_separated);
+                }
+            }
+            let error_count = errors.len();
+            let wiped_count = errors.iter()
+                .filter(|error| error.kind == CheckErrorKind::DataWiped)
+                .count();
+
+            if let Some(report_path) = report_path {
+                let report = serde_json::to_string(&Document::new(CheckReport {
+                    package: path,
+                    error_count,
+                    wiped_count,
+                    errors: &errors,
+                }))?;
+                let mut writer = match File::create(report_path) {
+                    Ok(file) => BufWriter::new(file),
+                    Err(error) => return Err(Error::io_with_path(error, report_path)),
+                };
+                if let Err(error) = writeln!(writer, "{}", report).and_then(|_| writer.flush()) {
+                    return Err(Error::io_with_path(error, report_path));
+                }
+            }
+
+            if error_count == 0 {
+                print!("All ok{}", sep);
+            } else if wiped_count > 0 {
+                // zeroed data is usually intentional, so say so
+                print!("Found {} error(s), the data of {} file(s) is wiped (all zero){}", error_count, wiped_count, sep);
+                std::process::exit(error_count_exit_code(error_count));
+            } else {
+                print!("Found {} error(s){}", error_count, sep);
+                std::process::exit(error_count_exit_code(error_count));
+            }
+        }
+        ("verify", Some(args)) => {
+            let null_separated = args.is_present("print0");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let thread_count = get_threads(args)?;
+            let path = args.value_of("package").unwrap();
+            let dir = args.value_of("dir").unwrap();
+            let paths = get_paths(args)?;
+            let paths: Option<Vec<&str>> = paths.as_ref()
+                .map(|paths| paths.iter().map(String::as_str).collect());
+            let paths = paths.as_deref();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let mut file = match File::open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            };
+            let mut reader = BufReader::new(&mut file);
+
+            let pak = Pak::from_reader(
+                &mut reader,
+                Options {
+                    variant,
+                    ignore_magic,
+                    magic,
+                    encoding,
+                    force_version,
+                    encryption_key: encryption_key.clone(),
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
+                },
+            )?;
+
+            drop(reader);
+
+            let differences = verify(&pak, &mut file, dir, VerifyOptions {
+                paths,
+                thread_count,
+                encryption_key,
+            })?;
+
+            let sep = if null_separated { '\0' } else { '\n' };
+            if differences.is_empty() {
+                print!("All ok{}", sep);
+            } else {
+                for difference in &differences {
+                    print!("{}{}", difference, sep);
+                }
+                print!("Found {} difference(s){}", differences.len(), sep);
+                std::process::exit(error_count_exit_code(differences.len()));
+            }
+        }
+        ("hash", Some(args)) => {
+            let null_separated = args.is_present("print0");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let thread_count = get_threads(args)?;
+            let recompute = args.is_present("recompute");
+            let algorithm = args.value_of("digest").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let paths = get_paths(args)?;
+            let paths: Option<Vec<&str>> = paths.as_ref()
+                .map(|paths| paths.iter().map(String::as_str).collect());
+            let paths = paths.as_deref();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let mut file = match File::open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            };
+            let mut reader = BufReader::new(&mut file);
+
+            let pak = Pak::from_reader(
+                &mut reader,
+                Options {
+                    variant,
+                    ignore_magic,
+                    magic,
+                    encoding,
+                    force_version,
+                    encryption_key: encryption_key.clone(),
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
+                },
+            )?;
+
+            drop(reader);
+
+            let hashes = hash(&pak, &mut file, HashOptions {
+                paths,
+                thread_count,
+                encryption_key,
+                recompute,
+                algorithm,
+            })?;
+
+            let sep = if null_separated { '\0' } else { '\n' };
+            for hash in &hashes {
+                print!("{}{}", hash, sep);
+            }
+        }
+        ("grep", Some(args)) => {
+            let null_separated = args.is_present("print0");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let thread_count = get_threads(args)?;
+            let files_with_matches = args.is_present("files-with-matches");
+            let path = args.value_of("package").unwrap();
+            let paths = get_paths(args)?;
+            let paths: Option<Vec<&str>> = paths.as_ref()
+                .map(|paths| paths.iter().map(String::as_str).collect());
+            let paths = paths.as_deref();
+
+            let pattern = args.value_of("pattern").unwrap();
+            let pattern = if args.is_present("hex") {
+                GrepPattern::parse_hex(pattern)?
+            } else {
+                GrepPattern::text(pattern, args.is_present("ignore-case"), args.is_present("utf16"))?
+            };
+
+            let max_count = if let Some(max_count) = args.value_of("max-count") {
+                Some(max_count.parse()?)
+            } else {
+                None
+            };
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let mut file = match File::open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            };
+            let mut reader = BufReader::new(&mut file);
+
+            let pak = Pak::from_reader(
+                &mut reader,
+                Options {
+                    variant,
+                    ignore_magic,
+                    magic,
+                    encoding,
+                    force_version,
+                    encryption_key: encryption_key.clone(),
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
+                },
+            )?;
+
+            drop(reader);
+
+            let matches = grep(&pak, &mut file, &pattern, GrepOptions {
+                paths,
+                thread_count,
+                encryption_key,
+                max_count,
+            })?;
+
+            let sep = if null_separated { '\0' } else { '\n' };
+            for found in &matches {
+                if files_with_matches {
+                    print!("{}{}", found.filename, sep);
+                } else {
+                    for offset in &found.offsets {
+                        print!("{}:{}{}", found.filename, offset, sep);
+                    }
+                }
+            }
+
+            if matches.is_empty() {
+                std::process::exit(EXIT_FAILURE);
+            }
+        }
+        ("ranges", Some(args)) => {
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let include_index = args.is_present("include-index");
+            let paths = get_paths(args)?;
+            let paths: Option<Vec<&str>> = paths.as_ref()
+                .map(|paths| paths.iter().map(String::as_str).collect());
+
+            if is_toc(path) {
+                return Err(Error::new("ranges is not supported for IoStore containers".to_string())
+                    .with_kind(ErrorKind::InvalidArgument));
+            }
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let pak = open_package(path, Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key,
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
+            })?;
+
+            let ranges = if let Some(paths) = &paths {
+                let mut filter: Filter = paths[..].into();
+                let records = pak.filter_records(&mut filter);
+                filter.assert_all_visited()?;
+                byte_ranges(&pak, records, include_index)
+            } else {
+                byte_ranges(&pak, pak.index().records(), include_index)
+            };
+
+            // HTTP ranges include their last byte
+            let ranges: Vec<String> = ranges.iter()
+                .map(|(start, end)| format!("{}-{}", start, end - 1))
+                .collect();
+
+            if args.is_present("header") {
+                println!("bytes={}", ranges.join(","));
+            } else {
+                for range in &ranges {
+                    println!("{}", range);
+                }
+            }
+        }
+        ("locate", Some(args)) => {
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let offsets = args.values_of("offset").unwrap()
+                .map(parse_offset)
+                .collect::<Result<Vec<_>>>()?;
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let pak = Pak::from_path(path, Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key,
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
+            })?;
+
+            let mut all_found = true;
+            for offset in offsets {
+                match locate(&pak, offset) {
+                    Location::Records(records) => {
+                        for location in &records {
+                            let record = location.record;
+                            let part = match location.part {
+                                RecordPart::Header => "record header".to_string(),
+                                RecordPart::Data { offset } => format!("data offset 0x{:X}", offset),
+                                RecordPart::Block { index, start, end, uncompressed_offset } => format!(
+                                    "compression block {} of {} (0x{:X}..0x{:X}), uncompressed offset 0x{:X}",
+                                    index, record.compression_blocks().as_ref().map_or(0, Vec::len),
+                                    start, end, uncompressed_offset),
+                                RecordPart::Padding => "padding".to_string(),
+                            };
+                            println!("0x{:X}: {} (0x{:X}..0x{:X}): {}",
+                                offset, record.filename(), location.start, location.end, part);
+                        }
+                    }
+                    Location::Index { offset: index_offset } =>
+                        println!("0x{:X}: index offset 0x{:X}", offset, index_offset),
+                    Location::PathHashIndex { offset: index_offset } =>
+                        println!("0x{:X}: path hash index offset 0x{:X}", offset, index_offset),
+                    Location::FullDirectoryIndex { offset: index_offset } =>
+                        println!("0x{:X}: full directory index offset 0x{:X}", offset, index_offset),
+                    Location::Footer { offset: footer_offset } =>
+                        println!("0x{:X}: footer offset 0x{:X}", offset, footer_offset),
+                    Location::Unused => {
+                        println!("0x{:X}: not referenced by the index", offset);
+                        all_found = false;
+                    }
+                }
+            }
+
+            if !all_found {
+                std::process::exit(EXIT_FAILURE);
+            }
+        }
+        ("dedupe", Some(args)) => {
+            let null_separated = args.is_present("print0");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let pak = Pak::from_path(path, Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key,
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
+            })?;
+
+            let duplicates = find_duplicates(&pak);
+            let sep = if null_separated { '\0' } else { '\n' };
+            for group in &duplicates {
+                print!("{} ({} bytes, {} files, {} bytes wasted):{}",
+                    HexDisplay::new(&group.sha1), group.uncompressed_size, group.records.len(), group.wasted, sep);
+                for record in &group.records {
+                    print!("  {}{}", record.filename(), sep);
+                }
+            }
+            print!("{} group(s) of duplicates, {} bytes wasted{}", duplicates.len(),
+                duplicates.iter().map(|group| group.wasted).sum::<u64>(), sep);
+        }
+        ("slack", Some(args)) => {
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let ignore_padding = args.is_present("ignore-padding");
+            let dump_dir = args.value_of("dump");
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            };
+            let mut reader = BufReader::new(file);
+
+            let pak = Pak::from_reader(&mut reader, Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key,
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                ..Options::default()
+            }).map_err(|error| error.with_path_if_none(path))?;
+
+            let mut slack = find_slack(&pak, &mut reader)
+                .map_err(|error| error.with_path_if_none(path))?;
+            if ignore_padding {
+                slack.retain(|region| !region.zeroed);
+            }
+
+            if let Some(dump_dir) = dump_dir {
+                if let Err(error) = std::fs::create_dir_all(dump_dir) {
+                    return Err(Error::io_with_path(error, dump_dir));
+                }
+                for region in &slack {
+                    let out_path = Path::new(dump_dir).join(format!("slack-0x{:X}.bin", region.offset));
+                    let mut writer = match File::create(&out_path) {
+                        Ok(file) => BufWriter::new(file),
+                        Err(error) => return Err(Error::io_with_path(error, out_path)),
+                    };
+                    copy_slack(&mut reader, region, &mut writer)
+                        .and_then(|_| writer.flush().map_err(Error::from))
+                        .map_err(|error| error.with_path_if_none(&out_path))?;
+                }
+            }
+
+            let body: Vec<Vec<String>> = slack.iter()
+                .map(|region| vec![
+                    format!("0x{:X}", region.offset),
+                    region.size.to_string(),
+                    region.location.name().to_string(),
+                    if region.zeroed { "zeros" } else { "data" }.to_string(),
+                ])
+                .collect();
+            if !body.is_empty() {
+                print_table(&["Offset", "Size", "Location", "Content"],
+                    &[Align::Right, Align::Right, Align::Left, Align::Left], &body);
+                println!();
+            }
+            println!("{} unused region(s), {} bytes", slack.len(),
+                slack.iter().map(|region| region.size).sum::<u64>());
+        }
+        ("keycheck", Some(args)) => {
+            let path = args.value_of("package").unwrap();
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let mut labels = Vec::new();
+            let mut keys = Vec::new();
+            if let Some(values) = args.values_of("key") {
+                for value in values {
+                    keys.push(parse_key(value)?);
+                    labels.push(value.to_string());
+                }
+            }
+            let mut keyring: Vec<_> = get_keyring(args)?.iter().map(|(guid, key)| (guid, key.clone())).collect();
+            keyring.sort();
+            for (guid, key) in keyring {
+                labels.push(format!("keyring GUID {:032X}", guid));
+                keys.push(key);
+            }
+
+            let mut wordlist = Vec::new();
+            if let Some(wordlist_path) = args.value_of("wordlist") {
+                let text = std::fs::read_to_string(wordlist_path)
+                    .map_err(|error| Error::io_with_path(error, wordlist_path))?;
+                let mut skipped = 0usize;
+                for line in text.lines() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    match parse_key(line) {
+                        Ok(key) => wordlist.push((line.to_string(), key)),
+                        Err(_) => skipped += 1,
+                    }
+                }
+                if skipped > 0 {
+                    eprintln!("{}: skipped {} lines that aren't AES-256 keys", wordlist_path, skipped);
+                }
+            }
+
+            if keys.is_empty() && wordlist.is_empty() {
+                return Err(Error::new("no keys given".to_string()).with_kind(ErrorKind::InvalidArgument));
+            }
+
+            let options = Options {
+                ignore_magic: args.is_present("ignore-magic"),
+                magic: get_magic(args)?,
+                force_version,
+                cipher: get_cipher(args)?,
+                index_transform: get_index_transform(args)?,
+                scan_footer: get_scan_footer(args)?,
+                ..Options::default()
+            };
+
+            let mut file = File::open(path).map_err(|error| Error::io_with_path(error, path))?;
+            let mut reader = BufReader::new(&mut file);
+            let mut any_works = false;
+
+            if !keys.is_empty() {
+                let results = check_keys(&mut reader, &options, &keys)
+                    .map_err(|error| error.with_path_if_none(path))?;
+
+                for (label, result) in labels.iter().zip(results.iter()) {
+                    any_works |= result.works();
+                    print_key_check(label, result);
+                }
+            }
+
+            // only the keys of the wordlist that work are printed
+            if !wordlist.is_empty() {
+                let wordlist_keys: Vec<Vec<u8>> = wordlist.iter().map(|(_, key)| key.clone()).collect();
+                let found = search_keys(&mut reader, &options, &wordlist_keys, get_threads(args)?)
+                    .map_err(|error| error.with_path_if_none(path))?;
+
+                for (index, result) in &found {
+                    any_works = true;
+                    print_key_check(&wordlist[*index].0, result);
+                }
+            }
+
+            if !any_works {
+                std::process::exit(EXIT_FAILURE);
+            }
+        }
+        ("hexdump", Some(args)) => {
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let filename = args.value_of("path").unwrap();
+            let offset = parse_offset(args.value_of("offset").unwrap())?;
+            let length = parse_offset(args.value_of("length").unwrap())?;
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let mut file = match File::open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path))
+            };
+
+            let pak = Pak::from_file(&mut file, Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key: encryption_key.clone(),
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
+            })?;
+
+            let record = pak.record(filename).ok_or_else(||
+                Error::new("path not found in pak".to_string())
+                    .with_kind(ErrorKind::PathNotFound)
+                    .with_path(filename))?;
+
+            let data = read_record_range(record, pak.version(), pak.variant(), &mut file, offset, length, encryption_key, pak.cipher())
+                .map_err(|error| error.with_path_if_none(record.filename()))?;
+
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            write_hexdump(&mut stdout, &data, offset)?;
+        }
+        ("repair", Some(args)) => {
+            let null_separated = args.is_present("print0");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let output = args.value_of("output").unwrap();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let mut file = match File::open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            };
+            let mut reader = BufReader::new(&mut file);
+
+            let pak = Pak::from_reader(
+                &mut reader,
+                Options {
+                    variant,
+                    ignore_magic,
+                    magic,
+                    encoding,
+                    force_version,
+                    encryption_key,
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
+                },
+            )?;
+
+            drop(reader);
+
+            let repaired = repair(&pak, &mut file, output, encoding)?;
+
+            let sep = if null_separated { '\0' } else { '\n' };
+            for record in &repaired {
+                print!("{}: fixed metadata:\n{}{}", record.filename, record.diff.trim_end(), sep);
+            }
+            print!("Repaired {} record(s){}", repaired.len(), sep);
+        }
+        ("rename", Some(args)) => {
+            let null_separated = args.is_present("print0");
+            let verbose = args.is_present("verbose");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let old_path = args.value_of("old-path").unwrap();
+            let new_path = args.value_of("new-path").unwrap();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let mut file = match std::fs::OpenOptions::new().read(true).write(true).open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            };
+            let mut reader = BufReader::new(&mut file);
+
+            let pak = Pak::from_reader(
+                &mut reader,
+                Options {
+                    variant,
+                    ignore_magic,
+                    magic,
+                    encoding,
+                    force_version,
+                    encryption_key,
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    ..Options::default()
+                },
+            )?;
+
+            drop(reader);
+
+            let (_, renamed) = rename(&pak, &mut file, old_path, new_path, encoding)
+                .map_err(|error| error.with_path_if_none(path))?;
+
+            let sep = if null_separated { '\0' } else { '\n' };
+            if verbose {
+                for record in &renamed {
+                    print!("{} -> {}{}", record.old_filename, record.new_filename, sep);
+                }
+            }
+            print!("Renamed {} file(s){}", renamed.len(), sep);
+        }
+        ("set-mount-point", Some(args)) => {
+            let null_separated = args.is_present("print0");
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let mount_point = args.value_of("mount-point").unwrap();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let mut file = match std::fs::OpenOptions::new().read(true).write(true).open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            };
+            let mut reader = BufReader::new(&mut file);
+
+            let pak = Pak::from_reader(
+                &mut reader,
+                Options {
+                    variant,
+                    ignore_magic,
+                    magic,
+                    encoding,
+                    force_version,
+                    encryption_key,
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    ..Options::default()
+                },
+            )?;
+
+            drop(reader);
+
+            let old_mount_point = pak.index().mount_point().unwrap_or("").to_string();
+            set_mount_point(&pak, &mut file, mount_point, encoding)
+                .map_err(|error| error.with_path_if_none(path))?;
+
+            let sep = if null_separated { '\0' } else { '\n' };
+            print!("Changed mount point from {:?} to {:?}{}", old_mount_point, mount_point, sep);
+        }
+        ("dump-index", Some(args)) => {
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            };
+            let mut reader = BufReader::new(file);
+
+            let options = Options {
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key,
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                ..Options::default()
+            };
+
+            let footer = Pak::read_footer(&mut reader, &options)
+                .map_err(|error| error.with_path_if_none(path))?;
+            let raw_index = read_raw_index(&mut reader, &footer, encoding, options.encryption_key_for(&footer).cloned(), &*options.cipher, options.index_transform.as_ref(), &options.warnings)
+                .map_err(|error| error.with_path_if_none(path))?;
+
+            let parts = [
+                ("primary_index", Some(&raw_index.primary_index)),
+                ("encoded_records", raw_index.encoded_records.as_ref()),
+                ("path_hash_index", raw_index.path_hash_index.as_ref()),
+                ("full_directory_index", raw_index.full_directory_index.as_ref()),
+            ];
+
+            if let Some(outdir) = args.value_of("outdir") {
+                if let Err(error) = std::fs::create_dir_all(outdir) {
+                    return Err(Error::io_with_path(error, outdir));
+                }
+                for (name, data) in &parts {
+                    if let Some(data) = data {
+                        let out_path = Path::new(outdir).join(format!("{}.bin", name));
+                        if let Err(error) = std::fs::write(&out_path, data) {
+                            return Err(Error::io_with_path(error, out_path));
+                        }
+                        println!("{}: {} bytes", out_path.to_string_lossy(), data.len());
+                    }
+                }
+            } else {
+                let stdout = std::io::stdout();
+                let mut stdout = stdout.lock();
+                for (name, data) in &parts {
+                    if let Some(data) = data {
+                        writeln!(stdout, "{} ({} bytes):", name, data.len())?;
+                        write_hexdump(&mut stdout, data, 0)?;
+                        writeln!(stdout)?;
+                    }
+                }
+            }
+        }
+        ("export-index", Some(args)) => {
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let path = args.value_of("package").unwrap();
+            let output = args.value_of("output").unwrap();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let pak = Pak::from_path(path, Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key,
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
+            })?;
+
+            if args.is_present("raw") {
+                let mut file = match File::open(path) {
+                    Ok(file) => file,
+                    Err(error) => return Err(Error::io_with_path(error, path)),
+                };
+                if output == "-" {
+                    let stdout = std::io::stdout();
+                    let mut writer = BufWriter::new(stdout.lock());
+                    copy_raw_index(&pak, &mut file, &mut writer)?;
+                    writer.flush()?;
+                } else {
+                    let mut writer = match File::create(output) {
+                        Ok(file) => BufWriter::new(file),
+                        Err(error) => return Err(Error::io_with_path(error, output)),
+                    };
+                    copy_raw_index(&pak, &mut file, &mut writer)
+                        .map_err(|error| error.with_path_if_none(path))?;
+                    if let Err(error) = writer.flush() {
+                        return Err(Error::io_with_path(error, output));
+                    }
+                }
+                return Ok(());
+            }
+
+            if output == "-" {
+                let stdout = std::io::stdout();
+                let mut writer = BufWriter::new(stdout.lock());
+                write_index_template(&mut writer, &pak)?;
+                writer.flush()?;
+            } else {
+                let mut writer = match File::create(output) {
+                    Ok(file) => BufWriter::new(file),
+                    Err(error) => return Err(Error::io_with_path(error, output)),
+                };
+                if let Err(error) = write_index_template(&mut writer, &pak).and_then(|_| writer.flush()) {
+                    return Err(Error::io_with_path(error, output));
+                }
+            }
+        }
+        ("salvage", Some(args)) => {
+            let variant: Variant = args.value_of("variant").unwrap().try_into()?;
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let ignore_checksums = args.is_present("ignore-checksums");
+            let human_readable = args.is_present("human-readable");
             let null_separated = args.is_present("print0");
-            let ignore_magic = args.is_present("ignore-magic");
-            let ignore_null_checksums = args.is_present("ignore-null-checksums");
-            let abort_on_error = args.is_present("abort-on-error");
             let verbose = args.is_present("verbose");
-            let variant = args.value_of("variant").unwrap().try_into()?;
-            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let thread_count = get_threads(args)?;
             let path = args.value_of("package").unwrap();
-            let paths = get_paths(args)?;
-            let paths: Option<&[&str]> = if let Some(paths) = &paths {
-                Some(paths)
-            } else {
-                None
-            };
-
-            let force_version = if let Some(version) = args.value_of("force-version") {
-                Some(version.parse()?)
+            let version = if let Some(version) = args.value_of("version") {
+                version.parse()?
             } else {
-                None
+                variant.spec().default_version()
             };
 
-            let encryption_key = if let Some(key) = args.value_of("encryption-key") {
-                Some(
-                    base64::decode(
-                        key.parse::<String>()
-                            .expect("Failed to read encryption key."),
-                    )
-                    .expect("Failed to parse encryption key."),
-                )
-            } else {
-                None
-            };
+            let encryption_key = get_encryption_key(args, path)?;
 
             let mut file = match File::open(path) {
                 Ok(file) => file,
                 Err(error) => return Err(Error::io_with_path(error, path)),
             };
-            let mut reader = BufReader::new(&mut file);
-
-            let pak = Pak::from_reader(
-                &mut reader,
-                Options {
-                    variant,
-                    ignore_magic,
-                    encoding,
-                    force_version,
-                    encryption_key,
-                },
-            )?;
 
-            let options = CheckOptions {
+            let pak = salvage(&mut file, SalvageOptions {
                 variant,
-                abort_on_error,
-                ignore_null_checksums,
-                null_separated,
-                verbose,
-                thread_count: get_threads(args)?,
-                paths,
-            };
+                version,
+                ignore_checksums,
+            }).map_err(|error| error.with_path_if_none(path))?;
 
-            let error_count = check(&pak, &mut file, options)?;
+            if pak.index().records().is_empty() {
+                return Err(Error::new("no records found".to_string()).with_path(path));
+            }
 
-            let sep = if null_separated { '\0' } else { '\n' };
-            if error_count == 0 {
-                print!("All ok{}", sep);
-            } else {
-                print!("Found {} error(s){}", error_count, sep);
-                std::process::exit(1);
+            let rebuild = args.value_of("rebuild");
+            if let Some(rebuild) = rebuild {
+                write_rebuilt_pak(&pak, &mut file, rebuild, encoding)?;
+            }
+
+            if let Some(outdir) = args.value_of("outdir") {
+                unpack(
+                    &pak,
+                    &mut file,
+                    outdir,
+                    UnpackOptions {
+                        verbose,
+                        null_separated,
+                        thread_count,
+                        encryption_key,
+                        ..UnpackOptions::default()
+                    },
+                )?;
+            } else if rebuild.is_none() {
+                list(
+                    pak,
+                    ListOptions {
+                        style: ListStyle::Table {
+                            human_readable,
+                            no_header: false,
+                            ratio: false,
+                            blocks: false,
+                        },
+                        ..ListOptions::default()
+                    },
+                )?;
             }
         }
         ("unpack", Some(args)) => {
@@ -779,16 +3906,43 @@ fn run(matches: &ArgMatches) -> Result<()> {
             let null_separated = args.is_present("print0");
             let verbose = args.is_present("verbose");
             let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
             let dirname_from_compression = args.is_present("dirname-from-compression");
             let encoding = args.value_of("encoding").unwrap().try_into()?;
-            let thread_count = get_threads(args)?;
             let path = args.value_of("package").unwrap();
-            let paths = get_paths(args)?;
-            let paths: Option<&[&str]> = if let Some(paths) = &paths {
-                Some(paths)
+            let pak_timestamp = args.is_present("pak-timestamp");
+            let allow_unsafe_paths = args.is_present("allow-unsafe-paths");
+            let strip_prefix = args.value_of("strip-prefix");
+            let flatten = args.is_present("flatten");
+            let name_policy: Option<NamePolicy> = args.value_of("invalid-names").map(|name| name.try_into()).transpose()?;
+            let name_policy = name_policy.unwrap_or_default();
+            let normalize_nfc = if args.is_present("nfc") {
+                true
+            } else if args.is_present("no-nfc") {
+                false
             } else {
-                None
+                NORMALIZE_NFC_DEFAULT
             };
+            let digest: Option<DigestAlgorithm> = args.value_of("digest").map(|name| name.try_into()).transpose()?;
+            let preserve_timestamps = pak_timestamp || args.is_present("preserve-timestamps");
+            let order: WorkOrder = args.value_of("order").unwrap().try_into()?;
+            let skip_errors = args.is_present("skip-errors");
+            let fallback_decompression = args.is_present("fallback-decompression");
+            let overwrite = if args.is_present("no-clobber") {
+                Overwrite::Never
+            } else if args.is_present("update") {
+                Overwrite::Update
+            } else {
+                Overwrite::Always
+            };
+            let paths = get_paths(args)?;
+            let paths: Option<Vec<&str>> = paths.as_ref()
+                .map(|paths| paths.iter().map(String::as_str).collect());
+            let paths = paths.as_deref();
+            let predicate = get_predicate(args)?;
 
             let force_version = if let Some(version) = args.value_of("force-version") {
                 Some(version.parse()?)
@@ -796,38 +3950,224 @@ fn run(matches: &ArgMatches) -> Result<()> {
                 None
             };
 
-            let encryption_key = if let Some(key) = args.value_of("encryption-key") {
-                Some(
-                    base64::decode(
-                        key.parse::<String>()
-                            .expect("Failed to read encryption key."),
-                    )
-                    .expect("Failed to parse encryption key."),
-                )
-            } else {
-                None
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let options = Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key: encryption_key.clone(),
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
             };
 
+            if skip_errors && (path == STDIN_PATH || is_url(path) || is_toc(path)) {
+                return Err(Error::new(
+                    "--skip-errors is not supported when unpacking from stdin, a URL or an IoStore container".to_string())
+                    .with_kind(ErrorKind::InvalidArgument));
+            }
+
+            if path == STDIN_PATH {
+                if pak_timestamp || args.is_present("to-zip") || args.is_present("to-tar") {
+                    return Err(Error::new(
+                        "--pak-timestamp, --to-zip and --to-tar are not supported when unpacking from stdin".to_string())
+                        .with_kind(ErrorKind::InvalidArgument));
+                }
+
+                let pak = open_index_file(args, options)?;
+                let stdin = std::io::stdin();
+                let progress_output = get_progress_output(matches, args)?;
+                let summary = get_summary(matches, &progress_output);
+                let result = unpack_from_stream(
+                    &pak,
+                    stdin.lock(),
+                    outdir,
+                    UnpackOptions {
+                        dirname_from_compression,
+                        verbose,
+                        null_separated,
+                        paths,
+                        encryption_key,
+                        progress: get_progress_callback(&summary, &progress_output),
+                        overwrite,
+                        preserve_timestamps,
+                        allow_unsafe_paths,
+                        predicate: predicate.as_ref(),
+                        digest,
+                        fallback_decompression,
+                        strip_prefix,
+                        flatten,
+                        name_policy,
+                        normalize_nfc,
+                        ..UnpackOptions::default()
+                    },
+                );
+                if let Some(output) = &progress_output {
+                    output.finish(&result);
+                }
+                if let (Ok(_), Some(summary)) = (&result, &summary) {
+                    summary.print("Unpacked");
+                }
+                return result;
+            }
+
+            if is_url(path) {
+                if pak_timestamp || args.is_present("to-zip") || args.is_present("to-tar") {
+                    return Err(Error::new(
+                        "--pak-timestamp, --to-zip and --to-tar are not supported when unpacking from a URL".to_string()));
+                }
+
+                let (pak, mut reader) = open_url(path, options)?;
+                let progress_output = get_progress_output(matches, args)?;
+                let summary = get_summary(matches, &progress_output);
+                let result = unpack_from_reader(
+                    &pak,
+                    &mut reader,
+                    outdir,
+                    UnpackOptions {
+                        dirname_from_compression,
+                        verbose,
+                        null_separated,
+                        paths,
+                        encryption_key,
+                        progress: get_progress_callback(&summary, &progress_output),
+                        overwrite,
+                        preserve_timestamps,
+                        allow_unsafe_paths,
+                        predicate: predicate.as_ref(),
+                        digest,
+                        order,
+                        fallback_decompression,
+                        strip_prefix,
+                        flatten,
+                        name_policy,
+                        normalize_nfc,
+                        ..UnpackOptions::default()
+                    },
+                );
+                if let Some(output) = &progress_output {
+                    output.finish(&result);
+                }
+                if let (Ok(_), Some(summary)) = (&result, &summary) {
+                    summary.print("Unpacked");
+                }
+                return result;
+            }
+
+            if is_toc(path) {
+                if pak_timestamp || args.is_present("to-zip") || args.is_present("to-tar") {
+                    return Err(Error::new(
+                        "--pak-timestamp, --to-zip and --to-tar are not supported for IoStore containers".to_string()));
+                }
+                if fallback_decompression {
+                    return Err(Error::new(
+                        "--fallback-decompression is not supported for IoStore containers".to_string())
+                        .with_kind(ErrorKind::InvalidArgument));
+                }
+
+                let toc = Toc::from_path(path, encryption_key)?;
+                let mut partitions = toc.open_partitions(path)?;
+                let progress_output = get_progress_output(matches, args)?;
+                let summary = get_summary(matches, &progress_output);
+                let result = unpack_toc(
+                    &toc,
+                    &mut partitions,
+                    outdir,
+                    UnpackOptions {
+                        dirname_from_compression,
+                        verbose,
+                        null_separated,
+                        paths,
+                        progress: get_progress_callback(&summary, &progress_output),
+                        overwrite,
+                        allow_unsafe_paths,
+                        predicate: predicate.as_ref(),
+                        digest,
+                        order,
+                        strip_prefix,
+                        flatten,
+                        name_policy,
+                        normalize_nfc,
+                        ..UnpackOptions::default()
+                    },
+                );
+                if let Some(output) = &progress_output {
+                    output.finish(&result);
+                }
+                if let (Ok(_), Some(summary)) = (&result, &summary) {
+                    summary.print("Unpacked");
+                }
+                return result;
+            }
+
             let mut file = match File::open(path) {
                 Ok(file) => file,
                 Err(error) => return Err(Error::io_with_path(error, path)),
             };
-            let mut reader = BufReader::new(&mut file);
+            let pak = Pak::from_reader(&mut BufReader::new(&mut file), options)?;
 
-            let pak = Pak::from_reader(
-                &mut reader,
-                Options {
-                    variant,
-                    ignore_magic,
-                    encoding,
-                    force_version,
-                    encryption_key: encryption_key.clone(),
-                },
-            )?;
+            let fallback_timestamp = if pak_timestamp {
+                let modified = file.metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .map_err(|error| Error::io_with_path(error, path))?;
+                Some(modified.duration_since(UNIX_EPOCH)?.as_secs())
+            } else {
+                None
+            };
 
-            drop(reader);
+            let archive = if let Some(archive_path) = args.value_of("to-zip") {
+                Some((ArchiveFormat::Zip, archive_path))
+            } else {
+                args.value_of("to-tar").map(|archive_path| (ArchiveFormat::Tar, archive_path))
+            };
+
+            if let Some((format, archive_path)) = archive {
+                let progress_output = get_progress_output(matches, args)?;
+                let summary = get_summary(matches, &progress_output);
+                let options = ArchiveOptions {
+                    paths,
+                    verbose,
+                    null_separated,
+                    encryption_key,
+                    fallback_timestamp,
+                    allow_unsafe_paths,
+                    predicate: predicate.as_ref(),
+                    progress: get_progress_callback(&summary, &progress_output),
+                    ..ArchiveOptions::default()
+                };
+                let result = if archive_path == "-" {
+                    let stdout = std::io::stdout();
+                    unpack_to_archive(&pak, &mut file, BufWriter::new(stdout.lock()), format, options)
+                } else {
+                    match File::create(archive_path) {
+                        Ok(archive_file) => unpack_to_archive(&pak, &mut file, BufWriter::new(archive_file), format, options)
+                            .map_err(|error| error.with_path_if_none(archive_path)),
+                        Err(error) => Err(Error::io_with_path(error, archive_path)),
+                    }
+                };
+                if let Some(output) = &progress_output {
+                    output.finish(&result);
+                }
+                if let (Ok(_), Some(summary)) = (&result, &summary) {
+                    summary.print("Unpacked");
+                }
+                return result;
+            }
 
-            unpack(
+            let (thread_count, io_strategy, read_size) = get_tuned_io(args, path)?;
+            let progress_output = get_progress_output(matches, args)?;
+            let summary = get_summary(matches, &progress_output);
+            let result = unpack_with_report(
                 &pak,
                 &mut file,
                 outdir,
@@ -837,24 +4177,82 @@ fn run(matches: &ArgMatches) -> Result<()> {
                     null_separated,
                     paths,
                     thread_count,
+                    max_memory: get_max_memory(args)?,
+                    io_strategy,
+                    read_size,
                     encryption_key,
+                    progress: get_progress_callback(&summary, &progress_output),
+                    overwrite,
+                    preserve_timestamps,
+                    fallback_timestamp,
+                    allow_unsafe_paths,
+                    predicate: predicate.as_ref(),
+                    digest,
+                    order,
+                    skip_errors,
+                    fallback_decompression,
+                    strip_prefix,
+                    flatten,
+                    name_policy,
+                    normalize_nfc,
                 },
-            )?;
+            );
+            if let Some(output) = &progress_output {
+                for failure in result.iter().flatten() {
+                    output.error(Some(&failure.filename), failure.error.kind().as_str(), &failure.error.error_type().to_string());
+                }
+                output.finish(&result);
+            }
+            let failures = result?;
+            if let Some(summary) = &summary {
+                summary.print("Unpacked");
+            }
+
+            if !failures.is_empty() {
+                let errors_path = match args.value_of("errors-file") {
+                    Some(errors_path) => Path::new(errors_path).to_path_buf(),
+                    None => Path::new(outdir).join("errors.json"),
+                };
+                let report = serde_json::to_string(&Document::new(UnpackReport {
+                    package: path,
+                    error_count: failures.len(),
+                    errors: &failures,
+                }))?;
+                let mut writer = match File::create(&errors_path) {
+                    Ok(file) => BufWriter::new(file),
+                    Err(error) => return Err(Error::io_with_path(error, errors_path)),
+                };
+                if let Err(error) = writeln!(writer, "{}", report).and_then(|_| writer.flush()) {
+                    return Err(Error::io_with_path(error, errors_path));
+                }
+
+                eprintln!("{} file(s) could not be unpacked, see {}", failures.len(), errors_path.to_string_lossy());
+                std::process::exit(error_count_exit_code(failures.len()));
+            }
         }
         ("pack", Some(args)) => {
-            let variant = args.value_of("variant").unwrap().try_into()?;
+            let index_template = if let Some(template_path) = args.value_of("index-template") {
+                Some(read_index_template(template_path)?)
+            } else {
+                None
+            };
+            let template_variant = index_template.as_ref().and_then(|template| template.variant);
+            let variant: Variant = match template_variant {
+                Some(variant) if args.occurrences_of("variant") == 0 => variant,
+                _ => args.value_of("variant").unwrap().try_into()?,
+            };
             let thread_count = get_threads(args)?;
             let null_separated = args.is_present("print0");
             let verbose = args.is_present("verbose");
-            let mount_point = args.value_of("mount-point");
+            let mount_point = args.value_of("mount-point").or_else(||
+                index_template.as_ref().and_then(|template| template.mount_point.as_deref()));
             let encoding = args.value_of("encoding").unwrap().try_into()?;
             let version = if let Some(version) = args.value_of("version") {
                 version.parse()?
+            } else if let Some(version) = index_template.as_ref().and_then(|template| template.version) {
+                version
             } else {
-                match variant {
-                    Variant::Standard => 3,
-                    Variant::ConanExiles => 4,
-                }
+                variant.spec().default_version()
             };
             let compression_block_size =
                 parse_size(args.value_of("compression-block-size").unwrap())?;
@@ -877,7 +4275,7 @@ fn run(matches: &ArgMatches) -> Result<()> {
             if compression_min_size > u64::MAX as usize {
                 return Err(Error::new(format!(
                     "--compression-min-size too big: {}",
-                    compression_block_size
+                    compression_min_size
                 )));
             }
             let compression_min_size =
@@ -893,42 +4291,323 @@ fn run(matches: &ArgMatches) -> Result<()> {
                 parse_compression_method(args.value_of("compression-method").unwrap())?;
             let compression_level =
                 parse_compression_level(args.value_of("compression-level").unwrap())?;
+            let mut no_compress_exts: HashSet<String> = DEFAULT_NO_COMPRESS_EXTS
+                .iter()
+                .map(|ext| ext.to_string())
+                .collect();
+            if let Some(exts) = args.values_of("no-compress-ext") {
+                no_compress_exts.extend(exts.map(normalize_ext));
+            }
+            if let Some(exts) = args.values_of("compress-ext") {
+                for ext in exts {
+                    if ext.eq_ignore_ascii_case("all") {
+                        no_compress_exts.clear();
+                    } else {
+                        no_compress_exts.remove(&normalize_ext(ext));
+                    }
+                }
+            }
             let path = args.value_of("package").unwrap();
-            let paths = if let Some(path_strs) = args.values_of("paths") {
-                let mut paths = Vec::<PackPath>::new();
-
+            let mut paths = Vec::<PackPath>::new();
+            if let Some(path_strs) = args.values_of("paths") {
                 for path in path_strs {
                     paths.push(path.try_into()?);
                 }
+            }
 
-                paths
-            } else {
+            if let Some(files_from) = args.value_of("files-from") {
+                // plain file names, the path syntax is not supported here
+                for path in read_path_list(files_from)? {
+                    paths.push(PackPath::new(path));
+                }
+            }
+
+            if let Some(manifest) = args.value_of("manifest") {
+                paths.append(&mut read_manifest(manifest)?);
+            }
+
+            if let Some(template) = &index_template {
+                paths.extend(template.files.iter().cloned());
+            }
+
+            if paths.is_empty() {
                 return Err(Error::new("missing argument: PATH".to_string()));
+            }
+
+            let encrypted = args.is_present("encrypt");
+            let encryption_key = get_default_key(args, &get_keyring(args)?)?;
+            check_encrypt_key(encrypted, &encryption_key)?;
+
+            let progress_output = get_progress_output(matches, args)?;
+            let summary = get_summary(matches, &progress_output);
+            let options = PackOptions {
+                variant,
+                version,
+                magic: get_magic(args)?,
+                mount_point,
+                compression_method,
+                compression_block_size,
+                compression_min_size,
+                compression_level,
+                no_compress_exts,
+                encrypted,
+                encryption_key,
+                encryption_guid: match args.value_of("encryption-guid") {
+                    Some(guid) => parse_guid(guid)?,
+                    None => 0,
+                },
+                cipher: get_cipher(args)?,
+                encoding,
+                verbose,
+                null_separated,
+                thread_count,
+                max_memory: get_max_memory(args)?,
+                dedupe: args.is_present("dedupe"),
+                progress: get_progress_callback(&summary, &progress_output),
+                ..PackOptions::default()
+            };
+            // read the key first, so a broken key doesn't waste a whole pack run
+            let signing_key = if let Some(key_path) = args.value_of("signing-key") {
+                Some(read_private_key(key_path)?)
+            } else {
+                None
+            };
+
+            let result = if args.value_of("format") == Some("iostore") {
+                pack_iostore(path, &paths, options).map(|_| ())
+            } else {
+                pack(path, &paths, options).map(|_| ())
+            };
+            if let Some(output) = &progress_output {
+                output.finish(&result);
+            }
+            result?;
+            if let Some(summary) = &summary {
+                summary.print("Packed");
+            }
+
+            if let Some(key) = &signing_key {
+                let pak_path = if args.value_of("format") == Some("iostore") {
+                    Path::new(path).with_extension("pak")
+                } else {
+                    Path::new(path).to_path_buf()
+                };
+                sign(pak_path, key)?;
+            }
+        }
+        ("bench-compress", Some(args)) => {
+            let dir = args.value_of("dir").unwrap();
+            let sample_size = parse_size(args.value_of("sample-size").unwrap())? as u64;
+            let levels = args.value_of("levels").unwrap().split(',')
+                .map(|level| parse_compression_level(level).map(NonZeroU32::get))
+                .collect::<Result<Vec<_>>>()?;
+            let block_sizes = args.value_of("block-sizes").unwrap().split(',')
+                .map(|size| match parse_size(size.trim()) {
+                    Ok(size) if size > 0 && size <= u32::MAX as usize => Ok(size as u32),
+                    _ => Err(Error::new(format!("illegal block size: {:?}", size))
+                        .with_kind(ErrorKind::InvalidArgument)),
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let report = bench_compress(dir, &BenchOptions {
+                sample_size,
+                levels,
+                block_sizes,
+                no_compress_exts: DEFAULT_NO_COMPRESS_EXTS.iter().map(|ext| ext.to_string()).collect(),
+            })?;
+
+            println!("Sample: {} of {} file(s)", format_size(report.sample_size), report.file_count);
+            println!();
+
+            let body: Vec<Vec<String>> = report.results.iter()
+                .map(|result| {
+                    let seconds = result.elapsed.as_secs_f64();
+                    vec![
+                        if result.compression_method == COMPR_ZLIB { "zlib" } else { "none" }.to_string(),
+                        if result.level == 0 { "-".to_string() } else { result.level.to_string() },
+                        if result.block_size == 0 { "-".to_string() } else { format_size(result.block_size as u64) },
+                        format_size(result.compressed_size),
+                        format!("{:.1} %", result.compressed_size as f64 * 100.0 / report.sample_size.max(1) as f64),
+                        format!("{:.2} s", seconds),
+                        if seconds > 0.0 { format!("{}/s", format_size((report.sample_size as f64 / seconds) as u64)) } else { "-".to_string() },
+                    ]
+                })
+                .collect();
+            print_table(&["Method", "Level", "Block-Size", "Size", "Ratio", "Time", "Speed"],
+                &[Align::Left, Align::Right, Align::Right, Align::Right, Align::Right, Align::Right, Align::Right], &body);
+
+            if let Some(result) = report.recommendation() {
+                println!();
+                if result.compression_method == COMPR_ZLIB {
+                    println!("Recommended: --compression-method=zlib --compression-level={} --compression-block-size={}",
+                        result.level, result.block_size);
+                } else {
+                    println!("Recommended: --compression-method=none (zlib hardly makes the files smaller)");
+                }
+            }
+        }
+        ("patch", Some(args)) => {
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let thread_count = get_threads(args)?;
+            let null_separated = args.is_present("print0");
+            let verbose = args.is_present("verbose");
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let base_path = args.value_of("base").unwrap();
+            let path = args.value_of("package").unwrap();
+            let compression_method =
+                parse_compression_method(args.value_of("compression-method").unwrap())?;
+            let compression_level =
+                parse_compression_level(args.value_of("compression-level").unwrap())?;
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
             };
 
-            pack(
+            let mut paths = Vec::<PackPath>::new();
+            for path in args.values_of("source").unwrap() {
+                paths.push(path.try_into()?);
+            }
+
+            let encrypted = args.is_present("encrypt");
+            let encryption_key = get_encryption_key(args, base_path)?;
+            check_encrypt_key(encrypted, &encryption_key)?;
+
+            let mut base_file = match File::open(base_path) {
+                Ok(file) => file,
+                Err(error) => return Err(Error::io_with_path(error, base_path)),
+            };
+            let base = Pak::from_reader(&mut BufReader::new(&mut base_file), Options {
+                variant,
+                ignore_magic,
+                magic,
+                encoding,
+                force_version,
+                encryption_key: encryption_key.clone(),
+                keyring: get_keyring(args)?,
+                cipher: get_cipher(args)?,
+                index_transform,
+                name_list,
+                harvest_names,
+                keep_unknown: false,
+                detect_variant: !args.is_present("no-detect-variant"),
+                detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                scan_footer: get_scan_footer(args)?,
+                warnings: Warnings::Log,
+            })?;
+
+            let version = if let Some(version) = args.value_of("version") {
+                version.parse()?
+            } else if base.variant().spec().can_write(base.version()) {
+                base.version()
+            } else {
+                let version = base.variant().spec().default_version();
+                warn!("can't write packages of version {}, using version {}", base.version(), version);
+                version
+            };
+
+            if !Path::new(path).file_stem().is_some_and(|stem| stem.to_string_lossy().ends_with("_P")) {
+                warn!("the name of the patch package doesn't end in _P: {}", path);
+            }
+
+            let mount_point = args.value_of("mount-point").or_else(|| base.index().mount_point());
+
+            let progress_output = get_progress_output(matches, args)?;
+            let result = patch(&base, &mut base_file, path, &paths, encryption_key.clone(), PackOptions {
+                variant: base.variant(),
+                version,
+                magic,
+                mount_point,
+                compression_method,
+                compression_level,
+                encrypted,
+                encryption_key,
+                cipher: get_cipher(args)?,
+                encoding,
+                verbose,
+                null_separated,
+                thread_count,
+                progress: progress_output.as_ref().map(|output| output as &dyn ProgressCallback),
+                ..PackOptions::default()
+            });
+            if let Some(output) = &progress_output {
+                output.finish(&result);
+            }
+            let (_, summary) = result?;
+
+            let sep = if null_separated { '\0' } else { '\n' };
+            eprint!("{} added, {} changed, {} unchanged{}", summary.added, summary.changed, summary.unchanged, sep);
+        }
+        ("serve", Some(args)) => {
+            let variant = args.value_of("variant").unwrap().try_into()?;
+            let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
+            let verbose = args.is_present("verbose");
+            let encoding = args.value_of("encoding").unwrap().try_into()?;
+            let thread_count = get_threads(args)?;
+            let listen = args.value_of("listen").unwrap();
+            let path = args.value_of("package").unwrap();
+
+            let force_version = if let Some(version) = args.value_of("force-version") {
+                Some(version.parse()?)
+            } else {
+                None
+            };
+
+            let encryption_key = get_encryption_key(args, path)?;
+
+            let pak = Pak::from_path(
                 path,
-                &paths,
-                PackOptions {
+                Options {
                     variant,
-                    version,
-                    mount_point,
-                    compression_method,
-                    compression_block_size,
-                    compression_min_size,
-                    compression_level,
+                    ignore_magic,
+                    magic,
                     encoding,
-                    verbose,
-                    null_separated,
-                    thread_count,
+                    force_version,
+                    encryption_key: encryption_key.clone(),
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
                 },
             )?;
+
+            serve(&pak, path, ServeOptions {
+                listen,
+                thread_count,
+                encryption_key,
+                verbose,
+            })?;
         }
-        #[cfg(target_os = "linux")]
+        #[cfg(all(target_os = "linux", feature = "fuse"))]
         ("mount", Some(args)) => {
             let foreground = args.is_present("foreground");
             let debug = args.is_present("debug");
+            let cache_size = if let Some(size) = args.value_of("cache-size") {
+                parse_size(size)?
+            } else {
+                DEFAULT_ENTRY_CACHE_SIZE
+            };
             let ignore_magic = args.is_present("ignore-magic");
+            let magic = get_magic(args)?;
+            let index_transform = get_index_transform(args)?;
+            let name_list = get_name_list(args)?;
+            let harvest_names = args.is_present("harvest-names");
             let variant = args.value_of("variant").unwrap().try_into()?;
             let encoding = args.value_of("encoding").unwrap().try_into()?;
             let path = args.value_of("package").unwrap();
@@ -940,17 +4619,7 @@ fn run(matches: &ArgMatches) -> Result<()> {
                 None
             };
 
-            let encryption_key = if let Some(key) = args.value_of("encryption-key") {
-                Some(
-                    base64::decode(
-                        key.parse::<String>()
-                            .expect("Failed to read encryption key."),
-                    )
-                    .expect("Failed to parse encryption key."),
-                )
-            } else {
-                None
-            };
+            let encryption_key = get_encryption_key(args, path)?;
 
             let mut file = match File::open(path) {
                 Ok(file) => file,
@@ -963,15 +4632,26 @@ fn run(matches: &ArgMatches) -> Result<()> {
                 Options {
                     variant,
                     ignore_magic,
+                    magic,
                     encoding,
                     force_version,
                     encryption_key,
+                    keyring: get_keyring(args)?,
+                    cipher: get_cipher(args)?,
+                    index_transform,
+                    name_list,
+                    harvest_names,
+                    keep_unknown: false,
+                    detect_variant: !args.is_present("no-detect-variant"),
+                    detect_block_offsets: !args.is_present("no-detect-block-offsets"),
+                    scan_footer: get_scan_footer(args)?,
+                    warnings: Warnings::Log,
                 },
             )?;
 
             drop(reader);
 
-            mount(pak, file, mountpt, MountOptions { foreground, debug })
+            mount(pak, file, mountpt, MountOptions { foreground, debug, cache_size })
                 .map_err(|error| error.with_path_if_none(path))?;
         }
         ("", _) => {
@@ -1026,7 +4706,9 @@ mod windows {
         let _ = std::io::stdin().read(&mut buf);
     }
 
-    pub fn pause_if_owns_terminal() {
+    // true if u4pak was started by double click or drag and drop, i.e. no
+    // other process shares its console window
+    pub fn owns_terminal() -> bool {
         let mut process_list = [0, 0];
         let count = unsafe {
             GetConsoleProcessList(
@@ -1035,8 +4717,42 @@ mod windows {
             )
         };
 
-        if count == 1 {
+        count == 1
+    }
+
+    pub fn pause_if_owns_terminal() {
+        if owns_terminal() {
             pause();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths_app() -> App<'static, 'static> {
+        App::new("test").arg(Arg::with_name("package").index(1)).arg(arg_paths()).arg(arg_files_from())
+    }
+
+    #[test]
+    fn get_paths_files_from() {
+        let dir = std::env::temp_dir().join(format!("u4pak-get-paths-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.txt");
+        let list = dir.join("list.txt");
+        std::fs::write(&empty, b"").unwrap();
+        std::fs::write(&list, b"a.txt\nb.txt\n").unwrap();
+
+        let args = paths_app().get_matches_from(vec!["test", "Game.pak"]);
+        assert_eq!(get_paths(&args).unwrap(), None);
+
+        let args = paths_app().get_matches_from(vec!["test", "Game.pak", "--files-from", empty.to_str().unwrap()]);
+        assert_eq!(get_paths(&args).unwrap(), Some(vec![]));
+
+        let args = paths_app().get_matches_from(vec!["test", "Game.pak", "c.txt", "--files-from", list.to_str().unwrap()]);
+        assert_eq!(get_paths(&args).unwrap(), Some(vec!["c.txt".to_string(), "a.txt".to_string(), "b.txt".to_string()]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{stderr, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use terminal_size::{terminal_size, Width};
+
+use u4pak::serialize::Document;
+use u4pak::progress::{ProgressCallback, ProgressInfo};
+use u4pak::util::format_size;
+use u4pak::Result;
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct State {
+    last_redraw: Option<Instant>,
+    line_len: usize,
+}
+
+// Progress bar on stderr, so it doesn't get mixed into the --verbose output
+// when that is redirected.
+#[derive(Debug)]
+pub struct ProgressBar {
+    start: Instant,
+    state: Mutex<State>,
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            state: Mutex::new(State {
+                last_redraw: None,
+                line_len: 0,
+            }),
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Ok(state) = self.state.lock() {
+            if state.line_len > 0 {
+                let _ = writeln!(stderr());
+            }
+        }
+    }
+
+    fn draw(&self, state: &mut State, info: &ProgressInfo) {
+        let ratio = if info.total_bytes > 0 {
+            info.processed_bytes as f64 / info.total_bytes as f64
+        } else if info.total_files > 0 {
+            info.processed_files as f64 / info.total_files as f64
+        } else {
+            0.0
+        }.min(1.0);
+
+        let elapsed = self.start.elapsed();
+        let eta = if info.is_done() {
+            format!("took {}", format_duration(elapsed))
+        } else if ratio > 0.0 {
+            let remaining = elapsed.as_secs_f64() * (1.0 - ratio) / ratio;
+            format!("ETA {}", format_duration(Duration::from_secs_f64(remaining)))
+        } else {
+            "ETA --:--:--".to_string()
+        };
+
+        let stats = format!(" {:3.0}% {} / {}  {} / {} files  {}",
+            ratio * 100.0,
+            format_size(info.processed_bytes),
+            format_size(info.total_bytes),
+            info.processed_files,
+            info.total_files,
+            eta);
+
+        let width = if let Some((Width(width), _)) = terminal_size() {
+            width as usize
+        } else {
+            80
+        };
+
+        // leave the last column free so the cursor doesn't wrap
+        let mut line = String::new();
+        let bar_width = width.saturating_sub(stats.chars().count() + 3);
+        if bar_width >= 10 {
+            let filled = (bar_width as f64 * ratio) as usize;
+            line.push('[');
+            for _ in 0..filled {
+                line.push('=');
+            }
+            for _ in filled..bar_width {
+                line.push(' ');
+            }
+            line.push(']');
+        }
+        line.push_str(&stats);
+
+        let line_len = line.chars().count();
+        for _ in line_len..state.line_len {
+            line.push(' ');
+        }
+        state.line_len = line_len;
+
+        let mut stderr = stderr();
+        let _ = write!(stderr, "\r{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressCallback for ProgressBar {
+    fn progress(&self, info: &ProgressInfo) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+
+        let now = Instant::now();
+        if !info.is_done() {
+            if let Some(last_redraw) = state.last_redraw {
+                if now.duration_since(last_redraw) < REDRAW_INTERVAL {
+                    return;
+                }
+            }
+        }
+        state.last_redraw = Some(now);
+
+        self.draw(&mut state, info);
+    }
+}
+
+#[derive(Serialize)]
+struct Counts {
+    processed_files: usize,
+    total_files: usize,
+    processed_bytes: u64,
+    total_bytes: u64,
+}
+
+impl From<&ProgressInfo> for Counts {
+    #[inline]
+    fn from(info: &ProgressInfo) -> Self {
+        Self {
+            processed_files: info.processed_files,
+            total_files: info.total_files,
+            processed_bytes: info.processed_bytes,
+            total_bytes: info.total_bytes,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Start {
+        command: &'a str,
+    },
+    Progress {
+        #[serde(flatten)]
+        counts: Counts,
+    },
+    File {
+        path: &'a str,
+        #[serde(flatten)]
+        counts: Counts,
+    },
+    Error {
+        path: Option<&'a str>,
+        kind: &'a str,
+        message: &'a str,
+    },
+    Summary {
+        ok: bool,
+        #[serde(flatten)]
+        counts: Counts,
+        error_count: usize,
+        elapsed_secs: f64,
+    },
+}
+
+#[derive(Debug)]
+struct JsonState {
+    info: ProgressInfo,
+    error_count: usize,
+}
+
+// Newline-delimited JSON events for frontends that run u4pak: start, file
+// (every processed file), error and summary. Each event is a single line
+// that is flushed right away, so it can be read as it arrives.
+pub struct JsonProgress {
+    start: Instant,
+    writer: Mutex<Box<dyn Write + Send>>,
+    state: Mutex<JsonState>,
+}
+
+impl std::fmt::Debug for JsonProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JsonProgress")
+            .field("start", &self.start)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl JsonProgress {
+    pub fn new(writer: Box<dyn Write + Send>, command: &str) -> Self {
+        let progress = Self {
+            start: Instant::now(),
+            writer: Mutex::new(writer),
+            state: Mutex::new(JsonState {
+                info: ProgressInfo::default(),
+                error_count: 0,
+            }),
+        };
+        progress.emit(Document::new(Event::Start { command }));
+        progress
+    }
+
+    // A broken pipe (e.g. the frontend went away) must not abort the actual
+    // work, so write errors are ignored.
+    fn emit(&self, event: impl Serialize) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = serde_json::to_writer(&mut *writer, &event)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writeln!(writer))
+                .and_then(|_| writer.flush());
+        }
+    }
+
+    fn update(&self, info: &ProgressInfo) {
+        if let Ok(mut state) = self.state.lock() {
+            // infos from different threads may arrive out of order
+            if info.processed_files >= state.info.processed_files {
+                state.info = *info;
+            }
+        }
+    }
+
+    pub fn error(&self, path: Option<&str>, kind: &str, message: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.error_count += 1;
+        }
+        self.emit(Event::Error { path, kind, message });
+    }
+
+    pub fn finish<T>(&self, result: &Result<T>) {
+        if let Err(error) = result {
+            self.error(
+                error.path().as_ref().map(|path| path.to_string_lossy()).as_deref(),
+                error.kind().as_str(),
+                &error.error_type().to_string());
+        }
+
+        let (info, error_count) = match self.state.lock() {
+            Ok(state) => (state.info, state.error_count),
+            Err(_) => return,
+        };
+        self.emit(Event::Summary {
+            ok: result.is_ok(),
+            counts: Counts::from(&info),
+            error_count,
+            // milliseconds are precise enough
+            elapsed_secs: (self.start.elapsed().as_secs_f64() * 1000.0).round() / 1000.0,
+        });
+    }
+}
+
+impl ProgressCallback for JsonProgress {
+    fn progress(&self, info: &ProgressInfo) {
+        self.update(info);
+        self.emit(Event::Progress { counts: Counts::from(info) });
+    }
+
+    fn file_done(&self, path: &str, info: &ProgressInfo) {
+        self.update(info);
+        self.emit(Event::File { path, counts: Counts::from(info) });
+    }
+}
+
+// What --progress shows: a progress bar for humans or JSON events for
+// programs.
+#[derive(Debug)]
+pub enum ProgressOutput {
+    Bar(ProgressBar),
+    Json(JsonProgress),
+}
+
+impl ProgressOutput {
+    #[inline]
+    pub fn is_json(&self) -> bool {
+        matches!(self, ProgressOutput::Json(_))
+    }
+
+    // Errors of single files, e.g. found by check or skipped by unpack.
+    pub fn error(&self, path: Option<&str>, kind: &str, message: &str) {
+        if let ProgressOutput::Json(json) = self {
+            json.error(path, kind, message);
+        }
+    }
+
+    pub fn finish<T>(&self, result: &Result<T>) {
+        match self {
+            ProgressOutput::Bar(bar) => bar.finish(),
+            ProgressOutput::Json(json) => json.finish(result),
+        }
+    }
+}
+
+impl ProgressCallback for ProgressOutput {
+    #[inline]
+    fn progress(&self, info: &ProgressInfo) {
+        match self {
+            ProgressOutput::Bar(bar) => bar.progress(info),
+            ProgressOutput::Json(json) => json.progress(info),
+        }
+    }
+
+    #[inline]
+    fn file_done(&self, path: &str, info: &ProgressInfo) {
+        match self {
+            ProgressOutput::Bar(bar) => bar.file_done(path, info),
+            ProgressOutput::Json(json) => json.file_done(path, info),
+        }
+    }
+}
+
+// Counts what was processed for the one-line summary printed after pack and
+// unpack, and forwards the progress to the progress output if there is one.
+#[derive(Debug)]
+pub struct Summary<'a> {
+    start: Instant,
+    progress_output: Option<&'a ProgressOutput>,
+    info: Mutex<ProgressInfo>,
+}
+
+impl<'a> Summary<'a> {
+    pub fn new(progress_output: Option<&'a ProgressOutput>) -> Self {
+        Self {
+            start: Instant::now(),
+            progress_output,
+            info: Mutex::new(ProgressInfo::default()),
+        }
+    }
+
+    // e.g. "Unpacked 12 files, 3.5 M in 0.25 s (14.0 M/s)", on stderr so
+    // it doesn't get mixed into the --verbose output
+    pub fn print(&self, action: &str) {
+        let info = match self.info.lock() {
+            Ok(info) => *info,
+            Err(_) => return,
+        };
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0.0 { info.processed_bytes as f64 / elapsed } else { 0.0 };
+
+        eprintln!("{} {} file{}, {} in {:.2} s ({}/s)",
+            action,
+            info.processed_files,
+            if info.processed_files == 1 { "" } else { "s" },
+            format_size(info.processed_bytes),
+            elapsed,
+            format_size(throughput as u64));
+    }
+}
+
+impl Summary<'_> {
+    fn update(&self, info: &ProgressInfo) {
+        if let Ok(mut last) = self.info.lock() {
+            // infos from different threads may arrive out of order
+            if info.processed_files >= last.processed_files {
+                *last = *info;
+            }
+        }
+    }
+}
+
+impl ProgressCallback for Summary<'_> {
+    fn progress(&self, info: &ProgressInfo) {
+        self.update(info);
+
+        if let Some(progress_output) = self.progress_output {
+            progress_output.progress(info);
+        }
+    }
+
+    fn file_done(&self, path: &str, info: &ProgressInfo) {
+        self.update(info);
+
+        if let Some(progress_output) = self.progress_output {
+            progress_output.file_done(path, info);
+        }
+    }
+}
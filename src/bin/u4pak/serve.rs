@@ -0,0 +1,422 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use crossbeam_channel::unbounded;
+use crossbeam_utils::thread;
+use log::error;
+
+use u4pak::{DirTree, Error, ErrorKind, Pak, Record, Result};
+use u4pak::dirtree::ROOT;
+use u4pak::entry::unpack_record_range_to_writer;
+use u4pak::pak::{COMPR_NONE, COMPR_ZLIB};
+use u4pak::util::format_size;
+
+pub struct ServeOptions<'a> {
+    pub listen: &'a str,
+    pub thread_count: NonZeroUsize,
+    pub encryption_key: Option<Vec<u8>>,
+    pub verbose: bool,
+}
+
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+const MAX_HEADER_COUNT: usize = 100;
+
+enum Body<'a> {
+    Data(Vec<u8>),
+    // streamed from the package when the response is written
+    Record { record: &'a Record, start: u64, len: u64 },
+}
+
+impl Body<'_> {
+    #[inline]
+    fn len(&self) -> u64 {
+        match self {
+            Body::Data(data) => data.len() as u64,
+            Body::Record { len, .. } => *len,
+        }
+    }
+}
+
+struct Response<'a> {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body<'a>,
+}
+
+impl<'a> Response<'a> {
+    fn new(status: u16, content_type: &str, body: Body<'a>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body,
+        }
+    }
+
+    fn error(status: u16) -> Self {
+        let message = format!("{} {}\n", status, reason(status));
+        Self::new(status, "text/plain; charset=utf-8", Body::Data(message.into_bytes()))
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    // The head is already sent when streaming a record fails, so then the
+    // error is returned and the connection is closed with an incomplete body.
+    fn write_to(&self, out: &mut impl Write, head_only: bool, pak: &Pak, in_file: &mut File, encryption_key: &Option<Vec<u8>>) -> Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        let _ = write!(head, "Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len());
+
+        out.write_all(head.as_bytes())?;
+        if !head_only {
+            match &self.body {
+                Body::Data(data) => out.write_all(data)?,
+                Body::Record { record, start, len } => {
+                    if let Err(error) = unpack_record_range_to_writer(record, pak.version(), pak.variant(), in_file, *start, *len, out, encryption_key.clone(), pak.cipher()) {
+                        // don't log clients that went away
+                        if error.kind() != ErrorKind::Io {
+                            error!("{}: {}", record.filename(), error);
+                        }
+                        return Err(error.with_path_if_none(record.filename()));
+                    }
+                }
+            }
+        }
+        out.flush()?;
+
+        Ok(())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+fn content_type(filename: &str) -> &'static str {
+    let ext = match filename.rfind('.') {
+        Some(index) => filename[index + 1..].to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+
+    match ext.as_str() {
+        "txt" | "ini" | "log" | "csv" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css"  => "text/css",
+        "js"   => "text/javascript",
+        "json" => "application/json",
+        "xml"  => "application/xml",
+        "png"  => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif"  => "image/gif",
+        "bmp"  => "image/bmp",
+        "svg"  => "image/svg+xml",
+        "wav"  => "audio/wav",
+        "ogg"  => "audio/ogg",
+        "mp3"  => "audio/mpeg",
+        "mp4"  => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = value.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for &byte in value.as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&'  => escaped.push_str("&amp;"),
+            '<'  => escaped.push_str("&lt;"),
+            '>'  => escaped.push_str("&gt;"),
+            '"'  => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, PartialEq)]
+enum Range {
+    Full,
+    // inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Only single ranges are supported. Anything else means the whole file is
+// sent, which is allowed by RFC 7233.
+fn parse_range(value: Option<&str>, size: u64) -> Range {
+    let spec = match value.and_then(|value| value.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Range::Full,
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some(range) => range,
+        None => return Range::Full,
+    };
+
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if size == 0 => Range::Unsatisfiable,
+            Ok(suffix) => Range::Partial(size.saturating_sub(suffix), size - 1),
+            Err(_) => Range::Full,
+        };
+    }
+
+    let start: u64 = match start.parse() {
+        Ok(start) => start,
+        Err(_) => return Range::Full,
+    };
+
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse() {
+            Ok(end) if end >= start => end,
+            _ => return Range::Full,
+        }
+    };
+
+    if start >= size {
+        Range::Unsatisfiable
+    } else {
+        Range::Partial(start, end.min(size - 1))
+    }
+}
+
+fn dir_listing(tree: &DirTree, node: usize) -> Vec<u8> {
+    let path = if node == ROOT {
+        "/".to_string()
+    } else {
+        format!("/{}/", tree.path(node))
+    };
+    let title = html_escape(&path);
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {}</title>\n</head>\n<body>\n\
+         <h1>Index of {}</h1>\n<table>\n<tr><th align=\"left\">Name</th><th align=\"right\">Size</th><th align=\"right\">Files</th></tr>\n",
+        title, title);
+
+    if node != ROOT {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for child in tree.sorted_children(node) {
+        let child = &tree.nodes()[child];
+        let name = child.name();
+        if child.is_dir() {
+            let _ = writeln!(html, "<tr><td><a href=\"{}/\">{}/</a></td><td align=\"right\">{}</td><td align=\"right\">{}</td></tr>",
+                percent_encode(name), html_escape(name), format_size(child.uncompressed_size()), child.file_count());
+        } else {
+            let _ = writeln!(html, "<tr><td><a href=\"{}\">{}</a></td><td align=\"right\">{}</td><td></td></tr>",
+                percent_encode(name), html_escape(name), format_size(child.uncompressed_size()));
+        }
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html.into_bytes()
+}
+
+fn respond<'a>(tree: &'a DirTree, method: &str, target: &str, range: Option<&str>, options: &ServeOptions) -> Response<'a> {
+    if method != "GET" && method != "HEAD" {
+        return Response::error(405).with_header("Allow", "GET, HEAD".to_string());
+    }
+
+    let raw_path = target.split(['?', '#']).next().unwrap_or("");
+    let path = match percent_decode(raw_path) {
+        Some(path) if path.starts_with('/') => path,
+        _ => return Response::error(400),
+    };
+
+    let node = match tree.lookup(&path) {
+        Some(node) => node,
+        None => return Response::error(404),
+    };
+    let dir_node = &tree.nodes()[node];
+
+    let record = match dir_node.record() {
+        Some(record) => record,
+        None => {
+            if !path.ends_with('/') {
+                return Response::error(301).with_header("Location", format!("{}/", raw_path));
+            }
+            return Response::new(200, "text/html; charset=utf-8", Body::Data(dir_listing(tree, node)));
+        }
+    };
+
+    let size = record.uncompressed_size();
+    let (status, start, len) = match parse_range(range, size) {
+        Range::Full => (200, 0, size),
+        Range::Partial(start, end) => (206, start, end - start + 1),
+        Range::Unsatisfiable => {
+            return Response::error(416).with_header("Content-Range", format!("bytes */{}", size));
+        }
+    };
+
+    // catch what would fail anyway before the head is sent
+    if record.encrypted() && options.encryption_key.is_none() {
+        error!("{}: file is encrypted, but no encryption key was provided", record.filename());
+        return Response::error(500);
+    }
+    if record.compression_method() != COMPR_NONE && record.compression_method() != COMPR_ZLIB {
+        error!("{}: unsupported compression method", record.filename());
+        return Response::error(500);
+    }
+
+    let mut response = Response::new(status, content_type(record.filename()), Body::Record { record, start, len })
+        .with_header("Accept-Ranges", "bytes".to_string());
+
+    if status == 206 {
+        response = response.with_header("Content-Range", format!("bytes {}-{}/{}", start, start + len - 1, size));
+    }
+
+    if let Some(timestamp) = record.timestamp().and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)) {
+        response = response.with_header("Last-Modified", timestamp.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+    }
+
+    response
+}
+
+fn handle_connection(pak: &Pak, tree: &DirTree, in_file: &mut File, stream: TcpStream, options: &ServeOptions) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+
+    let mut line = String::new();
+    (&mut reader).take(MAX_LINE_LENGTH).read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => {
+            Response::error(400).write_to(&mut &stream, false, pak, in_file, &options.encryption_key)?;
+            return Ok(());
+        }
+    };
+
+    let mut range = None;
+    for _ in 0..MAX_HEADER_COUNT {
+        line.clear();
+        (&mut reader).take(MAX_LINE_LENGTH).read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let response = respond(tree, &method, &target, range.as_deref(), options);
+    if options.verbose {
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        eprintln!("{} {} {} {}", peer, method, target, response.status);
+    }
+    response.write_to(&mut BufWriter::new(&stream), method == "HEAD", pak, in_file, &options.encryption_key)?;
+
+    Ok(())
+}
+
+// Serves the content of the pak as static files until the process is killed.
+pub fn serve(pak: &Pak, pak_path: &str, options: ServeOptions) -> Result<()> {
+    let tree = DirTree::new(pak.index().records())?;
+    let listener = TcpListener::bind(options.listen)
+        .map_err(|error| Error::io_with_path(error, options.listen))?;
+
+    eprintln!("Serving {} on http://{}/", pak_path, listener.local_addr()?);
+
+    let thread_result = thread::scope::<_, Result<()>>(|scope| {
+        let (sender, receiver) = unbounded::<TcpStream>();
+
+        for _ in 0..options.thread_count.get() {
+            let receiver = receiver.clone();
+            let mut in_file = File::open(pak_path)
+                .map_err(|error| Error::io_with_path(error, pak_path))?;
+            let tree = &tree;
+            let options = &options;
+
+            scope.spawn(move |_| {
+                while let Ok(stream) = receiver.recv() {
+                    if let Err(error) = handle_connection(pak, tree, &mut in_file, stream, options) {
+                        if options.verbose {
+                            eprintln!("{}", error);
+                        }
+                    }
+                }
+            });
+        }
+
+        drop(receiver);
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => sender.send(stream)
+                    .map_err(|error| Error::new(error.to_string()))?,
+                Err(error) => error!("{}: {}", options.listen, error),
+            }
+        }
+
+        Ok(())
+    });
+
+    match thread_result {
+        Err(error) => Err(Error::new(format!("threading error: {:?}", error))),
+        Ok(result) => result,
+    }
+}
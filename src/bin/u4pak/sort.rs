@@ -7,7 +7,7 @@
 use std::cmp::Ordering;
 use std::convert::TryFrom;
 
-use u4pak::result::{Result, Error};
+use u4pak::result::{Result, Error, ErrorKind};
 use u4pak::record::Record;
 
 #[derive(Debug)]
@@ -20,6 +20,8 @@ pub enum SortKey {
     ComprBlockSize,
     Timestamp,
     Encrypted,
+    Ratio,
+    Extension,
 
     RevName,
     RevOffset,
@@ -29,6 +31,8 @@ pub enum SortKey {
     RevComprBlockSize,
     RevTimestamp,
     RevEncrypted,
+    RevRatio,
+    RevExtension,
 }
 
 pub type Order = [SortKey];
@@ -56,6 +60,10 @@ impl TryFrom<&str> for SortKey {
             Ok(SortKey::Timestamp)
         } else if value.eq_ignore_ascii_case("e") || value.eq_ignore_ascii_case("encrypted") {
             Ok(SortKey::Encrypted)
+        } else if value.eq_ignore_ascii_case("r") || value.eq_ignore_ascii_case("ratio") || value.eq_ignore_ascii_case("compression-ratio") {
+            Ok(SortKey::Ratio)
+        } else if value.eq_ignore_ascii_case("x") || value.eq_ignore_ascii_case("ext") || value.eq_ignore_ascii_case("extension") {
+            Ok(SortKey::Extension)
         } else if value.eq_ignore_ascii_case("-p") || value.eq_ignore_ascii_case("-name") || value.eq_ignore_ascii_case("-path") || value.eq_ignore_ascii_case("-filename") {
             Ok(SortKey::RevName)
         } else if value.eq_ignore_ascii_case("-s") || value.eq_ignore_ascii_case("-size") || value.eq_ignore_ascii_case("-compressed-size") {
@@ -72,8 +80,12 @@ impl TryFrom<&str> for SortKey {
             Ok(SortKey::RevTimestamp)
         } else if value.eq_ignore_ascii_case("-e") || value.eq_ignore_ascii_case("-encrypted") {
             Ok(SortKey::RevEncrypted)
+        } else if value.eq_ignore_ascii_case("-r") || value.eq_ignore_ascii_case("-ratio") || value.eq_ignore_ascii_case("-compression-ratio") {
+            Ok(SortKey::RevRatio)
+        } else if value.eq_ignore_ascii_case("-x") || value.eq_ignore_ascii_case("-ext") || value.eq_ignore_ascii_case("-extension") {
+            Ok(SortKey::RevExtension)
         } else {
-            Err(Error::new(format!("illegal argument --sort={:?}", value)))
+            Err(Error::new(format!("illegal argument --sort={:?}", value)).with_kind(ErrorKind::InvalidArgument))
         }
     }
 }
@@ -90,6 +102,8 @@ impl SortKey {
             SortKey::ComprBlockSize    => |a: &Record, b: &Record| a.compression_block_size().cmp(&b.compression_block_size()),
             SortKey::Timestamp         => |a: &Record, b: &Record| a.timestamp().cmp(&b.timestamp()),
             SortKey::Encrypted         => |a: &Record, b: &Record| a.encrypted().cmp(&b.encrypted()),
+            SortKey::Ratio             => |a: &Record, b: &Record| ratio(a).total_cmp(&ratio(b)),
+            SortKey::Extension         => |a: &Record, b: &Record| extension(a).cmp(extension(b)),
 
             SortKey::RevName           => |a: &Record, b: &Record| b.filename().cmp(&a.filename()),
             SortKey::RevSize           => |a: &Record, b: &Record| b.size().cmp(&a.size()),
@@ -99,10 +113,33 @@ impl SortKey {
             SortKey::RevComprBlockSize => |a: &Record, b: &Record| b.compression_block_size().cmp(&a.compression_block_size()),
             SortKey::RevTimestamp      => |a: &Record, b: &Record| b.timestamp().cmp(&a.timestamp()),
             SortKey::RevEncrypted      => |a: &Record, b: &Record| b.encrypted().cmp(&a.encrypted()),
+            SortKey::RevRatio          => |a: &Record, b: &Record| ratio(b).total_cmp(&ratio(a)),
+            SortKey::RevExtension      => |a: &Record, b: &Record| extension(b).cmp(extension(a)),
         }
     }
 }
 
+// Empty files count as not compressed at all.
+#[inline]
+fn ratio(record: &Record) -> f64 {
+    if record.uncompressed_size() == 0 {
+        1.0
+    } else {
+        record.size() as f64 / record.uncompressed_size() as f64
+    }
+}
+
+// Everything after the last dot of the file name, or "" if there is none.
+#[inline]
+fn extension(record: &Record) -> &str {
+    let filename = record.filename();
+    let name = filename.rsplit('/').next().unwrap_or(filename);
+    match name.rfind('.') {
+        Some(index) if index > 0 => &name[index + 1..],
+        _ => "",
+    }
+}
+
 fn chain(cmp1: Box<dyn Fn(&Record, &Record) -> Ordering>, cmp2: Box<dyn Fn(&Record, &Record) -> Ordering>) -> Box<dyn Fn(&Record, &Record) -> Ordering> {
     Box::new(move |a: &Record, b: &Record|
         match cmp1(a, b) {
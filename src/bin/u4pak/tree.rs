@@ -0,0 +1,76 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::Write;
+
+use u4pak::{DirTree, Pak, Result};
+use u4pak::dirtree::ROOT;
+use u4pak::util::format_size;
+
+#[derive(Debug, Default)]
+pub struct TreeOptions {
+    pub human_readable: bool,
+    // also print the compressed size
+    pub compressed: bool,
+}
+
+pub fn tree(pak: &Pak, options: TreeOptions) -> Result<()> {
+    let tree = DirTree::new(pak.index().records())?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    let root_name = pak.index().mount_point().unwrap_or("/");
+    write_node(&mut out, &tree, ROOT, root_name, &options)?;
+    write_children(&mut out, &tree, ROOT, &mut String::new(), &options)?;
+
+    Ok(())
+}
+
+fn write_children(out: &mut impl Write, tree: &DirTree, dir: usize, prefix: &mut String, options: &TreeOptions) -> Result<()> {
+    let children = tree.sorted_children(dir);
+    let count = children.len();
+
+    for (index, child) in children.into_iter().enumerate() {
+        let last = index + 1 == count;
+        let node = &tree.nodes()[child];
+        write!(out, "{}{}", prefix, if last { "└── " } else { "├── " })?;
+        write_node(out, tree, child, node.name(), options)?;
+
+        if node.is_dir() {
+            let prefix_len = prefix.len();
+            prefix.push_str(if last { "    " } else { "│   " });
+            write_children(out, tree, child, prefix, options)?;
+            prefix.truncate(prefix_len);
+        }
+    }
+
+    Ok(())
+}
+
+fn write_node(out: &mut impl Write, tree: &DirTree, node: usize, name: &str, options: &TreeOptions) -> Result<()> {
+    let node = &tree.nodes()[node];
+    let fmt_size = |size: u64| if options.human_readable {
+        format_size(size)
+    } else {
+        size.to_string()
+    };
+
+    let size = if options.compressed {
+        format!("{} / {}", fmt_size(node.size()), fmt_size(node.uncompressed_size()))
+    } else {
+        fmt_size(node.uncompressed_size())
+    };
+
+    if node.is_dir() {
+        let file_count = node.file_count();
+        writeln!(out, "{}/  [{} {}, {}]", name.trim_end_matches('/'), file_count,
+            if file_count == 1 { "file" } else { "files" }, size)?;
+    } else {
+        writeln!(out, "{}  [{}]", name, size)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Limit for the data that is in flight between the threads of pack, unpack
+// and check. Workers acquire the (estimated) size of a record before reading
+// it and give it back by dropping the returned guard, which e.g. for pack only
+// happens once the main thread wrote the record.
+
+use std::num::NonZeroU64;
+use std::sync::{Condvar, Mutex, PoisonError};
+
+#[derive(Debug, Default)]
+struct Usage {
+    in_use: u64,
+    // see acquire_in_order()
+    next_ticket: u64,
+}
+
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: Option<u64>,
+    usage: Mutex<Usage>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    // None means no limit.
+    pub fn new(limit: Option<NonZeroU64>) -> Self {
+        Self {
+            limit: limit.map(NonZeroU64::get),
+            usage: Mutex::new(Usage::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    #[inline]
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    #[inline]
+    pub fn in_use(&self) -> u64 {
+        self.usage.lock().unwrap_or_else(PoisonError::into_inner).in_use
+    }
+
+    // Blocks until size bytes are available. Anything bigger than the whole
+    // budget only counts as the whole budget, so it is let through once
+    // nothing else is in flight.
+    pub fn acquire(&self, size: u64) -> MemoryGuard<'_> {
+        let limit = if let Some(limit) = self.limit {
+            limit
+        } else {
+            return MemoryGuard { budget: self, size: 0 };
+        };
+
+        let size = std::cmp::min(size, limit);
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        while usage.in_use + size > limit {
+            usage = self.released.wait(usage).unwrap_or_else(PoisonError::into_inner);
+        }
+        usage.in_use += size;
+
+        MemoryGuard { budget: self, size }
+    }
+
+    // Like acquire(), but in the order of ticket, which has to count up from 0
+    // without gaps. Needed when the results are written in order, so that the
+    // next result to write can't be stuck behind later ones that already used
+    // up the budget.
+    pub fn acquire_in_order(&self, ticket: u64, size: u64) -> MemoryGuard<'_> {
+        let limit = if let Some(limit) = self.limit {
+            limit
+        } else {
+            return MemoryGuard { budget: self, size: 0 };
+        };
+
+        let size = std::cmp::min(size, limit);
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        while usage.next_ticket != ticket || usage.in_use + size > limit {
+            usage = self.released.wait(usage).unwrap_or_else(PoisonError::into_inner);
+        }
+        usage.in_use += size;
+        usage.next_ticket += 1;
+        // wake up the one waiting for the next ticket
+        self.released.notify_all();
+
+        MemoryGuard { budget: self, size }
+    }
+}
+
+#[derive(Debug)]
+pub struct MemoryGuard<'a> {
+    budget: &'a MemoryBudget,
+    size: u64,
+}
+
+impl MemoryGuard<'_> {
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for MemoryGuard<'_> {
+    fn drop(&mut self) {
+        if self.size > 0 {
+            let mut usage = self.budget.usage.lock().unwrap_or_else(PoisonError::into_inner);
+            usage.in_use -= self.size;
+            self.budget.released.notify_all();
+        }
+    }
+}
@@ -4,17 +4,29 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{collections::HashSet, fs::File, io::{BufReader, Read, Seek, SeekFrom, stderr}, num::NonZeroUsize};
+use std::{collections::HashSet, fs::File, io::{BufReader, Read, Seek, SeekFrom, Write}, num::{NonZeroU64, NonZeroUsize}};
 
-use crossbeam_channel::{Sender, unbounded};
+use crossbeam_channel::{Sender, bounded, unbounded};
 use crossbeam_utils::thread;
+use log::debug;
 use openssl::sha::Sha1 as OpenSSLSha1;
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
 
-use crate::{Error, Filter, Pak, pak::{BUFFER_SIZE, COMPR_METHODS, COMPR_NONE, HexDisplay, Sha1, Variant}};
+use crate::{Error, ErrorKind, Filter, Pak, pak::{BUFFER_SIZE, COMPR_METHODS, COMPR_NONE, HexDisplay, PAK_RELATIVE_COMPRESSION_OFFSET_VERSION, Sha1, Variant}};
+use crate::budget::MemoryBudget;
+use crate::pread::{IoStrategy, PakReader, DEFAULT_READ_SIZE};
+use crate::progress::{Progress, ProgressCallback};
 use crate::reopen::Reopen;
+use crate::schedule::{sort_records, WorkOrder};
+use crate::unpack::unpack_record_to_writer;
+use crate::util::align;
+use crate::variant::record_layout;
 use crate::{Record, Result};
 
-pub const NULL_SHA1: Sha1 = [0u8; 20];
+pub use crate::pak::NULL_SHA1;
+
+// bytes read to tell if the data of a record is wiped, see is_wiped()
+const WIPED_PROBE_SIZE: usize = 4096;
 
 #[derive(Debug)]
 pub struct CheckOptions<'a> {
@@ -25,6 +37,17 @@ pub struct CheckOptions<'a> {
     pub verbose: bool,
     pub paths: Option<&'a [&'a str]>,
     pub thread_count: NonZeroUsize,
+    // limit for the memory used by all threads together (None means no limit)
+    pub max_memory: Option<NonZeroU64>,
+    // how the worker threads read the pak
+    pub io_strategy: IoStrategy,
+    pub read_size: NonZeroUsize,
+    // order in which the records are queued for the worker threads
+    pub order: WorkOrder,
+    pub progress: Option<&'a dyn ProgressCallback>,
+    // actually decompress all records
+    pub deep: bool,
+    pub encryption_key: Option<Vec<u8>>,
 }
 
 impl Default for CheckOptions<'_> {
@@ -37,10 +60,154 @@ impl Default for CheckOptions<'_> {
             verbose: false,
             paths: None,
             thread_count: NonZeroUsize::new(num_cpus::get()).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            max_memory: None,
+            io_strategy: IoStrategy::default(),
+            read_size: DEFAULT_READ_SIZE,
+            order: WorkOrder::default(),
+            progress: None,
+            deep: false,
+            encryption_key: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CheckErrorKind {
+    Io,
+    InvalidRecord,
+    ChecksumMismatch,
+    MetadataMismatch,
+    UnknownCompressionMethod,
+    SizeMismatch,
+    InlineOffset,
+    BlockOffset,
+    DataBleedsIntoIndex,
+    Overlap,
+    DecompressionFailed,
+    DuplicateFilename,
+    PathNotFound,
+    SignatureMismatch,
+    DataWiped,
+}
+
+impl CheckErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckErrorKind::Io                       => "io",
+            CheckErrorKind::InvalidRecord            => "invalid_record",
+            CheckErrorKind::ChecksumMismatch         => "checksum_mismatch",
+            CheckErrorKind::MetadataMismatch         => "metadata_mismatch",
+            CheckErrorKind::UnknownCompressionMethod => "unknown_compression_method",
+            CheckErrorKind::SizeMismatch             => "size_mismatch",
+            CheckErrorKind::InlineOffset             => "inline_offset",
+            CheckErrorKind::BlockOffset              => "block_offset",
+            CheckErrorKind::DataBleedsIntoIndex      => "data_bleeds_into_index",
+            CheckErrorKind::Overlap                  => "overlap",
+            CheckErrorKind::DecompressionFailed      => "decompression_failed",
+            CheckErrorKind::DuplicateFilename        => "duplicate_filename",
+            CheckErrorKind::PathNotFound             => "path_not_found",
+            CheckErrorKind::SignatureMismatch        => "signature_mismatch",
+            CheckErrorKind::DataWiped                => "data_wiped",
+        }
+    }
+}
+
+impl std::fmt::Display for CheckErrorKind {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// A problem found by check_with_report(). The error contains the path and the
+// human readable message, expected and actual are set where it makes sense.
+#[derive(Debug)]
+pub struct CheckError {
+    pub kind: CheckErrorKind,
+    pub error: Error,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl CheckError {
+    #[inline]
+    pub fn new(kind: CheckErrorKind, error: Error) -> Self {
+        Self {
+            kind,
+            error,
+            expected: None,
+            actual: None,
+        }
+    }
+
+    #[inline]
+    pub fn with_values(self, expected: impl ToString, actual: impl ToString) -> Self {
+        Self {
+            expected: Some(expected.to_string()),
+            actual: Some(actual.to_string()),
+            ..self
+        }
+    }
+}
+
+// Written as path, kind, message, expected and actual.
+#[cfg(feature = "serde")]
+impl serde::Serialize for CheckError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        #[derive(serde::Serialize)]
+        struct CheckErrorRef<'a> {
+            path: Option<std::borrow::Cow<'a, str>>,
+            kind: CheckErrorKind,
+            message: String,
+            expected: &'a Option<String>,
+            actual: &'a Option<String>,
+        }
+
+        CheckErrorRef {
+            path: self.error.path().as_ref().map(|path| path.to_string_lossy()),
+            kind: self.kind,
+            message: self.error.error_type().to_string(),
+            expected: &self.expected,
+            actual: &self.actual,
+        }.serialize(serializer)
+    }
+}
+
+// The document of check --report.
+#[cfg(feature = "serde")]
+#[derive(Debug, serde::Serialize)]
+pub struct CheckReport<'a> {
+    pub package: &'a str,
+    pub error_count: usize,
+    pub wiped_count: usize,
+    pub errors: &'a [CheckError],
+}
+
+impl From<Error> for CheckError {
+    fn from(error: Error) -> Self {
+        let kind = match error.kind() {
+            ErrorKind::Io                       => CheckErrorKind::Io,
+            ErrorKind::UnknownCompressionMethod => CheckErrorKind::UnknownCompressionMethod,
+            ErrorKind::DecompressionFailed      => CheckErrorKind::DecompressionFailed,
+            ErrorKind::PathNotFound             => CheckErrorKind::PathNotFound,
+            _ => CheckErrorKind::InvalidRecord,
+        };
+        CheckError::new(kind, error)
+    }
+}
+
+fn checksum_error(filename: &str, expected: &Sha1, actual: &Sha1) -> CheckError {
+    CheckError::new(CheckErrorKind::ChecksumMismatch, Error::new(format!(
+        "checksum missmatch:\n\
+         \texpected: {}\n\
+         \tactual:   {}",
+         HexDisplay::new(expected),
+         HexDisplay::new(actual)
+    )).with_path(filename)).with_values(HexDisplay::new(expected), HexDisplay::new(actual))
+}
+
 macro_rules! check_error {
     ($ok:expr, $result_sender:expr, $abort_on_error:expr, $error:expr) => {
         {
@@ -69,42 +236,94 @@ macro_rules! io {
     };
 }
 
-fn check_data<R>(reader: &mut R, filename: &str, offset: u64, size: u64, checksum: &Sha1, ignore_null_checksums: bool, buffer: &mut Vec<u8>) -> Result<()>
+fn check_data<R>(reader: &mut R, filename: &str, offset: u64, size: u64, checksum: &Sha1, ignore_null_checksums: bool, buffer: &mut Vec<u8>) -> std::result::Result<(), CheckError>
 where R: Read, R: Seek {
     if ignore_null_checksums && checksum == &NULL_SHA1 {
         return Ok(());
     }
-    reader.seek(SeekFrom::Start(offset))?;
+    let io_error = |error| CheckError::new(CheckErrorKind::Io, Error::io_with_path(error, filename));
+    reader.seek(SeekFrom::Start(offset)).map_err(io_error)?;
     let mut hasher = OpenSSLSha1::new();
     let mut remaining = size;
     buffer.resize(BUFFER_SIZE, 0);
     loop {
         if remaining >= BUFFER_SIZE as u64 {
-            reader.read_exact(buffer)?;
+            reader.read_exact(buffer).map_err(io_error)?;
             hasher.update(&buffer);
             remaining -= BUFFER_SIZE as u64;
         } else {
             let buffer = &mut buffer[..remaining as usize];
-            reader.read_exact(buffer)?;
+            reader.read_exact(buffer).map_err(io_error)?;
             hasher.update(&buffer);
             break;
         }
     }
     let actual_digest = hasher.finish();
     if &actual_digest != checksum {
-        return Err(Error::new(format!(
-            "checksum missmatch:\n\
-             \texpected: {}\n\
-             \tactual:   {}",
-             HexDisplay::new(checksum),
-             HexDisplay::new(&actual_digest)
-        )).with_path(filename));
+        return Err(checksum_error(filename, checksum, &actual_digest));
     }
     Ok(())
 }
 
+// Some games strip packages by zeroing out the data of files. Instead of a
+// checksum mismatch (and errors about the inline header if that is zeroed too)
+// such records are reported as wiped. Only the first bytes are read for
+// records that aren't, so this costs next to nothing. Read errors are left to
+// the actual checks to report.
+fn is_wiped<R>(reader: &mut R, pak: &Pak, record: &Record, version: u32, variant: Variant, ignore_null_checksums: bool, buffer: &mut Vec<u8>) -> bool
+where R: Read, R: Seek {
+    let start = record.data_offset(version, variant);
+    let (_, end) = pak.record_region(record);
+    if record.uncompressed_size() == 0 || end <= start || reader.seek(SeekFrom::Start(start)).is_err() {
+        return false;
+    }
+
+    let mut remaining = end - start;
+    // start small, most records are told apart by their first bytes
+    let mut chunk_size = WIPED_PROBE_SIZE;
+    while remaining > 0 {
+        let count = remaining.min(chunk_size as u64) as usize;
+        buffer.resize(count, 0);
+        if reader.read_exact(buffer).is_err() || buffer.iter().any(|&byte| byte != 0) {
+            return false;
+        }
+        remaining -= count as u64;
+        chunk_size = BUFFER_SIZE;
+    }
+
+    // zeroes are no valid compressed or (in practice) encrypted data, but
+    // they might be the actual content of an uncompressed file
+    if record.compression_method() != COMPR_NONE || record.encrypted() {
+        return true;
+    }
+    match record.sha1() {
+        Some(sha1) if *sha1 != NULL_SHA1 => *sha1 != zeroes_sha1(record.size(), buffer),
+        _ => !ignore_null_checksums,
+    }
+}
+
+fn zeroes_sha1(size: u64, buffer: &mut Vec<u8>) -> Sha1 {
+    buffer.clear();
+    buffer.resize(BUFFER_SIZE, 0);
+    let mut hasher = OpenSSLSha1::new();
+    let mut remaining = size;
+    while remaining > 0 {
+        let count = remaining.min(BUFFER_SIZE as u64) as usize;
+        hasher.update(&buffer[..count]);
+        remaining -= count as u64;
+    }
+    hasher.finish()
+}
+
+
+pub fn check(pak: &Pak, in_file: &mut File, options: CheckOptions) -> Result<usize> {
+    check_with_report(pak, in_file, options).map(|errors| errors.len())
+}
 
-pub fn check<'a>(pak: &'a Pak, in_file: &mut File, options: CheckOptions) -> Result<usize> {
+// Like check(), but returns all found errors. Nothing is printed, reporting
+// them is up to the caller. With abort_on_error the first error is returned
+// as Err.
+pub fn check_with_report(pak: &Pak, in_file: &mut File, options: CheckOptions) -> Result<Vec<CheckError>> {
     let CheckOptions {
         variant,
         abort_on_error,
@@ -112,101 +331,132 @@ pub fn check<'a>(pak: &'a Pak, in_file: &mut File, options: CheckOptions) -> Res
         null_separated,
         verbose,
         thread_count,
+        max_memory,
+        io_strategy,
+        read_size,
+        order,
         paths,
+        progress,
+        deep,
+        encryption_key,
     } = options;
-    let mut error_count = 0usize;
+    let progress = Progress::new(progress);
+    let budget = MemoryBudget::new(max_memory);
+    let mut errors = Vec::new();
     let pak_path = in_file.path()?;
     let index_offset = pak.index_offset();
     let version = pak.version();
+    let cipher = pak.cipher();
     let mut filter: Option<Filter> = paths.map(|paths| paths.into());
-    let mut stderr = stderr();
 
-    if let Err(error) = check_data(&mut BufReader::new(in_file), "<archive index>", index_offset, pak.index_size(), pak.index_sha1(), ignore_null_checksums, &mut vec![0u8; BUFFER_SIZE]) {
-        error_count += 1;
-        if abort_on_error {
-            return Err(error);
-        } else {
-            let _ = error.write_to(&mut stderr, null_separated);
+    let mut indices = vec![("<archive index>", index_offset, pak.index_size(), pak.index_sha1())];
+    if let Some(info) = pak.secondary_index() {
+        if info.has_path_hash_index() {
+            indices.push(("<path hash index>", info.path_hash_index_offset(), info.path_hash_index_size(), info.path_hash_index_sha1()));
+        }
+        if info.has_full_directory_index() {
+            indices.push(("<full directory index>", info.full_directory_index_offset(), info.full_directory_index_size(), info.full_directory_index_sha1()));
         }
     }
 
-    let read_record = match variant {
-        Variant::ConanExiles => {
-            if version != 4 {
-                return Err(Error::new(format!("Only know how to handle Conan Exile paks of version 4, but version was {}.", version)));
-            }
-            Record::read_conan_exiles
-        }
-        Variant::Standard => match version {
-            1 => Record::read_v1,
-            2 => Record::read_v2,
-            _ if version <= 5 || version == 7 => Record::read_v3,
-            _ => {
-                return Err(Error::new(format!("unsupported version: {}", version)));
+    let mut index_reader = BufReader::new(in_file);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    for (name, offset, size, sha1) in indices {
+        if let Err(error) = check_data(&mut index_reader, name, offset, size, sha1, ignore_null_checksums, &mut buffer) {
+            if abort_on_error {
+                return Err(error.error);
             }
+            errors.push(error);
         }
-    };
+    }
 
-    let thread_result = thread::scope::<_, Result<usize>>(|scope| {
+    let read_record = variant.spec().inline_record_reader(version)?;
+    let layout = record_layout(version, variant)?;
+
+    errors.extend(check_layout(pak, abort_on_error)?);
+
+    let thread_result = thread::scope::<_, Result<Vec<CheckError>>>(|scope| {
         let (work_sender, work_receiver) = unbounded::<&Record>();
-        let (result_sender, result_receiver) = unbounded::<Result<&Record>>();
+        let (result_sender, result_receiver) = bounded::<std::result::Result<&Record, CheckError>>(thread_count.get());
 
         for _ in 0..thread_count.get() {
             let work_receiver = work_receiver.clone();
             let result_sender = result_sender.clone();
             let in_file = File::open(&pak_path)?;
+            let progress = &progress;
+            let encryption_key = &encryption_key;
+            let budget = &budget;
 
             scope.spawn(move |_| {
-                let mut reader = BufReader::new(in_file);
+                let mut reader = PakReader::new(in_file, io_strategy, read_size);
                 let mut buffer = vec![0u8; BUFFER_SIZE];
 
                 while let Ok(record) = work_receiver.recv() {
+                    debug!("checking {}", record.filename());
+                    let _memory = budget.acquire(memory_estimate(record, deep));
                     let mut ok = true;
+                    let _advance = progress.advance_on_drop(record.filename(), record.size());
+
+                    if is_wiped(&mut reader, pak, record, version, variant, ignore_null_checksums, &mut buffer) {
+                        // the other checks would only fail because of it
+                        let error = CheckError::new(CheckErrorKind::DataWiped, Error::new(format!(
+                            "data wiped: all {} bytes of the data are zero",
+                            pak.record_region(record).1 - record.data_offset(version, variant),
+                        )).with_path(record.filename()));
+                        if result_sender.send(Err(error)).is_err() || abort_on_error {
+                            return;
+                        }
+                        continue;
+                    }
 
                     if !COMPR_METHODS.contains(&record.compression_method()) {
-                        check_error!(ok, result_sender, abort_on_error, Error::new(format!(
+                        check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::UnknownCompressionMethod, Error::new(format!(
                             "unknown compression method: 0x{:02x}",
                             record.compression_method(),
-                        )).with_path(record.filename()));
+                        )).with_path(record.filename())));
                     }
 
                     if record.compression_method() == COMPR_NONE && record.size() != record.uncompressed_size() {
-                        check_error!(ok, result_sender, abort_on_error, Error::new(format!(
+                        check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::SizeMismatch, Error::new(format!(
                             "file is not compressed but compressed size ({}) differes from uncompressed size ({})",
                             record.size(),
                             record.uncompressed_size(),
-                        )).with_path(record.filename()));
+                        )).with_path(record.filename())).with_values(record.uncompressed_size(), record.size()));
                     }
 
-                    let offset = record.offset() + Pak::header_size(version, variant, record);
+                    let offset = record.data_offset(version, variant);
                     if offset + record.size() > index_offset {
-                        check_error!(ok, result_sender, abort_on_error, Error::new(
+                        check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::DataBleedsIntoIndex, Error::new(
                             "data bleeds into index".to_string()
-                        ).with_path(record.filename()));
+                        ).with_path(record.filename())).with_values(index_offset, offset + record.size()));
                     }
 
                     if let Err(error) = reader.seek(SeekFrom::Start(record.offset())) {
                         check_error!(ok, result_sender, abort_on_error,
-                            Error::io_with_path(error, record.filename()));
+                            CheckError::new(CheckErrorKind::Io, Error::io_with_path(error, record.filename())));
                     } else {
-                        match read_record(&mut reader, record.filename().to_string()) {
-                            Ok(other_record) => {
+                        match read_record(&mut reader, record.filename().to_string(), pak.warnings()) {
+                            Ok(mut other_record) => {
+                                if pak.rebased_block_offsets() {
+                                    other_record.rebase_block_offsets(record.offset(), version >= PAK_RELATIVE_COMPRESSION_OFFSET_VERSION);
+                                }
+
                                 if other_record.offset() != 0 {
-                                    check_error!(ok, result_sender, abort_on_error,
+                                    check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::InlineOffset,
                                         Error::new(format!("data record offset field is not 0 but {}",
                                                 other_record.offset()))
-                                            .with_path(other_record.filename()));
+                                            .with_path(other_record.filename())).with_values(0, other_record.offset()));
                                 }
 
                                 if !record.same_metadata(&other_record) {
-                                    check_error!(ok, result_sender, abort_on_error,
+                                    check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::MetadataMismatch,
                                         Error::new(format!("metadata missmatch:\n{}",
                                                 record.metadata_diff(&other_record)))
-                                            .with_path(other_record.filename()));
+                                            .with_path(other_record.filename())));
                                 }
                             }
                             Err(error) => {
-                                check_error!(ok, result_sender, abort_on_error, error);
+                                check_error!(ok, result_sender, abort_on_error, error.with_path_if_none(record.filename()).into());
                             }
                         };
                         //if version >= 4 {
@@ -219,88 +469,115 @@ pub fn check<'a>(pak: &'a Pak, in_file: &mut File, options: CheckOptions) -> Res
                         //}
                     }
 
+                    // with deep the checksum is computed while decompressing,
+                    // so the data doesn't need to be read twice
+                    let mut unpacked_sha1 = None;
+                    if deep {
+                        let mut writer = CountingWriter(0);
+                        // the checksum of encrypted records without compression
+                        // blocks doesn't include the padding
+                        let limit = if record.compression_blocks().is_some() { u64::MAX } else { record.size() };
+                        let mut sha1_reader = Sha1Reader::new(&mut reader, limit);
+                        match unpack_record_to_writer(record, version, variant, &mut sha1_reader, &mut writer, encryption_key.clone(), cipher) {
+                            Ok(()) => {
+                                unpacked_sha1 = Some(sha1_reader.finish());
+                                if writer.0 != record.uncompressed_size() {
+                                    check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::SizeMismatch, Error::new(format!(
+                                        "decompressed size differs from uncompressed size: {} != {}",
+                                        writer.0, record.uncompressed_size(),
+                                    )).with_path(record.filename())).with_values(record.uncompressed_size(), writer.0));
+                                }
+                            }
+                            Err(error) => {
+                                check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::DecompressionFailed, Error::new(format!(
+                                    "decompression failed: {}", error.error_type()
+                                )).with_path(record.filename())));
+                            }
+                        }
+                    }
+
                     if let Some(blocks) = record.compression_blocks() {
                         if !ignore_null_checksums || record.sha1().map_or(true, |sha1| sha1 != NULL_SHA1) {
-                            let header_size = Pak::header_size(version, variant, record);
+                            let header_size = layout.header_size(record);
                             let mut hasher = OpenSSLSha1::new();
 
                             let base_offset;
                             let mut next_start_offset;
 
-                            if variant == Variant::ConanExiles {
-                                // only version 4 is correctly supported
-                                base_offset = 0;
-                                next_start_offset = record.offset() + header_size + 20;
-                            } else if version >= 7 {
-                                // + 4 for unknown extra field in inline record
+                            // unknown extra field in inline record of some versions
+                            let padding = layout.inline_padding;
+                            if version >= PAK_RELATIVE_COMPRESSION_OFFSET_VERSION {
                                 base_offset = record.offset();
-                                next_start_offset = header_size + 4;
-                            } else if version >= 4 {
-                                base_offset = 0;
-                                next_start_offset = record.offset() + header_size + 4;
+                                next_start_offset = header_size + padding;
                             } else {
                                 base_offset = 0;
-                                next_start_offset = record.offset() + header_size;
+                                next_start_offset = record.offset() + header_size + padding;
                             }
 
                             let end_offset = next_start_offset + record.size();
 
                             for (index, block) in blocks.iter().enumerate() {
                                 if block.start_offset > block.end_offset {
-                                    check_error!(ok, result_sender, abort_on_error,
+                                    check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::BlockOffset,
                                         Error::new(format!(
                                             "compression block start offset is bigger than end offset: {} > {}",
                                             block.start_offset, block.end_offset,
-                                        )).with_path(record.filename()));
+                                        )).with_path(record.filename())));
                                 } else {
                                     if next_start_offset != block.start_offset {
-                                        check_error!(ok, result_sender, abort_on_error,
+                                        check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::BlockOffset,
                                             Error::new(format!(
                                                 "compression block with index {} start offset differes from expected value: {} != {} ({})",
                                                 index, block.start_offset, next_start_offset, block.start_offset as i64 - next_start_offset as i64,
-                                            )).with_path(record.filename()));
+                                            )).with_path(record.filename())).with_values(next_start_offset, block.start_offset));
                                     }
 
-                                    let block_size = block.end_offset - block.start_offset;
-
-                                    buffer.resize(block_size as usize, 0);
-                                    if let Err(error) = io!{
-                                        reader.seek(SeekFrom::Start(base_offset + block.start_offset)),
-                                        reader.read_exact(&mut buffer)
-                                    } {
-                                        let _ = result_sender.send(Err(Error::io_with_path(error, record.filename())));
-                                        return;
+                                    // encrypted blocks are padded to the AES block size
+                                    let block_size = if record.encrypted() {
+                                        align(block.end_offset - block.start_offset, AES_BLOCK_SIZE as u64)
+                                    } else {
+                                        block.end_offset - block.start_offset
+                                    };
+
+                                    if unpacked_sha1.is_none() {
+                                        buffer.resize(block_size as usize, 0);
+                                        if let Err(error) = io!{
+                                            reader.seek(SeekFrom::Start(base_offset + block.start_offset)),
+                                            reader.read_exact(&mut buffer)
+                                        } {
+                                            let _ = result_sender.send(Err(CheckError::new(CheckErrorKind::Io, Error::io_with_path(error, record.filename()))));
+                                            return;
+                                        }
+                                        hasher.update(&buffer);
                                     }
-                                    hasher.update(&buffer);
 
                                     next_start_offset += block_size;
                                 }
                             }
 
                             if next_start_offset != end_offset {
-                                check_error!(ok, result_sender, abort_on_error,
+                                check_error!(ok, result_sender, abort_on_error, CheckError::new(CheckErrorKind::BlockOffset,
                                     Error::new(format!(
                                         "actual record end offset differes from expected value: {} != {} ({})",
                                         next_start_offset, end_offset, next_start_offset as i64 - end_offset as i64,
-                                    )).with_path(record.filename()));
+                                    )).with_path(record.filename())).with_values(end_offset, next_start_offset));
                             }
 
-                            let actual_digest = hasher.finish();
+                            let actual_digest = unpacked_sha1.unwrap_or_else(|| hasher.finish());
                             if &actual_digest != record.sha1().as_ref().unwrap_or(&NULL_SHA1) {
-                                check_error!(ok, result_sender, abort_on_error, Error::new(format!(
-                                    "checksum missmatch:\n\
-                                    \texpected: {}\n\
-                                    \tactual:   {}",
-                                    HexDisplay::new(record.sha1().as_ref().unwrap_or(&NULL_SHA1)),
-                                    HexDisplay::new(&actual_digest)
-                                )).with_path(record.filename()));
+                                check_error!(ok, result_sender, abort_on_error,
+                                    checksum_error(record.filename(), record.sha1().as_ref().unwrap_or(&NULL_SHA1), &actual_digest));
                             }
                         }
+                    } else if let Some(actual_digest) = unpacked_sha1 {
+                        let expected_digest = record.sha1().as_ref().unwrap_or(&NULL_SHA1);
+                        if (!ignore_null_checksums || expected_digest != &NULL_SHA1) && &actual_digest != expected_digest {
+                            check_error!(ok, result_sender, abort_on_error, checksum_error(record.filename(), expected_digest, &actual_digest));
+                        }
                     } else if let Err(error) = check_data(&mut reader, record.filename(), offset,
                             record.size(), record.sha1().as_ref().unwrap_or(&NULL_SHA1), ignore_null_checksums, &mut buffer) {
                         check_error!(ok, result_sender, abort_on_error, error);
                     }
-
                     if ok {
                         let _ = result_sender.send(Ok(record));
                     }
@@ -312,16 +589,20 @@ pub fn check<'a>(pak: &'a Pak, in_file: &mut File, options: CheckOptions) -> Res
         drop(result_sender);
 
         if let Some(filter) = &mut filter {
-            let records = pak.index().records()
-                .iter()
-                .filter(|&record| filter.visit(record.filename()));
+            let mut records = pak.filter_records(filter);
+            sort_records(&mut records, order);
 
-            error_count += enqueue(records, work_sender, abort_on_error, null_separated)?;
+            errors.extend(enqueue(records.into_iter(), work_sender, &progress, abort_on_error)?);
+        } else if order == WorkOrder::Index {
+            errors.extend(enqueue(pak.index().records().iter(), work_sender, &progress, abort_on_error)?);
         } else {
-            error_count += enqueue(pak.index().records().iter(), work_sender, abort_on_error, null_separated)?;
+            let mut records: Vec<&Record> = pak.index().records().iter().collect();
+            sort_records(&mut records, order);
+
+            errors.extend(enqueue(records.into_iter(), work_sender, &progress, abort_on_error)?);
         }
 
-        let linesep = if options.null_separated { '\0' } else { '\n' };
+        let linesep = if null_separated { '\0' } else { '\n' };
 
         while let Ok(result) = result_receiver.recv() {
             match result {
@@ -331,34 +612,27 @@ pub fn check<'a>(pak: &'a Pak, in_file: &mut File, options: CheckOptions) -> Res
                     }
                 }
                 Err(error) => {
-                    error_count += 1;
                     if abort_on_error {
-                        return Err(error);
+                        return Err(error.error);
                     }
-                    let _ = error.write_to(&mut stderr, null_separated);
+                    errors.push(error);
                 }
             }
         }
 
         if let Some(filter) = &filter {
-            let mut iter = filter.non_visited_paths();
-            if let Some(filename) = iter.next() {
-                let mut message = format!("Paths not found in pak:\n* {}", filename);
-                error_count += 1;
-                for filename in iter {
-                    message.push_str("\n* ");
-                    message.push_str(&filename);
-                    error_count += 1;
-                }
-                let error = Error::new(message);
-                if abort_on_error {
-                    return Err(error);
-                }
-                let _ = error.write_to(&mut stderr, null_separated);
+            let not_found: Vec<String> = filter.non_visited_paths().collect();
+            if !not_found.is_empty() && abort_on_error {
+                return Err(Error::new(format!("Paths not found in pak:\n* {}", not_found.join("\n* "))));
+            }
+
+            for filename in not_found {
+                errors.push(CheckError::new(CheckErrorKind::PathNotFound,
+                    Error::new("path not found in pak".to_string()).with_path(filename)));
             }
         }
 
-        Ok(error_count)
+        Ok(errors)
     });
 
     match thread_result {
@@ -369,24 +643,165 @@ pub fn check<'a>(pak: &'a Pak, in_file: &mut File, options: CheckOptions) -> Res
     }
 }
 
-fn enqueue<'a>(records: impl std::iter::Iterator<Item=&'a Record>, work_sender: Sender<&'a Record>, abort_on_error: bool, null_separated: bool) -> Result<usize> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Overlap<'a> {
+    pub record: &'a Record,
+    // the record that starts before record
+    pub other: &'a Record,
+    pub size: u64,
+}
+
+// Finds overlapping records and unused regions between the start of the file
+// and the index. Records that point to exactly the same region share their
+// data and are not reported. Overlaps with the index are already reported per
+// record by check() as "data bleeds into index".
+pub fn analyze_layout(pak: &Pak) -> (Vec<Overlap<'_>>, Vec<Gap>) {
+    let mut regions: Vec<(u64, u64, &Record)> = pak.index().records().iter()
+        .map(|record| {
+            let (start, end) = pak.record_region(record);
+            (start, end, record)
+        })
+        .collect();
+    regions.sort_by_key(|&(start, end, _)| (start, end));
+
+    let mut overlaps = Vec::new();
+    let mut gaps = Vec::new();
+    let mut prev: Option<(u64, u64, &Record)> = None;
+    let mut covered_end = 0u64;
+
+    for &(start, end, record) in &regions {
+        if let Some((prev_start, prev_end, prev_record)) = prev {
+            if start < prev_end && (start, end) != (prev_start, prev_end) {
+                overlaps.push(Overlap {
+                    record,
+                    other: prev_record,
+                    size: prev_end.min(end) - start,
+                });
+            }
+        }
+
+        if start > covered_end {
+            gaps.push(Gap { offset: covered_end, size: start - covered_end });
+        }
+
+        if end > covered_end {
+            covered_end = end;
+            prev = Some((start, end, record));
+        }
+    }
+
+    if pak.index_offset() > covered_end {
+        gaps.push(Gap { offset: covered_end, size: pak.index_offset() - covered_end });
+    }
+
+    (overlaps, gaps)
+}
+
+fn check_layout(pak: &Pak, abort_on_error: bool) -> Result<Vec<CheckError>> {
+    let (overlaps, _) = analyze_layout(pak);
+    let mut errors = Vec::new();
+
+    for overlap in &overlaps {
+        let error = Error::new(format!(
+            "data overlaps with {} by {} bytes",
+            overlap.other.filename(), overlap.size,
+        )).with_path(overlap.record.filename());
+
+        if abort_on_error {
+            return Err(error);
+        }
+        errors.push(CheckError::new(CheckErrorKind::Overlap, error));
+    }
+
+    Ok(errors)
+}
+
+// Read adapter that hashes the first limit bytes read through it.
+struct Sha1Reader<R> {
+    inner: R,
+    hasher: OpenSSLSha1,
+    limit: u64,
+}
+
+impl<R> Sha1Reader<R> {
+    #[inline]
+    fn new(inner: R, limit: u64) -> Self {
+        Self { inner, hasher: OpenSSLSha1::new(), limit }
+    }
+
+    #[inline]
+    fn finish(self) -> Sha1 {
+        self.hasher.finish()
+    }
+}
+
+impl<R: Read> Read for Sha1Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        let hashed = std::cmp::min(count as u64, self.limit) as usize;
+        self.hasher.update(&buf[..hashed]);
+        self.limit -= hashed as u64;
+        Ok(count)
+    }
+}
+
+impl<R: Seek> Seek for Sha1Reader<R> {
+    #[inline]
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+struct CountingWriter(u64);
+
+impl Write for CountingWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Rough upper bound of the memory needed for checking record.
+fn memory_estimate(record: &Record, deep: bool) -> u64 {
+    let block_size = record.compression_block_size() as u64;
+    let read_size = std::cmp::max(block_size, BUFFER_SIZE as u64);
+    if deep {
+        read_size + 2 * block_size
+    } else {
+        read_size
+    }
+}
+
+fn enqueue<'a>(records: impl std::iter::Iterator<Item=&'a Record>, work_sender: Sender<&'a Record>, progress: &Progress, abort_on_error: bool) -> Result<Vec<CheckError>> {
     let mut filenames: HashSet<&str> = HashSet::new();
-    let mut error_count = 0usize;
+    let mut errors = Vec::new();
     for record in records {
         if !filenames.insert(record.filename()) {
             let error = Error::new(
                 "filename not unique in archive".to_string()
             ).with_path(record.filename());
 
-            error_count += 1;
             if abort_on_error {
                 return Err(error);
-            } else {
-                let _ = error.write_to(&mut stderr(), null_separated);
             }
+            errors.push(CheckError::new(CheckErrorKind::DuplicateFilename, error));
         }
 
+        progress.add_total(1, record.size());
         let _ = work_sender.send(record);
     }
-    Ok(error_count)
+    progress.finish_totals();
+    Ok(errors)
 }
@@ -186,7 +186,8 @@ macro_rules! decode {
     (@read ($($wrap:tt)*) ($reader:expr) $name:ident $type:ty [$count:ty]) => {
         $name = {
             let _count = <$count>::decode($reader)? as usize;
-            let mut _items = Vec::with_capacity(_count);
+            // the count comes from the file, don't trust it for the allocation
+            let mut _items = Vec::with_capacity(_count.min(4096));
             for _ in 0.._count {
                 _items.push(<$type>::decode($reader)?);
             }
@@ -4,16 +4,175 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use aes::cipher::{BlockDecrypt, NewBlockCipher};
+// How encrypted data (index and record data) is en- and decrypted. Unreal
+// Engine uses AES-256 in ECB mode, but some games use other schemes (e.g. CBC
+// with a game specific IV). Every encrypted part (the index, the secondary
+// indices, the data of an uncompressed record, each compression block) is en-
+// or decrypted on its own, so a CBC chain starts with the IV for each of them.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, NewBlockCipher};
 use aes::{Aes256, Block, BLOCK_SIZE};
 use log::trace;
 
-pub fn decrypt(data: &mut Vec<u8>, key: &Vec<u8>) {
-    trace!("Decrypting data using aes256 with key {:?}", key);
-    let cipher = Aes256::new_from_slice(&key).expect("Unable to convert key to Aes256 cipher");
-    assert_eq!(data.len() % BLOCK_SIZE, 0, "Data length must be a multiple of 16");
+use crate::{Error, ErrorKind, Result};
+
+pub trait Cipher: Debug + Send + Sync {
+    fn name(&self) -> String;
+
+    // data.len() is always a multiple of the AES block size
+    fn decrypt(&self, data: &mut [u8], key: &[u8]);
+    fn encrypt(&self, data: &mut [u8], key: &[u8]);
+
+    // Decrypts data that directly follows the encrypted block prev of the same
+    // part, e.g. when an uncompressed record is read in chunks.
+    fn decrypt_after(&self, data: &mut [u8], key: &[u8], _prev: &[u8; BLOCK_SIZE]) {
+        self.decrypt(data, key)
+    }
+}
+
+#[inline]
+fn new_aes256(key: &[u8]) -> Aes256 {
+    Aes256::new_from_slice(key).expect("Unable to convert key to Aes256 cipher")
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ecb;
+
+impl Cipher for Ecb {
+    #[inline]
+    fn name(&self) -> String {
+        "ecb".to_string()
+    }
+
+    fn decrypt(&self, data: &mut [u8], key: &[u8]) {
+        trace!("Decrypting data using aes256 with key {:?}", key);
+        let cipher = new_aes256(key);
+        assert_eq!(data.len() % BLOCK_SIZE, 0, "Data length must be a multiple of 16");
+
+        for block in data.chunks_mut(BLOCK_SIZE) {
+            cipher.decrypt_block(Block::from_mut_slice(block));
+        }
+    }
+
+    fn encrypt(&self, data: &mut [u8], key: &[u8]) {
+        trace!("Encrypting data using aes256");
+        let cipher = new_aes256(key);
+        assert_eq!(data.len() % BLOCK_SIZE, 0, "Data length must be a multiple of 16");
+
+        for block in data.chunks_mut(BLOCK_SIZE) {
+            cipher.encrypt_block(Block::from_mut_slice(block));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cbc {
+    pub iv: [u8; BLOCK_SIZE],
+}
+
+impl Cbc {
+    #[inline]
+    pub fn new(iv: [u8; BLOCK_SIZE]) -> Self {
+        Self { iv }
+    }
+}
 
-    for block in data.chunks_mut(BLOCK_SIZE) {
-        cipher.decrypt_block(Block::from_mut_slice(block));
+impl Cipher for Cbc {
+    fn name(&self) -> String {
+        if self.iv == [0u8; BLOCK_SIZE] {
+            "cbc".to_string()
+        } else {
+            let iv: String = self.iv.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("cbc:{}", iv)
+        }
     }
+
+    #[inline]
+    fn decrypt(&self, data: &mut [u8], key: &[u8]) {
+        self.decrypt_after(data, key, &self.iv)
+    }
+
+    fn decrypt_after(&self, data: &mut [u8], key: &[u8], prev: &[u8; BLOCK_SIZE]) {
+        trace!("Decrypting data using aes256-cbc with key {:?}", key);
+        let cipher = new_aes256(key);
+        assert_eq!(data.len() % BLOCK_SIZE, 0, "Data length must be a multiple of 16");
+
+        let mut prev = *prev;
+        for block in data.chunks_mut(BLOCK_SIZE) {
+            let mut encrypted = [0u8; BLOCK_SIZE];
+            encrypted.copy_from_slice(block);
+            cipher.decrypt_block(Block::from_mut_slice(block));
+            for (byte, prev_byte) in block.iter_mut().zip(prev.iter()) {
+                *byte ^= prev_byte;
+            }
+            prev = encrypted;
+        }
+    }
+
+    fn encrypt(&self, data: &mut [u8], key: &[u8]) {
+        trace!("Encrypting data using aes256-cbc");
+        let cipher = new_aes256(key);
+        assert_eq!(data.len() % BLOCK_SIZE, 0, "Data length must be a multiple of 16");
+
+        let mut prev = self.iv;
+        for block in data.chunks_mut(BLOCK_SIZE) {
+            for (byte, prev_byte) in block.iter_mut().zip(prev.iter()) {
+                *byte ^= prev_byte;
+            }
+            cipher.encrypt_block(Block::from_mut_slice(block));
+            prev.copy_from_slice(block);
+        }
+    }
+}
+
+#[inline]
+pub fn default_cipher() -> Arc<dyn Cipher> {
+    Arc::new(Ecb)
+}
+
+// "ecb", "cbc" (IV of zeros) or "cbc:IV" with the IV as 32 hex digits
+pub fn parse_cipher(value: &str) -> Result<Arc<dyn Cipher>> {
+    let value = value.trim();
+    let (name, iv) = match value.find(':') {
+        Some(index) => (&value[..index], Some(&value[index + 1..])),
+        None => (value, None),
+    };
+
+    if name.eq_ignore_ascii_case("ecb") && iv.is_none() {
+        return Ok(Arc::new(Ecb));
+    }
+
+    if !name.eq_ignore_ascii_case("cbc") {
+        return Err(Error::new(format!("unsupported cipher: {:?}", value))
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+
+    let mut cbc = Cbc::default();
+    if let Some(iv) = iv {
+        let hex = iv.strip_prefix("0x").or_else(|| iv.strip_prefix("0X")).unwrap_or(iv);
+        if hex.len() != BLOCK_SIZE * 2 || !hex.is_ascii() {
+            return Err(Error::new(format!("illegal IV {:?}: expected {} hex digits", iv, BLOCK_SIZE * 2))
+                .with_kind(ErrorKind::InvalidArgument));
+        }
+        for (index, byte) in cbc.iv.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).map_err(|error|
+                Error::new(format!("illegal IV {:?}: {}", iv, error)).with_kind(ErrorKind::InvalidArgument))?;
+        }
+    }
+
+    Ok(Arc::new(cbc))
+}
+
+// AES-256 in ECB mode, as used by Unreal Engine.
+#[inline]
+pub fn decrypt(data: &mut [u8], key: &[u8]) {
+    Ecb.decrypt(data, key)
+}
+
+#[inline]
+pub fn encrypt(data: &mut [u8], key: &[u8]) {
+    Ecb.encrypt(data, key)
 }
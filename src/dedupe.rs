@@ -0,0 +1,62 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{Pak, Record};
+use crate::pak::Sha1;
+
+#[derive(Debug, Clone)]
+pub struct DuplicateGroup<'a> {
+    pub sha1: Sha1,
+    pub uncompressed_size: u64,
+    // in index order
+    pub records: Vec<&'a Record>,
+    // bytes that could be saved if all records shared the data of one of
+    // them, records that already share their data don't count
+    pub wasted: u64,
+}
+
+// Groups the records of a package by the SHA-1 stored in the index and their
+// uncompressed size. Only groups with more than one record are returned,
+// the ones that waste the most space first. Records without a SHA-1 are
+// ignored. The SHA-1 is of the data as it is stored, so the same file
+// compressed with different settings isn't found.
+pub fn find_duplicates(pak: &Pak) -> Vec<DuplicateGroup<'_>> {
+    let mut groups: HashMap<(Sha1, u64), Vec<&Record>> = HashMap::new();
+    for record in pak.index().records() {
+        if let Some(sha1) = record.sha1() {
+            groups.entry((*sha1, record.uncompressed_size())).or_default().push(record);
+        }
+    }
+
+    let mut duplicates: Vec<DuplicateGroup> = groups.into_iter()
+        .filter(|(_, records)| records.len() > 1)
+        .map(|((sha1, uncompressed_size), records)| {
+            let mut offsets = HashSet::with_capacity(records.len());
+            let wasted = records.iter()
+                .filter(|record| offsets.insert(record.offset()))
+                .skip(1)
+                .map(|record| {
+                    let (start, end) = pak.record_region(record);
+                    end - start
+                })
+                .sum();
+
+            DuplicateGroup {
+                sha1,
+                uncompressed_size,
+                records,
+                wasted,
+            }
+        })
+        .collect();
+
+    duplicates.sort_by(|a, b| b.wasted.cmp(&a.wasted)
+        .then_with(|| a.records[0].filename().cmp(b.records[0].filename())));
+
+    duplicates
+}
@@ -0,0 +1,248 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use crate::{Error, Record, Result};
+use crate::util::{make_pak_path, parse_pak_path};
+
+// Directory tree view of the records of an index. Nodes are stored in a flat
+// vector and referenced by their index. The root directory is always node 0
+// and nodes are in the order they where encountered, i.e. parents always come
+// before their children.
+
+pub const ROOT: usize = 0;
+
+#[derive(Debug)]
+pub enum DirNodeKind<'a> {
+    File(&'a Record),
+    Dir(HashMap<&'a str, usize>),
+}
+
+#[derive(Debug)]
+pub struct DirNode<'a> {
+    parent: usize,
+    name: &'a str,
+    kind: DirNodeKind<'a>,
+    // cumulative for directories
+    file_count: usize,
+    size: u64,
+    uncompressed_size: u64,
+}
+
+impl<'a> DirNode<'a> {
+    // the root is its own parent
+    #[inline]
+    pub fn parent(&self) -> usize {
+        self.parent
+    }
+
+    // empty for the root
+    #[inline]
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    #[inline]
+    pub fn kind(&self) -> &DirNodeKind<'a> {
+        &self.kind
+    }
+
+    #[inline]
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind, DirNodeKind::Dir(_))
+    }
+
+    #[inline]
+    pub fn is_file(&self) -> bool {
+        matches!(self.kind, DirNodeKind::File(_))
+    }
+
+    #[inline]
+    pub fn record(&self) -> Option<&'a Record> {
+        match self.kind {
+            DirNodeKind::File(record) => Some(record),
+            DirNodeKind::Dir(_) => None,
+        }
+    }
+
+    #[inline]
+    pub fn children(&self) -> Option<&HashMap<&'a str, usize>> {
+        match &self.kind {
+            DirNodeKind::File(_) => None,
+            DirNodeKind::Dir(children) => Some(children),
+        }
+    }
+
+    // number of files in this directory and all sub-directories, 1 for files
+    #[inline]
+    pub fn file_count(&self) -> usize {
+        self.file_count
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[inline]
+    pub fn uncompressed_size(&self) -> u64 {
+        self.uncompressed_size
+    }
+}
+
+#[derive(Debug)]
+pub struct DirTree<'a> {
+    nodes: Vec<DirNode<'a>>,
+}
+
+impl<'a> DirTree<'a> {
+    pub fn new(records: &'a [Record]) -> Result<Self> {
+        let mut tree = DirTree {
+            nodes: vec![DirNode {
+                parent: ROOT,
+                name: "",
+                kind: DirNodeKind::Dir(HashMap::new()),
+                file_count: 0,
+                size: 0,
+                uncompressed_size: 0,
+            }],
+        };
+
+        for record in records {
+            tree.insert(record)?;
+        }
+
+        Ok(tree)
+    }
+
+    fn insert(&mut self, record: &'a Record) -> Result<usize> {
+        let path: Vec<_> = parse_pak_path(record.filename()).collect();
+        let mut parent = ROOT;
+
+        let name = if let Some(&name) = path.last() {
+            name
+        } else {
+            return Err(Error::new("empty path".to_string()).with_path(record.filename()));
+        };
+
+        for (index, &dirname) in path[..path.len() - 1].iter().enumerate() {
+            self.add_to(parent, record);
+
+            let new_node = self.nodes.len();
+            if let DirNodeKind::Dir(children) = &mut self.nodes[parent].kind {
+                if let Some(&child) = children.get(dirname) {
+                    parent = child;
+                } else {
+                    children.insert(dirname, new_node);
+                    self.nodes.push(DirNode {
+                        parent,
+                        name: dirname,
+                        kind: DirNodeKind::Dir(HashMap::new()),
+                        file_count: 0,
+                        size: 0,
+                        uncompressed_size: 0,
+                    });
+                    parent = new_node;
+                }
+            } else {
+                return Err(Error::new(format!("{}: not a directory", make_pak_path(path[0..index].iter()))));
+            }
+        }
+
+        let new_node = self.nodes.len();
+        if let DirNodeKind::Dir(children) = &mut self.nodes[parent].kind {
+            if children.contains_key(name) {
+                return Err(Error::new(format!("{}: file already exists", record.filename())));
+            }
+            children.insert(name, new_node);
+        } else {
+            return Err(Error::new(format!("{}: not a directory", make_pak_path(path[..path.len() - 1].iter()))));
+        }
+
+        self.add_to(parent, record);
+        self.nodes.push(DirNode {
+            parent,
+            name,
+            kind: DirNodeKind::File(record),
+            file_count: 1,
+            size: record.size(),
+            uncompressed_size: record.uncompressed_size(),
+        });
+
+        Ok(new_node)
+    }
+
+    #[inline]
+    fn add_to(&mut self, dir: usize, record: &Record) {
+        let node = &mut self.nodes[dir];
+        node.file_count += 1;
+        node.size += record.size();
+        node.uncompressed_size += record.uncompressed_size();
+    }
+
+    #[inline]
+    pub fn root(&self) -> &DirNode<'a> {
+        &self.nodes[ROOT]
+    }
+
+    #[inline]
+    pub fn get(&self, node: usize) -> Option<&DirNode<'a>> {
+        self.nodes.get(node)
+    }
+
+    #[inline]
+    pub fn nodes(&self) -> &[DirNode<'a>] {
+        &self.nodes
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // there is always at least the root
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn lookup(&self, path: &str) -> Option<usize> {
+        let mut node = ROOT;
+        for name in parse_pak_path(path) {
+            node = *self.nodes[node].children()?.get(name)?;
+        }
+        Some(node)
+    }
+
+    // directories first, then files, each sorted by name
+    pub fn sorted_children(&self, dir: usize) -> Vec<usize> {
+        let mut children: Vec<usize> = if let Some(children) = self.nodes.get(dir).and_then(DirNode::children) {
+            children.values().cloned().collect()
+        } else {
+            Vec::new()
+        };
+
+        children.sort_by(|&a, &b| {
+            let a = &self.nodes[a];
+            let b = &self.nodes[b];
+            a.is_file().cmp(&b.is_file()).then_with(|| a.name.cmp(b.name))
+        });
+
+        children
+    }
+
+    // path of node relative to the root of the tree
+    pub fn path(&self, mut node: usize) -> String {
+        let mut names = Vec::new();
+        while node != ROOT {
+            let dir_node = &self.nodes[node];
+            names.push(dir_node.name);
+            node = dir_node.parent;
+        }
+        make_pak_path(names.into_iter().rev())
+    }
+}
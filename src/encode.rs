@@ -19,6 +19,14 @@ impl Encode for u8 {
         Ok(())
     }
 }
+impl Encode for u16 {
+    #[inline]
+    fn encode(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&self.to_le_bytes())?;
+        Ok(())
+    }
+}
+
 impl Encode for u32 {
     #[inline]
     fn encode(&self, writer: &mut impl Write) -> Result<()> {
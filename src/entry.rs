@@ -0,0 +1,401 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+
+use flate2::bufread::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use aes::BLOCK_SIZE;
+use log::debug;
+
+use crate::{Error, ErrorKind, Pak, Record, Result};
+use crate::decrypt::Cipher;
+use crate::pak::{self, compression_method_name, Variant, PAK_RELATIVE_COMPRESSION_OFFSET_VERSION};
+use crate::util::align;
+use crate::warnings::Warnings;
+
+// Reading of single records. This doesn't need the file system or threads,
+// so it is also available without the "fs" feature (e.g. for wasm builds).
+
+#[inline]
+fn decompression_error(error: std::io::Error) -> Error {
+    Error::new(format!("decompression failed: {}", error)).with_kind(ErrorKind::DecompressionFailed)
+}
+
+// Uncompressed records are copied in chunks of this size. It is a multiple of
+// the AES block size, so each chunk of an encrypted record can be decrypted on
+// its own.
+const COPY_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Compression blocks are usually 64 KiB, but nothing stops a pak from using
+// huge ones. Buffers that grew beyond this aren't kept for the next record.
+const MAX_RETAINED_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+// Buffers that can be reused by unpack_record_to_writer_with() for several
+// records, which avoids two allocations per record when unpacking lots of
+// small files.
+#[derive(Debug, Default)]
+pub struct UnpackBuffers {
+    in_buffer: Vec<u8>,
+    out_buffer: Vec<u8>,
+}
+
+impl UnpackBuffers {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn release_large(&mut self) {
+        for buffer in [&mut self.in_buffer, &mut self.out_buffer] {
+            if buffer.capacity() > MAX_RETAINED_BUFFER_SIZE {
+                *buffer = Vec::new();
+            }
+        }
+    }
+}
+
+pub fn unpack_record_to_writer(record: &Record, version: u32, variant: Variant, in_file: &mut (impl Read + Seek), writer: &mut impl Write, encryption_key: Option<Vec<u8>>, cipher: &dyn Cipher) -> Result<()> {
+    unpack_record_to_writer_with(record, version, variant, in_file, writer, encryption_key, cipher, &mut UnpackBuffers::new())
+}
+
+// Like unpack_record_to_writer(), but uses the given buffers.
+#[allow(clippy::too_many_arguments)]
+pub fn unpack_record_to_writer_with(record: &Record, version: u32, variant: Variant, in_file: &mut (impl Read + Seek), writer: &mut impl Write, encryption_key: Option<Vec<u8>>, cipher: &dyn Cipher, buffers: &mut UnpackBuffers) -> Result<()> {
+    let result = unpack_record_buffered(record, version, variant, in_file, writer, encryption_key, cipher, buffers);
+    buffers.release_large();
+    result
+}
+
+// Writes the uncompressed content of the file at path in the package to
+// writer, without touching the file system. The path is looked up like with
+// Pak::record().
+pub fn extract_to(pak: &Pak, reader: &mut (impl Read + Seek), path: &str, writer: &mut impl Write) -> Result<()> {
+    extract_to_with_key(pak, reader, path, writer, None)
+}
+
+// Like extract_to(), but for encrypted records.
+pub fn extract_to_with_key(pak: &Pak, reader: &mut (impl Read + Seek), path: &str, writer: &mut impl Write, encryption_key: Option<Vec<u8>>) -> Result<()> {
+    let record = match pak.record(path) {
+        Some(record) => record,
+        None => return Err(Error::new("file not found in package".to_string())
+            .with_kind(ErrorKind::PathNotFound)
+            .with_path(path)),
+    };
+    unpack_record_to_writer(record, pak.version(), pak.variant(), reader, writer, encryption_key, pak.cipher())
+        .map_err(|error| error.with_path_if_none(record.filename()))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn unpack_record_buffered(record: &Record, version: u32, variant: Variant, in_file: &mut (impl Read + Seek), writer: &mut impl Write, encryption_key: Option<Vec<u8>>, cipher: &dyn Cipher, buffers: &mut UnpackBuffers) -> Result<()> {
+    let data_start = record.data_offset(version, variant);
+    in_file.seek(SeekFrom::Start(data_start))?;
+    debug!("unpacking {:?}", record);
+
+    let in_buffer = &mut buffers.in_buffer;
+    let out_buffer = &mut buffers.out_buffer;
+    match record.compression_method() {
+        pak::COMPR_NONE => {
+            let mut remaining = record.size();
+            let mut prev = None;
+            while remaining > 0 {
+                let chunk_size = std::cmp::min(remaining, COPY_CHUNK_SIZE);
+                prev = read_chunk(record, in_file, in_buffer, chunk_size, &encryption_key, cipher, prev.as_ref())?;
+                writer.write_all(in_buffer)?;
+                remaining -= chunk_size;
+            }
+        }
+        pak::COMPR_ZLIB => {
+            if record.compression_blocks().is_some() {
+                let mut pos = data_start;
+                for (block_start, block_end) in record.block_ranges(version) {
+                    // blocks are usually contiguous, don't throw away the
+                    // buffer of a BufReader for nothing
+                    if block_start != pos {
+                        pos = block_start;
+                        in_file.seek(SeekFrom::Start(pos))?;
+                    }
+
+                    let block_size = block_end - block_start;
+                    read_chunk(record, in_file, in_buffer, block_size, &encryption_key, cipher, None)?;
+                    pos += if record.encrypted() { align(block_size, BLOCK_SIZE as u64) } else { block_size };
+
+                    let mut zlib = ZlibDecoder::new(&in_buffer[..]);
+                    out_buffer.clear();
+                    zlib.read_to_end(out_buffer).map_err(decompression_error)?;
+                    writer.write_all(out_buffer)?;
+                }
+            } else {
+                // version 2 has compression support, but neither compression
+                // blocks nor encryption
+                let mut zlib = ZlibDecoder::new(BufReader::new(in_file.by_ref().take(record.size())));
+                out_buffer.clear();
+                out_buffer.resize(COPY_CHUNK_SIZE as usize, 0);
+                loop {
+                    let count = zlib.read(out_buffer).map_err(decompression_error)?;
+                    if count == 0 {
+                        break;
+                    }
+                    writer.write_all(&out_buffer[..count])?;
+                }
+            }
+        }
+        _ => {
+            return Err(Error::new(format!(
+                    "unsupported compression method: {}",
+                    compression_method_name(record.compression_method())))
+                .with_kind(ErrorKind::UnknownCompressionMethod)
+                .with_path(record.filename()));
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+// How compressed data is wrapped, for unpack_record_with_fallback().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeflateWrapper {
+    Zlib,
+    Raw,
+    Gzip,
+}
+
+impl DeflateWrapper {
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            DeflateWrapper::Zlib => "zlib",
+            DeflateWrapper::Raw  => "raw deflate",
+            DeflateWrapper::Gzip => "gzip",
+        }
+    }
+
+    fn inflate(self, data: &[u8], out: &mut Vec<u8>) -> std::io::Result<usize> {
+        match self {
+            DeflateWrapper::Zlib => ZlibDecoder::new(data).read_to_end(out),
+            DeflateWrapper::Raw  => DeflateDecoder::new(data).read_to_end(out),
+            DeflateWrapper::Gzip => GzDecoder::new(data).read_to_end(out),
+        }
+    }
+}
+
+const DEFLATE_WRAPPERS: [DeflateWrapper; 3] = [DeflateWrapper::Zlib, DeflateWrapper::Raw, DeflateWrapper::Gzip];
+
+// Decompresses all of a zlib record into out, with the given wrapper and the
+// block offsets taken as relative to the record or as absolute.
+#[allow(clippy::too_many_arguments)]
+fn inflate_record(record: &Record, version: u32, variant: Variant, relative_offsets: bool, wrapper: DeflateWrapper, in_file: &mut (impl Read + Seek), encryption_key: &Option<Vec<u8>>, cipher: &dyn Cipher, in_buffer: &mut Vec<u8>, out: &mut Vec<u8>) -> Result<()> {
+    if let Some(blocks) = record.compression_blocks() {
+        let base_offset = if relative_offsets { record.offset() } else { 0 };
+        // with the wrong interpretation the offsets can be anything
+        let max_block_size = align(record.size(), BLOCK_SIZE as u64);
+        for block in blocks {
+            if block.end_offset < block.start_offset || block.end_offset - block.start_offset > max_block_size {
+                return Err(Error::new("compression block out of bounds".to_string()));
+            }
+            in_file.seek(SeekFrom::Start(base_offset + block.start_offset))?;
+            read_chunk(record, in_file, in_buffer, block.end_offset - block.start_offset, encryption_key, cipher, None)?;
+            wrapper.inflate(in_buffer, out).map_err(decompression_error)?;
+        }
+    } else {
+        in_file.seek(SeekFrom::Start(record.data_offset(version, variant)))?;
+        read_chunk(record, in_file, in_buffer, record.size(), encryption_key, cipher, None)?;
+        wrapper.inflate(in_buffer, out).map_err(decompression_error)?;
+    }
+    Ok(())
+}
+
+// Like unpack_record_to_writer_with(), but if the data of a zlib record can't
+// be decompressed it is retried as raw deflate and gzip stream, and with the
+// block offsets taken the other way around (absolute instead of relative to
+// the record or vice versa), which some packages of version 5 to 7 get wrong.
+// A fallback only counts if it gives exactly the uncompressed size and it is
+// reported as warning. The content is decompressed into memory before it is
+// written to writer.
+#[allow(clippy::too_many_arguments)]
+pub fn unpack_record_with_fallback(record: &Record, version: u32, variant: Variant, in_file: &mut (impl Read + Seek), writer: &mut impl Write, encryption_key: Option<Vec<u8>>, cipher: &dyn Cipher, buffers: &mut UnpackBuffers, warnings: &Warnings) -> Result<()> {
+    if record.compression_method() != pak::COMPR_ZLIB {
+        return unpack_record_to_writer_with(record, version, variant, in_file, writer, encryption_key, cipher, buffers);
+    }
+
+    let mut content = Vec::new();
+    let error = match unpack_record_to_writer_with(record, version, variant, in_file, &mut content, encryption_key.clone(), cipher, buffers) {
+        Ok(()) => {
+            writer.write_all(&content)?;
+            writer.flush()?;
+            return Ok(());
+        }
+        Err(error) if error.kind() == ErrorKind::DecompressionFailed => error,
+        Err(error) => return Err(error),
+    };
+
+    let relative = version >= PAK_RELATIVE_COMPRESSION_OFFSET_VERSION;
+    let offset_interpretations: &[bool] = if record.compression_blocks().is_some() { &[relative, !relative] } else { &[relative] };
+    for &relative_offsets in offset_interpretations {
+        for wrapper in DEFLATE_WRAPPERS {
+            if relative_offsets == relative && wrapper == DeflateWrapper::Zlib {
+                // that is what failed
+                continue;
+            }
+
+            content.clear();
+            let result = inflate_record(record, version, variant, relative_offsets, wrapper, in_file, &encryption_key, cipher, &mut buffers.in_buffer, &mut content);
+            if result.is_ok() && content.len() as u64 == record.uncompressed_size() {
+                warnings.warn(Some(record.filename()), format!(
+                    "corrupt zlib stream, decompressed it as {} with {} block offsets instead",
+                    wrapper.name(), if relative_offsets { "relative" } else { "absolute" }));
+                buffers.release_large();
+                writer.write_all(&content)?;
+                writer.flush()?;
+                return Ok(());
+            }
+        }
+    }
+    buffers.release_large();
+
+    Err(error)
+}
+
+// Reads size bytes at the current position of in_file into buffer and decrypts
+// them if needed. Encrypted data is read in whole AES blocks, the padding is
+// cut off again. prev is the encrypted block in front of the chunk if it
+// continues the data of the previous chunk. Returns the last encrypted block
+// of the chunk.
+#[allow(clippy::too_many_arguments)]
+fn read_chunk(record: &Record, in_file: &mut impl Read, buffer: &mut Vec<u8>, size: u64, encryption_key: &Option<Vec<u8>>, cipher: &dyn Cipher, prev: Option<&[u8; BLOCK_SIZE]>) -> Result<Option<[u8; BLOCK_SIZE]>> {
+    let buffer_length = if record.encrypted() { align(size, BLOCK_SIZE as u64) } else { size };
+    buffer.clear();
+    buffer.resize(buffer_length as usize, 0);
+    in_file.read_exact(buffer)?;
+
+    if record.encrypted() {
+        if let Some(key) = encryption_key {
+            let mut last = [0u8; BLOCK_SIZE];
+            if buffer.len() >= BLOCK_SIZE {
+                last.copy_from_slice(&buffer[buffer.len() - BLOCK_SIZE..]);
+            }
+            match prev {
+                Some(prev) => cipher.decrypt_after(buffer, key, prev),
+                None => cipher.decrypt(buffer, key),
+            }
+            buffer.truncate(size as usize);
+            return Ok(Some(last));
+        } else {
+            return Err(Error::new(
+                "File is encrypted, but no encryption key was provided".to_string(),
+            ).with_kind(ErrorKind::EncryptedNoKey).with_path(record.filename()));
+        }
+    }
+    Ok(None)
+}
+
+// Write adapter that only passes the bytes in the range offset..end of the
+// data written to it on to writer.
+struct RangeWriter<W: Write> {
+    writer: W,
+    pos: u64,
+    offset: u64,
+    end: u64,
+}
+
+impl<W: Write> Write for RangeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let buf_end = self.pos + buf.len() as u64;
+        if buf_end > self.offset && self.pos < self.end {
+            let start = self.offset.saturating_sub(self.pos) as usize;
+            let end = (std::cmp::min(buf_end, self.end) - self.pos) as usize;
+            self.writer.write_all(&buf[start..end])?;
+        }
+        self.pos = buf_end;
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Reads up to length bytes of the uncompressed content starting at offset.
+// See unpack_record_range_to_writer().
+#[allow(clippy::too_many_arguments)]
+pub fn read_record_range(record: &Record, version: u32, variant: Variant, in_file: &mut (impl Read + Seek), offset: u64, length: u64, encryption_key: Option<Vec<u8>>, cipher: &dyn Cipher) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    unpack_record_range_to_writer(record, version, variant, in_file, offset, length, &mut data, encryption_key, cipher)?;
+    Ok(data)
+}
+
+// Writes up to length bytes of the uncompressed content starting at offset to
+// writer. Only the needed part of uncompressed records and only the needed
+// compression blocks of compressed records are read, and they are written
+// one chunk or block at a time.
+#[allow(clippy::too_many_arguments)]
+pub fn unpack_record_range_to_writer(record: &Record, version: u32, variant: Variant, in_file: &mut (impl Read + Seek), offset: u64, length: u64, writer: &mut impl Write, encryption_key: Option<Vec<u8>>, cipher: &dyn Cipher) -> Result<()> {
+    let end = std::cmp::min(offset.saturating_add(length), record.uncompressed_size());
+    if offset >= end {
+        return Ok(());
+    }
+
+    let data_start = record.data_offset(version, variant);
+    let block_size = record.compression_block_size() as u64;
+    let mut buffer = Vec::new();
+
+    match (record.compression_method(), record.compression_blocks()) {
+        (pak::COMPR_NONE, _) => {
+            // encrypted data can only be decrypted in whole AES blocks
+            let read_start = if record.encrypted() { offset - offset % BLOCK_SIZE as u64 } else { offset };
+            // the encrypted block in front of the range, for chained ciphers
+            let mut prev = if record.encrypted() && read_start > 0 {
+                let mut block = [0u8; BLOCK_SIZE];
+                in_file.seek(SeekFrom::Start(data_start + read_start - BLOCK_SIZE as u64))?;
+                in_file.read_exact(&mut block)?;
+                Some(block)
+            } else {
+                None
+            };
+
+            in_file.seek(SeekFrom::Start(data_start + read_start))?;
+            let mut pos = read_start;
+            while pos < end {
+                let chunk_size = std::cmp::min(end - pos, COPY_CHUNK_SIZE);
+                prev = read_chunk(record, in_file, &mut buffer, chunk_size, &encryption_key, cipher, prev.as_ref())?;
+                writer.write_all(&buffer[offset.saturating_sub(pos) as usize..])?;
+                pos += chunk_size;
+            }
+        }
+        (pak::COMPR_ZLIB, Some(_)) if block_size > 0 => {
+            let first = offset / block_size;
+            let last = (end - 1) / block_size;
+            let mut out_buffer = Vec::new();
+            // uncompressed offset of the current block
+            let mut pos = first * block_size;
+
+            for (block_start, block_end) in record.block_ranges(version).take(last as usize + 1).skip(first as usize) {
+                in_file.seek(SeekFrom::Start(block_start))?;
+                read_chunk(record, in_file, &mut buffer, block_end - block_start, &encryption_key, cipher, None)?;
+
+                let mut zlib = ZlibDecoder::new(&buffer[..]);
+                out_buffer.clear();
+                zlib.read_to_end(&mut out_buffer).map_err(decompression_error)?;
+
+                let len = out_buffer.len() as u64;
+                let start = std::cmp::min(offset.saturating_sub(pos), len) as usize;
+                let stop = std::cmp::min(end - pos, len) as usize;
+                if start < stop {
+                    writer.write_all(&out_buffer[start..stop])?;
+                }
+                pos += block_size;
+            }
+        }
+        _ => {
+            let mut writer = RangeWriter { writer: &mut *writer, pos: 0, offset, end };
+            unpack_record_to_writer(record, version, variant, in_file, &mut writer, encryption_key, cipher)?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
@@ -6,7 +6,7 @@
 
 use std::iter::Map;
 
-use crate::Error;
+use crate::{Error, ErrorKind};
 use crate::Result;
 
 #[derive(Debug)]
@@ -150,7 +150,7 @@ impl<'a> Filter<'a> {
                 message.push_str("\n* ");
                 message.push_str(&filename);
             }
-            return Err(Error::new(message));
+            return Err(Error::new(message).with_kind(ErrorKind::PathNotFound));
         }
         Ok(())
     }
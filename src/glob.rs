@@ -0,0 +1,199 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Shell like wildcards in pack paths, e.g. Content/**/*.uasset, expanded by
+// u4pak itself because the Windows shell doesn't. Patterns are matched
+// against '/' separated relative paths:
+//
+//   *       any number of characters, but not '/'
+//   ?       any single character, but not '/'
+//   [abc]   one of the characters, also ranges like [a-z], [!abc] negated
+//   **      as a whole path component: any number of components (also none)
+//
+// On Windows matching ignores case like the file system does.
+
+use crate::{Error, ErrorKind, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    AnyChar,
+    AnyString,
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Component {
+    AnyComponents,
+    Pattern(Vec<Token>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    components: Vec<Component>,
+}
+
+#[inline]
+fn is_glob_char(ch: char) -> bool {
+    ch == '*' || ch == '?' || ch == '['
+}
+
+#[inline]
+fn is_separator(ch: char) -> bool {
+    ch == '/' || (cfg!(target_os = "windows") && ch == '\\')
+}
+
+#[inline]
+fn fold_case(ch: char) -> char {
+    if cfg!(target_os = "windows") {
+        ch.to_lowercase().next().unwrap_or(ch)
+    } else {
+        ch
+    }
+}
+
+#[inline]
+pub fn is_glob(path: &str) -> bool {
+    path.chars().any(is_glob_char)
+}
+
+// Splits a path with wildcards into the directory before the first component
+// with wildcards and the pattern for the paths below it, e.g.
+// Content/**/*.uasset into Content and **/*.uasset. None if the path has no
+// wildcards.
+pub fn split_glob(path: &str) -> Option<(&str, &str)> {
+    let glob_index = path.find(is_glob_char)?;
+    match path[..glob_index].rfind(is_separator) {
+        Some(index) => Some((&path[..index], &path[index + 1..])),
+        None => Some(("", path)),
+    }
+}
+
+fn parse_component(pattern: &str, component: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = component.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' => {
+                // ** inside of a component is the same as *
+                if tokens.last() != Some(&Token::AnyString) {
+                    tokens.push(Token::AnyString);
+                }
+            }
+            '?' => tokens.push(Token::AnyChar),
+            '[' => {
+                let negated = chars.next_if(|&ch| ch == '!' || ch == '^').is_some();
+                let mut ranges = Vec::new();
+                let mut first = true;
+                loop {
+                    let start = match chars.next() {
+                        // ] right after [ is a literal ]
+                        Some(']') if !first => break,
+                        Some(ch) => fold_case(ch),
+                        None => {
+                            return Err(Error::new(format!(
+                                "illegal glob pattern, unterminated [ in: {:?}", pattern))
+                                .with_kind(ErrorKind::InvalidArgument));
+                        }
+                    };
+                    first = false;
+                    let end = if chars.next_if_eq(&'-').is_some() {
+                        match chars.next() {
+                            Some(']') => {
+                                // trailing - is a literal -
+                                ranges.push((start, start));
+                                ranges.push(('-', '-'));
+                                break;
+                            }
+                            Some(ch) => fold_case(ch),
+                            None => {
+                                return Err(Error::new(format!(
+                                    "illegal glob pattern, unterminated [ in: {:?}", pattern))
+                                    .with_kind(ErrorKind::InvalidArgument));
+                            }
+                        }
+                    } else {
+                        start
+                    };
+                    ranges.push((start, end));
+                }
+                tokens.push(Token::Class { negated, ranges });
+            }
+            _ => tokens.push(Token::Char(fold_case(ch))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn match_tokens(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((Token::AnyString, rest)) => {
+            (0..=name.len()).any(|skip| match_tokens(rest, &name[skip..]))
+        }
+        Some((token, rest)) => {
+            let (&ch, name_rest) = match name.split_first() {
+                Some(split) => split,
+                None => return false,
+            };
+            let matches = match token {
+                Token::Char(expected) => *expected == ch,
+                Token::AnyChar => true,
+                Token::Class { negated, ranges } => {
+                    ranges.iter().any(|&(start, end)| start <= ch && ch <= end) != *negated
+                }
+                Token::AnyString => unreachable!(),
+            };
+            matches && match_tokens(rest, name_rest)
+        }
+    }
+}
+
+fn match_components(components: &[Component], names: &[Vec<char>]) -> bool {
+    match components.split_first() {
+        None => names.is_empty(),
+        Some((Component::AnyComponents, rest)) => {
+            (0..=names.len()).any(|skip| match_components(rest, &names[skip..]))
+        }
+        Some((Component::Pattern(tokens), rest)) => {
+            match names.split_first() {
+                Some((name, names_rest)) => match_tokens(tokens, name) && match_components(rest, names_rest),
+                None => false,
+            }
+        }
+    }
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut components = Vec::new();
+        for component in pattern.split(is_separator).filter(|component| !component.is_empty()) {
+            if component == "**" {
+                if components.last() != Some(&Component::AnyComponents) {
+                    components.push(Component::AnyComponents);
+                }
+            } else {
+                components.push(Component::Pattern(parse_component(pattern, component)?));
+            }
+        }
+
+        Ok(Self { components })
+    }
+
+    // path is relative to the directory the pattern is for, components are
+    // separated by '/' (and '\' on Windows)
+    pub fn matches(&self, path: &str) -> bool {
+        let names: Vec<Vec<char>> = path
+            .split(is_separator)
+            .filter(|name| !name.is_empty())
+            .map(|name| name.chars().map(fold_case).collect())
+            .collect();
+
+        match_components(&self.components, &names)
+    }
+}
@@ -0,0 +1,269 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Searching for a string or byte sequence in the decompressed content of
+// records. The content is streamed through the matcher, so records don't need
+// to fit into memory.
+
+use std::{fs::File, io::Write, num::NonZeroUsize};
+
+use crossbeam_channel::unbounded;
+use crossbeam_utils::thread;
+
+use crate::{Error, ErrorKind, Filter, Pak, Record, Result};
+use crate::reopen::Reopen;
+use crate::unpack::unpack_record_to_writer;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    // alternative encodings of the same pattern
+    needles: Vec<Vec<u8>>,
+    // ASCII only
+    ignore_case: bool,
+}
+
+impl Pattern {
+    // Matches the UTF-8 encoding of text and, if utf16 is set, also its
+    // UTF-16LE encoding (used for non-ASCII FStrings in packages).
+    pub fn text(text: &str, ignore_case: bool, utf16: bool) -> Result<Self> {
+        if text.is_empty() {
+            return Err(Error::new("empty search pattern".to_string()).with_kind(ErrorKind::InvalidArgument));
+        }
+        let mut needles = vec![text.as_bytes().to_vec()];
+        if utf16 {
+            needles.push(text.encode_utf16().flat_map(u16::to_le_bytes).collect());
+        }
+        Ok(Self { needles, ignore_case })
+    }
+
+    pub fn binary(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.is_empty() {
+            return Err(Error::new("empty search pattern".to_string()).with_kind(ErrorKind::InvalidArgument));
+        }
+        Ok(Self { needles: vec![bytes], ignore_case: false })
+    }
+
+    // Hex bytes, white space between the bytes is ignored, e.g. "DE AD BE EF".
+    pub fn parse_hex(hex: &str) -> Result<Self> {
+        let digits: Vec<u8> = hex.bytes().filter(|byte| !byte.is_ascii_whitespace()).collect();
+        if digits.len() % 2 == 1 {
+            return Err(Error::new(format!("odd number of hex digits: {:?}", hex)).with_kind(ErrorKind::InvalidArgument));
+        }
+        let mut bytes = Vec::with_capacity(digits.len() / 2);
+        for pair in digits.chunks(2) {
+            let pair = std::str::from_utf8(pair).unwrap_or("");
+            match u8::from_str_radix(pair, 16) {
+                Ok(byte) => bytes.push(byte),
+                Err(_) => return Err(Error::new(format!("illegal hex bytes: {:?}", hex)).with_kind(ErrorKind::InvalidArgument)),
+            }
+        }
+        Self::binary(bytes)
+    }
+
+    #[inline]
+    pub fn needles(&self) -> &[Vec<u8>] {
+        &self.needles
+    }
+
+    #[inline]
+    fn max_len(&self) -> usize {
+        self.needles.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    #[inline]
+    fn matches_at(&self, needle: &[u8], data: &[u8]) -> bool {
+        if self.ignore_case {
+            needle.eq_ignore_ascii_case(data)
+        } else {
+            needle == data
+        }
+    }
+}
+
+// Write adapter that records the offsets of all matches of the pattern in the
+// data written to it.
+pub struct MatchWriter<'a> {
+    pattern: &'a Pattern,
+    // the end of the previously written data, for matches spanning writes
+    tail: Vec<u8>,
+    // offset of the start of tail
+    offset: u64,
+    offsets: Vec<u64>,
+    max_count: Option<usize>,
+}
+
+impl<'a> MatchWriter<'a> {
+    pub fn new(pattern: &'a Pattern, max_count: Option<usize>) -> Self {
+        Self {
+            pattern,
+            tail: Vec::new(),
+            offset: 0,
+            offsets: Vec::new(),
+            max_count,
+        }
+    }
+
+    #[inline]
+    fn is_full(&self) -> bool {
+        matches!(self.max_count, Some(max_count) if self.offsets.len() >= max_count)
+    }
+
+    // Sorted offsets of the matches.
+    pub fn finish(mut self) -> Vec<u64> {
+        self.offsets.sort_unstable();
+        self.offsets.dedup();
+        self.offsets
+    }
+}
+
+impl Write for MatchWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.is_full() {
+            return Ok(buf.len());
+        }
+
+        let tail_len = self.tail.len();
+        self.tail.extend_from_slice(buf);
+        let data = &self.tail[..];
+
+        for needle in self.pattern.needles() {
+            // matches completely inside of the old tail were already found
+            let start = (tail_len + 1).saturating_sub(needle.len());
+            if data.len() < needle.len() {
+                continue;
+            }
+            for index in start..=(data.len() - needle.len()) {
+                if self.pattern.matches_at(needle, &data[index..index + needle.len()]) {
+                    self.offsets.push(self.offset + index as u64);
+                }
+            }
+        }
+
+        if let Some(max_count) = self.max_count {
+            if self.offsets.len() > max_count {
+                self.offsets.sort_unstable();
+                self.offsets.truncate(max_count);
+            }
+        }
+
+        let keep = std::cmp::min(self.pattern.max_len().saturating_sub(1), self.tail.len());
+        let drop_len = self.tail.len() - keep;
+        self.tail.drain(..drop_len);
+        self.offset += drop_len as u64;
+
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct GrepOptions<'a> {
+    pub paths: Option<&'a [&'a str]>,
+    pub thread_count: NonZeroUsize,
+    pub encryption_key: Option<Vec<u8>>,
+    // stop searching a record after that many matches
+    pub max_count: Option<usize>,
+}
+
+impl Default for GrepOptions<'_> {
+    fn default() -> Self {
+        Self {
+            paths: None,
+            thread_count: NonZeroUsize::new(num_cpus::get()).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            encryption_key: None,
+            max_count: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrepMatch {
+    pub filename: String,
+    // offsets of the matches in the uncompressed content
+    pub offsets: Vec<u64>,
+}
+
+fn grep_record(pak: &Pak, record: &Record, in_file: &mut File, pattern: &Pattern, options: &GrepOptions) -> Result<Vec<u64>> {
+    let mut writer = MatchWriter::new(pattern, options.max_count);
+    unpack_record_to_writer(record, pak.version(), pak.variant(), in_file, &mut writer, options.encryption_key.clone(), pak.cipher())
+        .map_err(|error| error.with_path_if_none(record.filename()))?;
+    Ok(writer.finish())
+}
+
+// Records that contain the pattern, in index order.
+pub fn grep(pak: &Pak, in_file: &mut File, pattern: &Pattern, options: GrepOptions) -> Result<Vec<GrepMatch>> {
+    let pak_path = in_file.path()?;
+    let mut filter: Option<Filter> = options.paths.map(|paths| paths.into());
+
+    let records: Vec<&Record> = if let Some(filter) = &mut filter {
+        pak.filter_records(filter)
+    } else {
+        pak.index().records().iter().collect()
+    };
+
+    if let Some(filter) = &filter {
+        filter.assert_all_visited()?;
+    }
+
+    let mut matches: Vec<Option<GrepMatch>> = vec![None; records.len()];
+
+    let thread_result = thread::scope::<_, Result<()>>(|scope| {
+        let (work_sender, work_receiver) = unbounded::<(usize, &Record)>();
+        let (result_sender, result_receiver) = unbounded::<(usize, Result<Vec<u64>>)>();
+
+        for _ in 0..options.thread_count.get() {
+            let work_receiver = work_receiver.clone();
+            let result_sender = result_sender.clone();
+            let mut in_file = File::open(&pak_path)?;
+            let options = &options;
+
+            scope.spawn(move |_| {
+                while let Ok((index, record)) = work_receiver.recv() {
+                    let result = grep_record(pak, record, &mut in_file, pattern, options);
+                    if result_sender.send((index, result)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        drop(work_receiver);
+        drop(result_sender);
+
+        for (index, record) in records.iter().enumerate() {
+            if let Err(error) = work_sender.send((index, record)) {
+                return Err(Error::new(error.to_string()).with_path(record.filename()));
+            }
+        }
+
+        drop(work_sender);
+
+        while let Ok((index, result)) = result_receiver.recv() {
+            let offsets = result?;
+            if !offsets.is_empty() {
+                matches[index] = Some(GrepMatch {
+                    filename: records[index].filename().to_string(),
+                    offsets,
+                });
+            }
+        }
+
+        Ok(())
+    });
+
+    match thread_result {
+        Err(error) => {
+            return Err(Error::new(format!("threading error: {:?}", error)));
+        }
+        Ok(result) => result?
+    }
+
+    Ok(matches.into_iter().flatten().collect())
+}
@@ -0,0 +1,254 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{convert::TryFrom, fs::File, io::Write, num::NonZeroUsize};
+
+use crossbeam_channel::unbounded;
+use crossbeam_utils::thread;
+use openssl::sha::{Sha1 as OpenSSLSha1, Sha256 as OpenSSLSha256};
+
+use crate::{Error, ErrorKind, Filter, Pak, Record, Result};
+use crate::pak::{COMPR_NONE, HexDisplay, NULL_SHA1, Sha1};
+use crate::reopen::Reopen;
+use crate::unpack::unpack_record_to_writer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl Default for DigestAlgorithm {
+    #[inline]
+    fn default() -> Self {
+        DigestAlgorithm::Sha1
+    }
+}
+
+impl DigestAlgorithm {
+    pub const ALL: [DigestAlgorithm; 2] = [DigestAlgorithm::Sha1, DigestAlgorithm::Sha256];
+
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha1   => "sha1",
+            DigestAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    #[inline]
+    pub fn digest_size(self) -> usize {
+        match self {
+            DigestAlgorithm::Sha1   => 20,
+            DigestAlgorithm::Sha256 => 32,
+        }
+    }
+}
+
+impl TryFrom<&str> for DigestAlgorithm {
+    type Error = crate::result::Error;
+
+    fn try_from(name: &str) -> std::result::Result<Self, Error> {
+        let name = name.trim();
+        DigestAlgorithm::ALL.iter().cloned()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name) ||
+                  algorithm.name().eq_ignore_ascii_case(&name.replace('-', "")))
+            .ok_or_else(|| Error::new(format!("unsupported digest algorithm: {:?}", name)).with_kind(ErrorKind::InvalidArgument))
+    }
+}
+
+// Write adapter that hashes everything written to it.
+pub enum DigestWriter {
+    Sha1(OpenSSLSha1),
+    Sha256(OpenSSLSha256),
+}
+
+impl DigestWriter {
+    pub fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Sha1   => DigestWriter::Sha1(OpenSSLSha1::new()),
+            DigestAlgorithm::Sha256 => DigestWriter::Sha256(OpenSSLSha256::new()),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            DigestWriter::Sha1(hasher)   => hasher.finish().to_vec(),
+            DigestWriter::Sha256(hasher) => hasher.finish().to_vec(),
+        }
+    }
+}
+
+impl Write for DigestWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DigestWriter::Sha1(hasher)   => hasher.update(buf),
+            DigestWriter::Sha256(hasher) => hasher.update(buf),
+        }
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Writes to the inner writer and hashes what was written if a digest writer
+// is given.
+pub(crate) struct TeeDigestWriter<W: Write> {
+    pub(crate) inner: W,
+    pub(crate) digest: Option<DigestWriter>,
+}
+
+impl<W: Write> Write for TeeDigestWriter<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        if let Some(digest) = &mut self.digest {
+            digest.write_all(&buf[..count])?;
+        }
+        Ok(count)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug)]
+pub struct HashOptions<'a> {
+    pub paths: Option<&'a [&'a str]>,
+    pub thread_count: NonZeroUsize,
+    pub encryption_key: Option<Vec<u8>>,
+    // also compute the SHA-1 of records where the one in the index already is
+    // the SHA-1 of the content
+    pub recompute: bool,
+    pub algorithm: DigestAlgorithm,
+}
+
+impl Default for HashOptions<'_> {
+    fn default() -> Self {
+        Self {
+            paths: None,
+            thread_count: NonZeroUsize::new(num_cpus::get()).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            encryption_key: None,
+            recompute: false,
+            algorithm: DigestAlgorithm::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHash {
+    pub filename: String,
+    // of the uncompressed and decrypted content
+    pub digest: Vec<u8>,
+}
+
+impl std::fmt::Display for FileHash {
+    // same format as sha1sum/sha256sum
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}  {}", HexDisplay::new(&self.digest), self.filename)
+    }
+}
+
+// The SHA-1 in the index is of the stored data, which only is the SHA-1 of the
+// content for uncompressed and unencrypted records.
+pub fn content_sha1(record: &Record) -> Option<&Sha1> {
+    if record.compression_method() != COMPR_NONE || record.encrypted() {
+        return None;
+    }
+    record.sha1().as_ref().filter(|sha1| *sha1 != &NULL_SHA1)
+}
+
+fn hash_record(pak: &Pak, record: &Record, in_file: &mut File, options: &HashOptions) -> Result<FileHash> {
+    if !options.recompute && options.algorithm == DigestAlgorithm::Sha1 {
+        if let Some(sha1) = content_sha1(record) {
+            return Ok(FileHash {
+                filename: record.filename().to_string(),
+                digest: sha1.to_vec(),
+            });
+        }
+    }
+
+    let encryption_key = &options.encryption_key;
+    let mut writer = DigestWriter::new(options.algorithm);
+    unpack_record_to_writer(record, pak.version(), pak.variant(), in_file, &mut writer, encryption_key.clone(), pak.cipher())
+        .map_err(|error| error.with_path_if_none(record.filename()))?;
+
+    Ok(FileHash {
+        filename: record.filename().to_string(),
+        digest: writer.finish(),
+    })
+}
+
+// Digests of the content of the records, in index order.
+pub fn hash(pak: &Pak, in_file: &mut File, options: HashOptions) -> Result<Vec<FileHash>> {
+    let pak_path = in_file.path()?;
+    let mut filter: Option<Filter> = options.paths.map(|paths| paths.into());
+
+    let records: Vec<&Record> = if let Some(filter) = &mut filter {
+        pak.filter_records(filter)
+    } else {
+        pak.index().records().iter().collect()
+    };
+
+    if let Some(filter) = &filter {
+        filter.assert_all_visited()?;
+    }
+
+    let mut hashes: Vec<Option<FileHash>> = vec![None; records.len()];
+
+    let thread_result = thread::scope::<_, Result<()>>(|scope| {
+        let (work_sender, work_receiver) = unbounded::<(usize, &Record)>();
+        let (result_sender, result_receiver) = unbounded::<(usize, Result<FileHash>)>();
+
+        for _ in 0..options.thread_count.get() {
+            let work_receiver = work_receiver.clone();
+            let result_sender = result_sender.clone();
+            let mut in_file = File::open(&pak_path)?;
+            let options = &options;
+
+            scope.spawn(move |_| {
+                while let Ok((index, record)) = work_receiver.recv() {
+                    let result = hash_record(pak, record, &mut in_file, options);
+                    if result_sender.send((index, result)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        drop(work_receiver);
+        drop(result_sender);
+
+        for (index, record) in records.iter().enumerate() {
+            if let Err(error) = work_sender.send((index, record)) {
+                return Err(Error::new(error.to_string()).with_path(record.filename()));
+            }
+        }
+
+        drop(work_sender);
+
+        while let Ok((index, result)) = result_receiver.recv() {
+            hashes[index] = Some(result?);
+        }
+
+        Ok(())
+    });
+
+    match thread_result {
+        Err(error) => {
+            return Err(Error::new(format!("threading error: {:?}", error)));
+        }
+        Ok(result) => result?
+    }
+
+    Ok(hashes.into_iter().flatten().collect())
+}
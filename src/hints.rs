@@ -0,0 +1,235 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Heuristics for info --game-hints. Looks at the footer, the mount point and
+// the file names to guess the engine version and sometimes the game, and
+// suggests the command line flags needed to read the package. Everything is
+// best effort, a package that can't be read at all still gives some hints.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::decode;
+use crate::decode::Decode;
+use crate::pak::{Options, Pak, Variant, PAK_MAX_SUPPORTED_VERSION};
+use crate::result::Result;
+use crate::warnings::Warnings;
+
+// Bytes from the magic to the end of the file: magic, version, index offset,
+// index size and index SHA-1 are the same in all versions. Newer versions
+// append the frozen flag and/or the compression method names.
+const FOOTER_TAIL_SIZE: u64 = 44;
+const V8_422_MAGIC_DISTANCE: u64 = FOOTER_TAIL_SIZE + 4 * 32;
+const MAGIC_DISTANCES: [u64; 4] = [
+    FOOTER_TAIL_SIZE,
+    V8_422_MAGIC_DISTANCE,
+    FOOTER_TAIL_SIZE + 5 * 32,
+    FOOTER_TAIL_SIZE + 1 + 5 * 32,
+];
+
+// project name (first path component of the files) -> game
+const KNOWN_GAMES: [(&str, &str, Option<Variant>); 4] = [
+    ("ConanSandbox",   "Conan Exiles",     Some(Variant::ConanExiles)),
+    ("DeadByDaylight", "Dead by Daylight", None),
+    ("FortniteGame",   "Fortnite",         None),
+    ("TslGame",        "PUBG",             None),
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GameHints {
+    // None if no footer was found
+    pub version: Option<u32>,
+    pub engine_version: Option<&'static str>,
+    pub project: Option<String>,
+    pub game: Option<&'static str>,
+    // the variant the index could be read with
+    pub variant: Option<Variant>,
+    // command line flags that are probably needed to read the package
+    pub flags: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+// Unreal Engine versions that write the given pak version.
+pub fn engine_version(version: u32) -> Option<&'static str> {
+    match version {
+        1  => Some("< 4.0"),
+        2  => Some("4.0 - 4.2"),
+        3  => Some("4.3 - 4.15"),
+        4  => Some("4.16 - 4.19"),
+        5  => Some("4.20"),
+        7  => Some("4.21"),
+        8  => Some("4.22 - 4.24"),
+        9  => Some("4.25"),
+        11 => Some("4.26 - 4.27"),
+        _  => None,
+    }
+}
+
+#[inline]
+fn magic_distance(version: u32) -> u64 {
+    (Pak::footer_size(version) - Pak::footer_magic_offset(version)) as u64
+}
+
+// magic, version, index offset and index size if the file is big enough
+fn read_footer_head(reader: &mut (impl Read + Seek), file_size: u64, distance: u64) -> Result<Option<(u32, u32, u64, u64)>> {
+    if distance > file_size {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(file_size - distance))?;
+    decode!(reader, magic: u32, version: u32, index_offset: u64, index_size: u64);
+    Ok(Some((magic, version, index_offset, index_size)))
+}
+
+// First path component of the files that isn't Engine, the mount point
+// usually is something like "../../../".
+fn project_name(pak: &Pak) -> Option<String> {
+    let mount_point = pak.index().mount_point().unwrap_or("");
+    pak.index().records().iter()
+        .filter_map(|record| {
+            let path = format!("{}/{}", mount_point, record.filename());
+            path.split('/')
+                .find(|component| !component.is_empty() && *component != "." && *component != "..")
+                .map(str::to_string)
+        })
+        .find(|project| !project.eq_ignore_ascii_case("Engine"))
+}
+
+pub fn game_hints(reader: &mut (impl Read + Seek), options: &Options) -> Result<GameHints> {
+    let mut hints = GameHints::default();
+    let file_size = reader.seek(SeekFrom::End(0))?;
+
+    let mut heads = Vec::with_capacity(MAGIC_DISTANCES.len());
+    for &distance in &MAGIC_DISTANCES {
+        if let Some(head) = read_footer_head(reader, file_size, distance)? {
+            heads.push((distance, head));
+        }
+    }
+
+    let mut magic = options.magic;
+    let version = if let Some(&(distance, (_, version, _, _))) = heads.iter().find(|(_, (found, _, _, _))| *found == magic) {
+        if version == 8 && distance == V8_422_MAGIC_DISTANCE {
+            hints.version = Some(version);
+            hints.engine_version = Some("4.22");
+            hints.notes.push("version 8 footer with only 4 compression methods (Unreal Engine 4.22), which isn't supported".to_string());
+            return Ok(hints);
+        }
+        if version > PAK_MAX_SUPPORTED_VERSION {
+            hints.version = Some(version);
+            hints.notes.push(format!("version {} is newer than the newest supported version {}, reading it as that might work",
+                version, PAK_MAX_SUPPORTED_VERSION));
+            hints.flags.push(format!("--force-version={}", PAK_MAX_SUPPORTED_VERSION));
+            PAK_MAX_SUPPORTED_VERSION
+        } else if distance != magic_distance(version) {
+            hints.version = Some(version);
+            hints.notes.push(format!("the footer doesn't have the layout of version {}", version));
+            return Ok(hints);
+        } else {
+            version
+        }
+    } else if let Some(&(_, (found, version, _, _))) = heads.iter().find(|&&(distance, (_, version, index_offset, index_size))| {
+        (1..=PAK_MAX_SUPPORTED_VERSION).contains(&version) &&
+        distance == magic_distance(version) &&
+        matches!(index_offset.checked_add(index_size), Some(end) if end <= file_size - distance)
+    }) {
+        hints.notes.push(format!("the package uses the non-standard magic 0x{:08X}", found));
+        hints.flags.push(format!("--magic=0x{:08X}", found));
+        magic = found;
+        version
+    } else {
+        hints.notes.push("no pak footer found, this is not a pak file or its footer is obfuscated".to_string());
+        return Ok(hints);
+    };
+
+    hints.version.get_or_insert(version);
+    hints.engine_version = hints.version.and_then(engine_version);
+
+    let footer = Pak::decode_footer(reader, version)?;
+    if footer.encrypted_index() && options.encryption_key_for(&footer).is_none() {
+        hints.notes.push("the index is encrypted, the AES key is needed to read it".to_string());
+        hints.flags.push("--encryption-key=KEY".to_string());
+        return Ok(hints);
+    }
+
+    for name in footer.compression_methods() {
+        if !name.eq_ignore_ascii_case("zlib") {
+            hints.notes.push(format!("uses {} compression, which isn't supported", name));
+        }
+    }
+
+    // The index can only be read with the right variant. Reading it with the
+    // wrong one might still work, but then gives warnings (e.g. about bytes
+    // at the end of the index that weren't read).
+    let mut variants = vec![options.variant];
+    variants.extend(Variant::ALL.iter().cloned().filter(|&variant| variant != options.variant));
+
+    let mut pak = None;
+    let mut first_error = None;
+    for variant in variants {
+        let warnings = Warnings::collect();
+        let result = Pak::from_reader(reader, Options {
+            variant,
+            magic,
+            force_version: Some(version),
+            harvest_names: false,
+            detect_variant: false,
+            warnings: warnings.clone(),
+            ..options.clone()
+        });
+        match result {
+            Ok(found) => {
+                let clean = warnings.take().is_empty();
+                if pak.is_none() || clean {
+                    pak = Some(found);
+                }
+                if clean {
+                    break;
+                }
+            }
+            Err(error) => {
+                if first_error.is_none() {
+                    first_error = Some(error);
+                }
+            }
+        }
+    }
+
+    let pak = if let Some(pak) = pak {
+        pak
+    } else {
+        if let Some(error) = first_error {
+            hints.notes.push(format!("the index can't be read with any variant: {}", error));
+        }
+        return Ok(hints);
+    };
+
+    hints.variant = Some(pak.variant());
+    if pak.variant() != options.variant {
+        hints.notes.push(format!("the index doesn't fit the {} variant, but the {} variant", options.variant.name(), pak.variant().name()));
+        // otherwise reading it detects the variant on its own
+        if !options.detect_variant {
+            hints.flags.push(format!("--variant={}", pak.variant().name()));
+        }
+    }
+
+    hints.project = project_name(&pak);
+    if let Some(project) = &hints.project {
+        if let Some(&(_, game, variant)) = KNOWN_GAMES.iter().find(|(name, _, _)| name.eq_ignore_ascii_case(project)) {
+            hints.game = Some(game);
+            if let Some(variant) = variant {
+                if variant != pak.variant() {
+                    hints.notes.push(format!("{} usually uses the {} variant", game, variant.name()));
+                }
+            }
+        }
+    }
+
+    if pak.index().hashed_names() && options.name_list.is_none() && !options.harvest_names {
+        hints.notes.push("the package only stores hashes of the file names".to_string());
+        hints.flags.push("--name-list=FILE".to_string());
+        hints.flags.push("--harvest-names".to_string());
+    }
+
+    Ok(hints)
+}
@@ -6,13 +6,53 @@
 
 use crate::decode;
 use crate::decode::Decode;
-use crate::decrypt::decrypt;
+use crate::decrypt::Cipher;
+use crate::pak::{Footer, Sha1, COMPR_NONE};
 use crate::Variant;
-use crate::{Error, Record, Result};
+use crate::{Error, ErrorKind, Record, Result};
+use crate::warnings::Warnings;
 
 use std::convert::TryFrom;
+use std::fmt::Debug;
 use std::io::{Cursor, Read, Seek, SeekFrom};
-use log::{debug, error, trace, warn};
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
+use std::sync::Arc;
+use log::{debug, trace};
+
+// Undoes an obfuscation of the index that some games apply besides (or instead
+// of) AES. It is called with the (decrypted) index and full directory index
+// before they are parsed.
+pub type IndexTransformFn = dyn Fn(&mut [u8]) + Send + Sync;
+
+#[derive(Clone)]
+pub struct IndexTransform(Arc<IndexTransformFn>);
+
+impl IndexTransform {
+    #[inline]
+    pub fn new(transform: impl Fn(&mut [u8]) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(transform))
+    }
+
+    // XOR every byte with key
+    pub fn xor(key: u8) -> Self {
+        Self::new(move |data| {
+            for byte in data {
+                *byte ^= key;
+            }
+        })
+    }
+
+    #[inline]
+    pub fn apply(&self, data: &mut [u8]) {
+        (self.0)(data)
+    }
+}
+
+impl Debug for IndexTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IndexTransform(..)")
+    }
+}
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Encoding {
@@ -60,7 +100,7 @@ impl TryFrom<&str> for Encoding {
         {
             Ok(Encoding::Latin1)
         } else {
-            Err(Error::new(format!("unsupported encoding: {:?}", encoding)))
+            Err(Error::new(format!("unsupported encoding: {:?}", encoding)).with_kind(ErrorKind::InvalidArgument))
         }
     }
 }
@@ -74,21 +114,102 @@ pub struct IndexLoadParams {
     write_full_directory_index: bool,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SecondaryIndexInfo {
+    path_hash_seed: u64,
     has_path_hash_index: bool,
     path_hash_index_offset: i64,
     path_hash_index_size: i64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::sha1_hex"))]
+    path_hash_index_sha1: Sha1,
     has_full_directory_index: bool,
     full_directory_index_offset: i64,
     full_directory_index_size: i64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::sha1_hex"))]
+    full_directory_index_sha1: Sha1,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::base64_bytes"))]
     encoded_record_info: Vec<u8>,
 }
 
+impl SecondaryIndexInfo {
+    #[inline]
+    pub fn path_hash_seed(&self) -> u64 {
+        self.path_hash_seed
+    }
+
+    #[inline]
+    pub fn has_path_hash_index(&self) -> bool {
+        self.has_path_hash_index
+    }
+
+    #[inline]
+    pub fn path_hash_index_offset(&self) -> u64 {
+        self.path_hash_index_offset as u64
+    }
+
+    #[inline]
+    pub fn path_hash_index_size(&self) -> u64 {
+        self.path_hash_index_size as u64
+    }
+
+    #[inline]
+    pub fn path_hash_index_sha1(&self) -> &Sha1 {
+        &self.path_hash_index_sha1
+    }
+
+    #[inline]
+    pub fn has_full_directory_index(&self) -> bool {
+        self.has_full_directory_index
+    }
+
+    #[inline]
+    pub fn full_directory_index_offset(&self) -> u64 {
+        self.full_directory_index_offset as u64
+    }
+
+    #[inline]
+    pub fn full_directory_index_size(&self) -> u64 {
+        self.full_directory_index_size as u64
+    }
+
+    #[inline]
+    pub fn full_directory_index_sha1(&self) -> &Sha1 {
+        &self.full_directory_index_sha1
+    }
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Index {
     mount_point: Option<String>,
     records: Vec<Record>,
+    // only in version >= 10, without the encoded records
+    #[cfg_attr(feature = "serde", serde(default))]
+    secondary_index: Option<SecondaryIndexInfo>,
+    // records are named by their path hash, because there was only a path hash index
+    #[cfg_attr(feature = "serde", serde(default))]
+    hashed_names: bool,
+}
+
+// FPakFile::HashPath(): FNV-1a 64 of the lower case UTF-16 path relative to the
+// mount point, with the seed added to the offset basis.
+pub fn path_hash(path: &str, seed: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64.wrapping_add(seed);
+    for unit in path.to_lowercase().encode_utf16() {
+        for byte in unit.to_le_bytes() {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+// The hash of a record that was named after its path hash.
+pub fn hashed_name(filename: &str) -> Option<u64> {
+    if filename.is_empty() || filename.len() > 16 || !filename.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(filename, 16).ok()
 }
 
 impl Index {
@@ -96,8 +217,11 @@ impl Index {
         Self {
             mount_point,
             records,
+            secondary_index: None,
+            hashed_names: false,
         }
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn read<R>(
         reader: &mut R,
         index_size: usize,
@@ -105,6 +229,9 @@ impl Index {
         variant: Variant,
         encoding: Encoding,
         encryption_key: Option<Vec<u8>>,
+        cipher: &dyn Cipher,
+        index_transform: Option<&IndexTransform>,
+        warnings: &Warnings,
     ) -> Result<Self> 
     where
         R: Read,
@@ -113,34 +240,53 @@ impl Index {
         let mut index_buff = vec![0; index_size as usize];
         reader.read_exact(&mut index_buff)?;
         if let Some(encryption_key) = &encryption_key {
-            decrypt(&mut index_buff, &encryption_key);
+            cipher.decrypt(&mut index_buff, encryption_key);
+        }
+        if let Some(index_transform) = index_transform {
+            index_transform.apply(&mut index_buff);
         }
 
         let decrypted_index = &mut Cursor::new(index_buff);
 
         let mount_point = read_path(decrypted_index, encoding)?;
         let records;
+        let mut secondary_index = None;
+        let mut hashed_names = false;
         if version < 10 {
-            records = read_records_legacy(decrypted_index, version, variant, encoding)
-                .expect("Failed to read index records");
+            records = read_records_legacy(decrypted_index, version, variant, encoding, warnings)?;
+
+            // an encrypted index is padded to the AES block size, anything
+            // beyond that usually means the records were read with the wrong
+            // variant
+            let unread = decrypted_index.get_ref().len() as u64 - decrypted_index.position();
+            let padding = if encryption_key.is_some() { AES_BLOCK_SIZE as u64 - 1 } else { 0 };
+            if unread > padding {
+                warnings.warn(None, format!("{} bytes at the end of the index weren't read", unread));
+            }
         } else {
-            if let Ok((index_info, mut r)) = read_records(decrypted_index, encoding) {
-                if let Ok(mut sec_records) = read_secondary_index_records(reader, &index_info, encryption_key, encoding) {
-                    r.append(&mut sec_records);
+            if let Ok((mut index_info, mut r)) = read_records(decrypted_index, encoding) {
+                hashed_names = !index_info.has_full_directory_index && index_info.has_path_hash_index;
+                match read_secondary_index_records(reader, &index_info, encryption_key, cipher, index_transform, encoding, warnings) {
+                    Ok(mut sec_records) => r.append(&mut sec_records),
+                    Err(error) => warnings.warn(None, format!("Failed to read secondary index: {}", error)),
                 }
+                index_info.encoded_record_info = Vec::new();
+                secondary_index = Some(index_info);
 
                 records = r;
             } else {
                 return Err(Error::new(format!(
                     "Only know how to handle Conan Exile paks of version 4, but version was {}.",
                     version
-                )));
+                )).with_kind(ErrorKind::CorruptIndex));
             }
         };
 
         Ok(Self {
             mount_point: if mount_point.is_empty() { None } else { Some(mount_point) },
             records,
+            secondary_index,
+            hashed_names,
         })
     }
 
@@ -152,17 +298,80 @@ impl Index {
         }
     }
 
+    // The records in index order.
     #[inline]
     pub fn records(&self) -> &[Record] {
         &self.records
     }
 
+    // Same as records().iter(), also what `for record in &index` iterates.
+    #[inline]
+    pub fn iter(&self) -> std::slice::Iter<'_, Record> {
+        self.records.iter()
+    }
+
+    // The records in index order for tools that post-process them (e.g.
+    // rename or fix offsets). Records can't be added or removed this way.
+    #[inline]
+    pub fn records_mut(&mut self) -> &mut [Record] {
+        &mut self.records
+    }
+
+    #[inline]
+    pub fn path_hash_seed(&self) -> Option<u64> {
+        self.secondary_index.as_ref().map(SecondaryIndexInfo::path_hash_seed)
+    }
+
+    #[inline]
+    pub fn secondary_index(&self) -> Option<&SecondaryIndexInfo> {
+        self.secondary_index.as_ref()
+    }
+
+    // Whether records without a known name are named after their path hash (as
+    // hex number).
+    #[inline]
+    pub fn hashed_names(&self) -> bool {
+        self.hashed_names
+    }
+
+    // The records in index order, dropping the rest of the index.
     #[inline]
     pub fn into_records<'a>(self) -> Vec<Record> {
         self.records
     }
 }
 
+// Iterates over the records in index order, like Index::into_records().
+impl IntoIterator for Index {
+    type Item = Record;
+    type IntoIter = std::vec::IntoIter<Record>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.records.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Index {
+    type Item = &'a Record;
+    type IntoIter = std::slice::Iter<'a, Record>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.records.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Index {
+    type Item = &'a mut Record;
+    type IntoIter = std::slice::IterMut<'a, Record>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.records.iter_mut()
+    }
+}
+
 pub fn read_path(reader: &mut impl Read, encoding: Encoding) -> Result<String> {
     let mut buf = [0; 4];
     reader.read_exact(&mut buf)?;
@@ -197,31 +406,117 @@ pub fn read_path(reader: &mut impl Read, encoding: Encoding) -> Result<String> {
     encoding.parse_vec(buf)
 }
 
+// Number of records detect_variant() looks at.
+const DETECT_VARIANT_RECORD_COUNT: usize = 16;
+// How much of the index is read for that at first.
+const DETECT_VARIANT_PREFIX_SIZE: u64 = 1024 * 1024;
+
+// Decodes the first few records of a version < 10 index with the given
+// variant and checks that they make sense. data is the (decrypted) index or
+// a prefix of it. None if the prefix was too short to tell.
+fn variant_fits(data: &[u8], complete: bool, padding: u64, version: u32, variant: Variant, encoding: Encoding, index_offset: u64) -> Option<bool> {
+    let read_record = match variant.spec().index_record_reader(version) {
+        Ok(read_record) => read_record,
+        Err(_) => return Some(false),
+    };
+
+    let mut cursor = Cursor::new(data);
+    let result = (|| -> Result<bool> {
+        read_path(&mut cursor, encoding)?;
+        decode!(&mut cursor, entry_count: u32);
+
+        let count = (entry_count as usize).min(DETECT_VARIANT_RECORD_COUNT);
+        for _ in 0..count {
+            let path_start = cursor.position();
+            skip_path(&mut cursor)?;
+            // just the size field and a NUL byte
+            if cursor.position() - path_start <= 5 {
+                return Ok(false);
+            }
+
+            let record = read_record(&mut cursor, String::new(), &Warnings::Ignore)?;
+            let sane_size = record.compression_method() != COMPR_NONE || record.size() == record.uncompressed_size();
+            let sane_offset = matches!(record.offset().checked_add(record.size()), Some(end) if end <= index_offset);
+            let sane_blocks = record.compression_blocks().iter().flatten()
+                .all(|block| block.start_offset <= block.end_offset);
+            if !sane_size || !sane_offset || !sane_blocks {
+                return Ok(false);
+            }
+        }
+
+        // all records were decoded, so nothing but padding may be left
+        if count == entry_count as usize && complete {
+            let unread = data.len() as u64 - cursor.position();
+            return Ok(unread <= padding);
+        }
+
+        Ok(true)
+    })();
+
+    match result {
+        Ok(fits) => Some(fits),
+        Err(_) if complete => Some(false),
+        Err(_) => None,
+    }
+}
+
+// The variant the records of a version < 10 index fit. preferred if it fits or
+// no variant does, None if data is a prefix of the index that is too short to
+// tell.
+pub fn detect_variant(data: &[u8], complete: bool, padding: u64, version: u32, preferred: Variant, encoding: Encoding, index_offset: u64) -> Option<Variant> {
+    let mut variants = vec![preferred];
+    variants.extend(Variant::ALL.iter().cloned().filter(|&variant| variant != preferred));
+
+    for variant in variants {
+        match variant_fits(data, complete, padding, version, variant, encoding, index_offset) {
+            Some(true) => {
+                if variant != preferred {
+                    debug!("Detected variant {}", variant.name());
+                }
+                return Some(variant);
+            }
+            Some(false) => {}
+            None => return None,
+        }
+    }
+
+    Some(preferred)
+}
+
+// detect_variant() for the pak the footer belongs to. Reads only the start of
+// the index if that is enough. options.variant if detection is disabled or
+// not needed (version >= 10).
+pub(crate) fn detect_pak_variant<R>(reader: &mut R, footer: &Footer, options: &crate::pak::Options) -> Result<Variant>
+where R: Read, R: Seek {
+    if !options.detect_variant || footer.version() >= 10 {
+        return Ok(options.variant);
+    }
+
+    let encryption_key = if footer.encrypted_index() { options.encryption_key_for(footer) } else { None };
+    let padding = if encryption_key.is_some() { AES_BLOCK_SIZE as u64 - 1 } else { 0 };
+    let cipher = &*options.cipher;
+    let index_transform = options.index_transform.as_ref();
+
+    let prefix_size = footer.index_size().min(DETECT_VARIANT_PREFIX_SIZE);
+    let data = read_index_data(reader, footer.index_offset(), prefix_size, encryption_key, cipher, index_transform)?;
+    let complete = prefix_size == footer.index_size();
+    if let Some(variant) = detect_variant(&data, complete, padding, footer.version(), options.variant, options.encoding, footer.index_offset()) {
+        return Ok(variant);
+    }
+
+    let data = read_index_data(reader, footer.index_offset(), footer.index_size(), encryption_key, cipher, index_transform)?;
+    Ok(detect_variant(&data, true, padding, footer.version(), options.variant, options.encoding, footer.index_offset())
+        .unwrap_or(options.variant))
+}
+
 pub fn read_records_legacy(
     reader: &mut impl Read,
     version: u32,
     variant: Variant,
     encoding: Encoding,
+    warnings: &Warnings,
 ) -> Result<Vec<Record>> {
-    let read_record = match variant {
-        Variant::ConanExiles => {
-            if version != 4 {
-                return Err(Error::new(format!(
-                    "Only know how to handle Conan Exile paks of version 4, but version was {}.",
-                    version
-                )));
-            }
-            Record::read_conan_exiles
-        }
-        Variant::Standard => match version {
-            1 => Record::read_v1,
-            2 => Record::read_v2,
-            _ if version <= 5 || version <= 9 => Record::read_v3,
-            _ => {
-                return Err(Error::new(format!("unsupported version: {}", version)));
-            }
-        },
-    };
+    let read_record = variant.spec().index_record_reader(version)?;
 
     decode!(reader, entry_count: u32);
 
@@ -229,7 +524,7 @@ pub fn read_records_legacy(
 
     for _ in 0..entry_count {
         let filename = read_path(reader, encoding)?;
-        let record = read_record(reader, filename)?;
+        let record = read_record(reader, filename, warnings)?;
         records.push(record);
     }
 
@@ -240,15 +535,33 @@ pub fn read_records(
     reader: &mut impl Read,
     encoding: Encoding,
 ) -> Result<(SecondaryIndexInfo, Vec<Record>)> {
+    let secondary_index_info = read_secondary_index_info(reader)?;
+
+    decode!(reader, file_count: u32);
+    let mut records = Vec::with_capacity(file_count as usize);
+    for _ in 0..file_count {
+        let filename = read_path(reader, encoding)?;
+        let record = Record::read_v3(reader, filename)?;
+        records.push(record);
+    }
+
+    Ok((secondary_index_info, records))
+}
+
+// The part of the primary index of version >= 10 in front of the records.
+fn read_secondary_index_info(reader: &mut impl Read) -> Result<SecondaryIndexInfo> {
     decode!(
         reader,
-        entry_count: i32,
+        _entry_count: i32,
         path_hash_seed: u64,
         has_path_hash_index: u32
     );
 
-    let mut secondary_index_info = SecondaryIndexInfo::default();
-    secondary_index_info.has_path_hash_index = has_path_hash_index != 0;
+    let mut secondary_index_info = SecondaryIndexInfo {
+        path_hash_seed,
+        has_path_hash_index: has_path_hash_index != 0,
+        ..Default::default()
+    };
 
     if secondary_index_info.has_path_hash_index {
         decode!(
@@ -261,6 +574,7 @@ pub fn read_records(
         secondary_index_info.has_path_hash_index = path_hash_index_size != -1;
         secondary_index_info.path_hash_index_offset = path_hash_index_offset;
         secondary_index_info.path_hash_index_size = path_hash_index_size;
+        secondary_index_info.path_hash_index_sha1 = path_hash_index_hash;
     }
     decode!(reader, has_full_directory_index: u32);
     secondary_index_info.has_full_directory_index = has_full_directory_index != 0;
@@ -275,28 +589,123 @@ pub fn read_records(
         secondary_index_info.has_full_directory_index = full_directory_index_size != -1;
         secondary_index_info.full_directory_index_offset = full_directory_index_offset;
         secondary_index_info.full_directory_index_size = full_directory_index_size;
+        secondary_index_info.full_directory_index_sha1 = full_directory_index_hash;
     }
     decode!(reader, pak_entries_size: i32);
     let mut pak_entries = vec![0u8; pak_entries_size as usize];
     reader.read_exact(&mut pak_entries)?;
     secondary_index_info.encoded_record_info = pak_entries;
 
-    decode!(reader, file_count: u32);
-    let mut records = Vec::with_capacity(file_count as usize);
-    for _ in 0..file_count {
-        let filename = read_path(reader, encoding)?;
-        let record = Record::read_v3(reader, filename)?;
-        records.push(record);
+    Ok(secondary_index_info)
+}
+
+// Offsets and sizes of the secondary indices of a version >= 10 pak, read from
+// the start of its primary index. Used to fetch them before parsing the index.
+#[cfg(feature = "async")]
+pub(crate) fn secondary_index_ranges<R>(reader: &mut R, footer: &Footer, options: &crate::pak::Options) -> Result<Vec<(u64, u64)>>
+where R: Read, R: Seek {
+    let encryption_key = if footer.encrypted_index() { options.encryption_key_for(footer) } else { None };
+    let primary_index = read_index_data(
+        reader, footer.index_offset(), footer.index_size(),
+        encryption_key, &*options.cipher, options.index_transform.as_ref())?;
+
+    let mut cursor = Cursor::new(&primary_index[..]);
+    read_path(&mut cursor, options.encoding)?;
+    let index_info = read_secondary_index_info(&mut cursor)?;
+
+    let mut ranges = Vec::new();
+    if index_info.has_path_hash_index {
+        ranges.push((index_info.path_hash_index_offset(), index_info.path_hash_index_size()));
+    }
+    if index_info.has_full_directory_index {
+        ranges.push((index_info.full_directory_index_offset(), index_info.full_directory_index_size()));
     }
+    Ok(ranges)
+}
 
-    Ok((secondary_index_info, records))
+// The index as it is stored in the pak (after decryption and transformation),
+// for debugging the index format.
+#[derive(Debug, Clone, Default)]
+pub struct RawIndex {
+    pub primary_index: Vec<u8>,
+    // the rest only in version >= 10 and if the primary index could be parsed
+    pub encoded_records: Option<Vec<u8>>,
+    pub path_hash_index: Option<Vec<u8>>,
+    pub full_directory_index: Option<Vec<u8>>,
+}
+
+fn read_index_data<R>(reader: &mut R, offset: u64, size: u64, encryption_key: Option<&Vec<u8>>, cipher: &dyn Cipher, index_transform: Option<&IndexTransform>) -> Result<Vec<u8>>
+where R: Read, R: Seek {
+    let mut data = vec![0u8; size as usize];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut data)?;
+    if let Some(key) = encryption_key {
+        cipher.decrypt(&mut data, key);
+    }
+    if let Some(index_transform) = index_transform {
+        index_transform.apply(&mut data);
+    }
+    Ok(data)
+}
+
+pub fn read_raw_index<R>(
+    reader: &mut R,
+    footer: &Footer,
+    encoding: Encoding,
+    encryption_key: Option<Vec<u8>>,
+    cipher: &dyn Cipher,
+    index_transform: Option<&IndexTransform>,
+    warnings: &Warnings,
+) -> Result<RawIndex>
+where
+    R: Read,
+    R: Seek,
+{
+    let encryption_key = if footer.encrypted_index() { encryption_key.as_ref() } else { None };
+    let primary_index = read_index_data(reader, footer.index_offset(), footer.index_size(), encryption_key, cipher, index_transform)?;
+    let mut raw_index = RawIndex {
+        primary_index,
+        ..RawIndex::default()
+    };
+
+    if footer.version() < 10 {
+        return Ok(raw_index);
+    }
+
+    let mut cursor = Cursor::new(&raw_index.primary_index[..]);
+    let index_info = match read_path(&mut cursor, encoding).and_then(|_| read_records(&mut cursor, encoding)) {
+        Ok((index_info, _)) => index_info,
+        Err(error) => {
+            warnings.warn(None, format!("Failed to parse primary index: {}", error));
+            return Ok(raw_index);
+        }
+    };
+
+    if index_info.has_path_hash_index {
+        raw_index.path_hash_index = Some(read_index_data(
+            reader, index_info.path_hash_index_offset(), index_info.path_hash_index_size(),
+            encryption_key, cipher, index_transform)?);
+    }
+
+    if index_info.has_full_directory_index {
+        raw_index.full_directory_index = Some(read_index_data(
+            reader, index_info.full_directory_index_offset(), index_info.full_directory_index_size(),
+            encryption_key, cipher, index_transform)?);
+    }
+
+    raw_index.encoded_records = Some(index_info.encoded_record_info);
+
+    Ok(raw_index)
 }
 
 fn read_secondary_index_records<R>(
     reader: &mut R,
     index_info: &SecondaryIndexInfo,
     encryption_key: Option<Vec<u8>>,
-    encoding: Encoding
+    cipher: &dyn Cipher,
+    index_transform: Option<&IndexTransform>,
+    encoding: Encoding,
+    warnings: &Warnings,
 ) -> Result<Vec<Record>> where
     R: Read,
     R: Seek,
@@ -309,19 +718,14 @@ fn read_secondary_index_records<R>(
         debug!("Reading full directory index");
         let mut full_directory_index_data =
             vec![0u8; index_info.full_directory_index_size as usize];
-        if let Err(err) = reader.seek(SeekFrom::Start(
-            index_info.full_directory_index_offset as u64,
-        )) {
-            error!("Failed to load fill directory index: {}", err);
-            return Err(Error::from(err));
-        }
-        if let Err(err) = reader.read_exact(&mut full_directory_index_data) {
-            error!("Failed to read full directory index: {}", err);
-            return Err(Error::from(err));
-        }
+        reader.seek(SeekFrom::Start(index_info.full_directory_index_offset as u64))?;
+        reader.read_exact(&mut full_directory_index_data)?;
 
         if let Some(key) = encryption_key {
-            decrypt(&mut full_directory_index_data, &key);
+            cipher.decrypt(&mut full_directory_index_data, &key);
+        }
+        if let Some(index_transform) = index_transform {
+            index_transform.apply(&mut full_directory_index_data);
         }
 
         let mut index_buff = &full_directory_index_data[..];
@@ -336,7 +740,7 @@ fn read_secondary_index_records<R>(
                     file_path.push_str(&p);
                 }
             } else {
-                warn!("Failed to resolve path for file {}. Skipping.", i);
+                warnings.warn(None, format!("Failed to resolve path for file {}. Skipping.", i));
                 continue;
             }
 
@@ -353,32 +757,24 @@ fn read_secondary_index_records<R>(
                     if let Ok(record) = Record::decode_entry(&mut encoded_record_info, p.clone()) {
                         records.push(record);
                     } else {
-                        warn!("Failed to read record for file {}. Skipping.", p);
+                        warnings.warn(Some(&p), "Failed to read record for file. Skipping.".to_string());
                     }
                 } else {
-                    warn!("Failed to resolve name for file {} in folder {}. Skipping.", i, file_path);
+                    warnings.warn(None, format!("Failed to resolve name for file {} in folder {}. Skipping.", i, file_path));
                     continue;
                 }
             }
         }
     } else if index_info.has_path_hash_index {
-        warn!("Hash index is used as no full directory index was found. Filenames and paths can not be restored using this index!");
+        warnings.warn(None, "Hash index is used as no full directory index was found. Filenames and paths can only be restored from a list of known names!".to_string());
         debug!("Reading path hash index from {} with size {}", index_info.path_hash_index_offset, index_info.path_hash_index_size);
         let mut path_hash_index_data =
             vec![0u8; index_info.path_hash_index_size as usize];
-        if let Err(err) = reader.seek(SeekFrom::Start(
-            index_info.path_hash_index_offset as u64,
-        )) {
-            error!("Failed to load fill directory index: {}", err);
-            return Err(Error::from(err));
-        }
-        if let Err(err) = reader.read_exact(&mut path_hash_index_data) {
-            error!("Failed to read full directory index: {}", err);
-            return Err(Error::from(err));
-        }
+        reader.seek(SeekFrom::Start(index_info.path_hash_index_offset as u64))?;
+        reader.read_exact(&mut path_hash_index_data)?;
 
         if let Some(key) = encryption_key {
-            decrypt(&mut path_hash_index_data, &key);
+            cipher.decrypt(&mut path_hash_index_data, &key);
         }
 
         let mut index_buff = &path_hash_index_data[..];
@@ -392,14 +788,315 @@ fn read_secondary_index_records<R>(
             if let Ok(record) = Record::decode_entry(&mut encoded_record_info, format!("{:x}", hash)) {
                 records.push(record);
             } else {
-                warn!("Failed to read record for file {:x}. Skipping.", hash);
+                warnings.warn(None, format!("Failed to read record for file {:x}. Skipping.", hash));
             }
         }
     } else {
-        warn!("Neither full direcotry nor hash index found! Files are probably missing!");
+        warnings.warn(None, "Neither full direcotry nor hash index found! Files are probably missing!".to_string());
     }
 
     debug!("Read {} records from secondary index", records.len());
 
     Ok(records)
 }
+
+// Where the data of an entry of a LazyIndex is.
+#[derive(Debug, Clone, Copy)]
+enum LazyEntry {
+    // path followed by the record in the primary index
+    Primary { path: usize },
+    // directory and file name in the full directory index, record in the
+    // encoded records
+    Directory { dir: usize, name: usize, record: usize },
+    // record in the encoded records, only the path hash is known
+    Hashed { hash: u64, record: usize },
+}
+
+// An index that keeps the (decrypted) index data and only decodes records when
+// they are asked for. Reading it only remembers where the entries are, which
+// is a lot faster and needs a lot less memory than Index::read() for paks
+// with many files. Entries are in the same order as in Index::records().
+#[derive(Debug)]
+pub struct LazyIndex {
+    footer: Footer,
+    variant: Variant,
+    encoding: Encoding,
+    mount_point: Option<String>,
+    primary_index: Vec<u8>,
+    full_directory_index: Vec<u8>,
+    // with the encoded records
+    secondary_index: Option<SecondaryIndexInfo>,
+    hashed_names: bool,
+    entries: Vec<LazyEntry>,
+    warnings: Warnings,
+}
+
+// Skips a string without decoding it.
+fn skip_path(reader: &mut Cursor<&[u8]>) -> Result<()> {
+    decode!(reader, size: i32);
+    let length = if size < 0 { 2 * -(size as i64) } else { size as i64 } as u64;
+    let pos = reader.position() + length;
+    if pos > reader.get_ref().len() as u64 {
+        return Err(Error::new("path exceeds index".to_string()).with_kind(ErrorKind::CorruptIndex));
+    }
+    reader.set_position(pos);
+    Ok(())
+}
+
+// How the secondary indices of a lazy index are read.
+struct SecondaryIndexParams<'a> {
+    encryption_key: Option<&'a Vec<u8>>,
+    cipher: &'a dyn Cipher,
+    index_transform: Option<&'a IndexTransform>,
+    warnings: &'a Warnings,
+}
+
+fn read_lazy_secondary_entries<R>(
+    reader: &mut R,
+    index_info: &SecondaryIndexInfo,
+    params: &SecondaryIndexParams,
+    entries: &mut Vec<LazyEntry>,
+    full_directory_index: &mut Vec<u8>,
+) -> Result<()> where
+    R: Read,
+    R: Seek,
+{
+    let SecondaryIndexParams { encryption_key, cipher, index_transform, warnings } = *params;
+    if index_info.has_full_directory_index {
+        *full_directory_index = read_index_data(
+            reader, index_info.full_directory_index_offset(), index_info.full_directory_index_size(),
+            encryption_key, cipher, index_transform)?;
+
+        let mut cursor = Cursor::new(&full_directory_index[..]);
+        decode!(&mut cursor, dir_count: u32);
+        for _ in 0..dir_count {
+            let dir = cursor.position() as usize;
+            skip_path(&mut cursor)?;
+            decode!(&mut cursor, file_count: u32);
+            for _ in 0..file_count {
+                let name = cursor.position() as usize;
+                skip_path(&mut cursor)?;
+                decode!(&mut cursor, record: u32);
+                entries.push(LazyEntry::Directory { dir, name, record: record as usize });
+            }
+        }
+    } else if index_info.has_path_hash_index {
+        warnings.warn(None, "Hash index is used as no full directory index was found. Filenames and paths can only be restored from a list of known names!".to_string());
+        // the index transform isn't used for the path hash index, see Index::read()
+        let path_hash_index = read_index_data(
+            reader, index_info.path_hash_index_offset(), index_info.path_hash_index_size(),
+            encryption_key, cipher, None)?;
+
+        let mut cursor = &path_hash_index[..];
+        decode!(&mut cursor, file_count: u32);
+        for _ in 0..file_count {
+            decode!(&mut cursor, hash: u64, record: u32);
+            entries.push(LazyEntry::Hashed { hash, record: record as usize });
+        }
+    } else {
+        warnings.warn(None, "Neither full direcotry nor hash index found! Files are probably missing!".to_string());
+    }
+
+    Ok(())
+}
+
+impl LazyIndex {
+    #[cfg(feature = "fs")]
+    pub fn from_path(path: impl AsRef<std::path::Path>, options: &crate::pak::Options) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).map_err(|error| Error::io_with_path(error, path))?;
+        Self::from_reader(&mut std::io::BufReader::new(file), options)
+            .map_err(|error| error.with_path_if_none(path))
+    }
+
+    // Names of paks that only have a path hash index are not recovered, see
+    // options.name_list and options.harvest_names.
+    pub fn from_reader<R>(reader: &mut R, options: &crate::pak::Options) -> Result<Self>
+    where R: Read, R: Seek {
+        let footer = crate::Pak::read_footer(reader, options)?;
+        Self::read(reader, footer, options).map_err(|error| match error.kind() {
+            ErrorKind::Other | ErrorKind::Io => error.with_kind(ErrorKind::CorruptIndex),
+            _ => error,
+        })
+    }
+
+    fn read<R>(reader: &mut R, footer: Footer, options: &crate::pak::Options) -> Result<Self>
+    where R: Read, R: Seek {
+        let version = footer.version();
+        let encoding = options.encoding;
+        let encryption_key = if footer.encrypted_index() { options.encryption_key_for(&footer) } else { None };
+        let cipher = &*options.cipher;
+        let index_transform = options.index_transform.as_ref();
+        let primary_index = read_index_data(reader, footer.index_offset(), footer.index_size(), encryption_key, cipher, index_transform)?;
+
+        let variant = if options.detect_variant && version < 10 {
+            let padding = if encryption_key.is_some() { AES_BLOCK_SIZE as u64 - 1 } else { 0 };
+            detect_variant(&primary_index, true, padding, version, options.variant, encoding, footer.index_offset())
+                .unwrap_or(options.variant)
+        } else {
+            options.variant
+        };
+
+        let mut entries = Vec::new();
+        let mut full_directory_index = Vec::new();
+        let mut secondary_index = None;
+        let mut hashed_names = false;
+
+        let mut cursor = Cursor::new(&primary_index[..]);
+        let mount_point = read_path(&mut cursor, encoding)?;
+
+        // the records are decoded to find where they end, but without their name
+        if version < 10 {
+            let read_record = variant.spec().index_record_reader(version)?;
+            decode!(&mut cursor, entry_count: u32);
+            entries.reserve(entry_count as usize);
+            for _ in 0..entry_count {
+                let path = cursor.position() as usize;
+                skip_path(&mut cursor)?;
+                read_record(&mut cursor, String::new(), &Warnings::Ignore)?;
+                entries.push(LazyEntry::Primary { path });
+            }
+        } else {
+            let index_info = read_secondary_index_info(&mut cursor)?;
+            decode!(&mut cursor, file_count: u32);
+            for _ in 0..file_count {
+                let path = cursor.position() as usize;
+                skip_path(&mut cursor)?;
+                Record::read_v3(&mut cursor, String::new())?;
+                entries.push(LazyEntry::Primary { path });
+            }
+
+            hashed_names = !index_info.has_full_directory_index && index_info.has_path_hash_index;
+            let primary_count = entries.len();
+            let params = SecondaryIndexParams {
+                encryption_key,
+                cipher,
+                index_transform,
+                warnings: &options.warnings,
+            };
+            if let Err(error) = read_lazy_secondary_entries(
+                    reader, &index_info, &params, &mut entries, &mut full_directory_index) {
+                options.warnings.warn(None, format!("Failed to read secondary index: {}", error));
+                entries.truncate(primary_count);
+                full_directory_index = Vec::new();
+            }
+
+            secondary_index = Some(index_info);
+        }
+
+        Ok(Self {
+            footer,
+            variant,
+            encoding,
+            mount_point: if mount_point.is_empty() { None } else { Some(mount_point) },
+            primary_index,
+            full_directory_index,
+            secondary_index,
+            hashed_names,
+            entries,
+            warnings: options.warnings.clone(),
+        })
+    }
+
+    #[inline]
+    pub fn footer(&self) -> &Footer {
+        &self.footer
+    }
+
+    #[inline]
+    pub fn version(&self) -> u32 {
+        self.footer.version()
+    }
+
+    #[inline]
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    #[inline]
+    pub fn mount_point(&self) -> Option<&str> {
+        self.mount_point.as_deref()
+    }
+
+    #[inline]
+    pub fn secondary_index(&self) -> Option<&SecondaryIndexInfo> {
+        self.secondary_index.as_ref()
+    }
+
+    // See Index::hashed_names().
+    #[inline]
+    pub fn hashed_names(&self) -> bool {
+        self.hashed_names
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn entry(&self, index: usize) -> Result<LazyEntry> {
+        self.entries.get(index).copied().ok_or_else(||
+            Error::new(format!("index out of bounds: {} >= {}", index, self.entries.len())))
+    }
+
+    // Only decodes the file name, not the whole record.
+    pub fn filename(&self, index: usize) -> Result<String> {
+        match self.entry(index)? {
+            LazyEntry::Primary { path } => {
+                read_path(&mut Cursor::new(&self.primary_index[path..]), self.encoding)
+            }
+            LazyEntry::Directory { dir, name, .. } => {
+                let dir = read_path(&mut Cursor::new(&self.full_directory_index[dir..]), self.encoding)?;
+                let name = read_path(&mut Cursor::new(&self.full_directory_index[name..]), self.encoding)?;
+                if dir == "/" {
+                    Ok(name)
+                } else {
+                    Ok(dir + &name)
+                }
+            }
+            LazyEntry::Hashed { hash, .. } => Ok(format!("{:x}", hash)),
+        }
+    }
+
+    pub fn record(&self, index: usize) -> Result<Record> {
+        let filename = self.filename(index)?;
+        match self.entry(index)? {
+            LazyEntry::Primary { path } => {
+                let mut cursor = Cursor::new(&self.primary_index[..]);
+                cursor.set_position(path as u64);
+                skip_path(&mut cursor)?;
+                if self.version() < 10 {
+                    let read_record = self.variant.spec().index_record_reader(self.version())?;
+                    read_record(&mut cursor, filename, &self.warnings)
+                } else {
+                    Record::read_v3(&mut cursor, filename)
+                }
+            }
+            LazyEntry::Directory { record, .. } | LazyEntry::Hashed { record, .. } => {
+                let encoded_records = self.secondary_index.as_ref()
+                    .map_or(&[][..], |info| &info.encoded_record_info[..]);
+                if record > encoded_records.len() {
+                    return Err(Error::new(format!("record offset out of bounds: {}", record))
+                        .with_kind(ErrorKind::CorruptIndex)
+                        .with_path(filename));
+                }
+                Record::decode_entry(&mut &encoded_records[record..], filename)
+            }
+        }
+    }
+
+    #[inline]
+    pub fn filenames(&self) -> impl Iterator<Item=Result<String>> + '_ {
+        (0..self.len()).map(move |index| self.filename(index))
+    }
+
+    #[inline]
+    pub fn records(&self) -> impl Iterator<Item=Result<Record>> + '_ {
+        (0..self.len()).map(move |index| self.record(index))
+    }
+}
@@ -4,91 +4,420 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{pak::{Pak, COMPR_NONE, COMPR_ZLIB, COMPR_BIAS_MEMORY, COMPR_BIAS_SPEED}, util::print_table};
+use std::collections::HashMap;
+
+use crate::hints::GameHints;
+use crate::index::LazyIndex;
+use crate::{pak::{Footer, Pak, COMPR_NONE, COMPR_ZLIB, COMPR_BIAS_MEMORY, COMPR_BIAS_SPEED}, util::print_table};
+use crate::merge::{pak_priority, MergedView};
+use crate::record::Record;
 use crate::result::Result;
+use crate::toc::Toc;
 use crate::util::{format_size, Align};
+#[cfg(feature = "serde")]
+use crate::pak::PakMetadata;
+#[cfg(feature = "serde")]
+use crate::serialize::Document;
 
-pub fn info(pak: &Pak, human_readable: bool) -> Result<()> {
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExtensionStats {
+    // lower case and without the dot, empty for files without extension
+    pub extension: String,
+    pub count: usize,
+    pub size: u64,
+    pub uncompressed_size: u64,
+}
+
+impl ExtensionStats {
+    // compressed size in percent of the uncompressed size
+    #[inline]
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_size == 0 {
+            100.0
+        } else {
+            self.size as f64 * 100.0 / self.uncompressed_size as f64
+        }
+    }
+}
+
+#[inline]
+fn file_extension(filename: &str) -> String {
+    let name = filename.rsplit('/').next().unwrap_or(filename);
+    match name.rfind('.') {
+        Some(index) if index > 0 => name[index + 1..].to_ascii_lowercase(),
+        _ => String::new(),
+    }
+}
+
+// Sorted by uncompressed size, biggest first.
+pub fn extension_stats(pak: &Pak) -> Vec<ExtensionStats> {
+    let mut stats: HashMap<String, ExtensionStats> = HashMap::new();
+
+    for record in pak.index().records() {
+        let extension = file_extension(record.filename());
+        let entry = stats.entry(extension.clone()).or_insert_with(|| ExtensionStats {
+            extension,
+            count: 0,
+            size: 0,
+            uncompressed_size: 0,
+        });
+        entry.count += 1;
+        entry.size += record.size();
+        entry.uncompressed_size += record.uncompressed_size();
+    }
+
+    let mut stats: Vec<_> = stats.into_values().collect();
+    stats.sort_by(|a, b| b.uncompressed_size.cmp(&a.uncompressed_size).then_with(|| a.extension.cmp(&b.extension)));
+
+    stats
+}
+
+pub fn info_by_extension(pak: &Pak, human_readable: bool) -> Result<()> {
     let fmt_size = if human_readable {
         |size: u64| format_size(size)
     } else {
         |size: u64| format!("{}", size)
     };
 
-    let mut sum_size              = 0;
-    let mut sum_uncompressed_size = 0;
-    let mut uncompr_count     = 0usize;
-    let mut zlib_count        = 0usize;
-    let mut bias_speed_count  = 0usize;
-    let mut bias_memory_count = 0usize;
-    let mut other_count       = 0usize;
-    let mut encrypted_count   = 0usize;
-    let mut sum_uncompr_size     = 0;
-    let mut sum_zlib_size        = 0;
-    let mut sum_bias_speed_size  = 0;
-    let mut sum_bias_memory_size = 0;
-    let mut sum_unknown_size     = 0;
-    let mut sum_encrypted_size   = 0;
-
-    let mut sum_uncompr_zlib_size        = 0;
-    let mut sum_uncompr_bias_speed_size  = 0;
-    let mut sum_uncompr_bias_memory_size = 0;
-    let mut sum_uncompr_unknown_size     = 0;
-    let mut sum_uncompr_encrypted_size   = 0;
+    let body: Vec<Vec<String>> = extension_stats(pak).iter().map(|stats| vec![
+        if stats.extension.is_empty() { "(none)".to_string() } else { format!(".{}", stats.extension) },
+        format!("{}", stats.count),
+        fmt_size(stats.size),
+        fmt_size(stats.uncompressed_size),
+        format!("{:.1}%", stats.ratio()),
+    ]).collect();
 
-    for record in pak.index().records() {
-        sum_size += record.size();
-        sum_uncompressed_size += record.uncompressed_size();
+    print_table(
+        &["Extension", "Count", "Size", "Uncompr.", "Ratio"],
+        &[Align::Left, Align::Right, Align::Right, Align::Right, Align::Right],
+        &body,
+    );
+
+    Ok(())
+}
+
+pub fn info(pak: &Pak, human_readable: bool) -> Result<()> {
+    let fmt_size = size_formatter(human_readable);
+
+    print_header(pak.version(), pak.index().mount_point(), pak.footer(), pak.index_offset(), pak.index_size(), fmt_size);
+
+    print_summary(pak.index().records(), fmt_size);
+
+    Ok(())
+}
+
+// Like info(), but records are decoded one at a time.
+pub fn info_lazy(index: &LazyIndex, human_readable: bool) -> Result<()> {
+    let fmt_size = size_formatter(human_readable);
+    let footer = index.footer();
+
+    print_header(index.version(), index.mount_point(), Some(footer), footer.index_offset(), footer.index_size(), fmt_size);
+
+    let mut summary = RecordSummary::default();
+    for record in index.records() {
+        summary.add(&record?);
+    }
+    summary.print(fmt_size);
+
+    Ok(())
+}
+
+// The document of info --format=json: the metadata of the package, the
+// footer fields that apply to its version (None otherwise), and the summary of
+// its records.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PakInfo {
+    #[serde(flatten)]
+    pub metadata: PakMetadata,
+    pub mount_point: Option<String>,
+    pub footer_offset: Option<u64>,
+    pub footer_size: Option<u64>,
+    // version >= 7
+    pub encryption_guid: Option<String>,
+    // version 9
+    pub frozen_index: Option<bool>,
+    // version >= 8
+    pub compression_methods: Option<Vec<String>>,
+    pub files: RecordSummary,
+    // only with by_extension
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Vec<ExtensionStats>>,
+}
+
+#[cfg(feature = "serde")]
+impl PakInfo {
+    fn new(metadata: PakMetadata, mount_point: Option<&str>, footer: Option<&Footer>, files: RecordSummary) -> Self {
+        let version = metadata.version;
+        let footer_field = |versions: std::ops::RangeInclusive<u32>| footer.filter(|_| versions.contains(&version));
+
+        Self {
+            metadata,
+            mount_point: mount_point.map(str::to_string),
+            footer_offset: footer.map(Footer::footer_offset),
+            footer_size: footer.map(Footer::footer_size),
+            encryption_guid: footer_field(7..=u32::MAX).map(|footer| format!("{:032X}", footer.encryption_guid())),
+            frozen_index: footer_field(9..=9).map(Footer::frozen),
+            compression_methods: footer_field(8..=u32::MAX).map(Footer::compression_methods),
+            files,
+            extensions: None,
+        }
+    }
+}
+
+// info() as JSON document, with by_extension also the extension_stats().
+#[cfg(feature = "serde")]
+pub fn info_json(pak: &Pak, by_extension: bool) -> Document<PakInfo> {
+    let mut info = PakInfo::new(pak.metadata(), pak.index().mount_point(), pak.footer(), RecordSummary::new(pak.index().records()));
+
+    if by_extension {
+        info.extensions = Some(extension_stats(pak));
+    }
+
+    Document::new(info)
+}
+
+// Like info_json(), but records are decoded one at a time.
+#[cfg(feature = "serde")]
+pub fn info_lazy_json(index: &LazyIndex) -> Result<Document<PakInfo>> {
+    let footer = index.footer();
+    let metadata = PakMetadata {
+        version: index.version(),
+        variant: index.variant(),
+        index_offset: footer.index_offset(),
+        index_size: footer.index_size(),
+        index_sha1: *footer.index_sha1(),
+        encrypted_index: footer.encrypted_index(),
+    };
+
+    let mut summary = RecordSummary::default();
+    for record in index.records() {
+        summary.add(&record?);
+    }
+
+    Ok(Document::new(PakInfo::new(metadata, index.mount_point(), Some(footer), summary)))
+}
+
+#[inline]
+fn size_formatter(human_readable: bool) -> fn(u64) -> String {
+    if human_readable {
+        |size: u64| format_size(size)
+    } else {
+        |size: u64| format!("{}", size)
+    }
+}
+
+fn print_header(version: u32, mount_point: Option<&str>, footer: Option<&Footer>, index_offset: u64, index_size: u64, fmt_size: fn(u64) -> String) {
+    println!("Pak Version: {}", version);
+    println!("Mount Point: {}", mount_point.unwrap_or(""));
+
+    if let Some(footer) = footer {
+        println!("Footer Offset: {}", footer.footer_offset());
+        println!("Footer Size: {}", fmt_size(footer.footer_size()));
+        println!("Index Offset: {}", index_offset);
+        println!("Index Size: {}", fmt_size(index_size));
+        if version >= 4 {
+            println!("Encrypted Index: {}", if footer.encrypted_index() { "yes" } else { "no" });
+        }
+        if version >= 7 {
+            println!("Encryption GUID: {:032X}", footer.encryption_guid());
+        }
+        if version == 9 {
+            println!("Frozen Index: {}", if footer.frozen() { "yes" } else { "no" });
+        }
+        if version >= 8 {
+            println!("Compression Methods: {}", footer.compression_methods().join(", "));
+        }
+    }
+    println!();
+}
+
+fn print_summary(records: &[Record], fmt_size: fn(u64) -> String) {
+    RecordSummary::new(records).print(fmt_size);
+}
+
+// count and sizes of a group of records
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SizeSummary {
+    pub count: usize,
+    pub size: u64,
+    pub uncompressed_size: u64,
+}
+
+impl SizeSummary {
+    #[inline]
+    fn add(&mut self, record: &Record) {
+        self.count += 1;
+        self.size += record.size();
+        self.uncompressed_size += record.uncompressed_size();
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompressionSummary {
+    pub none: SizeSummary,
+    pub zlib: SizeSummary,
+    pub bias_speed: SizeSummary,
+    pub bias_memory: SizeSummary,
+    pub unknown: SizeSummary,
+}
+
+// count and sizes of the records by compression method
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecordSummary {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub all: SizeSummary,
+    pub compression: CompressionSummary,
+    pub encrypted: SizeSummary,
+}
+
+impl RecordSummary {
+    pub fn new(records: &[Record]) -> Self {
+        let mut summary = Self::default();
+        for record in records {
+            summary.add(record);
+        }
+        summary
+    }
+
+    pub fn add(&mut self, record: &Record) {
+        self.all.add(record);
         if record.encrypted() {
-            encrypted_count += 1;
-            sum_encrypted_size += record.size();
-            sum_uncompr_encrypted_size += record.uncompressed_size();
+            self.encrypted.add(record);
         }
         match record.compression_method() {
-            self::COMPR_NONE => {
-                uncompr_count += 1;
-                sum_uncompr_size += record.size();
-            }
-            self::COMPR_ZLIB => {
-                zlib_count += 1;
-                sum_zlib_size += record.size();
-                sum_uncompr_zlib_size += record.uncompressed_size();
-            }
-            self::COMPR_BIAS_SPEED => {
-                bias_speed_count += 1;
-                sum_bias_speed_size += record.size();
-                sum_uncompr_bias_speed_size += record.uncompressed_size();
-            }
-            self::COMPR_BIAS_MEMORY => {
-                bias_memory_count += 1;
-                sum_bias_memory_size += record.size();
-                sum_uncompr_bias_memory_size += record.uncompressed_size();
-            }
-            _ => {
-                other_count += 1;
-                sum_unknown_size += record.size();
-                sum_uncompr_unknown_size += record.uncompressed_size();
-            }
+            self::COMPR_NONE        => self.compression.none.add(record),
+            self::COMPR_ZLIB        => self.compression.zlib.add(record),
+            self::COMPR_BIAS_SPEED  => self.compression.bias_speed.add(record),
+            self::COMPR_BIAS_MEMORY => self.compression.bias_memory.add(record),
+            _                       => self.compression.unknown.add(record),
         }
     }
 
-    println!("Pak Version: {}", pak.version());
-    println!("Mount Point: {}", pak.index().mount_point().unwrap_or(""));
-    println!();
+    fn print(&self, fmt_size: fn(u64) -> String) {
+        let compression = &self.compression;
+        print_table(
+            &["", "Count", "Size", "Uncompr."],
+            &[Align::Left, Align::Right, Align::Right, Align::Right],
+            &[
+                vec!["Files:",              &format!("{}", self.all.count),               &fmt_size(self.all.size),               &fmt_size(self.all.uncompressed_size)],
+                vec!["Uncompr.:",           &format!("{}", compression.none.count),        &fmt_size(compression.none.size),        ""],
+                vec!["ZLIB Compr.:",        &format!("{}", compression.zlib.count),        &fmt_size(compression.zlib.size),        &fmt_size(compression.zlib.uncompressed_size)],
+                vec!["Bias Speed Compr.:",  &format!("{}", compression.bias_speed.count),  &fmt_size(compression.bias_speed.size),  &fmt_size(compression.bias_speed.uncompressed_size)],
+                vec!["Bias Memory Compr.:", &format!("{}", compression.bias_memory.count), &fmt_size(compression.bias_memory.size), &fmt_size(compression.bias_memory.uncompressed_size)],
+                vec!["Unknown Compr.:",     &format!("{}", compression.unknown.count),     &fmt_size(compression.unknown.size),     &fmt_size(compression.unknown.uncompressed_size)],
+                vec!["Encrypted:",          &format!("{}", self.encrypted.count),          &fmt_size(self.encrypted.size),          &fmt_size(self.encrypted.uncompressed_size)],
+            ],
+        );
+    }
+}
+
+pub fn info_game_hints(hints: &GameHints) {
+    println!("Game Hints:");
+    if let Some(version) = hints.version {
+        println!("Pak Version: {}", version);
+    }
+    if let Some(engine_version) = hints.engine_version {
+        println!("Engine Version: {}", engine_version);
+    }
+    if let Some(project) = &hints.project {
+        println!("Project: {}", project);
+    }
+    if let Some(game) = hints.game {
+        println!("Game: {}", game);
+    }
+    if let Some(variant) = hints.variant {
+        println!("Variant: {}", variant.name());
+    }
+    if !hints.flags.is_empty() {
+        println!("Suggested Flags: {}", hints.flags.join(" "));
+    }
+    for note in &hints.notes {
+        println!("Note: {}", note);
+    }
+}
+
+// Summary of the effective files when mounting all the paks of the view. names
+// are the same that were passed to MergedView::new().
+pub fn info_merged(view: &MergedView, names: &[impl AsRef<str>], human_readable: bool) -> Result<()> {
+    let fmt_size = if human_readable {
+        |size: u64| format_size(size)
+    } else {
+        |size: u64| format!("{}", size)
+    };
+
+    let paks = view.paks();
+    let mut effective_counts = vec![0usize; paks.len()];
+    for entry in view.effective() {
+        effective_counts[entry.package] += 1;
+    }
+
+    let body: Vec<Vec<String>> = view.load_order().iter().map(|&index| {
+        let pak = &paks[index];
+        let name = names[index].as_ref();
+        vec![
+            format!("{}", pak_priority(name)),
+            format!("{}", pak.version()),
+            format!("{}", pak.index().records().len()),
+            format!("{}", effective_counts[index]),
+            name.to_string(),
+        ]
+    }).collect();
 
     print_table(
-        &["", "Count", "Size", "Uncompr."],
-        &[Align::Left, Align::Right, Align::Right, Align::Right],
-        &[
-            vec!["Files:",              &format!("{}", pak.index().records().len()), &fmt_size(sum_size),             &fmt_size(sum_uncompressed_size)],
-            vec!["Uncompr.:",           &format!("{}", uncompr_count),       &fmt_size(sum_uncompr_size),     ""],
-            vec!["ZLIB Compr.:",        &format!("{}", zlib_count),          &fmt_size(sum_zlib_size),        &fmt_size(sum_uncompr_zlib_size)],
-            vec!["Bias Speed Compr.:",  &format!("{}", bias_speed_count),    &fmt_size(sum_bias_speed_size),  &fmt_size(sum_uncompr_bias_speed_size)],
-            vec!["Bias Memory Compr.:", &format!("{}", bias_memory_count),   &fmt_size(sum_bias_memory_size), &fmt_size(sum_uncompr_bias_memory_size)],
-            vec!["Unknown Compr.:",     &format!("{}", other_count),         &fmt_size(sum_unknown_size),     &fmt_size(sum_uncompr_unknown_size)],
-            vec!["Encrypted:",          &format!("{}", encrypted_count),     &fmt_size(sum_encrypted_size),   &fmt_size(sum_uncompr_encrypted_size)],
-        ],
+        &["Priority", "Version", "Files", "Effective", "Package"],
+        &[Align::Right, Align::Right, Align::Right, Align::Right, Align::Left],
+        &body,
     );
+    println!();
+
+    if let Some(mount_point) = view.mount_point() {
+        println!("Mount Point: {}", mount_point);
+    }
+    println!("Shadowed Files: {}", view.shadowed().len());
+    println!();
+
+    print_summary(view.to_pak().index().records(), fmt_size);
+
+    Ok(())
+}
+
+pub fn info_toc(toc: &Toc, human_readable: bool) -> Result<()> {
+    let fmt_size = if human_readable {
+        |size: u64| format_size(size)
+    } else {
+        |size: u64| format!("{}", size)
+    };
+
+    let header = toc.header();
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+
+    println!("IoStore Version: {}", header.version());
+    println!("Mount Point: {}", toc.mount_point().unwrap_or(""));
+    println!("Container ID: {:016X}", header.container_id());
+    println!("Chunks: {}", header.entry_count());
+    println!("Compression Blocks: {}", header.compressed_block_entry_count());
+    println!("Compression Block Size: {}", fmt_size(header.compression_block_size() as u64));
+    println!("Compression Methods: {}", toc.compression_methods().join(", "));
+    println!("Partitions: {}", header.partition_count());
+    if header.partition_count() > 1 {
+        println!("Partition Size: {}", fmt_size(header.partition_size()));
+    }
+    println!("Directory Index Size: {}", fmt_size(header.directory_index_size() as u64));
+    println!("Compressed: {}", yes_no(header.compressed()));
+    println!("Encrypted: {}", yes_no(header.encrypted()));
+    if header.encrypted() {
+        println!("Encryption GUID: {:032X}", header.encryption_guid());
+    }
+    println!("Signed: {}", yes_no(header.signed()));
+    println!("Indexed: {}", yes_no(header.indexed()));
+    println!();
+
+    print_summary(toc.to_pak().index().records(), fmt_size);
 
     Ok(())
 }
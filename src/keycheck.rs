@@ -0,0 +1,196 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Finding out which of several candidate AES keys a package is encrypted with
+// by only decrypting its index. A key fits if the SHA-1 of the decrypted index
+// matches the one in the footer, or (because not all games write it) if the
+// index starts with something that looks like a mount point.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
+
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
+use crossbeam_utils::thread;
+
+use crate::keyring::KEY_SIZE;
+use crate::pak::{Footer, Options, Pak};
+use crate::util::{align, sha1_digest};
+use crate::{Error, ErrorKind, Result};
+
+// mount points are relative paths like "../../../Game/"
+const MAX_MOUNT_POINT_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyCheck {
+    // the SHA-1 of the decrypted index matches the one in the footer
+    pub sha1_matches: bool,
+    // the mount point at the start of the decrypted index, if it looks like one
+    pub mount_point: Option<String>,
+}
+
+impl KeyCheck {
+    #[inline]
+    pub fn works(&self) -> bool {
+        self.sha1_matches || self.mount_point.is_some()
+    }
+}
+
+// The mount point at the start of a (decrypted) index if it is a null
+// terminated string of printable characters.
+pub fn plausible_mount_point(index: &[u8]) -> Option<String> {
+    if index.len() < 4 {
+        return None;
+    }
+    let size = i32::from_le_bytes([index[0], index[1], index[2], index[3]]);
+    let data = &index[4..];
+
+    let chars: Vec<char> = if size < 0 {
+        let size = size.unsigned_abs() as usize;
+        if size > MAX_MOUNT_POINT_SIZE || size * 2 > data.len() {
+            return None;
+        }
+        let utf16: Vec<u16> = data[..size * 2].chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        if utf16.last() != Some(&0) {
+            return None;
+        }
+        char::decode_utf16(utf16[..size - 1].iter().cloned())
+            .collect::<std::result::Result<_, _>>()
+            .ok()?
+    } else {
+        let size = size as usize;
+        if size == 0 || size > MAX_MOUNT_POINT_SIZE || size > data.len() || data[size - 1] != 0 {
+            return None;
+        }
+        std::str::from_utf8(&data[..size - 1]).ok()?.chars().collect()
+    };
+
+    if chars.iter().any(|ch| ch.is_control()) {
+        return None;
+    }
+
+    Some(chars.into_iter().collect())
+}
+
+// The footer and the encrypted index, after checking that the keys can be
+// tried on it.
+fn read_encrypted_index(reader: &mut (impl Read + Seek), options: &Options, keys: &[Vec<u8>]) -> Result<(Footer, Vec<u8>)> {
+    // reading the footer of an encrypted index insists on a key
+    let footer = Pak::read_footer(reader, &Options {
+        encryption_key: keys.first().cloned(),
+        ..options.clone()
+    })?;
+
+    if !footer.encrypted_index() {
+        return Err(Error::new("index is not encrypted, keys can only be checked by the index".to_string())
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+
+    if footer.index_size() % AES_BLOCK_SIZE as u64 != 0 {
+        return Err(Error::new(format!("size of the encrypted index isn't a multiple of {}: {}",
+            AES_BLOCK_SIZE, footer.index_size())).with_kind(ErrorKind::CorruptIndex));
+    }
+
+    if let Some(key) = keys.iter().find(|key| key.len() != KEY_SIZE) {
+        return Err(Error::new(format!("illegal key: expected {} bytes, but got {}", KEY_SIZE, key.len()))
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+
+    let mut encrypted = vec![0u8; footer.index_size() as usize];
+    reader.seek(SeekFrom::Start(footer.index_offset()))?;
+    reader.read_exact(&mut encrypted)?;
+
+    Ok((footer, encrypted))
+}
+
+fn check_key(footer: &Footer, encrypted: &[u8], options: &Options, key: &[u8], index: &mut Vec<u8>) -> Result<KeyCheck> {
+    index.clear();
+    index.extend_from_slice(encrypted);
+    options.cipher.decrypt(index, key);
+
+    let sha1_matches = sha1_digest(&index[..])? == *footer.index_sha1();
+
+    if let Some(index_transform) = &options.index_transform {
+        index_transform.apply(index);
+    }
+
+    Ok(KeyCheck {
+        sha1_matches,
+        mount_point: plausible_mount_point(index),
+    })
+}
+
+// Tries every key on the index of the package. options are used for finding
+// the footer and for the cipher and index transform.
+pub fn check_keys(reader: &mut (impl Read + Seek), options: &Options, keys: &[Vec<u8>]) -> Result<Vec<KeyCheck>> {
+    let (footer, encrypted) = read_encrypted_index(reader, options, keys)?;
+
+    let mut index = Vec::with_capacity(encrypted.len());
+    keys.iter()
+        .map(|key| check_key(&footer, &encrypted, options, key, &mut index))
+        .collect()
+}
+
+// For long lists of keys (e.g. collected by the community): only keys that
+// give a plausible mount point are checked any further, for that only the
+// start of the index needs to be decrypted (and transformed, so the index
+// transform has to work on a prefix too). The keys are distributed over
+// thread_count threads. Returns the indices of the keys that work with their
+// results, in the order of keys.
+pub fn search_keys(reader: &mut (impl Read + Seek), options: &Options, keys: &[Vec<u8>], thread_count: NonZeroUsize) -> Result<Vec<(usize, KeyCheck)>> {
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let (footer, encrypted) = read_encrypted_index(reader, options, keys)?;
+    let prefix_size = encrypted.len().min(align((4 + MAX_MOUNT_POINT_SIZE * 2) as u64, AES_BLOCK_SIZE as u64) as usize);
+    let prefix = &encrypted[..prefix_size];
+
+    let chunk_size = keys.len().div_ceil(thread_count.get());
+    let thread_result = thread::scope::<_, Result<Vec<(usize, KeyCheck)>>>(|scope| {
+        let mut handles = Vec::with_capacity(thread_count.get());
+        for (chunk_index, chunk) in keys.chunks(chunk_size).enumerate() {
+            let footer = &footer;
+            let encrypted = &encrypted[..];
+            handles.push(scope.spawn(move |_| -> Result<Vec<(usize, KeyCheck)>> {
+                let mut found = Vec::new();
+                let mut data = Vec::with_capacity(prefix_size);
+                for (offset, key) in chunk.iter().enumerate() {
+                    data.clear();
+                    data.extend_from_slice(prefix);
+                    options.cipher.decrypt(&mut data, key);
+                    if let Some(index_transform) = &options.index_transform {
+                        index_transform.apply(&mut data);
+                    }
+
+                    if plausible_mount_point(&data).is_some() {
+                        let result = check_key(footer, encrypted, options, key, &mut data)?;
+                        if result.works() {
+                            found.push((chunk_index * chunk_size + offset, result));
+                        }
+                    }
+                }
+                Ok(found)
+            }));
+        }
+
+        let mut found = Vec::new();
+        for handle in handles {
+            match handle.join() {
+                Ok(result) => found.extend(result?),
+                Err(error) => return Err(Error::new(format!("threading error: {:?}", error))),
+            }
+        }
+
+        Ok(found)
+    });
+
+    match thread_result {
+        Err(error) => Err(Error::new(format!("threading error: {:?}", error))),
+        Ok(result) => result,
+    }
+}
@@ -0,0 +1,193 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// AES keys by the encryption GUID that paks of version >= 7 store in their
+// footer, for games that use several keys. The text format has one key per
+// line: the GUID as 32 hex digits (as printed by info, dashes are ignored),
+// then (separated by white space or a colon) the base64 encoded key or the
+// key as hex number with 0x prefix. Anything after # is a comment. Paks of
+// version < 7 have the GUID 0.
+//
+// The Crypto.json written by the Unreal Editor can be read as well. Its
+// EncryptionKey is the key for the GUID 0, its SecondaryEncryptionKeys have
+// their own GUIDs.
+
+use std::collections::HashMap;
+
+use crate::{Error, ErrorKind, Result};
+
+pub const KEY_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keyring {
+    keys: HashMap<u128, Vec<u8>>,
+}
+
+impl Keyring {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn insert(&mut self, guid: u128, key: Vec<u8>) -> Option<Vec<u8>> {
+        self.keys.insert(guid, key)
+    }
+
+    #[inline]
+    pub fn get(&self, guid: u128) -> Option<&Vec<u8>> {
+        self.keys.get(&guid)
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item=(u128, &Vec<u8>)> + '_ {
+        self.keys.iter().map(|(&guid, key)| (guid, key))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut keyring = Keyring::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |message: String| Error::new(format!("keyring line {}: {}", index + 1, message))
+                .with_kind(ErrorKind::InvalidArgument);
+
+            let mut fields = line.split(|ch: char| ch.is_whitespace() || ch == ':')
+                .filter(|field| !field.is_empty());
+            let (guid, key) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(guid), Some(key), None) => (guid, key),
+                _ => return Err(error("expected GUID and key".to_string())),
+            };
+
+            let guid = parse_guid(guid).map_err(|err| error(err.to_string()))?;
+            let key = parse_key(key).map_err(|err| error(err.to_string()))?;
+
+            if keyring.insert(guid, key).is_some() {
+                return Err(error(format!("duplicate GUID {:032X}", guid)));
+            }
+        }
+
+        Ok(keyring)
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|error| Error::io_with_path(error, path))?;
+        Self::parse(&text).map_err(|error| error.with_path(path))
+    }
+
+    #[cfg(feature = "json")]
+    pub fn parse_crypto_json(text: &str) -> Result<Self> {
+        // the other fields (signing keys etc.) are ignored
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct CryptoJson {
+            encryption_key: Option<CryptoKey>,
+            secondary_encryption_keys: Option<Vec<CryptoKey>>,
+        }
+
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct CryptoKey {
+            guid: Option<String>,
+            key: Option<String>,
+        }
+
+        let error = |message: String| Error::new(format!("Crypto.json: {}", message))
+            .with_kind(ErrorKind::InvalidArgument);
+        let key_of = |value: &CryptoKey, what: &str| match &value.key {
+            Some(key) => parse_key(key).map_err(|err| error(format!("{}: {}", what, err))),
+            None => Err(error(format!("{}: missing Key", what))),
+        };
+
+        let json: CryptoJson = serde_json::from_str(text).map_err(|err| error(err.to_string()))?;
+        let mut keyring = Keyring::new();
+
+        if let Some(value) = &json.encryption_key {
+            keyring.insert(0, key_of(value, "EncryptionKey")?);
+        }
+
+        for (index, value) in json.secondary_encryption_keys.iter().flatten().enumerate() {
+            let what = format!("SecondaryEncryptionKeys[{}]", index);
+            let guid = match &value.guid {
+                Some(guid) => parse_guid(guid).map_err(|err| error(format!("{}: {}", what, err)))?,
+                None => return Err(error(format!("{}: missing Guid", what))),
+            };
+            if keyring.insert(guid, key_of(value, &what)?).is_some() {
+                return Err(error(format!("{}: duplicate GUID {:032X}", what, guid)));
+            }
+        }
+
+        Ok(keyring)
+    }
+
+    #[cfg(all(feature = "fs", feature = "json"))]
+    pub fn from_crypto_json_path(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|error| Error::io_with_path(error, path))?;
+        Self::parse_crypto_json(&text).map_err(|error| error.with_path(path))
+    }
+
+    // Adds the keys of other for GUIDs that aren't in this keyring yet.
+    pub fn merge(&mut self, other: Keyring) {
+        for (guid, key) in other.keys {
+            self.keys.entry(guid).or_insert(key);
+        }
+    }
+}
+
+// 32 hex digits, optionally with 0x prefix and dashes.
+pub fn parse_guid(value: &str) -> Result<u128> {
+    let value = value.trim();
+    let hex: String = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value)
+        .chars().filter(|&ch| ch != '-').collect();
+    if hex.len() != 32 {
+        return Err(Error::new(format!("illegal GUID {:?}: expected 32 hex digits", value))
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+    u128::from_str_radix(&hex, 16).map_err(|error|
+        Error::new(format!("illegal GUID {:?}: {}", value, error)).with_kind(ErrorKind::InvalidArgument))
+}
+
+// Base64 or hex with 0x prefix, must be 32 bytes (AES-256).
+pub fn parse_key(value: &str) -> Result<Vec<u8>> {
+    let value = value.trim();
+    let key = if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        if !hex.is_ascii() || hex.len() % 2 != 0 {
+            return Err(Error::new("illegal key: expected an even number of hex digits".to_string())
+                .with_kind(ErrorKind::InvalidArgument));
+        }
+        (0..hex.len()).step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|error| Error::new(format!("illegal key: {}", error)).with_kind(ErrorKind::InvalidArgument))?
+    } else {
+        base64::decode(value)
+            .map_err(|error| Error::new(format!("illegal key: {}", error)).with_kind(ErrorKind::InvalidArgument))?
+    };
+
+    if key.len() != KEY_SIZE {
+        return Err(Error::new(format!("illegal key: expected {} bytes, but got {}", KEY_SIZE, key.len()))
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+
+    Ok(key)
+}
@@ -1,27 +1,98 @@
 pub mod pak;
 pub use pak::{Pak, Variant};
+pub mod variant;
+pub use variant::VariantSpec;
 
 pub mod decrypt;
+pub mod keyring;
+pub use keyring::Keyring;
 pub mod index;
 pub mod result;
-pub use result::{Error, Result};
+pub use result::{Error, ErrorKind, Result};
 
 pub mod record;
 pub use record::Record;
 
 pub mod info;
+pub mod hints;
 pub mod util;
 pub mod decode;
 pub mod encode;
 pub mod filter;
 pub use filter::Filter;
+pub mod glob;
+pub mod predicate;
+pub use predicate::Predicate;
+pub mod dirtree;
+pub use dirtree::DirTree;
+pub mod entry;
+pub mod merge;
+pub use merge::MergedView;
+pub mod names;
+pub mod locate;
+pub mod dedupe;
+pub mod ranges;
+pub mod warnings;
+pub use warnings::{Warning, Warnings};
+pub mod range_reader;
+pub mod stream;
+pub mod toc;
+pub use toc::Toc;
 
+#[cfg(feature = "serde")]
+pub mod serialize;
+#[cfg(feature = "async")]
+pub mod async_reader;
+
+#[cfg(feature = "fs")]
 pub mod unpack;
+#[cfg(feature = "fs")]
+pub mod archive;
+#[cfg(feature = "fs")]
 pub mod pack;
+#[cfg(feature = "fs")]
+pub mod patch;
+#[cfg(all(feature = "fs", feature = "json"))]
+pub mod manifest;
+#[cfg(feature = "fs")]
 pub mod check;
+#[cfg(feature = "fs")]
+pub mod keycheck;
+#[cfg(feature = "fs")]
+pub mod verify;
+#[cfg(feature = "fs")]
+pub mod hash;
+#[cfg(feature = "fs")]
+pub mod grep;
+#[cfg(feature = "fs")]
+pub mod sig;
+#[cfg(feature = "fs")]
+pub mod salvage;
+#[cfg(feature = "fs")]
+pub mod repair;
+#[cfg(feature = "fs")]
+pub mod rename;
+#[cfg(feature = "fs")]
+pub mod slack;
+#[cfg(feature = "fs")]
+pub mod bench;
+#[cfg(feature = "fs")]
+pub mod progress;
+#[cfg(feature = "fs")]
+pub mod budget;
+#[cfg(feature = "fs")]
+pub mod schedule;
+#[cfg(feature = "fs")]
+pub mod portable;
 
+#[cfg(feature = "fs")]
 pub mod reopen;
+#[cfg(feature = "fs")]
+pub mod pread;
+#[cfg(feature = "fs")]
+pub mod tune;
+#[cfg(feature = "fs")]
 pub mod walkdir;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "fuse"))]
 pub mod mount;
@@ -0,0 +1,127 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Mapping a raw file offset (e.g. from a crash log or a hex editor) to what is
+// stored there, using only the index.
+
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
+
+use crate::util::align;
+use crate::{Pak, Record};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordPart {
+    // the inline record header
+    Header,
+    // data of a record without compression blocks, offset is relative to the
+    // start of the data
+    Data { offset: u64 },
+    Block {
+        index: usize,
+        // file offsets of the (compressed) block
+        start: u64,
+        end: u64,
+        // offset of the uncompressed block in the uncompressed content
+        uncompressed_offset: u64,
+    },
+    // between the header and the first block or encryption padding
+    Padding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordLocation<'a> {
+    pub record: &'a Record,
+    // file offsets of the whole record, see Pak::record_region()
+    pub start: u64,
+    pub end: u64,
+    pub part: RecordPart,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location<'a> {
+    // several records if they share their data
+    Records(Vec<RecordLocation<'a>>),
+    // offset is relative to the start of the index
+    Index { offset: u64 },
+    PathHashIndex { offset: u64 },
+    FullDirectoryIndex { offset: u64 },
+    Footer { offset: u64 },
+    // not referenced by the index
+    Unused,
+}
+
+fn record_part(pak: &Pak, record: &Record, offset: u64) -> RecordPart {
+    let data_start = record.data_offset(pak.version(), pak.variant());
+    if offset < data_start {
+        return RecordPart::Header;
+    }
+
+    if record.compression_blocks().is_some() {
+        for (index, (start, end)) in record.block_ranges(pak.version()).enumerate() {
+            let end = if record.encrypted() {
+                start + align(end.saturating_sub(start), AES_BLOCK_SIZE as u64)
+            } else {
+                end
+            };
+            if offset >= start && offset < end {
+                return RecordPart::Block {
+                    index,
+                    start,
+                    end,
+                    uncompressed_offset: index as u64 * record.compression_block_size() as u64,
+                };
+            }
+        }
+        RecordPart::Padding
+    } else if offset - data_start < record.size() {
+        RecordPart::Data { offset: offset - data_start }
+    } else {
+        RecordPart::Padding
+    }
+}
+
+pub fn locate(pak: &Pak, offset: u64) -> Location<'_> {
+    let records: Vec<RecordLocation> = pak.index().records().iter()
+        .filter_map(|record| {
+            let (start, end) = pak.record_region(record);
+            if offset >= start && offset < end {
+                Some(RecordLocation { record, start, end, part: record_part(pak, record, offset) })
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if !records.is_empty() {
+        return Location::Records(records);
+    }
+
+    if offset >= pak.index_offset() && offset - pak.index_offset() < pak.index_size() {
+        return Location::Index { offset: offset - pak.index_offset() };
+    }
+
+    if let Some(info) = pak.secondary_index() {
+        let path_hash_index_offset = info.path_hash_index_offset();
+        if info.has_path_hash_index() && offset >= path_hash_index_offset &&
+           offset - path_hash_index_offset < info.path_hash_index_size() {
+            return Location::PathHashIndex { offset: offset - path_hash_index_offset };
+        }
+
+        let full_directory_index_offset = info.full_directory_index_offset();
+        if info.has_full_directory_index() && offset >= full_directory_index_offset &&
+           offset - full_directory_index_offset < info.full_directory_index_size() {
+            return Location::FullDirectoryIndex { offset: offset - full_directory_index_offset };
+        }
+    }
+
+    if let Some(footer) = pak.footer() {
+        if offset >= footer.footer_offset() && offset - footer.footer_offset() < footer.footer_size() {
+            return Location::Footer { offset: offset - footer.footer_offset() };
+        }
+    }
+
+    Location::Unused
+}
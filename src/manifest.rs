@@ -0,0 +1,394 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::convert::TryFrom;
+use std::io::Write;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::path::Path;
+
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::{Error, Pak, Record, Result, Variant};
+use crate::pack::{PackPath, COMPR_DEFAULT};
+use crate::pak::{compression_method_name, Sha1, COMPR_NONE, COMPR_ZLIB, DEFAULT_BLOCK_SIZE, DEFAULT_MIN_COMPRESSION_SIZE};
+use crate::util::{parse_compression_level, parse_size};
+
+// A pack manifest is a JSON file that lists the files to pack with their
+// per-file options, e.g.:
+//
+//  {
+//      "files": [
+//          {
+//              "source": "Content/Maps/Map.umap",
+//              "path": "Game/Content/Maps/Map.umap",
+//              "compression": "zlib",
+//              "level": 9,
+//              "block_size": "64K",
+//              "min_size": 100,
+//              "encrypted": true
+//          },
+//          { "source": "Movies", "path": "Game/Content/Movies", "compression": "none" }
+//      ]
+//  }
+//
+// Instead of an object with a "files" array the top level value can also
+// be just the array. Only "source" is required, all other values default to
+// the command line options. Relative source paths are relative to the
+// directory containing the manifest.
+//
+// An index template (written by export-index) is a manifest of all files of
+// a package that additionally has the "version", "variant" and "mount_point"
+// of the package at the top level, and "offset", "size", "uncompressed_size"
+// and "sha1" per file. These are only informational and ignored when packing.
+
+// Values that may be a number or a string, like "level": "best".
+fn value_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Number(number) => Some(number.to_string()),
+        Value::String(string) => Some(string.clone()),
+        _ => None,
+    }
+}
+
+fn get_size(entry_index: usize, key: &str, value: &Value) -> Result<Option<u64>> {
+    let size = match value {
+        Value::Null => return Ok(None),
+        Value::Number(_) => value.as_u64(),
+        Value::String(value) if value.eq_ignore_ascii_case("default") => return Ok(None),
+        Value::String(value) => parse_size(value).ok().map(|size| size as u64),
+        _ => None,
+    };
+
+    match size {
+        Some(size) if size > 0 => Ok(Some(size)),
+        _ => Err(Error::new(format!(
+            "manifest entry {}: illegal value for {:?}: {}",
+            entry_index, key, value))),
+    }
+}
+
+// Some(Value::Null) for an explicit null, so it can mean something else than
+// a missing value.
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+    source: String,
+    path: Option<String>,
+    compression: Option<String>,
+    // a number or a name like "best"
+    level: Option<Value>,
+    // a number or a size like "64K", null or "default" for the default
+    #[serde(default, deserialize_with = "present")]
+    block_size: Option<Value>,
+    #[serde(default, deserialize_with = "present")]
+    min_size: Option<Value>,
+    encrypted: Option<bool>,
+    // informational fields of index templates
+    #[serde(default, rename = "offset")]
+    _offset: IgnoredAny,
+    #[serde(default, rename = "size")]
+    _size: IgnoredAny,
+    #[serde(default, rename = "uncompressed_size")]
+    _uncompressed_size: IgnoredAny,
+    #[serde(default, rename = "sha1")]
+    _sha1: IgnoredAny,
+}
+
+fn parse_entry(entry_index: usize, entry: Value, base_dir: Option<&Path>) -> Result<PackPath> {
+    let entry: ManifestEntry = serde_json::from_value(entry)
+        .map_err(|error| Error::new(format!("manifest entry {}: {}", entry_index, error)))?;
+
+    let mut pack_path = PackPath::new(entry.source.clone());
+    pack_path.rename = entry.path;
+    pack_path.encrypted = entry.encrypted;
+
+    pack_path.compression_method = match entry.compression.as_deref() {
+        Some(method) if method.eq_ignore_ascii_case("zlib") => COMPR_ZLIB,
+        Some(method) if method.eq_ignore_ascii_case("none") => COMPR_NONE,
+        Some(method) if method.eq_ignore_ascii_case("default") => COMPR_DEFAULT,
+        None => COMPR_DEFAULT,
+        Some(method) => return Err(Error::new(format!(
+            "manifest entry {}: unsupported compression method: {:?}",
+            entry_index, method))),
+    };
+
+    if let Some(level) = entry.level {
+        pack_path.compression_level = match value_to_string(&level) {
+            Some(level) => Some(parse_compression_level(&level)
+                .map_err(|error| Error::new(format!("manifest entry {}: {}", entry_index, error)))?),
+            None => return Err(Error::new(format!(
+                "manifest entry {}: illegal compression level: {}",
+                entry_index, level))),
+        };
+    }
+
+    if let Some(block_size) = entry.block_size {
+        pack_path.compression_block_size = match get_size(entry_index, "block_size", &block_size)? {
+            Some(size) if size <= u32::MAX as u64 => NonZeroU32::new(size as u32),
+            Some(size) => return Err(Error::new(format!(
+                "manifest entry {}: block_size too big: {}",
+                entry_index, size))),
+            None => Some(DEFAULT_BLOCK_SIZE),
+        };
+    }
+
+    if let Some(min_size) = entry.min_size {
+        pack_path.compression_min_size = match get_size(entry_index, "min_size", &min_size)? {
+            Some(size) => NonZeroU64::new(size),
+            None => Some(DEFAULT_MIN_COMPRESSION_SIZE),
+        };
+    }
+
+    if let Some(base_dir) = base_dir {
+        let source_path = Path::new(&entry.source);
+        if source_path.is_relative() && base_dir.components().count() > 0 {
+            if pack_path.rename.is_none() {
+                // keep the path inside of the pak relative to the manifest
+                #[cfg(target_os = "windows")]
+                let rename = entry.source.replace('\\', "/");
+
+                #[cfg(not(target_os = "windows"))]
+                let rename = entry.source.clone();
+
+                pack_path.rename = Some(rename);
+            }
+            pack_path.filename = base_dir.join(source_path).to_string_lossy().into_owned();
+        }
+    }
+
+    Ok(pack_path)
+}
+
+fn parse_entries(entries: Vec<Value>, base_dir: Option<&Path>) -> Result<Vec<PackPath>> {
+    entries.into_iter()
+        .enumerate()
+        .map(|(entry_index, entry)| parse_entry(entry_index, entry, base_dir))
+        .collect()
+}
+
+pub fn parse_manifest(source: &str, base_dir: Option<&Path>) -> Result<Vec<PackPath>> {
+    let entries = match serde_json::from_str(source)? {
+        Value::Array(entries) => entries,
+        Value::Object(mut manifest) => match manifest.remove("files") {
+            Some(Value::Array(entries)) => entries,
+            _ => return Err(Error::new(
+                "manifest needs to be an array or an object with a \"files\" array".to_string())),
+        },
+        _ => return Err(Error::new(
+            "manifest needs to be an array or an object with a \"files\" array".to_string())),
+    };
+
+    parse_entries(entries, base_dir)
+}
+
+pub fn read_manifest(path: impl AsRef<Path>) -> Result<Vec<PackPath>> {
+    let path = path.as_ref();
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => return Err(Error::io_with_path(error, path)),
+    };
+
+    parse_manifest(&source, path.parent())
+        .map_err(|error| error.with_path(path))
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexTemplate {
+    pub version: Option<u32>,
+    pub variant: Option<Variant>,
+    pub mount_point: Option<String>,
+    pub files: Vec<PackPath>,
+}
+
+// An entry of the index template, see ManifestEntry.
+#[derive(Serialize)]
+struct TemplateFile<'a> {
+    source: &'a str,
+    path: &'a str,
+    compression: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_size: Option<u32>,
+    encrypted: bool,
+    offset: u64,
+    size: u64,
+    uncompressed_size: u64,
+    #[serde(with = "crate::serialize::option_sha1_hex")]
+    sha1: Option<Sha1>,
+}
+
+impl<'a> TemplateFile<'a> {
+    fn new(pak: &Pak, record: &'a Record) -> Self {
+        let compression = match record.compression_method() {
+            COMPR_NONE => "none",
+            method => compression_method_name(method),
+        };
+
+        Self {
+            source: record.filename(),
+            path: record.filename(),
+            compression,
+            block_size: if record.compression_method() != COMPR_NONE && pak.version() >= 3 {
+                Some(record.compression_block_size())
+            } else {
+                None
+            },
+            encrypted: record.encrypted(),
+            offset: record.offset(),
+            size: record.size(),
+            uncompressed_size: record.uncompressed_size(),
+            sha1: *record.sha1(),
+        }
+    }
+}
+
+// Writes the index template of a package with one file per line, so it is
+// easy to edit by hand or with line based tools.
+pub fn write_index_template(writer: &mut impl Write, pak: &Pak) -> std::io::Result<()> {
+    writeln!(writer, "{{")?;
+    writeln!(writer, "    \"version\": {},", pak.version())?;
+    writeln!(writer, "    \"variant\": {},", serde_json::to_string(pak.variant().name())?)?;
+    writeln!(writer, "    \"mount_point\": {},", serde_json::to_string(&pak.index().mount_point())?)?;
+    write!(writer, "    \"files\": [")?;
+    let mut first = true;
+    for record in pak.index().records() {
+        if first {
+            first = false;
+            writeln!(writer)?;
+        } else {
+            writeln!(writer, ",")?;
+        }
+        write!(writer, "        {}", serde_json::to_string(&TemplateFile::new(pak, record))?)?;
+    }
+    if !first {
+        write!(writer, "\n    ")?;
+    }
+    writeln!(writer, "]")?;
+    writeln!(writer, "}}")
+}
+
+#[derive(Deserialize)]
+struct IndexTemplateJson {
+    version: Option<u32>,
+    variant: Option<String>,
+    mount_point: Option<String>,
+    files: Vec<Value>,
+}
+
+pub fn parse_index_template(source: &str, base_dir: Option<&Path>) -> Result<IndexTemplate> {
+    let template: IndexTemplateJson = serde_json::from_str(source)
+        .map_err(|error| Error::new(format!("index template: {}", error)))?;
+
+    Ok(IndexTemplate {
+        version: template.version,
+        variant: template.variant.as_deref().map(Variant::try_from).transpose()?,
+        mount_point: template.mount_point,
+        files: parse_entries(template.files, base_dir)?,
+    })
+}
+
+pub fn read_index_template(path: impl AsRef<Path>) -> Result<IndexTemplate> {
+    let path = path.as_ref();
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => return Err(Error::io_with_path(error, path)),
+    };
+
+    parse_index_template(&source, path.parent())
+        .map_err(|error| error.with_path(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_entries() {
+        let paths = parse_manifest(r#"[
+            {"source": "a.txt"},
+            {"source": "b.txt", "path": "x/b.txt", "compression": "ZLIB", "level": "best", "block_size": "1K", "min_size": 10, "encrypted": true},
+            {"source": "c.txt", "path": null, "compression": "none", "level": 3, "block_size": null, "min_size": "default"},
+            {"source": "d.txt", "offset": 0, "size": 1, "uncompressed_size": 1, "sha1": null}
+        ]"#, None).unwrap();
+
+        assert_eq!(paths.len(), 4);
+        assert_eq!(paths[0].filename, "a.txt");
+        assert_eq!(paths[0].compression_method, COMPR_DEFAULT);
+        assert_eq!(paths[0].compression_block_size, None);
+        assert_eq!(paths[0].compression_min_size, None);
+
+        assert_eq!(paths[1].rename.as_deref(), Some("x/b.txt"));
+        assert_eq!(paths[1].compression_method, COMPR_ZLIB);
+        assert_eq!(paths[1].compression_level, Some(parse_compression_level("best").unwrap()));
+        assert_eq!(paths[1].compression_block_size, NonZeroU32::new(1024));
+        assert_eq!(paths[1].compression_min_size, NonZeroU64::new(10));
+        assert_eq!(paths[1].encrypted, Some(true));
+
+        // null and "default" are the built-in defaults, not the command line options
+        assert_eq!(paths[2].rename, None);
+        assert_eq!(paths[2].compression_method, COMPR_NONE);
+        assert_eq!(paths[2].compression_level, NonZeroU32::new(3));
+        assert_eq!(paths[2].compression_block_size, Some(DEFAULT_BLOCK_SIZE));
+        assert_eq!(paths[2].compression_min_size, Some(DEFAULT_MIN_COMPRESSION_SIZE));
+    }
+
+    #[test]
+    fn manifest_base_dir() {
+        let paths = parse_manifest(r#"{"files": [{"source": "src/a.txt"}, {"source": "b.txt", "path": "x/b.txt"}]}"#, Some(Path::new("dir"))).unwrap();
+        assert_eq!(Path::new(&paths[0].filename), Path::new("dir").join("src/a.txt"));
+        assert_eq!(paths[0].rename.as_deref(), Some("src/a.txt"));
+        assert_eq!(Path::new(&paths[1].filename), Path::new("dir").join("b.txt"));
+        assert_eq!(paths[1].rename.as_deref(), Some("x/b.txt"));
+    }
+
+    #[test]
+    fn manifest_errors() {
+        for source in &[
+            "", "{}", "1", r#"{"files": {}}"#, "[1]", "[{}]",
+            r#"[{"source": 1}]"#,
+            r#"[{"source": "a", "unknown": 1}]"#,
+            r#"[{"source": "a", "compression": "lz4"}]"#,
+            r#"[{"source": "a", "level": true}]"#,
+            r#"[{"source": "a", "level": "fastest"}]"#,
+            r#"[{"source": "a", "block_size": 0}]"#,
+            r#"[{"source": "a", "block_size": "8G"}]"#,
+            r#"[{"source": "a", "min_size": -1}]"#,
+            r#"[{"source": "a", "encrypted": "yes"}]"#,
+            "[] []",
+        ] {
+            assert!(parse_manifest(source, None).is_err(), "{:?} should not parse", source);
+        }
+
+        let error = parse_manifest(r#"[{"source": "a"}, {"source": "b", "unknown": 1}]"#, None).unwrap_err();
+        assert!(error.to_string().contains("manifest entry 1: unknown field `unknown`"), "{}", error);
+    }
+
+    #[test]
+    fn index_template() {
+        let template = parse_index_template(r#"{
+            "version": 8,
+            "variant": "conan_exiles",
+            "mount_point": "../../../",
+            "files": [{"source": "a.txt", "path": "a.txt", "compression": "zlib", "block_size": 65536}]
+        }"#, None).unwrap();
+        assert_eq!(template.version, Some(8));
+        assert_eq!(template.variant, Some(Variant::ConanExiles));
+        assert_eq!(template.mount_point.as_deref(), Some("../../../"));
+        assert_eq!(template.files.len(), 1);
+
+        let template = parse_index_template(r#"{"version": null, "files": []}"#, None).unwrap();
+        assert_eq!(template.version, None);
+        assert_eq!(template.variant, None);
+
+        assert!(parse_index_template(r#"{"version": -1, "files": []}"#, None).is_err());
+        assert!(parse_index_template(r#"{"variant": "unreal", "files": []}"#, None).is_err());
+        assert!(parse_index_template("[]", None).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Resolving which entry is used when several paks contain the same file, the
+// way the engine does it when mounting all of them.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::index::Index;
+use crate::pak::NULL_SHA1;
+use crate::{Pak, Record};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedRecord<'a> {
+    // filename, prefixed with the mount point if the mount points differ
+    pub path: String,
+    // index of the package in the list passed to MergedView::new()
+    pub package: usize,
+    pub record: &'a Record,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowedRecord<'a> {
+    pub entry: MergedRecord<'a>,
+    // package of the entry that is used instead
+    pub overridden_by: usize,
+}
+
+impl AsRef<Record> for MergedRecord<'_> {
+    #[inline]
+    fn as_ref(&self) -> &Record {
+        self.record
+    }
+}
+
+impl AsRef<Record> for ShadowedRecord<'_> {
+    #[inline]
+    fn as_ref(&self) -> &Record {
+        self.entry.record
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MergedView<'a> {
+    paks: &'a [Pak],
+    load_order: Vec<usize>,
+    mount_point: Option<String>,
+    effective: Vec<MergedRecord<'a>>,
+    shadowed: Vec<ShadowedRecord<'a>>,
+}
+
+// Mirrors FPakPlatformFile::GetPakOrderFromPakFilePath() minus the location
+// based part: foo_P.pak gets 100, foo_N_P.pak gets (N + 1) * 100 for N >= 1,
+// everything else 0. Higher priority paks override lower priority ones.
+pub fn pak_priority(path: impl AsRef<Path>) -> u32 {
+    let stem = match path.as_ref().file_stem() {
+        Some(stem) => stem.to_string_lossy(),
+        None => return 0,
+    };
+
+    let stem = match stem.strip_suffix("_P") {
+        Some(stem) => stem,
+        None => return 0,
+    };
+
+    let mut version = 1;
+    if let Some(index) = stem.rfind('_') {
+        let number = &stem[index + 1..];
+        if !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit()) {
+            if let Ok(number) = number.parse::<u32>() {
+                if number >= 1 {
+                    version = number.saturating_add(1);
+                }
+            }
+        }
+    }
+
+    version.saturating_mul(100)
+}
+
+// Indices of the packages sorted from lowest to highest priority. Packages of
+// the same priority keep their order, so a later one overrides an earlier one.
+pub fn load_order(names: &[impl AsRef<Path>]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..names.len()).collect();
+    order.sort_by_key(|&index| pak_priority(&names[index]));
+    order
+}
+
+fn join_mount_point(mount_point: Option<&str>, filename: &str) -> String {
+    match mount_point {
+        None | Some("") => filename.to_string(),
+        Some(mount_point) if mount_point.ends_with('/') => format!("{}{}", mount_point, filename),
+        Some(mount_point) => format!("{}/{}", mount_point, filename),
+    }
+}
+
+impl<'a> MergedView<'a> {
+    // names are the file names of the paks and are used for their priority.
+    pub fn new(paks: &'a [Pak], names: &[impl AsRef<Path>]) -> Self {
+        assert_eq!(paks.len(), names.len());
+
+        let load_order = load_order(names);
+
+        let mount_point = paks.first().and_then(|pak| pak.index().mount_point());
+        let same_mount_point = paks.iter().all(|pak| pak.index().mount_point() == mount_point);
+        let mount_point = if same_mount_point { mount_point.map(str::to_string) } else { None };
+
+        let mut effective: Vec<MergedRecord<'a>> = Vec::new();
+        let mut shadowed = Vec::new();
+        let mut lookup: HashMap<String, usize> = HashMap::new();
+
+        for &package in &load_order {
+            let pak = &paks[package];
+            for record in pak.index().records() {
+                let path = if same_mount_point {
+                    record.filename().to_string()
+                } else {
+                    join_mount_point(pak.index().mount_point(), record.filename())
+                };
+
+                let entry = MergedRecord { path, package, record };
+                if let Some(&index) = lookup.get(&entry.path) {
+                    let old = std::mem::replace(&mut effective[index], entry);
+                    shadowed.push(ShadowedRecord { entry: old, overridden_by: package });
+                } else {
+                    lookup.insert(entry.path.clone(), effective.len());
+                    effective.push(entry);
+                }
+            }
+        }
+
+        // an entry might be overridden several times, report the winner
+        for shadowed in &mut shadowed {
+            shadowed.overridden_by = effective[lookup[&shadowed.entry.path]].package;
+        }
+
+        Self {
+            paks,
+            load_order,
+            mount_point,
+            effective,
+            shadowed,
+        }
+    }
+
+    #[inline]
+    pub fn paks(&self) -> &'a [Pak] {
+        self.paks
+    }
+
+    #[inline]
+    pub fn load_order(&self) -> &[usize] {
+        &self.load_order
+    }
+
+    // Only set if all paks have the same mount point.
+    #[inline]
+    pub fn mount_point(&self) -> Option<&str> {
+        self.mount_point.as_deref()
+    }
+
+    #[inline]
+    pub fn effective(&self) -> &[MergedRecord<'a>] {
+        &self.effective
+    }
+
+    #[inline]
+    pub fn shadowed(&self) -> &[ShadowedRecord<'a>] {
+        &self.shadowed
+    }
+
+    // A pak of the effective records, using the version and variant of the
+    // highest priority pak. Offsets refer to the respective pak the record is
+    // from, so this is only good for listing.
+    pub fn to_pak(&self) -> Pak {
+        let top = self.load_order.last().map(|&index| &self.paks[index]);
+        let records = self.effective.iter()
+            .map(|entry| entry.record.renamed(entry.path.clone()))
+            .collect();
+
+        Pak::new(
+            top.map(Pak::variant).unwrap_or_default(),
+            top.map(Pak::version).unwrap_or(3),
+            0,
+            0,
+            NULL_SHA1,
+            Index::new(self.mount_point.clone(), records),
+        )
+    }
+}
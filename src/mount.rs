@@ -4,7 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{collections::HashMap, ffi::OsStr, fs::File, io::Read, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::{collections::{HashMap, VecDeque}, ffi::OsStr, fs::File, io::Read, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
 use std::os::unix::fs::FileExt;
 use std::os::linux::fs::MetadataExt;
 
@@ -14,7 +14,17 @@ use fuse::{Filesystem, FileType, Request, ReplyEntry, FileAttr, ReplyAttr, Reply
 use daemonize::{Daemonize, DaemonizeError};
 use libc::{ENOENT, EISDIR, EACCES, ENOTDIR, EINVAL, EIO, ENOSYS, O_RDONLY};
 
-use crate::{Error, Pak, Record, Result, pak::{self, Variant}, record::CompressionBlock, util::{make_pak_path, parse_pak_path}};
+use crate::{DirTree, Error, Pak, Record, Result, dirtree::DirNodeKind, pak::{self, Variant}};
+
+// A compression block with its absolute offset in the pak and its range in
+// the uncompressed file, all computed when mounting.
+#[derive(Debug, Clone, Copy)]
+struct MountBlock {
+    start_offset: u64,
+    end_offset: u64,
+    uncompressed_offset: u64,
+    uncompressed_size: u64,
+}
 
 #[derive(Debug)]
 enum INodeData {
@@ -23,13 +33,81 @@ enum INodeData {
         size: u64,
         uncompressed_size: u64,
         compression_method: u32,
-        compression_blocks: Option<Vec<CompressionBlock>>,
+        compression_blocks: Option<Vec<MountBlock>>,
         encrypted: bool,
-        compression_block_size: u32,
     },
     Dir(HashMap<String, u64>)
 }
 
+// The last decompressed compression block, so that small sequential reads
+// decompress each block only once. The buffers are reused for every read.
+#[derive(Debug, Default)]
+struct BlockCache {
+    // (inode, block index)
+    key: Option<(u64, usize)>,
+    compressed: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl BlockCache {
+    fn block(&mut self, file: &File, inode: u64, index: usize, block: &MountBlock) -> std::io::Result<&[u8]> {
+        if self.key != Some((inode, index)) {
+            self.key = None;
+            self.compressed.resize((block.end_offset - block.start_offset) as usize, 0);
+            file.read_exact_at(&mut self.compressed, block.start_offset)?;
+
+            self.data.resize(block.uncompressed_size as usize, 0);
+            ZlibDecoder::new(&self.compressed[..]).read_exact(&mut self.data)?;
+            self.key = Some((inode, index));
+        }
+
+        Ok(&self.data)
+    }
+}
+
+pub const DEFAULT_ENTRY_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+// Decompressed content of entries without compression blocks (version 2),
+// which can only be inflated as a whole. Entries are evicted least recently
+// used first once the limit is exceeded, but the last one is always kept.
+#[derive(Debug)]
+struct EntryCache {
+    // (inode, data), least recently used first
+    entries: VecDeque<(u64, Vec<u8>)>,
+    size: usize,
+    limit: usize,
+}
+
+impl EntryCache {
+    #[inline]
+    fn new(limit: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            size: 0,
+            limit,
+        }
+    }
+
+    fn entry(&mut self, inode: u64, load: impl FnOnce() -> std::io::Result<Vec<u8>>) -> std::io::Result<&[u8]> {
+        if let Some(index) = self.entries.iter().position(|(entry_inode, _)| *entry_inode == inode) {
+            let entry = self.entries.remove(index).unwrap();
+            self.entries.push_back(entry);
+        } else {
+            let data = load()?;
+            self.size += data.len();
+            self.entries.push_back((inode, data));
+
+            while self.size > self.limit && self.entries.len() > 1 {
+                if let Some((_, data)) = self.entries.pop_front() {
+                    self.size -= data.len();
+                }
+            }
+        }
+
+        Ok(&self.entries.back().unwrap().1)
+    }
+}
+
 #[derive(Debug)]
 struct INode {
     parent: u64,
@@ -55,6 +133,9 @@ impl INode {
 pub struct U4PakFS {
     file: File,
     inodes: Vec<INode>,
+    block_cache: BlockCache,
+    entry_cache: EntryCache,
+    read_buffer: Vec<u8>,
 
     atime:  SystemTime,
     mtime:  SystemTime,
@@ -69,12 +150,22 @@ pub struct U4PakFS {
 }
 
 impl U4PakFS {
+    #[inline]
     pub fn new(pak: &Pak, file: File) -> Result<Self> {
+        Self::with_cache_size(pak, file, DEFAULT_ENTRY_CACHE_SIZE)
+    }
+
+    // cache_size limits the memory used for the decompressed content of
+    // files without compression blocks.
+    pub fn with_cache_size(pak: &Pak, file: File, cache_size: usize) -> Result<Self> {
         let meta = file.metadata()?;
 
         let mut u4pakfs = U4PakFS {
             file,
             inodes: Vec::new(),
+            block_cache: BlockCache::default(),
+            entry_cache: EntryCache::new(cache_size),
+            read_buffer: Vec::new(),
 
             atime:  make_time(meta.st_atime(), meta.st_atime_nsec()),
             mtime:  make_time(meta.st_mtime(), meta.st_mtime_nsec()),
@@ -88,32 +179,20 @@ impl U4PakFS {
             blocks:  0,
         };
 
-        u4pakfs.inodes.push(INode {
-            parent: FUSE_ROOT_ID,
-            inode:  FUSE_ROOT_ID,
-            data: INodeData::Dir(HashMap::new()),
-            stat: FileAttr {
-                ino:    FUSE_ROOT_ID,
-                size:   5,
-                blocks: 1 + ((5 - 1) / u4pakfs.blksize),
-                atime:  u4pakfs.atime,
-                mtime:  u4pakfs.mtime,
-                ctime:  u4pakfs.ctime,
-                crtime: u4pakfs.crtime,
-                kind:   FileType::Directory,
-                perm:   0o555,
-                nlink:  1,
-                uid:    u4pakfs.uid,
-                gid:    u4pakfs.gid,
-                rdev:   0,
-                flags:  0,
-            },
-        });
-
         let version = pak.version();
         let variant = pak.variant();
-        for record in pak.index().records() {
-            u4pakfs.insert(variant, version, record)?;
+        let tree = DirTree::new(pak.index().records())?;
+
+        // DirTree nodes are already in an order where parents come first, so
+        // the inode numbers are just the node indices offset by FUSE_ROOT_ID.
+        for (index, node) in tree.nodes().iter().enumerate() {
+            let inode = index as u64 + FUSE_ROOT_ID;
+            let parent = node.parent() as u64 + FUSE_ROOT_ID;
+            let inode_data = match node.kind() {
+                DirNodeKind::Dir(children) => u4pakfs.make_dir(parent, inode, children),
+                DirNodeKind::File(record) => u4pakfs.make_file(variant, version, parent, inode, record),
+            };
+            u4pakfs.inodes.push(inode_data);
         }
 
         Ok(u4pakfs)
@@ -124,138 +203,99 @@ impl U4PakFS {
         self.inodes.get((inode - FUSE_ROOT_ID) as usize)
     }
 
-    fn insert(&mut self, variant: Variant, version: u32, record: &Record) -> Result<u64> {
-        let mut parent = FUSE_ROOT_ID;
-        let path: Vec<_> = parse_pak_path(record.filename()).collect();
-
-        if path.len() > 1 {
-            for (index, &name) in path[0..path.len() - 1].iter().enumerate() {
-                let new_inode = self.inodes.len() as u64 + FUSE_ROOT_ID;
-                let parent_inode = &mut self.inodes[(parent - FUSE_ROOT_ID) as usize];
+    fn make_dir(&self, parent: u64, inode: u64, children: &HashMap<&str, usize>) -> INode {
+        let size = 5 + children.keys().map(|name| name.len() as u64 + 1).sum::<u64>();
 
-                if let INodeData::Dir(children) = &mut parent_inode.data {
-                    if let Some(&child_inode) = children.get(name) {
-                        parent = child_inode;
-                    } else {
-                        parent_inode.stat.nlink += 1;
-                        parent_inode.stat.size += name.len() as u64 + 1;
-                        parent_inode.stat.blocks = 1 + ((parent_inode.stat.size - 1) / self.blksize);
-
-                        children.insert(name.to_string(), new_inode);
-                        self.inodes.push(INode {
-                            parent,
-                            inode:  new_inode,
-                            data: INodeData::Dir(HashMap::new()),
-                            stat: FileAttr {
-                                ino:    new_inode,
-                                size:   5,
-                                blocks: 1 + ((5 - 1) / self.blksize),
-                                atime:  self.atime,
-                                mtime:  self.mtime,
-                                ctime:  self.ctime,
-                                crtime: self.crtime,
-                                kind:   FileType::Directory,
-                                perm:   0o555,
-                                nlink:  1,
-                                uid:    self.uid,
-                                gid:    self.gid,
-                                rdev:   0,
-                                flags:  0,
-                            },
-                        });
-
-                        parent = new_inode;
-                    }
-                } else {
-                    return Err(Error::new(format!("{}: not a directory", make_pak_path(path[0..index].iter()))));
-                }
-            }
+        INode {
+            parent,
+            inode,
+            data: INodeData::Dir(children.iter()
+                .map(|(&name, &child)| (name.to_string(), child as u64 + FUSE_ROOT_ID))
+                .collect()),
+            stat: FileAttr {
+                ino:    inode,
+                size,
+                blocks: 1 + ((size - 1) / self.blksize),
+                atime:  self.atime,
+                mtime:  self.mtime,
+                ctime:  self.ctime,
+                crtime: self.crtime,
+                kind:   FileType::Directory,
+                perm:   0o555,
+                nlink:  1 + children.len() as u32,
+                uid:    self.uid,
+                gid:    self.gid,
+                rdev:   0,
+                flags:  0,
+            },
         }
+    }
 
-        if let Some(&name) = path.last() {
-            let new_inode = self.inodes.len() as u64 + FUSE_ROOT_ID;
-            let parent_inode = &mut self.inodes[(parent - FUSE_ROOT_ID) as usize];
-
-            if let INodeData::Dir(children) = &mut parent_inode.data {
-                if children.contains_key(name) {
-                    return Err(Error::new(format!("{}: file already exists", record.filename())));
-                }
-
-                parent_inode.stat.nlink += 1;
-                parent_inode.stat.size += name.len() as u64 + 1;
-                parent_inode.stat.blocks = 1 + ((parent_inode.stat.size - 1) / self.blksize);
-
-                children.insert(name.to_string(), new_inode);
-
-                let atime:  SystemTime;
-                let mtime:  SystemTime;
-                let ctime:  SystemTime;
-                let crtime: SystemTime;
-                if let Some(timestamp) = record.timestamp() {
-                    atime  = UNIX_EPOCH + Duration::from_secs(timestamp);
-                    mtime  = atime;
-                    ctime  = atime;
-                    crtime = atime;
-                } else {
-                    atime  = self.atime;
-                    mtime  = self.mtime;
-                    ctime  = self.ctime;
-                    crtime = self.crtime;
-                }
-
-                let offset = record.offset();
-                let compression_blocks;
-                if version < 7 {
-                    compression_blocks = (*record.compression_blocks()).clone();
-                } else if let Some(blocks) = record.compression_blocks() {
-                    compression_blocks = Some(blocks.iter().map(|block| CompressionBlock {
-                        start_offset: offset + block.start_offset,
-                        end_offset:   offset + block.end_offset,
-                    }).collect());
-                } else {
-                    compression_blocks = None;
-                }
-
-                let uncompressed_size = record.uncompressed_size();
-
-                self.inodes.push(INode {
-                    parent,
-                    inode: new_inode,
-                    data: INodeData::File {
-                        offset: offset + pak::Pak::header_size(version, variant, record),
-                        size: record.size(),
-                        uncompressed_size,
-                        compression_method: record.compression_method(),
-                        compression_blocks,
-                        encrypted: record.encrypted(),
-                        compression_block_size: record.compression_block_size(),
-                    },
-                    stat: FileAttr {
-                        ino:    new_inode,
-                        size:   uncompressed_size,
-                        blocks: if uncompressed_size != 0 { 1 + ((uncompressed_size - 1) / self.blksize) } else { 0 },
-                        atime,
-                        mtime,
-                        ctime,
-                        crtime,
-                        kind:   FileType::RegularFile,
-                        perm:   0o444,
-                        nlink:  1,
-                        uid:    self.uid,
-                        gid:    self.gid,
-                        rdev:   0,
-                        flags:  0,
-                    },
-                });
-
-            } else {
-                return Err(Error::new(format!("{}: not a directory", make_pak_path(path[0..path.len() - 1].iter()))));
-            }
+    fn make_file(&self, variant: Variant, version: u32, parent: u64, inode: u64, record: &Record) -> INode {
+        let atime:  SystemTime;
+        let mtime:  SystemTime;
+        let ctime:  SystemTime;
+        let crtime: SystemTime;
+        if let Some(timestamp) = record.timestamp() {
+            atime  = UNIX_EPOCH + Duration::from_secs(timestamp);
+            mtime  = atime;
+            ctime  = atime;
+            crtime = atime;
         } else {
-            return Err(Error::new("empty path".to_string()));
+            atime  = self.atime;
+            mtime  = self.mtime;
+            ctime  = self.ctime;
+            crtime = self.crtime;
         }
 
-        Ok(0)
+        let uncompressed_size = record.uncompressed_size();
+        let compression_block_size = match record.compression_block_size() {
+            0 => uncompressed_size,
+            block_size => block_size as u64,
+        };
+        let compression_blocks = record.compression_blocks().as_ref().map(|_| {
+            let mut uncompressed_offset = 0;
+            record.block_ranges(version).map(|(start_offset, end_offset)| {
+                let block_uncompressed_size = std::cmp::min(compression_block_size, uncompressed_size.saturating_sub(uncompressed_offset));
+                let block = MountBlock {
+                    start_offset,
+                    end_offset,
+                    uncompressed_offset,
+                    uncompressed_size: block_uncompressed_size,
+                };
+                uncompressed_offset += block_uncompressed_size;
+                block
+            }).collect()
+        });
+
+        INode {
+            parent,
+            inode,
+            data: INodeData::File {
+                offset: record.data_offset(version, variant),
+                size: record.size(),
+                uncompressed_size,
+                compression_method: record.compression_method(),
+                compression_blocks,
+                encrypted: record.encrypted(),
+            },
+            stat: FileAttr {
+                ino:    inode,
+                size:   uncompressed_size,
+                blocks: if uncompressed_size != 0 { 1 + ((uncompressed_size - 1) / self.blksize) } else { 0 },
+                atime,
+                mtime,
+                ctime,
+                crtime,
+                kind:   FileType::RegularFile,
+                perm:   0o444,
+                nlink:  1,
+                uid:    self.uid,
+                gid:    self.gid,
+                rdev:   0,
+                flags:  0,
+            },
+        }
     }
 }
 
@@ -391,10 +431,11 @@ impl Filesystem for U4PakFS {
     }
 
     fn read(&mut self, _req: &Request, ino: u64, _fh: u64, read_offset: i64, read_size: u32, reply: ReplyRead) {
-        if let Some(inode_data) = self.get(ino) {
+        // borrowed separately, so the buffers can be used while the inode is borrowed
+        let U4PakFS { file, inodes, block_cache, entry_cache, read_buffer, .. } = self;
+        if let Some(inode_data) = inodes.get((ino - FUSE_ROOT_ID) as usize) {
             if let INodeData::File {
                     compression_method,
-                    compression_block_size,
                     compression_blocks,
                     encrypted,
                     offset,
@@ -421,74 +462,56 @@ impl Filesystem for U4PakFS {
                         let end_offset = std::cmp::min(offset + uncompressed_size, read_offset + read_size as u64);
                         let read_size = end_offset - read_offset;
 
-                        let mut buffer = vec![0; read_size as usize];
-                        if let Err(error) = self.file.read_exact_at(&mut buffer, read_offset) {
+                        read_buffer.resize(read_size as usize, 0);
+                        if let Err(error) = file.read_exact_at(read_buffer, read_offset) {
                             return reply.error(error.raw_os_error().unwrap_or(EIO));
                         }
 
-                        return reply.data(&buffer);
+                        return reply.data(read_buffer);
                     }
                     pak::COMPR_ZLIB => {
                         if let Some(blocks) = compression_blocks {
-                            let compression_block_size = *compression_block_size as u64;
-                            let end_offset = std::cmp::min(read_offset as u64 + read_size as u64, uncompressed_size);
-                            let start_block_index   = (read_offset as u64 / compression_block_size) as usize;
-                            let mut end_block_index = (end_offset         / compression_block_size) as usize;
-
-                            if end_offset % compression_block_size != 0 {
-                                end_block_index += 1;
-                            }
-
-                            let mut current_offset = compression_block_size * start_block_index as u64;
-                            let mut in_buffer = Vec::new();
-                            let mut out_buffer = Vec::new();
-                            for block in &blocks[start_block_index..end_block_index] {
-                                let block_size = block.end_offset - block.start_offset;
-                                in_buffer.resize(block_size as usize, 0);
-                                if let Err(error) = self.file.read_exact_at(&mut in_buffer, block.start_offset) {
-                                    return reply.error(error.raw_os_error().unwrap_or(EIO));
+                            let read_offset = read_offset as u64;
+                            let end_offset = std::cmp::min(read_offset + read_size as u64, uncompressed_size);
+                            let start_block_index = blocks.partition_point(|block|
+                                block.uncompressed_offset + block.uncompressed_size <= read_offset);
+
+                            read_buffer.clear();
+                            for (index, block) in blocks.iter().enumerate().skip(start_block_index) {
+                                if block.uncompressed_offset >= end_offset {
+                                    break;
                                 }
 
-                                let mut zlib = ZlibDecoder::new(&in_buffer[..]);
-
-                                if current_offset < read_offset as u64 {
-                                    out_buffer.resize(std::cmp::min(compression_block_size, end_offset) as usize, 0);
-                                    if let Err(error) = zlib.read_exact(&mut out_buffer) {
-                                        return reply.error(error.raw_os_error().unwrap_or(EIO));
-                                    }
-                                    out_buffer.drain(0..read_offset as usize);
-                                } else if end_offset < current_offset + compression_block_size {
-                                    let remaining = end_offset - current_offset;
-                                    let index = out_buffer.len();
-                                    out_buffer.resize(index + remaining as usize, 0);
-                                    if let Err(error) = zlib.read_exact(&mut out_buffer[index..]) {
-                                        return reply.error(error.raw_os_error().unwrap_or(EIO));
-                                    }
-                                } else if let Err(error) = zlib.read_to_end(&mut out_buffer) {
-                                    return reply.error(error.raw_os_error().unwrap_or(EIO));
-                                }
-                                current_offset += compression_block_size;
+                                let data = match block_cache.block(file, ino, index, block) {
+                                    Ok(data) => data,
+                                    Err(error) => return reply.error(error.raw_os_error().unwrap_or(EIO)),
+                                };
+
+                                let start = read_offset.saturating_sub(block.uncompressed_offset) as usize;
+                                let end = (end_offset - block.uncompressed_offset) as usize;
+                                read_buffer.extend_from_slice(&data[start..std::cmp::min(end, data.len())]);
                             }
 
-                            return reply.data(&out_buffer);
+                            return reply.data(read_buffer);
                         } else {
                             // version 2 has compression support, but not compression blocks
                             let size = *size;
-                            let mut in_buffer = vec![0u8; size as usize];
-                            let mut out_buffer = Vec::with_capacity(uncompressed_size as usize);
-                            if let Err(error) = self.file.read_exact_at(&mut in_buffer, offset) {
-                                return reply.error(error.raw_os_error().unwrap_or(EIO));
-                            }
-
-                            let mut zlib = ZlibDecoder::new(&in_buffer[..]);
-                            if let Err(error) = zlib.read_to_end(&mut out_buffer) {
-                                return reply.error(error.raw_os_error().unwrap_or(EIO));
-                            }
-
-                            if (read_size as usize) < out_buffer.len() {
-                                return reply.data(&out_buffer[0..read_size as usize]);
-                            }
-                            return reply.data(&out_buffer);
+                            let data = entry_cache.entry(ino, || {
+                                read_buffer.resize(size as usize, 0);
+                                file.read_exact_at(read_buffer, offset)?;
+
+                                let mut data = Vec::with_capacity(uncompressed_size as usize);
+                                ZlibDecoder::new(&read_buffer[..]).read_to_end(&mut data)?;
+                                Ok(data)
+                            });
+                            let data = match data {
+                                Ok(data) => data,
+                                Err(error) => return reply.error(error.raw_os_error().unwrap_or(EIO)),
+                            };
+
+                            let start = std::cmp::min(read_offset as usize, data.len());
+                            let end = std::cmp::min(start + read_size as usize, data.len());
+                            return reply.data(&data[start..end]);
                         }
                     }
                     _ => return reply.error(ENOSYS)
@@ -506,6 +529,8 @@ impl Filesystem for U4PakFS {
 pub struct MountOptions {
     pub foreground: bool,
     pub debug: bool,
+    // memory for the decompressed content of files without compression blocks
+    pub cache_size: usize,
 }
 
 impl Default for MountOptions {
@@ -513,6 +538,7 @@ impl Default for MountOptions {
         Self {
             foreground: false,
             debug: false,
+            cache_size: DEFAULT_ENTRY_CACHE_SIZE,
         }
     }
 }
@@ -543,7 +569,7 @@ pub fn mount(pak: Pak, file: File, mountpt: impl AsRef<Path>, options: MountOpti
         foreground = options.foreground;
     }
 
-    let fs = U4PakFS::new(&pak, file)?;
+    let fs = U4PakFS::with_cache_size(&pak, file, options.cache_size)?;
 
     drop(pak);
 
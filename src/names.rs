@@ -0,0 +1,105 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Guessing file names for paks that only have a path hash index. Packages
+// (.uasset/.umap) contain the package paths of themselves and of the packages
+// they reference (e.g. /Game/Maps/Level), which can be turned into candidate
+// file names that are then checked against the path hashes.
+
+use std::collections::BTreeSet;
+
+// FPackageFileSummary::Tag at the start of .uasset/.umap files
+pub const PACKAGE_FILE_TAG: u32 = 0x9E2A83C1;
+
+pub const PACKAGE_EXTENSIONS: [&str; 5] = ["uasset", "umap", "uexp", "ubulk", "uptnl"];
+
+#[inline]
+pub fn is_package(data: &[u8]) -> bool {
+    data.len() >= 4 && u32::from_le_bytes([data[0], data[1], data[2], data[3]]) == PACKAGE_FILE_TAG
+}
+
+#[inline]
+fn is_path_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-' | b'/' | b'.')
+}
+
+// Package paths starting with /Game/ or /Engine/ found anywhere in data,
+// without the object name (/Game/Foo.Foo -> /Game/Foo).
+pub fn harvest_package_paths(data: &[u8]) -> BTreeSet<String> {
+    let mut paths = BTreeSet::new();
+
+    let mut index = 0;
+    while index < data.len() {
+        if data[index] == b'/' && (data[index..].starts_with(b"/Game/") || data[index..].starts_with(b"/Engine/")) {
+            let start = index;
+            while index < data.len() && is_path_byte(data[index]) {
+                index += 1;
+            }
+            let mut path = &data[start..index];
+            if let Some(dot) = path.iter().position(|&byte| byte == b'.') {
+                path = &path[..dot];
+            }
+            while path.last() == Some(&b'/') {
+                path = &path[..path.len() - 1];
+            }
+            // "/Game/" alone is left as "/Game"
+            if path.starts_with(b"/Game/") || path.starts_with(b"/Engine/") {
+                paths.insert(String::from_utf8_lossy(path).into_owned());
+            }
+        } else {
+            index += 1;
+        }
+    }
+
+    paths
+}
+
+// File names relative to the mount point that the package path might have.
+// content_prefixes are the directories that /Game/ might be mapped to, e.g.
+// "MyGame/Content/" if the mount point is "../../../".
+pub fn package_path_candidates(package_path: &str, content_prefixes: &[String]) -> Vec<String> {
+    let mut candidates = Vec::new();
+
+    if let Some(rest) = package_path.strip_prefix("/Game/") {
+        for prefix in content_prefixes {
+            for ext in &PACKAGE_EXTENSIONS {
+                candidates.push(format!("{}{}.{}", prefix, rest, ext));
+            }
+        }
+    } else if let Some(rest) = package_path.strip_prefix("/Engine/") {
+        for ext in &PACKAGE_EXTENSIONS {
+            candidates.push(format!("Engine/Content/{}.{}", rest, ext));
+        }
+    }
+
+    candidates
+}
+
+// Directories that /Game/ might be mapped to judging by the known file names.
+// Always contains "" (for paks mounted at the content directory).
+pub fn content_prefixes<'a>(mount_point: &str, filenames: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut prefixes = BTreeSet::new();
+    prefixes.insert(String::new());
+
+    // mounted at the project directory (e.g. "../../../MyGame/")
+    let mount_point = mount_point.trim_start_matches("../").trim_matches('/');
+    if !mount_point.is_empty() && !mount_point.ends_with("Content") && !mount_point.contains('/') {
+        prefixes.insert("Content/".to_string());
+    }
+
+    for filename in filenames {
+        if let Some(index) = filename.find("Content/") {
+            if index == 0 || filename.as_bytes()[index - 1] == b'/' {
+                let prefix = &filename[..index + "Content/".len()];
+                if prefix != "Engine/Content/" {
+                    prefixes.insert(prefix.to_string());
+                }
+            }
+        }
+    }
+
+    prefixes.into_iter().collect()
+}
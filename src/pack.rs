@@ -4,32 +4,49 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{collections::HashMap, convert::TryFrom, io::{BufWriter, Read, Seek, SeekFrom, Write}, num::{NonZeroU32, NonZeroUsize, NonZeroU64}, path::{Path, PathBuf}, time::UNIX_EPOCH};
+use std::{borrow::Cow, collections::{BTreeMap, HashMap, HashSet}, convert::TryFrom, io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write}, num::{NonZeroU32, NonZeroUsize, NonZeroU64}, path::{Path, PathBuf}, sync::Arc, time::UNIX_EPOCH};
 use std::fs::{OpenOptions, File};
 
-use crossbeam_channel::{Receiver, Sender, unbounded};
+use crossbeam_channel::{Receiver, Sender, bounded, select, unbounded};
 use crossbeam_utils::thread;
+use log::debug;
 use openssl::sha::Sha1 as OpenSSLSha1;
 use flate2::{Compression, write::ZlibEncoder};
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
 
-use crate::{Result, pak::{BUFFER_SIZE, COMPRESSION_BLOCK_HEADER_SIZE, CONAN_EXILE_RECORD_HEADER_SIZE, DEFAULT_COMPRESSION_LEVEL, V1_RECORD_HEADER_SIZE, V2_RECORD_HEADER_SIZE, V3_RECORD_HEADER_SIZE, Variant}, record::CompressionBlock, walkdir::walkdir};
+use crate::{Result, pak::{BUFFER_SIZE, COMPRESSION_BLOCK_HEADER_SIZE, DEFAULT_COMPRESSION_LEVEL, Variant}, record::CompressionBlock, walkdir::walkdir};
 use crate::Pak;
-use crate::result::Error;
-use crate::pak::{PAK_MAGIC, Sha1, COMPR_NONE, COMPR_ZLIB, DEFAULT_BLOCK_SIZE, DEFAULT_MIN_COMPRESSION_SIZE, compression_method_name};
+use crate::budget::{MemoryBudget, MemoryGuard};
+use crate::result::{Error, ErrorKind};
+use crate::pak::{PAK_COMPRESSION_METHOD_SIZE, PAK_ENCRYPTION_GUID_SIZE, PAK_MAGIC, Sha1, COMPR_NONE, COMPR_ZLIB, DEFAULT_BLOCK_SIZE, DEFAULT_MIN_COMPRESSION_SIZE, compression_method_name};
 use crate::record::Record;
-use crate::util::{make_pak_path, parse_compression_level, parse_pak_path, parse_size};
+use crate::util::{align, make_pak_path, parse_compression_level, parse_pak_path, parse_size};
+use crate::decrypt::{default_cipher, encrypt, Cipher};
 use crate::encode;
 use crate::encode::Encode;
+use crate::glob::{split_glob, Glob};
 use crate::index::Encoding;
 use crate::index::Index;
+use crate::progress::{Progress, ProgressCallback};
+use crate::toc::{Toc, TOC_COMPRESSION_BLOCK_SIZE, TOC_FLAG_COMPRESSED, TOC_FLAG_ENCRYPTED, TOC_FLAG_INDEXED, TOC_HEADER_SIZE, TOC_MAGIC, TOC_VERSION_PARTITION_SIZE};
+use crate::variant::record_layout;
 
 pub const COMPR_DEFAULT: u32 = u32::MAX;
 
+// File formats that are already compressed and won't get any smaller by
+// compressing them again.
+pub const DEFAULT_NO_COMPRESS_EXTS: [&str; 15] = [
+    "7z", "bk2", "gz", "jpeg", "jpg", "mp3", "mp4", "ogg", "opus", "png",
+    "usm", "webm", "webp", "wem", "zip",
+];
+
 #[derive(Debug, Clone)]
 pub struct PackPath {
     pub compression_method: u32,
     pub compression_block_size: Option<NonZeroU32>,
     pub compression_level: Option<NonZeroU32>,
+    pub compression_min_size: Option<NonZeroU64>,
+    pub encrypted: Option<bool>,
     pub filename: String,
     pub rename: Option<String>,
 }
@@ -40,6 +57,8 @@ impl PackPath {
             compression_method: COMPR_DEFAULT,
             compression_block_size: None,
             compression_level: None,
+            compression_min_size: None,
+            encrypted: None,
             filename,
             rename: None,
         }
@@ -50,7 +69,7 @@ impl TryFrom<&str> for PackPath {
     type Error = crate::result::Error;
 
     fn try_from(path_spec: &str) -> std::result::Result<Self, Self::Error> {
-        // :zlib,level=5,block_size=512,rename=egg/spam.txt:/foo/bar/baz.txt
+        // :zlib,level=5,block_size=512,min_size=1K,rename=egg/spam.txt:/foo/bar/baz.txt
         if let Some(suffix) = path_spec.strip_prefix(':') {
             if let Some(index) = suffix.find(':') {
                 let (param_str, filename) = suffix.split_at(index + 1);
@@ -59,6 +78,8 @@ impl TryFrom<&str> for PackPath {
                 let mut compression_method = COMPR_DEFAULT;
                 let mut compression_block_size = None;
                 let mut compression_level = None;
+                let mut compression_min_size = None;
+                let mut encrypted = None;
                 let mut rename = None;
 
                 for param in param_str.split(',') {
@@ -66,6 +87,10 @@ impl TryFrom<&str> for PackPath {
                         compression_method = COMPR_ZLIB;
                     } else if param.eq_ignore_ascii_case("none") {
                         compression_method = COMPR_NONE;
+                    } else if param.eq_ignore_ascii_case("encrypt") {
+                        encrypted = Some(true);
+                    } else if param.eq_ignore_ascii_case("no_encrypt") {
+                        encrypted = Some(false);
                     } else if let Some(index) = param.find('=') {
                         let (key, value) = param.split_at(index + 1);
                         let key = &key[..key.len() - 1];
@@ -87,6 +112,21 @@ impl TryFrom<&str> for PackPath {
                                     }
                                 }
                             }
+                        } else if key.eq_ignore_ascii_case("min_size") {
+                            if value.eq_ignore_ascii_case("default") {
+                                compression_min_size = Some(DEFAULT_MIN_COMPRESSION_SIZE);
+                            } else {
+                                match parse_size(value) {
+                                    Ok(min_size) if min_size > 0 => {
+                                        compression_min_size = NonZeroU64::new(min_size as u64);
+                                    }
+                                    _ => {
+                                        return Err(Error::new(format!(
+                                            "illegal path specification, illegal parameter value {:?} in: {:?}",
+                                            param, path_spec)));
+                                    }
+                                }
+                            }
                         } else if key.eq_ignore_ascii_case("rename") {
                             rename = Some(value.to_string());
                         } else {
@@ -104,7 +144,9 @@ impl TryFrom<&str> for PackPath {
                 return Ok(Self {
                     compression_block_size,
                     compression_level,
+                    compression_min_size,
                     compression_method,
+                    encrypted,
                     filename: filename.to_string(),
                     rename,
                 });
@@ -119,19 +161,103 @@ impl TryFrom<&str> for PackPath {
     }
 }
 
-#[derive(Debug)]
+// Options for a single file as decided by PackOptions::entry_options. What is
+// None is taken from the PackPath the file belongs to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryOptions {
+    pub compression_method: Option<u32>,
+    pub compression_block_size: Option<NonZeroU32>,
+    pub compression_level: Option<NonZeroU32>,
+    pub compression_min_size: Option<NonZeroU64>,
+    pub encrypted: Option<bool>,
+}
+
+// Called with the source path and size of every file before it is packed.
+// The compression method returned wins over PackOptions::no_compress_exts.
+pub trait EntryOptionsCallback: Sync {
+    fn entry_options(&self, path: &Path, size: u64) -> EntryOptions;
+}
+
+impl<F> EntryOptionsCallback for F where F: Fn(&Path, u64) -> EntryOptions + Sync {
+    #[inline]
+    fn entry_options(&self, path: &Path, size: u64) -> EntryOptions {
+        self(path, size)
+    }
+}
+
+impl std::fmt::Debug for dyn EntryOptionsCallback + '_ {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EntryOptionsCallback")
+    }
+}
+
+// Order of the records in the package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackOrder {
+    // in the order the threads finish them, which is the fastest
+    Unordered,
+    // in the order of the paths, files of directories in walk order
+    Input,
+    // sorted by the filename in the package
+    Name,
+}
+
+impl Default for PackOrder {
+    #[inline]
+    fn default() -> Self {
+        PackOrder::Unordered
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct PackOptions<'a> {
     pub variant: Variant,
     pub version: u32,
+    pub magic: u32,
     pub mount_point: Option<&'a str>,
     pub compression_method: u32,
     pub compression_block_size: NonZeroU32,
     pub compression_min_size: NonZeroU64,
     pub compression_level: NonZeroU32,
+    pub no_compress_exts: HashSet<String>,
+    pub encrypted: bool,
+    pub encryption_key: Option<Vec<u8>>,
+    // GUID of encryption_key, games look the key up by it (IoStore containers
+    // only)
+    pub encryption_guid: u128,
+    // how record data is encrypted, AES-256-ECB by default (not used for
+    // IoStore containers)
+    pub cipher: Arc<dyn Cipher>,
     pub encoding: Encoding,
     pub verbose: bool,
     pub null_separated: bool,
     pub thread_count: NonZeroUsize,
+    // limit for the file data that was read, but not yet written to the
+    // package (None means no limit)
+    pub max_memory: Option<NonZeroU64>,
+    pub order: PackOrder,
+    // start every record at a multiple of this many bytes (pack() only)
+    pub alignment: Option<NonZeroU64>,
+    // files with exactly the same stored data share it instead of writing it
+    // again, their records point to the same offset (pack() only)
+    pub dedupe: bool,
+    pub entry_options: Option<&'a dyn EntryOptionsCallback>,
+    pub progress: Option<&'a dyn ProgressCallback>,
+}
+
+impl PackOptions<'_> {
+    #[inline]
+    pub fn is_no_compress_ext(&self, path: &Path) -> bool {
+        if let Some(ext) = path.extension() {
+            self.no_compress_exts.contains(&ext.to_string_lossy().to_ascii_lowercase())
+        } else {
+            false
+        }
+    }
+}
+
+pub fn normalize_ext(ext: &str) -> String {
+    ext.trim().trim_start_matches('.').to_ascii_lowercase()
 }
 
 impl Default for PackOptions<'_> {
@@ -139,50 +265,34 @@ impl Default for PackOptions<'_> {
         Self {
             variant: Variant::default(),
             version: 3,
+            magic: PAK_MAGIC,
             mount_point: None,
             compression_method: COMPR_NONE,
             compression_block_size: DEFAULT_BLOCK_SIZE,
             compression_min_size: DEFAULT_MIN_COMPRESSION_SIZE,
             compression_level: DEFAULT_COMPRESSION_LEVEL,
+            no_compress_exts: DEFAULT_NO_COMPRESS_EXTS.iter().map(|ext| ext.to_string()).collect(),
+            encrypted: false,
+            encryption_key: None,
+            encryption_guid: 0,
+            cipher: default_cipher(),
             encoding: Encoding::default(),
             verbose: false,
             null_separated: false,
             thread_count: NonZeroUsize::new(num_cpus::get()).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            max_memory: None,
+            order: PackOrder::default(),
+            alignment: None,
+            dedupe: false,
+            entry_options: None,
+            progress: None,
         }
     }
 }
 
 pub fn pack(pak_path: impl AsRef<Path>, paths: &[PackPath], options: PackOptions) -> Result<Pak> {
-    let write_record_inline = match options.variant {
-        Variant::ConanExiles => {
-            return Err(Error::new("Writing of Conan Exile paks is not supported.".to_string()).
-                with_path(pak_path));
-            // XXX: There a are 20 unknown bytes after the inline record information if compressed.
-            //      That is 16 extra to the already 4 extra bytes in standard version >= 4.
-            //      In the index record there are only 4 extra bytes that are always 0.
-            //if options.version != 4 {
-            //    return Err(Error::new(format!(
-            //        "Only know how to handle Conan Exile paks of version 4, but version was {}.",
-            //        options.version)).
-            //        with_path(pak_path));
-            //}
-            //Record::write_conan_exiles_inline
-        }
-        Variant::Standard => match options.version {
-            1 => Record::write_v1_inline,
-            2 => Record::write_v2_inline,
-            3 => Record::write_v3_inline,
-            // XXX: There is an unknown 32bit field after the inline(!) record information if compressed.
-            // 4 => Record::write_v3_inline, // maybe?
-            // 5 => Record::write_v3_inline, // maybe?
-            // 7 => Record::write_v3_inline, // maybe?
-            _ => {
-                return Err(Error::new(
-                    format!("unsupported version: {}", options.version)).
-                    with_path(pak_path));
-            }
-        }
-    };
+    let write_record_inline = options.variant.spec().inline_record_writer(options.version)
+        .map_err(|error| error.with_path(&pak_path))?;
 
     match options.compression_method {
         self::COMPR_NONE | self::COMPR_ZLIB => {}
@@ -207,20 +317,29 @@ pub fn pack(pak_path: impl AsRef<Path>, paths: &[PackPath], options: PackOptions
     let mut writer = BufWriter::new(&mut out_file);
 
     let mut data_size = 0u64;
+    let progress = Progress::new(options.progress);
+    let budget = MemoryBudget::new(options.max_memory);
+    let block_jobs = BlockJobs::new(options.thread_count.get());
 
     let thread_result = thread::scope::<_, Result<()>>(|scope| {
-        let mut filenames = HashMap::new();
+        // collect_work() has to be done before results are received, so only
+        // the result channel can be bounded
         let (work_sender, work_receiver) = unbounded();
-        let (result_sender, result_receiver) = unbounded();
+        let (result_sender, result_receiver) = bounded(options.thread_count.get());
 
         for _ in 0..options.thread_count.get() {
             let work_receiver = work_receiver.clone();
             let result_sender = result_sender.clone();
-
-            scope.spawn(|_| {
-                if let Err(error) = worker_proc(&options, work_receiver, result_sender) {
+            let error_sender = result_sender.clone();
+            let options = &options;
+            let budget = &budget;
+            let block_jobs = block_jobs.clone();
+
+            scope.spawn(move |_| {
+                if let Err(error) = worker_proc(options, budget, &block_jobs, work_receiver, result_sender) {
+                    // the receiving side is gone if it already stopped because of an error
                     if !error.error_type().is_channel_disconnected() {
-                        eprintln!("error in worker thread: {}", error);
+                        let _ = error_sender.send(Err(error));
                     }
                 }
             });
@@ -229,128 +348,90 @@ pub fn pack(pak_path: impl AsRef<Path>, paths: &[PackPath], options: PackOptions
         drop(work_receiver);
         drop(result_sender);
 
-        for path in paths {
-            let compression_method = if path.compression_method == COMPR_DEFAULT {
-                options.compression_method
-            } else {
-                path.compression_method
-            };
-
-            if options.version < 2 && compression_method != COMPR_NONE {
-                return Err(Error::new("Compression is only supported startig with version 2".to_string())
-                    .with_path(&path.filename));
+        if options.order == PackOrder::Name {
+            let mut items = Vec::new();
+            collect_work(paths, &options, |work, size| {
+                progress.add_total(1, size);
+                items.push(work);
+                Ok(())
+            })?;
+
+            items.sort_by(|a, b| a.filename.cmp(&b.filename));
+            for (index, mut work) in items.into_iter().enumerate() {
+                work.index = index;
+                let file_path = work.file_path.clone();
+                work_sender.send(work).map_err(|error| Error::new(error.to_string()).with_path(file_path))?;
             }
+        } else {
+            collect_work(paths, &options, |work, size| {
+                progress.add_total(1, size);
+                let file_path = work.file_path.clone();
+                work_sender.send(work).map_err(|error| Error::new(error.to_string()).with_path(file_path))
+            })?;
+        }
 
-            let source_path: PathBuf;
-            let filename = if let Some(filename) = &path.rename {
-                source_path = (&path.filename).into();
-                parse_pak_path(&filename).collect::<Vec<_>>()
-            } else {
-                #[cfg(target_os = "windows")]
-                let filename = path.filename
-                    .trim_end_matches(|ch| ch == '/' || ch == '\\')
-                    .split(|ch| ch == '/' || ch == '\\')
-                    .filter(|comp| !comp.is_empty())
-                    .collect::<Vec<_>>();
-
-                #[cfg(not(target_os = "windows"))]
-                let filename = path.filename
-                    .trim_end_matches('/')
-                    .split('/')
-                    .filter(|comp| !comp.is_empty())
-                    .collect::<Vec<_>>();
-
-                source_path = filename.iter().collect();
-                filename
-            };
+        progress.finish_totals();
+        drop(work_sender);
 
-            let component_count = source_path.components().count();
+        let seperator = if options.null_separated { '\0' } else { '\n' };
+        let alignment = options.alignment.map_or(1, NonZeroU64::get);
 
-            let metadata = match source_path.metadata() {
-                Ok(metadata) => metadata,
-                Err(error) => return Err(Error::io_with_path(error, source_path))
-            };
+        // results that arrived before the ones that have to be written first
+        let mut pending = BTreeMap::new();
+        let mut next_index = 0;
+        // index in records of the first record with that data
+        let mut written = HashMap::new();
 
-            let mut make_filename = |file_path: &Path| -> Result<String> {
-                let mut pak_filename: Vec<String> = filename.iter().map(|comp| comp.to_string()).collect();
+        while let Ok(result) = result_receiver.recv() {
+            let (index, record, data, guard) = result?;
+            let index = if options.order == PackOrder::Unordered { next_index } else { index };
+            pending.insert(index, (record, data, guard));
 
-                pak_filename.extend(file_path
-                    .components()
-                    .skip(component_count)
-                    .map(|comp| comp.as_os_str().to_string_lossy().into_owned()));
+            // the memory of data is given back when the guard is dropped
+            while let Some((mut record, mut data, _guard)) = pending.remove(&next_index) {
+                next_index += 1;
 
-                let filename = make_pak_path(pak_filename.iter());
+                if options.dedupe && record.sha1().is_some() {
+                    if let Some(&written_index) = written.get(&dedupe_key(&record)) {
+                        let shared: &Record = &records[written_index];
+                        let record = shared.renamed(record.filename().to_string());
 
-                if let Some(other_path) = filenames.insert(filename.clone(), file_path.to_owned()) {
-                    return Err(Error::new(
-                        format!("{}: filename not unique in archive, other path: {:?}", filename, other_path)
-                    ).with_path(file_path));
-                }
+                        if options.verbose {
+                            print!("{}{}", record.filename(), seperator);
+                        }
 
-                Ok(filename)
-            };
+                        progress.advance(record.filename(), record.uncompressed_size());
 
-            if metadata.is_dir() {
-                let iter = match walkdir(&source_path) {
-                    Ok(iter) => iter,
-                    Err(error) => return Err(Error::io_with_path(error, source_path))
-                };
-                for entry in iter {
-                    let entry = match entry {
-                        Ok(entry) => entry,
-                        Err(error) => return Err(Error::io_with_path(error, source_path))
-                    };
-                    let file_path = entry.path();
-                    let filename = make_filename(&file_path)?;
-                    match work_sender.send(Work {
-                        filename,
-                        file_path,
-                        path,
-                        compression_method,
-                    }) {
-                        Ok(()) => {}
-                        Err(error) =>
-                            return Err(Error::new(error.to_string()).with_path(entry.path()))
+                        records.push(record);
+                        continue;
                     }
+                    written.insert(dedupe_key(&record), records.len());
                 }
-            } else {
-                let file_path = source_path.clone();
-                let filename = make_filename(&file_path)?;
-                match work_sender.send(Work {
-                    filename,
-                    file_path,
-                    path,
-                    compression_method,
-                }) {
-                    Ok(()) => {}
-                    Err(error) =>
-                        return Err(Error::new(error.to_string()).with_path(source_path))
-                }
-            }
-        }
 
-        drop(work_sender);
+                let padding = align(data_size, alignment) - data_size;
+                if padding > 0 {
+                    std::io::copy(&mut std::io::repeat(0).take(padding), &mut writer)?;
+                    data_size += padding;
+                }
 
-        let seperator = if options.null_separated { '\0' } else { '\n' };
+                record.move_to(options.version, data_size);
 
-        while let Ok(result) = result_receiver.recv() {
-            let (mut record, mut data) = result?;
+                buffer.clear();
+                write_record_inline(&record, &mut buffer)?;
 
-            record.move_to(options.version, data_size);
+                data.splice(0..buffer.len(), buffer.iter().cloned());
 
-            buffer.clear();
-            write_record_inline(&record, &mut buffer)?;
+                writer.write_all(&data)?;
+                data_size += data.len() as u64;
 
-            data.splice(0..buffer.len(), buffer.iter().cloned());
+                if options.verbose {
+                    print!("{}{}", record.filename(), seperator);
+                }
 
-            writer.write_all(&data)?;
-            data_size += data.len() as u64;
+                progress.advance(record.filename(), record.uncompressed_size());
 
-            if options.verbose {
-                print!("{}{}", record.filename(), seperator);
+                records.push(record);
             }
-
-            records.push(record);
         }
 
         drop(result_receiver);
@@ -369,50 +450,379 @@ pub fn pack(pak_path: impl AsRef<Path>, paths: &[PackPath], options: PackOptions
 
     writer.seek(SeekFrom::Start(index_offset))?;
 
-    let mut index_size = 0u64;
+    let (index_size, index_sha1) = write_index(
+        &mut writer, options.variant, options.version,
+        options.mount_point.unwrap_or(""), &records, options.encoding)
+        .map_err(|error| error.with_path_if_none(pak_path))?;
 
-    let mount_pount = options.mount_point.unwrap_or("");
+    write_footer(&mut writer, options.version, options.magic, index_offset, index_size, &index_sha1)?;
+    writer.flush()?;
 
-    let mut hasher = OpenSSLSha1::new();
+    let index = Index::new(
+        options
+            .mount_point
+            .map(str::to_string),
+        records,
+    );
 
-    buffer.clear();
+    let mut pak = Pak::new(
+        options.variant,
+        options.version,
+        index_offset,
+        index_size,
+        index_sha1,
+        index,
+    );
+    pak.set_cipher(options.cipher.clone());
 
-    write_path(&mut buffer, mount_pount, options.encoding)?;
-    encode!(&mut buffer, records.len() as u32);
-    writer.write_all(&buffer)?;
-    hasher.update(&buffer);
+    Ok(pak)
+}
 
-    index_size += buffer.len() as u64;
+// Records with the same key have exactly the same stored data (the SHA-1 is
+// of the compressed and encrypted data), so one can use the data of the other.
+type DedupeKey = (Option<Sha1>, u64, u64, u32, u32, bool);
 
-    let write_record = match options.variant {
-        Variant::ConanExiles => {
-            if options.version != 4 {
-                return Err(Error::new(format!(
-                    "Only know how to handle Conan Exile paks of version 4, but version was {}.",
-                    options.version)).
-                    with_path(pak_path));
+#[inline]
+fn dedupe_key(record: &Record) -> DedupeKey {
+    (*record.sha1(), record.size(), record.uncompressed_size(), record.compression_method(),
+     record.compression_block_size(), record.encrypted())
+}
+
+// IoStore chunk type for loose files, see toc::TocChunk::chunk_type()
+pub const TOC_CHUNK_TYPE_EXTERNAL_FILE: u8 = 7;
+
+// 64 bit FNV-1a, used for chunk and container ids
+fn fnv1a64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+// Writes a .utoc, .ucas and empty stub .pak (of options.version) with the same
+// file stem as pak_path. Files are stored as they are, i.e. .uasset files are
+// not converted to zen packages, and chunk ids are derived from the lower case
+// path. Per path block sizes and encryption settings are not supported,
+// because an IoStore container has only one of each.
+//
+// This is a round-trip format for u4pak (and other tools that read the
+// directory index): games expect a container header chunk and chunk ids of
+// zen packages, so they won't load the files.
+pub fn pack_iostore(pak_path: impl AsRef<Path>, paths: &[PackPath], options: PackOptions) -> Result<Toc> {
+    let pak_path = pak_path.as_ref().with_extension("pak");
+    let pak_path = pak_path.as_path();
+    let utoc_path = pak_path.with_extension("utoc");
+    let ucas_path = pak_path.with_extension("ucas");
+
+    match options.compression_method {
+        self::COMPR_NONE | self::COMPR_ZLIB => {}
+        _ => return Err(Error::new(
+            format!("unsupported compression method: {} ({})",
+                compression_method_name(options.compression_method), options.compression_method)).
+            with_path(pak_path))
+    }
+
+    let compression_block_size = options.compression_block_size.get();
+    if compression_block_size > 0xFF_FFFF {
+        return Err(Error::new(format!(
+            "compression block size too big for IoStore containers: {} (max. {})",
+            compression_block_size, 0xFF_FFFF)).with_path(pak_path));
+    }
+
+    let encryption_key = if options.encrypted {
+        match &options.encryption_key {
+            Some(key) => Some(key),
+            None => return Err(Error::new("encryption requested, but no key was given".to_string())
+                .with_kind(ErrorKind::EncryptedNoKey)
+                .with_path(pak_path)),
+        }
+    } else {
+        None
+    };
+
+    let progress = Progress::new(options.progress);
+    let mut work = Vec::new();
+    collect_work(paths, &options, |item, size| {
+        if item.path.compression_block_size.is_some() || item.path.encrypted.is_some() {
+            return Err(Error::new(
+                "per path block_size, encrypt and no_encrypt are not supported for IoStore containers".to_string())
+                .with_path(&item.path.filename));
+        }
+        progress.add_total(1, size);
+        work.push(item);
+        Ok(())
+    })?;
+    progress.finish_totals();
+
+    // chunk indices are 16 bit
+    if work.len() > u16::MAX as usize + 1 {
+        return Err(Error::new(format!(
+            "too many files for an IoStore container: {} (max. {})",
+            work.len(), u16::MAX as usize + 1))
+            .with_kind(ErrorKind::InvalidArgument)
+            .with_path(pak_path));
+    }
+
+    if options.order == PackOrder::Name {
+        work.sort_by(|a, b| a.filename.cmp(&b.filename));
+    }
+
+    let mut ucas = match File::create(&ucas_path) {
+        Ok(file) => BufWriter::new(file),
+        Err(error) => return Err(Error::io_with_path(error, &ucas_path)),
+    };
+
+    let compression_level = Compression::new(options.compression_level.get());
+    let compression_min_size = options.compression_min_size.get();
+    let seperator = if options.null_separated { '\0' } else { '\n' };
+
+    // (chunk id, offset, length) in the uncompressed address space
+    let mut chunks = Vec::with_capacity(work.len());
+    // (offset in .ucas, compressed size, uncompressed size, compressed)
+    let mut blocks = Vec::new();
+    let mut buffer = vec![0u8; compression_block_size as usize];
+    let mut out_buffer = Vec::new();
+    let mut ucas_size = 0u64;
+    let mut any_compressed = false;
+
+    for Work { filename, file_path, path, compression_method, .. } in &work {
+        let mut in_file = match File::open(file_path) {
+            Ok(file) => file,
+            Err(error) => return Err(Error::io_with_path(error, file_path)),
+        };
+        let length = match in_file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(error) => return Err(Error::io_with_path(error, file_path)),
+        };
+
+        let compression_min_size = path.compression_min_size.map_or(compression_min_size, NonZeroU64::get);
+        let compression_level = path.compression_level.map_or(compression_level, |level| Compression::new(level.get()));
+        let compress = *compression_method == COMPR_ZLIB && length >= compression_min_size;
+
+        // every chunk starts at a new block
+        let offset = blocks.len() as u64 * compression_block_size as u64;
+        let mut remaining = length;
+        while remaining > 0 {
+            let block_size = remaining.min(compression_block_size as u64) as usize;
+            let block = &mut buffer[..block_size];
+            if let Err(error) = in_file.read_exact(block) {
+                return Err(Error::io_with_path(error, file_path));
             }
-            Record::write_conan_exiles
-        }
-        Variant::Standard => match options.version {
-            1 => Record::write_v1,
-            2 => Record::write_v2,
-            3 => Record::write_v3,
-            // XXX: There is an unknown 32bit field after the inline(!) record information if compressed.
-            // 4 => Record::write_v3, // maybe?
-            // 5 => Record::write_v3, // maybe?
-            // 7 => Record::write_v3, // maybe?
-            _ => {
-                return Err(Error::new(
-                    format!("unsupported version: {}", options.version)).
-                    with_path(pak_path));
+
+            out_buffer.clear();
+            let mut compressed = false;
+            if compress {
+                let mut zlib = ZlibEncoder::new(&mut out_buffer, compression_level);
+                zlib.write_all(block)?;
+                zlib.finish()?;
+                compressed = out_buffer.len() < block_size;
+            }
+            if !compressed {
+                out_buffer.clear();
+                out_buffer.extend_from_slice(block);
+            }
+            let compressed_size = out_buffer.len();
+
+            if let Some(key) = encryption_key {
+                out_buffer.resize(align(compressed_size as u64, AES_BLOCK_SIZE as u64) as usize, 0);
+                encrypt(&mut out_buffer, key);
             }
+
+            blocks.push((ucas_size, compressed_size as u32, block_size as u32, compressed));
+            any_compressed |= compressed;
+            ucas.write_all(&out_buffer).map_err(|error| Error::io_with_path(error, &ucas_path))?;
+            ucas_size += out_buffer.len() as u64;
+            remaining -= block_size as u64;
+        }
+
+        chunks.push((fnv1a64(filename.to_lowercase().as_bytes()), offset, length));
+
+        if options.verbose {
+            print!("{}{}", filename, seperator);
+        }
+        progress.advance(filename, length);
+    }
+
+    ucas.flush().map_err(|error| Error::io_with_path(error, &ucas_path))?;
+    drop(ucas);
+
+    let mut directory_index = Vec::new();
+    write_path(&mut directory_index, options.mount_point.unwrap_or("../../../"), Encoding::UTF8)?;
+    write_toc_directory_index(&mut directory_index, work.iter().map(|work| work.filename.as_str()))?;
+    if let Some(key) = encryption_key {
+        directory_index.resize(align(directory_index.len() as u64, AES_BLOCK_SIZE as u64) as usize, 0);
+        encrypt(&mut directory_index, key);
+    }
+
+    let mut flags = TOC_FLAG_INDEXED;
+    if any_compressed {
+        flags |= TOC_FLAG_COMPRESSED;
+    }
+    if encryption_key.is_some() {
+        flags |= TOC_FLAG_ENCRYPTED;
+    }
+
+    let container_name = pak_path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut utoc = Vec::new();
+    utoc.write_all(TOC_MAGIC)?;
+    utoc.write_all(&[TOC_VERSION_PARTITION_SIZE, 0, 0, 0])?;
+    for value in [
+        TOC_HEADER_SIZE,
+        chunks.len() as u32,
+        blocks.len() as u32,
+        TOC_COMPRESSION_BLOCK_SIZE,
+        // compression method count and name length
+        if any_compressed { 1 } else { 0 },
+        PAK_COMPRESSION_METHOD_SIZE as u32,
+        compression_block_size,
+        directory_index.len() as u32,
+        // partition count
+        1,
+    ] {
+        utoc.write_all(&value.to_le_bytes())?;
+    }
+    utoc.write_all(&fnv1a64(container_name.to_lowercase().as_bytes()).to_le_bytes())?;
+    let encryption_guid = if encryption_key.is_some() { options.encryption_guid } else { 0 };
+    utoc.write_all(&encryption_guid.to_le_bytes())?;
+    utoc.write_all(&[flags, 0, 0, 0])?;
+    // perfect hash seeds count
+    utoc.write_all(&0u32.to_le_bytes())?;
+    // partition size
+    utoc.write_all(&u64::MAX.to_le_bytes())?;
+    utoc.resize(TOC_HEADER_SIZE as usize, 0);
+
+    for (index, &(id, _, _)) in chunks.iter().enumerate() {
+        utoc.write_all(&id.to_le_bytes())?;
+        // chunk index, padding, chunk type
+        let index = (index as u16).to_le_bytes();
+        utoc.write_all(&[index[0], index[1], 0, TOC_CHUNK_TYPE_EXTERNAL_FILE])?;
+    }
+
+    for &(_, offset, length) in &chunks {
+        if offset > 0xFF_FFFF_FFFF || length > 0xFF_FFFF_FFFF {
+            return Err(Error::new("IoStore container too big".to_string()).with_path(&utoc_path));
         }
+        utoc.write_all(&offset.to_be_bytes()[3..])?;
+        utoc.write_all(&length.to_be_bytes()[3..])?;
+    }
+
+    for &(offset, compressed_size, uncompressed_size, compressed) in &blocks {
+        if offset > 0xFF_FFFF_FFFF {
+            return Err(Error::new("IoStore container too big".to_string()).with_path(&ucas_path));
+        }
+        utoc.write_all(&offset.to_le_bytes()[..5])?;
+        utoc.write_all(&compressed_size.to_le_bytes()[..3])?;
+        utoc.write_all(&uncompressed_size.to_le_bytes()[..3])?;
+        utoc.write_all(&[compressed as u8])?;
+    }
+
+    if any_compressed {
+        let mut name = [0u8; PAK_COMPRESSION_METHOD_SIZE];
+        name[..4].copy_from_slice(b"Zlib");
+        utoc.write_all(&name)?;
+    }
+
+    utoc.write_all(&directory_index)?;
+
+    if let Err(error) = std::fs::write(&utoc_path, &utoc) {
+        return Err(Error::io_with_path(error, &utoc_path));
+    }
+
+    // Games look for paks, so an empty one is needed to mount the container.
+    pack(pak_path, &[], PackOptions {
+        verbose: false,
+        progress: None,
+        ..options.clone()
+    })?;
+
+    Toc::from_reader(&mut Cursor::new(&utoc), options.encryption_key.clone())
+        .map_err(|error| error.with_path_if_none(&utoc_path))
+}
+
+// Writes the directory entries, file entries and string table of an IoStore
+// directory index. The chunk index of each file is its position in filenames.
+fn write_toc_directory_index<'a>(writer: &mut impl Write, filenames: impl Iterator<Item=&'a str>) -> Result<()> {
+    const NO_ENTRY: u32 = u32::MAX;
+
+    // name, first child, next sibling, first file
+    let mut dirs = vec![[NO_ENTRY; 4]];
+    // name, next file, chunk index
+    let mut files: Vec<[u32; 3]> = Vec::new();
+    let mut strings: Vec<&str> = Vec::new();
+    let mut string_map: HashMap<&str, u32> = HashMap::new();
+    let mut dir_map: HashMap<(usize, &str), usize> = HashMap::new();
+
+    let mut intern = |string: &'a str, strings: &mut Vec<&'a str>| -> u32 {
+        *string_map.entry(string).or_insert_with(|| {
+            strings.push(string);
+            strings.len() as u32 - 1
+        })
     };
 
-    for record in &records {
+    for (chunk_index, filename) in filenames.enumerate() {
+        let mut components: Vec<&str> = parse_pak_path(filename).collect();
+        let name = match components.pop() {
+            Some(name) => name,
+            None => return Err(Error::new(format!("empty filename in IoStore container: {:?}", filename))),
+        };
+
+        let mut dir = 0;
+        for component in components {
+            dir = match dir_map.get(&(dir, component)) {
+                Some(&child) => child,
+                None => {
+                    let child = dirs.len();
+                    let name = intern(component, &mut strings);
+                    dirs.push([name, NO_ENTRY, dirs[dir][1], NO_ENTRY]);
+                    dirs[dir][1] = child as u32;
+                    dir_map.insert((dir, component), child);
+                    child
+                }
+            };
+        }
+
+        let name = intern(name, &mut strings);
+        files.push([name, dirs[dir][3], chunk_index as u32]);
+        dirs[dir][3] = files.len() as u32 - 1;
+    }
+
+    writer.write_all(&(dirs.len() as u32).to_le_bytes())?;
+    for dir in &dirs {
+        for value in dir {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    writer.write_all(&(files.len() as u32).to_le_bytes())?;
+    for file in &files {
+        for value in file {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    writer.write_all(&(strings.len() as u32).to_le_bytes())?;
+    for string in strings {
+        write_path(writer, string, Encoding::UTF8)?;
+    }
+
+    Ok(())
+}
+
+// Writes the index at the current position of writer and returns its size and SHA-1.
+pub fn write_index(writer: &mut impl Write, variant: Variant, version: u32, mount_point: &str, records: &[Record], encoding: Encoding) -> Result<(u64, Sha1)> {
+    let write_record = variant.spec().index_record_writer(version)?;
+
+    let mut index_size = 0u64;
+    let mut hasher = OpenSSLSha1::new();
+    let mut buffer = Vec::new();
+
+    write_path(&mut buffer, mount_point, encoding)?;
+    encode!(&mut buffer, records.len() as u32);
+    writer.write_all(&buffer)?;
+    hasher.update(&buffer);
+
+    index_size += buffer.len() as u64;
+
+    for record in records {
         buffer.clear();
-        write_path(&mut buffer, record.filename(), options.encoding)?;
+        write_path(&mut buffer, record.filename(), encoding)?;
         write_record(record, &mut buffer)?;
 
         writer.write_all(&buffer)?;
@@ -420,32 +830,33 @@ pub fn pack(pak_path: impl AsRef<Path>, paths: &[PackPath], options: PackOptions
         index_size += buffer.len() as u64;
     }
 
-    let index_sha1: Sha1 = hasher.finish();
+    Ok((index_size, hasher.finish()))
+}
 
-    encode!(&mut writer,
-        PAK_MAGIC,
-        options.version,
-        index_offset,
-        index_size,
-        index_sha1,
-    );
-    writer.flush()?;
+pub fn write_footer(writer: &mut impl Write, version: u32, magic: u32, index_offset: u64, index_size: u64, index_sha1: &Sha1) -> Result<()> {
+    if version > 7 {
+        return Err(Error::new(format!("writing footers of version {} is not supported", version)));
+    }
 
-    let index = Index::new(
-        options
-            .mount_point
-            .map(str::to_string),
-        records,
-    );
+    if version >= 7 {
+        // encryption key GUID
+        writer.write_all(&[0u8; PAK_ENCRYPTION_GUID_SIZE])?;
+    }
 
-    Ok(Pak::new(
-        options.variant,
-        options.version,
+    if version >= 4 {
+        // index is not encrypted
+        encode!(writer, 0u8);
+    }
+
+    encode!(writer,
+        magic,
+        version,
         index_offset,
         index_size,
         index_sha1,
-        index,
-    ))
+    );
+
+    Ok(())
 }
 
 pub fn write_path(writer: &mut impl Write, path: &str, encoding: Encoding) -> Result<()> {
@@ -502,12 +913,202 @@ pub fn write_path(writer: &mut impl Write, path: &str, encoding: Encoding) -> Re
     Ok(())
 }
 
+// Walks the given paths and calls visit for every file with the size of the
+// file. Fails on duplicate filenames.
+// Applies options.entry_options to the PackPath of a single file.
+fn entry_path<'a>(path: &'a PackPath, compression_method: u32, file_path: &Path, size: u64, options: &PackOptions) -> Result<(Cow<'a, PackPath>, u32)> {
+    let entry = if let Some(callback) = options.entry_options {
+        callback.entry_options(file_path, size)
+    } else {
+        return Ok((Cow::Borrowed(path), compression_method));
+    };
+
+    if entry == EntryOptions::default() {
+        return Ok((Cow::Borrowed(path), compression_method));
+    }
+
+    let compression_method = entry.compression_method.unwrap_or(compression_method);
+    if options.version < 2 && compression_method != COMPR_NONE {
+        return Err(Error::new("Compression is only supported startig with version 2".to_string())
+            .with_path(file_path));
+    }
+
+    Ok((Cow::Owned(PackPath {
+        compression_method,
+        compression_block_size: entry.compression_block_size.or(path.compression_block_size),
+        compression_level: entry.compression_level.or(path.compression_level),
+        compression_min_size: entry.compression_min_size.or(path.compression_min_size),
+        encrypted: entry.encrypted.or(path.encrypted),
+        filename: path.filename.clone(),
+        rename: path.rename.clone(),
+    }), compression_method))
+}
+
+pub(crate) fn collect_work<'a>(paths: &'a [PackPath], options: &PackOptions, mut visit: impl FnMut(Work<'a>, u64) -> Result<()>) -> Result<()> {
+    let mut filenames = HashMap::new();
+    let mut index = 0;
+
+    for path in paths {
+        let compression_method = if path.compression_method == COMPR_DEFAULT {
+            options.compression_method
+        } else {
+            path.compression_method
+        };
+
+        if options.version < 2 && compression_method != COMPR_NONE {
+            return Err(Error::new("Compression is only supported startig with version 2".to_string())
+                .with_path(&path.filename));
+        }
+
+        // Content/**/*.uasset packs the files below Content that match
+        let (spec_filename, glob) = match split_glob(&path.filename) {
+            Some((base, pattern)) => {
+                let glob = Glob::new(pattern).map_err(|error| error.with_path(&path.filename))?;
+                (base, Some(glob))
+            }
+            None => (path.filename.as_str(), None),
+        };
+
+        let source_path: PathBuf;
+        let filename = if let Some(filename) = &path.rename {
+            source_path = spec_filename.into();
+            parse_pak_path(filename).collect::<Vec<_>>()
+        } else {
+            #[cfg(target_os = "windows")]
+            let filename = spec_filename
+                .trim_end_matches(|ch| ch == '/' || ch == '\\')
+                .split(|ch| ch == '/' || ch == '\\')
+                .filter(|comp| !comp.is_empty())
+                .collect::<Vec<_>>();
+
+            #[cfg(not(target_os = "windows"))]
+            let filename = spec_filename
+                .trim_end_matches('/')
+                .split('/')
+                .filter(|comp| !comp.is_empty())
+                .collect::<Vec<_>>();
+
+            source_path = filename.iter().collect();
+            filename
+        };
+
+        // a pattern without directory, like *.txt
+        let source_path = if glob.is_some() && source_path.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            source_path
+        };
+
+        let component_count = source_path.components().count();
+
+        let metadata = match source_path.metadata() {
+            Ok(metadata) => metadata,
+            Err(error) => return Err(Error::io_with_path(error, source_path))
+        };
+
+        let mut make_filename = |file_path: &Path| -> Result<String> {
+            let mut pak_filename: Vec<String> = filename.iter().map(|comp| comp.to_string()).collect();
+
+            pak_filename.extend(file_path
+                .components()
+                .skip(component_count)
+                .map(|comp| comp.as_os_str().to_string_lossy().into_owned()));
+
+            let filename = make_pak_path(pak_filename.iter());
+
+            if let Some(other_path) = filenames.insert(filename.clone(), file_path.to_owned()) {
+                return Err(Error::new(
+                    format!("{}: filename not unique in archive, other path: {:?}", filename, other_path)
+                ).with_path(file_path));
+            }
+
+            Ok(filename)
+        };
+
+        if metadata.is_dir() {
+            let iter = match walkdir(&source_path) {
+                Ok(iter) => iter,
+                Err(error) => return Err(Error::io_with_path(error, source_path))
+            };
+            let mut matched = false;
+            for entry in iter {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(error) => return Err(Error::io_with_path(error, source_path))
+                };
+                let file_path = entry.path();
+                if let Some(glob) = &glob {
+                    let relative: Vec<_> = file_path.components()
+                        .skip(component_count)
+                        .map(|comp| comp.as_os_str().to_string_lossy())
+                        .collect();
+                    if !glob.matches(&relative.join("/")) {
+                        continue;
+                    }
+                    matched = true;
+                }
+                let filename = make_filename(&file_path)?;
+                let size = entry.metadata().map_or(0, |metadata| metadata.len());
+                // explicitly requested compression methods win over the extension rules
+                let compression_method = if path.compression_method == COMPR_DEFAULT && options.is_no_compress_ext(&file_path) {
+                    COMPR_NONE
+                } else {
+                    compression_method
+                };
+                let (path, compression_method) = entry_path(path, compression_method, &file_path, size, options)?;
+                visit(Work {
+                    index,
+                    size,
+                    filename,
+                    file_path,
+                    path,
+                    compression_method,
+                }, size)?;
+                index += 1;
+            }
+            if glob.is_some() && !matched {
+                return Err(Error::new("no files match the pattern".to_string())
+                    .with_kind(ErrorKind::PathNotFound)
+                    .with_path(&path.filename));
+            }
+        } else if glob.is_some() {
+            return Err(Error::new(format!("not a directory: {:?}", source_path))
+                .with_kind(ErrorKind::InvalidArgument)
+                .with_path(&path.filename));
+        } else {
+            let file_path = source_path.clone();
+            let filename = make_filename(&file_path)?;
+            let compression_method = if path.compression_method == COMPR_DEFAULT && options.is_no_compress_ext(&file_path) {
+                COMPR_NONE
+            } else {
+                compression_method
+            };
+            let (path, compression_method) = entry_path(path, compression_method, &file_path, metadata.len(), options)?;
+            visit(Work {
+                index,
+                size: metadata.len(),
+                filename,
+                file_path,
+                path,
+                compression_method,
+            }, metadata.len())?;
+            index += 1;
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Debug)]
-struct Work<'a> {
-    filename: String,
-    file_path: PathBuf,
-    path: &'a PackPath,
-    compression_method: u32,
+pub(crate) struct Work<'a> {
+    // position in the package with an ordered PackOptions::order
+    pub(crate) index: usize,
+    // size of the file when it was collected
+    pub(crate) size: u64,
+    pub(crate) filename: String,
+    pub(crate) file_path: PathBuf,
+    pub(crate) path: Cow<'a, PackPath>,
+    pub(crate) compression_method: u32,
 }
 
 #[inline]
@@ -542,40 +1143,207 @@ fn write_uncompressed(data: &mut Vec<u8>, header_buffer: &mut Vec<u8>, base_head
     Ok(hasher.finish())
 }
 
-fn worker_proc(options: &PackOptions, work_channel: Receiver<Work>, result_channel: Sender<Result<(Record, Vec<u8>)>>) -> Result<()> {
-    let mut buffer = vec![0u8; BUFFER_SIZE];
-    let mut out_buffer = Vec::new();
+// Files with at least that many compression blocks have their blocks
+// compressed by all pack threads instead of only the one packing the file.
+const PARALLEL_COMPRESSION_MIN_BLOCKS: u64 = 8;
 
-    let compression_level = Compression::new(options.compression_level.get());
-    let compression_min_size = options.compression_min_size.get();
+// An uncompressed block of a big file that any pack thread can compress.
+struct BlockJob {
+    index: usize,
+    data: Vec<u8>,
+    compression_level: Compression,
+    result_sender: Sender<(usize, std::io::Result<Vec<u8>>)>,
+}
 
-    let base_header_size = match options.variant {
-        Variant::ConanExiles => {
-            if options.version != 4 {
-                return Err(Error::new(format!(
-                    "Only know how to handle Conan Exile paks of version 4, but version was {}.",
-                    options.version)));
+impl BlockJob {
+    fn run(self) {
+        let mut out_buffer = Vec::with_capacity(self.data.len());
+        let result = compress_block(&self.data, self.compression_level, &mut out_buffer)
+            .map(|_| out_buffer);
+        // the receiving side is gone if packing that file failed
+        let _ = self.result_sender.send((self.index, result));
+    }
+}
+
+// Queue of block jobs shared by all pack threads. Threads waiting for work or
+// for the blocks of their own file help with whatever blocks are queued.
+#[derive(Clone)]
+struct BlockJobs {
+    sender: Sender<BlockJob>,
+    receiver: Receiver<BlockJob>,
+    thread_count: usize,
+}
+
+impl BlockJobs {
+    fn new(thread_count: usize) -> Self {
+        let (sender, receiver) = unbounded();
+        Self { sender, receiver, thread_count }
+    }
+
+    // The next file to pack or None if there is no more work.
+    fn next_work<'a>(&self, work_channel: &Receiver<Work<'a>>) -> Option<Work<'a>> {
+        loop {
+            select! {
+                recv(work_channel) -> work => return work.ok(),
+                recv(self.receiver) -> job => if let Ok(job) = job { job.run() },
             }
-            CONAN_EXILE_RECORD_HEADER_SIZE
-        }
-        Variant::Standard => match options.version {
-            1 => V1_RECORD_HEADER_SIZE,
-            2 => V2_RECORD_HEADER_SIZE,
-            3 => V3_RECORD_HEADER_SIZE,
-            4 => V3_RECORD_HEADER_SIZE, // maybe?
-            5 => V3_RECORD_HEADER_SIZE, // maybe?
-            7 => V3_RECORD_HEADER_SIZE, // maybe?
-            _ => {
-                panic!("unsupported version: {}", options.version)
+        }
+    }
+
+    fn wait_for<T>(&self, result_channel: &Receiver<T>) -> Result<T> {
+        loop {
+            select! {
+                recv(result_channel) -> result => return result.map_err(|error| Error::new(error.to_string())),
+                recv(self.receiver) -> job => if let Ok(job) = job { job.run() },
             }
         }
+    }
+}
+
+#[inline]
+fn compress_block(data: &[u8], compression_level: Compression, out_buffer: &mut Vec<u8>) -> std::io::Result<()> {
+    out_buffer.clear();
+    let mut zlib = ZlibEncoder::new(out_buffer, compression_level);
+    zlib.write_all(data)?;
+    zlib.finish()?;
+    Ok(())
+}
+
+// Compresses the content of in_file block by block and appends the compressed
+// blocks to data. Returns the compression blocks and the compressed size.
+#[allow(clippy::too_many_arguments)]
+fn compress_blocks(
+        in_file: &mut File, data: &mut Vec<u8>, hasher: &mut OpenSSLSha1, header_size: u64, uncompressed_size: u64,
+        compression_block_size: u32, compression_level: Compression, buffer: &mut Vec<u8>, out_buffer: &mut Vec<u8>,
+        block_jobs: &BlockJobs) -> Result<(Vec<CompressionBlock>, u64)> {
+    let block_size = compression_block_size as u64;
+    let block_count = if uncompressed_size == 0 { 0 } else { 1 + (uncompressed_size - 1) / block_size };
+
+    let mut blocks = Vec::<CompressionBlock>::with_capacity(block_count as usize);
+    let mut start_offset = header_size;
+    let mut append = |compressed: &[u8]| -> Result<()> {
+        data.write_all(compressed)?;
+        hasher.update(compressed);
+
+        let end_offset = start_offset + compressed.len() as u64;
+        blocks.push(CompressionBlock {
+            start_offset,
+            end_offset,
+        });
+        start_offset = end_offset;
+        Ok(())
     };
+
+    let mut remaining = uncompressed_size;
+    if block_jobs.thread_count < 2 || block_count < PARALLEL_COMPRESSION_MIN_BLOCKS {
+        if buffer.len() < compression_block_size as usize {
+            buffer.resize(compression_block_size as usize, 0);
+        }
+
+        while remaining > 0 {
+            let buffer = &mut buffer[..std::cmp::min(remaining, block_size) as usize];
+            in_file.read_exact(buffer)?;
+            compress_block(buffer, compression_level, out_buffer)?;
+            append(out_buffer)?;
+            remaining -= buffer.len() as u64;
+        }
+    } else {
+        let (result_sender, result_receiver) = unbounded();
+        let max_in_flight = 2 * block_jobs.thread_count;
+        // compressed blocks that are done before the blocks in front of them
+        let mut done = HashMap::new();
+        let mut next_read = 0;
+        let mut next_append = 0;
+
+        while next_append < block_count as usize {
+            while next_read < block_count as usize && next_read - next_append < max_in_flight {
+                let mut block = vec![0u8; std::cmp::min(remaining, block_size) as usize];
+                in_file.read_exact(&mut block)?;
+                remaining -= block.len() as u64;
+
+                block_jobs.sender.send(BlockJob {
+                    index: next_read,
+                    data: block,
+                    compression_level,
+                    result_sender: result_sender.clone(),
+                }).map_err(|error| Error::new(error.to_string()))?;
+                next_read += 1;
+            }
+
+            let (index, result) = block_jobs.wait_for(&result_receiver)?;
+            done.insert(index, result?);
+
+            while let Some(compressed) = done.remove(&next_append) {
+                append(&compressed)?;
+                next_append += 1;
+            }
+        }
+    }
+
+    let size = start_offset - header_size;
+    Ok((blocks, size))
+}
+
+// Encrypts the record data after the header in place. Compression blocks are
+// each padded to the AES block size and encrypted on their own, so their
+// offsets are adjusted. Returns the new size of the stored data and its
+// checksum.
+fn encrypt_data(data: &mut Vec<u8>, header_size: usize, compression_blocks: &mut Option<Vec<CompressionBlock>>, key: &[u8], cipher: &dyn Cipher) -> (u64, Sha1) {
+    let mut hasher = OpenSSLSha1::new();
+    let size;
+
+    if let Some(blocks) = compression_blocks {
+        let mut payload = Vec::with_capacity(data.len() - header_size + blocks.len() * AES_BLOCK_SIZE);
+        for block in blocks.iter_mut() {
+            let block_data = &data[block.start_offset as usize..block.end_offset as usize];
+            let payload_start = payload.len();
+            let start_offset = (header_size + payload_start) as u64;
+            payload.extend_from_slice(block_data);
+            payload.resize(align(payload.len() as u64, AES_BLOCK_SIZE as u64) as usize, 0);
+            cipher.encrypt(&mut payload[payload_start..], key);
+            block.end_offset = start_offset + block_data.len() as u64;
+            block.start_offset = start_offset;
+        }
+        hasher.update(&payload);
+        size = payload.len() as u64;
+        data.truncate(header_size);
+        data.extend_from_slice(&payload);
+    } else {
+        size = (data.len() - header_size) as u64;
+        data.resize(header_size + align(size, AES_BLOCK_SIZE as u64) as usize, 0);
+        cipher.encrypt(&mut data[header_size..], key);
+        // only the unpadded size is used for the checksum
+        hasher.update(&data[header_size..header_size + size as usize]);
+    }
+
+    (size, hasher.finish())
+}
+
+// (Work::index, record, data including space for the inline record, memory of data)
+type PackedFile<'a> = (usize, Record, Vec<u8>, MemoryGuard<'a>);
+
+fn worker_proc<'a>(options: &PackOptions, budget: &'a MemoryBudget, block_jobs: &BlockJobs, work_channel: Receiver<Work>, result_channel: Sender<Result<PackedFile<'a>>>) -> Result<()> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut out_buffer = Vec::new();
+
+    let compression_level = Compression::new(options.compression_level.get());
+    let compression_min_size = options.compression_min_size.get();
+
+    let layout = record_layout(options.version, options.variant)?;
+    let base_header_size = layout.base_header_size;
     let mut header_buffer = vec![0u8; base_header_size as usize];
 
-    while let Ok(Work { filename, file_path, path, mut compression_method }) = work_channel.recv() {
+    while let Some(Work { index, size: collected_size, filename, file_path, path, mut compression_method }) = block_jobs.next_work(&work_channel) {
+        debug!("packing {:?} as {}", file_path, filename);
+        // has to happen before anything can fail, so there are no gaps in the tickets
+        let ordered_guard = if options.order == PackOrder::Unordered {
+            None
+        } else {
+            Some(budget.acquire_in_order(index as u64, collected_size))
+        };
         let mut data = Vec::new();
         let offset = 0;
-        let compression_blocks;
+        let mut compression_blocks;
         let mut compression_block_size = 0u32;
         let mut size;
 
@@ -596,6 +1364,11 @@ fn worker_proc(options: &PackOptions, work_channel: Receiver<Work>, result_chann
         };
 
         let uncompressed_size = metadata.len();
+        let guard = if let Some(guard) = ordered_guard {
+            guard
+        } else {
+            budget.acquire(uncompressed_size)
+        };
 
         let timestamp = if options.version == 1 {
             let created = match metadata.created() {
@@ -617,7 +1390,10 @@ fn worker_proc(options: &PackOptions, work_channel: Receiver<Work>, result_chann
             None
         };
 
-        let sha1: Sha1;
+        let mut sha1: Sha1;
+
+        let compression_min_size = path.compression_min_size
+            .map_or(compression_min_size, NonZeroU64::get);
 
         if uncompressed_size < compression_min_size {
             compression_method = COMPR_NONE;
@@ -672,7 +1448,6 @@ fn worker_proc(options: &PackOptions, work_channel: Receiver<Work>, result_chann
                         sha1 = hasher.finish();
                     }
                 } else {
-                    size = 0u64;
                     compression_block_size = path.compression_block_size
                         .unwrap_or(options.compression_block_size)
                         .get();
@@ -681,68 +1456,21 @@ fn worker_proc(options: &PackOptions, work_channel: Receiver<Work>, result_chann
                         compression_block_size = uncompressed_size as u32;
                     }
 
-                    let mut header_size = base_header_size + 4;
-                    if uncompressed_size > 0 {
-                        header_size += (1 + ((uncompressed_size - 1) / compression_block_size as u64)) * COMPRESSION_BLOCK_HEADER_SIZE;
-                    }
+                    let block_count = if uncompressed_size > 0 {
+                        1 + ((uncompressed_size - 1) / compression_block_size as u64) as usize
+                    } else {
+                        0
+                    };
+                    let header_size = layout.data_start_for(Some(block_count));
                     if header_buffer.len() < header_size as usize {
                         header_buffer.resize(header_size as usize, 0);
                     }
                     data.write_all(&header_buffer[..header_size as usize])?;
 
-                    if buffer.len() < compression_block_size as usize {
-                        buffer.resize(compression_block_size as usize, 0);
-                    }
-
-                    let mut blocks = Vec::<CompressionBlock>::new();
-                    {
-                        let buffer = &mut buffer[..compression_block_size as usize];
-                        let mut remaining = uncompressed_size as usize;
-                        let mut start_offset = header_size;
-
-                        while remaining >= compression_block_size as usize {
-                            in_file.read_exact(buffer)?;
-
-                            out_buffer.clear();
-                            let mut zlib = ZlibEncoder::new(&mut out_buffer, compression_level);
-                            zlib.write_all(&buffer)?;
-                            zlib.finish()?;
-                            data.write_all(&out_buffer)?;
-                            hasher.update(&out_buffer);
-
-                            let compressed_block_size = out_buffer.len() as u64;
-                            size += compressed_block_size;
-
-                            remaining -= compression_block_size as usize;
-                            let end_offset = start_offset + compressed_block_size;
-                            blocks.push(CompressionBlock {
-                                start_offset,
-                                end_offset,
-                            });
-                            start_offset = end_offset;
-                        }
-
-                        if remaining > 0 {
-                            let buffer = &mut buffer[..remaining];
-                            in_file.read_exact(buffer)?;
-
-                            out_buffer.clear();
-                            let mut zlib = ZlibEncoder::new(&mut out_buffer, compression_level);
-                            zlib.write_all(buffer)?;
-                            zlib.finish()?;
-                            data.write_all(&out_buffer)?;
-                            hasher.update(&out_buffer);
-
-                            let compressed_block_size = out_buffer.len() as u64;
-                            size += compressed_block_size;
-
-                            let end_offset = start_offset + compressed_block_size;
-                            blocks.push(CompressionBlock {
-                                start_offset,
-                                end_offset,
-                            });
-                        }
-                    }
+                    let (blocks, compressed_size) = compress_blocks(
+                        &mut in_file, &mut data, &mut hasher, header_size, uncompressed_size,
+                        compression_block_size, compression_level, &mut buffer, &mut out_buffer, block_jobs)?;
+                    size = compressed_size;
 
                     if size + blocks.len() as u64 * COMPRESSION_BLOCK_HEADER_SIZE as u64 >= uncompressed_size {
                         // compressed actually bigger (or same size),
@@ -768,6 +1496,33 @@ fn worker_proc(options: &PackOptions, work_channel: Receiver<Work>, result_chann
             }
         }
 
+        let encrypted = path.encrypted.unwrap_or(options.encrypted);
+        if encrypted {
+            if options.version < 3 {
+                result_channel.send(Err(Error::new(
+                    "Encryption is only supported starting with version 3".to_string())
+                    .with_path(file_path)))?;
+                break;
+            }
+
+            let key = if let Some(key) = &options.encryption_key {
+                key
+            } else {
+                result_channel.send(Err(Error::new(
+                    "File should be encrypted, but no encryption key was provided".to_string())
+                    .with_path(file_path)))?;
+                break;
+            };
+
+            let header_size = layout.data_start_for(compression_blocks.as_ref().map(Vec::len));
+
+            let (encrypted_size, encrypted_sha1) = encrypt_data(&mut data, header_size as usize, &mut compression_blocks, key, &*options.cipher);
+            if compression_blocks.is_some() {
+                size = encrypted_size;
+            }
+            sha1 = encrypted_sha1;
+        }
+
         let record = Record::new(
             filename,
             offset,
@@ -777,11 +1532,11 @@ fn worker_proc(options: &PackOptions, work_channel: Receiver<Work>, result_chann
             timestamp,
             Some(sha1),
             compression_blocks,
-            false,
+            encrypted,
             compression_block_size,
         );
 
-        result_channel.send(Ok((record, data)))?;
+        result_channel.send(Ok((index, record, data, guard)))?;
     }
 
     Ok(())
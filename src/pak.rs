@@ -4,21 +4,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{convert::TryFrom, fmt::Display, num::{NonZeroU32, NonZeroU64}, path::Path, usize};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, BufReader};
+use std::{collections::{BTreeSet, HashMap}, convert::TryFrom, fmt::Display, num::{NonZeroU32, NonZeroU64}, sync::{Arc, OnceLock}};
+use std::io::{Read, Seek, SeekFrom};
+#[cfg(feature = "fs")]
+use std::{fs::File, io::BufReader, path::Path};
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
 use log::{debug};
 
-use crate::{Error, Record, Result};
+use crate::{Error, ErrorKind, Filter, Keyring, Record, Result};
 use crate::decode;
 use crate::decode::Decode;
-use crate::index::{Encoding, Index};
+use crate::decrypt::{default_cipher, Cipher};
+use crate::entry::unpack_record_to_writer;
+use crate::index::{detect_pak_variant, hashed_name, path_hash, Encoding, Index, IndexTransform, SecondaryIndexInfo};
+use crate::names::{content_prefixes, harvest_package_paths, is_package, package_path_candidates};
+use crate::stream::OffsetReader;
+use crate::util::{align, make_pak_path, parse_pak_path};
+use crate::warnings::Warnings;
 
 pub const BUFFER_SIZE: usize = 2 * 1024 * 1024;
 
 pub const PAK_MAGIC: u32 = 0x5A6F12E1;
 pub const PAK_RELATIVE_COMPRESSION_OFFSET_VERSION: u32 = 5;
 pub const PAK_MAX_SUPPORTED_VERSION: u32 = 11;
+// how much Pak::scan_footer() searches by default
+pub const DEFAULT_FOOTER_SCAN_SIZE: u64 = 16 * 1024 * 1024;
 
 pub const DEFAULT_BLOCK_SIZE: NonZeroU32 = unsafe { NonZeroU32::new_unchecked(64 * 1024) };
 pub const DEFAULT_COMPRESSION_LEVEL: NonZeroU32 = unsafe { NonZeroU32::new_unchecked(6) };
@@ -45,6 +55,15 @@ pub const PAK_ENCRYPTION_GUID_SIZE: usize = std::mem::size_of::<u128>();
 pub const COMPR_METHODS: [u32; 4] = [COMPR_NONE, COMPR_ZLIB, COMPR_BIAS_MEMORY, COMPR_BIAS_SPEED];
 
 pub type Sha1 = [u8; 20];
+pub const NULL_SHA1: Sha1 = [0u8; 20];
+
+// versions for which the convention of the compression block offsets is
+// checked against the data, see Pak::detect_relative_block_offsets()
+pub const DETECT_BLOCK_OFFSETS_VERSIONS: std::ops::RangeInclusive<u32> =
+    PAK_RELATIVE_COMPRESSION_OFFSET_VERSION - 2..=PAK_RELATIVE_COMPRESSION_OFFSET_VERSION + 2;
+
+// number of records looked at by Pak::detect_relative_block_offsets()
+const DETECT_BLOCK_OFFSETS_SAMPLES: usize = 8;
 
 pub fn compression_method_name(compression_method: u32) -> &'static str {
     match compression_method {
@@ -79,6 +98,8 @@ impl<'a> Display for HexDisplay<'a> {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Variant {
     Standard,
     ConanExiles,
@@ -94,24 +115,56 @@ impl TryFrom<&str> for Variant {
     type Error = crate::result::Error;
 
     fn try_from(variant: &str) -> std::result::Result<Self, Error> {
-        let trimmed_variant = variant.trim();
-        if trimmed_variant.eq_ignore_ascii_case("standard") {
-            Ok(Variant::Standard)
-        } else if trimmed_variant.eq_ignore_ascii_case("conan_exiles") || trimmed_variant.eq_ignore_ascii_case("conanexiles") || trimmed_variant.eq_ignore_ascii_case("conan exiles") {
-            Ok(Variant::ConanExiles)
-        } else {
-            Err(Error::new(format!("illegal variant: {:?}", variant)))
-        }
+        Variant::from_name(variant)
+            .ok_or_else(|| Error::new(format!("illegal variant: {:?}", variant)).with_kind(ErrorKind::InvalidArgument))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Options {
     pub variant: Variant,
     pub ignore_magic: bool,
+    // some games use a different magic to obfuscate their paks
+    pub magic: u32,
     pub encoding: Encoding,
     pub force_version: Option<u32>,
     pub encryption_key: Option<Vec<u8>>,
+    // keys by the encryption GUID in the footer, a matching key is used
+    // instead of encryption_key
+    pub keyring: Keyring,
+    // how the index and record data are encrypted, AES-256-ECB by default
+    pub cipher: Arc<dyn Cipher>,
+    pub index_transform: Option<IndexTransform>,
+    // known file names (relative to the mount point or including it), for paks
+    // that only have a path hash index
+    pub name_list: Option<Vec<String>>,
+    // guess file names from the package paths referenced in packages, for paks
+    // that only have a path hash index
+    pub harvest_names: bool,
+    // keep bytes that aren't understood (see Pak::trailing_bytes()) so they
+    // can be written back verbatim
+    pub keep_unknown: bool,
+    // use another variant if the records of a version < 10 index don't fit the
+    // given one, see index::detect_variant()
+    pub detect_variant: bool,
+    // check if the compression block offsets of a version 3 to 7 package are
+    // relative to the record or absolute instead of relying on the version,
+    // see Pak::detect_relative_block_offsets()
+    pub detect_block_offsets: bool,
+    // if the footer isn't at the end of the file, search the last that many
+    // bytes for it (see Pak::scan_footer())
+    pub scan_footer: Option<u64>,
+    // where non-fatal problems found while reading are reported, also used
+    // by later operations on the pak
+    pub warnings: Warnings,
+}
+
+impl Options {
+    // The key from the keyring for the encryption GUID of the footer, or
+    // encryption_key if there is none.
+    pub fn encryption_key_for(&self, footer: &Footer) -> Option<&Vec<u8>> {
+        self.keyring.get(footer.encryption_guid()).or(self.encryption_key.as_ref())
+    }
 }
 
 impl Default for Options {
@@ -119,13 +172,25 @@ impl Default for Options {
         Self {
             variant: Variant::default(),
             ignore_magic: false,
+            magic: PAK_MAGIC,
             encoding: Encoding::UTF8,
             force_version: None,
             encryption_key: None,
+            keyring: Keyring::default(),
+            cipher: default_cipher(),
+            index_transform: None,
+            name_list: None,
+            harvest_names: false,
+            keep_unknown: false,
+            detect_variant: true,
+            detect_block_offsets: true,
+            scan_footer: None,
+            warnings: Warnings::default(),
         }
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct Footer {
     footer_offset: u64,
     encryption_uuid: u128,
@@ -139,6 +204,88 @@ pub struct Footer {
     compression: Vec<u8>,
 }
 
+impl Footer {
+    #[inline]
+    pub fn footer_offset(&self) -> u64 {
+        self.footer_offset
+    }
+
+    #[inline]
+    pub fn footer_size(&self) -> u64 {
+        Pak::footer_size(self.version) as u64
+    }
+
+    // only in version >= 7
+    #[inline]
+    pub fn encryption_guid(&self) -> u128 {
+        self.encryption_uuid
+    }
+
+    // only in version >= 4
+    #[inline]
+    pub fn encrypted_index(&self) -> bool {
+        self.encrypted
+    }
+
+    #[inline]
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+
+    #[inline]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    #[inline]
+    pub fn index_offset(&self) -> u64 {
+        self.index_offset
+    }
+
+    #[inline]
+    pub fn index_size(&self) -> u64 {
+        self.index_size
+    }
+
+    #[inline]
+    pub fn index_sha1(&self) -> &Sha1 {
+        &self.index_sha1
+    }
+
+    // only in version 9
+    #[inline]
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    // Names of the compression methods as stored in the footer (version >= 8),
+    // empty slots are skipped.
+    pub fn compression_methods(&self) -> Vec<String> {
+        self.compression
+            .chunks(PAK_COMPRESSION_METHOD_SIZE)
+            .map(|name| {
+                let len = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+                String::from_utf8_lossy(&name[..len]).into_owned()
+            })
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
+
+// What is known about a package apart from its index, see Pak::metadata().
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PakMetadata {
+    pub version: u32,
+    pub variant: Variant,
+    pub index_offset: u64,
+    pub index_size: u64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::sha1_hex"))]
+    pub index_sha1: Sha1,
+    // false for packages that weren't read from a file
+    pub encrypted_index: bool,
+}
+
 #[derive(Debug)]
 pub struct Pak {
     variant: Variant,
@@ -147,6 +294,74 @@ pub struct Pak {
     index_size: u64,
     index_sha1: Sha1,
     index: Index,
+    // None if the pak wasn't read from a file (e.g. salvaged)
+    footer: Option<Footer>,
+    // the cipher the pak was read or written with
+    cipher: Arc<dyn Cipher>,
+    // bytes between the end of the index and the footer, only read with
+    // Options::keep_unknown
+    trailing_bytes: Vec<u8>,
+    // built on first use by record() and records_under()
+    lookup: OnceLock<PathLookup>,
+    warnings: Warnings,
+    // the compression block offsets in the file don't follow the convention
+    // of the version and were converted, see normalize_block_offsets()
+    rebased_block_offsets: bool,
+}
+
+#[derive(Debug)]
+struct PathLookup {
+    // normalized path -> index of the first record with that path
+    by_path: HashMap<String, usize>,
+    // (normalized path, record index) sorted by path
+    sorted: Vec<(String, usize)>,
+}
+
+impl PathLookup {
+    fn new(records: &[Record]) -> Self {
+        let mut by_path = HashMap::with_capacity(records.len());
+        let mut sorted = Vec::with_capacity(records.len());
+
+        for (index, record) in records.iter().enumerate() {
+            let path = make_pak_path(parse_pak_path(record.filename()));
+            by_path.entry(path.clone()).or_insert(index);
+            sorted.push((path, index));
+        }
+
+        sorted.sort();
+
+        Self { by_path, sorted }
+    }
+
+    fn indices_under(&self, prefix: &str, indices: &mut Vec<usize>) {
+        let prefix = make_pak_path(parse_pak_path(prefix));
+        if prefix == "/" {
+            indices.extend(self.sorted.iter().map(|&(_, index)| index));
+            return;
+        }
+
+        let start = self.sorted.partition_point(|(path, _)| path.as_str() < prefix.as_str());
+        indices.extend(self.sorted[start..].iter()
+            .take_while(|(path, _)| *path == prefix)
+            .map(|&(_, index)| index));
+
+        // "dir-x" sorts between "dir" and "dir/x", so look for the children separately
+        let dir_prefix = format!("{}/", prefix);
+        let start = self.sorted.partition_point(|(path, _)| path.as_str() < dir_prefix.as_str());
+        indices.extend(self.sorted[start..].iter()
+            .take_while(|(path, _)| path.starts_with(&dir_prefix))
+            .map(|&(_, index)| index));
+    }
+}
+
+// Whether there is a zlib header at offset: deflate with a window of at most
+// 32K and a valid check value.
+fn at_zlib_header(reader: &mut (impl Read + Seek), offset: u64) -> bool {
+    let mut header = [0u8; 2];
+    if reader.seek(SeekFrom::Start(offset)).and_then(|_| reader.read_exact(&mut header)).is_err() {
+        return false;
+    }
+    header[0] & 0x0F == 8 && header[0] >> 4 <= 7 && u16::from_be_bytes(header) % 31 == 0
 }
 
 impl Pak {
@@ -166,9 +381,16 @@ impl Pak {
             index_size,
             index_sha1,
             index,
+            footer: None,
+            cipher: default_cipher(),
+            trailing_bytes: Vec::new(),
+            lookup: OnceLock::new(),
+            warnings: Warnings::default(),
+            rebased_block_offsets: false,
         }
     }
 
+    #[cfg(feature = "fs")]
     pub fn from_path(path: impl AsRef<Path>, options: Options) -> Result<Pak> {
         match File::open(&path) {
             Ok(mut file) => match Self::from_file(&mut file, options) {
@@ -183,44 +405,86 @@ impl Pak {
         }
     }
 
+    #[cfg(feature = "fs")]
     #[inline]
     pub fn from_file(file: &mut File, options: Options) -> Result<Pak> {
         Self::from_reader(&mut BufReader::new(file), options)
     }
 
-    pub fn from_reader<R>(reader: &mut R, options: Options) -> Result<Pak>
+    // Reads and validates the footer as done by from_reader().
+    pub fn read_footer<R>(reader: &mut R, options: &Options) -> Result<Footer>
+    where R: Read, R: Seek {
+        let footer = Self::read_unvalidated_footer(reader, options)?;
+        Self::validate_footer(footer, options)
+    }
+
+    fn read_unvalidated_footer<R>(reader: &mut R, options: &Options) -> Result<Footer>
     where R: Read, R: Seek {
         let footer: Footer;
         
         if let Some(force_version) = options.force_version {
             footer = Self::decode_footer(reader, force_version)?;
-            if !options.ignore_magic && footer.magic != 0x5A6F12E1 {
+            if !options.ignore_magic && footer.magic != options.magic {
+                if let Some(found) = Self::scan_footer_if_enabled(reader, options, Some(force_version))? {
+                    return Ok(found);
+                }
                 return Err(Error::new(format!(
                     "illegal file magic: 0x{:X}",
                     footer.magic
-                )));
+                )).with_kind(ErrorKind::InvalidMagic));
             }
         } else {
-            if let Ok(version) = Self::get_version(reader) {
+            if let Ok(version) = Self::get_version(reader, options.magic) {
                 debug!("Determined pak version {}", version);
                 footer = Self::decode_footer(reader, version)?;
             } else if options.ignore_magic {
                 footer = Self::decode_footer(reader, PAK_MAX_SUPPORTED_VERSION)?;
+            } else if let Some(found) = Self::scan_footer_if_enabled(reader, options, None)? {
+                footer = found;
             } else {
-                return Err(Error::new(format!("Failed to determine pak file version.")))
+                return Err(Error::new("Failed to determine pak file version.".to_string()).with_kind(ErrorKind::UnsupportedVersion))
             }
         }
 
-        let variant = options.variant;
+        Ok(footer)
+    }
+
+    fn scan_footer_if_enabled<R>(reader: &mut R, options: &Options, force_version: Option<u32>) -> Result<Option<Footer>>
+    where R: Read, R: Seek {
+        match options.scan_footer {
+            Some(limit) => Self::scan_footer(reader, options.magic, force_version, limit),
+            None => Ok(None),
+        }
+    }
 
+    fn validate_footer(footer: Footer, options: &Options) -> Result<Footer> {
         if footer.index_offset + footer.index_size > footer.footer_offset {
             return Err(Error::new(format!(
                 "illegal index offset/size: index_offset ({}) + index_size ({}) > footer_offset ({})",
-                footer.index_offset, footer.index_size, footer.footer_offset)));
+                footer.index_offset, footer.index_size, footer.footer_offset)).with_kind(ErrorKind::CorruptIndex));
+        }
+
+        if footer.encrypted && options.encryption_key_for(&footer).is_none() {
+            let message = if footer.version >= 7 {
+                format!("index is encrypted, but no encryption key for GUID {:032X} was provided", footer.encryption_uuid)
+            } else {
+                "index is encrypted, but no encryption key was provided".to_string()
+            };
+            return Err(Error::new(message).with_kind(ErrorKind::EncryptedNoKey));
         }
 
+        Ok(footer)
+    }
+
+    pub fn from_reader<R>(reader: &mut R, options: Options) -> Result<Pak>
+    where R: Read, R: Seek {
+        let footer = Self::read_footer(reader, &options)?;
+        let variant = detect_pak_variant(reader, &footer, &options)?;
+
         reader.seek(SeekFrom::Start(footer.index_offset))?;
 
+        let encryption_key = options.encryption_key_for(&footer).cloned();
+        let data_encryption_key = if options.harvest_names { encryption_key.clone() } else { None };
         let index = Index::read(
             reader,
             footer.index_size as usize,
@@ -228,24 +492,92 @@ impl Pak {
             variant,
             options.encoding,
             match footer.encrypted {
-                true => options.encryption_key,
+                true => encryption_key,
                 false => None,
             },
-        )?;
+            &*options.cipher,
+            options.index_transform.as_ref(),
+            &options.warnings,
+        ).map_err(|error| match error.kind() {
+            ErrorKind::Other | ErrorKind::Io => error.with_kind(ErrorKind::CorruptIndex),
+            _ => error,
+        })?;
 
         let pos = reader.seek(SeekFrom::Current(0))?;
         if pos > footer.footer_offset {
-            return Err(Error::new("index bleeds into footer".to_owned()));
+            return Err(Error::new("index bleeds into footer".to_owned()).with_kind(ErrorKind::CorruptIndex));
+        }
+
+        let mut trailing_bytes = Vec::new();
+        if options.keep_unknown {
+            let mut index_end = footer.index_offset + footer.index_size;
+            if let Some(info) = index.secondary_index() {
+                if info.has_path_hash_index() {
+                    index_end = index_end.max(info.path_hash_index_offset() + info.path_hash_index_size());
+                }
+                if info.has_full_directory_index() {
+                    index_end = index_end.max(info.full_directory_index_offset() + info.full_directory_index_size());
+                }
+            }
+
+            if index_end < footer.footer_offset {
+                trailing_bytes.resize((footer.footer_offset - index_end) as usize, 0);
+                reader.seek(SeekFrom::Start(index_end))?;
+                reader.read_exact(&mut trailing_bytes)?;
+            }
         }
 
-        Ok(Self {
+        let mut pak = Self {
             variant,
             version: footer.version,
             index_offset: footer.index_offset,
             index_size: footer.index_size,
             index_sha1: footer.index_sha1,
             index,
-        })
+            footer: Some(footer),
+            cipher: options.cipher.clone(),
+            trailing_bytes,
+            lookup: OnceLock::new(),
+            warnings: options.warnings,
+            rebased_block_offsets: false,
+        };
+
+        if options.detect_block_offsets && DETECT_BLOCK_OFFSETS_VERSIONS.contains(&pak.version) {
+            pak.normalize_block_offsets(reader);
+        }
+
+        if pak.index.hashed_names() {
+            if let Some(name_list) = &options.name_list {
+                let count = pak.recover_names(name_list.iter().map(String::as_str));
+                debug!("Recovered {} file names from name list", count);
+            }
+            if options.harvest_names {
+                let count = pak.harvest_names(reader, data_encryption_key)?;
+                debug!("Recovered {} file names from packages", count);
+            }
+        }
+
+        Ok(pak)
+    }
+
+    // Reads a package from a copy of its end starting at the index offset, i.e.
+    // the index, the secondary indices and the footer, as written by
+    // copy_raw_index(). Such a package has no data, so only its index can be
+    // used, e.g. for unpack_from_stream().
+    pub fn from_index_reader<R>(reader: &mut R, options: Options) -> Result<Pak>
+    where R: Read, R: Seek {
+        // the offsets in the footer are not of this file, but the index
+        // offset is where it starts
+        let footer = Self::read_unvalidated_footer(reader, &options)?;
+        Self::from_reader(&mut OffsetReader::new(reader, footer.index_offset), options)
+    }
+
+    // Reads and validates the footer of a copy of the end of a package, see
+    // from_index_reader().
+    pub fn read_index_footer<R>(reader: &mut R, options: &Options) -> Result<Footer>
+    where R: Read, R: Seek {
+        let footer = Self::read_unvalidated_footer(reader, options)?;
+        Self::read_footer(&mut OffsetReader::new(reader, footer.index_offset), options)
     }
 
     #[inline]
@@ -253,6 +585,17 @@ impl Pak {
         self.variant
     }
 
+    #[inline]
+    pub fn cipher(&self) -> &dyn Cipher {
+        &*self.cipher
+    }
+
+    #[cfg(feature = "fs")]
+    #[inline]
+    pub(crate) fn set_cipher(&mut self, cipher: Arc<dyn Cipher>) {
+        self.cipher = cipher;
+    }
+
     #[inline]
     pub fn version(&self) -> u32 {
         self.version
@@ -273,11 +616,279 @@ impl Pak {
         &self.index_sha1
     }
 
+    // Seed of the path hashes, only in version >= 10.
+    #[inline]
+    pub fn path_hash_seed(&self) -> Option<u64> {
+        self.index.path_hash_seed()
+    }
+
+    // Location and SHA-1 of the path hash index and full directory index, only
+    // in version >= 10.
+    #[inline]
+    pub fn secondary_index(&self) -> Option<&SecondaryIndexInfo> {
+        self.index.secondary_index()
+    }
+
+    // Bytes between the end of the index (including the secondary indices of
+    // version >= 10) and the footer. Some games store data there. Only read
+    // when the pak was opened with Options::keep_unknown, empty otherwise.
+    #[inline]
+    pub fn trailing_bytes(&self) -> &[u8] {
+        &self.trailing_bytes
+    }
+
+    #[inline]
+    fn lookup(&self) -> &PathLookup {
+        self.lookup.get_or_init(|| PathLookup::new(self.index.records()))
+    }
+
+    // Looks up a record by its path. Leading, trailing and double slashes are
+    // ignored, just like in Filter.
+    pub fn record(&self, path: &str) -> Option<&Record> {
+        let path = make_pak_path(parse_pak_path(path));
+        self.lookup().by_path.get(&path).map(|&index| &self.index.records()[index])
+    }
+
+    // All records that are the file prefix or are inside of the directory
+    // prefix, in index order.
+    pub fn records_under(&self, prefix: &str) -> Vec<&Record> {
+        let mut indices = Vec::new();
+        self.lookup().indices_under(prefix, &mut indices);
+        indices.sort_unstable();
+
+        let records = self.index.records();
+        indices.into_iter().map(|index| &records[index]).collect()
+    }
+
+    // Region of the file that is occupied by a record (inline header + data).
+    pub fn record_region(&self, record: &Record) -> (u64, u64) {
+        let data_size = if record.encrypted() {
+            if let Some(blocks) = record.compression_blocks() {
+                blocks.iter()
+                    .map(|block| align(block.end_offset.saturating_sub(block.start_offset), AES_BLOCK_SIZE as u64))
+                    .sum()
+            } else {
+                align(record.size(), AES_BLOCK_SIZE as u64)
+            }
+        } else {
+            record.size()
+        };
+        let start = record.offset();
+        let end = start + Self::header_size(self.version, self.variant, record) + data_size;
+        (start, end)
+    }
+
+    // Whether the compression block offsets of the package are relative to
+    // their record, judged by which interpretation points at a zlib header for
+    // the first block of a few unencrypted zlib records. None if that doesn't
+    // tell (no such records, or as many records look right either way).
+    pub fn detect_relative_block_offsets(&self, reader: &mut (impl Read + Seek)) -> Option<bool> {
+        let mut relative_count = 0usize;
+        let mut absolute_count = 0usize;
+
+        // for a record at offset 0 both interpretations are the same
+        let samples = self.index.records().iter()
+            .filter(|record| record.compression_method() == COMPR_ZLIB && !record.encrypted() && record.offset() != 0)
+            .filter_map(|record| record.compression_blocks().as_ref()
+                .and_then(|blocks| blocks.first())
+                .map(|block| (record.offset(), block.start_offset)))
+            .take(DETECT_BLOCK_OFFSETS_SAMPLES);
+
+        for (offset, start_offset) in samples {
+            if let Some(relative_start) = offset.checked_add(start_offset) {
+                if at_zlib_header(reader, relative_start) {
+                    relative_count += 1;
+                }
+            }
+            if at_zlib_header(reader, start_offset) {
+                absolute_count += 1;
+            }
+        }
+
+        match relative_count.cmp(&absolute_count) {
+            std::cmp::Ordering::Greater => Some(true),
+            std::cmp::Ordering::Less => Some(false),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    // Converts the compression block offsets to the convention of the version
+    // if the data shows that the package uses the other one, so unpack, check
+    // and mount read the right blocks.
+    fn normalize_block_offsets(&mut self, reader: &mut (impl Read + Seek)) {
+        let relative = self.version >= PAK_RELATIVE_COMPRESSION_OFFSET_VERSION;
+        if self.detect_relative_block_offsets(reader) != Some(!relative) {
+            return;
+        }
+
+        let convertible = !relative || self.index.records().iter().all(|record|
+            record.compression_blocks().iter().flatten().all(|block| block.start_offset >= record.offset()));
+        if !convertible {
+            return;
+        }
+
+        for record in self.index.records_mut() {
+            let offset = record.offset();
+            record.rebase_block_offsets(offset, relative);
+        }
+        self.rebased_block_offsets = true;
+
+        let convention = |relative: bool| if relative { "relative to the record" } else { "absolute" };
+        self.warnings.warn(None, format!(
+            "compression block offsets are {} instead of {} like in other version {} packages, using them as such",
+            convention(!relative), convention(relative), self.version));
+    }
+
+    // Renames records that are named after their path hash if the hash of one
+    // of the names matches. Names are relative to the mount point, but may also
+    // include it. Returns the number of recovered names.
+    pub fn recover_names<'a>(&mut self, names: impl IntoIterator<Item = &'a str>) -> usize {
+        let seed = match self.index.path_hash_seed() {
+            Some(seed) if self.index.hashed_names() => seed,
+            _ => return 0,
+        };
+
+        let mut unresolved: HashMap<u64, usize> = self.index.records().iter()
+            .enumerate()
+            .filter_map(|(index, record)| hashed_name(record.filename()).map(|hash| (hash, index)))
+            .collect();
+
+        let mount_point = self.index.mount_point().unwrap_or("").to_string();
+        let records = self.index.records_mut();
+        let mut count = 0;
+
+        for name in names {
+            if unresolved.is_empty() {
+                break;
+            }
+            let name = name.trim();
+            let name = if mount_point.is_empty() { name } else { name.strip_prefix(mount_point.as_str()).unwrap_or(name) };
+            let name = name.trim_start_matches('/');
+            if let Some(index) = unresolved.remove(&path_hash(name, seed)) {
+                records[index].set_filename(name.to_string());
+                count += 1;
+            }
+        }
+
+        if count > 0 {
+            self.lookup = OnceLock::new();
+        }
+
+        count
+    }
+
+    // Reads all packages (.uasset/.umap, or records with unknown name that
+    // start with the package file tag) and recovers the names of records from
+    // the package paths found in them, see recover_names().
+    pub fn harvest_names(&mut self, reader: &mut (impl Read + Seek), encryption_key: Option<Vec<u8>>) -> Result<usize> {
+        const MAX_PACKAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+        if !self.index.hashed_names() {
+            return Ok(0);
+        }
+
+        let mut package_paths = BTreeSet::new();
+        let mut data = Vec::new();
+        for record in self.index.records() {
+            if record.uncompressed_size() > MAX_PACKAGE_SIZE || (record.encrypted() && encryption_key.is_none()) {
+                continue;
+            }
+
+            if hashed_name(record.filename()).is_none() {
+                let filename = record.filename();
+                if !filename.ends_with(".uasset") && !filename.ends_with(".umap") {
+                    continue;
+                }
+            }
+
+            data.clear();
+            if unpack_record_to_writer(record, self.version, self.variant, reader, &mut data, encryption_key.clone(), &*self.cipher).is_err() {
+                continue;
+            }
+
+            if is_package(&data) {
+                package_paths.append(&mut harvest_package_paths(&data));
+            }
+        }
+
+        let prefixes = content_prefixes(
+            self.index.mount_point().unwrap_or(""),
+            self.index.records().iter().map(Record::filename));
+        let candidates: Vec<String> = package_paths.iter()
+            .flat_map(|package_path| package_path_candidates(package_path, &prefixes))
+            .collect();
+
+        Ok(self.recover_names(candidates.iter().map(String::as_str)))
+    }
+
+    // Same as filtering all records with filter.visit(), but only looks at
+    // records that are actually under one of the paths of the filter.
+    pub fn filter_records(&self, filter: &mut Filter) -> Vec<&Record> {
+        let paths: Vec<String> = filter.paths().collect();
+        let mut indices = Vec::new();
+        for path in &paths {
+            self.lookup().indices_under(path, &mut indices);
+        }
+        indices.sort_unstable();
+        indices.dedup();
+
+        let records = self.index.records();
+        indices.into_iter()
+            .map(|index| &records[index])
+            .filter(|record| filter.visit(record.filename()))
+            .collect()
+    }
+
+    #[inline]
+    pub fn footer(&self) -> Option<&Footer> {
+        self.footer.as_ref()
+    }
+
+    #[inline]
+    pub fn warnings(&self) -> &Warnings {
+        &self.warnings
+    }
+
+    // Whether the compression block offsets were converted to the convention
+    // of the version when reading the package, see Options::detect_block_offsets.
+    // Inline record headers still have them the other way.
+    #[inline]
+    pub fn rebased_block_offsets(&self) -> bool {
+        self.rebased_block_offsets
+    }
+
     #[inline]
     pub fn index(&self) -> &Index {
         &self.index
     }
 
+    pub fn metadata(&self) -> PakMetadata {
+        PakMetadata {
+            version: self.version,
+            variant: self.variant,
+            index_offset: self.index_offset,
+            index_size: self.index_size,
+            index_sha1: self.index_sha1,
+            encrypted_index: self.footer.as_ref().is_some_and(Footer::encrypted_index),
+        }
+    }
+
+    // The records for tools that post-process them. The path lookup of
+    // record() and records_under() is rebuilt on next use, in case records
+    // were renamed.
+    #[inline]
+    pub fn records_mut(&mut self) -> &mut [Record] {
+        self.lookup = OnceLock::new();
+        self.index.records_mut()
+    }
+
+    // Takes the index (and with it the records) out of the package, e.g. to
+    // iterate over owned records with into_iter().
+    #[inline]
+    pub fn into_index(self) -> Index {
+        self.index
+    }
+
     //#[inline]
     //pub fn filter_records<'a>(&'a self, filter: &'a mut Filter<'a>) -> std::iter::Filter<impl Iterator<Item=&'a Record>, impl FnMut(&&'a Record) -> bool> {
     //    filter.filter(self.records.iter())
@@ -285,31 +896,88 @@ impl Pak {
 
     // FIXME: inline header has different size in some versions/variants!
     pub fn header_size(version: u32, variant: Variant, record: &Record) -> u64 {
-        match variant {
-            Variant::ConanExiles => {
-                if version != 4 {
-                    panic!("unsupported Conan Exile pak version: {}", version)
-                }
-                CONAN_EXILE_RECORD_HEADER_SIZE
-            }
-            Variant::Standard => match version {
-                1 => V1_RECORD_HEADER_SIZE,
-                2 => V2_RECORD_HEADER_SIZE,
-                _ => {
-                    let mut size: u64 = V3_RECORD_HEADER_SIZE;
+        variant.spec().header_size(version, record)
+    }
 
-                    if let Some(blocks) = &record.compression_blocks() {
-                        size += blocks.len() as u64 * COMPRESSION_BLOCK_HEADER_SIZE;
-                        if version >= 3 {
-                            size += 4;
-                        }
+    // Offset of the magic in the footer of the given version.
+    pub fn footer_magic_offset(version: u32) -> i64 {
+        let mut offset = 0;
+        if version >= 4 {
+            offset += PAK_BOOL_SIZE;
+        }
+        if version >= 7 {
+            offset += PAK_ENCRYPTION_GUID_SIZE;
+        }
+        offset as i64
+    }
+
+    // Searches the last limit bytes of the file backwards for a footer, for
+    // paks that have something else (e.g. zeros) appended. A footer is a
+    // magic followed by a supported version (or force_version) where a footer
+    // of that version fits before the end of the file and that has an index
+    // in front of it.
+    pub fn scan_footer<R>(reader: &mut R, magic: u32, force_version: Option<u32>, limit: u64) -> Result<Option<Footer>>
+    where R: Read, R: Seek {
+        const CHUNK_SIZE: u64 = 1024 * 1024;
+        let magic_bytes = magic.to_le_bytes();
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        let scan_start = file_size.saturating_sub(limit);
+
+        let mut chunk = Vec::new();
+        let mut chunk_end = file_size;
+        while chunk_end > scan_start {
+            // overlap with the previous chunk, so a magic crossing the
+            // chunk boundary is found
+            let chunk_start = chunk_end.saturating_sub(CHUNK_SIZE).max(scan_start);
+            let read_end = (chunk_end + magic_bytes.len() as u64 - 1).min(file_size);
+            chunk.resize((read_end - chunk_start) as usize, 0);
+            reader.seek(SeekFrom::Start(chunk_start))?;
+            reader.read_exact(&mut chunk)?;
+
+            for index in (0..(chunk_end - chunk_start) as usize).rev() {
+                if chunk[index..].starts_with(&magic_bytes) {
+                    let magic_offset = chunk_start + index as u64;
+                    if let Some(footer) = Self::footer_candidate(reader, magic_offset, file_size, force_version)? {
+                        debug!("Found footer of version {} at offset {}", footer.version, footer.footer_offset);
+                        return Ok(Some(footer));
                     }
-                    size
-                }
-                _ => {
-                    panic!("unsupported version: {}", version)
                 }
-            },
+            }
+
+            chunk_end = chunk_start;
+        }
+
+        Ok(None)
+    }
+
+    fn footer_candidate<R>(reader: &mut R, magic_offset: u64, file_size: u64, force_version: Option<u32>) -> Result<Option<Footer>>
+    where R: Read, R: Seek {
+        reader.seek(SeekFrom::Start(magic_offset + 4))?;
+        let mut buf = [0u8; 4];
+        if reader.read_exact(&mut buf).is_err() {
+            return Ok(None);
+        }
+        let version = match force_version {
+            Some(version) => version,
+            None => u32::from_le_bytes(buf),
+        };
+        if !(1..=PAK_MAX_SUPPORTED_VERSION).contains(&version) {
+            return Ok(None);
+        }
+
+        let footer_offset = match magic_offset.checked_sub(Self::footer_magic_offset(version) as u64) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let end = footer_offset + Self::footer_size(version) as u64;
+        if end > file_size {
+            return Ok(None);
+        }
+
+        let footer = Self::decode_footer_at(reader, end, version)?;
+        match footer.index_offset.checked_add(footer.index_size) {
+            Some(index_end) if footer.index_size > 0 && index_end <= footer_offset => Ok(Some(footer)),
+            _ => Ok(None),
         }
     }
 
@@ -347,7 +1015,7 @@ impl Pak {
         return i64::try_from(size).unwrap();
     }
 
-    pub fn get_version<R>(reader: &mut R) -> Result<u32>
+    pub fn get_version<R>(reader: &mut R, magic: u32) -> Result<u32>
     where
         R: Read,
         R: Seek,
@@ -355,8 +1023,8 @@ impl Pak {
         // Check if version >= 10 footer is found
         if reader.seek(SeekFrom::End(-Self::footer_size(10) +
                 (PAK_ENCRYPTION_GUID_SIZE + PAK_BOOL_SIZE) as i64)).is_ok() {
-            decode!(reader, magic: u32, version: u32);
-            if magic == PAK_MAGIC {
+            decode!(reader, found_magic: u32, version: u32);
+            if found_magic == magic {
                 return Ok(version);
             }
         }
@@ -364,8 +1032,8 @@ impl Pak {
         // Check if version 9 footer is found
         if reader.seek(SeekFrom::End(-Self::footer_size(9) +
                 (PAK_ENCRYPTION_GUID_SIZE + PAK_BOOL_SIZE) as i64)).is_ok() {
-            decode!(reader, magic: u32, version: u32);
-            if magic == PAK_MAGIC {
+            decode!(reader, found_magic: u32, version: u32);
+            if found_magic == magic {
                 return Ok(version);
             }
         }
@@ -373,29 +1041,29 @@ impl Pak {
         // Check if version 8 footer is found
         if reader.seek(SeekFrom::End(-Self::footer_size(8) +
                 (PAK_ENCRYPTION_GUID_SIZE + PAK_BOOL_SIZE) as i64)).is_ok() {
-            decode!(reader, magic: u32, version: u32);
-            if magic == PAK_MAGIC {
+            decode!(reader, found_magic: u32, version: u32);
+            if found_magic == magic {
                 return Ok(version);
             }
         }
 
         // Check if version <= 7 footer is found
         if reader.seek(SeekFrom::End(-Self::footer_size(7) + (PAK_BOOL_SIZE) as i64)).is_ok() {
-            decode!(reader, magic: u32, version: u32);
-            if magic == PAK_MAGIC {
+            decode!(reader, found_magic: u32, version: u32);
+            if found_magic == magic {
                 return Ok(version);
             }
         }
 
         // Check if version <= 3 footer is found
         if reader.seek(SeekFrom::End(-Self::footer_size(3) as i64)).is_ok() {
-            decode!(reader, magic: u32, version: u32);
-            if magic == PAK_MAGIC {
+            decode!(reader, found_magic: u32, version: u32);
+            if found_magic == magic {
                 return Ok(version);
             }
         }
 
-        Err(Error::new(String::from("No valid version detected")))
+        Err(Error::new(String::from("No valid version detected")).with_kind(ErrorKind::UnsupportedVersion))
     }
 
     pub fn decode_footer<R>(reader: &mut R, target_version: u32) -> Result<Footer>
@@ -403,9 +1071,23 @@ impl Pak {
         R: Read,
         R: Seek,
     {
-        let footer_offset = reader
-            .seek(SeekFrom::End(-Self::footer_size(target_version)));
-        
+        let end = reader.seek(SeekFrom::End(0))?;
+        Self::decode_footer_at(reader, end, target_version)
+    }
+
+    // Like decode_footer(), but for a footer that ends at the given offset
+    // instead of at the end of the file.
+    pub fn decode_footer_at<R>(reader: &mut R, end: u64, target_version: u32) -> Result<Footer>
+    where
+        R: Read,
+        R: Seek,
+    {
+        let footer_offset = match end.checked_sub(Self::footer_size(target_version) as u64) {
+            Some(offset) => reader.seek(SeekFrom::Start(offset)),
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position")),
+        };
+
         if let Ok(offset) = footer_offset {
             
             let encryption_uuid: u128 = 0;
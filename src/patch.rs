@@ -0,0 +1,88 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::path::Path;
+
+use crate::entry::unpack_record_to_writer;
+use crate::pack::{collect_work, pack, PackOptions, PackPath};
+use crate::pak::COMPR_NONE;
+use crate::util::{sha1_digest, Sha1Writer};
+use crate::{Error, Pak, Record, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PatchSummary {
+    // files that aren't in the base pak
+    pub added: usize,
+    // files that are in the base pak, but with different content
+    pub changed: usize,
+    // files that are the same as in the base pak and thus not in the patch
+    pub unchanged: usize,
+}
+
+fn same_content(base: &Pak, base_file: &mut File, record: &Record, file_path: &Path, encryption_key: &Option<Vec<u8>>) -> Result<bool> {
+    let metadata = match std::fs::metadata(file_path) {
+        Ok(metadata) => metadata,
+        Err(error) => return Err(Error::io_with_path(error, file_path)),
+    };
+
+    if metadata.len() != record.uncompressed_size() {
+        return Ok(false);
+    }
+
+    let file_sha1 = match File::open(file_path) {
+        Ok(file) => sha1_digest(file).map_err(|error| error.with_path_if_none(file_path))?,
+        Err(error) => return Err(Error::io_with_path(error, file_path)),
+    };
+
+    // the SHA-1 in the index is of the stored data, which only is the file
+    // content for uncompressed and unencrypted records
+    if record.compression_method() == COMPR_NONE && !record.encrypted() {
+        if let Some(sha1) = record.sha1() {
+            return Ok(sha1 == &file_sha1);
+        }
+    }
+
+    let mut writer = Sha1Writer::new();
+    unpack_record_to_writer(record, base.version(), base.variant(), base_file, &mut writer, encryption_key.clone(), base.cipher())
+        .map_err(|error| error.with_path_if_none(record.filename()))?;
+
+    Ok(writer.finish() == file_sha1)
+}
+
+// Packs only the files from paths that are not in the base pak or differ from
+// it (compared by SHA-1 of the uncompressed content). File names are matched
+// the same way as when packing, so use rename in paths to make them match the
+// names in the base pak. The encryption key is used for reading the base pak.
+pub fn patch(base: &Pak, base_file: &mut File, out_path: impl AsRef<Path>, paths: &[PackPath], encryption_key: Option<Vec<u8>>, options: PackOptions) -> Result<(Pak, PatchSummary)> {
+    let mut summary = PatchSummary::default();
+    let mut patch_paths = Vec::new();
+
+    collect_work(paths, &options, |work, _size| {
+        match base.record(&work.filename) {
+            Some(record) => {
+                if same_content(base, base_file, record, &work.file_path, &encryption_key)? {
+                    summary.unchanged += 1;
+                    return Ok(());
+                }
+                summary.changed += 1;
+            }
+            None => summary.added += 1,
+        }
+
+        patch_paths.push(PackPath {
+            filename: work.file_path.to_string_lossy().into_owned(),
+            rename: Some(work.filename),
+            ..work.path.into_owned()
+        });
+
+        Ok(())
+    })?;
+
+    let pak = pack(out_path, &patch_paths, options)?;
+
+    Ok((pak, summary))
+}
@@ -0,0 +1,282 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// File names of records that can't be written on strict file systems (NTFS,
+// FAT, exFAT and SMB shares also reject them on Linux). Names read with
+// --encoding=latin1 from packages that aren't really UTF-8 often contain C1
+// control characters, other packages contain names with ':' or '?' that were
+// created on consoles. A name is invalid if it contains control characters or
+// any of <>:"\|?*, ends in '.' or ' ' or is a reserved DOS device name like
+// CON or NUL.txt. Also paths too long for the classic Windows API.
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf, Prefix};
+
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::{Error, ErrorKind, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamePolicy {
+    // write the name as is, which fails on file systems that reject it
+    Keep,
+    // replace the offending characters with %XX of their UTF-8 bytes, and
+    // also '%' itself in such names, so the original name can be restored
+    PercentEncode,
+    // replace the offending characters with '_'
+    Replace,
+    // refuse to unpack anything if there are invalid names
+    Error,
+}
+
+impl Default for NamePolicy {
+    // Windows can't write these names anyway
+    #[inline]
+    fn default() -> Self {
+        if cfg!(target_os = "windows") {
+            NamePolicy::PercentEncode
+        } else {
+            NamePolicy::Keep
+        }
+    }
+}
+
+impl NamePolicy {
+    pub const ALL: [NamePolicy; 4] = [NamePolicy::Keep, NamePolicy::PercentEncode, NamePolicy::Replace, NamePolicy::Error];
+
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            NamePolicy::Keep          => "keep",
+            NamePolicy::PercentEncode => "percent",
+            NamePolicy::Replace       => "replace",
+            NamePolicy::Error         => "error",
+        }
+    }
+}
+
+impl TryFrom<&str> for NamePolicy {
+    type Error = Error;
+
+    fn try_from(name: &str) -> std::result::Result<Self, Error> {
+        let name = name.trim();
+        NamePolicy::ALL.iter().cloned()
+            .find(|policy| policy.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| Error::new(format!("unsupported name policy: {:?}", name)).with_kind(ErrorKind::InvalidArgument))
+    }
+}
+
+// macOS file systems compare names in a normalization insensitive way (and
+// HFS+ stores them decomposed), so names that only differ in their
+// normalization would overwrite each other.
+pub const NORMALIZE_NFC_DEFAULT: bool = cfg!(target_os = "macos");
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[inline]
+fn is_invalid_char(ch: char) -> bool {
+    ch.is_control() || matches!(ch, '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*')
+}
+
+// CON and CON.txt are reserved, but not CONSOLE
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end_matches(' ');
+    RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.chars().any(is_invalid_char) &&
+    !name.ends_with('.') && !name.ends_with(' ') &&
+    !is_reserved_name(name)
+}
+
+// Indices of the characters that have to be encoded/replaced.
+fn invalid_chars(name: &str) -> Vec<usize> {
+    let mut invalid: Vec<usize> = name.char_indices()
+        .filter(|&(_, ch)| is_invalid_char(ch))
+        .map(|(index, _)| index)
+        .collect();
+
+    // trailing dots and spaces, but not a name that is only dots ("." and
+    // ".." are handled as unsafe paths)
+    let trimmed = name.trim_end_matches(['.', ' ']);
+    if trimmed.len() < name.len() && !trimmed.is_empty() {
+        invalid.extend(trimmed.len()..name.len());
+    }
+
+    if is_reserved_name(name) {
+        invalid.push(0);
+    }
+
+    invalid.sort_unstable();
+    invalid.dedup();
+    invalid
+}
+
+// Maps a single path component according to the policy, after normalizing it
+// to NFC if requested. Names are only changed if they are invalid.
+pub fn map_name(name: &str, policy: NamePolicy, normalize_nfc: bool) -> Result<Cow<'_, str>> {
+    let name: Cow<str> = if normalize_nfc && !is_nfc(name) {
+        Cow::Owned(name.nfc().collect())
+    } else {
+        Cow::Borrowed(name)
+    };
+
+    if policy == NamePolicy::Keep || is_valid_name(&name) {
+        return Ok(name);
+    }
+
+    let invalid = invalid_chars(&name);
+    let mut mapped = String::with_capacity(name.len() + 2 * invalid.len());
+    let mut buf = [0u8; 4];
+    for (index, ch) in name.char_indices() {
+        let is_invalid = invalid.binary_search(&index).is_ok();
+        match policy {
+            NamePolicy::PercentEncode if is_invalid || ch == '%' => {
+                for byte in ch.encode_utf8(&mut buf).bytes() {
+                    let _ = write!(mapped, "%{:02X}", byte);
+                }
+            }
+            NamePolicy::Replace if is_invalid => mapped.push('_'),
+            NamePolicy::Error => {
+                return Err(Error::new(format!(
+                    "file name is invalid on some file systems (use --invalid-names to map it): {:?}", name))
+                    .with_kind(ErrorKind::UnsafePath));
+            }
+            _ => mapped.push(ch),
+        }
+    }
+
+    Ok(Cow::Owned(mapped))
+}
+
+// Windows can't create directories with paths of MAX_PATH - 12 characters or
+// more, and files with MAX_PATH (260) or more, unless the path has the \\?\
+// prefix.
+const MAX_DIR_PATH: usize = 248;
+
+// The path to use for file system operations on unpacked files. On Windows
+// long paths get the \\?\ prefix, which also turns off the interpretation of
+// "." and ".." and of '/' as separator, so they are made absolute and
+// normalized first. Other paths are returned as they are.
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    if !cfg!(target_os = "windows") || path.as_os_str().len() < MAX_DIR_PATH {
+        return Cow::Borrowed(path);
+    }
+
+    match verbatim_path(path) {
+        Some(verbatim) => Cow::Owned(verbatim),
+        None => Cow::Borrowed(path),
+    }
+}
+
+// None for paths that already are verbatim or device paths, and if the
+// current directory is unknown.
+fn verbatim_path(path: &Path) -> Option<PathBuf> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().ok()?.join(path)
+    };
+
+    let mut components = absolute.components();
+    let mut verbatim = match components.next()? {
+        Component::Prefix(prefix) => match prefix.kind() {
+            Prefix::Disk(letter) => PathBuf::from(format!("\\\\?\\{}:\\", letter as char)),
+            Prefix::UNC(server, share) => {
+                let mut prefix = OsString::from("\\\\?\\UNC\\");
+                prefix.push(server);
+                prefix.push("\\");
+                prefix.push(share);
+                prefix.push("\\");
+                PathBuf::from(prefix)
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    for component in components {
+        match component {
+            Component::Normal(name) => verbatim.push(name),
+            Component::ParentDir => { verbatim.pop(); }
+            Component::RootDir | Component::CurDir => {}
+            Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(verbatim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_names() {
+        for name in &["a.txt", "CONSOLE", "con-1.txt", "COM10", "100%", ".hidden", "ä€😀"] {
+            assert!(is_valid_name(name), "{:?} should be valid", name);
+        }
+        for name in &["a:b", "a?", "a*b", "a<b>", "a\"b", "a\\b", "a|b", "a\tb", "a\u{85}b", "a.", "a ", "CON", "aux.txt", "Nul .txt", "lpt9.tar.gz"] {
+            assert!(!is_valid_name(name), "{:?} should be invalid", name);
+        }
+    }
+
+    #[test]
+    fn map_name_keep() {
+        assert_eq!(map_name("a:b?", NamePolicy::Keep, false).unwrap(), "a:b?");
+        assert!(matches!(map_name("a.txt", NamePolicy::Keep, false).unwrap(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn map_name_percent_encode() {
+        assert_eq!(map_name("a:b?%.txt", NamePolicy::PercentEncode, false).unwrap(), "a%3Ab%3F%25.txt");
+        assert_eq!(map_name("name. .", NamePolicy::PercentEncode, false).unwrap(), "name%2E%20%2E");
+        assert_eq!(map_name("aux.txt", NamePolicy::PercentEncode, false).unwrap(), "%61ux.txt");
+        assert_eq!(map_name("a\u{85}", NamePolicy::PercentEncode, false).unwrap(), "a%C2%85");
+        // '%' is only encoded in names that are changed anyway
+        assert!(matches!(map_name("100%", NamePolicy::PercentEncode, false).unwrap(), Cow::Borrowed("100%")));
+    }
+
+    #[test]
+    fn map_name_replace() {
+        assert_eq!(map_name("a:b?%.txt", NamePolicy::Replace, false).unwrap(), "a_b_%.txt");
+        assert_eq!(map_name("name. ", NamePolicy::Replace, false).unwrap(), "name__");
+        assert_eq!(map_name("CON", NamePolicy::Replace, false).unwrap(), "_ON");
+        assert_eq!(map_name("a\u{85}b", NamePolicy::Replace, false).unwrap(), "a_b");
+    }
+
+    #[test]
+    fn map_name_error() {
+        let error = map_name("a:b", NamePolicy::Error, false).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnsafePath);
+        assert_eq!(map_name("a.txt", NamePolicy::Error, false).unwrap(), "a.txt");
+    }
+
+    #[test]
+    fn map_name_nfc() {
+        let decomposed = "a\u{308}.txt";
+        assert_eq!(map_name(decomposed, NamePolicy::Keep, false).unwrap(), decomposed);
+        assert_eq!(map_name(decomposed, NamePolicy::Keep, true).unwrap(), "\u{e4}.txt");
+        assert_eq!(map_name("a\u{308}:", NamePolicy::Replace, true).unwrap(), "\u{e4}_");
+    }
+
+    #[test]
+    fn name_policy_from_str() {
+        for &policy in &NamePolicy::ALL {
+            assert_eq!(NamePolicy::try_from(policy.name()).unwrap(), policy);
+        }
+        assert_eq!(NamePolicy::try_from(" Percent ").unwrap(), NamePolicy::PercentEncode);
+        assert!(NamePolicy::try_from("percent-encode").is_err());
+    }
+}
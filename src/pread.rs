@@ -0,0 +1,247 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// How the worker threads of check and unpack read the pak. BufReader throws
+// away its buffer on every seek, so reading the many small parts of a record
+// (inline header, compression blocks) means a lot of redundant read calls.
+// PositionalReader uses pread (or ReadFile with an offset on Windows) instead
+// and keeps its buffer across seeks, which are just position updates.
+
+use std::{convert::TryFrom, fs::File, io::{BufReader, Read, Seek, SeekFrom}, num::NonZeroUsize};
+
+use crate::{Error, ErrorKind};
+
+pub const DEFAULT_READ_SIZE: NonZeroUsize = NonZeroUsize::new(256 * 1024).unwrap();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoStrategy {
+    // seek + read through a BufReader
+    Buffered,
+    // positional reads, falls back to Buffered where not available
+    Positional,
+}
+
+impl Default for IoStrategy {
+    #[inline]
+    fn default() -> Self {
+        IoStrategy::Positional
+    }
+}
+
+impl IoStrategy {
+    pub const ALL: [IoStrategy; 2] = [IoStrategy::Buffered, IoStrategy::Positional];
+
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            IoStrategy::Buffered   => "buffered",
+            IoStrategy::Positional => "pread",
+        }
+    }
+
+    // If this strategy is actually used on this platform.
+    #[inline]
+    pub fn is_supported(self) -> bool {
+        match self {
+            IoStrategy::Buffered   => true,
+            IoStrategy::Positional => cfg!(any(unix, windows)),
+        }
+    }
+}
+
+impl TryFrom<&str> for IoStrategy {
+    type Error = crate::result::Error;
+
+    fn try_from(name: &str) -> std::result::Result<Self, Error> {
+        let name = name.trim();
+        IoStrategy::ALL.iter().cloned()
+            .find(|strategy| strategy.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| Error::new(format!("unsupported I/O strategy: {:?}", name)).with_kind(ErrorKind::InvalidArgument))
+    }
+}
+
+#[cfg(unix)]
+#[inline]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+// seek_read() moves the file cursor, but it is never used otherwise.
+#[cfg(windows)]
+#[inline]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+// Read + Seek on top of positional reads. Reads smaller than the read size
+// are served from a buffer of that size, bigger reads go directly to the file.
+#[cfg(any(unix, windows))]
+#[derive(Debug)]
+pub struct PositionalReader {
+    file: File,
+    pos: u64,
+    read_size: usize,
+    buffer: Vec<u8>,
+    // file offset of buffer[0]
+    buffer_offset: u64,
+}
+
+#[cfg(any(unix, windows))]
+impl PositionalReader {
+    #[inline]
+    pub fn new(file: File) -> Self {
+        Self::with_read_size(file, DEFAULT_READ_SIZE)
+    }
+
+    #[inline]
+    pub fn with_read_size(file: File, read_size: NonZeroUsize) -> Self {
+        Self {
+            file,
+            pos: 0,
+            read_size: read_size.get(),
+            buffer: Vec::new(),
+            buffer_offset: 0,
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    fn buffered(&self) -> &[u8] {
+        if self.pos >= self.buffer_offset {
+            let start = self.pos - self.buffer_offset;
+            if start < self.buffer.len() as u64 {
+                return &self.buffer[start as usize..];
+            }
+        }
+        &[]
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl Read for PositionalReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut data = self.buffered();
+        if data.is_empty() {
+            if buf.len() >= self.read_size {
+                let count = read_at(&self.file, buf, self.pos)?;
+                self.pos += count as u64;
+                return Ok(count);
+            }
+
+            self.buffer.resize(self.read_size, 0);
+            let count = match read_at(&self.file, &mut self.buffer, self.pos) {
+                Ok(count) => count,
+                Err(error) => {
+                    self.buffer.clear();
+                    return Err(error);
+                }
+            };
+            self.buffer.truncate(count);
+            self.buffer_offset = self.pos;
+            data = &self.buffer;
+        }
+
+        let count = data.len().min(buf.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        self.pos += count as u64;
+
+        Ok(count)
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl Seek for PositionalReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.file.metadata()?.len(), offset),
+        };
+
+        let pos = if offset < 0 {
+            base.checked_sub(offset.unsigned_abs())
+        } else {
+            base.checked_add(offset as u64)
+        };
+
+        if let Some(pos) = pos {
+            self.pos = pos;
+            Ok(pos)
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position"))
+        }
+    }
+
+    #[inline]
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.pos)
+    }
+}
+
+// The reader a worker thread uses for the given IoStrategy.
+#[derive(Debug)]
+pub enum PakReader {
+    Buffered(BufReader<File>),
+    #[cfg(any(unix, windows))]
+    Positional(PositionalReader),
+}
+
+impl PakReader {
+    pub fn new(file: File, strategy: IoStrategy, read_size: NonZeroUsize) -> Self {
+        match strategy {
+            #[cfg(any(unix, windows))]
+            IoStrategy::Positional => PakReader::Positional(PositionalReader::with_read_size(file, read_size)),
+            _ => PakReader::Buffered(BufReader::with_capacity(read_size.get(), file)),
+        }
+    }
+
+    #[inline]
+    pub fn strategy(&self) -> IoStrategy {
+        match self {
+            PakReader::Buffered(_) => IoStrategy::Buffered,
+            #[cfg(any(unix, windows))]
+            PakReader::Positional(_) => IoStrategy::Positional,
+        }
+    }
+}
+
+impl Read for PakReader {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            PakReader::Buffered(reader) => reader.read(buf),
+            #[cfg(any(unix, windows))]
+            PakReader::Positional(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for PakReader {
+    #[inline]
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            PakReader::Buffered(reader) => reader.seek(pos),
+            #[cfg(any(unix, windows))]
+            PakReader::Positional(reader) => reader.seek(pos),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::NaiveDateTime;
+
+use crate::{Error, ErrorKind, Record, Result};
+use crate::pak::{COMPR_BIAS_MEMORY, COMPR_BIAS_SPEED, COMPR_NONE, COMPR_ZLIB};
+use crate::util::parse_size;
+
+// Selects records by their metadata. Used in addition to the path based
+// Filter, i.e. a record has to match both.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Offset,
+    Size,
+    UncompressedSize,
+    CompressionMethod,
+    Timestamp,
+    Encrypted,
+}
+
+impl Field {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "offset" => Ok(Field::Offset),
+            "size" | "compressed_size" => Ok(Field::Size),
+            "uncompressed_size" | "usize" => Ok(Field::UncompressedSize),
+            "method" | "compression_method" => Ok(Field::CompressionMethod),
+            "timestamp" => Ok(Field::Timestamp),
+            "encrypted" => Ok(Field::Encrypted),
+            _ => Err(Error::new(format!("unknown record field: {:?}", name)).with_kind(ErrorKind::InvalidArgument)),
+        }
+    }
+
+    // None if the record doesn't have this field (only timestamp for now)
+    pub fn value(&self, record: &Record) -> Option<u64> {
+        match self {
+            Field::Offset            => Some(record.offset()),
+            Field::Size              => Some(record.size()),
+            Field::UncompressedSize  => Some(record.uncompressed_size()),
+            Field::CompressionMethod => Some(record.compression_method() as u64),
+            Field::Timestamp         => record.timestamp(),
+            Field::Encrypted         => Some(record.encrypted() as u64),
+        }
+    }
+
+    pub fn parse_value(&self, value: &str) -> Result<u64> {
+        let value = value.trim();
+        let parsed = match self {
+            Field::Offset | Field::Size | Field::UncompressedSize => {
+                parse_size(value).ok().map(|size| size as u64)
+            }
+            Field::CompressionMethod => {
+                match value.to_ascii_lowercase().as_str() {
+                    "none"        => Some(COMPR_NONE as u64),
+                    "zlib"        => Some(COMPR_ZLIB as u64),
+                    "bias_memory" => Some(COMPR_BIAS_MEMORY as u64),
+                    "bias_speed"  => Some(COMPR_BIAS_SPEED as u64),
+                    _ => value.parse().ok(),
+                }
+            }
+            Field::Timestamp => {
+                value.parse().ok()
+                    .or_else(|| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").ok()
+                        .or_else(|| NaiveDateTime::parse_from_str(&format!("{} 00:00:00", value), "%Y-%m-%d %H:%M:%S").ok())
+                        .map(|datetime| datetime.timestamp().max(0) as u64))
+            }
+            Field::Encrypted => {
+                match value.to_ascii_lowercase().as_str() {
+                    "true"  | "yes" | "1" => Some(1),
+                    "false" | "no"  | "0" => Some(0),
+                    _ => None,
+                }
+            }
+        };
+
+        parsed.ok_or_else(|| Error::new(format!("illegal value for {:?}: {:?}", self, value)).with_kind(ErrorKind::InvalidArgument))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    #[inline]
+    pub fn apply(&self, lhs: u64, rhs: u64) -> bool {
+        match self {
+            CompareOp::Eq => lhs == rhs,
+            CompareOp::Ne => lhs != rhs,
+            CompareOp::Lt => lhs <  rhs,
+            CompareOp::Le => lhs <= rhs,
+            CompareOp::Gt => lhs >  rhs,
+            CompareOp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    Compare(Field, CompareOp, u64),
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
+    Not(Box<Predicate>),
+}
+
+impl Predicate {
+    // Parses one condition like "method=zlib", "size>=1M" or "encrypted".
+    // Several conditions can be combined with commas, which means all of them
+    // have to match.
+    pub fn parse(expr: &str) -> Result<Self> {
+        let mut items = Vec::new();
+        for cond in expr.split(',') {
+            items.push(Self::parse_condition(cond)
+                .map_err(|error| Error::new(format!("{}: {}", expr, error.error_type())).with_kind(error.kind()))?);
+        }
+
+        if items.len() == 1 {
+            Ok(items.pop().unwrap())
+        } else {
+            Ok(Predicate::And(items))
+        }
+    }
+
+    fn parse_condition(cond: &str) -> Result<Self> {
+        let cond = cond.trim();
+        if let Some(field) = cond.strip_prefix('!') {
+            return Ok(Predicate::Compare(Field::parse(field)?, CompareOp::Eq, 0));
+        }
+
+        const OPS: &[(&str, CompareOp)] = &[
+            ("!=", CompareOp::Ne),
+            ("<=", CompareOp::Le),
+            (">=", CompareOp::Ge),
+            ("==", CompareOp::Eq),
+            ("=",  CompareOp::Eq),
+            ("<",  CompareOp::Lt),
+            (">",  CompareOp::Gt),
+        ];
+
+        for &(op_str, op) in OPS {
+            if let Some(index) = cond.find(op_str) {
+                let field = Field::parse(&cond[..index])?;
+                let value = field.parse_value(&cond[index + op_str.len()..])?;
+                return Ok(Predicate::Compare(field, op, value));
+            }
+        }
+
+        // just a field name means field != 0, e.g. "encrypted"
+        Ok(Predicate::Compare(Field::parse(cond)?, CompareOp::Ne, 0))
+    }
+
+    #[inline]
+    pub fn and(self, other: Predicate) -> Self {
+        match self {
+            Predicate::And(mut items) => {
+                items.push(other);
+                Predicate::And(items)
+            }
+            _ => Predicate::And(vec![self, other]),
+        }
+    }
+
+    #[inline]
+    pub fn or(self, other: Predicate) -> Self {
+        match self {
+            Predicate::Or(mut items) => {
+                items.push(other);
+                Predicate::Or(items)
+            }
+            _ => Predicate::Or(vec![self, other]),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    #[inline]
+    pub fn not(self) -> Self {
+        Predicate::Not(Box::new(self))
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        match self {
+            Predicate::Compare(field, op, value) => {
+                matches!(field.value(record), Some(field_value) if op.apply(field_value, *value))
+            }
+            Predicate::And(items) => items.iter().all(|item| item.matches(record)),
+            Predicate::Or(items)  => items.iter().any(|item| item.matches(record)),
+            Predicate::Not(item)  => !item.matches(record),
+        }
+    }
+}
+
+#[inline]
+pub fn matches(predicate: Option<&Predicate>, record: &Record) -> bool {
+    match predicate {
+        Some(predicate) => predicate.matches(record),
+        None => true,
+    }
+}
@@ -0,0 +1,132 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProgressInfo {
+    pub processed_files: usize,
+    pub total_files: usize,
+    pub processed_bytes: u64,
+    pub total_bytes: u64,
+    // whether total_files and total_bytes won't grow anymore
+    pub totals_final: bool,
+}
+
+impl ProgressInfo {
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.totals_final && self.processed_files >= self.total_files
+    }
+}
+
+// Called from worker threads, so implementations need to be thread safe and
+// should be cheap (or throttle themselves).
+pub trait ProgressCallback: Sync {
+    fn progress(&self, info: &ProgressInfo);
+
+    // Called instead of progress() when the file with the given path was
+    // processed, for callbacks that report file by file.
+    #[inline]
+    fn file_done(&self, _path: &str, info: &ProgressInfo) {
+        self.progress(info)
+    }
+}
+
+impl<F> ProgressCallback for F where F: Fn(&ProgressInfo) + Sync {
+    #[inline]
+    fn progress(&self, info: &ProgressInfo) {
+        self(info)
+    }
+}
+
+impl std::fmt::Debug for dyn ProgressCallback + '_ {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+pub(crate) struct Progress<'a> {
+    callback: Option<&'a dyn ProgressCallback>,
+    processed_files: AtomicUsize,
+    total_files: AtomicUsize,
+    processed_bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    totals_final: AtomicBool,
+}
+
+impl<'a> Progress<'a> {
+    pub(crate) fn new(callback: Option<&'a dyn ProgressCallback>) -> Self {
+        Self {
+            callback,
+            processed_files: AtomicUsize::new(0),
+            total_files: AtomicUsize::new(0),
+            processed_bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+            totals_final: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn info(&self) -> ProgressInfo {
+        ProgressInfo {
+            processed_files: self.processed_files.load(Ordering::Relaxed),
+            total_files: self.total_files.load(Ordering::Relaxed),
+            processed_bytes: self.processed_bytes.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            totals_final: self.totals_final.load(Ordering::Relaxed),
+        }
+    }
+
+    // The total can grow while processing is already running, e.g. while pack
+    // is still walking the input directories.
+    #[inline]
+    pub(crate) fn add_total(&self, files: usize, bytes: u64) {
+        if self.callback.is_some() {
+            self.total_files.fetch_add(files, Ordering::Relaxed);
+            self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    // Called after the last add_total(). Reports the progress again if
+    // everything was already processed, so the callback still sees is_done().
+    pub(crate) fn finish_totals(&self) {
+        if let Some(callback) = self.callback {
+            self.totals_final.store(true, Ordering::Relaxed);
+            let info = self.info();
+            if info.is_done() {
+                callback.progress(&info);
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn advance(&self, path: &str, bytes: u64) {
+        if let Some(callback) = self.callback {
+            self.processed_files.fetch_add(1, Ordering::Relaxed);
+            self.processed_bytes.fetch_add(bytes, Ordering::Relaxed);
+            callback.file_done(path, &self.info());
+        }
+    }
+
+    // For loops with lots of early exits.
+    #[inline]
+    pub(crate) fn advance_on_drop<'p>(&'p self, path: &'p str, bytes: u64) -> AdvanceOnDrop<'p, 'a> {
+        AdvanceOnDrop { progress: self, path, bytes }
+    }
+}
+
+pub(crate) struct AdvanceOnDrop<'p, 'a> {
+    progress: &'p Progress<'a>,
+    path: &'p str,
+    bytes: u64,
+}
+
+impl Drop for AdvanceOnDrop<'_, '_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.progress.advance(self.path, self.bytes);
+    }
+}
@@ -0,0 +1,433 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+use crate::{Error, ErrorKind, Result};
+
+// Something that can read arbitrary byte ranges of a file, like a HTTP server
+// that supports range requests.
+pub trait RangeTransport {
+    // total size of the file
+    fn size(&mut self) -> Result<u64>;
+
+    // reads up to buf.len() bytes starting at offset and returns the number
+    // of bytes read
+    fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+}
+
+pub const DEFAULT_RANGE_BLOCK_SIZE: usize = 256 * 1024;
+const CACHED_BLOCKS: usize = 16;
+
+// Read + Seek on top of a RangeTransport. Small reads (like those of the index
+// parser) are served from a cache of aligned blocks, reads of at least one
+// block size go directly to the transport.
+pub struct RangeReader<T: RangeTransport> {
+    transport: T,
+    size: u64,
+    pos: u64,
+    block_size: usize,
+    // (block number, data), least recently used first
+    cache: VecDeque<(u64, Vec<u8>)>,
+    request_count: usize,
+}
+
+impl<T: RangeTransport> RangeReader<T> {
+    #[inline]
+    pub fn new(transport: T) -> Result<Self> {
+        Self::with_block_size(transport, DEFAULT_RANGE_BLOCK_SIZE)
+    }
+
+    pub fn with_block_size(mut transport: T, block_size: usize) -> Result<Self> {
+        if block_size == 0 {
+            return Err(Error::new("block size must not be 0".to_string()));
+        }
+        let size = transport.size()?;
+        Ok(Self {
+            transport,
+            size,
+            pos: 0,
+            block_size,
+            cache: VecDeque::with_capacity(CACHED_BLOCKS),
+            request_count: 0,
+        })
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // number of read_range() calls so far
+    #[inline]
+    pub fn request_count(&self) -> usize {
+        self.request_count
+    }
+
+    #[inline]
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    fn read_exact_range(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let mut total = 0;
+        while total < buf.len() {
+            self.request_count += 1;
+            let count = self.transport.read_range(offset + total as u64, &mut buf[total..])?;
+            if count == 0 {
+                break;
+            }
+            total += count;
+        }
+        Ok(total)
+    }
+
+    fn block(&mut self, block: u64) -> Result<&[u8]> {
+        if let Some(index) = self.cache.iter().position(|(number, _)| *number == block) {
+            let entry = self.cache.remove(index).unwrap();
+            self.cache.push_back(entry);
+        } else {
+            let offset = block * self.block_size as u64;
+            let len = (self.size - offset).min(self.block_size as u64) as usize;
+            let mut data = vec![0u8; len];
+            let count = self.read_exact_range(offset, &mut data)?;
+            data.truncate(count);
+
+            if self.cache.len() >= CACHED_BLOCKS {
+                self.cache.pop_front();
+            }
+            self.cache.push_back((block, data));
+        }
+
+        Ok(&self.cache.back().unwrap().1)
+    }
+}
+
+impl<T: RangeTransport> Read for RangeReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+
+        let remaining = (self.size - self.pos).min(buf.len() as u64) as usize;
+        let count = if remaining >= self.block_size {
+            self.read_exact_range(self.pos, &mut buf[..remaining])
+        } else {
+            let block_size = self.block_size as u64;
+            let block_offset = (self.pos % block_size) as usize;
+            let block = self.pos / block_size;
+            self.block(block).map(|data| {
+                let data = data.get(block_offset..).unwrap_or(&[]);
+                let count = data.len().min(remaining);
+                buf[..count].copy_from_slice(&data[..count]);
+                count
+            })
+        }.map_err(|error| std::io::Error::other(error.to_string()))?;
+
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl<T: RangeTransport> Seek for RangeReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset)   => Some(offset),
+            SeekFrom::End(offset)     => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek to a negative position")),
+        }
+    }
+}
+
+const MAX_REDIRECTS: usize = 5;
+
+// Minimal HTTP/1.1 client for range requests. The connection is kept open
+// between requests if the server allows it. Only plain http:// is supported,
+// there is no TLS.
+#[derive(Debug)]
+pub struct HttpTransport {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl Clone for HttpTransport {
+    // the clone opens its own connection
+    fn clone(&self) -> Self {
+        Self {
+            url: self.url.clone(),
+            host: self.host.clone(),
+            port: self.port,
+            path: self.path.clone(),
+            connection: None,
+        }
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    content_length: Option<u64>,
+    keep_alive: bool,
+}
+
+impl HttpResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl HttpTransport {
+    pub fn new(url: &str) -> Result<Self> {
+        let rest = if let Some(rest) = url.strip_prefix("http://") {
+            rest
+        } else if url.starts_with("https://") {
+            return Err(Error::new(
+                "https:// URLs are not supported (no TLS), use a http:// URL or download the file first".to_string())
+                .with_kind(ErrorKind::InvalidArgument)
+                .with_path(url));
+        } else {
+            return Err(Error::new("not a http:// URL".to_string()).with_path(url));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(index) if !authority.ends_with(']') => {
+                let port = authority[index + 1..].parse()
+                    .map_err(|_| Error::new(format!("illegal port: {:?}", &authority[index + 1..])).with_path(url))?;
+                (&authority[..index], port)
+            }
+            _ => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(Error::new("URL has no host".to_string()).with_path(url));
+        }
+
+        Ok(Self {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            connection: None,
+        })
+    }
+
+    #[inline]
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn request(&mut self, start: u64, end: u64) -> Result<HttpResponse> {
+        // the server might have closed a kept open connection in the meantime
+        let reused = self.connection.is_some();
+        let mut result = self.send_request(start, end);
+        if result.is_err() && reused {
+            self.connection = None;
+            result = self.send_request(start, end);
+        }
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    fn send_request(&mut self, start: u64, end: u64) -> Result<HttpResponse> {
+        let url = &self.url;
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => {
+                let stream = TcpStream::connect((self.host.trim_start_matches('[').trim_end_matches(']'), self.port))
+                    .map_err(|error| Error::io_with_path(error, url))?;
+                self.connection.insert(BufReader::new(stream))
+            }
+        };
+
+        let host = if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             User-Agent: u4pak/{}\r\n\
+             Range: bytes={}-{}\r\n\
+             Connection: keep-alive\r\n\r\n",
+            self.path, host, env!("CARGO_PKG_VERSION"), start, end);
+        connection.get_mut().write_all(request.as_bytes())
+            .map_err(|error| Error::io_with_path(error, url))?;
+
+        let mut line = String::new();
+        let count = connection.read_line(&mut line)
+            .map_err(|error| Error::io_with_path(error, url))?;
+        if count == 0 {
+            return Err(Error::new("connection closed by server".to_string()).with_path(url));
+        }
+
+        let mut status_line = line.split_whitespace();
+        let http_1_0 = status_line.next() == Some("HTTP/1.0");
+        let status = status_line.next()
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| Error::new(format!("illegal HTTP status line: {:?}", line.trim_end())).with_path(url))?;
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            connection.read_line(&mut line)
+                .map_err(|error| Error::io_with_path(error, url))?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(index) = header.find(':') {
+                headers.push((header[..index].trim().to_string(), header[index + 1..].trim().to_string()));
+            }
+        }
+
+        let mut response = HttpResponse { status, headers, content_length: None, keep_alive: false };
+
+        if matches!(response.header("Transfer-Encoding"), Some(encoding) if encoding.eq_ignore_ascii_case("chunked")) {
+            return Err(Error::new("chunked transfer encoding is not supported".to_string()).with_path(url));
+        }
+
+        response.content_length = response.header("Content-Length").and_then(|length| length.trim().parse().ok());
+        // without a length the body ends when the connection is closed
+        response.keep_alive = response.content_length.is_some() && !http_1_0 &&
+            !matches!(response.header("Connection"), Some(connection) if connection.eq_ignore_ascii_case("close"));
+
+        Ok(response)
+    }
+
+    // Reads up to buf.len() bytes of the body. The connection is only kept if
+    // the whole body was read.
+    fn read_body(&mut self, response: &HttpResponse, buf: &mut [u8]) -> Result<usize> {
+        let url = &self.url;
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return Ok(0),
+        };
+        let limit = response.content_length.map_or(buf.len(), |length| buf.len().min(length as usize));
+
+        let mut total = 0;
+        while total < limit {
+            let count = match connection.read(&mut buf[total..limit]) {
+                Ok(count) => count,
+                Err(error) => {
+                    let error = Error::io_with_path(error, url);
+                    self.connection = None;
+                    return Err(error);
+                }
+            };
+            if count == 0 {
+                break;
+            }
+            total += count;
+        }
+
+        if !response.keep_alive || response.content_length != Some(total as u64) {
+            self.connection = None;
+        }
+
+        Ok(total)
+    }
+
+    fn skip_body(&mut self, response: &HttpResponse) -> Result<()> {
+        match response.content_length {
+            // small bodies like those of errors and redirects
+            Some(length) if response.keep_alive && length <= 64 * 1024 => {
+                let mut buf = vec![0; length as usize];
+                self.read_body(response, &mut buf)?;
+            }
+            _ => self.connection = None,
+        }
+        Ok(())
+    }
+
+    fn unexpected_status(&self, response: &HttpResponse) -> Error {
+        if response.status == 200 {
+            Error::new("server doesn't support range requests".to_string()).with_path(&self.url)
+        } else {
+            Error::new(format!("unexpected HTTP status: {}", response.status)).with_path(&self.url)
+        }
+    }
+}
+
+impl RangeTransport for HttpTransport {
+    fn size(&mut self) -> Result<u64> {
+        for _ in 0..MAX_REDIRECTS {
+            let response = self.request(0, 0)?;
+            self.skip_body(&response)?;
+            match response.status {
+                206 => {
+                    // Content-Range: bytes 0-0/12345
+                    return response.header("Content-Range")
+                        .and_then(|range| range.rsplit('/').next())
+                        .and_then(|size| size.trim().parse().ok())
+                        .ok_or_else(|| Error::new("missing or illegal Content-Range header".to_string()).with_path(&self.url));
+                }
+                // empty file
+                416 => return Ok(0),
+                301 | 302 | 303 | 307 | 308 => {
+                    let location = response.header("Location")
+                        .ok_or_else(|| Error::new("redirect without Location header".to_string()).with_path(&self.url))?;
+                    let location = if location.starts_with('/') {
+                        let port = if self.port == 80 { String::new() } else { format!(":{}", self.port) };
+                        format!("http://{}{}{}", self.host, port, location)
+                    } else {
+                        location.to_string()
+                    };
+                    *self = HttpTransport::new(&location)?;
+                }
+                _ => return Err(self.unexpected_status(&response)),
+            }
+        }
+
+        Err(Error::new("too many redirects".to_string()).with_path(&self.url))
+    }
+
+    fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let response = self.request(offset, offset + buf.len() as u64 - 1)?;
+        match response.status {
+            206 => self.read_body(&response, buf),
+            416 => {
+                self.skip_body(&response)?;
+                Ok(0)
+            }
+            _ => {
+                self.connection = None;
+                Err(self.unexpected_status(&response))
+            }
+        }
+    }
+}
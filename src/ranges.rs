@@ -0,0 +1,76 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Byte ranges of a package that are needed to extract some of its files. Only
+// these ranges of a remote copy have to be fetched (e.g. with HTTP range
+// requests) and written at the same offsets into a local file of the same
+// size, which can then be unpacked as usual.
+
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
+
+use crate::{Pak, Record};
+use crate::pak::Footer;
+use crate::util::align;
+
+// Regions of the index, the secondary indices and the footer, unsorted.
+pub fn index_regions(pak: &Pak) -> Vec<(u64, u64)> {
+    let encrypted = pak.footer().is_some_and(Footer::encrypted_index);
+    let index_region = |offset: u64, size: u64| {
+        let size = if encrypted { align(size, AES_BLOCK_SIZE as u64) } else { size };
+        (offset, offset + size)
+    };
+
+    let mut regions = vec![index_region(pak.index_offset(), pak.index_size())];
+    if let Some(info) = pak.secondary_index() {
+        if info.has_path_hash_index() {
+            regions.push(index_region(info.path_hash_index_offset(), info.path_hash_index_size()));
+        }
+        if info.has_full_directory_index() {
+            regions.push(index_region(info.full_directory_index_offset(), info.full_directory_index_size()));
+        }
+    }
+
+    // paks that were just written (e.g. by pack()) have no footer, then it
+    // follows the index
+    let footer_offset = pak.footer().map_or_else(
+        || regions.iter().map(|&(_, end)| end).max().unwrap_or(0),
+        Footer::footer_offset);
+    regions.push((footer_offset, footer_offset + Pak::footer_size(pak.version()) as u64));
+
+    regions
+}
+
+// Sorts the ranges (start, end) and merges the ones that overlap or touch.
+pub fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.retain(|&(start, end)| end > start);
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+// The sorted and merged ranges (start, end) of the package that are needed to
+// extract the given records: their inline headers and all of their data
+// (compression blocks). With include_index also the ranges needed to read
+// the package in the first place.
+pub fn byte_ranges<'a>(pak: &Pak, records: impl IntoIterator<Item=&'a Record>, include_index: bool) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = records.into_iter()
+        .map(|record| pak.record_region(record))
+        .collect();
+
+    if include_index {
+        ranges.extend(index_regions(pak));
+    }
+
+    merge_ranges(ranges)
+}
@@ -8,13 +8,14 @@ use std::io::{Read, Write};
 use std::fmt::Write as FmtWrite;
 use aes::BLOCK_SIZE;
 
-use crate::{Error, Result, check::NULL_SHA1, pak::{COMPR_NONE, HexDisplay, Sha1}};
+use crate::{Result, pak::{COMPR_NONE, HexDisplay, NULL_SHA1, Sha1}};
 use crate::decode;
 use crate::decode::Decode;
 use crate::encode;
 use crate::encode::Encode;
-use crate::pak::V3_RECORD_HEADER_SIZE;
+use crate::pak::{PAK_RELATIVE_COMPRESSION_OFFSET_VERSION, V3_RECORD_HEADER_SIZE, Variant};
 use crate::util::align;
+use crate::warnings::Warnings;
 
 macro_rules! cmp_record_field {
     ($buf:expr, $field:ident, $r1:expr, $r2:expr) => {
@@ -24,21 +25,30 @@ macro_rules! cmp_record_field {
     };
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Record {
+    #[cfg_attr(feature = "serde", serde(rename = "path"))]
     filename: String,
     offset: u64,
     size: u64,
     uncompressed_size: u64,
     compression_method: u32,
     timestamp: Option<u64>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::option_sha1_hex"))]
     sha1: Option<Sha1>,
     compression_blocks: Option<Vec<CompressionBlock>>,
     encrypted: bool,
+    #[cfg_attr(feature = "serde", serde(rename = "block_size"))]
     compression_block_size: u32,
+    // Conan Exiles: 32 bit field of unknown meaning at the end of the record
+    // header. Kept so it can be written back as read, always 0 otherwise.
+    #[cfg_attr(feature = "serde", serde(default))]
+    unknown: u32,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CompressionBlock {
     pub start_offset: u64,
     pub end_offset: u64,
@@ -69,6 +79,7 @@ impl Record {
             compression_blocks,
             encrypted,
             compression_block_size,
+            unknown: 0,
         }
     }
 
@@ -84,6 +95,7 @@ impl Record {
             compression_blocks: None,
             encrypted: false,
             compression_block_size: 0,
+            unknown: 0,
         }
     }
 
@@ -99,6 +111,7 @@ impl Record {
             compression_blocks: None,
             encrypted: false,
             compression_block_size: 0,
+            unknown: 0,
         }
     }
 
@@ -115,6 +128,7 @@ impl Record {
             compression_blocks,
             encrypted,
             compression_block_size,
+            unknown: 0,
         }
     }
 
@@ -168,6 +182,34 @@ impl Record {
         self.compression_block_size
     }
 
+    #[inline]
+    pub fn unknown(&self) -> u32 {
+        self.unknown
+    }
+
+    #[inline]
+    pub(crate) fn with_unknown(mut self, unknown: u32) -> Self {
+        self.unknown = unknown;
+        self
+    }
+
+    // Offset of the (compressed and/or encrypted) data in the package, i.e.
+    // the offset of the record plus the size of its inline header.
+    #[inline]
+    pub fn data_offset(&self, version: u32, variant: Variant) -> u64 {
+        self.offset + variant.spec().header_size(version, self)
+    }
+
+    // Start and end offsets of the compression blocks in the package. The
+    // stored block offsets are relative to the record since version 5 and
+    // absolute before that. The data of encrypted blocks is padded to a
+    // multiple of the AES block size, which isn't included in the end offset.
+    pub fn block_ranges(&self, version: u32) -> impl Iterator<Item=(u64, u64)> + '_ {
+        let base_offset = if version >= PAK_RELATIVE_COMPRESSION_OFFSET_VERSION { self.offset } else { 0 };
+        self.compression_blocks.iter().flatten()
+            .map(move |block| (base_offset + block.start_offset, base_offset + block.end_offset))
+    }
+
     pub fn read_v1(reader: &mut impl Read, filename: String) -> Result<Record> {
         decode!(reader,
             offset: u64,
@@ -296,7 +338,7 @@ impl Record {
         Ok(Self::new(filename, offset, size, uncompressed_size, compression_method, None, None, compression_blocks, encrypted, compression_block_size))
     }
 
-    pub fn read_conan_exiles(reader: &mut impl Read, filename: String) -> Result<Record> {
+    pub fn read_conan_exiles(reader: &mut impl Read, filename: String, warnings: &Warnings) -> Result<Record> {
         decode!(reader,
             offset: u64,
             size: u64,
@@ -312,10 +354,11 @@ impl Record {
         );
 
         if unknown != 0 {
-            eprintln!("{}: WARNING: unknown field has other value than 0: {}", filename, unknown);
+            warnings.warn(Some(&filename), format!("unknown field has other value than 0: {}", unknown));
         }
 
-        Ok(Record::v3(filename, offset, size, uncompressed_size, compression_method, Some(sha1), compression_blocks, encrypted != 0, compression_block_size))
+        Ok(Record::v3(filename, offset, size, uncompressed_size, compression_method, Some(sha1), compression_blocks, encrypted != 0, compression_block_size)
+            .with_unknown(unknown))
     }
 
     fn get_serialized_size(compression_method: u32, compression_block_count: u32) -> u64 {
@@ -405,6 +448,17 @@ impl Record {
         Ok(())
     }
 
+    // Like write_v3_inline(), but followed by the unknown 32 bit field that
+    // version 4 and later have in front of the data of compressed records.
+    // It is always written as 0.
+    pub fn write_v4_inline(&self, writer: &mut impl Write) -> Result<()> {
+        self.write_v3_inline(writer)?;
+        if self.compression_blocks.is_some() {
+            encode!(writer, 0u32);
+        }
+        Ok(())
+    }
+
     pub fn write_conan_exiles(&self, writer: &mut impl Write) -> Result<()> {
         encode!(writer,
             self.offset,
@@ -417,7 +471,7 @@ impl Record {
             }
             self.encrypted as u8,
             self.compression_block_size,
-            0u32,
+            self.unknown,
         );
         Ok(())
     }
@@ -434,8 +488,7 @@ impl Record {
             }
             self.encrypted as u8,
             self.compression_block_size,
-            // there are suppodes to be 20 more bytes of something that I don't know:
-            NULL_SHA1,
+            self.unknown,
         );
         Ok(())
     }
@@ -477,8 +530,39 @@ impl Record {
         buf
     }
 
+    // For renaming records through Pak::records_mut(), the path is relative
+    // to the mount point like filename().
+    #[inline]
+    pub fn set_filename(&mut self, filename: String) {
+        self.filename = filename;
+    }
+
+    #[inline]
+    pub(crate) fn renamed(&self, filename: String) -> Self {
+        Self { filename, ..self.clone() }
+    }
+
+    // Converts the stored compression block offsets from absolute to relative
+    // to the record at offset or the other way around. The offset is passed,
+    // because it is 0 in inline record headers. Converting to relative offsets
+    // requires all blocks to start at or after the record.
+    pub(crate) fn rebase_block_offsets(&mut self, offset: u64, to_relative: bool) {
+        if let Some(blocks) = &mut self.compression_blocks {
+            for block in blocks {
+                if to_relative {
+                    block.start_offset -= offset;
+                    block.end_offset   -= offset;
+                } else {
+                    block.start_offset += offset;
+                    block.end_offset   += offset;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "fs")]
     pub(crate) fn move_to(&mut self, version: u32, new_offset: u64) {
-        if version < 7 {
+        if version < PAK_RELATIVE_COMPRESSION_OFFSET_VERSION {
             if let Some(blocks) = &mut self.compression_blocks {
                 for block in blocks {
                     block.start_offset = (block.start_offset - self.offset) + new_offset;
@@ -0,0 +1,140 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::{Error, ErrorKind, Pak, Record, Result};
+use crate::index::{Encoding, Index};
+use crate::pack::{write_footer, write_index};
+use crate::pak::{Footer, PAK_MAGIC};
+use crate::util::{make_pak_path, parse_pak_path};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenamedRecord {
+    pub old_filename: String,
+    pub new_filename: String,
+}
+
+// New name of filename if it is old_path or inside of the directory old_path.
+fn renamed_path(filename: &str, old_path: &str, new_path: &str) -> Option<String> {
+    let rest = if old_path.is_empty() {
+        filename
+    } else if filename == old_path {
+        ""
+    } else {
+        filename.strip_prefix(old_path)?.strip_prefix('/')?
+    };
+
+    Some(if rest.is_empty() {
+        new_path.to_string()
+    } else if new_path.is_empty() {
+        rest.to_string()
+    } else {
+        format!("{}/{}", new_path, rest)
+    })
+}
+
+// Writes a new index with the given mount point and records and a new footer
+// over the index and footer of the package. Only the data before the index
+// stays as it is. The new index and footer are built before anything is
+// written, so a package that can't be rewritten (e.g. unsupported version) is
+// left as it is.
+fn rewrite_index(pak: &Pak, file: &mut File, mount_point: Option<String>, records: Vec<Record>, encoding: Encoding) -> Result<Pak> {
+    // paks that were just written (e.g. by pack()) have no footer
+    let footer = pak.footer();
+    if footer.is_some_and(Footer::encrypted_index) {
+        return Err(Error::new("rewriting a package with an encrypted index is not supported".to_string())
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+
+    if pak.index().hashed_names() {
+        return Err(Error::new("rewriting a package that only stores hashes of the file names is not supported".to_string())
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+
+    let footer_offset = footer.map_or(pak.index_offset() + pak.index_size(), Footer::footer_offset);
+    let file_size = file.seek(SeekFrom::End(0))?;
+    if footer_offset + Pak::footer_size(pak.version()) as u64 != file_size {
+        return Err(Error::new("the footer is not at the end of the file, rewriting the index would cut off the data after it".to_string())
+            .with_kind(ErrorKind::InvalidArgument));
+    }
+
+    let mut buffer = Vec::new();
+    let (index_size, index_sha1) = write_index(&mut buffer, pak.variant(), pak.version(), mount_point.as_deref().unwrap_or(""), &records, encoding)?;
+    let footer_start = buffer.len();
+    write_footer(&mut buffer, pak.version(), footer.map_or(PAK_MAGIC, Footer::magic), pak.index_offset(), index_size, &index_sha1)?;
+    if let (Some(footer), true) = (footer, pak.version() >= 7) {
+        // keep the GUID, so a keyring still finds the key for the data
+        buffer[footer_start..footer_start + 16].copy_from_slice(&footer.encryption_guid().to_le_bytes());
+    }
+
+    file.seek(SeekFrom::Start(pak.index_offset()))?;
+    file.write_all(&buffer)?;
+    file.set_len(pak.index_offset() + buffer.len() as u64)?;
+    file.flush()?;
+
+    Ok(Pak::new(
+        pak.variant(),
+        pak.version(),
+        pak.index_offset(),
+        index_size,
+        index_sha1,
+        Index::new(mount_point, records),
+    ))
+}
+
+// Renames the file old_path (or all files inside of the directory old_path)
+// to new_path by rewriting the index and footer of the package in place. File
+// names are only stored in the index, so the data is not touched at all.
+pub fn rename(pak: &Pak, file: &mut File, old_path: &str, new_path: &str, encoding: Encoding) -> Result<(Pak, Vec<RenamedRecord>)> {
+    let old_path = make_pak_path(parse_pak_path(old_path));
+    let new_path = make_pak_path(parse_pak_path(new_path));
+
+    let mut renamed = Vec::new();
+    let mut records = Vec::with_capacity(pak.index().records().len());
+    for record in pak.index().records() {
+        if let Some(new_filename) = renamed_path(record.filename(), &old_path, &new_path) {
+            if new_filename.is_empty() {
+                return Err(Error::new("new path is empty".to_string())
+                    .with_kind(ErrorKind::InvalidArgument));
+            }
+            renamed.push(RenamedRecord {
+                old_filename: record.filename().to_string(),
+                new_filename: new_filename.clone(),
+            });
+            records.push(record.renamed(new_filename));
+        } else {
+            records.push(record.clone());
+        }
+    }
+
+    if renamed.is_empty() {
+        return Err(Error::new("path not found in pak".to_string())
+            .with_kind(ErrorKind::PathNotFound)
+            .with_path(old_path));
+    }
+
+    let mut filenames = HashSet::with_capacity(records.len());
+    for record in &records {
+        if !filenames.insert(record.filename()) {
+            return Err(Error::new("a file of this name already exists in the package".to_string())
+                .with_kind(ErrorKind::InvalidArgument)
+                .with_path(record.filename()));
+        }
+    }
+
+    let renamed_pak = rewrite_index(pak, file, pak.index().mount_point().map(str::to_string), records, encoding)?;
+
+    Ok((renamed_pak, renamed))
+}
+
+// Changes the mount point of the package in place, like rename() only the
+// index and footer are rewritten.
+pub fn set_mount_point(pak: &Pak, file: &mut File, mount_point: &str, encoding: Encoding) -> Result<Pak> {
+    rewrite_index(pak, file, Some(mount_point.to_string()), pak.index().records().to_vec(), encoding)
+}
@@ -0,0 +1,82 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::{Error, Pak, Record, Result};
+use crate::index::{Encoding, Index};
+use crate::salvage::write_rebuilt_pak;
+
+#[derive(Debug)]
+pub struct RepairedRecord {
+    pub filename: String,
+    // same format as Record::metadata_diff()
+    pub diff: String,
+}
+
+// Replaces the metadata of all index records with the metadata of the inline
+// record headers and writes a copy of the package with the fixed index and a
+// new index checksum to out_path. The data region is copied as is.
+pub fn repair(pak: &Pak, in_file: &mut File, out_path: impl AsRef<Path>, encoding: Encoding) -> Result<Vec<RepairedRecord>> {
+    let version = pak.version();
+    let read_record = pak.variant().spec().inline_record_reader(version)?;
+
+    let mut repaired = Vec::new();
+    let mut records = Vec::with_capacity(pak.index().records().len());
+    let mut reader = BufReader::new(&mut *in_file);
+
+    for record in pak.index().records() {
+        if record.offset() >= pak.index_offset() {
+            return Err(Error::new(format!(
+                "record offset points into or after the index: {} >= {}",
+                record.offset(), pak.index_offset())).with_path(record.filename()));
+        }
+
+        reader.seek(SeekFrom::Start(record.offset()))?;
+        let inline_record = read_record(&mut reader, record.filename().to_string(), pak.warnings())
+            .map_err(|error| error.with_path_if_none(record.filename()))?;
+
+        let fixed_record = Record::new(
+            record.filename().to_string(),
+            // the offset field of inline records is 0
+            record.offset(),
+            inline_record.size(),
+            inline_record.uncompressed_size(),
+            inline_record.compression_method(),
+            inline_record.timestamp(),
+            *inline_record.sha1(),
+            inline_record.compression_blocks().clone(),
+            inline_record.encrypted(),
+            inline_record.compression_block_size(),
+        ).with_unknown(inline_record.unknown());
+
+        if !record.same_metadata(&fixed_record) {
+            repaired.push(RepairedRecord {
+                filename: record.filename().to_string(),
+                diff: record.metadata_diff(&fixed_record),
+            });
+        }
+
+        records.push(fixed_record);
+    }
+
+    drop(reader);
+
+    let fixed_pak = Pak::new(
+        pak.variant(),
+        version,
+        pak.index_offset(),
+        pak.index_size(),
+        *pak.index_sha1(),
+        Index::new(pak.index().mount_point().map(str::to_string), records),
+    );
+
+    write_rebuilt_pak(&fixed_pak, in_file, out_path, encoding)?;
+
+    Ok(repaired)
+}
@@ -6,6 +6,7 @@
 
 use std::{io::Write, path::{PathBuf, Path}};
 
+#[cfg(feature = "fs")]
 use crossbeam_channel::SendError;
 
 #[derive(Debug)]
@@ -32,10 +33,56 @@ impl ErrorType {
     }
 }
 
+// Coarse reason of an error, so callers can branch on it without parsing
+// messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Other,
+    Io,
+    ChannelDisconnected,
+    UnsupportedVersion,
+    InvalidMagic,
+    CorruptIndex,
+    UnknownCompressionMethod,
+    DecompressionFailed,
+    EncryptedNoKey,
+    PathNotFound,
+    UnsafePath,
+    // e.g. a command line argument that can't be parsed
+    InvalidArgument,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Other                    => "other",
+            ErrorKind::Io                       => "io",
+            ErrorKind::ChannelDisconnected      => "channel_disconnected",
+            ErrorKind::UnsupportedVersion       => "unsupported_version",
+            ErrorKind::InvalidMagic             => "invalid_magic",
+            ErrorKind::CorruptIndex             => "corrupt_index",
+            ErrorKind::UnknownCompressionMethod => "unknown_compression_method",
+            ErrorKind::DecompressionFailed      => "decompression_failed",
+            ErrorKind::EncryptedNoKey           => "encrypted_no_key",
+            ErrorKind::PathNotFound             => "path_not_found",
+            ErrorKind::UnsafePath               => "unsafe_path",
+            ErrorKind::InvalidArgument          => "invalid_argument",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
 #[derive(Debug)]
 pub struct Error {
     pub(crate) path: Option<PathBuf>,
     pub(crate) error_type: ErrorType,
+    pub(crate) kind: ErrorKind,
 }
 
 impl Error {
@@ -44,14 +91,17 @@ impl Error {
         Self {
             path: None,
             error_type: ErrorType::Message(message),
+            kind: ErrorKind::Other,
         }
     }
 
+
     #[inline]
     pub fn io(error: std::io::Error) -> Self {
         Self {
             path:       None,
             error_type: ErrorType::IO(error),
+            kind:       ErrorKind::Io,
         }
     }
 
@@ -60,6 +110,7 @@ impl Error {
         Self {
             path:       Some(path.as_ref().to_path_buf()),
             error_type: ErrorType::IO(error),
+            kind:       ErrorKind::Io,
         }
     }
 
@@ -68,6 +119,7 @@ impl Error {
         Self {
             path: None,
             error_type: ErrorType::ChannelDisconnected,
+            kind: ErrorKind::ChannelDisconnected,
         }
     }
 
@@ -76,6 +128,16 @@ impl Error {
         &self.error_type
     }
 
+    #[inline]
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    #[inline]
+    pub fn with_kind(self, kind: ErrorKind) -> Self {
+        Self { kind, ..self }
+    }
+
     #[inline]
     pub fn path(&self) -> &Option<PathBuf> {
         &self.path
@@ -85,7 +147,7 @@ impl Error {
     pub fn with_path(self, path: impl AsRef<Path>) -> Self {
         Self {
             path: Some(path.as_ref().to_path_buf()),
-            error_type: self.error_type,
+            ..self
         }
     }
 
@@ -96,7 +158,7 @@ impl Error {
         }
         Self {
             path: Some(path.as_ref().to_path_buf()),
-            error_type: self.error_type,
+            ..self
         }
     }
 
@@ -139,11 +201,21 @@ impl std::fmt::Display for Error {
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.error_type {
+            ErrorType::IO(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         Error {
             path: None,
             error_type: ErrorType::IO(error),
+            kind: ErrorKind::Io,
         }
     }
 }
@@ -166,9 +238,17 @@ impl From<std::string::FromUtf16Error> for Error {
     }
 }
 
+#[cfg(feature = "clap")]
 impl From<clap::Error> for Error {
     fn from(error: clap::Error) -> Self {
-        Error::new(error.message)
+        Error::new(error.message).with_kind(ErrorKind::InvalidArgument)
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::new(format!("JSON error: {}", error))
     }
 }
 
@@ -180,7 +260,7 @@ impl From<std::array::TryFromSliceError> for Error {
 
 impl From<std::num::ParseIntError> for Error {
     fn from(error: std::num::ParseIntError) -> Self {
-        Error::new(error.to_string())
+        Error::new(error.to_string()).with_kind(ErrorKind::InvalidArgument)
     }
 }
 
@@ -192,10 +272,11 @@ impl From<std::time::SystemTimeError> for Error {
 
 impl From<flate2::DecompressError> for Error {
     fn from(error: flate2::DecompressError) -> Self {
-        Error::new(error.to_string())
+        Error::new(error.to_string()).with_kind(ErrorKind::DecompressionFailed)
     }
 }
 
+#[cfg(feature = "fs")]
 impl<T: Sized> From<SendError<Result<T>>> for Error {
     fn from(_error: SendError<Result<T>>) -> Self {
         Error::channel_disconnected()
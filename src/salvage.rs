@@ -0,0 +1,336 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
+use openssl::sha::Sha1 as OpenSSLSha1;
+
+use crate::{Error, ErrorKind, Pak, Record, Result};
+use crate::check::NULL_SHA1;
+use crate::index::{Encoding, Index};
+use crate::pack::{write_footer, write_index};
+use crate::pak::{BUFFER_SIZE, COMPR_METHODS, COMPR_NONE, COMPRESSION_BLOCK_HEADER_SIZE, Footer, PAK_MAGIC,
+    PAK_RELATIVE_COMPRESSION_OFFSET_VERSION, Sha1, V1_RECORD_HEADER_SIZE, V2_RECORD_HEADER_SIZE, V3_RECORD_HEADER_SIZE, Variant};
+use crate::record::CompressionBlock;
+use crate::util::align;
+
+// Salvage mode scans a pak with a broken or missing index/footer for inline
+// record headers. Since inline headers don't contain filenames the found
+// records are named after their offsets.
+
+#[derive(Debug)]
+pub struct SalvageOptions {
+    pub variant: Variant,
+    pub version: u32,
+    // also accept records whose data doesn't match the SHA-1 in the header
+    pub ignore_checksums: bool,
+}
+
+impl Default for SalvageOptions {
+    fn default() -> Self {
+        Self {
+            variant: Variant::default(),
+            version: 3,
+            ignore_checksums: false,
+        }
+    }
+}
+
+// sanity limit so garbage doesn't make us read huge block tables
+const MAX_COMPRESSION_BLOCK_COUNT: u32 = 1 << 20;
+
+struct Scanner<'a> {
+    file: &'a mut File,
+    file_size: u64,
+    buffer: Vec<u8>,
+    buffer_offset: u64,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(file: &'a mut File) -> Result<Self> {
+        let file_size = file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            file_size,
+            buffer: Vec::new(),
+            buffer_offset: 0,
+        })
+    }
+
+    fn peek(&mut self, offset: u64, size: usize) -> Result<Option<&[u8]>> {
+        if offset + size as u64 > self.file_size {
+            return Ok(None);
+        }
+
+        if offset < self.buffer_offset || offset + size as u64 > self.buffer_offset + self.buffer.len() as u64 {
+            let read_size = (size.max(BUFFER_SIZE) as u64).min(self.file_size - offset) as usize;
+            self.buffer.resize(read_size, 0);
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut self.buffer)?;
+            self.buffer_offset = offset;
+        }
+
+        let start = (offset - self.buffer_offset) as usize;
+        Ok(Some(&self.buffer[start..start + size]))
+    }
+
+    fn sha1(&mut self, offset: u64, size: u64) -> Result<Sha1> {
+        let mut hasher = OpenSSLSha1::new();
+        let mut buffer = vec![0u8; BUFFER_SIZE.min(size as usize)];
+        let mut remaining = size;
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        while remaining > 0 {
+            let chunk = &mut buffer[..remaining.min(BUFFER_SIZE as u64) as usize];
+            self.file.read_exact(chunk)?;
+            hasher.update(chunk);
+            remaining -= chunk.len() as u64;
+        }
+
+        Ok(hasher.finish())
+    }
+}
+
+#[inline]
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[inline]
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// Returns the record and the offset where its data ends.
+fn parse_candidate(scanner: &mut Scanner, offset: u64, options: &SalvageOptions) -> Result<Option<(Record, u64)>> {
+    let version = options.version;
+    let spec = options.variant.spec();
+
+    // common prefix: offset, size, uncompressed_size, compression_method
+    let data = if let Some(data) = scanner.peek(offset, 28)? { data } else { return Ok(None) };
+
+    let record_offset = read_u64(data, 0);
+    let size = read_u64(data, 8);
+    let uncompressed_size = read_u64(data, 16);
+    let compression_method = read_u32(data, 24);
+
+    // inline records usually have 0 as offset, but some writers put the actual offset there
+    if record_offset != 0 && record_offset != offset {
+        return Ok(None);
+    }
+
+    if !COMPR_METHODS.contains(&compression_method) {
+        return Ok(None);
+    }
+
+    if compression_method == COMPR_NONE && size != uncompressed_size {
+        return Ok(None);
+    }
+
+    if size > scanner.file_size - offset {
+        return Ok(None);
+    }
+
+    let filename = format!("{:016x}.bin", offset);
+
+    let (record, header_size) = match version {
+        1 if spec.can_read(1) => {
+            let data = if let Some(data) = scanner.peek(offset, V1_RECORD_HEADER_SIZE as usize)? { data } else { return Ok(None) };
+            let timestamp = read_u64(data, 28);
+            let sha1: Sha1 = data[36..56].try_into().unwrap();
+            (Record::v1(filename, offset, size, uncompressed_size, compression_method, timestamp, Some(sha1)), V1_RECORD_HEADER_SIZE)
+        }
+        2 if spec.can_read(2) => {
+            let data = if let Some(data) = scanner.peek(offset, V2_RECORD_HEADER_SIZE as usize)? { data } else { return Ok(None) };
+            let sha1: Sha1 = data[28..48].try_into().unwrap();
+            (Record::v2(filename, offset, size, uncompressed_size, compression_method, Some(sha1)), V2_RECORD_HEADER_SIZE)
+        }
+        _ if version >= 3 => {
+            let data = if let Some(data) = scanner.peek(offset, V3_RECORD_HEADER_SIZE as usize)? { data } else { return Ok(None) };
+            let sha1: Sha1 = data[28..48].try_into().unwrap();
+
+            let block_count = if compression_method != COMPR_NONE {
+                let block_count = read_u32(data, 48);
+                if block_count == 0 || block_count > MAX_COMPRESSION_BLOCK_COUNT || block_count as u64 > size {
+                    return Ok(None);
+                }
+                block_count
+            } else {
+                0
+            };
+
+            let mut header_size = V3_RECORD_HEADER_SIZE;
+            if block_count > 0 {
+                header_size += 4 + block_count as u64 * COMPRESSION_BLOCK_HEADER_SIZE;
+            }
+            header_size += spec.record_trailer_size();
+
+            let data = if let Some(data) = scanner.peek(offset, header_size as usize)? { data } else { return Ok(None) };
+            let mut pos = 48;
+            let compression_blocks = if block_count > 0 {
+                pos += 4;
+                let mut blocks = Vec::with_capacity(block_count as usize);
+                for _ in 0..block_count {
+                    blocks.push(CompressionBlock {
+                        start_offset: read_u64(data, pos),
+                        end_offset: read_u64(data, pos + 8),
+                    });
+                    pos += 16;
+                }
+                Some(blocks)
+            } else {
+                None
+            };
+
+            let encrypted = data[pos];
+            if encrypted > 1 {
+                return Ok(None);
+            }
+            let compression_block_size = read_u32(data, pos + 1);
+            if spec.record_trailer_size() > 0 && read_u32(data, pos + 5) != 0 {
+                return Ok(None);
+            }
+            if compression_blocks.is_some() && compression_block_size == 0 {
+                return Ok(None);
+            }
+
+            (Record::v3(filename, offset, size, uncompressed_size, compression_method, Some(sha1),
+                compression_blocks, encrypted != 0, compression_block_size), header_size)
+        }
+        _ => {
+            return Err(Error::new(format!("unsupported version: {}", version)).with_kind(ErrorKind::UnsupportedVersion));
+        }
+    };
+
+    let data_start = offset + header_size;
+    let padded_size = if record.encrypted() {
+        align(size, AES_BLOCK_SIZE as u64)
+    } else {
+        size
+    };
+
+    let data_end = if let Some(blocks) = record.compression_blocks() {
+        let base_offset = if version >= PAK_RELATIVE_COMPRESSION_OFFSET_VERSION { offset } else { 0 };
+        let mut next_start = data_start;
+        let mut unpadded_size = 0u64;
+
+        for block in blocks {
+            if block.start_offset > block.end_offset || base_offset + block.start_offset != next_start {
+                return Ok(None);
+            }
+            let block_size = block.end_offset - block.start_offset;
+            unpadded_size += block_size;
+            next_start += if record.encrypted() {
+                align(block_size, AES_BLOCK_SIZE as u64)
+            } else {
+                block_size
+            };
+        }
+
+        let padded_blocks_size = next_start - data_start;
+        if size < unpadded_size || size > padded_blocks_size {
+            return Ok(None);
+        }
+        next_start
+    } else {
+        data_start + padded_size
+    };
+
+    if data_end > scanner.file_size {
+        return Ok(None);
+    }
+
+    let expected_sha1 = record.sha1().unwrap_or(NULL_SHA1);
+    if options.ignore_checksums {
+        // zeroed regions would otherwise yield an empty record at every byte
+        if size == 0 && expected_sha1 == NULL_SHA1 {
+            return Ok(None);
+        }
+    } else {
+        let hashed_size = if record.compression_blocks().is_some() { data_end - data_start } else { size };
+        if scanner.sha1(data_start, hashed_size)? != expected_sha1 {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some((record, data_end)))
+}
+
+// Scans in_file for plausible inline record headers and returns a pak with a
+// reconstructed index. The index offset of the pak is where the data of the
+// last found record ends.
+pub fn salvage(in_file: &mut File, options: SalvageOptions) -> Result<Pak> {
+    let mut scanner = Scanner::new(in_file)?;
+    let mut records = Vec::new();
+    let mut offset = 0u64;
+    let mut data_end = 0u64;
+
+    while offset < scanner.file_size {
+        if let Some((record, end)) = parse_candidate(&mut scanner, offset, &options)? {
+            offset = end.max(offset + 1);
+            data_end = end;
+            records.push(record);
+        } else {
+            offset += 1;
+        }
+    }
+
+    Ok(Pak::new(
+        options.variant,
+        options.version,
+        data_end,
+        0,
+        NULL_SHA1,
+        Index::new(None, records),
+    ))
+}
+
+// Copies the data region (everything before pak.index_offset()) of in_file
+// and writes a fresh index and footer for the records of pak after it.
+pub fn write_rebuilt_pak(pak: &Pak, in_file: &mut File, out_path: impl AsRef<Path>, encoding: Encoding) -> Result<Pak> {
+    let out_path = out_path.as_ref();
+    let out_file = match OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(out_path) {
+            Ok(file) => file,
+            Err(error) => return Err(Error::io_with_path(error, out_path))
+        };
+    let mut writer = BufWriter::new(out_file);
+
+    in_file.seek(SeekFrom::Start(0))?;
+    let data_size = pak.index_offset();
+    let copied = std::io::copy(&mut Read::by_ref(in_file).take(data_size), &mut writer)?;
+    if copied != data_size {
+        return Err(Error::new(format!(
+            "unexpected end of file while copying data: {} < {}",
+            copied, data_size)));
+    }
+
+    let mount_point = pak.index().mount_point().unwrap_or("");
+    let (index_size, index_sha1) = write_index(&mut writer, pak.variant(), pak.version(), mount_point, pak.index().records(), encoding)
+        .map_err(|error| error.with_path_if_none(out_path))?;
+    let magic = pak.footer().map_or(PAK_MAGIC, Footer::magic);
+    write_footer(&mut writer, pak.version(), magic, data_size, index_size, &index_sha1)
+        .map_err(|error| error.with_path_if_none(out_path))?;
+    writer.flush()?;
+
+    let records = pak.index().records().to_vec();
+
+    Ok(Pak::new(
+        pak.variant(),
+        pak.version(),
+        data_size,
+        index_size,
+        index_sha1,
+        Index::new(pak.index().mount_point().map(str::to_string), records),
+    ))
+}
@@ -0,0 +1,65 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// The order in which unpack and check queue the records for their worker
+// threads. The threads take the next record whenever they are done with one,
+// so the order decides how evenly the work ends up being distributed.
+
+use std::convert::TryFrom;
+
+use crate::{Error, ErrorKind, Record};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkOrder {
+    // the order of the index
+    Index,
+    // the order of the data in the package, which avoids seeking back and
+    // forth on HDDs and network file systems
+    Offset,
+    // biggest records (by uncompressed size) first, so that no thread is
+    // still busy with a giant file at the end while the others are idle
+    Size,
+}
+
+impl Default for WorkOrder {
+    #[inline]
+    fn default() -> Self {
+        WorkOrder::Index
+    }
+}
+
+impl WorkOrder {
+    pub const ALL: [WorkOrder; 3] = [WorkOrder::Index, WorkOrder::Offset, WorkOrder::Size];
+
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            WorkOrder::Index  => "index",
+            WorkOrder::Offset => "offset",
+            WorkOrder::Size   => "size",
+        }
+    }
+}
+
+impl TryFrom<&str> for WorkOrder {
+    type Error = Error;
+
+    fn try_from(name: &str) -> std::result::Result<Self, Error> {
+        let name = name.trim();
+        WorkOrder::ALL.iter().cloned()
+            .find(|order| order.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| Error::new(format!("unsupported work order: {:?}", name)).with_kind(ErrorKind::InvalidArgument))
+    }
+}
+
+// Stable, so records of the same size stay in index order.
+pub fn sort_records(records: &mut [&Record], order: WorkOrder) {
+    match order {
+        WorkOrder::Index  => {}
+        WorkOrder::Offset => records.sort_by_key(|record| record.offset()),
+        WorkOrder::Size   => records.sort_by_key(|record| std::cmp::Reverse(record.uncompressed_size())),
+    }
+}
@@ -0,0 +1,114 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Helpers for the serde representation of records, indices and packages
+// (the serde feature). Hashes are written as hex strings and raw bytes as
+// base64 like in the JSON files written by u4pak, so the formats agree.
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Pak;
+use crate::index::Index;
+use crate::pak::{HexDisplay, PakMetadata, Sha1};
+
+fn parse_sha1<E: serde::de::Error>(hex: &str) -> Result<Sha1, E> {
+    let mut sha1 = [0u8; 20];
+    if hex.len() != 2 * sha1.len() || !hex.is_ascii() {
+        return Err(E::custom(format!("illegal SHA-1: {:?}", hex)));
+    }
+    for (byte, pair) in sha1.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).unwrap_or("");
+        *byte = u8::from_str_radix(pair, 16)
+            .map_err(|_| E::custom(format!("illegal SHA-1: {:?}", hex)))?;
+    }
+    Ok(sha1)
+}
+
+pub mod sha1_hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(sha1: &Sha1, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&HexDisplay::new(sha1))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Sha1, D::Error> {
+        parse_sha1(&String::deserialize(deserializer)?)
+    }
+}
+
+pub mod option_sha1_hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(sha1: &Option<Sha1>, serializer: S) -> Result<S::Ok, S::Error> {
+        match sha1 {
+            Some(sha1) => serializer.collect_str(&HexDisplay::new(sha1)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Sha1>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|hex| parse_sha1(&hex))
+            .transpose()
+    }
+}
+
+pub mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(&encoded).map_err(D::Error::custom)
+    }
+}
+
+// Version of the structure of the JSON documents written by u4pak (info and
+// list with --format=json, check --report, unpack --skip-errors). It is only
+// increased when fields are removed or change their meaning. Fields may be
+// added at any time, so readers should ignore fields they don't know.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// A top level JSON document: the schema_version field followed by the fields
+// of body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Document<T> {
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+impl<T> Document<T> {
+    #[inline]
+    pub fn new(body: T) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            body,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PakRef<'a> {
+    #[serde(flatten)]
+    metadata: PakMetadata,
+    index: &'a Index,
+}
+
+// A package is written as its metadata plus its index. There is no
+// Deserialize, because a package also needs its data; deserialize
+// PakMetadata and Index separately instead.
+impl Serialize for Pak {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PakRef {
+            metadata: self.metadata(),
+            index: self.index(),
+        }.serialize(serializer)
+    }
+}
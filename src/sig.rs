@@ -0,0 +1,278 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Reading and writing of the .sig files of signed paks (Unreal Engine 4.25+).
+//
+// A .sig file contains a CRC-32 for every 64 KiB of the pak file and the
+// SHA-1 of these CRCs, encrypted with the private RSA key (PKCS#1 padding).
+// Games only need the public key to check a pak.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::Crc;
+use openssl::pkey::{Private, Public};
+use openssl::rsa::{Padding, Rsa};
+use openssl::sha::sha1;
+
+use crate::check::{CheckError, CheckErrorKind};
+use crate::decode::Decode;
+use crate::pak::HexDisplay;
+use crate::{Error, ErrorKind, Result, Warnings};
+
+pub const SIG_MAGIC: u32 = 0x73832DAA;
+pub const SIG_VERSION_INITIAL: u32 = 1;
+pub const SIG_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    version: u32,
+    encrypted_hash: Vec<u8>,
+    chunk_hashes: Vec<u32>,
+}
+
+// foo.pak -> foo.sig
+#[inline]
+pub fn signature_path(pak_path: impl AsRef<Path>) -> PathBuf {
+    pak_path.as_ref().with_extension("sig")
+}
+
+fn read_key_file(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|error| Error::io_with_path(error, path))
+}
+
+fn key_error(error: openssl::error::ErrorStack, path: &Path) -> Error {
+    Error::new(format!("illegal RSA key: {}", error)).with_path(path)
+}
+
+// Reads a PEM encoded RSA private key.
+pub fn read_private_key(path: impl AsRef<Path>) -> Result<Rsa<Private>> {
+    let path = path.as_ref();
+    Rsa::private_key_from_pem(&read_key_file(path)?).map_err(|error| key_error(error, path))
+}
+
+// Reads a PEM encoded RSA public key. The public part of a private key works,
+// too.
+pub fn read_public_key(path: impl AsRef<Path>) -> Result<Rsa<Public>> {
+    let path = path.as_ref();
+    let pem = read_key_file(path)?;
+
+    if let Ok(key) = Rsa::public_key_from_pem(&pem).or_else(|_| Rsa::public_key_from_pem_pkcs1(&pem)) {
+        return Ok(key);
+    }
+
+    let key = Rsa::private_key_from_pem(&pem).map_err(|error| key_error(error, path))?;
+    let n = key.n().to_owned().map_err(|error| key_error(error, path))?;
+    let e = key.e().to_owned().map_err(|error| key_error(error, path))?;
+    Rsa::from_public_components(n, e).map_err(|error| key_error(error, path))
+}
+
+// CRC-32 of every SIG_CHUNK_SIZE bytes
+pub fn chunk_hashes(reader: &mut impl Read) -> Result<Vec<u32>> {
+    let mut hashes = Vec::new();
+    let mut buffer = vec![0u8; SIG_CHUNK_SIZE];
+    loop {
+        let mut count = 0;
+        while count < buffer.len() {
+            let size = reader.read(&mut buffer[count..])?;
+            if size == 0 {
+                break;
+            }
+            count += size;
+        }
+        if count == 0 {
+            break;
+        }
+
+        let mut crc = Crc::new();
+        crc.update(&buffer[..count]);
+        hashes.push(crc.sum());
+
+        if count < buffer.len() {
+            break;
+        }
+    }
+    Ok(hashes)
+}
+
+// The SHA-1 of the chunk hashes, which is what is signed.
+pub fn principal_hash(chunk_hashes: &[u32]) -> [u8; 20] {
+    let bytes: Vec<u8> = chunk_hashes.iter().flat_map(|hash| hash.to_le_bytes()).collect();
+    sha1(&bytes)
+}
+
+impl Signature {
+    pub fn new(chunk_hashes: Vec<u32>, key: &Rsa<Private>) -> Result<Self> {
+        let hash = principal_hash(&chunk_hashes);
+        let mut encrypted_hash = vec![0u8; key.size() as usize];
+        let size = key.private_encrypt(&hash, &mut encrypted_hash, Padding::PKCS1)
+            .map_err(|error| Error::new(format!("signing failed: {}", error)))?;
+        encrypted_hash.truncate(size);
+
+        Ok(Self {
+            version: SIG_VERSION_INITIAL,
+            encrypted_hash,
+            chunk_hashes,
+        })
+    }
+
+    pub fn read(reader: &mut impl Read) -> Result<Self> {
+        let magic = u32::decode(reader)?;
+        if magic != SIG_MAGIC {
+            return Err(Error::new(format!("illegal signature file magic: 0x{:08X}", magic))
+                .with_kind(ErrorKind::InvalidMagic));
+        }
+
+        let version = u32::decode(reader)?;
+        if version != SIG_VERSION_INITIAL {
+            return Err(Error::new(format!("unsupported signature file version: {}", version))
+                .with_kind(ErrorKind::UnsupportedVersion));
+        }
+
+        let size = i32::decode(reader)?;
+        if size < 0 {
+            return Err(Error::new(format!("illegal encrypted hash size: {}", size)));
+        }
+        let mut encrypted_hash = vec![0u8; size as usize];
+        reader.read_exact(&mut encrypted_hash)?;
+
+        let count = i32::decode(reader)?;
+        if count < 0 {
+            return Err(Error::new(format!("illegal chunk hash count: {}", count)));
+        }
+        let mut chunk_hashes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            chunk_hashes.push(u32::decode(reader)?);
+        }
+
+        Ok(Self {
+            version,
+            encrypted_hash,
+            chunk_hashes,
+        })
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        match File::open(path) {
+            Ok(file) => Self::read(&mut BufReader::new(file)).map_err(|error| error.with_path_if_none(path)),
+            Err(error) => Err(Error::io_with_path(error, path)),
+        }
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&SIG_MAGIC.to_le_bytes())?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        writer.write_all(&(self.encrypted_hash.len() as i32).to_le_bytes())?;
+        writer.write_all(&self.encrypted_hash)?;
+        writer.write_all(&(self.chunk_hashes.len() as i32).to_le_bytes())?;
+        for hash in &self.chunk_hashes {
+            writer.write_all(&hash.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    #[inline]
+    pub fn encrypted_hash(&self) -> &[u8] {
+        &self.encrypted_hash
+    }
+
+    #[inline]
+    pub fn chunk_hashes(&self) -> &[u32] {
+        &self.chunk_hashes
+    }
+
+    // Returns the signed SHA-1, i.e. the encrypted hash decrypted with the
+    // public key.
+    pub fn decrypt_hash(&self, key: &Rsa<Public>) -> Result<Vec<u8>> {
+        let mut hash = vec![0u8; key.size() as usize];
+        let size = key.public_decrypt(&self.encrypted_hash, &mut hash, Padding::PKCS1)
+            .map_err(|error| Error::new(format!("decrypting signature failed (wrong key?): {}", error)))?;
+        hash.truncate(size);
+        Ok(hash)
+    }
+}
+
+// Creates the .sig file for the given pak and returns the signature.
+pub fn sign(pak_path: impl AsRef<Path>, key: &Rsa<Private>) -> Result<Signature> {
+    let pak_path = pak_path.as_ref();
+    let hashes = match File::open(pak_path) {
+        Ok(file) => chunk_hashes(&mut BufReader::new(file)).map_err(|error| error.with_path_if_none(pak_path))?,
+        Err(error) => return Err(Error::io_with_path(error, pak_path)),
+    };
+    let signature = Signature::new(hashes, key).map_err(|error| error.with_path_if_none(pak_path))?;
+
+    let sig_path = signature_path(pak_path);
+    let mut writer = match File::create(&sig_path) {
+        Ok(file) => BufWriter::new(file),
+        Err(error) => return Err(Error::io_with_path(error, &sig_path)),
+    };
+    signature.write(&mut writer)
+        .and_then(|_| writer.flush().map_err(Error::io))
+        .map_err(|error| error.with_path_if_none(&sig_path))?;
+
+    Ok(signature)
+}
+
+// Checks a pak against its .sig file. Found problems are also passed to
+// warnings.
+pub fn check_signature(pak_path: impl AsRef<Path>, sig_path: impl AsRef<Path>, key: &Rsa<Public>, warnings: &Warnings) -> Result<Vec<CheckError>> {
+    let pak_path = pak_path.as_ref();
+    let sig_path = sig_path.as_ref();
+    let signature = Signature::from_path(sig_path)?;
+    let mut errors = Vec::new();
+
+    let expected = principal_hash(signature.chunk_hashes());
+    let actual = signature.decrypt_hash(key).map_err(|error| error.with_path_if_none(sig_path))?;
+    if actual != expected {
+        errors.push(CheckError::new(CheckErrorKind::SignatureMismatch, Error::new(format!(
+            "signature doesn't match the chunk hashes:\n\
+             \texpected: {}\n\
+             \tactual:   {}",
+             HexDisplay::new(&expected),
+             HexDisplay::new(&actual)
+        )).with_path(sig_path)).with_values(HexDisplay::new(&expected), HexDisplay::new(&actual)));
+    }
+
+    let hashes = match File::open(pak_path) {
+        Ok(file) => chunk_hashes(&mut BufReader::new(file)).map_err(|error| error.with_path_if_none(pak_path))?,
+        Err(error) => return Err(Error::io_with_path(error, pak_path)),
+    };
+
+    if hashes.len() != signature.chunk_hashes().len() {
+        errors.push(CheckError::new(CheckErrorKind::SignatureMismatch, Error::new(format!(
+            "chunk count missmatch:\n\
+             \texpected: {}\n\
+             \tactual:   {}",
+             signature.chunk_hashes().len(), hashes.len()
+        )).with_path(pak_path)).with_values(signature.chunk_hashes().len(), hashes.len()));
+    }
+
+    for (index, (expected, actual)) in signature.chunk_hashes().iter().zip(hashes.iter()).enumerate() {
+        if expected != actual {
+            let offset = index * SIG_CHUNK_SIZE;
+            errors.push(CheckError::new(CheckErrorKind::SignatureMismatch, Error::new(format!(
+                "chunk {} ({}..{}) CRC-32 missmatch:\n\
+                 \texpected: {:08X}\n\
+                 \tactual:   {:08X}",
+                 index, offset, offset + SIG_CHUNK_SIZE, expected, actual
+            )).with_path(pak_path)).with_values(format!("{:08X}", expected), format!("{:08X}", actual)));
+        }
+    }
+
+    for error in &errors {
+        let path = error.error.path().as_ref().map(|path| path.to_string_lossy());
+        warnings.warn(path.as_deref(), error.error.error_type().to_string());
+    }
+
+    Ok(errors)
+}
@@ -0,0 +1,153 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Regions of a package that aren't covered by any record, the index(es) or
+// the footer: alignment padding, data of files that were removed from the
+// index, or junk that was appended to the file. Unlike check --gaps this
+// covers the whole file, not just the data region.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use aes::BLOCK_SIZE as AES_BLOCK_SIZE;
+
+use crate::{Error, Pak, Result};
+use crate::pak::Footer;
+use crate::util::align;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlackLocation {
+    // between the start of the file and the index
+    Data,
+    // between the index and the footer (including the secondary indices)
+    Index,
+    // after the footer
+    Appended,
+}
+
+impl SlackLocation {
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            SlackLocation::Data     => "data",
+            SlackLocation::Index    => "index",
+            SlackLocation::Appended => "appended",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slack {
+    pub offset: u64,
+    pub size: u64,
+    pub location: SlackLocation,
+    // only zero bytes, i.e. most likely padding
+    pub zeroed: bool,
+}
+
+// All regions of the file that are used by something, unsorted.
+fn used_regions(pak: &Pak, footer_offset: u64) -> Vec<(u64, u64)> {
+    let mut regions: Vec<(u64, u64)> = pak.index().records().iter()
+        .map(|record| pak.record_region(record))
+        .collect();
+
+    let encrypted = pak.footer().is_some_and(Footer::encrypted_index);
+    let index_region = |offset: u64, size: u64| {
+        let size = if encrypted { align(size, AES_BLOCK_SIZE as u64) } else { size };
+        (offset, offset + size)
+    };
+
+    regions.push(index_region(pak.index_offset(), pak.index_size()));
+    if let Some(info) = pak.index().secondary_index() {
+        if info.has_path_hash_index() {
+            regions.push(index_region(info.path_hash_index_offset(), info.path_hash_index_size()));
+        }
+        if info.has_full_directory_index() {
+            regions.push(index_region(info.full_directory_index_offset(), info.full_directory_index_size()));
+        }
+    }
+
+    regions.push((footer_offset, footer_offset + Pak::footer_size(pak.version()) as u64));
+
+    regions
+}
+
+fn is_zeroed(reader: &mut (impl Read + Seek), offset: u64, size: u64, buffer: &mut [u8]) -> Result<bool> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut remaining = size;
+    while remaining > 0 {
+        let count = remaining.min(buffer.len() as u64) as usize;
+        reader.read_exact(&mut buffer[..count])?;
+        if buffer[..count].iter().any(|&byte| byte != 0) {
+            return Ok(false);
+        }
+        remaining -= count as u64;
+    }
+    Ok(true)
+}
+
+// Finds all regions of the file that aren't used by the package, sorted by
+// offset. Regions used by more than one thing (overlapping records) are not
+// reported here, check does that.
+pub fn find_slack(pak: &Pak, reader: &mut (impl Read + Seek)) -> Result<Vec<Slack>> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    // paks that were just written (e.g. by pack()) have no footer, then it is
+    // at the end of the file
+    let footer_offset = pak.footer().map_or_else(
+        || file_size.saturating_sub(Pak::footer_size(pak.version()) as u64),
+        Footer::footer_offset);
+    let mut regions = used_regions(pak, footer_offset);
+    regions.sort_unstable();
+
+    let location = |offset: u64| if offset < pak.index_offset() {
+        SlackLocation::Data
+    } else if offset < footer_offset {
+        SlackLocation::Index
+    } else {
+        SlackLocation::Appended
+    };
+
+    let mut gaps = Vec::new();
+    let mut covered_end = 0u64;
+    for (start, end) in regions {
+        if start > covered_end {
+            gaps.push((covered_end, start.min(file_size)));
+        }
+        covered_end = covered_end.max(end);
+    }
+    if file_size > covered_end {
+        gaps.push((covered_end, file_size));
+    }
+
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut slack = Vec::with_capacity(gaps.len());
+    for (start, end) in gaps {
+        if end <= start {
+            continue;
+        }
+        slack.push(Slack {
+            offset: start,
+            size: end - start,
+            location: location(start),
+            zeroed: is_zeroed(reader, start, end - start, &mut buffer)?,
+        });
+    }
+
+    Ok(slack)
+}
+
+// Copies the bytes of a slack region to writer.
+pub fn copy_slack(reader: &mut (impl Read + Seek), slack: &Slack, writer: &mut impl Write) -> Result<()> {
+    reader.seek(SeekFrom::Start(slack.offset))?;
+    let copied = std::io::copy(&mut reader.take(slack.size), writer)?;
+    if copied != slack.size {
+        return Err(Error::new(format!(
+            "unexpected end of file while copying slack: {} < {}",
+            copied, slack.size)));
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Reading packages that aren't available as a whole seekable file: a stream
+// like stdin that can only be read front to back, and a copy of just the end
+// of a package (index and footer) that provides the index for it.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{Error, ErrorKind, Pak, Result};
+
+fn seek_error(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, message)
+}
+
+// Read + Seek for a stream that can only be read front to back, like stdin or
+// the output of a decompressor or download. Seeking forward reads and throws
+// away the skipped bytes, seeking backward or relative to the end fails.
+pub struct StreamReader<R: Read> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> StreamReader<R> {
+    #[inline]
+    pub fn new(inner: R) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    #[inline]
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl<R: Read> Seek for StreamReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta)
+                .ok_or_else(|| seek_error("invalid seek to a negative or overflowing position"))?,
+            SeekFrom::End(_) => return Err(seek_error("can't seek relative to the end of a stream")),
+        };
+
+        if target < self.pos {
+            return Err(seek_error("can't seek backward in a stream, the data was already read"));
+        }
+
+        let skip = target - self.pos;
+        let skipped = std::io::copy(&mut (&mut self.inner).take(skip), &mut std::io::sink())?;
+        self.pos += skipped;
+        if skipped < skip {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                "stream ended before the seek position"));
+        }
+
+        Ok(self.pos)
+    }
+}
+
+// Read + Seek for a file that holds the part of a bigger file starting at
+// base, i.e. offset 0 of the inner reader is at offset base. Seeking before
+// base fails.
+pub struct OffsetReader<R> {
+    inner: R,
+    base: u64,
+}
+
+impl<R: Read + Seek> OffsetReader<R> {
+    #[inline]
+    pub fn new(inner: R, base: u64) -> Self {
+        Self { inner, base }
+    }
+
+    #[inline]
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for OffsetReader<R> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read + Seek> Seek for OffsetReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(delta) => (self.inner.stream_position()? + self.base).checked_add_signed(delta)
+                .ok_or_else(|| seek_error("invalid seek to a negative or overflowing position"))?,
+            SeekFrom::End(delta) => (self.inner.seek(SeekFrom::End(0))? + self.base).checked_add_signed(delta)
+                .ok_or_else(|| seek_error("invalid seek to a negative or overflowing position"))?,
+        };
+
+        if target < self.base {
+            return Err(seek_error("can't seek before the start of the index, the file only contains the end of the package"));
+        }
+
+        Ok(self.inner.seek(SeekFrom::Start(target - self.base))? + self.base)
+    }
+}
+
+// Copies the end of the package starting at the index offset (the index, the
+// secondary indices and the footer) from reader to writer, for
+// Pak::from_index_reader(). Returns the number of bytes written.
+pub fn copy_raw_index(pak: &Pak, reader: &mut (impl Read + Seek), writer: &mut impl Write) -> Result<u64> {
+    if let Some(info) = pak.secondary_index() {
+        if (info.has_path_hash_index() && info.path_hash_index_offset() < pak.index_offset()) ||
+           (info.has_full_directory_index() && info.full_directory_index_offset() < pak.index_offset()) {
+            return Err(Error::new("the secondary indices of the package are before its index, they can't be copied along with it".to_string())
+                .with_kind(ErrorKind::InvalidArgument));
+        }
+    }
+
+    // paks that were just written (e.g. by pack()) have no footer, then it is
+    // at the end of the file
+    let end = match pak.footer() {
+        Some(footer) => footer.footer_offset() + footer.footer_size(),
+        None => reader.seek(SeekFrom::End(0))?,
+    };
+    let size = end - pak.index_offset();
+
+    reader.seek(SeekFrom::Start(pak.index_offset()))?;
+    let copied = std::io::copy(&mut reader.take(size), writer)?;
+    if copied != size {
+        return Err(Error::new(format!(
+            "unexpected end of file while copying the index: {} < {}",
+            copied, size)));
+    }
+
+    Ok(copied)
+}
@@ -0,0 +1,783 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Reading of IoStore containers, i.e. a .utoc file (the table of contents)
+// plus one or more .ucas files (the data), as used by Unreal Engine 4.25+
+// and Unreal Engine 5.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use aes::BLOCK_SIZE;
+use flate2::bufread::ZlibDecoder;
+
+use crate::decode::Decode;
+use crate::decrypt::decrypt;
+use crate::index::{read_path, Encoding, Index};
+use crate::pak::{HexDisplay, Variant, COMPR_NONE, COMPR_ZLIB, NULL_SHA1};
+use crate::util::align;
+use crate::{Error, ErrorKind, Pak, Record, Result};
+
+pub const TOC_MAGIC: &[u8; 16] = b"-==--==--==--==-";
+pub const TOC_HEADER_SIZE: u32 = 144;
+pub const TOC_CHUNK_ID_SIZE: u64 = 12;
+pub const TOC_OFFSET_LENGTH_SIZE: u64 = 10;
+pub const TOC_COMPRESSION_BLOCK_SIZE: u32 = 12;
+
+pub const TOC_VERSION_INITIAL: u8 = 1;
+pub const TOC_VERSION_DIRECTORY_INDEX: u8 = 2;
+pub const TOC_VERSION_PARTITION_SIZE: u8 = 3;
+pub const TOC_VERSION_PERFECT_HASH: u8 = 4;
+pub const TOC_VERSION_PERFECT_HASH_WITH_OVERFLOW: u8 = 5;
+pub const TOC_MAX_SUPPORTED_VERSION: u8 = TOC_VERSION_PERFECT_HASH_WITH_OVERFLOW;
+
+pub const TOC_FLAG_COMPRESSED: u8 = 1;
+pub const TOC_FLAG_ENCRYPTED : u8 = 2;
+pub const TOC_FLAG_SIGNED    : u8 = 4;
+pub const TOC_FLAG_INDEXED   : u8 = 8;
+
+// compression method of records of chunks using something other than zlib
+// (e.g. Oodle), so list and info can tell them apart
+pub const COMPR_TOC_OTHER: u32 = 0x100;
+
+const NO_ENTRY: u32 = u32::MAX;
+
+// Directory for chunks that aren't in the directory index.
+pub const UNNAMED_CHUNKS_DIR: &str = "$chunks";
+
+#[derive(Debug, Clone)]
+pub struct TocHeader {
+    version: u8,
+    header_size: u32,
+    entry_count: u32,
+    compressed_block_entry_count: u32,
+    compression_method_name_count: u32,
+    compression_method_name_length: u32,
+    compression_block_size: u32,
+    directory_index_size: u32,
+    partition_count: u32,
+    container_id: u64,
+    encryption_guid: u128,
+    flags: u8,
+    perfect_hash_seeds_count: u32,
+    partition_size: u64,
+    chunks_without_perfect_hash_count: u32,
+}
+
+impl TocHeader {
+    pub fn read(reader: &mut impl Read) -> Result<Self> {
+        let mut magic = [0u8; 16];
+        reader.read_exact(&mut magic)?;
+        if &magic != TOC_MAGIC {
+            return Err(Error::new(format!("illegal IoStore magic: {}", HexDisplay::new(&magic)))
+                .with_kind(ErrorKind::InvalidMagic));
+        }
+
+        let version = u8::decode(reader)?;
+        if !(TOC_VERSION_INITIAL..=TOC_MAX_SUPPORTED_VERSION).contains(&version) {
+            return Err(Error::new(format!("unsupported IoStore version: {}", version))
+                .with_kind(ErrorKind::UnsupportedVersion));
+        }
+        let mut reserved = [0u8; 3];
+        reader.read_exact(&mut reserved)?;
+
+        let header_size                    = u32::decode(reader)?;
+        let entry_count                    = u32::decode(reader)?;
+        let compressed_block_entry_count   = u32::decode(reader)?;
+        let compressed_block_entry_size    = u32::decode(reader)?;
+        let compression_method_name_count  = u32::decode(reader)?;
+        let compression_method_name_length = u32::decode(reader)?;
+        let compression_block_size         = u32::decode(reader)?;
+        let directory_index_size           = u32::decode(reader)?;
+        let partition_count                = u32::decode(reader)?;
+        let container_id                   = u64::decode(reader)?;
+
+        let mut guid = [0u8; 16];
+        reader.read_exact(&mut guid)?;
+        let encryption_guid = u128::from_le_bytes(guid);
+
+        let flags = u8::decode(reader)?;
+        reader.read_exact(&mut reserved)?;
+
+        let perfect_hash_seeds_count          = u32::decode(reader)?;
+        let partition_size                    = u64::decode(reader)?;
+        let chunks_without_perfect_hash_count = u32::decode(reader)?;
+
+        if header_size < TOC_HEADER_SIZE {
+            return Err(Error::new(format!("IoStore header size too small: {}", header_size))
+                .with_kind(ErrorKind::CorruptIndex));
+        }
+
+        if compressed_block_entry_size != TOC_COMPRESSION_BLOCK_SIZE {
+            return Err(Error::new(format!("unsupported IoStore compression block entry size: {}", compressed_block_entry_size))
+                .with_kind(ErrorKind::CorruptIndex));
+        }
+
+        if compression_block_size == 0 && compressed_block_entry_count > 0 {
+            return Err(Error::new("IoStore compression block size is 0".to_string())
+                .with_kind(ErrorKind::CorruptIndex));
+        }
+
+        if version >= TOC_VERSION_PARTITION_SIZE && partition_count > 0 && partition_size == 0 {
+            return Err(Error::new("IoStore partition size is 0".to_string())
+                .with_kind(ErrorKind::CorruptIndex));
+        }
+
+        // before version 3 there is exactly one unlimited partition
+        let (partition_count, partition_size) = if version < TOC_VERSION_PARTITION_SIZE || partition_count == 0 {
+            (1, u64::MAX)
+        } else {
+            (partition_count, partition_size)
+        };
+
+        Ok(Self {
+            version,
+            header_size,
+            entry_count,
+            compressed_block_entry_count,
+            compression_method_name_count,
+            compression_method_name_length,
+            compression_block_size,
+            directory_index_size,
+            partition_count,
+            container_id,
+            encryption_guid,
+            flags,
+            perfect_hash_seeds_count: if version >= TOC_VERSION_PERFECT_HASH { perfect_hash_seeds_count } else { 0 },
+            partition_size,
+            chunks_without_perfect_hash_count: if version >= TOC_VERSION_PERFECT_HASH_WITH_OVERFLOW { chunks_without_perfect_hash_count } else { 0 },
+        })
+    }
+
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    #[inline]
+    pub fn header_size(&self) -> u32 {
+        self.header_size
+    }
+
+    #[inline]
+    pub fn entry_count(&self) -> u32 {
+        self.entry_count
+    }
+
+    #[inline]
+    pub fn compressed_block_entry_count(&self) -> u32 {
+        self.compressed_block_entry_count
+    }
+
+    #[inline]
+    pub fn compression_block_size(&self) -> u32 {
+        self.compression_block_size
+    }
+
+    #[inline]
+    pub fn directory_index_size(&self) -> u32 {
+        self.directory_index_size
+    }
+
+    #[inline]
+    pub fn partition_count(&self) -> u32 {
+        self.partition_count
+    }
+
+    #[inline]
+    pub fn partition_size(&self) -> u64 {
+        self.partition_size
+    }
+
+    #[inline]
+    pub fn container_id(&self) -> u64 {
+        self.container_id
+    }
+
+    #[inline]
+    pub fn encryption_guid(&self) -> u128 {
+        self.encryption_guid
+    }
+
+    #[inline]
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    #[inline]
+    pub fn compressed(&self) -> bool {
+        self.flags & TOC_FLAG_COMPRESSED != 0
+    }
+
+    #[inline]
+    pub fn encrypted(&self) -> bool {
+        self.flags & TOC_FLAG_ENCRYPTED != 0
+    }
+
+    #[inline]
+    pub fn signed(&self) -> bool {
+        self.flags & TOC_FLAG_SIGNED != 0
+    }
+
+    #[inline]
+    pub fn indexed(&self) -> bool {
+        self.flags & TOC_FLAG_INDEXED != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TocCompressionBlock {
+    // offset in the .ucas file(s), including the partition
+    pub offset: u64,
+    pub compressed_size: u32,
+    pub uncompressed_size: u32,
+    // 0 means uncompressed, everything else is an index into
+    // Toc::compression_methods() plus 1
+    pub method_index: u8,
+}
+
+impl TocCompressionBlock {
+    pub fn read(reader: &mut impl Read) -> Result<Self> {
+        let mut buf = [0u8; TOC_COMPRESSION_BLOCK_SIZE as usize];
+        reader.read_exact(&mut buf)?;
+
+        let mut offset = [0u8; 8];
+        offset[..5].copy_from_slice(&buf[..5]);
+        let mut compressed_size = [0u8; 4];
+        compressed_size[..3].copy_from_slice(&buf[5..8]);
+        let mut uncompressed_size = [0u8; 4];
+        uncompressed_size[..3].copy_from_slice(&buf[8..11]);
+
+        Ok(Self {
+            offset: u64::from_le_bytes(offset),
+            compressed_size: u32::from_le_bytes(compressed_size),
+            uncompressed_size: u32::from_le_bytes(uncompressed_size),
+            method_index: buf[11],
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TocChunk {
+    id: u64,
+    index: u16,
+    chunk_type: u8,
+    // offset and length in the uncompressed address space
+    offset: u64,
+    length: u64,
+    // the path from the directory index or a synthetic name for chunks that
+    // aren't in the directory index (e.g. package headers in UE5)
+    filename: String,
+    indexed: bool,
+}
+
+impl TocChunk {
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[inline]
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    #[inline]
+    pub fn chunk_type(&self) -> u8 {
+        self.chunk_type
+    }
+
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    #[inline]
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    #[inline]
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    // false if the chunk has a synthetic filename
+    #[inline]
+    pub fn indexed(&self) -> bool {
+        self.indexed
+    }
+}
+
+#[derive(Debug)]
+pub struct Toc {
+    header: TocHeader,
+    mount_point: Option<String>,
+    chunks: Vec<TocChunk>,
+    compression_blocks: Vec<TocCompressionBlock>,
+    compression_methods: Vec<String>,
+    chunk_map: HashMap<String, usize>,
+    encryption_key: Option<Vec<u8>>,
+}
+
+fn read_uint40_be(buf: &[u8]) -> u64 {
+    buf[..5].iter().fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+// Indices of the compression blocks that hold offset..offset + length of the
+// uncompressed address space. Blocks are never shared between partitions, but
+// the first and last one might be shared with other chunks.
+fn block_range(offset: u64, length: u64, block_size: u64) -> Range<usize> {
+    if length == 0 || block_size == 0 {
+        return 0..0;
+    }
+    let first = offset / block_size;
+    let last = (offset + length - 1) / block_size;
+    first as usize..last as usize + 1
+}
+
+// The partition (.ucas file) index and the offset in that file of an offset
+// in the compressed address space.
+#[inline]
+fn partition_offset(offset: u64, partition_size: u64) -> (usize, u64) {
+    ((offset / partition_size) as usize, offset % partition_size)
+}
+
+fn corrupt(message: String) -> Error {
+    Error::new(message).with_kind(ErrorKind::CorruptIndex)
+}
+
+impl Toc {
+    // The encryption key is needed for encrypted directory indices and for
+    // reading the data of encrypted containers.
+    pub fn from_reader(reader: &mut (impl Read + Seek), encryption_key: Option<Vec<u8>>) -> Result<Self> {
+        let header = TocHeader::read(reader)?;
+        reader.seek(SeekFrom::Start(header.header_size as u64))?;
+
+        let entry_count = header.entry_count as usize;
+        let mut ids = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            let mut buf = [0u8; TOC_CHUNK_ID_SIZE as usize];
+            reader.read_exact(&mut buf)?;
+            let mut id = [0u8; 8];
+            id.copy_from_slice(&buf[..8]);
+            ids.push((u64::from_le_bytes(id), u16::from_le_bytes([buf[8], buf[9]]), buf[11]));
+        }
+
+        let mut offset_lengths = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            let mut buf = [0u8; TOC_OFFSET_LENGTH_SIZE as usize];
+            reader.read_exact(&mut buf)?;
+            offset_lengths.push((read_uint40_be(&buf[..5]), read_uint40_be(&buf[5..])));
+        }
+
+        // perfect hash seeds and the list of chunks without perfect hash are
+        // only needed for lookups by chunk id
+        let skip = 4 * (header.perfect_hash_seeds_count as i64 + header.chunks_without_perfect_hash_count as i64);
+        reader.seek(SeekFrom::Current(skip))?;
+
+        let mut compression_blocks = Vec::with_capacity(header.compressed_block_entry_count as usize);
+        for _ in 0..header.compressed_block_entry_count {
+            compression_blocks.push(TocCompressionBlock::read(reader)?);
+        }
+
+        let mut compression_methods = Vec::with_capacity(header.compression_method_name_count as usize);
+        let mut name = vec![0u8; header.compression_method_name_length as usize];
+        for _ in 0..header.compression_method_name_count {
+            reader.read_exact(&mut name)?;
+            let len = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+            compression_methods.push(String::from_utf8_lossy(&name[..len]).into_owned());
+        }
+
+        if header.signed() {
+            let hash_size = i32::decode(reader)?;
+            if hash_size < 0 {
+                return Err(corrupt(format!("illegal IoStore signature size: {}", hash_size)));
+            }
+            // TOC signature, block signature and one SHA-1 per compression block
+            let skip = 2 * hash_size as i64 + 20 * header.compressed_block_entry_count as i64;
+            reader.seek(SeekFrom::Current(skip))?;
+        }
+
+        let mut paths: Vec<Option<String>> = vec![None; entry_count];
+        let mut mount_point = None;
+        if header.version >= TOC_VERSION_DIRECTORY_INDEX && header.indexed() && header.directory_index_size > 0 {
+            let mut buffer = vec![0u8; header.directory_index_size as usize];
+            reader.read_exact(&mut buffer)?;
+
+            if header.encrypted() {
+                if let Some(key) = &encryption_key {
+                    if !buffer.len().is_multiple_of(BLOCK_SIZE) {
+                        return Err(corrupt("encrypted IoStore directory index size is not a multiple of 16".to_string()));
+                    }
+                    decrypt(&mut buffer, key);
+                } else {
+                    return Err(Error::new("IoStore directory index is encrypted, but no key was given".to_string())
+                        .with_kind(ErrorKind::EncryptedNoKey));
+                }
+            }
+
+            mount_point = Some(read_directory_index(&mut &buffer[..], &mut paths)?);
+        }
+
+        let mut chunks = Vec::with_capacity(entry_count);
+        let mut chunk_map = HashMap::with_capacity(entry_count);
+        for (index, ((id, chunk_index, chunk_type), (offset, length))) in ids.into_iter().zip(offset_lengths).enumerate() {
+            let (filename, indexed) = match paths[index].take() {
+                Some(path) => (path, true),
+                None => (format!("{}/{:016x}_{:04x}_{:02x}.chunk", UNNAMED_CHUNKS_DIR, id, chunk_index, chunk_type), false),
+            };
+            chunk_map.insert(filename.clone(), index);
+            chunks.push(TocChunk {
+                id,
+                index: chunk_index,
+                chunk_type,
+                offset,
+                length,
+                filename,
+                indexed,
+            });
+        }
+
+        Ok(Self {
+            header,
+            mount_point,
+            chunks,
+            compression_blocks,
+            compression_methods,
+            chunk_map,
+            encryption_key,
+        })
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn from_path(path: impl AsRef<std::path::Path>, encryption_key: Option<Vec<u8>>) -> Result<Self> {
+        let path = path.as_ref();
+        match std::fs::File::open(path) {
+            Ok(file) => Self::from_reader(&mut std::io::BufReader::new(file), encryption_key)
+                .map_err(|error| error.with_path_if_none(path)),
+            Err(error) => Err(Error::io_with_path(error, path)),
+        }
+    }
+
+    // Opens the .ucas files belonging to the given .utoc file. The first
+    // partition is "name.ucas", all further are "name_s1.ucas" etc.
+    #[cfg(feature = "fs")]
+    pub fn open_partitions(&self, utoc_path: impl AsRef<std::path::Path>) -> Result<Vec<std::fs::File>> {
+        let utoc_path = utoc_path.as_ref();
+        let mut partitions = Vec::with_capacity(self.header.partition_count as usize);
+        for partition in 0..self.header.partition_count {
+            let path = if partition == 0 {
+                utoc_path.with_extension("ucas")
+            } else {
+                let stem = utoc_path.file_stem().unwrap_or_default().to_string_lossy();
+                utoc_path.with_file_name(format!("{}_s{}.ucas", stem, partition))
+            };
+            match std::fs::File::open(&path) {
+                Ok(file) => partitions.push(file),
+                Err(error) => return Err(Error::io_with_path(error, path)),
+            }
+        }
+        Ok(partitions)
+    }
+
+    #[inline]
+    pub fn header(&self) -> &TocHeader {
+        &self.header
+    }
+
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.header.version
+    }
+
+    #[inline]
+    pub fn mount_point(&self) -> Option<&str> {
+        self.mount_point.as_deref()
+    }
+
+    #[inline]
+    pub fn chunks(&self) -> &[TocChunk] {
+        &self.chunks
+    }
+
+    #[inline]
+    pub fn compression_blocks(&self) -> &[TocCompressionBlock] {
+        &self.compression_blocks
+    }
+
+    #[inline]
+    pub fn compression_methods(&self) -> &[String] {
+        &self.compression_methods
+    }
+
+    pub fn chunk(&self, filename: &str) -> Option<&TocChunk> {
+        self.chunk_map.get(filename).map(|&index| &self.chunks[index])
+    }
+
+    fn chunk_blocks(&self, chunk: &TocChunk) -> &[TocCompressionBlock] {
+        let range = block_range(chunk.offset, chunk.length, self.header.compression_block_size as u64);
+        let end = range.end.min(self.compression_blocks.len());
+        &self.compression_blocks[range.start.min(end)..end]
+    }
+
+    fn compression_method(&self, block: &TocCompressionBlock) -> u32 {
+        if block.method_index == 0 {
+            return COMPR_NONE;
+        }
+        match self.compression_methods.get(block.method_index as usize - 1) {
+            Some(name) if name.eq_ignore_ascii_case("zlib") => COMPR_ZLIB,
+            _ => COMPR_TOC_OTHER,
+        }
+    }
+
+    // Converts the chunks to records, so the list and info code works with
+    // IoStore containers, too. Record offsets are the .ucas offsets of the
+    // first compression block and sizes are the summed up compressed sizes.
+    pub fn to_pak(&self) -> Pak {
+        let encrypted = self.header.encrypted();
+        let records = self.chunks.iter().map(|chunk| {
+            let blocks = self.chunk_blocks(chunk);
+            let offset = blocks.first().map_or(0, |block| block.offset);
+            let size = blocks.iter().map(|block| if encrypted {
+                align(block.compressed_size as u64, BLOCK_SIZE as u64)
+            } else {
+                block.compressed_size as u64
+            }).sum();
+            let compression_method = blocks.iter()
+                .map(|block| self.compression_method(block))
+                .find(|&method| method != COMPR_NONE)
+                .unwrap_or(COMPR_NONE);
+
+            Record::v3(chunk.filename.clone(), offset, size, chunk.length, compression_method, None,
+                None, encrypted, self.header.compression_block_size)
+        }).collect();
+
+        Pak::new(Variant::Standard, 3, 0, 0, NULL_SHA1, Index::new(self.mount_point.clone(), records))
+    }
+
+    // Writes the uncompressed (and decrypted) data of a chunk to writer. The
+    // partitions are the readers of the .ucas files, see open_partitions().
+    pub fn read_chunk(&self, chunk: &TocChunk, partitions: &mut [impl Read + Seek], writer: &mut impl Write) -> Result<()> {
+        let encrypted = self.header.encrypted();
+        let encryption_key = if encrypted {
+            match &self.encryption_key {
+                Some(key) => Some(key),
+                None => return Err(Error::new("IoStore container is encrypted, but no key was given".to_string())
+                    .with_kind(ErrorKind::EncryptedNoKey)
+                    .with_path(&chunk.filename)),
+            }
+        } else {
+            None
+        };
+
+        let block_size = self.header.compression_block_size as u64;
+        let blocks = self.chunk_blocks(chunk);
+        let first_block = chunk.offset.checked_div(block_size).unwrap_or(0);
+        let chunk_end = chunk.offset + chunk.length;
+
+        let mut in_buffer = Vec::new();
+        let mut out_buffer = Vec::with_capacity(block_size as usize);
+        let mut remaining = chunk.length;
+        for (index, block) in blocks.iter().enumerate() {
+            let (partition, partition_offset) = partition_offset(block.offset, self.header.partition_size);
+            let reader = match partitions.get_mut(partition) {
+                Some(reader) => reader,
+                None => return Err(corrupt(format!("compression block refers to missing partition {}", partition))
+                    .with_path(&chunk.filename)),
+            };
+
+            let read_size = if encrypted {
+                align(block.compressed_size as u64, BLOCK_SIZE as u64)
+            } else {
+                block.compressed_size as u64
+            };
+            in_buffer.resize(read_size as usize, 0);
+            reader.seek(SeekFrom::Start(partition_offset))?;
+            reader.read_exact(&mut in_buffer)?;
+
+            if let Some(key) = encryption_key {
+                decrypt(&mut in_buffer, key);
+            }
+            in_buffer.truncate(block.compressed_size as usize);
+
+            let data = match self.compression_method(block) {
+                COMPR_NONE => &in_buffer[..],
+                COMPR_ZLIB => {
+                    out_buffer.clear();
+                    ZlibDecoder::new(&in_buffer[..]).read_to_end(&mut out_buffer).map_err(|error|
+                        Error::new(format!("decompression failed: {}", error))
+                            .with_kind(ErrorKind::DecompressionFailed)
+                            .with_path(&chunk.filename))?;
+                    &out_buffer[..]
+                }
+                _ => {
+                    return Err(match self.compression_methods.get(block.method_index as usize - 1) {
+                        Some(name) => Error::new(format!("unsupported compression method: {}", name))
+                            .with_kind(ErrorKind::UnknownCompressionMethod),
+                        None => corrupt(format!("compression block refers to missing compression method {}", block.method_index)),
+                    }.with_path(&chunk.filename));
+                }
+            };
+
+            // the first and last block might be shared with other chunks
+            let block_start = (first_block + index as u64) * block_size;
+            let start = chunk.offset.saturating_sub(block_start) as usize;
+            let end = (chunk_end - block_start).min(data.len() as u64) as usize;
+            if start > end {
+                return Err(corrupt("compression block is too small".to_string()).with_path(&chunk.filename));
+            }
+            writer.write_all(&data[start..end])?;
+            remaining -= (end - start) as u64;
+        }
+
+        if remaining != 0 {
+            return Err(corrupt(format!("chunk data is {} bytes short", remaining)).with_path(&chunk.filename));
+        }
+
+        Ok(())
+    }
+}
+
+// Returns the mount point and fills in the paths of the chunks referenced by
+// the directory index.
+fn read_directory_index(reader: &mut impl Read, paths: &mut [Option<String>]) -> Result<String> {
+    let mount_point = read_path(reader, Encoding::UTF8)?;
+
+    let dir_count = u32::decode(reader)?;
+    let mut dirs = Vec::with_capacity(dir_count as usize);
+    for _ in 0..dir_count {
+        let name         = u32::decode(reader)?;
+        let first_child  = u32::decode(reader)?;
+        let next_sibling = u32::decode(reader)?;
+        let first_file   = u32::decode(reader)?;
+        dirs.push((name, first_child, next_sibling, first_file));
+    }
+
+    let file_count = u32::decode(reader)?;
+    let mut files = Vec::with_capacity(file_count as usize);
+    for _ in 0..file_count {
+        let name      = u32::decode(reader)?;
+        let next_file = u32::decode(reader)?;
+        let user_data = u32::decode(reader)?;
+        files.push((name, next_file, user_data));
+    }
+
+    let string_count = u32::decode(reader)?;
+    let mut strings = Vec::with_capacity(string_count as usize);
+    for _ in 0..string_count {
+        strings.push(read_path(reader, Encoding::UTF8)?);
+    }
+
+    let string = |index: u32| strings.get(index as usize)
+        .ok_or_else(|| corrupt(format!("illegal string index in IoStore directory index: {}", index)));
+
+    if dirs.is_empty() {
+        return Ok(mount_point);
+    }
+
+    // (directory index, path of the directory)
+    let mut stack = vec![(0u32, String::new())];
+    let mut visited = vec![false; dirs.len()];
+    while let Some((dir_index, parent_path)) = stack.pop() {
+        let dir = match dirs.get(dir_index as usize) {
+            Some(dir) => *dir,
+            None => return Err(corrupt(format!("illegal directory index in IoStore directory index: {}", dir_index))),
+        };
+        if std::mem::replace(&mut visited[dir_index as usize], true) {
+            return Err(corrupt("cycle in IoStore directory index".to_string()));
+        }
+        let (name, first_child, next_sibling, first_file) = dir;
+        let dir_path = if name == NO_ENTRY {
+            parent_path.clone()
+        } else if parent_path.is_empty() {
+            string(name)?.clone()
+        } else {
+            format!("{}/{}", parent_path, string(name)?)
+        };
+
+        let mut file_index = first_file;
+        let mut file_count = 0;
+        while file_index != NO_ENTRY {
+            let (name, next_file, user_data) = match files.get(file_index as usize) {
+                Some(file) => *file,
+                None => return Err(corrupt(format!("illegal file index in IoStore directory index: {}", file_index))),
+            };
+            file_count += 1;
+            if file_count > files.len() {
+                return Err(corrupt("cycle in IoStore directory index".to_string()));
+            }
+            let path = if dir_path.is_empty() {
+                string(name)?.clone()
+            } else {
+                format!("{}/{}", dir_path, string(name)?)
+            };
+            match paths.get_mut(user_data as usize) {
+                Some(slot) => *slot = Some(path),
+                None => return Err(corrupt(format!("illegal chunk index in IoStore directory index: {}", user_data))),
+            }
+            file_index = next_file;
+        }
+
+        if next_sibling != NO_ENTRY {
+            stack.push((next_sibling, parent_path));
+        }
+
+        if first_child != NO_ENTRY {
+            stack.push((first_child, dir_path));
+        }
+    }
+
+    Ok(mount_point)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_ranges() {
+        assert_eq!(block_range(0, 0, 0x10000), 0..0);
+        assert_eq!(block_range(0, 1, 0), 0..0);
+        assert_eq!(block_range(0, 1, 0x10000), 0..1);
+        assert_eq!(block_range(0, 0x10000, 0x10000), 0..1);
+        assert_eq!(block_range(0, 0x10001, 0x10000), 0..2);
+        assert_eq!(block_range(0xFFFF, 2, 0x10000), 0..2);
+        assert_eq!(block_range(0x30000, 5, 0x10000), 3..4);
+    }
+
+    #[test]
+    fn partition_offsets() {
+        assert_eq!(partition_offset(0, u64::MAX), (0, 0));
+        assert_eq!(partition_offset(12345, u64::MAX), (0, 12345));
+        assert_eq!(partition_offset(0x1000, 0x1000), (1, 0));
+        assert_eq!(partition_offset(0x2FFF, 0x1000), (2, 0xFFF));
+    }
+
+    #[test]
+    fn compression_block_entry() {
+        let entry = [
+            0x05, 0x04, 0x03, 0x02, 0x01, // offset
+            0x03, 0x02, 0x01,             // compressed size
+            0x00, 0x00, 0x01,             // uncompressed size
+            0x01,                         // method index
+        ];
+        assert_eq!(TocCompressionBlock::read(&mut &entry[..]).unwrap(), TocCompressionBlock {
+            offset: 0x01_0203_0405,
+            compressed_size: 0x01_0203,
+            uncompressed_size: 0x01_0000,
+            method_index: 1,
+        });
+        assert!(TocCompressionBlock::read(&mut &entry[..11]).is_err());
+    }
+
+    #[test]
+    fn uint40_be() {
+        assert_eq!(read_uint40_be(&[0, 0, 0, 0, 0]), 0);
+        assert_eq!(read_uint40_be(&[0x01, 0x02, 0x03, 0x04, 0x05, 0xFF]), 0x01_0203_0405);
+    }
+}
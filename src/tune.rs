@@ -0,0 +1,114 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Picking the thread count and read size for unpack and check by the storage
+// the package is on. A few small reads spread over the file tell apart an SSD
+// (no seek time), an HDD (several milliseconds per seek) and a network mount
+// (long round trips). This is only a heuristic: files that are in the page
+// cache look like they are on an SSD, which doesn't hurt because they are
+// read from memory then.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::pread::DEFAULT_READ_SIZE;
+use crate::{Error, Result};
+
+const PROBE_COUNT: u64 = 16;
+const PROBE_SIZE: usize = 4096;
+
+const HDD_LATENCY: Duration = Duration::from_millis(1);
+const NETWORK_LATENCY: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Ssd,
+    Hdd,
+    Network,
+}
+
+impl StorageKind {
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            StorageKind::Ssd     => "SSD",
+            StorageKind::Hdd     => "HDD",
+            StorageKind::Network => "network",
+        }
+    }
+
+    pub fn from_latency(latency: Duration) -> Self {
+        if latency < HDD_LATENCY {
+            StorageKind::Ssd
+        } else if latency < NETWORK_LATENCY {
+            StorageKind::Hdd
+        } else {
+            StorageKind::Network
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    pub storage: StorageKind,
+    pub thread_count: NonZeroUsize,
+    pub read_size: NonZeroUsize,
+}
+
+impl Tuning {
+    // SSDs keep up with all cores. HDDs get slower with every additional
+    // thread seeking around, so few threads doing big reads. Network mounts
+    // are bound by latency, so more threads than cores keep more requests in
+    // flight.
+    pub fn for_storage(storage: StorageKind, cpu_count: usize) -> Self {
+        let cpu_count = cpu_count.max(1);
+        let (thread_count, read_size) = match storage {
+            StorageKind::Ssd     => (cpu_count, DEFAULT_READ_SIZE.get()),
+            StorageKind::Hdd     => (cpu_count.min(2), 4 * 1024 * 1024),
+            StorageKind::Network => ((cpu_count * 2).clamp(4, 32), 1024 * 1024),
+        };
+
+        Self {
+            storage,
+            thread_count: NonZeroUsize::new(thread_count).unwrap(),
+            read_size: NonZeroUsize::new(read_size).unwrap(),
+        }
+    }
+}
+
+// Median time of small reads at offsets spread over the whole file.
+pub fn probe_latency(file: &mut File) -> std::io::Result<Duration> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    let stride = (file_size / PROBE_COUNT).max(1);
+    let mut buffer = [0u8; PROBE_SIZE];
+    let mut latencies = Vec::with_capacity(PROBE_COUNT as usize);
+
+    // backwards, so read-ahead of one probe doesn't serve the next one
+    for index in (0..PROBE_COUNT).rev() {
+        let offset = index * stride;
+        if offset >= file_size {
+            continue;
+        }
+        let start = Instant::now();
+        file.seek(SeekFrom::Start(offset))?;
+        let _ = file.read(&mut buffer)?;
+        latencies.push(start.elapsed());
+    }
+
+    latencies.sort_unstable();
+    Ok(latencies.get(latencies.len() / 2).cloned().unwrap_or_default())
+}
+
+pub fn tune(path: impl AsRef<Path>) -> Result<Tuning> {
+    let path = path.as_ref();
+    let mut file = File::open(path).map_err(|error| Error::io_with_path(error, path))?;
+    let latency = probe_latency(&mut file).map_err(|error| Error::io_with_path(error, path))?;
+
+    Ok(Tuning::for_storage(StorageKind::from_latency(latency), num_cpus::get()))
+}
@@ -4,22 +4,50 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{fs::OpenOptions, io::{BufWriter, Read, Seek, SeekFrom, Write}, num::NonZeroUsize, path::{Path, PathBuf}};
+use std::{collections::{HashMap, HashSet}, fs::OpenOptions, io::{BufWriter, Read, Seek, Write}, num::{NonZeroU64, NonZeroUsize}, path::{Component, Path, PathBuf}, time::{Duration, UNIX_EPOCH}};
 use std::fs::File;
+use std::sync::Mutex;
 
-use crossbeam_channel::{Receiver, Sender, unbounded};
+use crossbeam_channel::{Receiver, Sender, bounded, unbounded};
 use crossbeam_utils::thread;
-use flate2::bufread::ZlibDecoder;
-use aes::BLOCK_SIZE;
+use log::debug;
 
-use crate::util::align;
-use crate::decrypt::decrypt;
+use crate::decrypt::Cipher;
+use crate::hash::{DigestAlgorithm, DigestWriter, TeeDigestWriter};
+use crate::pak::HexDisplay;
+use crate::util::{sha1_digest, Sha1Writer};
 
-use crate::{Error, Result, Pak, pak::{self, COMPR_NONE, PAK_RELATIVE_COMPRESSION_OFFSET_VERSION, Variant, compression_method_name}, util::parse_pak_path};
+use crate::{Error, ErrorKind, Result, Pak, pak::{COMPR_NONE, Variant}, util::{make_pak_path, parse_pak_path}};
 use crate::Record;
+use crate::budget::MemoryBudget;
 use crate::Filter;
+use crate::portable::{is_valid_name, long_path, map_name, NamePolicy, NORMALIZE_NFC_DEFAULT};
+use crate::predicate::{self, Predicate};
+use crate::pread::{IoStrategy, PakReader, DEFAULT_READ_SIZE};
+use crate::progress::{Progress, ProgressCallback};
 use crate::reopen::Reopen;
-use log::{debug};
+use crate::schedule::{sort_records, WorkOrder};
+use crate::stream::StreamReader;
+use crate::toc::{Toc, TocChunk};
+use crate::warnings::Warnings;
+
+pub use crate::entry::{extract_to, extract_to_with_key, unpack_record_to_writer, unpack_record_to_writer_with, unpack_record_with_fallback, DeflateWrapper, UnpackBuffers};
+
+// What to do when an output file already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    Always,
+    Never,
+    // only if the record is newer (pak version 1) or the content differs
+    Update,
+}
+
+impl Default for Overwrite {
+    #[inline]
+    fn default() -> Self {
+        Overwrite::Always
+    }
+}
 
 #[derive(Debug)]
 pub struct UnpackOptions<'a> {
@@ -28,7 +56,44 @@ pub struct UnpackOptions<'a> {
     pub null_separated: bool,
     pub paths: Option<&'a [&'a str]>,
     pub thread_count: NonZeroUsize,
+    // limit for the memory used by all threads together (None means no limit)
+    pub max_memory: Option<NonZeroU64>,
+    // how the worker threads read the pak
+    pub io_strategy: IoStrategy,
+    pub read_size: NonZeroUsize,
     pub encryption_key: Option<Vec<u8>>,
+    pub progress: Option<&'a dyn ProgressCallback>,
+    pub overwrite: Overwrite,
+    // set the modification time of unpacked files to the record timestamp
+    pub preserve_timestamps: bool,
+    // used by preserve_timestamps for records without timestamp (seconds since the epoch)
+    pub fallback_timestamp: Option<u64>,
+    // unpack records with ".." or drive prefixes in their path anyway
+    pub allow_unsafe_paths: bool,
+    // only unpack records with matching metadata
+    pub predicate: Option<&'a Predicate>,
+    // print a digest of every unpacked file in the format of sha1sum/sha256sum
+    // (paths relative to the output directory)
+    pub digest: Option<DigestAlgorithm>,
+    // order in which the records are queued for the worker threads
+    pub order: WorkOrder,
+    // keep going when a file can't be unpacked (e.g. corrupt compressed data)
+    // and report it afterwards, see unpack_with_report()
+    pub skip_errors: bool,
+    // retry corrupt zlib streams with other wrappers and block offset
+    // interpretations, see unpack_record_with_fallback()
+    pub fallback_decompression: bool,
+    // drop this leading directory (e.g. "Game/Content/") from the record
+    // paths, records outside of it keep their full path
+    pub strip_prefix: Option<&'a str>,
+    // unpack all records into the output directory itself, without their
+    // directories; name collisions get a " (2)", " (3)" etc. suffix
+    pub flatten: bool,
+    // what to do with file names that are invalid on some file systems, see
+    // the portable module
+    pub name_policy: NamePolicy,
+    // normalize file names to NFC, by default only on macOS
+    pub normalize_nfc: bool,
 }
 
 impl Default for UnpackOptions<'_> {
@@ -39,17 +104,70 @@ impl Default for UnpackOptions<'_> {
             null_separated: false,
             paths: None,
             thread_count: NonZeroUsize::new(num_cpus::get()).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            max_memory: None,
+            io_strategy: IoStrategy::default(),
+            read_size: DEFAULT_READ_SIZE,
             encryption_key: None,
+            progress: None,
+            overwrite: Overwrite::default(),
+            preserve_timestamps: false,
+            fallback_timestamp: None,
+            allow_unsafe_paths: false,
+            predicate: None,
+            digest: None,
+            order: WorkOrder::default(),
+            skip_errors: false,
+            fallback_decompression: false,
+            strip_prefix: None,
+            flatten: false,
+            name_policy: NamePolicy::default(),
+            normalize_nfc: NORMALIZE_NFC_DEFAULT,
         }
     }
 }
 
-#[inline]
-fn unpack_iter<'a>(pak: &Pak, in_file: &mut File, outdir: &Path, options: &'a UnpackOptions<'a>, records_iter: impl Iterator<Item=&'a Record>) -> Result<()> {
-    let version = pak.version();
-    let variant = pak.variant();
+// A file that couldn't be unpacked with UnpackOptions::skip_errors. Its output
+// file may be left incomplete.
+#[derive(Debug)]
+pub struct UnpackFailure {
+    pub filename: String,
+    pub offset: u64,
+    pub error: Error,
+}
 
-    let dirnames = if options.dirname_from_compression {
+// Written as path, offset, kind and reason.
+#[cfg(feature = "serde")]
+impl serde::Serialize for UnpackFailure {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        #[derive(serde::Serialize)]
+        struct UnpackFailureRef<'a> {
+            path: &'a str,
+            offset: u64,
+            kind: &'static str,
+            reason: String,
+        }
+
+        UnpackFailureRef {
+            path: &self.filename,
+            offset: self.offset,
+            kind: self.error.kind().as_str(),
+            reason: self.error.error_type().to_string(),
+        }.serialize(serializer)
+    }
+}
+
+// The document of the errors.json of unpack --skip-errors.
+#[cfg(feature = "serde")]
+#[derive(Debug, serde::Serialize)]
+pub struct UnpackReport<'a> {
+    pub package: &'a str,
+    pub error_count: usize,
+    pub errors: &'a [UnpackFailure],
+}
+
+// separate output directories for compressed and uncompressed records
+fn compression_dirnames(outdir: &Path, options: &UnpackOptions) -> Option<(PathBuf, PathBuf)> {
+    if options.dirname_from_compression {
         let mut zlib_outdir = outdir.to_path_buf();
         let mut none_outdir = outdir.to_path_buf();
 
@@ -59,24 +177,82 @@ fn unpack_iter<'a>(pak: &Pak, in_file: &mut File, outdir: &Path, options: &'a Un
         Some((zlib_outdir, none_outdir))
     } else {
         None
-    };
+    }
+}
+
+fn print_path(path: &Path, null_separated: bool) {
+    let linesep = if null_separated { '\0' } else { '\n' };
+
+    #[cfg(target_family="unix")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(path.as_os_str().as_bytes());
+        let _ = stdout.write_all(&[linesep as u8]);
+    }
+
+    #[cfg(not(target_family="unix"))]
+    {
+        print!("{}{}", path.to_string_lossy(), linesep);
+    }
+}
+
+#[derive(Debug)]
+struct Unpacked {
+    path: PathBuf,
+    // only if requested via options.digest
+    digest: Option<Vec<u8>>,
+}
+
+fn report_unpacked(unpacked: &Unpacked, outdir: &Path, options: &UnpackOptions) {
+    if options.verbose {
+        print_path(&unpacked.path, options.null_separated);
+    }
+
+    if let Some(digest) = &unpacked.digest {
+        let linesep = if options.null_separated { '\0' } else { '\n' };
+        let path = unpacked.path.strip_prefix(outdir).unwrap_or(&unpacked.path);
+        let filename = make_pak_path(path.components().map(|comp| comp.as_os_str().to_string_lossy()));
+        print!("{}  {}{}", HexDisplay::new(digest), filename, linesep);
+    }
+}
+
+#[inline]
+fn unpack_iter<'a>(pak: &Pak, in_file: &mut File, outdir: &Path, options: &'a UnpackOptions<'a>, records_iter: impl Iterator<Item=&'a Record>) -> Result<Vec<UnpackFailure>> {
+    let version = pak.version();
+    let variant = pak.variant();
+    let cipher = pak.cipher();
+    let warnings = pak.warnings();
+
+    let dirnames = compression_dirnames(outdir, options);
+    let mut mapper = PathMapper::new(options);
 
     let pak_path = in_file.path()?;
+    let progress = Progress::new(options.progress);
+    let budget = MemoryBudget::new(options.max_memory);
+    let failures = Mutex::new(Vec::new());
 
     let thread_result = thread::scope::<_, Result<()>>(|scope| {
+        // all work is sent before results are received, so only the result
+        // channel can be bounded
         let (work_sender, work_receiver) = unbounded();
-        let (result_sender, result_receiver) = unbounded();
+        let (result_sender, result_receiver) = bounded(options.thread_count.get());
 
         for _ in 0..options.thread_count.get() {
             let work_receiver = work_receiver.clone();
             let result_sender = result_sender.clone();
-            let mut in_file = File::open(&pak_path)?;
+            let error_sender = result_sender.clone();
+            let in_file = File::open(&pak_path)?;
+            let progress = &progress;
+            let budget = &budget;
+            let failures = &failures;
 
             scope.spawn(move |_| {
-                let in_file = &mut in_file;
-                if let Err(error) = worker_proc(in_file, version, variant, options.encryption_key.clone(), work_receiver, result_sender) {
+                let mut reader = PakReader::new(in_file, options.io_strategy, options.read_size);
+                if let Err(error) = worker_proc(&mut reader, version, variant, cipher, warnings, options, progress, budget, failures, work_receiver, result_sender) {
+                    // the receiving side is gone if it already stopped because of an error
                     if !error.error_type().is_channel_disconnected() {
-                        eprintln!("error in worker thread: {}", error);
+                        let _ = error_sender.send(Err(error));
                     }
                 }
             });
@@ -90,7 +266,9 @@ fn unpack_iter<'a>(pak: &Pak, in_file: &mut File, outdir: &Path, options: &'a Un
                 let method = record.compression_method();
                 let outdir = if method == COMPR_NONE { &none_outdir } else { &zlib_outdir };
 
-                match work_sender.send(Work { record, outdir }) {
+                progress.add_total(1, record.uncompressed_size());
+                let path = mapper.map(record, outdir)?;
+                match work_sender.send(Work { record, path }) {
                     Ok(()) => {}
                     Err(error) =>
                         return Err(Error::new(error.to_string()).with_path(record.filename()))
@@ -98,7 +276,9 @@ fn unpack_iter<'a>(pak: &Pak, in_file: &mut File, outdir: &Path, options: &'a Un
             }
         } else {
             for record in records_iter {
-                match work_sender.send(Work { record, outdir }) {
+                progress.add_total(1, record.uncompressed_size());
+                let path = mapper.map(record, outdir)?;
+                match work_sender.send(Work { record, path }) {
                     Ok(()) => {}
                     Err(error) =>
                         return Err(Error::new(error.to_string()).with_path(record.filename()))
@@ -106,27 +286,12 @@ fn unpack_iter<'a>(pak: &Pak, in_file: &mut File, outdir: &Path, options: &'a Un
             }
         }
 
+        progress.finish_totals();
         drop(work_sender);
 
-        #[cfg(target_family="unix")]
-        let mut stdout = std::io::stdout();
-
-        let linesep = if options.null_separated { '\0' } else { '\n' };
-
         while let Ok(result) = result_receiver.recv() {
-            let path = result?;
-            if options.verbose {
-                #[cfg(target_family="unix")]
-                {
-                    use std::os::unix::ffi::OsStrExt;
-                    let _ = stdout.write_all(path.as_os_str().as_bytes());
-                    let _ = stdout.write_all(&[linesep as u8]);
-                }
-
-                #[cfg(not(target_family="unix"))]
-                {
-                    print!("{}{}", path.to_string_lossy(), linesep);
-                }
+            if let Some(unpacked) = result? {
+                report_unpacked(&unpacked, outdir, options);
             }
         }
 
@@ -139,146 +304,634 @@ fn unpack_iter<'a>(pak: &Pak, in_file: &mut File, outdir: &Path, options: &'a Un
         Err(error) => {
             return Err(Error::new(format!("threading error: {:?}", error)));
         }
-        Ok(result) => result
+        Ok(result) => result?
     }
+
+    Ok(failures.into_inner().unwrap_or_else(|error| error.into_inner()))
 }
 
 pub fn unpack<'a>(pak: &Pak, in_file: &mut File, outdir: impl AsRef<Path>, options: UnpackOptions<'a>) -> Result<()> {
+    let failures = unpack_with_report(pak, in_file, outdir, options)?;
+    if !failures.is_empty() {
+        return Err(Error::new(format!("{} file(s) could not be unpacked", failures.len())));
+    }
+    Ok(())
+}
+
+// Like unpack(), but with options.skip_errors files that can't be unpacked
+// are passed to the warnings of pak and returned instead of stopping at the
+// first one.
+pub fn unpack_with_report<'a>(pak: &Pak, in_file: &mut File, outdir: impl AsRef<Path>, options: UnpackOptions<'a>) -> Result<Vec<UnpackFailure>> {
     let outdir = outdir.as_ref();
 
     if let Some(paths) = options.paths {
         let mut filter: Filter = paths.into();
-        let records = pak.index().records().iter()
-            .filter(|record| filter.visit(record.filename()));
+        let mut records: Vec<&Record> = pak.filter_records(&mut filter).into_iter()
+            .filter(|record| predicate::matches(options.predicate, record))
+            .collect();
+        sort_records(&mut records, options.order);
 
-        unpack_iter(pak, in_file, outdir, &options, records)?;
+        if !options.allow_unsafe_paths {
+            assert_safe_paths(records.iter().copied())?;
+        }
+        if options.name_policy == NamePolicy::Error {
+            assert_valid_names(records.iter().copied())?;
+        }
+        let failures = unpack_iter(pak, in_file, outdir, &options, records.into_iter())?;
         filter.assert_all_visited()?;
+        Ok(failures)
     } else {
-        unpack_iter(pak, in_file, outdir, &options, pak.index().records().iter())?;
+        let records = pak.index().records().iter()
+            .filter(|record| predicate::matches(options.predicate, record));
+        if !options.allow_unsafe_paths {
+            assert_safe_paths(records.clone())?;
+        }
+        if options.name_policy == NamePolicy::Error {
+            assert_valid_names(records.clone())?;
+        }
+        if options.order == WorkOrder::Index {
+            unpack_iter(pak, in_file, outdir, &options, records)
+        } else {
+            let mut records: Vec<&Record> = records.collect();
+            sort_records(&mut records, options.order);
+            unpack_iter(pak, in_file, outdir, &options, records.into_iter())
+        }
+    }
+}
+
+// Like unpack(), but everything is read through the given reader in the
+// current thread, i.e. options.thread_count is ignored. For readers that can't
+// be opened once per thread, like a RangeReader.
+pub fn unpack_from_reader<'a>(pak: &Pak, reader: &mut (impl Read + Seek), outdir: impl AsRef<Path>, options: UnpackOptions<'a>) -> Result<()> {
+    let outdir = outdir.as_ref();
+    let version = pak.version();
+    let variant = pak.variant();
+
+    let mut filter: Option<Filter> = options.paths.map(Filter::from);
+    let mut records: Vec<&Record> = match &mut filter {
+        Some(filter) => pak.filter_records(filter),
+        None => pak.index().records().iter().collect(),
+    }.into_iter()
+        .filter(|record| predicate::matches(options.predicate, record))
+        .collect();
+    sort_records(&mut records, options.order);
+
+    if !options.allow_unsafe_paths {
+        assert_safe_paths(records.iter().copied())?;
     }
+    if options.name_policy == NamePolicy::Error {
+        assert_valid_names(records.iter().copied())?;
+    }
+
+    let dirnames = compression_dirnames(outdir, &options);
+    let progress = Progress::new(options.progress);
+    for record in &records {
+        progress.add_total(1, record.uncompressed_size());
+    }
+    progress.finish_totals();
+
+    let mut mapper = PathMapper::new(&options);
+    let mut worker = Worker::new(version, variant, pak.cipher())
+        .with_fallback(options.fallback_decompression, pak.warnings());
+    for record in records {
+        let record_outdir = match &dirnames {
+            Some((_, none_outdir)) if record.compression_method() == COMPR_NONE => none_outdir,
+            Some((zlib_outdir, _)) => zlib_outdir,
+            None => outdir,
+        };
+
+        let path = mapper.map(record, record_outdir)?;
+        let result = worker.unpack_work(record, reader, path, &options);
+        progress.advance(record.filename(), record.uncompressed_size());
+
+        if let Some(unpacked) = result? {
+            report_unpacked(&unpacked, outdir, &options);
+        }
+    }
+
+    if let Some(filter) = filter {
+        filter.assert_all_visited()?;
+    }
+
     Ok(())
 }
 
-pub fn unpack_record(record: &Record, version: u32, variant: Variant, in_file: &mut File, outdir: impl AsRef<Path>, encryption_key: Option<Vec<u8>>) -> Result<PathBuf> {
-    let header_size = pak::Pak::header_size(version, variant, record);
-    
-    let mut path = outdir.as_ref().to_path_buf();
-    for component in parse_pak_path(record.filename()) {
-        path.push(component);
+// Like unpack_from_reader(), but the package is read front to back from a
+// stream that can't seek, e.g. stdin. The pak has to be read from a separate
+// copy of its index, see Pak::from_index_reader(). The records are unpacked
+// in the order of their data, records that share their data with a record
+// before them can't be unpacked and fail with an error.
+pub fn unpack_from_stream<'a>(pak: &Pak, reader: impl Read, outdir: impl AsRef<Path>, options: UnpackOptions<'a>) -> Result<()> {
+    if options.overwrite == Overwrite::Update {
+        return Err(Error::new("updating existing files is not supported when unpacking from a stream".to_string())
+            .with_kind(ErrorKind::InvalidArgument));
     }
-    
-    let mut out_file = match OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path) {
-        Ok(file) => file,
-        Err(error) => {
-            if error.kind() == std::io::ErrorKind::NotFound {
-                if let Some(parent) = path.parent() {
-                    std::fs::create_dir_all(parent)?;
-                    OpenOptions::new().write(true).create(true).open(&path)?
-                } else {
-                    return Err(Error::io_with_path(error, path));
+
+    let mut reader = StreamReader::new(reader);
+    unpack_from_reader(pak, &mut reader, outdir, UnpackOptions {
+        order: WorkOrder::Offset,
+        ..options
+    })
+}
+
+// Unpacks the chunks of an IoStore container. The partitions are the readers of
+// the .ucas files, see Toc::open_partitions(). Like unpack_from_reader() this
+// runs in the current thread.
+pub fn unpack_toc<'a>(toc: &Toc, partitions: &mut [impl Read + Seek], outdir: impl AsRef<Path>, options: UnpackOptions<'a>) -> Result<()> {
+    let outdir = outdir.as_ref();
+    let pak = toc.to_pak();
+
+    let mut filter: Option<Filter> = options.paths.map(Filter::from);
+    let mut records: Vec<&Record> = match &mut filter {
+        Some(filter) => pak.filter_records(filter),
+        None => pak.index().records().iter().collect(),
+    }.into_iter()
+        .filter(|record| predicate::matches(options.predicate, record))
+        .collect();
+    sort_records(&mut records, options.order);
+
+    if !options.allow_unsafe_paths {
+        assert_safe_paths(records.iter().copied())?;
+    }
+    if options.name_policy == NamePolicy::Error {
+        assert_valid_names(records.iter().copied())?;
+    }
+
+    let dirnames = compression_dirnames(outdir, &options);
+    let progress = Progress::new(options.progress);
+    for record in &records {
+        progress.add_total(1, record.uncompressed_size());
+    }
+    progress.finish_totals();
+
+    let mut mapper = PathMapper::new(&options);
+    for record in records {
+        let record_outdir = match &dirnames {
+            Some((_, none_outdir)) if record.compression_method() == COMPR_NONE => none_outdir,
+            Some((zlib_outdir, _)) => zlib_outdir,
+            None => outdir,
+        };
+
+        // every record has a chunk of the same name
+        let chunk = toc.chunk(record.filename()).unwrap();
+        let path = mapper.map(record, record_outdir)?;
+        let result = unpack_chunk(toc, chunk, partitions, &path, options.overwrite, options.digest)
+            .map_err(|error| error.with_path_if_none(record.filename()));
+        progress.advance(record.filename(), record.uncompressed_size());
+
+        if let Some(unpacked) = result? {
+            report_unpacked(&unpacked, outdir, &options);
+        }
+    }
+
+    if let Some(filter) = filter {
+        filter.assert_all_visited()?;
+    }
+
+    Ok(())
+}
+
+// Returns None if the chunk was skipped because of overwrite.
+fn unpack_chunk(toc: &Toc, chunk: &TocChunk, partitions: &mut [impl Read + Seek], path: &Path, overwrite: Overwrite, digest: Option<DigestAlgorithm>) -> Result<Option<Unpacked>> {
+    let io_path = &*long_path(path);
+    if overwrite != Overwrite::Always {
+        match std::fs::metadata(io_path) {
+            Ok(metadata) => {
+                if overwrite == Overwrite::Never {
+                    return Ok(None);
+                }
+
+                // there are no timestamps, so compare the content
+                if metadata.len() == chunk.length() {
+                    let file = match File::open(io_path) {
+                        Ok(file) => file,
+                        Err(error) => return Err(Error::io_with_path(error, io_path)),
+                    };
+                    let file_sha1 = sha1_digest(file).map_err(|error| error.with_path_if_none(io_path))?;
+
+                    let mut writer = Sha1Writer::new();
+                    toc.read_chunk(chunk, partitions, &mut writer)?;
+                    if writer.finish() == file_sha1 {
+                        return Ok(None);
+                    }
                 }
-            } else {
-                return Err(Error::io_with_path(error, path));
             }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(Error::io_with_path(error, io_path)),
+        }
+    }
+
+    if let Some(parent) = io_path.parent() {
+        if let Err(error) = std::fs::create_dir_all(parent) {
+            return Err(Error::io_with_path(error, parent));
         }
+    }
+
+    let out_file = match File::create(io_path) {
+        Ok(file) => file,
+        Err(error) => return Err(Error::io_with_path(error, io_path)),
     };
 
-    let start_offset = record.offset() + header_size;
-    in_file.seek(SeekFrom::Start(start_offset))?;
+    let mut writer = TeeDigestWriter {
+        inner: BufWriter::new(out_file),
+        digest: digest.map(DigestWriter::new),
+    };
+    toc.read_chunk(chunk, partitions, &mut writer)?;
+    if let Err(error) = writer.flush() {
+        return Err(Error::io_with_path(error, io_path));
+    }
 
-    // Encrypted files need to be read in 16 byte blocks
-    let buffer_length = if record.encrypted() {
-        align(record.size(), BLOCK_SIZE as u64)
-    } else {
-        record.size()
-    } as usize;
+    Ok(Some(Unpacked {
+        path: path.to_path_buf(),
+        digest: writer.digest.map(DigestWriter::finish),
+    }))
+}
+
+// A path component is safe if it can't escape the output directory, i.e. it
+// isn't "..", "." or something the platform interprets as a root or drive
+// prefix (e.g. "C:" or "..\\" on Windows).
+#[inline]
+pub fn is_safe_path_component(component: &str) -> bool {
+    let mut components = Path::new(component).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
 
-    let mut in_buffer = vec![0u8; buffer_length];
-    in_file.read_exact(&mut in_buffer)?;
-    
-    decrypt_entry(&mut in_buffer, record, encryption_key, record.size() as usize)?;
-    debug!("unpacking {:?}", record);
+#[inline]
+pub fn is_safe_pak_path(path: &str) -> bool {
+    parse_pak_path(path).all(is_safe_path_component)
+}
 
-    match record.compression_method() {
-        pak::COMPR_NONE => {
-            out_file.write_all(&in_buffer)?;
-            out_file.flush()?;
+// Check all paths before anything is written, so a malicious pak doesn't
+// leave a partially unpacked directory behind.
+pub(crate) fn assert_safe_paths<'a>(records: impl Iterator<Item=&'a Record>) -> Result<()> {
+    let mut iter = records.filter(|record| !is_safe_pak_path(record.filename()));
+    if let Some(record) = iter.next() {
+        let mut message = format!(
+            "Refusing to unpack paths that could escape the output directory \
+             (use --allow-unsafe-paths to unpack anyway):\n* {}", record.filename());
+        for record in iter {
+            message.push_str("\n* ");
+            message.push_str(record.filename());
         }
-        pak::COMPR_ZLIB => {
-            if let Some(blocks) = record.compression_blocks() {
-                let mut out_file = BufWriter::new(out_file);
+        return Err(Error::new(message).with_kind(ErrorKind::UnsafePath));
+    }
+    Ok(())
+}
 
-                let mut out_buffer = Vec::with_capacity(record.compression_block_size() as usize);
+// Like assert_safe_paths() for UnpackOptions::name_policy == NamePolicy::Error.
+fn assert_valid_names<'a>(records: impl Iterator<Item=&'a Record>) -> Result<()> {
+    let mut iter = records.filter(|record| !parse_pak_path(record.filename()).all(is_valid_name));
+    if let Some(record) = iter.next() {
+        let mut message = format!(
+            "Refusing to unpack file names that are invalid on some file systems \
+             (use --invalid-names=percent or replace to map them):\n* {:?}", record.filename());
+        for record in iter {
+            message.push_str("\n* ");
+            message.push_str(&format!("{:?}", record.filename()));
+        }
+        return Err(Error::new(message).with_kind(ErrorKind::UnsafePath));
+    }
+    Ok(())
+}
 
-                for block in blocks {
-                    let mut block_start = (block.start_offset - header_size) as usize;
-                    let mut block_end = (block.end_offset - header_size) as usize;
+fn record_path(record: &Record, outdir: &Path) -> PathBuf {
+    let mut path = outdir.to_path_buf();
+    for component in parse_pak_path(record.filename()) {
+        path.push(component);
+    }
+    path
+}
 
-                    if version < PAK_RELATIVE_COMPRESSION_OFFSET_VERSION {
-                        block_start -= (start_offset - header_size) as usize;
-                        block_end -= (start_offset - header_size) as usize;
-                    }
+// "name.ext" -> "name (2).ext", a leading dot doesn't start an extension
+fn numbered_name(name: &str, number: usize) -> String {
+    match name.rfind('.') {
+        Some(index) if index > 0 => format!("{} ({}){}", &name[..index], number, &name[index..]),
+        _ => format!("{} ({})", name, number),
+    }
+}
 
-                    let mut zlib = ZlibDecoder::new(&in_buffer[block_start..block_end]);
-                    out_buffer.clear();
-                    zlib.read_to_end(&mut out_buffer)?;
-                    out_file.write_all(&out_buffer)?;
+// Maps record paths to output paths according to UnpackOptions::strip_prefix,
+// flatten, name_policy and normalize_nfc. Records have to be mapped in the
+// order they are unpacked, so that with colliding names the first record
+// keeps the plain name.
+#[derive(Debug)]
+struct PathMapper<'a> {
+    strip_prefix: Vec<&'a str>,
+    flatten: bool,
+    name_policy: NamePolicy,
+    normalize_nfc: bool,
+    // output paths that are already taken (folded to lower case on Windows
+    // and macOS) and the record that took them
+    used: HashMap<String, String>,
+}
+
+impl<'a> PathMapper<'a> {
+    fn new(options: &UnpackOptions<'a>) -> Self {
+        Self {
+            strip_prefix: options.strip_prefix.map_or_else(Vec::new, |prefix| parse_pak_path(prefix).collect()),
+            flatten: options.flatten,
+            name_policy: options.name_policy,
+            normalize_nfc: options.normalize_nfc,
+            used: HashMap::new(),
+        }
+    }
+
+    #[inline]
+    fn is_identity(&self) -> bool {
+        self.strip_prefix.is_empty() && !self.flatten &&
+        self.name_policy == NamePolicy::Keep && !self.normalize_nfc
+    }
+
+    fn map(&mut self, record: &Record, outdir: &Path) -> Result<PathBuf> {
+        if self.is_identity() {
+            return Ok(record_path(record, outdir));
+        }
+
+        let mut components: Vec<&str> = parse_pak_path(record.filename()).collect();
+        if self.flatten {
+            components.drain(..components.len().saturating_sub(1));
+        } else if components.len() > self.strip_prefix.len() && components.starts_with(&self.strip_prefix) {
+            components.drain(..self.strip_prefix.len());
+        }
+
+        let mut path = outdir.to_path_buf();
+        let (name, dirs) = match components.split_last() {
+            Some(split) => split,
+            None => return Ok(path),
+        };
+        for component in dirs {
+            path.push(&*map_name(component, self.name_policy, self.normalize_nfc)?);
+        }
+        let name = map_name(name, self.name_policy, self.normalize_nfc)?;
+
+        // a record that is in the package twice overwrites itself like before
+        let mut candidate = path.join(&*name);
+        let mut number = 1;
+        loop {
+            match self.used.get(&Self::key(&candidate)) {
+                None => break,
+                Some(filename) if filename == record.filename() => return Ok(candidate),
+                Some(_) => {
+                    number += 1;
+                    candidate = path.join(numbered_name(&name, number));
                 }
-                out_file.flush()?;
-            } else {
-                // version 2 has compression support, but not compression blocks
-                let mut out_buffer = Vec::new();
+            }
+        }
+        self.used.insert(Self::key(&candidate), record.filename().to_string());
+        Ok(candidate)
+    }
+
+    fn key(path: &Path) -> String {
+        let key = path.to_string_lossy();
+        if cfg!(any(target_os = "windows", target_os = "macos")) {
+            key.to_lowercase()
+        } else {
+            key.into_owned()
+        }
+    }
+}
+
+// Records up to this size are unpacked into memory and written with a single
+// write call, which is a lot faster for paks with lots of tiny files.
+const COALESCE_SIZE: u64 = 1024 * 1024;
+
+// Write buffer size for bigger records.
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+// State of one unpacking thread that is reused for all records it unpacks.
+#[derive(Debug)]
+struct Worker<'a> {
+    version: u32,
+    variant: Variant,
+    cipher: &'a dyn Cipher,
+    buffers: UnpackBuffers,
+    // the content of records up to COALESCE_SIZE
+    data: Vec<u8>,
+    // directories that were already created
+    dirs: HashSet<PathBuf>,
+    // where fallback decompressions are reported, None to not try them
+    fallback: Option<&'a Warnings>,
+}
 
-                let mut zlib = ZlibDecoder::new(&in_buffer[..]);
-                zlib.read_to_end(&mut out_buffer)?;
-                out_file.write_all(&out_buffer)?;
-                out_file.flush()?;
+impl<'a> Worker<'a> {
+    fn new(version: u32, variant: Variant, cipher: &'a dyn Cipher) -> Self {
+        Self {
+            version,
+            variant,
+            cipher,
+            buffers: UnpackBuffers::new(),
+            data: Vec::new(),
+            dirs: HashSet::new(),
+            fallback: None,
+        }
+    }
+
+    #[inline]
+    fn with_fallback(self, fallback_decompression: bool, warnings: &'a Warnings) -> Self {
+        Self { fallback: if fallback_decompression { Some(warnings) } else { None }, ..self }
+    }
+
+    fn unpack_record_to_writer(&mut self, record: &Record, in_file: &mut (impl Read + Seek), writer: &mut impl Write, encryption_key: Option<Vec<u8>>) -> Result<()> {
+        match self.fallback {
+            Some(warnings) => unpack_record_with_fallback(record, self.version, self.variant, in_file, writer, encryption_key, self.cipher, &mut self.buffers, warnings),
+            None => unpack_record_to_writer_with(record, self.version, self.variant, in_file, writer, encryption_key, self.cipher, &mut self.buffers),
+        }
+    }
+
+    // Rough upper bound of the memory needed for unpacking record: the record
+    // itself if it is coalesced and one compression block before and after
+    // decompression.
+    fn memory_estimate(record: &Record) -> u64 {
+        let data = if record.uncompressed_size() <= COALESCE_SIZE {
+            record.uncompressed_size()
+        } else {
+            WRITE_BUFFER_SIZE as u64
+        };
+        data + 2 * record.compression_block_size() as u64
+    }
+
+    // Decides if the record needs to be written according to the overwrite policy.
+    fn needs_unpack(&mut self, record: &Record, in_file: &mut (impl Read + Seek), path: &Path, overwrite: Overwrite, encryption_key: &Option<Vec<u8>>) -> Result<bool> {
+        if overwrite == Overwrite::Always {
+            return Ok(true);
+        }
+
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+            Err(error) => return Err(Error::io_with_path(error, path)),
+        };
+
+        if overwrite == Overwrite::Never {
+            return Ok(false);
+        }
+
+        if let Some(timestamp) = record.timestamp() {
+            let modified = metadata.modified().map_err(|error| Error::io_with_path(error, path))?;
+            let modified = modified.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+            return Ok(timestamp > modified);
+        }
+
+        if metadata.len() != record.uncompressed_size() {
+            return Ok(true);
+        }
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) => return Err(Error::io_with_path(error, path)),
+        };
+        let file_sha1 = sha1_digest(file).map_err(|error| error.with_path_if_none(path))?;
+
+        let mut writer = Sha1Writer::new();
+        self.unpack_record_to_writer(record, in_file, &mut writer, encryption_key.clone())?;
+
+        Ok(writer.finish() != file_sha1)
+    }
+
+    fn create_parent_dir(&mut self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            if !self.dirs.contains(parent) {
+                if let Err(error) = std::fs::create_dir_all(parent) {
+                    return Err(Error::io_with_path(error, parent));
+                }
+                self.dirs.insert(parent.to_path_buf());
             }
         }
-        _ => {
-            return Err(Error::new(format!(
-                    "unsupported compression method: {}",
-                    compression_method_name(record.compression_method())))
-                .with_path(record.filename()));
+        Ok(())
+    }
+
+    // Returns the digest of the content if requested.
+    fn unpack_to_path(&mut self, record: &Record, in_file: &mut (impl Read + Seek), path: &Path, encryption_key: Option<Vec<u8>>, digest: Option<DigestAlgorithm>) -> Result<Option<Vec<u8>>> {
+        self.create_parent_dir(path)?;
+
+        let mut out_file = match OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path) {
+            Ok(file) => file,
+            Err(error) => return Err(Error::io_with_path(error, path)),
+        };
+
+        if record.uncompressed_size() <= COALESCE_SIZE {
+            self.data.clear();
+            let mut data = std::mem::take(&mut self.data);
+            let result = self.unpack_record_to_writer(record, in_file, &mut data, encryption_key);
+            self.data = data;
+            result?;
+            if let Err(error) = out_file.write_all(&self.data) {
+                return Err(Error::io_with_path(error, path));
+            }
+
+            Ok(digest.map(|digest| {
+                let mut writer = DigestWriter::new(digest);
+                let _ = writer.write_all(&self.data);
+                writer.finish()
+            }))
+        } else {
+            let mut writer = TeeDigestWriter {
+                inner: BufWriter::with_capacity(WRITE_BUFFER_SIZE, out_file),
+                digest: digest.map(DigestWriter::new),
+            };
+            self.unpack_record_to_writer(record, in_file, &mut writer, encryption_key)?;
+
+            Ok(writer.digest.map(DigestWriter::finish))
         }
     }
 
+    // Returns None if the record was skipped because of options.overwrite.
+    fn unpack_work(&mut self, record: &Record, in_file: &mut (impl Read + Seek), path: PathBuf, options: &UnpackOptions) -> Result<Option<Unpacked>> {
+        let encryption_key = &options.encryption_key;
+        let io_path = long_path(&path);
+        self.needs_unpack(record, in_file, &io_path, options.overwrite, encryption_key)
+            .and_then(|needed| if needed {
+                // paths were already checked in unpack(), if requested
+                let digest = self.unpack_to_path(record, in_file, &io_path, encryption_key.clone(), options.digest)?;
+                if options.preserve_timestamps {
+                    if let Some(timestamp) = record.timestamp().or(options.fallback_timestamp) {
+                        set_timestamp(&io_path, timestamp)?;
+                    }
+                }
+                Ok(Some(Unpacked { path: path.clone(), digest }))
+            } else {
+                Ok(None)
+            })
+            .map_err(|error| error
+                .with_path_if_none(record.filename()))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn unpack_record(record: &Record, version: u32, variant: Variant, in_file: &mut (impl Read + Seek), outdir: impl AsRef<Path>, encryption_key: Option<Vec<u8>>, cipher: &dyn Cipher) -> Result<PathBuf> {
+    if !is_safe_pak_path(record.filename()) {
+        return Err(Error::new("refusing to unpack path that could escape the output directory".to_string())
+            .with_kind(ErrorKind::UnsafePath)
+            .with_path(record.filename()));
+    }
+    unpack_record_unchecked(record, version, variant, in_file, outdir, encryption_key, cipher)
+}
+
+// Like unpack_record(), but doesn't check if the path is safe.
+//
+// File names are mapped like by unpack() with the default UnpackOptions, i.e.
+// on Windows names like aux.uasset are percent-encoded and long paths get
+// the \\?\ prefix (the returned path doesn't have it).
+#[allow(clippy::too_many_arguments)]
+pub fn unpack_record_unchecked(record: &Record, version: u32, variant: Variant, in_file: &mut (impl Read + Seek), outdir: impl AsRef<Path>, encryption_key: Option<Vec<u8>>, cipher: &dyn Cipher) -> Result<PathBuf> {
+    let mut mapper = PathMapper {
+        strip_prefix: Vec::new(),
+        flatten: false,
+        name_policy: NamePolicy::default(),
+        normalize_nfc: NORMALIZE_NFC_DEFAULT,
+        used: HashMap::new(),
+    };
+    let path = mapper.map(record, outdir.as_ref())?;
+    Worker::new(version, variant, cipher).unpack_to_path(record, in_file, &long_path(&path), encryption_key, None)?;
     Ok(path)
 }
 
 #[derive(Debug)]
 struct Work<'a> {
     record: &'a Record,
-    outdir: &'a Path,
+    path: PathBuf,
 }
 
-fn worker_proc(in_file: &mut File, version: u32, variant: Variant, encryption_key: Option<Vec<u8>>, work_channel: Receiver<Work>, result_channel: Sender<Result<PathBuf>>) -> Result<()> {
-    while let Ok(Work { record, outdir }) = work_channel.recv() {
-        let result = unpack_record(record, version, variant, in_file, outdir, encryption_key.clone())
-            .map_err(|error| error
-                .with_path_if_none(record.filename()));
+fn set_timestamp(path: &Path, timestamp: u64) -> Result<()> {
+    let result = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_secs(timestamp)));
 
-        result_channel.send(result)?;
+    match result {
+        Ok(()) => Ok(()),
+        Err(error) => Err(Error::io_with_path(error, path)),
     }
-
-    Ok(())
 }
 
-fn decrypt_entry(buffer: &mut Vec<u8>, record: &Record, encryption_key: Option<Vec<u8>>, size: usize) -> Result<()> {
-    if record.encrypted() {
-        if let Some(key) = encryption_key {
-            decrypt(buffer, &key);
-            // Trim padded bytes from undersized encryption blocks
-            buffer.truncate(size);
-        } else {
-            return Err(Error::new(
-                "File is encrypted, but no encryption key was provided".to_string(),
-            ).with_path(record.filename()));
+#[allow(clippy::too_many_arguments)]
+fn worker_proc(in_file: &mut PakReader, version: u32, variant: Variant, cipher: &dyn Cipher, warnings: &Warnings, options: &UnpackOptions, progress: &Progress, budget: &MemoryBudget, failures: &Mutex<Vec<UnpackFailure>>, work_channel: Receiver<Work>, result_channel: Sender<Result<Option<Unpacked>>>) -> Result<()> {
+    let mut worker = Worker::new(version, variant, cipher)
+        .with_fallback(options.fallback_decompression, warnings);
+    while let Ok(Work { record, path }) = work_channel.recv() {
+        debug!("unpacking {}", record.filename());
+        let guard = budget.acquire(Worker::memory_estimate(record));
+        let result = worker.unpack_work(record, in_file, path, options);
+        drop(guard);
+        progress.advance(record.filename(), record.uncompressed_size());
+
+        match result {
+            Err(error) if options.skip_errors => {
+                warnings.warn(Some(record.filename()), error.error_type().to_string());
+                let mut failures = failures.lock().unwrap_or_else(|error| error.into_inner());
+                failures.push(UnpackFailure {
+                    filename: record.filename().to_string(),
+                    offset: record.offset(),
+                    error,
+                });
+            }
+            result => result_channel.send(result)?,
         }
     }
+
     Ok(())
-}
\ No newline at end of file
+}
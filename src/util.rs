@@ -4,9 +4,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::io::Read;
 use std::str::FromStr;
 use core::num::NonZeroU32;
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::io::Read;
+#[cfg(feature = "fs")]
 use openssl::sha::Sha1 as OpenSSLSha1;
 
 use crate::{Result, Error};
@@ -218,9 +221,41 @@ pub fn parse_compression_level(value: &str) -> Result<NonZeroU32> {
     }
 }
 
+// Write adapter that hashes everything written to it.
+#[cfg(feature = "fs")]
+pub(crate) struct Sha1Writer(pub(crate) OpenSSLSha1);
+
+#[cfg(feature = "fs")]
+impl Sha1Writer {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self(OpenSSLSha1::new())
+    }
+
+    #[inline]
+    pub(crate) fn finish(self) -> [u8; 20] {
+        self.0.finish()
+    }
+}
+
+#[cfg(feature = "fs")]
+impl Write for Sha1Writer {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fs")]
 pub fn sha1_digest<R: Read>(mut reader: R) -> Result<[u8; 20]> {
     let mut hasher = OpenSSLSha1::new();
-    let mut buffer = [0; 1024];
+    let mut buffer = vec![0; 64 * 1024];
 
     loop {
         let count = reader.read(&mut buffer)?;
@@ -232,3 +267,41 @@ pub fn sha1_digest<R: Read>(mut reader: R) -> Result<[u8; 20]> {
 
     Ok(hasher.finish())
 }
+
+// Same format as `hexdump -C`, offset is added to the printed addresses.
+pub fn write_hexdump(writer: &mut impl Write, data: &[u8], offset: u64) -> std::io::Result<()> {
+    let mut prev_line: Option<&[u8]> = None;
+    let mut skipping = false;
+
+    for (index, line) in data.chunks(16).enumerate() {
+        if line.len() == 16 && prev_line == Some(line) {
+            if !skipping {
+                writeln!(writer, "*")?;
+                skipping = true;
+            }
+            continue;
+        }
+        skipping = false;
+        prev_line = Some(line);
+
+        write!(writer, "{:08x} ", offset + index as u64 * 16)?;
+        for column in 0..16 {
+            if column == 8 {
+                write!(writer, " ")?;
+            }
+            match line.get(column) {
+                Some(byte) => write!(writer, " {:02x}", byte)?,
+                None => write!(writer, "   ")?,
+            }
+        }
+        write!(writer, "  |")?;
+        for &byte in line {
+            let ch = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+            write!(writer, "{}", ch)?;
+        }
+        writeln!(writer, "|")?;
+    }
+    writeln!(writer, "{:08x}", offset + data.len() as u64)?;
+
+    Ok(())
+}
@@ -0,0 +1,280 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Everything that differs between the pak variants of different games. To add
+// a variant add it to Variant, implement VariantSpec for it and return that in
+// Variant::spec().
+
+use std::io::{Read, Write};
+
+use crate::pak::{
+    Variant, COMPRESSION_BLOCK_HEADER_SIZE, CONAN_EXILE_RECORD_HEADER_SIZE,
+    V1_RECORD_HEADER_SIZE, V2_RECORD_HEADER_SIZE, V3_RECORD_HEADER_SIZE,
+};
+use crate::{Error, ErrorKind, Record, Result};
+use crate::warnings::Warnings;
+
+pub type ReadRecord = fn(&mut dyn Read, String, &Warnings) -> Result<Record>;
+pub type WriteRecord = fn(&Record, &mut dyn Write) -> Result<()>;
+
+// Sizes of the parts of the inline record header in front of the file data,
+// which only depend on the version and the variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLayout {
+    // header without compression blocks
+    pub base_header_size: u64,
+    // if compressed records have the list of compression blocks (block count
+    // + blocks) after the base header
+    pub compression_blocks: bool,
+    // unknown bytes between the header and the data of compressed records
+    pub inline_padding: u64,
+}
+
+impl RecordLayout {
+    // Offset of the data of a record with that many compression blocks
+    // relative to the record, i.e. including the inline padding.
+    #[inline]
+    pub fn data_start_for(&self, block_count: Option<usize>) -> u64 {
+        let header_size = self.header_size_for(block_count);
+        if block_count.is_some() && self.compression_blocks {
+            header_size + self.inline_padding
+        } else {
+            header_size
+        }
+    }
+
+    // Size of the header of a record with that many compression blocks.
+    pub fn header_size_for(&self, block_count: Option<usize>) -> u64 {
+        match block_count {
+            Some(count) if self.compression_blocks =>
+                self.base_header_size + 4 + count as u64 * COMPRESSION_BLOCK_HEADER_SIZE,
+            _ => self.base_header_size,
+        }
+    }
+
+    #[inline]
+    pub fn header_size(&self, record: &Record) -> u64 {
+        self.header_size_for(record.compression_blocks().as_ref().map(Vec::len))
+    }
+}
+
+// The layout of the inline record headers of a pak of that version and variant.
+#[inline]
+pub fn record_layout(version: u32, variant: Variant) -> Result<RecordLayout> {
+    variant.spec().record_layout(version)
+}
+
+pub trait VariantSpec: Sync {
+    // as used for --variant
+    fn name(&self) -> &'static str;
+
+    // other names that are accepted when parsing a variant
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    // version to use for writing if none is given
+    fn default_version(&self) -> u32;
+
+    // reader of the records in the index of version < 10 paks
+    fn index_record_reader(&self, version: u32) -> Result<ReadRecord>;
+
+    // reader of the record headers in front of the file data
+    fn inline_record_reader(&self, version: u32) -> Result<ReadRecord>;
+
+    fn index_record_writer(&self, version: u32) -> Result<WriteRecord>;
+
+    fn inline_record_writer(&self, version: u32) -> Result<WriteRecord>;
+
+    // see record_layout()
+    fn record_layout(&self, version: u32) -> Result<RecordLayout>;
+
+    // size of an inline record header without compression blocks
+    #[inline]
+    fn base_header_size(&self, version: u32) -> Result<u64> {
+        self.record_layout(version).map(|layout| layout.base_header_size)
+    }
+
+    // size of the inline record header of record, panics for unsupported versions
+    fn header_size(&self, version: u32, record: &Record) -> u64 {
+        match self.record_layout(version) {
+            Ok(layout) => layout.header_size(record),
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    // size of an extra field at the end of record headers that is always 0
+    fn record_trailer_size(&self) -> u64 {
+        0
+    }
+
+    // unknown bytes between the inline record header (as per header_size())
+    // and the data of compressed records
+    #[inline]
+    fn inline_padding(&self, version: u32) -> u64 {
+        self.record_layout(version).map_or(0, |layout| layout.inline_padding)
+    }
+
+    #[inline]
+    fn can_read(&self, version: u32) -> bool {
+        self.inline_record_reader(version).is_ok()
+    }
+
+    #[inline]
+    fn can_write(&self, version: u32) -> bool {
+        self.inline_record_writer(version).is_ok()
+    }
+}
+
+fn unsupported_version(version: u32) -> Error {
+    Error::new(format!("unsupported version: {}", version)).with_kind(ErrorKind::UnsupportedVersion)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StandardVariant;
+
+impl VariantSpec for StandardVariant {
+    fn name(&self) -> &'static str {
+        "standard"
+    }
+
+    fn default_version(&self) -> u32 {
+        3
+    }
+
+    fn index_record_reader(&self, version: u32) -> Result<ReadRecord> {
+        match version {
+            1 => Ok(|mut reader, filename, _| Record::read_v1(&mut reader, filename)),
+            2 => Ok(|mut reader, filename, _| Record::read_v2(&mut reader, filename)),
+            3..=9 => Ok(|mut reader, filename, _| Record::read_v3(&mut reader, filename)),
+            _ => Err(unsupported_version(version)),
+        }
+    }
+
+    fn inline_record_reader(&self, version: u32) -> Result<ReadRecord> {
+        match version {
+            1 => Ok(|mut reader, filename, _| Record::read_v1(&mut reader, filename)),
+            2 => Ok(|mut reader, filename, _| Record::read_v2(&mut reader, filename)),
+            3..=5 | 7 => Ok(|mut reader, filename, _| Record::read_v3(&mut reader, filename)),
+            _ => Err(unsupported_version(version)),
+        }
+    }
+
+    fn index_record_writer(&self, version: u32) -> Result<WriteRecord> {
+        match version {
+            1 => Ok(|record, mut writer| record.write_v1(&mut writer)),
+            2 => Ok(|record, mut writer| record.write_v2(&mut writer)),
+            3 | 4 | 5 | 7 => Ok(|record, mut writer| record.write_v3(&mut writer)),
+            _ => Err(unsupported_version(version)),
+        }
+    }
+
+    fn inline_record_writer(&self, version: u32) -> Result<WriteRecord> {
+        match version {
+            1 => Ok(|record, mut writer| record.write_v1_inline(&mut writer)),
+            2 => Ok(|record, mut writer| record.write_v2_inline(&mut writer)),
+            3 => Ok(|record, mut writer| record.write_v3_inline(&mut writer)),
+            4 | 5 | 7 => Ok(|record, mut writer| record.write_v4_inline(&mut writer)),
+            _ => Err(unsupported_version(version)),
+        }
+    }
+
+    fn record_layout(&self, version: u32) -> Result<RecordLayout> {
+        Ok(match version {
+            1 => RecordLayout { base_header_size: V1_RECORD_HEADER_SIZE, compression_blocks: false, inline_padding: 0 },
+            2 => RecordLayout { base_header_size: V2_RECORD_HEADER_SIZE, compression_blocks: false, inline_padding: 0 },
+            3 => RecordLayout { base_header_size: V3_RECORD_HEADER_SIZE, compression_blocks: true,  inline_padding: 0 },
+            // unknown 32 bit field in front of the data since version 4
+            _ => RecordLayout { base_header_size: V3_RECORD_HEADER_SIZE, compression_blocks: true,  inline_padding: 4 },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConanExilesVariant;
+
+impl ConanExilesVariant {
+    fn check_version(version: u32) -> Result<()> {
+        if version != 4 {
+            return Err(Error::new(format!(
+                "Only know how to handle Conan Exile paks of version 4, but version was {}.",
+                version)).with_kind(ErrorKind::UnsupportedVersion));
+        }
+        Ok(())
+    }
+}
+
+impl VariantSpec for ConanExilesVariant {
+    fn name(&self) -> &'static str {
+        "conan_exiles"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["conanexiles", "conan exiles"]
+    }
+
+    fn default_version(&self) -> u32 {
+        4
+    }
+
+    fn index_record_reader(&self, version: u32) -> Result<ReadRecord> {
+        Self::check_version(version)?;
+        Ok(|mut reader, filename, warnings| Record::read_conan_exiles(&mut reader, filename, warnings))
+    }
+
+    fn inline_record_reader(&self, version: u32) -> Result<ReadRecord> {
+        self.index_record_reader(version)
+    }
+
+    fn index_record_writer(&self, version: u32) -> Result<WriteRecord> {
+        Self::check_version(version)?;
+        Ok(|record, mut writer| record.write_conan_exiles(&mut writer))
+    }
+
+    fn inline_record_writer(&self, version: u32) -> Result<WriteRecord> {
+        Self::check_version(version)?;
+        Ok(|record, mut writer| record.write_conan_exiles_inline(&mut writer))
+    }
+
+    fn record_layout(&self, version: u32) -> Result<RecordLayout> {
+        Self::check_version(version)?;
+        // like version 3 plus an unknown (always 0) 32 bit field at the end
+        Ok(RecordLayout {
+            base_header_size: CONAN_EXILE_RECORD_HEADER_SIZE,
+            compression_blocks: true,
+            inline_padding: 0,
+        })
+    }
+
+    fn record_trailer_size(&self) -> u64 {
+        CONAN_EXILE_RECORD_HEADER_SIZE - V3_RECORD_HEADER_SIZE
+    }
+}
+
+impl Variant {
+    pub const ALL: [Variant; 2] = [Variant::Standard, Variant::ConanExiles];
+
+    pub fn spec(self) -> &'static dyn VariantSpec {
+        match self {
+            Variant::Standard => &StandardVariant,
+            Variant::ConanExiles => &ConanExilesVariant,
+        }
+    }
+
+    #[inline]
+    pub fn name(self) -> &'static str {
+        self.spec().name()
+    }
+
+    pub fn from_name(name: &str) -> Option<Variant> {
+        let name = name.trim();
+        Variant::ALL.iter().cloned().find(|variant| {
+            let spec = variant.spec();
+            spec.name().eq_ignore_ascii_case(name) ||
+            spec.aliases().iter().any(|alias| alias.eq_ignore_ascii_case(name))
+        })
+    }
+}
@@ -0,0 +1,204 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{collections::HashSet, fs::File, num::NonZeroUsize, path::{Path, PathBuf}};
+
+use crossbeam_channel::unbounded;
+use crossbeam_utils::thread;
+
+use crate::{Error, Filter, Pak, Record, Result};
+use crate::pak::{HexDisplay, Sha1};
+use crate::reopen::Reopen;
+use crate::unpack::unpack_record_to_writer;
+use crate::util::{make_pak_path, parse_pak_path, sha1_digest, Sha1Writer};
+use crate::walkdir::walkdir;
+
+#[derive(Debug)]
+pub struct VerifyOptions<'a> {
+    pub paths: Option<&'a [&'a str]>,
+    pub thread_count: NonZeroUsize,
+    pub encryption_key: Option<Vec<u8>>,
+}
+
+impl Default for VerifyOptions<'_> {
+    fn default() -> Self {
+        Self {
+            paths: None,
+            thread_count: NonZeroUsize::new(num_cpus::get()).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            encryption_key: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Difference {
+    // in the pak, but not in the directory
+    Missing { filename: String },
+    // in the directory, but not in the pak
+    Extra { filename: String },
+    Mismatch { filename: String, pak_sha1: Sha1, dir_sha1: Sha1 },
+}
+
+impl Difference {
+    #[inline]
+    pub fn filename(&self) -> &str {
+        match self {
+            Difference::Missing { filename } => filename,
+            Difference::Extra { filename } => filename,
+            Difference::Mismatch { filename, .. } => filename,
+        }
+    }
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Missing { filename } => write!(f, "{}: missing in directory", filename),
+            Difference::Extra { filename } => write!(f, "{}: not in pak", filename),
+            Difference::Mismatch { filename, pak_sha1, dir_sha1 } => write!(f,
+                "{}: content missmatch:\n\
+                 \tpak:       {}\n\
+                 \tdirectory: {}",
+                filename, HexDisplay::new(pak_sha1), HexDisplay::new(dir_sha1)),
+        }
+    }
+}
+
+fn verify_record(pak: &Pak, record: &Record, in_file: &mut File, dir: &Path, encryption_key: &Option<Vec<u8>>) -> Result<Option<Difference>> {
+    let mut path = dir.to_path_buf();
+    for component in parse_pak_path(record.filename()) {
+        path.push(component);
+    }
+
+    let dir_file = match File::open(&path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Some(Difference::Missing { filename: record.filename().to_string() }));
+        }
+        Err(error) => return Err(Error::io_with_path(error, path)),
+    };
+    let dir_sha1 = sha1_digest(dir_file).map_err(|error| error.with_path_if_none(&path))?;
+
+    let mut writer = Sha1Writer::new();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), in_file, &mut writer, encryption_key.clone(), pak.cipher())
+        .map_err(|error| error.with_path_if_none(record.filename()))?;
+    let pak_sha1 = writer.finish();
+
+    if pak_sha1 == dir_sha1 {
+        Ok(None)
+    } else {
+        Ok(Some(Difference::Mismatch {
+            filename: record.filename().to_string(),
+            pak_sha1,
+            dir_sha1,
+        }))
+    }
+}
+
+// Compares the content of a pak with an (extracted) directory. Returns the
+// list of differences sorted by filename.
+pub fn verify(pak: &Pak, in_file: &mut File, dir: impl AsRef<Path>, options: VerifyOptions) -> Result<Vec<Difference>> {
+    let dir = dir.as_ref();
+    let pak_path = in_file.path()?;
+    let mut filter: Option<Filter> = options.paths.map(|paths| paths.into());
+
+    let records: Vec<&Record> = if let Some(filter) = &mut filter {
+        pak.filter_records(filter)
+    } else {
+        pak.index().records().iter().collect()
+    };
+
+    if let Some(filter) = &filter {
+        filter.assert_all_visited()?;
+    }
+
+    let mut differences = Vec::new();
+
+    // files in the directory that aren't in the pak
+    let pak_filenames: HashSet<String> = records.iter()
+        .map(|record| make_pak_path(parse_pak_path(record.filename())))
+        .collect();
+
+    let iter = match walkdir(dir) {
+        Ok(iter) => iter,
+        Err(error) => return Err(Error::io_with_path(error, dir)),
+    };
+
+    for entry in iter {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => return Err(Error::io_with_path(error, dir)),
+        };
+        let file_path: PathBuf = entry.path();
+        let rel_path = match file_path.strip_prefix(dir) {
+            Ok(rel_path) => rel_path,
+            Err(_) => continue,
+        };
+        let filename = make_pak_path(rel_path.components()
+            .map(|comp| comp.as_os_str().to_string_lossy()));
+
+        if let Some(filter) = &filter {
+            if !filter.contains(&filename) {
+                continue;
+            }
+        }
+
+        if !pak_filenames.contains(&filename) {
+            differences.push(Difference::Extra { filename });
+        }
+    }
+
+    let thread_result = thread::scope::<_, Result<()>>(|scope| {
+        let (work_sender, work_receiver) = unbounded::<&Record>();
+        let (result_sender, result_receiver) = unbounded::<Result<Option<Difference>>>();
+
+        for _ in 0..options.thread_count.get() {
+            let work_receiver = work_receiver.clone();
+            let result_sender = result_sender.clone();
+            let mut in_file = File::open(&pak_path)?;
+            let encryption_key = &options.encryption_key;
+
+            scope.spawn(move |_| {
+                while let Ok(record) = work_receiver.recv() {
+                    let result = verify_record(pak, record, &mut in_file, dir, encryption_key);
+                    if result_sender.send(result).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        drop(work_receiver);
+        drop(result_sender);
+
+        for record in &records {
+            if let Err(error) = work_sender.send(record) {
+                return Err(Error::new(error.to_string()).with_path(record.filename()));
+            }
+        }
+
+        drop(work_sender);
+
+        while let Ok(result) = result_receiver.recv() {
+            if let Some(difference) = result? {
+                differences.push(difference);
+            }
+        }
+
+        Ok(())
+    });
+
+    match thread_result {
+        Err(error) => {
+            return Err(Error::new(format!("threading error: {:?}", error)));
+        }
+        Ok(result) => result?
+    }
+
+    differences.sort_by(|a, b| a.filename().cmp(b.filename()));
+
+    Ok(differences)
+}
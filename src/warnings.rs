@@ -0,0 +1,93 @@
+// This file is part of rust-u4pak.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Non-fatal problems found while reading a pak (e.g. unexpected values in
+// records or unreadable secondary index entries). Where they go is decided by
+// the application via Options::warnings.
+
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    // file name of the record the warning is about, if any
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}: {}", path, self.message)
+        } else {
+            self.message.fmt(f)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Warnings {
+    // log::warn!(), the default
+    Log,
+    Ignore,
+    Collect(Arc<Mutex<Vec<Warning>>>),
+    Callback(Arc<dyn Fn(&Warning) + Send + Sync>),
+}
+
+impl Default for Warnings {
+    #[inline]
+    fn default() -> Self {
+        Warnings::Log
+    }
+}
+
+impl std::fmt::Debug for Warnings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warnings::Log         => f.write_str("Log"),
+            Warnings::Ignore      => f.write_str("Ignore"),
+            Warnings::Collect(_)  => f.write_str("Collect"),
+            Warnings::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+impl Warnings {
+    #[inline]
+    pub fn collect() -> Self {
+        Warnings::Collect(Arc::new(Mutex::new(Vec::new())))
+    }
+
+    #[inline]
+    pub fn callback(callback: impl Fn(&Warning) + Send + Sync + 'static) -> Self {
+        Warnings::Callback(Arc::new(callback))
+    }
+
+    pub fn warn(&self, path: Option<&str>, message: String) {
+        let warning = Warning { path: path.map(str::to_string), message };
+        match self {
+            Warnings::Log => warn!("{}", warning),
+            Warnings::Ignore => {}
+            Warnings::Collect(warnings) => {
+                if let Ok(mut warnings) = warnings.lock() {
+                    warnings.push(warning);
+                }
+            }
+            Warnings::Callback(callback) => callback(&warning),
+        }
+    }
+
+    // The warnings collected so far. Always empty if not Warnings::Collect.
+    pub fn take(&self) -> Vec<Warning> {
+        match self {
+            Warnings::Collect(warnings) => warnings.lock()
+                .map(|mut warnings| std::mem::take(&mut *warnings))
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+}
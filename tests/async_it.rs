@@ -0,0 +1,113 @@
+mod util;
+
+use u4pak::pack::PackOptions;
+use u4pak::pak::COMPR_ZLIB;
+use u4pak::{ErrorKind, Pak, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_async_reader() -> Result<()> {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+    use u4pak::async_reader::{stream_record, AsyncReadAt};
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Arc::new(NoopWaker).into();
+        let mut context = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    // counts the requests to check that not the whole file is read
+    struct MemReader {
+        data: Vec<u8>,
+        bytes_read: usize,
+        request_count: usize,
+    }
+
+    impl AsyncReadAt for MemReader {
+        async fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+            let data = &self.data[(offset as usize).min(self.data.len())..];
+            let count = buf.len().min(data.len());
+            buf[..count].copy_from_slice(&data[..count]);
+            self.bytes_read += count;
+            self.request_count += 1;
+            Ok(count)
+        }
+
+        async fn size(&mut self) -> std::io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+    }
+
+    let work_dir = &util::work_dir("async_reader")?;
+
+    let data = compressible_data(300_000);
+    let big: Vec<u8> = (0..2_500_000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/big.bin", work_dir), &big)?;
+
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/a.txt:{}/src/a.txt", work_dir),
+        format!(":none,rename=/big.bin:{}/src/big.bin", work_dir),
+        format!(":none,encrypt,rename=/enc.bin:{}/src/big.bin", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions {
+        compression_method: COMPR_ZLIB,
+        encryption_key: Some(key.clone()),
+        ..PackOptions::default()
+    })?;
+
+    let mut reader = MemReader { data: std::fs::read(&pak_path)?, bytes_read: 0, request_count: 0 };
+    let pak = block_on(Pak::from_async_reader(&mut reader, u4pak::pak::Options::default()))?;
+    assert_eq!(pak.index().records().len(), 3);
+    assert!(reader.bytes_read < big.len());
+    // the footer and the index, which are both in the fetched tail
+    assert_eq!(reader.request_count, 1);
+
+    let record = pak.record("a.txt").expect("a.txt not found");
+    let mut out = Vec::new();
+    let mut chunks = 0;
+    block_on(stream_record(&pak, &mut reader, record, None, |chunk| {
+        chunks += 1;
+        out.extend_from_slice(chunk);
+        Ok(())
+    }))?;
+    assert_eq!(out, data);
+    assert_eq!(chunks, record.compression_blocks().as_ref().map_or(0, Vec::len));
+
+    // uncompressed records are streamed in bounded chunks, too
+    for (name, key) in [("big.bin", None), ("enc.bin", Some(key.clone()))] {
+        let record = pak.record(name).expect("record not found");
+        out.clear();
+        let mut chunks = 0;
+        block_on(stream_record(&pak, &mut reader, record, key, |chunk| {
+            assert!(chunk.len() <= 1024 * 1024);
+            chunks += 1;
+            out.extend_from_slice(chunk);
+            Ok(())
+        }))?;
+        assert_eq!(out, big);
+        assert_eq!(chunks, 3);
+    }
+
+    let record = pak.record("enc.bin").unwrap();
+    let error = block_on(stream_record(&pak, &mut reader, record, None, |_| Ok(()))).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::EncryptedNoKey);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
@@ -0,0 +1,436 @@
+mod util;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::path::Path;
+
+use u4pak::check::{analyze_layout, check, check_with_report, CheckErrorKind, CheckOptions, Gap};
+use u4pak::pack::{pack, PackOptions, PackPath};
+use u4pak::unpack::{unpack, UnpackOptions};
+use u4pak::verify::{verify, Difference, VerifyOptions};
+use u4pak::util::sha1_digest;
+use u4pak::{Pak, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+// v3 index record without compression blocks and filename
+const V3_INDEX_RECORD_SIZE: usize = 8 + 8 + 8 + 4 + 20 + 1 + 4;
+
+// fixes the index checksum in the footer after the index was modified
+fn update_index_sha1(pak: &Pak, pak_data: &mut [u8]) -> Result<()> {
+    let index_start = pak.index_offset() as usize;
+    let index_end = index_start + pak.index_size() as usize;
+    let index_sha1 = sha1_digest(&pak_data[index_start..index_end])?;
+    let footer_sha1_offset = pak_data.len() - 20;
+    pak_data[footer_sha1_offset..].copy_from_slice(&index_sha1);
+    Ok(())
+}
+
+#[test]
+fn test_pack_check_deep() -> Result<()> {
+    let work_dir = &util::work_dir("pack_check_deep")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    let deep_check = |path: &str, deep: bool| -> Result<usize> {
+        let pak = Pak::from_path(path, Default::default())?;
+        check(&pak, &mut File::open(path)?, CheckOptions {
+            deep,
+            ..CheckOptions::default()
+        })
+    };
+
+    assert_eq!(deep_check(&pak_path, true)?, 0);
+
+    // consistently wrong uncompressed size in the inline and index record
+    let mut pak_data = std::fs::read(&pak_path)?;
+    let index_field_offset = pak.index_offset() as usize + 4 + 1 + 4 + 4 + "a.txt".len() + 1 + 8 + 8;
+    let inline_field_offset = 8 + 8;
+    for &offset in &[index_field_offset, inline_field_offset] {
+        pak_data[offset..offset + 8].copy_from_slice(&(data.len() as u64 + 1).to_le_bytes());
+    }
+    update_index_sha1(&pak, &mut pak_data)?;
+    std::fs::write(&pak_path, &pak_data)?;
+
+    assert_eq!(deep_check(&pak_path, false)?, 0);
+    assert_eq!(deep_check(&pak_path, true)?, 1);
+
+    let broken = Pak::from_path(&pak_path, Default::default())?;
+    let errors = check_with_report(&broken, &mut File::open(&pak_path)?, CheckOptions {
+        deep: true,
+        ..CheckOptions::default()
+    })?;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, CheckErrorKind::SizeMismatch);
+    assert_eq!(errors[0].expected.as_deref(), Some((data.len() + 1).to_string().as_str()));
+    assert_eq!(errors[0].actual.as_deref(), Some(data.len().to_string().as_str()));
+    assert_eq!(errors[0].error.path().as_deref(), Some(Path::new("a.txt")));
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_check_wiped() -> Result<()> {
+    let work_dir = &util::work_dir("check_wiped")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..5000])?;
+    // really all zero, that is no error
+    write_file(format!("{}/src/zeroes.bin", work_dir), &[0u8; 3000])?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+        format!(":rename=/b.txt:{}/src/b.txt", work_dir),
+        format!(":rename=/zeroes.bin:{}/src/zeroes.bin", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+    assert_eq!(check(&pak, &mut File::open(&pak_path)?, CheckOptions::default())?, 0);
+
+    // wipe the whole region of a (inline header included) and the data of b
+    let mut bytes = std::fs::read(&pak_path)?;
+    for name in ["a.txt", "b.txt"] {
+        let record = pak.record(name).unwrap();
+        let (start, end) = pak.record_region(record);
+        let start = if name == "a.txt" { start } else { record.data_offset(pak.version(), pak.variant()) };
+        bytes[start as usize..end as usize].fill(0);
+    }
+    std::fs::write(&pak_path, &bytes)?;
+
+    for deep in [false, true] {
+        let mut errors = check_with_report(&pak, &mut File::open(&pak_path)?, CheckOptions {
+            deep,
+            ..CheckOptions::default()
+        })?;
+        errors.sort_by(|a, b| a.error.path().cmp(b.error.path()));
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.iter().all(|error| error.kind == CheckErrorKind::DataWiped), "{:?}", errors);
+        assert_eq!(errors[0].error.path().as_deref(), Some(Path::new("a.txt")));
+        assert_eq!(errors[1].error.path().as_deref(), Some(Path::new("b.txt")));
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_pack_check_layout() -> Result<()> {
+    let work_dir = &util::work_dir("pack_check_layout")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..1001])?;
+    write_file(format!("{}/src/c.txt", work_dir), &data[..5003])?;
+    write_file(format!("{}/src/d.txt", work_dir), &data[..7])?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+        format!(":rename=/b.txt:{}/src/b.txt", work_dir),
+        format!(":zlib,encrypt,rename=/c.txt:{}/src/c.txt", work_dir),
+        format!(":encrypt,rename=/d.txt:{}/src/d.txt", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        encryption_key: Some(base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap()),
+        ..PackOptions::default()
+    })?;
+
+    let (overlaps, gaps) = analyze_layout(&pak);
+    assert!(overlaps.is_empty(), "{:?}", overlaps);
+    assert!(gaps.is_empty(), "{:?}", gaps);
+
+    // move b one byte into a (offset field of the second index record)
+    let records = pak.index().records();
+    let mut pak_data = std::fs::read(&pak_path)?;
+    let field_offset = pak.index_offset() as usize + 4 + 1 + 4 +
+        4 + records[0].filename().len() + 1 + (V3_INDEX_RECORD_SIZE + 4 + records[0].compression_blocks().as_ref().unwrap().len() * 16) +
+        4 + records[1].filename().len() + 1;
+    pak_data[field_offset..field_offset + 8].copy_from_slice(&(records[1].offset() - 1).to_le_bytes());
+    update_index_sha1(&pak, &mut pak_data)?;
+    std::fs::write(&pak_path, &pak_data)?;
+
+    let broken = Pak::from_path(&pak_path, Default::default())?;
+    assert_eq!(broken.index().records()[1].offset(), records[1].offset() - 1);
+    let (overlaps, gaps) = analyze_layout(&broken);
+    assert_eq!(overlaps.len(), 1);
+    assert_eq!(overlaps[0].record.filename(), "b.txt");
+    assert_eq!(overlaps[0].other.filename(), "a.txt");
+    assert_eq!(overlaps[0].size, 1);
+    assert_eq!(gaps, vec![Gap { offset: records[2].offset() - 1, size: 1 }]);
+
+    let errors = check_with_report(&broken, &mut File::open(&pak_path)?, CheckOptions::default())?;
+    assert!(errors.iter().any(|error| error.kind == CheckErrorKind::Overlap), "{:?}", errors);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_check_deep_checksums() -> Result<()> {
+    let work_dir = &util::work_dir("check-deep-checksums")?;
+
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    let data = compressible_data(300_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..100_001])?;
+    write_file(format!("{}/src/c.txt", work_dir), &data[..5003])?;
+    write_file(format!("{}/src/d.txt", work_dir), &data[..7])?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+        format!(":rename=/b.txt:{}/src/b.txt", work_dir),
+        format!(":zlib,encrypt,rename=/c.txt:{}/src/c.txt", work_dir),
+        format!(":encrypt,rename=/d.txt:{}/src/d.txt", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        encryption_key: Some(key.clone()),
+        ..PackOptions::default()
+    })?;
+
+    let deep_check = || check_with_report(&pak, &mut File::open(&pak_path)?, CheckOptions {
+        deep: true,
+        encryption_key: Some(key.clone()),
+        ..CheckOptions::default()
+    });
+    let errors = deep_check()?;
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    // the data of b still decompresses, only the checksum is wrong
+    let b = pak.record("b.txt").unwrap();
+    let mut pak_data = std::fs::read(&pak_path)?;
+    let offset = (b.offset() + Pak::header_size(pak.version(), pak.variant(), b) + 1000) as usize;
+    pak_data[offset] ^= 0xFF;
+    std::fs::write(&pak_path, &pak_data)?;
+
+    let errors = deep_check()?;
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].kind, CheckErrorKind::ChecksumMismatch);
+    assert_eq!(errors[0].error.path().as_deref(), Some(Path::new("b.txt")));
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_max_memory() -> Result<()> {
+    use std::num::{NonZeroU64, NonZeroUsize};
+
+    let work_dir = &util::work_dir("max-memory")?;
+
+    // all files are bigger than the limit, so they are processed one at a time
+    let max_memory = NonZeroU64::new(16 * 1024);
+    let thread_count = NonZeroUsize::new(4).unwrap();
+    let mut paths: Vec<PackPath> = Vec::new();
+    let mut contents = Vec::new();
+    for index in 0..8 {
+        let data = compressible_data(40_000 + index * 1000);
+        write_file(format!("{}/files/{}.bin", work_dir, index), &data)?;
+        paths.push(format!(":zlib,rename=/{}.bin:{}/files/{}.bin", index, work_dir, index).as_str().try_into()?);
+        contents.push(data);
+    }
+
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let pak = pack(&pak_path, &paths, PackOptions {
+        thread_count,
+        max_memory,
+        ..PackOptions::default()
+    })?;
+
+    let error_count = check(&pak, &mut File::open(&pak_path)?, CheckOptions {
+        thread_count,
+        max_memory,
+        deep: true,
+        ..CheckOptions::default()
+    })?;
+    assert_eq!(error_count, 0);
+
+    let out_dir = format!("{}/out", work_dir);
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        thread_count,
+        max_memory,
+        ..UnpackOptions::default()
+    })?;
+    for (index, data) in contents.iter().enumerate() {
+        assert_eq!(&std::fs::read(format!("{}/{}.bin", out_dir, index))?, data);
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_io_strategies() -> Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::num::NonZeroUsize;
+    use u4pak::pread::{IoStrategy, PakReader};
+
+    let work_dir = &util::work_dir("io-strategies")?;
+
+    let data = compressible_data(300_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..100_001])?;
+    write_file(format!("{}/src/c.txt", work_dir), &data[..7])?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir).as_str().try_into()?,
+        format!(":rename=/b.txt:{}/src/b.txt", work_dir).as_str().try_into()?,
+        format!(":zlib,rename=/c.txt:{}/src/c.txt", work_dir).as_str().try_into()?,
+    ];
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+    let pak_data = std::fs::read(&pak_path)?;
+
+    for io_strategy in IoStrategy::ALL.iter().cloned() {
+        // smaller than the compression blocks, so both paths of the reader are used
+        for read_size in [1000, 1 << 20] {
+            let read_size = NonZeroUsize::new(read_size).unwrap();
+
+            let mut reader = PakReader::new(File::open(&pak_path)?, io_strategy, read_size);
+            let mut buf = [0u8; 100];
+            reader.seek(SeekFrom::Start(500))?;
+            reader.read_exact(&mut buf)?;
+            assert_eq!(&buf[..], &pak_data[500..600]);
+            reader.seek(SeekFrom::Current(-50))?;
+            reader.read_exact(&mut buf)?;
+            assert_eq!(&buf[..], &pak_data[550..650]);
+            reader.seek(SeekFrom::End(-100))?;
+            reader.read_exact(&mut buf)?;
+            assert_eq!(&buf[..], &pak_data[pak_data.len() - 100..]);
+            assert_eq!(reader.read(&mut buf)?, 0);
+
+            let errors = check_with_report(&pak, &mut File::open(&pak_path)?, CheckOptions {
+                deep: true,
+                io_strategy,
+                read_size,
+                ..CheckOptions::default()
+            })?;
+            assert!(errors.is_empty(), "{:?}", errors);
+
+            let out_dir = format!("{}/out-{}-{}", work_dir, io_strategy.name(), read_size);
+            unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+                io_strategy,
+                read_size,
+                ..UnpackOptions::default()
+            })?;
+            assert_eq!(std::fs::read(format!("{}/a.txt", out_dir))?, data);
+            assert_eq!(std::fs::read(format!("{}/b.txt", out_dir))?, &data[..100_001]);
+            assert_eq!(std::fs::read(format!("{}/c.txt", out_dir))?, &data[..7]);
+        }
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_pack_verify() -> Result<()> {
+    let work_dir = &util::work_dir("pack_verify")?;
+
+    let data = compressible_data(4096);
+    write_file(format!("{}/src/same.txt", work_dir), &data)?;
+    write_file(format!("{}/src/sub/changed.txt", work_dir), &data)?;
+    write_file(format!("{}/src/sub/removed.txt", work_dir), &data)?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":zlib,rename=/:{}/src", work_dir).as_str().try_into()?,
+    ];
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+
+    let src_dir = format!("{}/src", work_dir);
+    let differences = verify(&pak, &mut File::open(&pak_path)?, &src_dir, VerifyOptions::default())?;
+    assert_eq!(differences, vec![]);
+
+    write_file(format!("{}/src/sub/changed.txt", work_dir), b"changed")?;
+    write_file(format!("{}/src/extra.txt", work_dir), b"extra")?;
+    std::fs::remove_file(format!("{}/src/sub/removed.txt", work_dir))?;
+
+    let differences = verify(&pak, &mut File::open(&pak_path)?, &src_dir, VerifyOptions::default())?;
+    let summary: Vec<(&str, &str)> = differences.iter().map(|difference| match difference {
+        Difference::Missing { filename } => ("missing", filename.as_str()),
+        Difference::Extra { filename } => ("extra", filename.as_str()),
+        Difference::Mismatch { filename, .. } => ("mismatch", filename.as_str()),
+    }).collect();
+    assert_eq!(summary, vec![
+        ("extra", "extra.txt"),
+        ("mismatch", "sub/changed.txt"),
+        ("missing", "sub/removed.txt"),
+    ]);
+
+    let differences = verify(&pak, &mut File::open(&pak_path)?, &src_dir, VerifyOptions {
+        paths: Some(&["same.txt"]),
+        ..VerifyOptions::default()
+    })?;
+    assert_eq!(differences, vec![]);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_signature() -> Result<()> {
+    use openssl::rsa::Rsa;
+    use u4pak::sig::{check_signature, read_private_key, read_public_key, sign, signature_path, Signature, SIG_CHUNK_SIZE};
+    use u4pak::warnings::Warnings;
+
+    let work_dir = &util::work_dir("signature")?;
+
+    write_file(format!("{}/src/big.bin", work_dir), &compressible_data(3 * SIG_CHUNK_SIZE))?;
+    write_file(format!("{}/src/small.txt", work_dir), b"small")?;
+
+    let key = Rsa::generate(2048).unwrap();
+    let private_key_path = format!("{}/private.pem", work_dir);
+    let public_key_path = format!("{}/public.pem", work_dir);
+    std::fs::write(&private_key_path, key.private_key_to_pem().unwrap())?;
+    std::fs::write(&public_key_path, key.public_key_to_pem().unwrap())?;
+
+    let pak_path = format!("{}/signed.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    let signature = sign(&pak_path, &read_private_key(&private_key_path)?)?;
+    let pak_size = std::fs::metadata(&pak_path)?.len() as usize;
+    assert_eq!(signature.chunk_hashes().len(), pak_size.div_ceil(SIG_CHUNK_SIZE));
+    assert_eq!(Signature::from_path(signature_path(&pak_path))?, signature);
+
+    // a private key file works for checking, too
+    for key_path in [&public_key_path, &private_key_path] {
+        let errors = check_signature(&pak_path, signature_path(&pak_path), &read_public_key(key_path)?, &Warnings::Ignore)?;
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    let public_key = read_public_key(&public_key_path)?;
+    let mut pak_data = std::fs::read(&pak_path)?;
+    pak_data[SIG_CHUNK_SIZE + 10] ^= 0xFF;
+    std::fs::write(&pak_path, &pak_data)?;
+    let warnings = Warnings::collect();
+    let errors = check_signature(&pak_path, signature_path(&pak_path), &public_key, &warnings)?;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, CheckErrorKind::SignatureMismatch);
+    let collected = warnings.take();
+    assert_eq!(collected.len(), 1, "{:?}", collected);
+    assert_eq!(collected[0].path.as_deref(), Some(pak_path.as_str()));
+
+    // signed with another key
+    let other_key = Rsa::generate(2048).unwrap();
+    std::fs::write(&public_key_path, other_key.public_key_to_pem().unwrap())?;
+    assert!(check_signature(&pak_path, signature_path(&pak_path), &read_public_key(&public_key_path)?, &Warnings::Ignore).is_err());
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
@@ -0,0 +1,344 @@
+mod util;
+
+use std::fs::File;
+
+use u4pak::check::{check_with_report, CheckOptions};
+use u4pak::pack::PackOptions;
+use u4pak::pak::{COMPR_NONE, COMPR_ZLIB};
+use u4pak::util::sha1_digest;
+use u4pak::{Pak, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_pack_encrypt() -> Result<()> {
+    use std::fs::File;
+    use u4pak::check::{check, CheckOptions};
+
+    let work_dir = &util::work_dir("pack_encrypt")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/secret/small.txt", work_dir), &data[..1000])?;
+    write_file(format!("{}/secret/compressed.txt", work_dir), &data)?;
+    write_file(format!("{}/plain/a.txt", work_dir), &data[..1000])?;
+
+    let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,block_size=4K,min_size=2K,rename=/secret:{}/secret", work_dir),
+        format!(":no_encrypt,rename=/plain:{}/plain", work_dir),
+    ];
+
+    // too old for encryption
+    assert!(util::pack(&pak_path, &paths, PackOptions {
+        version: 2,
+        encrypted: true,
+        encryption_key: Some(base64::decode(key).unwrap()),
+        ..PackOptions::default()
+    }).is_err());
+
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        version: 3,
+        encrypted: true,
+        encryption_key: Some(base64::decode(key).unwrap()),
+        ..PackOptions::default()
+    })?;
+
+    for record in pak.index().records() {
+        let filename = record.filename();
+        assert_eq!(record.encrypted(), filename.starts_with("secret/"), "{}", filename);
+        let expected = if filename == "secret/compressed.txt" { COMPR_ZLIB } else { COMPR_NONE };
+        assert_eq!(record.compression_method(), expected, "{}", filename);
+    }
+
+    // the checksums and the padded compression blocks are consistent
+    let pak = Pak::from_path(&pak_path, Default::default())?;
+    let error_count = check(&pak, &mut File::open(&pak_path)?, CheckOptions {
+        abort_on_error: true,
+        ..CheckOptions::default()
+    })?;
+    assert_eq!(error_count, 0);
+
+    let out_dir = format!("{}/unpacked", work_dir);
+    util::unpack(&pak_path, &out_dir, Some(key.to_string()))?;
+    util::validate(&format!("{}/secret", work_dir), &format!("{}/secret", out_dir))?;
+    util::validate(&format!("{}/plain", work_dir), &format!("{}/plain", out_dir))?;
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_keyring() -> Result<()> {
+    use u4pak::pak::Options;
+    use u4pak::Keyring;
+
+    let work_dir = &util::work_dir("keyring")?;
+
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    let other_key = vec![0x42u8; 32];
+    let guid: u128 = 0x0123456789ABCDEF0011223344556677;
+
+    let keyring = Keyring::parse(&format!(
+        "# comment\n\
+         00000000-00000000-00000000-00000000: 0x{}\n\
+         \n\
+         {:032X} MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=  # the right one\n",
+        "42".repeat(32), guid))?;
+    assert_eq!(keyring.len(), 2);
+    assert_eq!(keyring.get(0), Some(&other_key));
+    assert_eq!(keyring.get(guid), Some(&key));
+
+    assert!(Keyring::parse("0123 MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").is_err());
+    assert!(Keyring::parse(&format!("{:032X} 0x1234", guid)).is_err());
+    assert!(Keyring::parse(&format!("{:032X}", guid)).is_err());
+    let error = Keyring::parse(&format!("{0:032X} 0x{1}\n{0:032X} 0x{1}", guid, "42".repeat(32))).unwrap_err();
+    assert!(error.to_string().contains("line 2"), "{}", error);
+
+    write_file(format!("{}/src/secret.txt", work_dir), &compressible_data(10_000))?;
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,encrypt,rename=/secret.txt:{}/src/secret.txt", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions {
+        version: 7,
+        encryption_key: Some(key.clone()),
+        ..PackOptions::default()
+    })?;
+
+    // the GUID is the first field of the version 7 footer
+    let footer_offset = Pak::from_path(&pak_path, Options::default())?.footer().unwrap().footer_offset() as usize;
+    let mut pak_data = std::fs::read(&pak_path)?;
+    pak_data[footer_offset..footer_offset + 16].copy_from_slice(&guid.to_le_bytes());
+    std::fs::write(&pak_path, &pak_data)?;
+
+    let options = Options {
+        encryption_key: Some(other_key.clone()),
+        keyring,
+        ..Options::default()
+    };
+    let pak = Pak::from_path(&pak_path, options.clone())?;
+    let footer = pak.footer().unwrap();
+    assert_eq!(footer.encryption_guid(), guid);
+    assert_eq!(options.encryption_key_for(footer), Some(&key));
+
+    // a GUID that isn't in the keyring falls back to --encryption-key
+    let options = Options {
+        keyring: Keyring::new(),
+        ..options
+    };
+    assert_eq!(options.encryption_key_for(footer), Some(&other_key));
+
+    let errors = check_with_report(&pak, &mut File::open(&pak_path)?, CheckOptions {
+        deep: true,
+        encryption_key: Some(key),
+        ..CheckOptions::default()
+    })?;
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_cbc_cipher() -> Result<()> {
+    use std::sync::Arc;
+    use u4pak::decrypt::{parse_cipher, Cbc, Cipher};
+    use u4pak::entry::{read_record_range, unpack_record_to_writer};
+    use u4pak::pak::Options;
+
+    let work_dir = &util::work_dir("cbc_cipher")?;
+
+    assert_eq!(parse_cipher("ecb")?.name(), "ecb");
+    assert_eq!(parse_cipher("CBC")?.name(), "cbc");
+    assert_eq!(parse_cipher("cbc:000102030405060708090a0b0c0d0e0f")?.name(), "cbc:000102030405060708090A0B0C0D0E0F");
+    assert!(parse_cipher("cbc:0001").is_err());
+    assert!(parse_cipher("ecb:000102030405060708090a0b0c0d0e0f").is_err());
+    assert!(parse_cipher("ctr").is_err());
+
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    let iv: [u8; 16] = [0x10, 0x32, 0x54, 0x76, 0x98, 0xBA, 0xDC, 0xFE, 1, 2, 3, 4, 5, 6, 7, 8];
+    let cipher: Arc<dyn Cipher> = Arc::new(Cbc::new(iv));
+
+    // bigger than the chunks uncompressed records are read in
+    let big: Vec<u8> = (0..3_000_000u32).map(|index| (index * 7 + index / 251) as u8).collect();
+    let small = compressible_data(300_000);
+    write_file(format!("{}/src/big.bin", work_dir), &big)?;
+    write_file(format!("{}/src/small.txt", work_dir), &small)?;
+
+    let paths = [
+        format!(":encrypt,rename=/big.bin:{}/src/big.bin", work_dir),
+        format!(":zlib,encrypt,rename=/small.txt:{}/src/small.txt", work_dir),
+
+    ];
+    let pak_path = format!("{}/cbc.pak", work_dir);
+    let ecb_path = format!("{}/ecb.pak", work_dir);
+    util::pack(&pak_path, &paths, PackOptions {
+        version: 7,
+        encryption_key: Some(key.clone()),
+        cipher: cipher.clone(),
+        ..PackOptions::default()
+    })?;
+    util::pack(&ecb_path, &paths, PackOptions {
+        version: 7,
+        encryption_key: Some(key.clone()),
+        ..PackOptions::default()
+    })?;
+    assert_ne!(std::fs::read(&pak_path)?, std::fs::read(&ecb_path)?);
+
+    let pak = Pak::from_path(&pak_path, Options {
+        cipher: cipher.clone(),
+        ..Options::default()
+    })?;
+    assert_eq!(pak.cipher().name(), "cbc:1032547698BADCFE0102030405060708");
+
+    let mut file = File::open(&pak_path)?;
+    for (name, expected) in [("big.bin", &big), ("small.txt", &small)] {
+        let record = pak.record(name).unwrap();
+        let mut data = Vec::new();
+        unpack_record_to_writer(record, pak.version(), pak.variant(), &mut file, &mut data, Some(key.clone()), pak.cipher())?;
+        assert!(data == *expected, "{}", name);
+
+        let data = read_record_range(record, pak.version(), pak.variant(), &mut file, 1_234_567, 1000, Some(key.clone()), pak.cipher())?;
+        let end = expected.len().min(1_234_567 + 1000);
+        assert_eq!(data, &expected[1_234_567.min(end)..end], "{}", name);
+
+        // crossing the chunks uncompressed records are read in
+        let data = read_record_range(record, pak.version(), pak.variant(), &mut file, 1_000_003, 1_500_000, Some(key.clone()), pak.cipher())?;
+        let end = expected.len().min(1_000_003 + 1_500_000);
+        assert!(data == expected[1_000_003.min(end)..end], "{}", name);
+    }
+
+    let errors = check_with_report(&pak, &mut file, CheckOptions {
+        deep: true,
+        encryption_key: Some(key.clone()),
+        ..CheckOptions::default()
+    })?;
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    // read with the wrong cipher the data is garbage
+    let pak = Pak::from_path(&pak_path, Options::default())?;
+    let record = pak.record("big.bin").unwrap();
+    let mut data = Vec::new();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), &mut file, &mut data, Some(key), pak.cipher())?;
+    assert!(data != big);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+// pack doesn't encrypt the index, so this rewrites the version 7 pak at
+// pak_path with an encrypted index
+fn encrypt_index(pak_path: &str, pak: &Pak, key: &[u8]) -> Result<()> {
+    use u4pak::decrypt::encrypt;
+    use u4pak::pak::PAK_MAGIC;
+
+    let data = std::fs::read(pak_path)?;
+    let index_offset = pak.index_offset() as usize;
+    let mut index = data[index_offset..index_offset + pak.index_size() as usize].to_vec();
+    index.resize(index.len().div_ceil(16) * 16, 0);
+    let index_sha1 = sha1_digest(&index[..])?;
+    encrypt(&mut index, key);
+
+    let mut encrypted = data[..index_offset].to_vec();
+    encrypted.extend_from_slice(&index);
+    encrypted.extend_from_slice(&[0u8; 16]);
+    encrypted.push(1);
+    encrypted.extend_from_slice(&PAK_MAGIC.to_le_bytes());
+    encrypted.extend_from_slice(&7u32.to_le_bytes());
+    encrypted.extend_from_slice(&(index_offset as u64).to_le_bytes());
+    encrypted.extend_from_slice(&(index.len() as u64).to_le_bytes());
+    encrypted.extend_from_slice(&index_sha1);
+    std::fs::write(pak_path, &encrypted)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_keycheck() -> Result<()> {
+    use u4pak::keycheck::{check_keys, plausible_mount_point};
+    use u4pak::pak::Options;
+
+    let work_dir = &util::work_dir("keycheck")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), b"hello")?;
+    let paths = [
+        format!(":rename=/Game/a.txt:{}/src/a.txt", work_dir),
+    ];
+    let pak_path = format!("{}/out.pak", work_dir);
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        version: 7,
+        mount_point: Some("../../../"),
+        ..PackOptions::default()
+    })?;
+
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    encrypt_index(&pak_path, &pak, &key)?;
+
+    let wrong_key = vec![0x42u8; 32];
+    let results = check_keys(&mut File::open(&pak_path)?, &Options::default(), &[wrong_key, key.clone()])?;
+    assert!(!results[0].works(), "{:?}", results[0]);
+    assert!(results[1].sha1_matches);
+    assert_eq!(results[1].mount_point.as_deref(), Some("../../../"));
+    assert!(results[1].works());
+
+    assert!(check_keys(&mut File::open(&pak_path)?, &Options::default(), &[vec![1, 2, 3]]).is_err());
+
+    let pak = Pak::from_path(&pak_path, Options {
+        encryption_key: Some(key),
+        ..Options::default()
+    })?;
+    assert!(pak.footer().unwrap().encrypted_index());
+    assert!(pak.record("Game/a.txt").is_some());
+
+    assert_eq!(plausible_mount_point(b"\x04\0\0\0abc\0").as_deref(), Some("abc"));
+    assert_eq!(plausible_mount_point(b"\x04\0\0\0abc!"), None);
+    assert_eq!(plausible_mount_point(b"\x04\0\0\0a\x01c\0"), None);
+    assert_eq!(plausible_mount_point(b"\xFE\xFF\xFF\xFFa\0\0\0").as_deref(), Some("a"));
+    assert_eq!(plausible_mount_point(b"\xFF\xFF\xFF\x7F"), None);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_keycheck_wordlist() -> Result<()> {
+    use std::num::NonZeroUsize;
+    use u4pak::keycheck::search_keys;
+    use u4pak::pak::Options;
+
+    let work_dir = &util::work_dir("keycheck_wordlist")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), b"hello")?;
+    let paths = [
+        format!(":rename=/Game/a.txt:{}/src/a.txt", work_dir),
+    ];
+    let pak_path = format!("{}/out.pak", work_dir);
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        version: 7,
+        mount_point: Some("../../../"),
+        ..PackOptions::default()
+    })?;
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    encrypt_index(&pak_path, &pak, &key)?;
+
+    let mut keys: Vec<Vec<u8>> = (0..1000u32)
+        .map(|index| index.to_le_bytes().iter().cycle().take(32).cloned().collect())
+        .collect();
+    keys[777] = key;
+
+    for thread_count in [1, 4, 2000] {
+        let found = search_keys(&mut File::open(&pak_path)?, &Options::default(), &keys, NonZeroUsize::new(thread_count).unwrap())?;
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert_eq!(found[0].0, 777);
+        assert!(found[0].1.sha1_matches);
+    }
+
+    assert!(search_keys(&mut File::open(&pak_path)?, &Options::default(), &keys[..777], NonZeroUsize::new(4).unwrap())?.is_empty());
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
@@ -0,0 +1,227 @@
+mod util;
+
+use std::convert::TryInto;
+use std::fs::File;
+
+use u4pak::entry::unpack_record_to_writer;
+use u4pak::check::{check, CheckOptions};
+use u4pak::pack::{pack, PackOptions, PackPath};
+use u4pak::pak::{COMPR_NONE, COMPR_ZLIB};
+use u4pak::rename::{rename, set_mount_point};
+use u4pak::slack::{copy_slack, find_slack, SlackLocation};
+use u4pak::{ErrorKind, Pak, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_rename() -> Result<()> {
+    let work_dir = &util::work_dir("rename")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/dir/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/dir/b.txt", work_dir), &data[..1000])?;
+    write_file(format!("{}/src/c.txt", work_dir), &data[..10])?;
+
+    let pak_path = format!("{}/test.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        version: 7,
+        ..PackOptions::default()
+    })?;
+    let data_before = std::fs::read(&pak_path)?[..pak.index_offset() as usize].to_vec();
+
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).open(&pak_path)?;
+    let (renamed_pak, renamed) = rename(&pak, &mut file, "/dir/", "other/place", Default::default())?;
+    assert_eq!(renamed.len(), 2);
+    assert!(renamed.iter().all(|record| record.new_filename == record.old_filename.replace("dir/", "other/place/")));
+
+    let error = rename(&renamed_pak, &mut file, "c.txt", "other/place/b.txt", Default::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    let error = rename(&renamed_pak, &mut file, "dir", "foo", Default::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::PathNotFound);
+    drop(file);
+
+    let reread = Pak::from_path(&pak_path, Default::default())?;
+    let mut filenames: Vec<&str> = reread.index().records().iter().map(|record| record.filename()).collect();
+    filenames.sort_unstable();
+    assert_eq!(filenames, ["c.txt", "other/place/a.txt", "other/place/b.txt"]);
+    assert_eq!(check(&reread, &mut File::open(&pak_path)?, CheckOptions::default())?, 0);
+    assert_eq!(&std::fs::read(&pak_path)?[..pak.index_offset() as usize], &data_before[..]);
+
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).open(&pak_path)?;
+    set_mount_point(&reread, &mut file, "../../../Game/", Default::default())?;
+    drop(file);
+
+    let reread = Pak::from_path(&pak_path, Default::default())?;
+    assert_eq!(reread.index().mount_point(), Some("../../../Game/"));
+    assert_eq!(reread.index().records().len(), 3);
+    assert_eq!(check(&reread, &mut File::open(&pak_path)?, CheckOptions::default())?, 0);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_slack() -> Result<()> {
+    let work_dir = &util::work_dir("slack")?;
+
+    let data = compressible_data(1000);
+    write_file(format!("{}/src/a.txt", work_dir), &data[..100])?;
+    write_file(format!("{}/src/b.txt", work_dir), &data)?;
+
+    let pak_path = format!("{}/test.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions {
+        version: 3,
+        alignment: std::num::NonZeroU64::new(4096),
+        ..PackOptions::default()
+    })?;
+
+    let mut pak_data = std::fs::read(&pak_path)?;
+    pak_data.extend_from_slice(b"appended junk");
+    std::fs::write(&pak_path, &pak_data)?;
+
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options {
+        scan_footer: Some(1000),
+        ..Default::default()
+    })?;
+    let mut file = File::open(&pak_path)?;
+    let slack = find_slack(&pak, &mut file)?;
+
+    let padding: Vec<_> = slack.iter().filter(|region| region.location == SlackLocation::Data).collect();
+    assert_eq!(padding.len(), 1);
+    assert!(padding.iter().all(|region| region.zeroed));
+    assert_eq!(padding.iter().map(|region| region.size).sum::<u64>(),
+        pak.index_offset() - pak.index().records().iter().map(|record| pak.record_region(record).1 - pak.record_region(record).0).sum::<u64>());
+
+    let appended = slack.last().unwrap();
+    assert_eq!(appended.location, SlackLocation::Appended);
+    assert!(!appended.zeroed);
+    let mut dumped = Vec::new();
+    copy_slack(&mut file, appended, &mut dumped)?;
+    assert_eq!(dumped, b"appended junk");
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_patch() -> Result<()> {
+    use u4pak::patch::{patch, PatchSummary};
+
+    let work_dir = &util::work_dir("patch")?;
+
+    let big = compressible_data(100_000);
+    write_file(format!("{}/base/Content/same.txt", work_dir), &big)?;
+    write_file(format!("{}/base/Content/raw.bin", work_dir), b"raw data")?;
+    write_file(format!("{}/base/Content/changed.txt", work_dir), b"old")?;
+
+    let base_path = format!("{}/Game.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":rename=/Game:{}/base", work_dir).as_str().try_into()?,
+    ];
+    let base = pack(&base_path, &paths, PackOptions {
+        compression_method: COMPR_ZLIB,
+        mount_point: Some("../../../"),
+        ..PackOptions::default()
+    })?;
+    // compared by decompressing and by the index SHA-1
+    assert_eq!(base.record("Game/Content/same.txt").unwrap().compression_method(), COMPR_ZLIB);
+    assert_eq!(base.record("Game/Content/raw.bin").unwrap().compression_method(), COMPR_NONE);
+
+    write_file(format!("{}/mod/Content/same.txt", work_dir), &big)?;
+    write_file(format!("{}/mod/Content/raw.bin", work_dir), b"raw data")?;
+    write_file(format!("{}/mod/Content/changed.txt", work_dir), b"new")?;
+    write_file(format!("{}/mod/Content/added.txt", work_dir), b"added")?;
+
+    let patch_path = format!("{}/Game_P.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":rename=/Game:{}/mod", work_dir).as_str().try_into()?,
+    ];
+    let mut base_file = File::open(&base_path)?;
+    let (pak, summary) = patch(&base, &mut base_file, &patch_path, &paths, None, PackOptions {
+        mount_point: base.index().mount_point(),
+        ..PackOptions::default()
+    })?;
+
+    assert_eq!(summary, PatchSummary { added: 1, changed: 1, unchanged: 2 });
+    let mut filenames: Vec<&str> = pak.index().records().iter().map(|record| record.filename()).collect();
+    filenames.sort_unstable();
+    assert_eq!(filenames, ["Game/Content/added.txt", "Game/Content/changed.txt"]);
+
+    let pak = Pak::from_path(&patch_path, u4pak::pak::Options::default())?;
+    assert_eq!(pak.index().mount_point(), Some("../../../"));
+    let record = pak.record("Game/Content/changed.txt").unwrap();
+    let mut data = Vec::new();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), &mut File::open(&patch_path)?, &mut data, None, pak.cipher())?;
+    assert_eq!(data, b"new");
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_merged_view() -> Result<()> {
+    use u4pak::merge::{load_order, pak_priority};
+    use u4pak::MergedView;
+
+    assert_eq!(pak_priority("Game.pak"), 0);
+    assert_eq!(pak_priority("Paks/Game_P.pak"), 100);
+    assert_eq!(pak_priority("pakchunk0_1_P.pak"), 200);
+    assert_eq!(pak_priority("pakchunk0_2_P.pak"), 300);
+    assert_eq!(pak_priority("pakchunk0_0_P.pak"), 100);
+    assert_eq!(load_order(&["b_P.pak", "a.pak", "c.pak"]), [1, 2, 0]);
+
+    let work_dir = &util::work_dir("merged-view")?;
+
+    write_file(format!("{}/base/a.txt", work_dir), b"base a")?;
+    write_file(format!("{}/base/b.txt", work_dir), b"base b")?;
+    write_file(format!("{}/other/b.txt", work_dir), b"other b")?;
+    write_file(format!("{}/other/c.txt", work_dir), b"other c")?;
+    write_file(format!("{}/patch/a.txt", work_dir), b"patch a")?;
+    write_file(format!("{}/patch/b.txt", work_dir), b"patch b")?;
+
+    let names = [
+        format!("{}/Game_P.pak", work_dir),
+        format!("{}/Game.pak", work_dir),
+        format!("{}/Other.pak", work_dir),
+    ];
+    let mut paks = Vec::new();
+    for (name, dir) in names.iter().zip(["patch", "base", "other"]) {
+        let paths: Vec<PackPath> = vec![
+            format!(":rename=/:{}/{}", work_dir, dir).as_str().try_into()?,
+        ];
+        paks.push(pack(name, &paths, PackOptions {
+            mount_point: Some("../../../Game/"),
+            ..PackOptions::default()
+        })?);
+    }
+
+    let view = MergedView::new(&paks, &names);
+    assert_eq!(view.load_order(), [1, 2, 0]);
+    assert_eq!(view.mount_point(), Some("../../../Game/"));
+
+    let mut effective: Vec<(&str, usize)> = view.effective().iter()
+        .map(|entry| (entry.path.as_str(), entry.package))
+        .collect();
+    effective.sort_unstable();
+    assert_eq!(effective, [("a.txt", 0), ("b.txt", 0), ("c.txt", 2)]);
+
+    let mut shadowed: Vec<(&str, usize, usize)> = view.shadowed().iter()
+        .map(|shadowed| (shadowed.entry.path.as_str(), shadowed.entry.package, shadowed.overridden_by))
+        .collect();
+    shadowed.sort_unstable();
+    assert_eq!(shadowed, [("a.txt", 1, 0), ("b.txt", 1, 0), ("b.txt", 2, 0)]);
+
+    let pak = view.to_pak();
+    assert_eq!(pak.index().records().len(), 3);
+    assert_eq!(pak.record("b.txt").unwrap().sha1(), paks[0].record("b.txt").unwrap().sha1());
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
@@ -0,0 +1,794 @@
+mod util;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::path::Path;
+
+use u4pak::info::extension_stats;
+use u4pak::entry::unpack_record_to_writer;
+use u4pak::pack::{pack, PackOptions, PackPath};
+use u4pak::pak::COMPR_ZLIB;
+use u4pak::ranges::{byte_ranges, merge_ranges};
+use u4pak::unpack::{unpack, UnpackOptions};
+use u4pak::util::sha1_digest;
+use u4pak::{DirTree, ErrorKind, Pak, Record, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_record_iteration() -> Result<()> {
+    let work_dir = &util::work_dir("record_iteration")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), b"a")?;
+    write_file(format!("{}/src/b.txt", work_dir), b"bb")?;
+    write_file(format!("{}/src/c/d.txt", work_dir), b"ddd")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions::default())?;
+    let mut pak = Pak::from_path(&pak_path, Default::default())?;
+
+    let mut names: Vec<&str> = pak.index().iter().map(|record| record.filename()).collect();
+    let mut borrowed = Vec::new();
+    for record in pak.index() {
+        borrowed.push(record.filename());
+    }
+    assert_eq!(names, borrowed);
+    names.sort_unstable();
+    assert_eq!(names, ["a.txt", "b.txt", "c/d.txt"]);
+
+    // the lookup by path follows renames
+    assert!(pak.record("b.txt").is_some());
+    for record in pak.records_mut() {
+        let filename = record.filename().to_uppercase();
+        record.set_filename(filename);
+    }
+    assert!(pak.record("b.txt").is_none());
+    assert_eq!(pak.record("B.TXT").map(|record| record.size()), Some(2));
+
+    let owned: Vec<Record> = pak.into_index().into_iter().collect();
+    let mut names: Vec<&str> = owned.iter().map(Record::filename).collect();
+    names.sort_unstable();
+    assert_eq!(names, ["A.TXT", "B.TXT", "C/D.TXT"]);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_pak_path_lookup() -> Result<()> {
+    let work_dir = &util::work_dir("pak_path_lookup")?;
+
+    for name in &["dir/a.txt", "dir/sub/b.txt", "dir-x/c.txt", "dir.txt", "z.txt"] {
+        write_file(format!("{}/src/{}", work_dir, name), name.as_bytes())?;
+    }
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+    let names = |records: Vec<&u4pak::Record>| -> Vec<String> {
+        let mut names: Vec<String> = records.iter().map(|record| record.filename().to_string()).collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(pak.record("dir/sub/b.txt").map(|record| record.filename()), Some("dir/sub/b.txt"));
+    assert_eq!(pak.record("/dir//sub/b.txt/").map(|record| record.filename()), Some("dir/sub/b.txt"));
+    assert!(pak.record("dir/sub").is_none());
+    assert!(pak.record("missing.txt").is_none());
+
+    assert_eq!(names(pak.records_under("dir")), vec!["dir/a.txt", "dir/sub/b.txt"]);
+    assert_eq!(names(pak.records_under("/dir/sub/")), vec!["dir/sub/b.txt"]);
+    assert_eq!(names(pak.records_under("z.txt")), vec!["z.txt"]);
+    assert_eq!(pak.records_under("/").len(), 5);
+    assert!(pak.records_under("di").is_empty());
+
+    let mut filter = u4pak::Filter::from_paths(["dir", "z.txt", "missing"].iter().cloned());
+    let filtered = pak.filter_records(&mut filter);
+    let expected: Vec<&u4pak::Record> = pak.index().records().iter()
+        .filter(|record| record.filename().starts_with("dir/") || record.filename() == "z.txt")
+        .collect();
+    assert_eq!(filtered.len(), expected.len());
+    assert!(filtered.iter().zip(expected.iter()).all(|(a, b)| std::ptr::eq(*a, *b)));
+    assert_eq!(filter.non_visited_paths().collect::<Vec<_>>(), vec!["/missing"]);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_dir_tree() -> Result<()> {
+    let work_dir = &util::work_dir("dir_tree")?;
+
+    write_file(format!("{}/src/a/b/x.txt", work_dir), b"x")?;
+    write_file(format!("{}/src/a/y.txt", work_dir), b"yy")?;
+    write_file(format!("{}/src/z.txt", work_dir), b"zzz")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+    let tree = DirTree::new(pak.index().records())?;
+
+    assert_eq!(tree.root().file_count(), 3);
+    assert_eq!(tree.root().uncompressed_size(), 6);
+
+    let a = tree.lookup("/a/").unwrap();
+    assert!(tree.get(a).unwrap().is_dir());
+    assert_eq!(tree.get(a).unwrap().file_count(), 2);
+    assert_eq!(tree.get(a).unwrap().uncompressed_size(), 3);
+
+    let names: Vec<&str> = tree.sorted_children(a).into_iter()
+        .map(|node| tree.get(node).unwrap().name())
+        .collect();
+    assert_eq!(names, vec!["b", "y.txt"]);
+
+    let x = tree.lookup("a/b/x.txt").unwrap();
+    assert_eq!(tree.get(x).unwrap().record().map(|record| record.filename()), Some("a/b/x.txt"));
+    assert_eq!(tree.path(x), "a/b/x.txt");
+    assert!(tree.lookup("a/b/x.txt/nope").is_none());
+    assert!(tree.lookup("nope").is_none());
+
+    // parents always come before their children
+    for (index, node) in tree.nodes().iter().enumerate().skip(1) {
+        assert!(node.parent() < index);
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_error_kinds() -> Result<()> {
+    let work_dir = &util::work_dir("error_kinds")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), &compressible_data(10_000))?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":zlib,rename=/:{}/src", work_dir).as_str().try_into()?,
+    ];
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+
+    // usable as a std error, e.g. with Box<dyn Error>
+    let boxed: Box<dyn std::error::Error> = Box::new(u4pak::Error::new("message".to_string()));
+    assert_eq!(boxed.to_string(), "message");
+
+    let error = unpack(&pak, &mut File::open(&pak_path)?, format!("{}/out", work_dir), UnpackOptions {
+        paths: Some(&["missing.txt"]),
+        ..UnpackOptions::default()
+    }).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::PathNotFound);
+
+    let missing = Pak::from_path(format!("{}/missing.pak", work_dir), Default::default()).unwrap_err();
+    assert_eq!(missing.kind(), ErrorKind::Io);
+    assert!(std::error::Error::source(&missing).is_some());
+
+    // garbage instead of compressed data
+    let record = &pak.index().records()[0];
+    let mut data = std::fs::read(&pak_path)?;
+    let data_start = (record.offset() + u4pak::Pak::header_size(pak.version(), pak.variant(), record)) as usize;
+    for byte in &mut data[data_start..data_start + 16] {
+        *byte = 0xFF;
+    }
+    write_file(&pak_path, &data)?;
+    let error = unpack(&pak, &mut File::open(&pak_path)?, format!("{}/out", work_dir), UnpackOptions::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::DecompressionFailed, "{}", error);
+
+    // not a pak at all
+    write_file(&pak_path, &[0u8; 1024])?;
+    let error = Pak::from_path(&pak_path, Default::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::UnsupportedVersion, "{}", error);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_lazy_index() -> Result<()> {
+    use u4pak::index::LazyIndex;
+    use u4pak::pak::Variant;
+
+    let work_dir = &util::work_dir("lazy-index")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/files/a.txt", work_dir), &data)?;
+    write_file(format!("{}/files/sub/b.bin", work_dir), &data[..1234])?;
+    write_file(format!("{}/files/sub/c.txt", work_dir), b"c")?;
+
+    for (variant, version) in [(Variant::Standard, 3), (Variant::ConanExiles, 4)] {
+        let pak_path = format!("{}/v{}.pak", work_dir, version);
+        let paths = [
+            format!(":zlib,rename=/a.txt:{}/files/a.txt", work_dir),
+            format!(":rename=/sub/b.bin:{}/files/sub/b.bin", work_dir),
+            format!(":rename=/sub/c.txt:{}/files/sub/c.txt", work_dir),
+        ];
+        util::pack(&pak_path, &paths, PackOptions {
+            variant,
+            version,
+            ..PackOptions::default()
+        })?;
+
+        let options = u4pak::pak::Options {
+            variant,
+            ..u4pak::pak::Options::default()
+        };
+        let pak = Pak::from_path(&pak_path, options.clone())?;
+        let index = LazyIndex::from_path(&pak_path, &options)?;
+
+        assert_eq!(index.version(), pak.version());
+        assert_eq!(index.mount_point(), pak.index().mount_point());
+        assert_eq!(index.len(), pak.index().records().len());
+
+        let filenames = index.filenames().collect::<Result<Vec<_>>>()?;
+        let expected: Vec<&str> = pak.index().records().iter().map(|record| record.filename()).collect();
+        assert_eq!(filenames, expected);
+
+        let records = index.records().collect::<Result<Vec<_>>>()?;
+        assert_eq!(&records[..], pak.index().records());
+        assert!(index.record(index.len()).is_err());
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_dump_index() -> Result<()> {
+    use u4pak::index::read_raw_index;
+    use u4pak::util::write_hexdump;
+
+    let work_dir = &util::work_dir("dump-index")?;
+
+    write_file(format!("{}/files/a.txt", work_dir), b"hello")?;
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/files", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    let options = u4pak::pak::Options::default();
+    let mut file = File::open(&pak_path)?;
+    let footer = Pak::read_footer(&mut file, &options)?;
+    assert_eq!(footer.index_offset(), pak.index_offset());
+    assert_eq!(footer.index_size(), pak.index_size());
+
+    let raw_index = read_raw_index(&mut file, &footer, options.encoding, None, &*options.cipher, None, &options.warnings)?;
+    let data = std::fs::read(&pak_path)?;
+    let index_start = pak.index_offset() as usize;
+    assert_eq!(&raw_index.primary_index[..], &data[index_start..index_start + pak.index_size() as usize]);
+    assert!(raw_index.encoded_records.is_none());
+    assert!(raw_index.path_hash_index.is_none());
+    assert!(raw_index.full_directory_index.is_none());
+
+    let mut dump = Vec::new();
+    write_hexdump(&mut dump, b"0123456789abcdef0123456789abcdef0123456789abcdef0123", 0x10)?;
+    assert_eq!(String::from_utf8(dump).unwrap(),
+        "00000010  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+         *\n\
+         00000040  30 31 32 33                                       |0123|\n\
+         00000044\n");
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_warnings() -> Result<()> {
+    use u4pak::pak::Variant;
+    use u4pak::Warnings;
+
+    let work_dir = &util::work_dir("warnings")?;
+
+    write_file(format!("{}/files/a.txt", work_dir), b"hello")?;
+
+    let pak_path = format!("{}/ConanSandbox.pak", work_dir);
+    let paths = [
+        format!(":rename=/a.txt:{}/files/a.txt", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions {
+        variant: Variant::ConanExiles,
+        version: 4,
+        ..PackOptions::default()
+    })?;
+
+    let options = || u4pak::pak::Options {
+        variant: Variant::ConanExiles,
+        warnings: Warnings::collect(),
+        ..u4pak::pak::Options::default()
+    };
+    let pak = Pak::from_path(&pak_path, options())?;
+    assert!(pak.warnings().take().is_empty());
+
+    // the unknown field is the last field of the only record in the index
+    let mut data = std::fs::read(&pak_path)?;
+    let unknown_offset = (pak.index_offset() + pak.index_size() - 4) as usize;
+    data[unknown_offset] = 1;
+    std::fs::write(&pak_path, &data)?;
+
+    let pak = Pak::from_path(&pak_path, options())?;
+    let warnings = pak.warnings().take();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].path.as_deref(), Some("a.txt"));
+    assert_eq!(warnings[0].to_string(), "a.txt: unknown field has other value than 0: 1");
+    assert!(pak.warnings().take().is_empty());
+
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options {
+        variant: Variant::ConanExiles,
+        warnings: Warnings::Ignore,
+        ..u4pak::pak::Options::default()
+    })?;
+    assert!(pak.warnings().take().is_empty());
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_record_data_offset_block_ranges() -> Result<()> {
+    use std::io::Read;
+    use flate2::read::ZlibDecoder;
+
+    let work_dir = &util::work_dir("block-ranges")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+
+    // pack() writes version 3, which still uses absolute block offsets
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/pad.txt:{}/src/a.txt", work_dir),
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        thread_count: std::num::NonZeroUsize::new(1).unwrap(),
+        ..PackOptions::default()
+    })?;
+    let pak_data = std::fs::read(&pak_path)?;
+
+    let record = pak.record("a.txt").unwrap();
+    let data_offset = record.data_offset(pak.version(), pak.variant());
+    assert!(data_offset > record.offset());
+
+    let ranges: Vec<(u64, u64)> = record.block_ranges(pak.version()).collect();
+    assert_eq!(ranges.len(), 4);
+    assert_eq!(ranges[0].0, data_offset);
+    assert_eq!(ranges[3].1, data_offset + record.size());
+
+    let mut content = Vec::new();
+    for (start, end) in ranges {
+        ZlibDecoder::new(&pak_data[start as usize..end as usize]).read_to_end(&mut content)?;
+    }
+    assert_eq!(content, data);
+
+    let pad = pak.record("pad.txt").unwrap();
+    assert_eq!(pad.block_ranges(pak.version()).count(), 0);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_record_layout() -> Result<()> {
+    use u4pak::pak::{COMPRESSION_BLOCK_HEADER_SIZE, CONAN_EXILE_RECORD_HEADER_SIZE, V1_RECORD_HEADER_SIZE, V2_RECORD_HEADER_SIZE, V3_RECORD_HEADER_SIZE, Variant};
+    use u4pak::variant::{record_layout, RecordLayout};
+
+    for version in 1..=11 {
+        let layout = record_layout(version, Variant::Standard)?;
+        let expected = match version {
+            1 => RecordLayout { base_header_size: V1_RECORD_HEADER_SIZE, compression_blocks: false, inline_padding: 0 },
+            2 => RecordLayout { base_header_size: V2_RECORD_HEADER_SIZE, compression_blocks: false, inline_padding: 0 },
+            3 => RecordLayout { base_header_size: V3_RECORD_HEADER_SIZE, compression_blocks: true,  inline_padding: 0 },
+            _ => RecordLayout { base_header_size: V3_RECORD_HEADER_SIZE, compression_blocks: true,  inline_padding: 4 },
+        };
+        assert_eq!(layout, expected, "version {}", version);
+        assert_eq!(layout.header_size_for(None), layout.base_header_size, "version {}", version);
+
+        let with_blocks = if version >= 3 { layout.base_header_size + 4 + 3 * COMPRESSION_BLOCK_HEADER_SIZE } else { layout.base_header_size };
+        assert_eq!(layout.header_size_for(Some(3)), with_blocks, "version {}", version);
+        assert_eq!(Variant::Standard.spec().base_header_size(version)?, layout.base_header_size);
+        assert_eq!(Variant::Standard.spec().inline_padding(version), layout.inline_padding);
+    }
+
+    let layout = record_layout(4, Variant::ConanExiles)?;
+    assert_eq!(layout.header_size_for(Some(1)), CONAN_EXILE_RECORD_HEADER_SIZE + 4 + COMPRESSION_BLOCK_HEADER_SIZE);
+    assert_eq!(layout.inline_padding, 0);
+    assert_eq!(record_layout(3, Variant::ConanExiles).unwrap_err().kind(), ErrorKind::UnsupportedVersion);
+
+    // the header sizes of packed records match what was written
+    let work_dir = &util::work_dir("record-layout")?;
+    let data = compressible_data(100_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+
+    for version in 1..=3 {
+        let pak_path = format!("{}/out-{}.pak", work_dir, version);
+        let method = if version >= 2 { "zlib" } else { "none" };
+        let paths = [
+            format!(":{},rename=/a.txt:{}/src/a.txt", method, work_dir),
+        ];
+        let pak = util::pack(&pak_path, &paths, PackOptions {
+            version,
+            ..PackOptions::default()
+        })?;
+        let record = &pak.index().records()[0];
+        let layout = record_layout(version, Variant::Standard)?;
+        assert_eq!(record.data_offset(version, Variant::Standard), record.offset() + layout.header_size(record));
+        assert_eq!(pak.index_offset(), record.data_offset(version, Variant::Standard) + record.size(), "version {}", version);
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_info_extension_stats() -> Result<()> {
+    let work_dir = &util::work_dir("info_extension_stats")?;
+
+    write_file(format!("{}/src/a.uasset", work_dir), &compressible_data(10_000))?;
+    write_file(format!("{}/src/sub/b.UASSET", work_dir), &compressible_data(5_000))?;
+    write_file(format!("{}/src/sub.dir/README", work_dir), b"hello")?;
+    write_file(format!("{}/src/c.uexp", work_dir), b"data")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    let stats = extension_stats(&pak);
+    let extensions: Vec<&str> = stats.iter().map(|stats| stats.extension.as_str()).collect();
+    assert_eq!(extensions, vec!["uasset", "", "uexp"]);
+    assert_eq!(stats[0].count, 2);
+    assert_eq!(stats[0].uncompressed_size, 15_000);
+    assert!(stats[0].size < stats[0].uncompressed_size);
+    assert!(stats[0].ratio() < 100.0);
+    assert_eq!(stats[1].uncompressed_size, 5);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_ranges() -> Result<()> {
+    assert_eq!(merge_ranges(vec![(10, 20), (0, 5), (5, 8), (15, 30), (40, 40)]), [(0, 8), (10, 30)]);
+
+    let work_dir = &util::work_dir("ranges")?;
+
+    let data = compressible_data(300_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..1000])?;
+    write_file(format!("{}/src/c.txt", work_dir), &data[..50_000])?;
+
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+
+    ];
+
+    let pak_path = format!("{}/test.pak", work_dir);
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+    let record = pak.record("c.txt").unwrap();
+
+    assert_eq!(byte_ranges(&pak, [record], false), [pak.record_region(record)]);
+
+    // only the fetched ranges are needed to unpack the file
+    let ranges = byte_ranges(&pak, [record], true);
+    let original = std::fs::read(&pak_path)?;
+    let mut sparse = vec![0u8; original.len()];
+    for &(start, end) in &ranges {
+        let (start, end) = (start as usize, end as usize);
+        sparse[start..end].copy_from_slice(&original[start..end]);
+    }
+    assert!(ranges.iter().map(|(start, end)| end - start).sum::<u64>() < original.len() as u64);
+
+    let sparse_pak = Pak::from_reader(&mut std::io::Cursor::new(&sparse), Default::default())?;
+    let mut content = Vec::new();
+    unpack_record_to_writer(sparse_pak.record("c.txt").unwrap(), sparse_pak.version(), sparse_pak.variant(),
+        &mut std::io::Cursor::new(&sparse), &mut content, None, sparse_pak.cipher())?;
+    assert_eq!(content, &data[..50_000]);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_locate() -> Result<()> {
+    use u4pak::locate::{locate, Location, RecordPart};
+
+    let work_dir = &util::work_dir("locate")?;
+
+    write_file(format!("{}/files/a.txt", work_dir), b"hello")?;
+    write_file(format!("{}/files/b.bin", work_dir), &compressible_data(200_000))?;
+
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let paths = [
+        format!(":rename=/a.txt:{}/files/a.txt", work_dir),
+        format!(":zlib,rename=/b.bin:{}/files/b.bin", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions::default())?;
+    // the returned pak has no footer
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options::default())?;
+    let a = pak.record("a.txt").unwrap();
+    let b = pak.record("b.bin").unwrap();
+
+    match locate(&pak, a.offset()) {
+        Location::Records(records) => {
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].record, a);
+            assert_eq!(records[0].part, RecordPart::Header);
+        }
+        other => panic!("unexpected location: {:?}", other),
+    }
+
+    let (_, a_end) = pak.record_region(a);
+    match locate(&pak, a_end - 1) {
+        Location::Records(records) => assert_eq!(records[0].part, RecordPart::Data { offset: 4 }),
+        other => panic!("unexpected location: {:?}", other),
+    }
+
+    // block offsets are absolute before version 5
+    assert_eq!(pak.version(), 3);
+    let blocks = b.compression_blocks().as_ref().unwrap();
+    assert!(blocks.len() > 1);
+    let block_start = blocks[1].start_offset;
+    match locate(&pak, block_start) {
+        Location::Records(records) => {
+            assert_eq!(records[0].record, b);
+            assert_eq!(records[0].part, RecordPart::Block {
+                index: 1,
+                start: block_start,
+                end: blocks[1].end_offset,
+                uncompressed_offset: b.compression_block_size() as u64,
+            });
+        }
+        other => panic!("unexpected location: {:?}", other),
+    }
+
+    assert_eq!(locate(&pak, pak.index_offset() + 3), Location::Index { offset: 3 });
+    let file_size = std::fs::metadata(&pak_path)?.len();
+    assert_eq!(locate(&pak, file_size - 1), Location::Footer { offset: pak.footer().unwrap().footer_size() - 1 });
+    assert_eq!(locate(&pak, file_size), Location::Unused);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_hash() -> Result<()> {
+    use u4pak::hash::{hash, DigestAlgorithm, HashOptions};
+
+    let work_dir = &util::work_dir("hash")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/files/a.txt", work_dir), b"hello")?;
+    write_file(format!("{}/files/dir/b.bin", work_dir), &data)?;
+
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":rename=/a.txt:{}/files/a.txt", work_dir).as_str().try_into()?,
+        format!(":zlib,rename=/dir/b.bin:{}/files/dir/b.bin", work_dir).as_str().try_into()?,
+    ];
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+    assert_eq!(pak.record("dir/b.bin").unwrap().compression_method(), COMPR_ZLIB);
+
+    let expected = vec![
+        ("a.txt".to_string(), sha1_digest(&b"hello"[..])?.to_vec()),
+        ("dir/b.bin".to_string(), sha1_digest(&data[..])?.to_vec()),
+    ];
+
+    for recompute in [false, true] {
+        let hashes = hash(&pak, &mut File::open(&pak_path)?, HashOptions {
+            recompute,
+            ..HashOptions::default()
+        })?;
+        let hashes: Vec<(String, Vec<u8>)> = hashes.into_iter()
+            .map(|hash| (hash.filename, hash.digest))
+            .collect();
+        assert_eq!(hashes, expected);
+    }
+
+    let hashes = hash(&pak, &mut File::open(&pak_path)?, HashOptions {
+        algorithm: "SHA-256".try_into()?,
+        ..HashOptions::default()
+    })?;
+    assert_eq!(hashes[0].digest, openssl::sha::sha256(b"hello").to_vec());
+    assert_eq!(hashes[1].digest, openssl::sha::sha256(&data).to_vec());
+    assert_eq!(hashes[1].digest.len(), DigestAlgorithm::Sha256.digest_size());
+
+    let hashes = hash(&pak, &mut File::open(&pak_path)?, HashOptions {
+        paths: Some(&["dir"]),
+        ..HashOptions::default()
+    })?;
+    assert_eq!(hashes.len(), 1);
+    assert_eq!(hashes[0].to_string(), format!("{}  dir/b.bin", u4pak::pak::HexDisplay::new(&expected[1].1)));
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_grep() -> Result<()> {
+    use std::io::Write;
+    use u4pak::grep::{grep, GrepOptions, MatchWriter, Pattern};
+
+    // matches spanning several writes
+    let pattern = Pattern::text("needle", true, false)?;
+    let mut writer = MatchWriter::new(&pattern, None);
+    for chunk in [&b"xxne"[..], b"e", b"dlexNEEDLE", b"n", b"eedle"] {
+        writer.write_all(chunk)?;
+    }
+    assert_eq!(writer.finish(), vec![2, 9, 15]);
+
+    let pattern = Pattern::text("ä", false, true)?;
+    assert_eq!(pattern.needles(), &[vec![0xC3, 0xA4], vec![0xE4, 0x00]]);
+    assert_eq!(Pattern::parse_hex("DE ad BE ef")?, Pattern::binary(vec![0xDE, 0xAD, 0xBE, 0xEF])?);
+    assert!(Pattern::parse_hex("ABC").is_err());
+    assert!(Pattern::text("", false, false).is_err());
+
+    let work_dir = &util::work_dir("grep")?;
+
+    let mut data = compressible_data(200_000);
+    data[150_000..150_006].copy_from_slice(b"needle");
+    write_file(format!("{}/files/a.txt", work_dir), b"no match here")?;
+    write_file(format!("{}/files/dir/b.bin", work_dir), &data)?;
+
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":zlib,rename=/:{}/files", work_dir).as_str().try_into()?,
+    ];
+    let pak = pack(&pak_path, &paths, PackOptions {
+        compression_block_size: std::num::NonZeroU32::new(64 * 1024).unwrap(),
+        ..PackOptions::default()
+    })?;
+
+    let pattern = Pattern::text("needle", false, false)?;
+    let matches = grep(&pak, &mut File::open(&pak_path)?, &pattern, GrepOptions::default())?;
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].filename, "dir/b.bin");
+    assert_eq!(matches[0].offsets, vec![150_000]);
+
+    let pattern = Pattern::text("World", false, false)?;
+    let matches = grep(&pak, &mut File::open(&pak_path)?, &pattern, GrepOptions {
+        paths: Some(&["dir"]),
+        max_count: Some(2),
+        ..GrepOptions::default()
+    })?;
+    assert_eq!(matches[0].offsets, vec![6, 19]);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_range_reader() -> Result<()> {
+    use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use u4pak::range_reader::{HttpTransport, RangeReader, RangeTransport};
+    use u4pak::unpack::unpack_from_reader;
+
+    struct MemTransport(Vec<u8>);
+
+    impl RangeTransport for MemTransport {
+        fn size(&mut self) -> Result<u64> {
+            Ok(self.0.len() as u64)
+        }
+
+        fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+            let data = &self.0[(offset as usize).min(self.0.len())..];
+            let count = buf.len().min(data.len());
+            buf[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        }
+    }
+
+    let work_dir = &util::work_dir("range_reader")?;
+
+    let data = compressible_data(500_000);
+    write_file(format!("{}/src/a/big.txt", work_dir), &data)?;
+    write_file(format!("{}/src/a/small.txt", work_dir), b"small")?;
+    write_file(format!("{}/src/b/other.txt", work_dir), b"other")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":rename=/:{}/src", work_dir).as_str().try_into()?,
+    ];
+    pack(&pak_path, &paths, PackOptions {
+        compression_method: COMPR_ZLIB,
+        ..PackOptions::default()
+    })?;
+    let pak_data = std::fs::read(&pak_path)?;
+
+    let mut reader = RangeReader::with_block_size(MemTransport(pak_data.clone()), 4096)?;
+    let pak = Pak::from_reader(&mut reader, u4pak::pak::Options::default())?;
+    assert_eq!(pak.index().records().len(), 3);
+
+    let outdir = format!("{}/out", work_dir);
+    unpack_from_reader(&pak, &mut reader, &outdir, UnpackOptions {
+        paths: Some(&["a"]),
+        ..UnpackOptions::default()
+    })?;
+    assert_eq!(std::fs::read(format!("{}/a/big.txt", outdir))?, data);
+    assert_eq!(std::fs::read(format!("{}/a/small.txt", outdir))?, b"small");
+    assert!(!Path::new(&format!("{}/b/other.txt", outdir)).exists());
+
+    // serves the pak with range requests, keeping connections open for up to
+    // 3 requests and then closing them without telling the client
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let connection_count = Arc::new(AtomicUsize::new(0));
+    let server_connection_count = connection_count.clone();
+    let server_data = pak_data.clone();
+    std::thread::spawn(move || {
+        let pak_data = server_data;
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => break,
+            };
+            server_connection_count.fetch_add(1, Ordering::SeqCst);
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            for _ in 0..3 {
+                let mut range = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                    if let Some(value) = line.strip_prefix("Range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some((start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+                    }
+                    line.clear();
+                }
+                let (start, end) = match range {
+                    Some(range) => range,
+                    None => break,
+                };
+                let end = end.min(pak_data.len() - 1);
+                let body = &pak_data[start..=end];
+                let _ = write!(stream,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                    start, end, pak_data.len(), body.len());
+                let _ = stream.write_all(body);
+            }
+        }
+    });
+
+    let transport = HttpTransport::new(&format!("http://127.0.0.1:{}/out.pak", port))?;
+    let mut reader = RangeReader::with_block_size(transport, 256)?;
+    let pak = Pak::from_reader(&mut reader, u4pak::pak::Options::default())?;
+    let record = pak.record("a/big.txt").expect("a/big.txt not found");
+    let mut out = Vec::new();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), &mut reader, &mut out, None, pak.cipher())?;
+    assert_eq!(out, data);
+
+    // reads from different blocks, each a request of its own
+    for offset in (0..pak_data.len()).step_by(pak_data.len() / 8) {
+        let mut byte = [0u8];
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        reader.read_exact(&mut byte)?;
+        assert_eq!(byte[0], pak_data[offset]);
+    }
+
+    // connections were reused and reopened after the server closed them (plus
+    // one request for the size)
+    let request_count = reader.request_count() + 1;
+    assert!(request_count > 3, "{} requests", request_count);
+    assert_eq!(connection_count.load(Ordering::SeqCst), request_count.div_ceil(3));
+
+    let error = HttpTransport::new("https://example.com/game.pak").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
@@ -0,0 +1,251 @@
+mod util;
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use u4pak::pack::{PackOptions, PackPath};
+use u4pak::pak::{COMPR_NONE, COMPR_ZLIB};
+use u4pak::unpack::UnpackOptions;
+use u4pak::{ErrorKind, Pak, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+// writes a minimal IoStore container (version 3, one partition) with a
+// directory index and returns the chunk contents
+fn write_toc(utoc_path: &str, ucas_path: &str) -> Result<Vec<Vec<u8>>> {
+    use std::io::Write;
+    use flate2::{write::ZlibEncoder, Compression};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn write_fstring(out: &mut Vec<u8>, value: &str) {
+        out.extend_from_slice(&(value.len() as i32 + 1).to_le_bytes());
+        out.extend_from_slice(value.as_bytes());
+        out.push(0);
+    }
+
+    let chunks = vec![compressible_data(10_000), b"uncompressed".repeat(8), b"unnamed".to_vec()];
+
+    // (offset in .ucas, compressed size, uncompressed size, method index)
+    let mut blocks = Vec::new();
+    let mut ucas = Vec::new();
+    let mut offset_lengths = Vec::new();
+    let mut uncompressed_offset = 0;
+    for (index, chunk) in chunks.iter().enumerate() {
+        offset_lengths.push((uncompressed_offset, chunk.len()));
+        for block in chunk.chunks(BLOCK_SIZE) {
+            let data = if index == 0 {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(block)?;
+                encoder.finish()?
+            } else {
+                block.to_vec()
+            };
+            blocks.push((ucas.len(), data.len(), block.len(), if index == 0 { 1u8 } else { 0 }));
+            ucas.extend_from_slice(&data);
+            uncompressed_offset += BLOCK_SIZE;
+        }
+    }
+
+    let mut directory_index = Vec::new();
+    write_fstring(&mut directory_index, "../../../");
+    // root, Game, Game/Content: name, first child, next sibling, first file
+    let dirs: [[u32; 4]; 3] = [[u32::MAX, 1, u32::MAX, u32::MAX], [0, 2, u32::MAX, 1], [1, u32::MAX, u32::MAX, 0]];
+    directory_index.extend_from_slice(&(dirs.len() as u32).to_le_bytes());
+    for dir in &dirs {
+        for value in dir {
+            directory_index.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    // a.txt -> chunk 0, b.bin -> chunk 1: name, next file, chunk index
+    let files: [[u32; 3]; 2] = [[2, u32::MAX, 0], [3, u32::MAX, 1]];
+    directory_index.extend_from_slice(&(files.len() as u32).to_le_bytes());
+    for file in &files {
+        for value in file {
+            directory_index.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    let strings = ["Game", "Content", "a.txt", "b.bin"];
+    directory_index.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+    for string in &strings {
+        write_fstring(&mut directory_index, string);
+    }
+
+    let mut utoc = Vec::new();
+    utoc.extend_from_slice(b"-==--==--==--==-");
+    utoc.extend_from_slice(&[3, 0, 0, 0]);
+    for value in [144, chunks.len() as u32, blocks.len() as u32, 12, 1, 32, BLOCK_SIZE as u32, directory_index.len() as u32, 1] {
+        utoc.extend_from_slice(&value.to_le_bytes());
+    }
+    utoc.extend_from_slice(&0x1234_5678_u64.to_le_bytes());
+    utoc.extend_from_slice(&[0u8; 16]);
+    // compressed | indexed
+    utoc.extend_from_slice(&[1 | 8, 0, 0, 0]);
+    utoc.extend_from_slice(&0u32.to_le_bytes());
+    utoc.extend_from_slice(&u64::MAX.to_le_bytes());
+    utoc.resize(144, 0);
+
+    for index in 0..chunks.len() {
+        utoc.extend_from_slice(&(0xABCD_0000_u64 + index as u64).to_le_bytes());
+        utoc.extend_from_slice(&[index as u8, 0, 0, 2]);
+    }
+    for &(offset, length) in &offset_lengths {
+        utoc.extend_from_slice(&(offset as u64).to_be_bytes()[3..]);
+        utoc.extend_from_slice(&(length as u64).to_be_bytes()[3..]);
+    }
+    for &(offset, compressed_size, uncompressed_size, method) in &blocks {
+        utoc.extend_from_slice(&(offset as u64).to_le_bytes()[..5]);
+        utoc.extend_from_slice(&(compressed_size as u32).to_le_bytes()[..3]);
+        utoc.extend_from_slice(&(uncompressed_size as u32).to_le_bytes()[..3]);
+        utoc.push(method);
+    }
+    let mut method_name = b"Zlib".to_vec();
+    method_name.resize(32, 0);
+    utoc.extend_from_slice(&method_name);
+    utoc.extend_from_slice(&directory_index);
+
+    std::fs::write(utoc_path, &utoc)?;
+    std::fs::write(ucas_path, &ucas)?;
+
+    Ok(chunks)
+}
+
+#[test]
+fn test_toc() -> Result<()> {
+    use u4pak::toc::UNNAMED_CHUNKS_DIR;
+    use u4pak::unpack::unpack_toc;
+    use u4pak::Toc;
+
+    let work_dir = &util::work_dir("toc")?;
+    std::fs::create_dir_all(work_dir)?;
+
+    let utoc_path = format!("{}/global.utoc", work_dir);
+    let chunks = write_toc(&utoc_path, &format!("{}/global.ucas", work_dir))?;
+
+    let toc = Toc::from_path(&utoc_path, None)?;
+    assert_eq!(toc.version(), 3);
+    assert_eq!(toc.mount_point(), Some("../../../"));
+    assert_eq!(toc.compression_methods(), &["Zlib".to_string()]);
+
+    let filenames: Vec<&str> = toc.chunks().iter().map(|chunk| chunk.filename()).collect();
+    assert_eq!(&filenames[..2], &["Game/Content/a.txt", "Game/b.bin"]);
+    assert!(filenames[2].starts_with(&format!("{}/", UNNAMED_CHUNKS_DIR)));
+    assert!(!toc.chunks()[2].indexed());
+
+    let pak = toc.to_pak();
+    let records = pak.index().records();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].compression_method(), COMPR_ZLIB);
+    assert_eq!(records[0].uncompressed_size(), chunks[0].len() as u64);
+    assert!(records[0].size() < records[0].uncompressed_size());
+    assert_eq!(records[1].compression_method(), COMPR_NONE);
+
+    let mut partitions = toc.open_partitions(&utoc_path)?;
+    let mut data = Vec::new();
+    toc.read_chunk(toc.chunk("Game/Content/a.txt").unwrap(), &mut partitions, &mut data)?;
+    assert_eq!(data, chunks[0]);
+
+    let outdir = format!("{}/out", work_dir);
+    unpack_toc(&toc, &mut partitions, &outdir, UnpackOptions {
+        paths: Some(&["Game"]),
+        ..UnpackOptions::default()
+    })?;
+    assert_eq!(std::fs::read(format!("{}/Game/Content/a.txt", outdir))?, chunks[0]);
+    assert_eq!(std::fs::read(format!("{}/Game/b.bin", outdir))?, chunks[1]);
+    assert!(!Path::new(&format!("{}/{}", outdir, UNNAMED_CHUNKS_DIR)).exists());
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_toc_corrupt() -> Result<()> {
+    use u4pak::Toc;
+
+    let work_dir = &util::work_dir("toc-corrupt")?;
+    std::fs::create_dir_all(work_dir)?;
+
+    let utoc_path = format!("{}/global.utoc", work_dir);
+    write_toc(&utoc_path, &format!("{}/global.ucas", work_dir))?;
+    let utoc = std::fs::read(&utoc_path)?;
+
+    // truncated anywhere
+    for size in [0, 20, 100, 144, 200, utoc.len() - 1] {
+        std::fs::write(&utoc_path, &utoc[..size])?;
+        assert!(Toc::from_path(&utoc_path, None).is_err(), "truncated to {} bytes", size);
+    }
+
+    // partition size 0 with partitions
+    let mut corrupt = utoc.clone();
+    corrupt[88..96].copy_from_slice(&0u64.to_le_bytes());
+    std::fs::write(&utoc_path, &corrupt)?;
+    assert_eq!(Toc::from_path(&utoc_path, None).unwrap_err().kind(), ErrorKind::CorruptIndex);
+
+    // the method index of the first compression block is out of range
+    let mut corrupt = utoc.clone();
+    corrupt[144 + 3 * 12 + 3 * 10 + 11] = 7;
+    std::fs::write(&utoc_path, &corrupt)?;
+    let toc = Toc::from_path(&utoc_path, None)?;
+    let mut partitions = toc.open_partitions(&utoc_path)?;
+    let error = toc.read_chunk(toc.chunk("Game/Content/a.txt").unwrap(), &mut partitions, &mut Vec::new()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::CorruptIndex);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_pack_iostore() -> Result<()> {
+    use u4pak::pack::pack_iostore;
+    use u4pak::unpack::unpack_toc;
+
+    let work_dir = &util::work_dir("pack_iostore")?;
+
+    let big = compressible_data(200_000);
+    write_file(format!("{}/src/Content/Maps/big.umap", work_dir), &big)?;
+    write_file(format!("{}/src/Content/small.txt", work_dir), b"small")?;
+    write_file(format!("{}/src/Config/Game.ini", work_dir), b"[Game]\n")?;
+
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    for encrypted in [false, true] {
+        let pak_path = format!("{}/Mod_P.pak", work_dir);
+        let paths: Vec<PackPath> = vec![
+            format!(":rename=/Game:{}/src", work_dir).as_str().try_into()?,
+        ];
+        let toc = pack_iostore(&pak_path, &paths, PackOptions {
+            compression_method: COMPR_ZLIB,
+            encrypted,
+            encryption_key: Some(key.clone()),
+            encryption_guid: 0x0123_4567_89AB_CDEF_0123_4567_89AB_CDEF,
+            ..PackOptions::default()
+        })?;
+
+        assert_eq!(toc.header().encrypted(), encrypted);
+        assert_eq!(toc.header().encryption_guid(), if encrypted { 0x0123_4567_89AB_CDEF_0123_4567_89AB_CDEF } else { 0 });
+        assert_eq!(toc.mount_point(), Some("../../../"));
+        assert_eq!(toc.chunks().len(), 3);
+
+        // the stub pak is empty
+        let stub = Pak::from_path(&pak_path, u4pak::pak::Options::default())?;
+        assert!(stub.index().records().is_empty());
+
+        let toc = u4pak::Toc::from_path(format!("{}/Mod_P.utoc", work_dir), Some(key.clone()))?;
+        let pak = toc.to_pak();
+        let record = pak.record("Game/Content/Maps/big.umap").unwrap();
+        assert_eq!(record.compression_method(), COMPR_ZLIB);
+        assert!(record.size() < big.len() as u64);
+
+        let outdir = format!("{}/out", work_dir);
+        remove_dir_all_if_exists(&outdir)?;
+        let mut partitions = toc.open_partitions(format!("{}/Mod_P.utoc", work_dir))?;
+        unpack_toc(&toc, &mut partitions, &outdir, UnpackOptions::default())?;
+        assert_eq!(std::fs::read(format!("{}/Game/Content/Maps/big.umap", outdir))?, big);
+        assert_eq!(std::fs::read(format!("{}/Game/Content/small.txt", outdir))?, b"small");
+        assert_eq!(std::fs::read(format!("{}/Game/Config/Game.ini", outdir))?, b"[Game]\n");
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+mod util;
+
+use std::fs::File;
+
+use u4pak::check::{check_with_report, CheckOptions};
+use u4pak::manifest::{read_index_template, read_manifest, write_index_template};
+use u4pak::pack::{pack, PackOptions, PackPath};
+use u4pak::pak::{COMPR_NONE, COMPR_ZLIB};
+use u4pak::{ErrorKind, Pak, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_pack_manifest() -> Result<()> {
+    let work_dir = &util::work_dir("pack_manifest")?;
+
+    let data = compressible_data(4096);
+    write_file(format!("{}/src/plain.txt", work_dir), &data)?;
+    write_file(format!("{}/src/compressed.txt", work_dir), &data)?;
+    write_file(format!("{}/src/secret.txt", work_dir), &data[..1000])?;
+    write_file(format!("{}/src/secret_compressed.txt", work_dir), &data)?;
+
+    let manifest_path = format!("{}/manifest.json", work_dir);
+    write_file(&manifest_path, br#"{
+        "files": [
+            {"source": "src/plain.txt", "path": "data/plain.txt", "compression": "none"},
+            {"source": "src/compressed.txt", "path": "data/compressed.txt", "compression": "zlib", "level": 9, "block_size": "1K"},
+            {"source": "src/secret.txt", "path": "data/secret.txt", "encrypted": true},
+            {"source": "src/secret_compressed.txt", "path": "data/secret_compressed.txt", "compression": "zlib", "min_size": 1, "encrypted": true}
+        ]
+    }"#)?;
+
+    let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    let paths = read_manifest(&manifest_path)?;
+    assert_eq!(paths.len(), 4);
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let pak = pack(&pak_path, &paths, PackOptions {
+        version: 3,
+        encryption_key: Some(base64::decode(key).unwrap()),
+        ..PackOptions::default()
+    })?;
+
+    for record in pak.index().records() {
+        let filename = record.filename();
+        let compressed = filename.contains("compressed");
+        assert_eq!(record.compression_method(), if compressed { COMPR_ZLIB } else { COMPR_NONE }, "{}", filename);
+        assert_eq!(record.encrypted(), filename.contains("secret"), "{}", filename);
+    }
+
+    let out_dir = format!("{}/unpacked", work_dir);
+    util::unpack(&pak_path, &out_dir, Some(key.to_string()))?;
+    util::validate(&format!("{}/src", work_dir), &format!("{}/data", out_dir))?;
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_index_template() -> Result<()> {
+    let work_dir = &util::work_dir("index_template")?;
+
+    let data = compressible_data(4096);
+    write_file(format!("{}/src/a/compressed.txt", work_dir), &data)?;
+    write_file(format!("{}/src/plain.txt", work_dir), &data[..100])?;
+
+    let pak_path = format!("{}/in.pak", work_dir);
+    let mut compressed = PackPath::new(format!("{}/src/a/compressed.txt", work_dir));
+    compressed.compression_method = COMPR_ZLIB;
+    compressed.rename = Some("a/compressed.txt".to_string());
+    let mut plain = PackPath::new(format!("{}/src/plain.txt", work_dir));
+    plain.rename = Some("plain.txt".to_string());
+    let pak = pack(&pak_path, &[compressed, plain], PackOptions {
+        version: 4,
+        mount_point: Some("../../../Game/"),
+        ..PackOptions::default()
+    })?;
+
+    let mut template = Vec::new();
+    write_index_template(&mut template, &pak)?;
+    let template = String::from_utf8(template).unwrap()
+        .replace(r#""path":"plain.txt""#, r#""path":"b/renamed.txt""#);
+    let template_path = format!("{}/src/index.json", work_dir);
+    write_file(&template_path, template.as_bytes())?;
+
+    let template = read_index_template(&template_path)?;
+    assert_eq!(template.version, Some(4));
+    assert_eq!(template.mount_point.as_deref(), Some("../../../Game/"));
+    assert_eq!(template.files.len(), 2);
+
+    let out_path = format!("{}/out.pak", work_dir);
+    let repacked = pack(&out_path, &template.files, PackOptions {
+        version: template.version.unwrap(),
+        mount_point: template.mount_point.as_deref(),
+        ..PackOptions::default()
+    })?;
+
+    assert_eq!(repacked.index().mount_point(), Some("../../../Game/"));
+    let filenames: Vec<&str> = repacked.index().records().iter().map(|record| record.filename()).collect();
+    assert_eq!(filenames, ["a/compressed.txt", "b/renamed.txt"]);
+    for (record, original) in repacked.index().records().iter().zip(pak.index().records()) {
+        assert_eq!(record.compression_method(), original.compression_method(), "{}", record.filename());
+        assert_eq!(record.sha1(), original.sha1(), "{}", record.filename());
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_json_schema() -> Result<()> {
+    use u4pak::check::CheckReport;
+    use u4pak::info::{info_json, PakInfo};
+    use u4pak::serialize::{Document, SCHEMA_VERSION};
+    use u4pak::unpack::{UnpackFailure, UnpackReport};
+
+    let work_dir = &util::work_dir("json_schema")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), b"b")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+        format!(":rename=/b.txt:{}/src/b.txt", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions::default())?;
+    let pak = Pak::from_path(&pak_path, Default::default())?;
+
+    // list --format=json uses the serde representation of records
+    let record = serde_json::to_value(pak.record("a.txt").unwrap()).unwrap();
+    assert_eq!(record["path"], "a.txt");
+    assert_eq!(record["compression_method"], u4pak::pak::COMPR_ZLIB);
+    assert_eq!(record["uncompressed_size"], data.len() as u64);
+    assert_eq!(record["sha1"].as_str().map(str::len), Some(40));
+    assert!(record["timestamp"].is_null());
+
+    // documents start with the schema version and survive a round trip
+    let info = serde_json::to_string(&info_json(&pak, true))?;
+    assert!(info.starts_with(&format!("{{\"schema_version\":{},", SCHEMA_VERSION)), "{}", info);
+    let info: Document<PakInfo> = serde_json::from_str(&info)?;
+    assert_eq!(info, info_json(&pak, true));
+    assert_eq!(info.body.metadata, pak.metadata());
+    assert_eq!(info.body.files.all.count, 2);
+    assert_eq!(info.body.files.compression.zlib.count, 1);
+    assert_eq!(info.body.extensions.map(|extensions| extensions.len()), Some(1));
+
+    // check --report
+    let mut pak_data = std::fs::read(&pak_path)?;
+    let b = pak.record("b.txt").unwrap();
+    let offset = (b.offset() + Pak::header_size(pak.version(), pak.variant(), b)) as usize;
+    pak_data[offset] ^= 0xFF;
+    std::fs::write(&pak_path, &pak_data)?;
+    let errors = check_with_report(&pak, &mut File::open(&pak_path)?, CheckOptions::default())?;
+    let report = serde_json::to_value(Document::new(CheckReport {
+        package: &pak_path,
+        error_count: errors.len(),
+        wiped_count: 0,
+        errors: &errors,
+    }))?;
+    assert_eq!(report["schema_version"], SCHEMA_VERSION);
+    assert_eq!(report["error_count"], 1);
+    assert_eq!(report["errors"][0]["path"], "b.txt");
+    assert_eq!(report["errors"][0]["kind"], "checksum_mismatch");
+    assert_eq!(report["errors"][0]["expected"].as_str().map(str::len), Some(40));
+
+    // errors.json of unpack --skip-errors
+    let failures = [UnpackFailure {
+        filename: "b.txt".to_string(),
+        offset: b.offset(),
+        error: u4pak::Error::new("broken".to_string()).with_kind(ErrorKind::DecompressionFailed),
+    }];
+    let report = serde_json::to_value(Document::new(UnpackReport {
+        package: &pak_path,
+        error_count: failures.len(),
+        errors: &failures,
+    }))?;
+    assert_eq!(report["schema_version"], SCHEMA_VERSION);
+    assert_eq!(report["errors"][0]["path"], "b.txt");
+    assert_eq!(report["errors"][0]["offset"], b.offset());
+    assert_eq!(report["errors"][0]["kind"], "decompression_failed");
+    assert_eq!(report["errors"][0]["reason"], "broken");
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_keyring_crypto_json() -> Result<()> {
+    use u4pak::Keyring;
+
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    let other_key = vec![0x42u8; 32];
+    let guid: u128 = 0x0123456789ABCDEF0011223344556677;
+
+    let keyring = Keyring::parse(&format!(
+        "00000000-00000000-00000000-00000000: 0x{}\n\
+         {:032X} MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=\n",
+        "42".repeat(32), guid))?;
+
+    let crypto = Keyring::parse_crypto_json(&format!(
+        r#"{{"EncryptionKey": {{"Name": null, "Guid": null, "Key": "{}"}},
+            "SecondaryEncryptionKeys": [{{"Name": "DLC", "Guid": "{:032X}", "Key": "{}"}}]}}"#,
+        base64::encode(&other_key), guid, base64::encode(&key)))?;
+    assert_eq!(crypto, keyring);
+    assert!(Keyring::parse_crypto_json(r#"{"SecondaryEncryptionKeys": [{"Key": "AAAA"}]}"#).is_err());
+
+    let mut merged = Keyring::parse(&format!("{:032X} 0x{}", guid, "42".repeat(32)))?;
+    merged.merge(crypto);
+    assert_eq!(merged.len(), 2);
+    assert_eq!(merged.get(0), Some(&other_key));
+    assert_eq!(merged.get(guid), Some(&other_key));
+
+    Ok(())
+}
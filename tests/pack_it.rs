@@ -0,0 +1,546 @@
+mod util;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use u4pak::dedupe::find_duplicates;
+use u4pak::glob::{split_glob, Glob};
+use u4pak::entry::unpack_record_to_writer;
+use u4pak::bench::{bench_compress, BenchOptions};
+use u4pak::check::{check, check_with_report, CheckOptions};
+use u4pak::pack::{pack, PackOptions, PackPath};
+use u4pak::pak::{COMPR_NONE, COMPR_ZLIB};
+use u4pak::progress::{ProgressCallback, ProgressInfo};
+use u4pak::tune::{tune, StorageKind, Tuning};
+use u4pak::unpack::{unpack, UnpackOptions};
+use u4pak::{ErrorKind, Pak, Record, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_pack_min_size() -> Result<()> {
+    let work_dir = &util::work_dir("pack_min_size")?;
+
+    let data = compressible_data(4096);
+    write_file(format!("{}/default/a.txt", work_dir), &data)?;
+    write_file(format!("{}/override/b.txt", work_dir), &data)?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/default:{}/default", work_dir),
+        format!(":zlib,min_size=8K,rename=/override:{}/override", work_dir),
+    ];
+
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        compression_method: COMPR_ZLIB,
+        ..PackOptions::default()
+    })?;
+
+    for record in pak.index().records() {
+        let expected = if record.filename().starts_with("default/") { COMPR_ZLIB } else { COMPR_NONE };
+        assert_eq!(record.compression_method(), expected, "{}", record.filename());
+    }
+
+    let pak = Pak::from_path(&pak_path, Default::default())?;
+    assert_eq!(pak.index().records().len(), 2);
+
+    let out_dir = format!("{}/unpacked", work_dir);
+    util::unpack(&pak_path, &out_dir, None)?;
+    util::validate(&format!("{}/default", work_dir), &format!("{}/default", out_dir))?;
+    util::validate(&format!("{}/override", work_dir), &format!("{}/override", out_dir))?;
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_pack_no_compress_ext() -> Result<()> {
+    let work_dir = &util::work_dir("pack_no_compress_ext")?;
+
+    let data = compressible_data(4096);
+    write_file(format!("{}/files/a.txt", work_dir), &data)?;
+    write_file(format!("{}/files/b.PNG", work_dir), &data)?;
+    write_file(format!("{}/forced/c.png", work_dir), &data)?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/files:{}/files", work_dir),
+        format!(":zlib,rename=/forced:{}/forced", work_dir),
+    ];
+
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        compression_method: COMPR_ZLIB,
+        ..PackOptions::default()
+    })?;
+
+    for record in pak.index().records() {
+        let expected = if record.filename() == "files/b.PNG" { COMPR_NONE } else { COMPR_ZLIB };
+        assert_eq!(record.compression_method(), expected, "{}", record.filename());
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_pack_progress() -> Result<()> {
+    let work_dir = &util::work_dir("pack_progress")?;
+
+    let sizes = [0usize, 10, 4096, 100_000];
+    for (index, size) in sizes.iter().enumerate() {
+        write_file(format!("{}/src/{}.txt", work_dir, index), &compressible_data(*size))?;
+    }
+    let total_bytes = sizes.iter().sum::<usize>() as u64;
+
+    let last_info = Mutex::new(ProgressInfo::default());
+    let callback = |info: &ProgressInfo| {
+        let mut last_info = last_info.lock().unwrap();
+        assert!(info.processed_files <= info.total_files);
+        assert!(info.processed_bytes <= info.total_bytes);
+        // not done while the totals can still grow
+        assert!(info.totals_final || !info.is_done());
+        if info.processed_files > last_info.processed_files || (info.processed_files == last_info.processed_files && info.totals_final) {
+            *last_info = *info;
+        }
+    };
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        compression_method: COMPR_ZLIB,
+        progress: Some(&callback),
+        ..PackOptions::default()
+    })?;
+
+    assert_eq!(*last_info.lock().unwrap(), ProgressInfo {
+        processed_files: sizes.len(),
+        total_files: sizes.len(),
+        processed_bytes: total_bytes,
+        total_bytes,
+        totals_final: true,
+    });
+
+    *last_info.lock().unwrap() = ProgressInfo::default();
+    let error_count = check(&pak, &mut File::open(&pak_path)?, CheckOptions {
+        progress: Some(&callback),
+        ..CheckOptions::default()
+    })?;
+    assert_eq!(error_count, 0);
+
+    let compressed_size: u64 = pak.index().records().iter().map(|record| record.size()).sum();
+    let info = *last_info.lock().unwrap();
+    assert!(info.is_done());
+    assert_eq!(info.processed_bytes, compressed_size);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct FileProgress {
+    paths: Mutex<Vec<String>>,
+}
+
+impl ProgressCallback for FileProgress {
+    fn progress(&self, _info: &ProgressInfo) {
+        panic!("progress() called instead of file_done()");
+    }
+
+    fn file_done(&self, path: &str, info: &ProgressInfo) {
+        assert!(info.processed_files <= info.total_files);
+        self.paths.lock().unwrap().push(path.to_string());
+    }
+}
+
+impl FileProgress {
+    fn take_sorted(&self) -> Vec<String> {
+        let mut paths = std::mem::take(&mut *self.paths.lock().unwrap());
+        paths.sort();
+        paths
+    }
+}
+
+#[test]
+fn test_progress_file_done() -> Result<()> {
+    let work_dir = &util::work_dir("progress_file_done")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), &compressible_data(100))?;
+    write_file(format!("{}/src/sub/b.txt", work_dir), &compressible_data(10_000))?;
+    let expected = vec!["a.txt".to_string(), "sub/b.txt".to_string()];
+
+    let progress = FileProgress::default();
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        progress: Some(&progress),
+        ..PackOptions::default()
+    })?;
+    assert_eq!(progress.take_sorted(), expected);
+
+    let error_count = check(&pak, &mut File::open(&pak_path)?, CheckOptions {
+        progress: Some(&progress),
+        ..CheckOptions::default()
+    })?;
+    assert_eq!(error_count, 0);
+    assert_eq!(progress.take_sorted(), expected);
+
+    unpack(&pak, &mut File::open(&pak_path)?, format!("{}/out", work_dir), UnpackOptions {
+        progress: Some(&progress),
+        ..UnpackOptions::default()
+    })?;
+    assert_eq!(progress.take_sorted(), expected);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_pack_footer() -> Result<()> {
+    let work_dir = &util::work_dir("pack_footer")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), b"hello")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+    // packing returns a pak that wasn't read from a file
+    assert!(util::pack(&pak_path, &paths, PackOptions::default())?.footer().is_none());
+
+    let pak = Pak::from_path(&pak_path, Default::default())?;
+    let footer = pak.footer().unwrap();
+    let file_size = std::fs::metadata(&pak_path)?.len();
+    assert_eq!(footer.footer_offset() + footer.footer_size(), file_size);
+    assert_eq!(footer.footer_offset(), pak.index_offset() + pak.index_size());
+    assert_eq!(footer.version(), 3);
+    assert_eq!(footer.magic(), u4pak::pak::PAK_MAGIC);
+    assert!(!footer.encrypted_index());
+    assert!(footer.compression_methods().is_empty());
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_parallel_block_compression() -> Result<()> {
+    use std::num::{NonZeroU32, NonZeroUsize};
+
+    let work_dir = &util::work_dir("parallel-block-compression")?;
+
+    let data = compressible_data(1024 * 1024 + 4321);
+    write_file(format!("{}/files/big.bin", work_dir), &data)?;
+    write_file(format!("{}/files/small.txt", work_dir), b"small file")?;
+
+    let paths: Vec<PackPath> = vec![
+        format!(":zlib,rename=/big.bin:{}/files/big.bin", work_dir).as_str().try_into()?,
+        format!(":rename=/small.txt:{}/files/small.txt", work_dir).as_str().try_into()?,
+    ];
+
+    let mut paks = Vec::new();
+    for thread_count in [1, 4] {
+        let pak_path = format!("{}/Game-{}.pak", work_dir, thread_count);
+        pack(&pak_path, &paths[..1], PackOptions {
+            thread_count: NonZeroUsize::new(thread_count).unwrap(),
+            compression_block_size: NonZeroU32::new(16 * 1024).unwrap(),
+            ..PackOptions::default()
+        })?;
+        paks.push(std::fs::read(&pak_path)?);
+    }
+    // the blocks are reassembled in order, so the result is the same
+    assert_eq!(paks[0], paks[1]);
+
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let pak = pack(&pak_path, &paths, PackOptions {
+        thread_count: NonZeroUsize::new(4).unwrap(),
+        compression_block_size: NonZeroU32::new(16 * 1024).unwrap(),
+        ..PackOptions::default()
+    })?;
+    let record = pak.record("big.bin").unwrap();
+    assert_eq!(record.compression_blocks().as_ref().unwrap().len(), 65);
+
+    let mut file = File::open(&pak_path)?;
+    let mut unpacked = Vec::new();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), &mut file, &mut unpacked, None, pak.cipher())?;
+    assert_eq!(unpacked, data);
+    assert_eq!(check(&pak, &mut file, CheckOptions::default())?, 0);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_pack_order_alignment_entry_options() -> Result<()> {
+    use std::num::NonZeroU64;
+    use u4pak::pack::{EntryOptions, PackOrder};
+
+    let work_dir = &util::work_dir("pack-order")?;
+
+    let data = compressible_data(300_000);
+    let names = ["d.txt", "b.bin", "a.txt", "c.bin", "e.txt"];
+    for (index, name) in names.iter().enumerate() {
+        write_file(format!("{}/src/{}", work_dir, name), &data[..1000 + index * 50_000])?;
+    }
+    let paths: Vec<PackPath> = names.iter()
+        .map(|name| format!(":rename=/{}:{}/src/{}", name, work_dir, name).as_str().try_into())
+        .collect::<Result<_>>()?;
+
+    // compress only the big .txt files
+    let entry_options = |path: &Path, size: u64| EntryOptions {
+        compression_method: Some(
+            if size > 10_000 && path.extension() == Some("txt".as_ref()) { COMPR_ZLIB } else { COMPR_NONE }),
+        ..EntryOptions::default()
+    };
+
+    for order in [PackOrder::Input, PackOrder::Name] {
+        let pak_path = format!("{}/out.pak", work_dir);
+        let pak = pack(&pak_path, &paths, PackOptions {
+            order,
+            alignment: NonZeroU64::new(4096),
+            entry_options: Some(&entry_options),
+            compression_method: COMPR_ZLIB,
+            max_memory: NonZeroU64::new(60_000),
+            ..PackOptions::default()
+        })?;
+
+        let filenames: Vec<&str> = pak.index().records().iter().map(|record| record.filename()).collect();
+        if order == PackOrder::Name {
+            assert_eq!(filenames, ["a.txt", "b.bin", "c.bin", "d.txt", "e.txt"]);
+        } else {
+            assert_eq!(filenames, names);
+        }
+
+        let mut last_offset = None;
+        for record in pak.index().records() {
+            assert_eq!(record.offset() % 4096, 0, "{}", record.filename());
+            assert!(last_offset < Some(record.offset()));
+            last_offset = Some(record.offset());
+
+            let expected = match record.filename() {
+                "a.txt" | "e.txt" => COMPR_ZLIB,
+                _ => COMPR_NONE,
+            };
+            assert_eq!(record.compression_method(), expected, "{}", record.filename());
+        }
+
+        assert_eq!(check(&pak, &mut File::open(&pak_path)?, CheckOptions::default())?, 0);
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_pack_v4_v5_v7() -> Result<()> {
+    use u4pak::pak::Variant;
+    use u4pak::variant::record_layout;
+
+    let work_dir = &util::work_dir("pack-v4-v5-v7")?;
+
+    let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/plain.txt", work_dir), &data[..4096])?;
+    write_file(format!("{}/src/compressed.txt", work_dir), &data)?;
+    write_file(format!("{}/src/secret.txt", work_dir), &data[..1000])?;
+    write_file(format!("{}/src/secret_compressed.txt", work_dir), &data)?;
+
+    for version in [4, 5, 7] {
+        let pak_path = format!("{}/out-{}.pak", work_dir, version);
+        let paths = [
+            format!(":rename=/plain.txt:{}/src/plain.txt", work_dir),
+            format!(":zlib,rename=/compressed.txt:{}/src/compressed.txt", work_dir),
+            format!(":encrypt,rename=/secret.txt:{}/src/secret.txt", work_dir),
+            format!(":zlib,encrypt,rename=/secret_compressed.txt:{}/src/secret_compressed.txt", work_dir),
+        ];
+        util::pack(&pak_path, &paths, PackOptions {
+            version,
+            encryption_key: Some(base64::decode(key).unwrap()),
+            ..PackOptions::default()
+        })?;
+
+        let pak = Pak::from_path(&pak_path, u4pak::pak::Options::default())?;
+        assert_eq!(pak.version(), version);
+
+        // the unknown field in front of the data of compressed records
+        let layout = record_layout(version, Variant::Standard)?;
+        let pak_data = std::fs::read(&pak_path)?;
+        let record = pak.record("compressed.txt").unwrap();
+        let field_offset = (record.offset() + layout.header_size(record)) as usize;
+        assert_eq!(&pak_data[field_offset..field_offset + 4], &[0, 0, 0, 0], "version {}", version);
+        assert_eq!(record.block_ranges(version).next().unwrap().0, field_offset as u64 + 4, "version {}", version);
+
+        let errors = check_with_report(&pak, &mut File::open(&pak_path)?, CheckOptions {
+            deep: true,
+            encryption_key: Some(base64::decode(key).unwrap()),
+            ..CheckOptions::default()
+        })?;
+        assert!(errors.is_empty(), "version {}: {:?}", version, errors);
+
+        let out_dir = format!("{}/unpacked-{}", work_dir, version);
+        util::unpack(&pak_path, &out_dir, Some(key.to_string()))?;
+        util::validate(&format!("{}/src", work_dir), &out_dir)?;
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_pack_glob() -> Result<()> {
+    let work_dir = &util::work_dir("pack_glob")?;
+
+    for path in ["x.uasset", "a/y.uasset", "a/b/z.uasset", "a/b/z.uexp", "c/w.txt"] {
+        write_file(format!("{}/Content/{}", work_dir, path), path.as_bytes())?;
+    }
+
+    let glob = Glob::new("**/*.uasset")?;
+    assert!(glob.matches("x.uasset"));
+    assert!(glob.matches("a/b/z.uasset"));
+    assert!(!glob.matches("a/b/z.uexp"));
+    assert!(Glob::new("a/?/[!x-z]*.u[a-e]*")?.matches("a/b/c.uexp"));
+    assert!(!Glob::new("a/?/[!x-z]*")?.matches("a/b/z.uexp"));
+    assert!(Glob::new("[a-").is_err());
+    assert_eq!(split_glob("Content/**/*.uasset"), Some(("Content", "**/*.uasset")));
+    assert_eq!(split_glob("*.txt"), Some(("", "*.txt")));
+    assert_eq!(split_glob("Content/a.txt"), None);
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":rename=/Game:{}/Content/**/*.uasset", work_dir).as_str().try_into()?,
+    ];
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+
+    let mut names: Vec<&str> = pak.index().records().iter().map(Record::filename).collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["Game/a/b/z.uasset", "Game/a/y.uasset", "Game/x.uasset"]);
+    let paths: Vec<PackPath> = vec![
+        format!("{}/Content/**/*.nothing", work_dir).as_str().try_into()?,
+    ];
+    let error = pack(&pak_path, &paths, PackOptions::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::PathNotFound);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_dedupe() -> Result<()> {
+    let work_dir = &util::work_dir("dedupe")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/dir/copy.txt", work_dir), &data)?;
+    write_file(format!("{}/src/other.txt", work_dir), &data[..5000])?;
+
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+
+    ];
+
+    let plain_path = format!("{}/plain.pak", work_dir);
+    let plain = util::pack(&plain_path, &paths, PackOptions::default())?;
+    let duplicates = find_duplicates(&plain);
+    assert_eq!(duplicates.len(), 1);
+    let mut filenames: Vec<&str> = duplicates[0].records.iter().map(|record| record.filename()).collect();
+    filenames.sort_unstable();
+    assert_eq!(filenames, ["a.txt", "dir/copy.txt"]);
+    assert_eq!(duplicates[0].uncompressed_size, data.len() as u64);
+    assert!(duplicates[0].wasted > 0);
+
+    let deduped_path = format!("{}/deduped.pak", work_dir);
+    util::pack(&deduped_path, &paths, PackOptions {
+        dedupe: true,
+        ..PackOptions::default()
+    })?;
+    let deduped = Pak::from_path(&deduped_path, Default::default())?;
+    let duplicates = find_duplicates(&deduped);
+    assert_eq!(duplicates.len(), 1);
+    assert_eq!(duplicates[0].wasted, 0);
+    assert_eq!(duplicates[0].records[0].offset(), duplicates[0].records[1].offset());
+    assert!(std::fs::metadata(&deduped_path)?.len() < std::fs::metadata(&plain_path)?.len());
+    assert_eq!(check(&deduped, &mut File::open(&deduped_path)?, CheckOptions::default())?, 0);
+
+    let out_dir = format!("{}/unpacked", work_dir);
+    util::unpack(&deduped_path, &out_dir, None)?;
+    util::validate(&format!("{}/src", work_dir), &out_dir)?;
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_bench_compress() -> Result<()> {
+    let work_dir = &util::work_dir("bench_compress")?;
+
+    write_file(format!("{}/text/a.txt", work_dir), &compressible_data(100_000))?;
+    write_file(format!("{}/text/b.txt", work_dir), &compressible_data(50_000))?;
+    write_file(format!("{}/text/skipped.png", work_dir), &compressible_data(50_000))?;
+
+    // xorshift output doesn't compress
+    let mut state = 0x2545F4914F6CDD1Du64;
+    let noise: Vec<u8> = (0..100_000).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect();
+    write_file(format!("{}/noise/noise.bin", work_dir), &noise)?;
+
+    let options = BenchOptions {
+        sample_size: 120_000,
+        levels: vec![1, 9],
+        block_sizes: vec![1024, 65536],
+        no_compress_exts: ["png".to_string()].iter().cloned().collect(),
+    };
+
+    let report = bench_compress(format!("{}/text", work_dir), &options)?;
+    assert_eq!(report.file_count, 2);
+    assert_eq!(report.sample_size, 120_000);
+    assert_eq!(report.results.len(), 5);
+    assert_eq!(report.results[0].compression_method, COMPR_NONE);
+    assert_eq!(report.results[0].compressed_size, report.sample_size);
+    let recommended = report.recommendation().unwrap();
+    assert_eq!(recommended.compression_method, COMPR_ZLIB);
+    assert!(recommended.compressed_size < report.sample_size / 10);
+
+    let report = bench_compress(format!("{}/noise", work_dir), &options)?;
+    assert_eq!(report.recommendation().unwrap().compression_method, COMPR_NONE);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_tune() -> Result<()> {
+    use std::time::Duration;
+
+    assert_eq!(StorageKind::from_latency(Duration::from_micros(50)), StorageKind::Ssd);
+    assert_eq!(StorageKind::from_latency(Duration::from_millis(8)), StorageKind::Hdd);
+    assert_eq!(StorageKind::from_latency(Duration::from_millis(80)), StorageKind::Network);
+
+    let ssd = Tuning::for_storage(StorageKind::Ssd, 8);
+    let hdd = Tuning::for_storage(StorageKind::Hdd, 8);
+    let network = Tuning::for_storage(StorageKind::Network, 8);
+    assert_eq!(ssd.thread_count.get(), 8);
+    assert_eq!(hdd.thread_count.get(), 2);
+    assert!(network.thread_count > ssd.thread_count);
+    assert!(hdd.read_size > ssd.read_size);
+    assert_eq!(Tuning::for_storage(StorageKind::Hdd, 1).thread_count.get(), 1);
+
+    let work_dir = &util::work_dir("tune")?;
+    let path = format!("{}/file.bin", work_dir);
+    write_file(&path, &compressible_data(1_000_000))?;
+    // just written, so it is in the page cache
+    assert_eq!(tune(&path)?.storage, StorageKind::Ssd);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
@@ -0,0 +1,246 @@
+mod util;
+
+use std::convert::TryInto;
+use std::fs::File;
+
+use u4pak::check::{check, CheckOptions};
+use u4pak::pack::{pack, PackOptions, PackPath};
+use u4pak::pak::{COMPR_NONE, COMPR_ZLIB};
+use u4pak::repair::repair;
+use u4pak::salvage::{salvage, write_rebuilt_pak, SalvageOptions};
+use u4pak::{Pak, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_pack_salvage() -> Result<()> {
+    let work_dir = &util::work_dir("pack_salvage")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..10])?;
+    write_file(format!("{}/src/c.txt", work_dir), &data[..5000])?;
+
+    for &version in &[1, 2, 3] {
+        let pak_path = format!("{}/v{}.pak", work_dir, version);
+        let paths = [
+            format!(":rename=/:{}/src", work_dir),
+        ];
+        let pak = util::pack(&pak_path, &paths, PackOptions {
+            version,
+            compression_method: if version > 1 { COMPR_ZLIB } else { COMPR_NONE },
+            compression_block_size: std::num::NonZeroU32::new(64 * 1024).unwrap(),
+            ..PackOptions::default()
+        })?;
+
+        // destroy index and footer
+        let mut pak_data = std::fs::read(&pak_path)?;
+        for byte in &mut pak_data[pak.index_offset() as usize..] {
+            *byte = 0;
+        }
+        std::fs::write(&pak_path, &pak_data)?;
+        assert!(Pak::from_path(&pak_path, Default::default()).is_err());
+
+        let mut file = File::open(&pak_path)?;
+        let salvaged = salvage(&mut file, SalvageOptions {
+            version,
+            ..SalvageOptions::default()
+        })?;
+
+        assert_eq!(salvaged.index_offset(), pak.index_offset());
+        assert_eq!(salvaged.index().records().len(), pak.index().records().len());
+        for (record, salvaged_record) in pak.index().records().iter().zip(salvaged.index().records()) {
+            assert!(record.same_metadata(salvaged_record), "v{} {}:\n{}",
+                version, record.filename(), record.metadata_diff(salvaged_record));
+        }
+
+        let rebuilt_path = format!("{}/v{}-rebuilt.pak", work_dir, version);
+        write_rebuilt_pak(&salvaged, &mut file, &rebuilt_path, Default::default())?;
+        let rebuilt = Pak::from_path(&rebuilt_path, Default::default())?;
+        let error_count = check(&rebuilt, &mut File::open(&rebuilt_path)?, CheckOptions::default())?;
+        assert_eq!(error_count, 0);
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_pack_repair() -> Result<()> {
+    let work_dir = &util::work_dir("pack_repair")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..1000])?;
+
+    let pak_path = format!("{}/broken.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+        format!(":rename=/b.txt:{}/src/b.txt", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    // corrupt the uncompressed size of the first index record (after the
+    // mount point, the record count, the filename, offset and size)
+    let mut pak_data = std::fs::read(&pak_path)?;
+    let filename_size = pak.index().records()[0].filename().len() + 1;
+    let field_offset = pak.index_offset() as usize + 4 + 1 + 4 + 4 + filename_size + 8 + 8;
+    pak_data[field_offset..field_offset + 8].copy_from_slice(&12345u64.to_le_bytes());
+    std::fs::write(&pak_path, &pak_data)?;
+
+    let broken = Pak::from_path(&pak_path, Default::default())?;
+    assert_eq!(broken.index().records()[0].uncompressed_size(), 12345);
+    assert!(check(&broken, &mut File::open(&pak_path)?, CheckOptions::default())? > 0);
+
+    let fixed_path = format!("{}/fixed.pak", work_dir);
+    let repaired = repair(&broken, &mut File::open(&pak_path)?, &fixed_path, Default::default())?;
+    assert_eq!(repaired.len(), 1);
+    assert_eq!(repaired[0].filename, "a.txt");
+
+    let fixed = Pak::from_path(&fixed_path, Default::default())?;
+    assert_eq!(fixed.index().records(), pak.index().records());
+    assert_eq!(check(&fixed, &mut File::open(&fixed_path)?, CheckOptions::default())?, 0);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_name_recovery() -> Result<()> {
+    use u4pak::index::{hashed_name, path_hash};
+    use u4pak::names::{content_prefixes, harvest_package_paths, is_package, package_path_candidates, PACKAGE_FILE_TAG};
+
+    // FNV-1a-64 offset basis for the empty string
+    assert_eq!(path_hash("", 0), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(path_hash("Game/Content/A.uasset", 7), path_hash("game/content/a.uasset", 7));
+    assert_ne!(path_hash("Game/Content/A.uasset", 7), path_hash("Game/Content/A.uasset", 8));
+
+    assert_eq!(hashed_name("00000000DEADBEEF"), Some(0xDEAD_BEEF));
+    assert_eq!(hashed_name("a.txt"), None);
+    assert_eq!(hashed_name("00000000000000000"), None);
+
+    let mut data = PACKAGE_FILE_TAG.to_le_bytes().to_vec();
+    data.extend_from_slice(b"\0\0\0\x1e/Game/Maps/Level.Level\0/Engine/BasicShapes/Cube\0/Game/\0");
+    assert!(is_package(&data));
+    assert!(!is_package(b"hello"));
+
+    let paths: Vec<String> = harvest_package_paths(&data).into_iter().collect();
+    assert_eq!(paths, vec!["/Engine/BasicShapes/Cube", "/Game/Maps/Level"]);
+
+    let prefixes = content_prefixes("../../../", vec!["MyGame/Content/A.uasset", "Engine/Content/B.uasset"]);
+    assert_eq!(prefixes, vec!["", "MyGame/Content/"]);
+    assert_eq!(content_prefixes("../../../MyGame/", Vec::new()), vec!["", "Content/"]);
+
+    let candidates = package_path_candidates("/Game/Maps/Level", &prefixes);
+    assert!(candidates.contains(&"Maps/Level.umap".to_string()));
+    assert!(candidates.contains(&"MyGame/Content/Maps/Level.uexp".to_string()));
+    assert_eq!(
+        package_path_candidates("/Engine/BasicShapes/Cube", &prefixes)[0],
+        "Engine/Content/BasicShapes/Cube.uasset");
+
+    // paks with a full directory index keep their names
+    let work_dir = &util::work_dir("name-recovery")?;
+    write_file(format!("{}/files/a.txt", work_dir), b"hello")?;
+    let paths: Vec<PackPath> = vec![
+        format!(":rename=/:{}/files", work_dir).as_str().try_into()?,
+    ];
+    let mut pak = pack(format!("{}/Game.pak", work_dir), &paths, PackOptions::default())?;
+    assert_eq!(pak.recover_names(vec!["a.txt"]), 0);
+    // no secondary index before version 10
+    assert_eq!(pak.path_hash_seed(), None);
+    assert!(pak.secondary_index().is_none());
+    assert_eq!(check(&pak, &mut File::open(format!("{}/Game.pak", work_dir))?, CheckOptions::default())?, 0);
+    assert!(pak.record("a.txt").is_some());
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_scan_footer() -> Result<()> {
+    use u4pak::pak::{Options, DEFAULT_FOOTER_SCAN_SIZE};
+
+    let work_dir = &util::work_dir("scan-footer")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    let paths = [
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+    ];
+
+    for version in [3, 7] {
+        let pak_path = format!("{}/out-{}.pak", work_dir, version);
+        util::pack(&pak_path, &paths, PackOptions {
+            version,
+            ..PackOptions::default()
+        })?;
+        let footer_offset = Pak::from_path(&pak_path, Options::default())?.footer().unwrap().footer_offset();
+
+        // something appended to the pak
+        let mut pak_data = std::fs::read(&pak_path)?;
+        pak_data.resize(pak_data.len() + 3000, 0);
+        std::fs::write(&pak_path, &pak_data)?;
+
+        assert!(Pak::from_path(&pak_path, Options::default()).is_err());
+
+        let pak = Pak::from_path(&pak_path, Options {
+            scan_footer: Some(DEFAULT_FOOTER_SCAN_SIZE),
+            ..Options::default()
+        })?;
+        assert_eq!(pak.version(), version);
+        assert_eq!(pak.footer().unwrap().footer_offset(), footer_offset);
+
+        let mut content = Vec::new();
+        u4pak::entry::extract_to(&pak, &mut File::open(&pak_path)?, "a.txt", &mut content)?;
+        assert_eq!(content, data);
+
+        let pak = Pak::from_path(&pak_path, Options {
+            force_version: Some(version),
+            scan_footer: Some(DEFAULT_FOOTER_SCAN_SIZE),
+            ..Options::default()
+        })?;
+        assert_eq!(pak.version(), version);
+
+        // the footer isn't within the searched range
+        assert!(Pak::from_path(&pak_path, Options {
+            scan_footer: Some(1000),
+            ..Options::default()
+        }).is_err());
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_keep_unknown_trailing_bytes() -> Result<()> {
+    let work_dir = &util::work_dir("keep-unknown")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), b"hello world")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/a.txt:{}/src/a.txt", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    // insert game specific data between the index and the footer
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options::default())?;
+    let footer_offset = pak.footer().unwrap().footer_offset() as usize;
+    let mut pak_data = std::fs::read(&pak_path)?;
+    pak_data.splice(footer_offset..footer_offset, b"extra data".iter().cloned());
+    std::fs::write(&pak_path, &pak_data)?;
+
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options::default())?;
+    assert!(pak.trailing_bytes().is_empty());
+
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options {
+        keep_unknown: true,
+        ..u4pak::pak::Options::default()
+    })?;
+    assert_eq!(pak.trailing_bytes(), b"extra data");
+    assert_eq!(pak.record("a.txt").unwrap().unknown(), 0);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
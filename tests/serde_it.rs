@@ -0,0 +1,45 @@
+mod util;
+
+use u4pak::pack::PackOptions;
+use u4pak::{Pak, Record, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_serde() -> Result<()> {
+    use u4pak::index::Index;
+    use u4pak::pak::PakMetadata;
+
+    let work_dir = &util::work_dir("serde")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), b"b")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/a.txt:{}/src/a.txt", work_dir),
+        format!(":rename=/b.txt:{}/src/b.txt", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions::default())?;
+    let pak = Pak::from_path(&pak_path, Default::default())?;
+
+    let record = pak.record("a.txt").unwrap();
+    let json = serde_json::to_value(record).unwrap();
+    assert_eq!(json["path"], "a.txt");
+    assert_eq!(json["sha1"], record.sha1().as_ref().map(|sha1| sha1.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()).unwrap());
+    assert_eq!(json["compression_blocks"].as_array().map(Vec::len), Some(record.compression_blocks().as_ref().unwrap().len()));
+    assert_eq!(serde_json::from_value::<Record>(json).unwrap(), *record);
+
+    let json = serde_json::to_string(&pak).unwrap();
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value["version"], pak.version());
+    assert_eq!(value["variant"], "standard");
+    assert_eq!(serde_json::from_value::<PakMetadata>(value.clone()).unwrap(), pak.metadata());
+
+    let index: Index = serde_json::from_value(value["index"].clone()).unwrap();
+    assert_eq!(index.records(), pak.index().records());
+    assert_eq!(index.mount_point(), pak.index().mount_point());
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
@@ -0,0 +1,916 @@
+mod util;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use u4pak::archive::{unpack_to_archive, ArchiveFormat, ArchiveOptions};
+use u4pak::entry::unpack_record_to_writer;
+use u4pak::check::{check, check_with_report, CheckOptions};
+use u4pak::pack::{pack, PackOptions, PackPath};
+use u4pak::pak::COMPR_ZLIB;
+use u4pak::progress::ProgressInfo;
+use u4pak::stream::copy_raw_index;
+use u4pak::unpack::{unpack, unpack_from_stream, unpack_with_report, Overwrite, UnpackOptions};
+use u4pak::{ErrorKind, Pak, Predicate, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_unpack_from_stream() -> Result<()> {
+    let work_dir = &util::work_dir("stream")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/dir/b.txt", work_dir), &data[..5000])?;
+    write_file(format!("{}/src/dir/copy.txt", work_dir), &data)?;
+
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+
+    ];
+
+    let pak_path = format!("{}/test.pak", work_dir);
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    let mut raw_index = Vec::new();
+    let size = copy_raw_index(&pak, &mut File::open(&pak_path)?, &mut raw_index)?;
+    assert_eq!(size, raw_index.len() as u64);
+    assert!(size < std::fs::metadata(&pak_path)?.len());
+
+    let index_pak = Pak::from_index_reader(&mut std::io::Cursor::new(&raw_index), Default::default())?;
+    assert_eq!(index_pak.index().records(), pak.index().records());
+
+    // a slice can only be read front to back
+    let stream = std::fs::read(&pak_path)?;
+    let out_dir = format!("{}/unpacked", work_dir);
+    unpack_from_stream(&index_pak, &stream[..], &out_dir, UnpackOptions::default())?;
+    util::validate(&format!("{}/src", work_dir), &out_dir)?;
+
+    // records sharing their data would need to seek back
+    let deduped_path = format!("{}/deduped.pak", work_dir);
+    let deduped = util::pack(&deduped_path, &paths, PackOptions {
+        dedupe: true,
+        ..PackOptions::default()
+    })?;
+    let stream = std::fs::read(&deduped_path)?;
+    let result = unpack_from_stream(&deduped, &stream[..], format!("{}/deduped", work_dir), UnpackOptions::default());
+    assert!(result.is_err());
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_skip_errors() -> Result<()> {
+    let work_dir = &util::work_dir("unpack_skip_errors")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..5000])?;
+    write_file(format!("{}/src/c.txt", work_dir), &data[..20_000])?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    // garble the deflate stream of b
+    let record = pak.record("b.txt").unwrap();
+    let start = record.data_offset(pak.version(), pak.variant()) as usize + 10;
+    let mut bytes = std::fs::read(&pak_path)?;
+    for byte in &mut bytes[start..start + 100] {
+        *byte ^= 0x55;
+    }
+    std::fs::write(&pak_path, &bytes)?;
+
+    let out_dir = format!("{}/out", work_dir);
+    assert!(unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions::default()).is_err());
+
+    remove_dir_all_if_exists(&out_dir)?;
+    let warnings = u4pak::warnings::Warnings::collect();
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options {
+        warnings: warnings.clone(),
+        ..u4pak::pak::Options::default()
+    })?;
+    let failures = unpack_with_report(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        skip_errors: true,
+        ..UnpackOptions::default()
+    })?;
+    assert_eq!(failures.len(), 1, "{:?}", failures);
+    // reported through the warnings of the pak instead of stderr
+    let collected = warnings.take();
+    assert_eq!(collected.len(), 1, "{:?}", collected);
+    assert_eq!(collected[0].path.as_deref(), Some("b.txt"));
+    assert_eq!(failures[0].filename, "b.txt");
+    assert_eq!(failures[0].offset, record.offset());
+    assert_eq!(failures[0].error.kind(), ErrorKind::DecompressionFailed);
+    assert_eq!(std::fs::read(format!("{}/a.txt", out_dir))?, data);
+    assert_eq!(std::fs::read(format!("{}/c.txt", out_dir))?, &data[..20_000]);
+
+    // still an error without reporting
+    assert!(unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        skip_errors: true,
+        ..UnpackOptions::default()
+    }).is_err());
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+// Packs a.txt (data) and b.txt (the first 5000 bytes of data) as a version 5
+// package with absolute compression block offsets, like version 4 has them.
+fn pack_v5_with_absolute_block_offsets(work_dir: &str, data: &[u8]) -> Result<String> {
+    write_file(format!("{}/src/a.txt", work_dir), data)?;
+    write_file(format!("{}/src/b.txt", work_dir), &data[..5000])?;
+
+    // version 4 stores absolute block offsets, claiming version 5 (same
+    // footer layout) makes them be read as relative to the record
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions {
+        version: 4,
+        ..PackOptions::default()
+    })?;
+
+    let mut bytes = std::fs::read(&pak_path)?;
+    let footer_start = bytes.len() - 64;
+    let magic_offset = footer_start + bytes[footer_start..].windows(4)
+        .position(|window| window == u4pak::pak::PAK_MAGIC.to_le_bytes())
+        .unwrap();
+    bytes[magic_offset + 4..magic_offset + 8].copy_from_slice(&5u32.to_le_bytes());
+    std::fs::write(&pak_path, &bytes)?;
+
+    Ok(pak_path)
+}
+
+#[test]
+fn test_unpack_fallback_decompression() -> Result<()> {
+    let work_dir = &util::work_dir("unpack_fallback_decompression")?;
+
+    let data = compressible_data(200_000);
+    let pak_path = pack_v5_with_absolute_block_offsets(work_dir, &data)?;
+
+    let warnings = u4pak::warnings::Warnings::collect();
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options {
+        detect_block_offsets: false,
+        warnings: warnings.clone(),
+        ..Default::default()
+    })?;
+    assert_eq!(pak.version(), 5);
+
+    let out_dir = format!("{}/out", work_dir);
+    assert!(unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions::default()).is_err());
+
+    remove_dir_all_if_exists(&out_dir)?;
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        fallback_decompression: true,
+        ..UnpackOptions::default()
+    })?;
+    assert_eq!(std::fs::read(format!("{}/a.txt", out_dir))?, data);
+    assert_eq!(std::fs::read(format!("{}/b.txt", out_dir))?, &data[..5000]);
+
+    let u4pak::warnings::Warnings::Collect(warnings) = warnings else { unreachable!() };
+    let warnings = warnings.lock().unwrap();
+    // both interpretations are the same for the record at offset 0
+    let moved: Vec<&str> = pak.index().records().iter()
+        .filter(|record| record.offset() != 0)
+        .map(|record| record.filename())
+        .collect();
+    assert_eq!(moved.len(), 1);
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert_eq!(warnings[0].path.as_deref(), Some(moved[0]));
+    assert!(warnings[0].message.contains("absolute block offsets"), "{:?}", warnings);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_detect_block_offsets() -> Result<()> {
+    use u4pak::pak::Options;
+    use u4pak::warnings::Warnings;
+
+    let work_dir = &util::work_dir("detect_block_offsets")?;
+
+    let data = compressible_data(200_000);
+    let pak_path = pack_v5_with_absolute_block_offsets(work_dir, &data)?;
+
+    let undetected = Pak::from_path(&pak_path, Options {
+        detect_block_offsets: false,
+        ..Options::default()
+    })?;
+    assert_eq!(undetected.detect_relative_block_offsets(&mut File::open(&pak_path)?), Some(false));
+    assert!(check(&undetected, &mut File::open(&pak_path)?, CheckOptions::default())? > 0);
+
+    let warnings = Warnings::collect();
+    let pak = Pak::from_path(&pak_path, Options {
+        warnings: warnings.clone(),
+        ..Options::default()
+    })?;
+    assert_eq!(pak.version(), 5);
+    assert!(pak.rebased_block_offsets());
+    let Warnings::Collect(collected) = &warnings else { unreachable!() };
+    assert_eq!(collected.lock().unwrap().len(), 1, "{:?}", collected.lock().unwrap());
+
+    // converted to the convention of the version
+    assert_eq!(pak.detect_relative_block_offsets(&mut File::open(&pak_path)?), Some(true));
+    assert_eq!(check(&pak, &mut File::open(&pak_path)?, CheckOptions::default())?, 0);
+
+    let out_dir = format!("{}/out", work_dir);
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions::default())?;
+    assert_eq!(std::fs::read(format!("{}/a.txt", out_dir))?, data);
+    assert_eq!(std::fs::read(format!("{}/b.txt", out_dir))?, &data[..5000]);
+
+    // regular packages are left alone
+    for version in [4, 5] {
+        let regular_path = format!("{}/regular-v{}.pak", work_dir, version);
+        let paths = [
+            format!(":zlib,rename=/:{}/src", work_dir),
+        ];
+        util::pack(&regular_path, &paths, PackOptions {
+            version,
+            ..PackOptions::default()
+        })?;
+        let regular = Pak::from_path(&regular_path, Options {
+            warnings: Warnings::collect(),
+            ..Options::default()
+        })?;
+        assert!(!regular.rebased_block_offsets());
+        assert_eq!(regular.detect_relative_block_offsets(&mut File::open(&regular_path)?), Some(version >= 5));
+        let Warnings::Collect(collected) = regular.warnings() else { unreachable!() };
+        assert!(collected.lock().unwrap().is_empty(), "{:?}", collected.lock().unwrap());
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_overwrite() -> Result<()> {
+    let work_dir = &util::work_dir("unpack_overwrite")?;
+
+    let data = compressible_data(4096);
+    write_file(format!("{}/src/same.txt", work_dir), &data)?;
+    write_file(format!("{}/src/changed.txt", work_dir), &data)?;
+    write_file(format!("{}/src/resized.txt", work_dir), &data)?;
+    write_file(format!("{}/src/missing.txt", work_dir), &data)?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    let out_dir = format!("{}/out", work_dir);
+    let mut changed = data.clone();
+    changed[0] ^= 1;
+    let reset = || -> Result<()> {
+        write_file(format!("{}/same.txt", out_dir), &data)?;
+        write_file(format!("{}/changed.txt", out_dir), &changed)?;
+        write_file(format!("{}/resized.txt", out_dir), b"resized")?;
+        let _ = std::fs::remove_file(format!("{}/missing.txt", out_dir));
+        Ok(())
+    };
+    let unpack_with = |overwrite: Overwrite| unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        overwrite,
+        ..UnpackOptions::default()
+    });
+    let read = |name: &str| std::fs::read(format!("{}/{}", out_dir, name));
+
+    reset()?;
+    unpack_with(Overwrite::Never)?;
+    assert_eq!(read("changed.txt")?, changed);
+    assert_eq!(read("resized.txt")?, b"resized");
+    assert_eq!(read("missing.txt")?, data);
+
+    reset()?;
+    unpack_with(Overwrite::Update)?;
+    assert_eq!(read("changed.txt")?, data);
+    assert_eq!(read("resized.txt")?, data);
+    assert_eq!(read("missing.txt")?, data);
+
+    reset()?;
+    unpack_with(Overwrite::Always)?;
+    util::validate(&format!("{}/src", work_dir), &out_dir)?;
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_preserve_timestamps() -> Result<()> {
+    let work_dir = &util::work_dir("unpack_preserve_timestamps")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), b"hello")?;
+
+    let mtime = |path: &str| -> Result<u64> {
+        let modified = std::fs::metadata(path)?.modified()?;
+        Ok(modified.duration_since(std::time::UNIX_EPOCH)?.as_secs())
+    };
+
+    for &version in &[1, 3] {
+        let pak_path = format!("{}/v{}.pak", work_dir, version);
+        let paths = [
+            format!(":rename=/a.txt:{}/src/a.txt", work_dir),
+        ];
+        let pak = util::pack(&pak_path, &paths, PackOptions {
+            version,
+            ..PackOptions::default()
+        })?;
+
+        let out_dir = format!("{}/out{}", work_dir, version);
+        unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+            preserve_timestamps: true,
+            fallback_timestamp: Some(1_000_000_000),
+            ..UnpackOptions::default()
+        })?;
+
+        let expected = pak.index().records()[0].timestamp().unwrap_or(1_000_000_000);
+        assert_eq!(mtime(&format!("{}/a.txt", out_dir))?, expected, "v{}", version);
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_unsafe_paths() -> Result<()> {
+    let work_dir = &util::work_dir("unpack_unsafe_paths")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), b"hello")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":rename=/a.txt:{}/src/a.txt", work_dir).as_str().try_into()?,
+        format!(":rename=/sub/../../escaped.txt:{}/src/a.txt", work_dir).as_str().try_into()?,
+    ];
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+    assert!(pak.index().records().iter().any(|record| record.filename().contains("..")));
+
+    let out_dir = format!("{}/out/dir", work_dir);
+    let error = unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions::default()).unwrap_err();
+    assert!(error.to_string().contains("escaped.txt"), "{}", error);
+    // nothing is written if there are unsafe paths
+    assert!(!Path::new(&format!("{}/a.txt", out_dir)).exists());
+
+    // unpacking only the safe file is fine
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        paths: Some(&["a.txt"]),
+        ..UnpackOptions::default()
+    })?;
+    assert!(Path::new(&format!("{}/a.txt", out_dir)).exists());
+
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        allow_unsafe_paths: true,
+        ..UnpackOptions::default()
+    })?;
+    assert!(Path::new(&format!("{}/out/escaped.txt", work_dir)).exists());
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_strip_prefix_flatten() -> Result<()> {
+    use u4pak::schedule::WorkOrder;
+
+    let work_dir = &util::work_dir("unpack-strip-prefix-flatten")?;
+
+    let files = [
+        ("Game/Content/Maps/level.umap", "level"),
+        ("Game/Content/Textures/wall.uasset", "wall 1"),
+        ("Game/Content/wall.uasset", "wall 2"),
+        ("Game/Config/wall.uasset", "wall 3"),
+        ("Engine/readme", "readme"),
+    ];
+    let mut paths: Vec<PackPath> = Vec::new();
+    for (index, (name, content)) in files.iter().enumerate() {
+        write_file(format!("{}/src/{}.txt", work_dir, index), content.as_bytes())?;
+        paths.push(format!(":rename=/{}:{}/src/{}.txt", name, work_dir, index).as_str().try_into()?);
+    }
+    let pak_path = format!("{}/out.pak", work_dir);
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+
+    let out_dir = format!("{}/stripped", work_dir);
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        strip_prefix: Some("/Game/Content/"),
+        ..UnpackOptions::default()
+    })?;
+    assert_eq!(std::fs::read_to_string(format!("{}/Maps/level.umap", out_dir))?, "level");
+    assert_eq!(std::fs::read_to_string(format!("{}/Textures/wall.uasset", out_dir))?, "wall 1");
+    assert_eq!(std::fs::read_to_string(format!("{}/wall.uasset", out_dir))?, "wall 2");
+    // outside of the prefix
+    assert_eq!(std::fs::read_to_string(format!("{}/Game/Config/wall.uasset", out_dir))?, "wall 3");
+    assert_eq!(std::fs::read_to_string(format!("{}/Engine/readme", out_dir))?, "readme");
+
+    let out_dir = format!("{}/flat", work_dir);
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        flatten: true,
+        order: WorkOrder::Offset,
+        ..UnpackOptions::default()
+    })?;
+    assert_eq!(std::fs::read_to_string(format!("{}/level.umap", out_dir))?, "level");
+    assert_eq!(std::fs::read_to_string(format!("{}/wall.uasset", out_dir))?, "wall 1");
+    assert_eq!(std::fs::read_to_string(format!("{}/wall (2).uasset", out_dir))?, "wall 2");
+    assert_eq!(std::fs::read_to_string(format!("{}/wall (3).uasset", out_dir))?, "wall 3");
+    assert_eq!(std::fs::read_to_string(format!("{}/readme", out_dir))?, "readme");
+    assert_eq!(std::fs::read_dir(&out_dir)?.count(), files.len());
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+// the source files need names that Windows can't create
+#[cfg(target_family = "unix")]
+#[test]
+fn test_unpack_invalid_names() -> Result<()> {
+    use u4pak::portable::{is_valid_name, map_name, NamePolicy};
+
+    let work_dir = &util::work_dir("unpack-invalid-names")?;
+
+    assert!(is_valid_name("CONSOLE.txt"));
+    assert!(!is_valid_name("nul.tar.gz"));
+    assert!(!is_valid_name("a.txt "));
+    assert_eq!(map_name("e\u{301}.txt", NamePolicy::Keep, true)?, "\u{e9}.txt");
+    assert_eq!(map_name("e\u{301}.txt", NamePolicy::Keep, false)?, "e\u{301}.txt");
+    assert_eq!(map_name("Com1", NamePolicy::Replace, false)?, "_om1");
+
+    let files = ["a:b?.txt", "ctl\u{85}.txt", "CON.txt", "100%.txt", "dir./x.txt", "e\u{301}.txt"];
+    for (index, name) in files.iter().enumerate() {
+        write_file(format!("{}/src/{}", work_dir, name), index.to_string().as_bytes())?;
+    }
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    let unpack_with = |out_dir: &str, name_policy: NamePolicy| unpack(&pak, &mut File::open(&pak_path)?, out_dir, UnpackOptions {
+        name_policy,
+        normalize_nfc: true,
+        ..UnpackOptions::default()
+    });
+
+    let out_dir = format!("{}/percent", work_dir);
+    unpack_with(&out_dir, NamePolicy::PercentEncode)?;
+    let expected = ["a%3Ab%3F.txt", "ctl%C2%85.txt", "%43ON.txt", "100%.txt", "dir%2E/x.txt", "\u{e9}.txt"];
+    for (index, name) in expected.iter().enumerate() {
+        assert_eq!(std::fs::read_to_string(format!("{}/{}", out_dir, name))?, index.to_string(), "{}", name);
+    }
+
+    let out_dir = format!("{}/replace", work_dir);
+    unpack_with(&out_dir, NamePolicy::Replace)?;
+    let expected = ["a_b_.txt", "ctl_.txt", "_ON.txt", "100%.txt", "dir_/x.txt", "\u{e9}.txt"];
+    for (index, name) in expected.iter().enumerate() {
+        assert_eq!(std::fs::read_to_string(format!("{}/{}", out_dir, name))?, index.to_string(), "{}", name);
+    }
+
+    let out_dir = format!("{}/error", work_dir);
+    let error = unpack_with(&out_dir, NamePolicy::Error).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::UnsafePath);
+    assert!(error.to_string().contains("CON.txt"), "{}", error);
+    assert!(!error.to_string().contains("100%.txt"), "{}", error);
+    assert!(!Path::new(&out_dir).exists());
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_reserved_names_long_paths() -> Result<()> {
+    use u4pak::portable::long_path;
+    use u4pak::unpack::unpack_record;
+
+    let work_dir = &util::work_dir("unpack-reserved-names-long-paths")?;
+
+    let long_dir = vec!["d".repeat(60); 5].join("/");
+    let files = ["Game/aux.uasset", "con/x.txt", &format!("Game/{}/long.uasset", long_dir)];
+    let mut paths: Vec<PackPath> = Vec::new();
+    for (index, name) in files.iter().enumerate() {
+        write_file(format!("{}/src/{}.txt", work_dir, index), name.as_bytes())?;
+        paths.push(format!(":rename=/{}:{}/src/{}.txt", name, work_dir, index).as_str().try_into()?);
+    }
+    let pak_path = format!("{}/out.pak", work_dir);
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+
+    // Windows can't write these names as they are
+    let expected = if cfg!(target_os = "windows") {
+        ["Game/%61ux.uasset", "%63on/x.txt"]
+    } else {
+        ["Game/aux.uasset", "con/x.txt"]
+    };
+
+    let out_dir = format!("{}/record", work_dir);
+    for (name, expected) in files.iter().zip(expected.iter()) {
+        let path = unpack_record(pak.record(name).unwrap(), pak.version(), pak.variant(), &mut File::open(&pak_path)?, &out_dir, None, pak.cipher())?;
+        assert_eq!(path, Path::new(&out_dir).join(expected));
+        assert_eq!(std::fs::read_to_string(&path)?, *name);
+    }
+
+    let out_dir = format!("{}/all", work_dir);
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions::default())?;
+    for (name, expected) in files.iter().zip(expected.iter()) {
+        assert_eq!(std::fs::read_to_string(format!("{}/{}", out_dir, expected))?, *name);
+    }
+    let long_path_name = Path::new(&out_dir).join(files[2]);
+    assert!(long_path_name.as_os_str().len() > 260);
+    assert_eq!(std::fs::read_to_string(long_path(&long_path_name))?, files[2]);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_to_archive() -> Result<()> {
+    use std::io::Read;
+
+    let work_dir = &util::work_dir("unpack_to_archive")?;
+
+    let data = compressible_data(100_000);
+    let long_name = format!("sub/{}.txt", "n".repeat(120));
+    write_file(format!("{}/src/a.txt", work_dir), &data)?;
+    write_file(format!("{}/src/{}", work_dir, long_name), b"hello")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/:{}/src", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions::default())?;
+    let expected: Vec<(String, Vec<u8>)> = vec![
+        ("a.txt".to_string(), data.clone()),
+        (long_name.clone(), b"hello".to_vec()),
+    ];
+
+    let read_u16 = |data: &[u8], offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]) as usize;
+    let read_u32 = |data: &[u8], offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+
+    // ZIP: sizes and CRC are only in the data descriptors and the central
+    // directory, since records are streamed into the archive
+    let mut zip = Vec::new();
+    unpack_to_archive(&pak, &mut File::open(&pak_path)?, &mut zip, ArchiveFormat::Zip, ArchiveOptions::default())?;
+    let mut entries = Vec::new();
+    let mut offset = read_u32(&zip, zip.len() - 22 + 16);
+    while read_u32(&zip, offset) == 0x02014b50 {
+        let flags = read_u16(&zip, offset + 8);
+        let method = read_u16(&zip, offset + 10);
+        let crc32 = read_u32(&zip, offset + 16);
+        let compressed_size = read_u32(&zip, offset + 20);
+        let size = read_u32(&zip, offset + 24);
+        let name_len = read_u16(&zip, offset + 28);
+        let extra_len = read_u16(&zip, offset + 30);
+        let comment_len = read_u16(&zip, offset + 32);
+        let local_offset = read_u32(&zip, offset + 42);
+        let name = String::from_utf8(zip[offset + 46..offset + 46 + name_len].to_vec()).unwrap();
+        offset += 46 + name_len + extra_len + comment_len;
+
+        assert_eq!(flags & (1 << 3), 1 << 3);
+        assert_eq!(read_u32(&zip, local_offset), 0x04034b50);
+        assert_eq!(read_u32(&zip, local_offset + 14), 0);
+        let start = local_offset + 30 + read_u16(&zip, local_offset + 26) + read_u16(&zip, local_offset + 28);
+        let raw = &zip[start..start + compressed_size];
+        let descriptor = start + compressed_size;
+        assert_eq!(read_u32(&zip, descriptor), 0x08074b50);
+        assert_eq!(read_u32(&zip, descriptor + 4), crc32);
+        assert_eq!(read_u32(&zip, descriptor + 8), compressed_size);
+        assert_eq!(read_u32(&zip, descriptor + 12), size);
+
+        let content = if method == 8 {
+            let mut content = Vec::new();
+            flate2::read::DeflateDecoder::new(raw).read_to_end(&mut content)?;
+            content
+        } else {
+            raw.to_vec()
+        };
+        assert_eq!(content.len(), size);
+        let mut crc = flate2::Crc::new();
+        crc.update(&content);
+        assert_eq!(crc.sum() as usize, crc32);
+        entries.push((name, content));
+    }
+    let eocd = zip.len() - 22;
+    assert_eq!(read_u32(&zip, eocd), 0x06054b50);
+    assert_eq!(read_u16(&zip, eocd + 10), expected.len());
+    entries.sort();
+    assert_eq!(entries, expected);
+
+    // tar: GNU long names are stored in an extra entry
+    let mut tar = Vec::new();
+    unpack_to_archive(&pak, &mut File::open(&pak_path)?, &mut tar, ArchiveFormat::Tar, ArchiveOptions::default())?;
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    let mut long_name: Option<String> = None;
+    while tar[offset] != 0 {
+        let header = &tar[offset..offset + 512];
+        let size = usize::from_str_radix(std::str::from_utf8(&header[124..135]).unwrap(), 8).unwrap();
+        let content = tar[offset + 512..offset + 512 + size].to_vec();
+        offset += 512 + size.div_ceil(512) * 512;
+        if header[156] == b'L' {
+            long_name = Some(String::from_utf8(content[..content.len() - 1].to_vec()).unwrap());
+        } else {
+            let name_len = header[..100].iter().position(|&byte| byte == 0).unwrap_or(100);
+            let name = long_name.take().unwrap_or_else(|| String::from_utf8(header[..name_len].to_vec()).unwrap());
+            entries.push((name, content));
+        }
+    }
+    assert_eq!(tar.len(), offset + 1024);
+    entries.sort();
+    assert_eq!(entries, expected);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_predicate() -> Result<()> {
+    let work_dir = &util::work_dir("unpack_predicate")?;
+
+    write_file(format!("{}/src/big.txt", work_dir), &compressible_data(100_000))?;
+    write_file(format!("{}/src/small.txt", work_dir), b"hello")?;
+    write_file(format!("{}/src/sub/other.txt", work_dir), &compressible_data(2_000))?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths: Vec<PackPath> = vec![
+        format!(":zlib,rename=/big.txt:{}/src/big.txt", work_dir).as_str().try_into()?,
+        format!(":rename=/small.txt:{}/src/small.txt", work_dir).as_str().try_into()?,
+        format!(":rename=/sub/other.txt:{}/src/sub/other.txt", work_dir).as_str().try_into()?,
+    ];
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+
+    let select = |expr: &str| -> Result<Vec<String>> {
+        let predicate = Predicate::parse(expr)?;
+        Ok(pak.index().records().iter()
+            .filter(|record| predicate.matches(record))
+            .map(|record| record.filename().to_string())
+            .collect())
+    };
+
+    assert_eq!(select("method=zlib")?, vec!["big.txt"]);
+    assert_eq!(select("method!=zlib,uncompressed_size>1K")?, vec!["sub/other.txt"]);
+    assert_eq!(select("uncompressed_size<=5")?, vec!["small.txt"]);
+    assert!(select("encrypted")?.is_empty());
+    assert_eq!(select("!encrypted")?.len(), 3);
+    // pak version 3 has no timestamps, so no record matches
+    assert!(select("timestamp>=0")?.is_empty());
+    assert!(Predicate::parse("bogus=1").is_err());
+    assert!(Predicate::parse("method=lz4").is_err());
+
+    let out_dir = format!("{}/out", work_dir);
+    let predicate = Predicate::parse("uncompressed_size>=1K")?;
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        predicate: Some(&predicate),
+        ..UnpackOptions::default()
+    })?;
+    assert!(Path::new(&format!("{}/big.txt", out_dir)).exists());
+    assert!(Path::new(&format!("{}/sub/other.txt", out_dir)).exists());
+    assert!(!Path::new(&format!("{}/small.txt", out_dir)).exists());
+
+    // paths that only fail the predicate are still found
+    let out_dir = format!("{}/out2", work_dir);
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        paths: Some(&["small.txt", "sub"]),
+        predicate: Some(&predicate),
+        ..UnpackOptions::default()
+    })?;
+    assert!(Path::new(&format!("{}/sub/other.txt", out_dir)).exists());
+    assert!(!Path::new(&format!("{}/small.txt", out_dir)).exists());
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_unpack_large_records() -> Result<()> {
+    let work_dir = &util::work_dir("unpack-large-records")?;
+
+    // bigger than the chunk size used for copying uncompressed records
+    let a_data = compressible_data(3 * 1024 * 1024 + 123);
+    let b_data = compressible_data(1024 * 1024);
+    write_file(format!("{}/files/a.bin", work_dir), &a_data)?;
+    write_file(format!("{}/files/b.bin", work_dir), &b_data)?;
+
+    for version in [2, 3] {
+        let pak_path = format!("{}/Game-v{}.pak", work_dir, version);
+        let paths = [
+            format!(":rename=/a.bin:{}/files/a.bin", work_dir),
+            format!(":zlib,rename=/b.bin:{}/files/b.bin", work_dir),
+        ];
+        util::pack(&pak_path, &paths, PackOptions {
+            version,
+            ..PackOptions::default()
+        })?;
+
+        let mut file = File::open(&pak_path)?;
+        let pak = Pak::from_file(&mut file, u4pak::pak::Options::default())?;
+        for (name, expected) in [("a.bin", &a_data), ("b.bin", &b_data)] {
+            let record = pak.record(name).unwrap();
+            let mut data = Vec::new();
+            unpack_record_to_writer(record, pak.version(), pak.variant(), &mut file, &mut data, None, pak.cipher())?;
+            assert_eq!(&data, expected, "{} of version {}", name, version);
+        }
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_unpack_order() -> Result<()> {
+    use std::convert::TryFrom;
+    use std::num::NonZeroUsize;
+    use u4pak::schedule::WorkOrder;
+
+    let work_dir = &util::work_dir("unpack-order")?;
+
+    let data = compressible_data(100_000);
+    let names = ["c.txt", "a.txt", "b.txt"];
+    for (index, name) in names.iter().enumerate() {
+        write_file(format!("{}/src/{}", work_dir, name), &data[..1000 * (index + 1)])?;
+    }
+    let paths: Vec<PackPath> = names.iter()
+        .map(|name| format!(":rename=/{}:{}/src/{}", name, work_dir, name).as_str().try_into())
+        .collect::<Result<_>>()?;
+    let pak_path = format!("{}/out.pak", work_dir);
+    let pak = pack(&pak_path, &paths, PackOptions::default())?;
+
+    let mut expected: Vec<_> = pak.index().records().iter().collect();
+    expected.sort_by_key(|record| record.offset());
+    let expected: Vec<u64> = expected.iter().map(|record| record.uncompressed_size()).collect();
+
+    assert_eq!(WorkOrder::try_from("Offset")?, WorkOrder::Offset);
+    assert_eq!(WorkOrder::try_from(" size ")?, WorkOrder::Size);
+    assert!(WorkOrder::try_from("name").is_err());
+
+    // with a single thread the processed bytes show the order of the records
+    let processed = Mutex::new(Vec::new());
+    let progress = |info: &ProgressInfo| processed.lock().unwrap().push(info.processed_bytes);
+    let out_dir = format!("{}/out", work_dir);
+    unpack(&pak, &mut File::open(&pak_path)?, &out_dir, UnpackOptions {
+        thread_count: NonZeroUsize::new(1).unwrap(),
+        order: WorkOrder::Offset,
+        progress: Some(&progress),
+        ..UnpackOptions::default()
+    })?;
+
+    let processed = processed.into_inner().unwrap();
+    let sizes: Vec<u64> = processed.iter().zip(std::iter::once(&0).chain(processed.iter()))
+        .map(|(processed, previous)| processed - previous)
+        .filter(|&size| size > 0)
+        .collect();
+    assert_eq!(sizes, expected);
+
+    for (index, name) in names.iter().enumerate() {
+        assert_eq!(std::fs::read(format!("{}/{}", out_dir, name))?, &data[..1000 * (index + 1)]);
+    }
+
+    // check gets the biggest files first
+    let processed = Mutex::new(Vec::new());
+    let progress = |info: &ProgressInfo| processed.lock().unwrap().push(info.processed_files);
+    let errors = check_with_report(&pak, &mut File::open(&pak_path)?, CheckOptions {
+        thread_count: NonZeroUsize::new(1).unwrap(),
+        order: WorkOrder::Size,
+        progress: Some(&progress),
+        ..CheckOptions::default()
+    })?;
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    let mut records: Vec<_> = pak.index().records().iter().collect();
+    u4pak::schedule::sort_records(&mut records, WorkOrder::Size);
+    let sizes: Vec<u64> = records.iter().map(|record| record.uncompressed_size()).collect();
+    assert_eq!(sizes, [3000, 2000, 1000]);
+    assert_eq!(processed.into_inner().unwrap().last(), Some(&3));
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_extract_to() -> Result<()> {
+    use u4pak::unpack::{extract_to, extract_to_with_key};
+
+    let work_dir = &util::work_dir("extract-to")?;
+
+    let key = base64::decode("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+    let data = compressible_data(300_000);
+    write_file(format!("{}/src/a.uasset", work_dir), &data)?;
+    write_file(format!("{}/src/b.uasset", work_dir), &data[..5003])?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":zlib,rename=/Game/Content/a.uasset:{}/src/a.uasset", work_dir),
+        format!(":zlib,encrypt,rename=/Game/Content/b.uasset:{}/src/b.uasset", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        encryption_key: Some(key.clone()),
+        ..PackOptions::default()
+    })?;
+
+    let mut reader = File::open(&pak_path)?;
+    let mut out = Vec::new();
+    extract_to(&pak, &mut reader, "/Game/Content/a.uasset", &mut out)?;
+    assert_eq!(out, data);
+
+    out.clear();
+    extract_to_with_key(&pak, &mut reader, "Game/Content/b.uasset", &mut out, Some(key))?;
+    assert_eq!(out, &data[..5003]);
+
+    let error = extract_to(&pak, &mut reader, "Game/Content/b.uasset", &mut Vec::new()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::EncryptedNoKey, "{}", error);
+
+    let error = extract_to(&pak, &mut reader, "Game/Content/c.uasset", &mut Vec::new()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::PathNotFound);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_read_record_range() -> Result<()> {
+    use u4pak::entry::{read_record_range, unpack_record_range_to_writer};
+
+    let work_dir = &util::work_dir("read-record-range")?;
+
+    let b_data = compressible_data(200_000);
+    write_file(format!("{}/files/a.txt", work_dir), b"hello world")?;
+    write_file(format!("{}/files/b.bin", work_dir), &b_data)?;
+
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let paths = [
+        format!(":rename=/a.txt:{}/files/a.txt", work_dir),
+        format!(":zlib,rename=/b.bin:{}/files/b.bin", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions::default())?;
+
+    let mut file = File::open(&pak_path)?;
+    let pak = Pak::from_file(&mut file, u4pak::pak::Options::default())?;
+    let a = pak.record("a.txt").unwrap();
+    let b = pak.record("b.bin").unwrap();
+
+    let data = read_record_range(a, pak.version(), pak.variant(), &mut file, 6, 100, None, pak.cipher())?;
+    assert_eq!(data, b"world");
+    let data = read_record_range(a, pak.version(), pak.variant(), &mut file, 20, 10, None, pak.cipher())?;
+    assert!(data.is_empty());
+
+    // spanning the boundary between the first two compression blocks
+    assert!(b.compression_blocks().as_ref().unwrap().len() > 1);
+    let block_size = b.compression_block_size() as usize;
+    let offset = block_size - 100;
+    let data = read_record_range(b, pak.version(), pak.variant(), &mut file, offset as u64, 300, None, pak.cipher())?;
+    assert_eq!(data, &b_data[offset..offset + 300]);
+
+    let offset = b_data.len() - 10;
+    let data = read_record_range(b, pak.version(), pak.variant(), &mut file, offset as u64, 300, None, pak.cipher())?;
+    assert_eq!(data, &b_data[offset..]);
+
+    // streamed, spanning several compression blocks
+    let offset = block_size / 2;
+    let mut data = Vec::new();
+    unpack_record_range_to_writer(b, pak.version(), pak.variant(), &mut file, offset as u64, 2 * block_size as u64, &mut data, None, pak.cipher())?;
+    assert!(data == b_data[offset..offset + 2 * block_size]);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_read_from_memory() -> Result<()> {
+    let work_dir = &util::work_dir("read_from_memory")?;
+
+    let data = compressible_data(200_000);
+    write_file(format!("{}/src/big.txt", work_dir), &data)?;
+    write_file(format!("{}/src/small.txt", work_dir), b"small")?;
+
+    let pak_path = format!("{}/out.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/src", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions {
+        compression_method: COMPR_ZLIB,
+        ..PackOptions::default()
+    })?;
+
+    // only uses what is available without the "fs" feature
+    let mut reader = std::io::Cursor::new(std::fs::read(&pak_path)?);
+    let pak = Pak::from_reader(&mut reader, u4pak::pak::Options::default())?;
+
+    let record = pak.record("big.txt").expect("big.txt not found");
+    assert_eq!(record.compression_method(), COMPR_ZLIB);
+    let mut out = Vec::new();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), &mut reader, &mut out, None, pak.cipher())?;
+    assert_eq!(out, data);
+
+    let record = pak.record("small.txt").expect("small.txt not found");
+    out.clear();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), &mut reader, &mut out, None, pak.cipher())?;
+    assert_eq!(out, b"small");
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
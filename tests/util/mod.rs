@@ -1,14 +1,19 @@
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use std::convert::TryInto;
 use std::fs::File;
 use std::io::BufReader;
 use std::num::NonZeroUsize;
 use std::path::Path;
 
 use u4pak::index::Encoding;
-use u4pak::pak::Options;
+use u4pak::pack::{PackOptions, PackPath};
+use u4pak::pak::{Options, PAK_MAGIC};
 use u4pak::unpack::UnpackOptions;
 use u4pak::util::{sha1_digest};
 use u4pak::walkdir::{walkdir};
-use u4pak::{Error, Pak, Result, Variant};
+use u4pak::{Error, Pak, Result, Variant, Warnings};
 
 pub fn remove_dir_all_if_exists(path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
     if let Err(error) = std::fs::remove_dir_all(path) {
@@ -21,6 +26,35 @@ pub fn remove_dir_all_if_exists(path: impl AsRef<std::path::Path>) -> std::io::R
     Ok(())
 }
 
+// Returns the directory ./NAME-it for the files of a test, after removing
+// what a previous run might have left there.
+pub fn work_dir(name: &str) -> Result<String> {
+    let work_dir = format!("./{}-it", name);
+    remove_dir_all_if_exists(&work_dir)?;
+    Ok(work_dir)
+}
+
+pub fn write_file(path: impl AsRef<Path>, data: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+pub fn compressible_data(size: usize) -> Vec<u8> {
+    b"Hello World! ".iter().cycle().take(size).cloned().collect()
+}
+
+// Packs paths given like on the command line (e.g. ":zlib,rename=/:DIR").
+pub fn pack(pak_path: &str, paths: &[String], options: PackOptions) -> Result<Pak> {
+    let paths = paths.iter()
+        .map(|path| path.as_str().try_into())
+        .collect::<Result<Vec<PackPath>>>()?;
+    u4pak::pack::pack(pak_path, &paths, options)
+}
+
 pub fn unpack(path: &str, outdir: &str, encryption: Option<String>) -> Result<()> {
     let encryption_key = if let Some(key) = encryption {
         Some(
@@ -45,9 +79,20 @@ pub fn unpack(path: &str, outdir: &str, encryption: Option<String>) -> Result<()
         Options {
             variant: Variant::default(),
             ignore_magic: false,
+            magic: PAK_MAGIC,
             encoding: Encoding::default(),
             force_version: None,
             encryption_key: encryption_key.clone(),
+            keyring: Default::default(),
+            cipher: u4pak::decrypt::default_cipher(),
+            index_transform: None,
+            name_list: None,
+            harvest_names: false,
+            keep_unknown: false,
+            detect_variant: true,
+            detect_block_offsets: true,
+            scan_footer: None,
+            warnings: Warnings::default(),
         },
     )?;
 
@@ -64,7 +109,24 @@ pub fn unpack(path: &str, outdir: &str, encryption: Option<String>) -> Result<()
             paths: None,
             thread_count: NonZeroUsize::new(num_cpus::get())
                 .unwrap_or(NonZeroUsize::new(1).unwrap()),
+            max_memory: None,
+            io_strategy: Default::default(),
+            read_size: u4pak::pread::DEFAULT_READ_SIZE,
             encryption_key,
+            progress: None,
+            overwrite: Default::default(),
+            preserve_timestamps: false,
+            fallback_timestamp: None,
+            allow_unsafe_paths: false,
+            predicate: None,
+            digest: None,
+            order: Default::default(),
+            skip_errors: false,
+            fallback_decompression: false,
+            strip_prefix: None,
+            flatten: false,
+            name_policy: u4pak::portable::NamePolicy::Keep,
+            normalize_nfc: false,
         },
     )
 }
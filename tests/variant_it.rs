@@ -0,0 +1,288 @@
+mod util;
+
+use std::convert::TryInto;
+use std::fs::File;
+
+use u4pak::entry::unpack_record_to_writer;
+use u4pak::check::{check, CheckOptions};
+use u4pak::pack::{pack, PackOptions, PackPath};
+use u4pak::pak::COMPR_ZLIB;
+use u4pak::{ErrorKind, Pak, Result};
+use util::{compressible_data, remove_dir_all_if_exists, write_file};
+
+#[test]
+fn test_pack_conan_exiles() -> Result<()> {
+    use u4pak::pak::Variant;
+
+    let work_dir = &util::work_dir("pack-conan-exiles")?;
+
+    let big = compressible_data(200_000);
+    write_file(format!("{}/files/big.txt", work_dir), &big)?;
+    write_file(format!("{}/files/small.txt", work_dir), b"small")?;
+
+    let pak_path = format!("{}/ConanSandbox.pak", work_dir);
+    let paths = [
+        format!(":rename=/ConanSandbox:{}/files", work_dir),
+    ];
+    let result = util::pack(&pak_path, &paths, PackOptions {
+        variant: Variant::ConanExiles,
+        version: 3,
+        ..PackOptions::default()
+    });
+    assert!(result.is_err());
+
+    util::pack(&pak_path, &paths, PackOptions {
+        variant: Variant::ConanExiles,
+        version: 4,
+        compression_method: COMPR_ZLIB,
+        compression_block_size: std::num::NonZeroU32::new(64 * 1024).unwrap(),
+        mount_point: Some("../../../"),
+        ..PackOptions::default()
+    })?;
+
+    let options = u4pak::pak::Options {
+        variant: Variant::ConanExiles,
+        ..u4pak::pak::Options::default()
+    };
+    let pak = Pak::from_path(&pak_path, options)?;
+    assert_eq!(pak.version(), 4);
+    let record = pak.record("ConanSandbox/big.txt").unwrap();
+    assert_eq!(record.compression_method(), COMPR_ZLIB);
+    assert_eq!(record.compression_blocks().as_ref().map(Vec::len), Some(4));
+
+    let errors = check(&pak, &mut File::open(&pak_path)?, CheckOptions {
+        variant: Variant::ConanExiles,
+        deep: true,
+        ..CheckOptions::default()
+    })?;
+    assert_eq!(errors, 0);
+
+    let mut data = Vec::new();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), &mut File::open(&pak_path)?, &mut data, None, pak.cipher())?;
+    assert_eq!(data, big);
+
+    let mut data = Vec::new();
+    let record = pak.record("ConanSandbox/small.txt").unwrap();
+    unpack_record_to_writer(record, pak.version(), pak.variant(), &mut File::open(&pak_path)?, &mut data, None, pak.cipher())?;
+    assert_eq!(data, b"small");
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_variant_spec() -> Result<()> {
+    use std::convert::TryFrom;
+    use u4pak::pak::Variant;
+
+    for variant in Variant::ALL.iter().cloned() {
+        assert_eq!(Variant::from_name(variant.name()), Some(variant));
+        assert!(variant.spec().can_write(variant.spec().default_version()));
+    }
+    assert_eq!(Variant::try_from(" Conan Exiles ")?, Variant::ConanExiles);
+    assert!(Variant::try_from("foo").is_err());
+
+    let standard = Variant::Standard.spec();
+    assert!(standard.can_read(7));
+    assert!(standard.can_write(7));
+    assert!(!standard.can_write(6));
+    assert!(!standard.can_write(8));
+    assert!(!Variant::ConanExiles.spec().can_read(3));
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_magic() -> Result<()> {
+    let work_dir = &util::work_dir("custom-magic")?;
+
+    write_file(format!("{}/files/a.txt", work_dir), b"hello")?;
+
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/files", work_dir),
+    ];
+    util::pack(&pak_path, &paths, PackOptions {
+        magic: 0xDEADBEEF,
+        ..PackOptions::default()
+    })?;
+
+    let error = Pak::from_path(&pak_path, u4pak::pak::Options::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::UnsupportedVersion);
+
+    let pak = Pak::from_path(&pak_path, u4pak::pak::Options {
+        magic: 0xDEADBEEF,
+        ..u4pak::pak::Options::default()
+    })?;
+    assert_eq!(pak.footer().unwrap().magic(), 0xDEADBEEF);
+    assert_eq!(pak.version(), 3);
+
+    let error = Pak::from_path(&pak_path, u4pak::pak::Options {
+        force_version: Some(3),
+        ..u4pak::pak::Options::default()
+    }).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidMagic);
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_index_xor() -> Result<()> {
+    use u4pak::index::IndexTransform;
+
+    let work_dir = &util::work_dir("index-xor")?;
+
+    write_file(format!("{}/files/a.txt", work_dir), b"hello")?;
+    write_file(format!("{}/files/dir/b.txt", work_dir), b"world")?;
+
+    let pak_path = format!("{}/Game.pak", work_dir);
+    let paths = [
+        format!(":rename=/:{}/files", work_dir),
+    ];
+    let pak = util::pack(&pak_path, &paths, PackOptions {
+        mount_point: Some("../../../"),
+        ..PackOptions::default()
+    })?;
+
+    let mut data = std::fs::read(&pak_path)?;
+    let index_start = pak.index_offset() as usize;
+    let index_end = index_start + pak.index_size() as usize;
+    for byte in &mut data[index_start..index_end] {
+        *byte ^= 0x5A;
+    }
+    std::fs::write(&pak_path, &data)?;
+
+    assert!(Pak::from_path(&pak_path, u4pak::pak::Options::default()).is_err());
+
+    let xored = Pak::from_path(&pak_path, u4pak::pak::Options {
+        index_transform: Some(IndexTransform::xor(0x5A)),
+        ..u4pak::pak::Options::default()
+    })?;
+    assert_eq!(xored.index().mount_point(), Some("../../../"));
+    assert_eq!(xored.index().records(), pak.index().records());
+
+    remove_dir_all_if_exists(work_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_game_hints() -> Result<()> {
+    use u4pak::hints::game_hints;
+    use u4pak::pak::{Options, Variant};
+
+    let work_dir = &util::work_dir("game-hints")?;
+
+    write_file(format!("{}/src/a.txt", work_dir), b"hello world")?;
+    let paths = [
+        format!(":rename=/ConanSandbox/Content/a.txt:{}/src/a.txt", work_dir),
+    ];
+
+    let standard_path = format!("{}/standard.pak", work_dir);
+    util::pack(&standard_path, &paths, PackOptions::default())?;
+    let hints = game_hints(&mut File::open(&standard_path)?, &Options::default())?;
+    assert_eq!(hints.version, Some(3));
+    assert_eq!(hints.engine_version, Some("4.3 - 4.15"));
+    assert_eq!(hints.variant, Some(Variant::Standard));
+    assert_eq!(hints.project.as_deref(), Some("ConanSandbox"));
+    assert_eq!(hints.game, Some("Conan Exiles"));
+    assert!(hints.flags.is_empty(), "{:?}", hints.flags);
+
+    let conan_path = format!("{}/conan.pak", work_dir);
+    util::pack(&conan_path, &paths, PackOptions {
+        variant: Variant::ConanExiles,
+        version: 4,
+        ..PackOptions::default()
+    })?;
+    let hints = game_hints(&mut File::open(&conan_path)?, &Options::default())?;
+    assert_eq!(hints.version, Some(4));
+    assert_eq!(hints.variant, Some(Variant::ConanExiles));
+    assert!(hints.flags.is_empty(), "{:?}", hints.flags);
+
+    let hints = game_hints(&mut File::open(&conan_path)?, &Options { detect_variant: false, ..Options::default() })?;
+    assert_eq!(hints.variant, Some(Variant::ConanExiles));
+    assert_eq!(hints.flags, vec!["--variant=conan_exiles".to_string()]);
+
+    let magic_path = format!("{}/magic.pak", work_dir);
+    util::pack(&magic_path, &paths, PackOptions {
+        magic: 0x12345678,
+        ..PackOptions::default()
+    })?;
+    let hints = game_hints(&mut File::open(&magic_path)?, &Options::default())?;
+    assert_eq!(hints.version, Some(3));
+    assert_eq!(hints.flags, vec!["--magic=0x12345678".to_string()]);
+
+    let not_a_pak = format!("{}/src/a.txt", work_dir);
+    let hints = game_hints(&mut File::open(&not_a_pak)?, &Options::default())?;
+    assert_eq!(hints.version, None);
+    assert_eq!(hints.notes.len(), 1);
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_detect_variant() -> Result<()> {
+    use u4pak::pak::{Options, Variant};
+
+    let work_dir = &util::work_dir("detect-variant")?;
+
+    let data = compressible_data(100_000);
+    write_file(format!("{}/src/a.txt", work_dir), b"hello world")?;
+    write_file(format!("{}/src/b.txt", work_dir), &data)?;
+    write_file(format!("{}/src/c.txt", work_dir), &data[..5000])?;
+
+    let single: Vec<PackPath> = vec![
+        format!(":rename=/a.txt:{}/src/a.txt", work_dir).as_str().try_into()?,
+    ];
+    let several: Vec<PackPath> = vec![
+        format!(":rename=/a.txt:{}/src/a.txt", work_dir).as_str().try_into()?,
+        format!(":zlib,rename=/b.txt:{}/src/b.txt", work_dir).as_str().try_into()?,
+        format!(":rename=/c.txt:{}/src/c.txt", work_dir).as_str().try_into()?,
+    ];
+
+    for (name, paths) in [("single", &single), ("several", &several)] {
+        let conan_path = format!("{}/conan-{}.pak", work_dir, name);
+        pack(&conan_path, paths, PackOptions {
+            variant: Variant::ConanExiles,
+            version: 4,
+            ..PackOptions::default()
+        })?;
+
+        let pak = Pak::from_path(&conan_path, Options::default())?;
+        assert_eq!(pak.variant(), Variant::ConanExiles, "{}", name);
+        assert_eq!(pak.index().records().len(), paths.len(), "{}", name);
+
+        let standard_path = format!("{}/standard-{}.pak", work_dir, name);
+        pack(&standard_path, paths, PackOptions {
+            version: 4,
+            ..PackOptions::default()
+        })?;
+
+        let pak = Pak::from_path(&standard_path, Options::default())?;
+        assert_eq!(pak.variant(), Variant::Standard, "{}", name);
+
+        // an explicitly given variant is preferred, but detection still
+        // switches if it doesn't fit
+        let pak = Pak::from_path(&standard_path, Options {
+            variant: Variant::ConanExiles,
+            ..Options::default()
+        })?;
+        assert_eq!(pak.variant(), Variant::Standard, "{}", name);
+
+        let result = Pak::from_path(&conan_path, Options {
+            detect_variant: false,
+            warnings: u4pak::warnings::Warnings::Ignore,
+            ..Options::default()
+        });
+        if let Ok(pak) = result {
+            assert_eq!(pak.variant(), Variant::Standard, "{}", name);
+        }
+    }
+
+    remove_dir_all_if_exists(work_dir)?;
+    Ok(())
+}